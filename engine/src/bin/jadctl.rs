@@ -0,0 +1,21 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+#[path = "../ctl.rs"]
+mod ctl;
+
+use std::io::IsTerminal;
+
+use clap::Parser;
+
+#[tokio::main]
+async fn main() {
+    let cli = ctl::Cli::parse();
+    let stdout = std::io::stdout();
+    let color = stdout.is_terminal() && std::env::var_os("NO_COLOR").is_none();
+    if let Err(e) = ctl::run(&cli, stdout, color).await {
+        eprintln!("jadctl: {e}");
+        std::process::exit(e.exit_code());
+    }
+}
@@ -2,17 +2,24 @@
  * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
  ******************************************************************************/
 
-use std::{collections::BTreeSet, fmt::Display, str::FromStr};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    fmt::Display,
+    ops::Deref,
+    str::FromStr,
+    sync::Arc,
+};
 
 use apistos::ApiComponent;
-use jaeger_anomaly_detection::{Duration, WindowConfig};
+use jaeger_anomaly_detection::{Duration, TraceAggrError, TraceExpr, WindowConfig};
 use prometheus_core::LabelName;
 use serde::{Deserialize, Serialize};
 use serde_with::{DeserializeFromStr, SerializeDisplay};
 
 use crate::{
     jaeger::{Span, TagValueRef},
-    processor::trace::TraceConfig,
+    metrics::is_valid_metric_name,
+    processor::{pushdown::PushdownError, trace::TraceConfig},
 };
 
 #[derive(Serialize, Deserialize, schemars::JsonSchema, ApiComponent, PartialEq, Clone, Debug)]
@@ -21,8 +28,67 @@ pub struct Config {
     #[serde(flatten)]
     pub trace: TraceConfig,
     pub query_interval: Duration,
+    /// The time between samples, if different from the query interval.
+    /// Spans carry their own timestamps, so OpenSearch can be queried
+    /// less often than samples are taken. Must divide the query
+    /// interval.
+    pub sample_interval: Option<Duration>,
     pub max_history: Duration,
     pub delay: Duration,
+    pub ingest_filter: IngestFilter,
+    /// Fetch span logs. Logs are needed by log-based metric sources
+    /// and selectors, but increase the memory used per span, so they
+    /// are left out of the OpenSearch responses unless enabled.
+    pub ingest_logs: bool,
+    /// Expressions evaluated periodically against prometheus, with
+    /// the latest results served from memory.
+    pub cached_queries: Vec<CachedQuery>,
+    /// The maximum number of series written per sample. When exceeded,
+    /// the lowest priority series are dropped: histogram buckets first,
+    /// then summaries, welford internals and counts, keeping anomaly
+    /// scores last. Should be set below the remote-write backend's
+    /// per-tenant series limit.
+    pub max_series: Option<usize>,
+    /// Drop the span and process tags that are not referenced by the
+    /// trace config right after parsing, before the spans are held in
+    /// memory for processing. Disable to keep full spans, e.g. while
+    /// troubleshooting. The debug trace endpoint always sees full
+    /// spans.
+    pub prune_tags: bool,
+    /// The maximum number of values of an `in` or `not_in` selector.
+    /// Large sets bloat the state and the config updates.
+    pub max_selector_values: usize,
+}
+
+#[derive(Serialize, Deserialize, schemars::JsonSchema, PartialEq, Clone, Debug)]
+pub struct CachedQuery {
+    /// The name under which the result is served. Names should be
+    /// unique; later entries replace earlier ones with the same name.
+    pub name: String,
+    #[schemars(with = "serde_json::Value")]
+    pub expr: TraceExpr,
+    /// The refresh interval.
+    pub refresh: Duration,
+}
+
+/// Restricts the traces fetched from OpenSearch based on the service
+/// name and namespace of their root span. Empty include lists match
+/// everything. Regexes are anchored and should stick to the syntax
+/// supported by both OpenSearch (Lucene) and the regex crate.
+#[derive(Serialize, Deserialize, schemars::JsonSchema, PartialEq, Eq, Clone, Default, Debug)]
+#[serde(default)]
+pub struct IngestFilter {
+    pub include_services: Vec<ValueMatch>,
+    pub exclude_services: Vec<ValueMatch>,
+    pub include_namespaces: Vec<ValueMatch>,
+    pub exclude_namespaces: Vec<ValueMatch>,
+}
+
+#[derive(Serialize, Deserialize, schemars::JsonSchema, PartialEq, Eq, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ValueMatch {
+    Eq(String),
+    Match(AnchoredRegex),
 }
 
 #[derive(
@@ -66,8 +132,8 @@ pub enum SpanSelector {
     Any(Vec<SpanSelector>),
     Not(Box<SpanSelector>),
     Has(SpanKey),
-    In(SpanKey, BTreeSet<String>),
-    NotIn(SpanKey, BTreeSet<String>),
+    In(SpanKey, ValueSet),
+    NotIn(SpanKey, ValueSet),
     Match(SpanKey, Regex),
     NoMatch(SpanKey, Regex),
     KeyEq(SpanKey, SpanKey),
@@ -78,14 +144,163 @@ pub enum SpanSelector {
     Outside(SpanKey, Range),
     IsTrue(SpanKey),
     IsFalse(SpanKey),
+    /// The span has a log entry with the given field, whose value
+    /// matches the regex. Requires `ingest_logs`.
+    HasLog(String, Regex),
+    /// The span is of (one of) the given kind(s). Spans without a
+    /// kind never match.
+    Kind(SpanKindSelector),
+}
+
+/// The values of an `in` or `not_in` selector. Sets of at least
+/// `VALUE_SET_INDEX_MIN` values are looked up in a hash set once
+/// indexed (see `SpanSelector::index`); the index is shared between
+/// clones and not serialized.
+#[derive(Serialize, Deserialize, schemars::JsonSchema, Clone, Debug)]
+#[serde(transparent)]
+pub struct ValueSet {
+    values: BTreeSet<String>,
+    #[serde(skip)]
+    index: Option<Arc<HashSet<String>>>,
+}
+
+/// The size from which value sets are indexed.
+const VALUE_SET_INDEX_MIN: usize = 64;
+
+impl ValueSet {
+    pub fn contains(&self, value: &str) -> bool {
+        match &self.index {
+            Some(index) => index.contains(value),
+            None => self.values.contains(value),
+        }
+    }
+
+    /// Whether the set is looked up in a hash set.
+    #[cfg(test)]
+    pub fn is_indexed(&self) -> bool {
+        self.index.is_some()
+    }
+
+    fn index(&mut self) {
+        if self.index.is_none() && self.values.len() >= VALUE_SET_INDEX_MIN {
+            self.index = Some(Arc::new(self.values.iter().cloned().collect()));
+        }
+    }
+}
+
+impl Deref for ValueSet {
+    type Target = BTreeSet<String>;
+    fn deref(&self) -> &Self::Target {
+        &self.values
+    }
+}
+
+impl From<BTreeSet<String>> for ValueSet {
+    fn from(values: BTreeSet<String>) -> Self {
+        Self {
+            values,
+            index: None,
+        }
+    }
+}
+
+impl FromIterator<String> for ValueSet {
+    fn from_iter<T: IntoIterator<Item = String>>(iter: T) -> Self {
+        Self::from(iter.into_iter().collect::<BTreeSet<_>>())
+    }
+}
+
+impl Eq for ValueSet {}
+impl PartialEq for ValueSet {
+    fn eq(&self, other: &Self) -> bool {
+        self.values == other.values
+    }
+}
+
+/// One span kind, or a list of kinds of which any may match.
+#[derive(Serialize, Deserialize, schemars::JsonSchema, PartialEq, Eq, Clone, Debug)]
+#[serde(untagged)]
+pub enum SpanKindSelector {
+    One(SpanKind),
+    AnyOf(BTreeSet<SpanKind>),
+}
+
+#[derive(
+    Serialize, Deserialize, schemars::JsonSchema, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum SpanKind {
+    Client,
+    Server,
+    Producer,
+    Consumer,
+    Internal,
 }
 
+/// The span tag holding the span kind. Jaeger stores the kind of OTLP
+/// spans in this tag as well.
+pub(crate) const SPAN_KIND_TAG: &str = "span.kind";
+
+/// The span tag holding the OTEL status code. Jaeger stores the status
+/// of OTLP spans in this tag, leaving it out for unset statuses.
+const OTEL_STATUS_CODE_TAG: &str = "otel.status_code";
+
+/// The span tags read to resolve the status code, in order of
+/// precedence.
+pub(crate) const STATUS_CODE_TAGS: [&str; 3] = [OTEL_STATUS_CODE_TAG, "error", "http.status_code"];
+
+/// The prefix of the span tags holding W3C baggage entries.
+pub(crate) const BAGGAGE_TAG_PREFIX: &str = "baggage.";
+
+/// The span tag holding the W3C trace state.
+pub(crate) const TRACESTATE_TAG: &str = "w3c.tracestate";
+
+/// The process tag holding the service namespace.
+pub(crate) const SERVICE_NAMESPACE_TAG: &str = "service.namespace";
+
+const STATUS_OK: &str = "OK";
+pub(crate) const STATUS_ERROR: &str = "ERROR";
+const STATUS_UNSET: &str = "UNSET";
+
+#[derive(thiserror::Error, Debug)]
+#[error("unknown span kind: {0}")]
+pub struct UnknownSpanKind(String);
+
 #[derive(SerializeDisplay, DeserializeFromStr, Clone, Debug)]
 pub struct Regex(regex::Regex);
 
+/// The maximum length of the regexes in the config, checked before
+/// parsing. Regexes are compiled as the config is deserialized, before
+/// it is validated.
+const REGEX_MAX_LEN: usize = 4096;
+/// The maximum compiled size of the regexes in the config. The regex
+/// crate matches in linear time, but large repetitions of large
+/// classes compile to huge automata, which make matching slow.
+const REGEX_SIZE_LIMIT: usize = 1 << 20;
+/// The maximum size of the lazy DFA cache of a regex, which bounds the
+/// memory used while matching.
+const REGEX_DFA_SIZE_LIMIT: usize = 1 << 20;
+/// The maximum nesting depth of the regexes in the config.
+const REGEX_NEST_LIMIT: u32 = 32;
+
+/// Compile a regex from the config, within the length, size and
+/// nesting limits.
+fn compile_regex(re: &str) -> Result<regex::Regex, regex::Error> {
+    if re.len() > REGEX_MAX_LEN {
+        return Err(regex::Error::Syntax(format!(
+            "the regex is longer than {REGEX_MAX_LEN} bytes"
+        )));
+    }
+    regex::RegexBuilder::new(re)
+        .size_limit(REGEX_SIZE_LIMIT)
+        .dfa_size_limit(REGEX_DFA_SIZE_LIMIT)
+        .nest_limit(REGEX_NEST_LIMIT)
+        .build()
+}
+
 impl Regex {
     pub fn new(re: &str) -> Result<Self, regex::Error> {
-        Ok(Self(regex::Regex::new(re)?))
+        Ok(Self(compile_regex(re)?))
     }
 
     pub fn matches(&self, s: &str) -> bool {
@@ -102,7 +317,7 @@ impl Display for Regex {
 impl FromStr for Regex {
     type Err = regex::Error;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Ok(Self(s.parse()?))
+        Self::new(s)
     }
 }
 
@@ -125,6 +340,62 @@ impl schemars::JsonSchema for Regex {
     }
 }
 
+/// A regex that must match the whole value.
+#[derive(SerializeDisplay, DeserializeFromStr, Clone, Debug)]
+pub struct AnchoredRegex {
+    pattern: String,
+    regex: regex::Regex,
+}
+
+impl AnchoredRegex {
+    pub fn new(re: &str) -> Result<Self, regex::Error> {
+        Ok(Self {
+            pattern: re.to_string(),
+            regex: compile_regex(&format!("^(?:{re})$"))?,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.pattern
+    }
+
+    pub fn matches(&self, s: &str) -> bool {
+        self.regex.is_match(s)
+    }
+}
+
+impl Display for AnchoredRegex {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.pattern)
+    }
+}
+
+impl FromStr for AnchoredRegex {
+    type Err = regex::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::new(s)
+    }
+}
+
+impl Eq for AnchoredRegex {}
+impl PartialEq for AnchoredRegex {
+    fn eq(&self, other: &Self) -> bool {
+        self.pattern == other.pattern
+    }
+}
+
+impl schemars::JsonSchema for AnchoredRegex {
+    fn schema_name() -> std::string::String {
+        "AnchoredRegex".to_owned()
+    }
+    fn schema_id() -> std::borrow::Cow<'static, str> {
+        std::borrow::Cow::Borrowed(std::concat!(std::module_path!(), "::", "AnchoredRegex"))
+    }
+    fn json_schema(gen: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+        gen.subschema_for::<String>()
+    }
+}
+
 #[derive(Serialize, Deserialize, schemars::JsonSchema, PartialEq, Eq, Clone, Debug)]
 pub struct Range {
     pub lower: Option<LowerBound>,
@@ -171,6 +442,7 @@ pub enum MetricSource {
 pub enum SpanKey {
     Current(KeyName),
     Parent(KeyName),
+    Grandparent(KeyName),
 }
 
 #[derive(
@@ -183,96 +455,303 @@ pub enum KeyName {
     ProcessTag(String),
     SpanTag(String),
     Duration,
+    /// The kind of the span, from the `span.kind` tag.
+    SpanKind,
+    /// The normalized status of the span: "OK", "ERROR" or "UNSET".
+    /// Resolved from the `otel.status_code` tag, the `error` tag and
+    /// the `http.status_code` tag, in that order. HTTP status codes
+    /// outside 200-299 are errors.
+    StatusCode,
+    /// A W3C baggage entry, from the `baggage.<name>` tag or, if the
+    /// span does not have it, from the `w3c.tracestate` tag.
+    Baggage(String),
+    /// The class assigned by the named classifier of the span config.
+    /// Only resolved in group keys, annotations and the `by` key of
+    /// `rate_by` sources; selectors never see a value.
+    Class(String),
+}
+
+/// The classifiers of a span config, by name.
+pub type SpanClassifiers = BTreeMap<String, SpanClassifier>;
+
+/// Maps spans onto a small, fixed set of label values, e.g. error
+/// messages onto "timeout", "connection_refused" and "other". The
+/// classes are tried in order; a span gets the value of the first
+/// class whose selector matches, or the fallback value.
+#[derive(Serialize, Deserialize, schemars::JsonSchema, PartialEq, Eq, Clone, Debug)]
+pub struct SpanClassifier {
+    pub classes: Vec<SpanClass>,
+    pub fallback: String,
+}
+
+#[derive(Serialize, Deserialize, schemars::JsonSchema, PartialEq, Eq, Clone, Debug)]
+pub struct SpanClass {
+    pub value: String,
+    pub select: SpanSelector,
+}
+
+/// The parent and grandparent of a span, if present in the trace.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Ancestors<'a> {
+    pub parent: Option<&'a Span>,
+    pub grandparent: Option<&'a Span>,
 }
 
 impl SpanSelector {
-    pub(crate) fn matches(&self, span: &Span, parent: Option<&Span>) -> bool {
+    /// Index the large value sets of the selector for lookups while
+    /// matching. Called when building the processors from the config,
+    /// so that the indices are built once per config rather than per
+    /// span.
+    pub(crate) fn index(&mut self) {
         match self {
-            SpanSelector::All(sels) => sels.iter().all(|sel| sel.matches(span, parent)),
-            SpanSelector::Any(sels) => sels.iter().any(|sel| sel.matches(span, parent)),
-            SpanSelector::Not(sel) => !sel.matches(span, parent),
-            SpanSelector::Has(key) => key.get(span, parent).is_some(),
+            SpanSelector::All(sels) | SpanSelector::Any(sels) => {
+                sels.iter_mut().for_each(SpanSelector::index)
+            }
+            SpanSelector::Not(sel) => sel.index(),
+            SpanSelector::In(_, values) | SpanSelector::NotIn(_, values) => values.index(),
+            SpanSelector::Has(_)
+            | SpanSelector::Match(_, _)
+            | SpanSelector::NoMatch(_, _)
+            | SpanSelector::KeyEq(_, _)
+            | SpanSelector::KeyNe(_, _)
+            | SpanSelector::Eq(_, _)
+            | SpanSelector::Ne(_, _)
+            | SpanSelector::Inside(_, _)
+            | SpanSelector::Outside(_, _)
+            | SpanSelector::IsTrue(_)
+            | SpanSelector::IsFalse(_)
+            | SpanSelector::HasLog(_, _)
+            | SpanSelector::Kind(_) => {}
+        }
+    }
+
+    /// The size of the largest value set of the selector.
+    fn max_values(&self) -> usize {
+        match self {
+            SpanSelector::All(sels) | SpanSelector::Any(sels) => {
+                sels.iter().map(SpanSelector::max_values).max().unwrap_or(0)
+            }
+            SpanSelector::Not(sel) => sel.max_values(),
+            SpanSelector::In(_, values) | SpanSelector::NotIn(_, values) => values.len(),
+            SpanSelector::Has(_)
+            | SpanSelector::Match(_, _)
+            | SpanSelector::NoMatch(_, _)
+            | SpanSelector::KeyEq(_, _)
+            | SpanSelector::KeyNe(_, _)
+            | SpanSelector::Eq(_, _)
+            | SpanSelector::Ne(_, _)
+            | SpanSelector::Inside(_, _)
+            | SpanSelector::Outside(_, _)
+            | SpanSelector::IsTrue(_)
+            | SpanSelector::IsFalse(_)
+            | SpanSelector::HasLog(_, _)
+            | SpanSelector::Kind(_) => 0,
+        }
+    }
+
+    /// Whether the selector reads span logs, which are only fetched
+    /// with `ingest_logs`.
+    pub(crate) fn reads_logs(&self) -> bool {
+        match self {
+            SpanSelector::All(sels) | SpanSelector::Any(sels) => {
+                sels.iter().any(SpanSelector::reads_logs)
+            }
+            SpanSelector::Not(sel) => sel.reads_logs(),
+            SpanSelector::HasLog(_, _) => true,
+            SpanSelector::Has(_)
+            | SpanSelector::In(_, _)
+            | SpanSelector::NotIn(_, _)
+            | SpanSelector::Match(_, _)
+            | SpanSelector::NoMatch(_, _)
+            | SpanSelector::KeyEq(_, _)
+            | SpanSelector::KeyNe(_, _)
+            | SpanSelector::Eq(_, _)
+            | SpanSelector::Ne(_, _)
+            | SpanSelector::Inside(_, _)
+            | SpanSelector::Outside(_, _)
+            | SpanSelector::IsTrue(_)
+            | SpanSelector::IsFalse(_)
+            | SpanSelector::Kind(_) => false,
+        }
+    }
+
+    pub(crate) fn matches(&self, span: &Span, ancestors: Ancestors) -> bool {
+        match self {
+            SpanSelector::All(sels) => sels.iter().all(|sel| sel.matches(span, ancestors)),
+            SpanSelector::Any(sels) => sels.iter().any(|sel| sel.matches(span, ancestors)),
+            SpanSelector::Not(sel) => !sel.matches(span, ancestors),
+            SpanSelector::Has(key) => key.get(span, ancestors).is_some(),
             SpanSelector::In(key, values) => {
-                if let Some(TagValueRef::String(s)) = key.get(span, parent) {
+                if let Some(TagValueRef::String(s)) = key.get(span, ancestors) {
                     values.contains(s)
                 } else {
                     false
                 }
             }
             SpanSelector::NotIn(key, values) => {
-                if let Some(TagValueRef::String(s)) = key.get(span, parent) {
+                if let Some(TagValueRef::String(s)) = key.get(span, ancestors) {
                     !values.contains(s)
                 } else {
                     false
                 }
             }
-            SpanSelector::KeyEq(a, b) => a.get(span, parent) == b.get(span, parent),
-            SpanSelector::KeyNe(a, b) => a.get(span, parent) != b.get(span, parent),
+            SpanSelector::KeyEq(a, b) => a.get(span, ancestors) == b.get(span, ancestors),
+            SpanSelector::KeyNe(a, b) => a.get(span, ancestors) != b.get(span, ancestors),
             SpanSelector::Eq(key, v) => {
-                if let Some(TagValueRef::Int64(n)) = key.get(span, parent) {
+                if let Some(TagValueRef::Int64(n)) = key.get(span, ancestors) {
                     n == *v
                 } else {
                     false
                 }
             }
             SpanSelector::Match(key, re) => {
-                if let Some(TagValueRef::String(s)) = key.get(span, parent) {
+                if let Some(TagValueRef::String(s)) = key.get(span, ancestors) {
                     re.matches(s)
                 } else {
                     false
                 }
             }
             SpanSelector::NoMatch(key, re) => {
-                if let Some(TagValueRef::String(s)) = key.get(span, parent) {
+                if let Some(TagValueRef::String(s)) = key.get(span, ancestors) {
                     !re.matches(s)
                 } else {
                     false
                 }
             }
             SpanSelector::Ne(key, v) => {
-                if let Some(TagValueRef::Int64(n)) = key.get(span, parent) {
+                if let Some(TagValueRef::Int64(n)) = key.get(span, ancestors) {
                     n != *v
                 } else {
                     false
                 }
             }
             SpanSelector::Inside(key, range) => {
-                if let Some(TagValueRef::Int64(n)) = key.get(span, parent) {
+                if let Some(TagValueRef::Int64(n)) = key.get(span, ancestors) {
                     range.contains(n)
                 } else {
                     false
                 }
             }
             SpanSelector::Outside(key, range) => {
-                if let Some(TagValueRef::Int64(n)) = key.get(span, parent) {
+                if let Some(TagValueRef::Int64(n)) = key.get(span, ancestors) {
                     !range.contains(n)
                 } else {
                     false
                 }
             }
             SpanSelector::IsTrue(key) => {
-                if let Some(TagValueRef::Bool(v)) = key.get(span, parent) {
+                if let Some(TagValueRef::Bool(v)) = key.get(span, ancestors) {
                     v
                 } else {
                     false
                 }
             }
             SpanSelector::IsFalse(key) => {
-                if let Some(TagValueRef::Bool(v)) = key.get(span, parent) {
+                if let Some(TagValueRef::Bool(v)) = key.get(span, ancestors) {
                     !v
                 } else {
                     false
                 }
             }
+            SpanSelector::HasLog(field, re) => span.logs.iter().any(|log| {
+                log.field(field)
+                    .and_then(|value| value.as_str())
+                    .is_some_and(|s| re.matches(s))
+            }),
+            SpanSelector::Kind(kinds) => {
+                if let Some(TagValueRef::String(s)) = KeyName::SpanKind.get(span) {
+                    s.parse().is_ok_and(|kind| kinds.contains(kind))
+                } else {
+                    false
+                }
+            }
+        }
+    }
+}
+
+impl SpanKindSelector {
+    pub fn contains(&self, kind: SpanKind) -> bool {
+        match self {
+            SpanKindSelector::One(k) => *k == kind,
+            SpanKindSelector::AnyOf(kinds) => kinds.contains(&kind),
+        }
+    }
+
+    pub fn kinds(&self) -> BTreeSet<SpanKind> {
+        match self {
+            SpanKindSelector::One(kind) => BTreeSet::from([*kind]),
+            SpanKindSelector::AnyOf(kinds) => kinds.clone(),
+        }
+    }
+}
+
+impl SpanKind {
+    /// The value of the `span.kind` tag.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SpanKind::Client => "client",
+            SpanKind::Server => "server",
+            SpanKind::Producer => "producer",
+            SpanKind::Consumer => "consumer",
+            SpanKind::Internal => "internal",
+        }
+    }
+}
+
+impl FromStr for SpanKind {
+    type Err = UnknownSpanKind;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "client" => Ok(SpanKind::Client),
+            "server" => Ok(SpanKind::Server),
+            "producer" => Ok(SpanKind::Producer),
+            "consumer" => Ok(SpanKind::Consumer),
+            "internal" => Ok(SpanKind::Internal),
+            _ => Err(UnknownSpanKind(s.to_string())),
         }
     }
 }
 
 impl SpanKey {
-    pub fn get<'a>(&self, span: &'a Span, parent: Option<&'a Span>) -> Option<TagValueRef<'a>> {
+    pub fn get<'a>(&self, span: &'a Span, ancestors: Ancestors<'a>) -> Option<TagValueRef<'a>> {
         match self {
             SpanKey::Current(key) => key.get(span),
-            SpanKey::Parent(key) => parent.and_then(|span| key.get(span)),
+            SpanKey::Parent(key) => ancestors.parent.and_then(|span| key.get(span)),
+            SpanKey::Grandparent(key) => ancestors.grandparent.and_then(|span| key.get(span)),
+        }
+    }
+
+    /// The value of the key, resolving class keys with the span
+    /// config's classifiers. Classifiers of the parent see its parent
+    /// (the grandparent) as their only ancestor.
+    pub fn get_with<'a>(
+        &self,
+        span: &'a Span,
+        ancestors: Ancestors<'a>,
+        classifiers: &'a SpanClassifiers,
+    ) -> Option<TagValueRef<'a>> {
+        match self {
+            SpanKey::Current(key) => key.get_with(span, ancestors, classifiers),
+            SpanKey::Parent(key) => ancestors.parent.and_then(|span| {
+                let ancestors = Ancestors {
+                    parent: ancestors.grandparent,
+                    grandparent: None,
+                };
+                key.get_with(span, ancestors, classifiers)
+            }),
+            SpanKey::Grandparent(key) => ancestors
+                .grandparent
+                .and_then(|span| key.get_with(span, Ancestors::default(), classifiers)),
+        }
+    }
+
+    /// The name of the classifier the key refers to, if any.
+    pub fn classifier(&self) -> Option<&str> {
+        match self {
+            SpanKey::Current(KeyName::Class(name))
+            | SpanKey::Parent(KeyName::Class(name))
+            | SpanKey::Grandparent(KeyName::Class(name)) => Some(name),
+            SpanKey::Current(_) | SpanKey::Parent(_) | SpanKey::Grandparent(_) => None,
         }
     }
 
@@ -280,13 +759,16 @@ impl SpanKey {
         match self {
             SpanKey::Current(key) => key.label(),
             SpanKey::Parent(key) => LabelName::new(format!("parent_{}", key.label())).unwrap(),
+            SpanKey::Grandparent(key) => {
+                LabelName::new(format!("grandparent_{}", key.label())).unwrap()
+            }
         }
     }
 
     pub fn is_required(&self) -> bool {
         match self {
             SpanKey::Current(key) => key.is_required(),
-            SpanKey::Parent(_) => false,
+            SpanKey::Parent(_) | SpanKey::Grandparent(_) => false,
         }
     }
 }
@@ -308,6 +790,40 @@ impl KeyName {
                 .iter()
                 .find(|tag| &tag.key == name)
                 .map(|tag| tag.value.as_ref()),
+            KeyName::SpanKind => span
+                .tags
+                .iter()
+                .find(|tag| tag.key == SPAN_KIND_TAG)
+                .map(|tag| tag.value.as_ref()),
+            KeyName::StatusCode => Some(TagValueRef::String(status_code(span))),
+            KeyName::Baggage(name) => span
+                .tags
+                .iter()
+                .find(|tag| tag.key.strip_prefix(BAGGAGE_TAG_PREFIX) == Some(name.as_str()))
+                .map(|tag| tag.value.as_ref())
+                .or_else(|| {
+                    span.tags
+                        .iter()
+                        .find(|tag| tag.key == TRACESTATE_TAG)
+                        .and_then(|tag| tag.value.as_str())
+                        .and_then(|state| tracestate_entry(state, name))
+                        .map(TagValueRef::String)
+                }),
+            KeyName::Class(_) => None,
+        }
+    }
+
+    fn get_with<'a>(
+        &self,
+        span: &'a Span,
+        ancestors: Ancestors<'a>,
+        classifiers: &'a SpanClassifiers,
+    ) -> Option<TagValueRef<'a>> {
+        match self {
+            KeyName::Class(name) => classifiers
+                .get(name)
+                .map(|classifier| TagValueRef::String(classifier.classify(span, ancestors))),
+            _ => self.get(span),
         }
     }
 
@@ -315,21 +831,547 @@ impl KeyName {
         match self {
             KeyName::OperationName => LabelName::new("operation_name").unwrap(),
             KeyName::ServiceName => LabelName::new("service_name").unwrap(),
-            KeyName::ProcessTag(tag) | KeyName::SpanTag(tag) => LabelName::new(
-                tag.chars()
-                    .skip_while(|c| !c.is_ascii_alphabetic())
-                    .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
-                    .collect::<String>(),
-            )
-            .unwrap(),
+            KeyName::ProcessTag(tag) | KeyName::SpanTag(tag) => {
+                LabelName::new(tag_label(tag)).unwrap()
+            }
             KeyName::Duration => LabelName::new("duration").unwrap(),
+            KeyName::SpanKind => LabelName::new("span_kind").unwrap(),
+            KeyName::StatusCode => LabelName::new("status_code").unwrap(),
+            KeyName::Baggage(name) => {
+                LabelName::new(format!("baggage_{}", tag_label(name))).unwrap()
+            }
+            KeyName::Class(name) => LabelName::new(tag_label(name)).unwrap(),
         }
     }
 
     pub fn is_required(&self) -> bool {
         match self {
-            KeyName::OperationName | KeyName::ServiceName | KeyName::Duration => true,
-            KeyName::ProcessTag(_) | KeyName::SpanTag(_) => false,
+            KeyName::OperationName
+            | KeyName::ServiceName
+            | KeyName::Duration
+            | KeyName::Class(_) => true,
+            KeyName::ProcessTag(_)
+            | KeyName::SpanTag(_)
+            | KeyName::SpanKind
+            | KeyName::StatusCode
+            | KeyName::Baggage(_) => false,
+        }
+    }
+}
+
+/// A tag name as a label name: leading characters other than letters
+/// are dropped and other characters than letters and digits replaced
+/// by `_`.
+impl SpanClassifier {
+    /// The value of the first class whose selector matches the span,
+    /// or the fallback value.
+    pub fn classify<'a>(&'a self, span: &Span, ancestors: Ancestors) -> &'a str {
+        self.classes
+            .iter()
+            .find(|class| class.select.matches(span, ancestors))
+            .map_or(self.fallback.as_str(), |class| class.value.as_str())
+    }
+
+    /// Check that the fallback is set and that no value is used twice,
+    /// which would make classes indistinguishable in the labels.
+    fn validate(&self) -> Result<(), &'static str> {
+        if self.fallback.is_empty() {
+            return Err("the fallback value is empty");
+        }
+        if self.classes.iter().any(|class| class.value.is_empty()) {
+            return Err("a class value is empty");
+        }
+        let mut values = BTreeSet::new();
+        if !self
+            .classes
+            .iter()
+            .map(|class| class.value.as_str())
+            .chain([self.fallback.as_str()])
+            .all(|value| values.insert(value))
+        {
+            return Err("class values are not unique");
+        }
+        Ok(())
+    }
+}
+
+fn tag_label(tag: &str) -> String {
+    tag.chars()
+        .skip_while(|c| !c.is_ascii_alphabetic())
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+/// The value of the entry `name` in a W3C trace state: a
+/// comma-separated list of `key=value` entries. Keys may carry the
+/// vendor that set them (`name@vendor`). Malformed and empty entries
+/// are skipped; the first matching entry wins.
+fn tracestate_entry<'a>(state: &'a str, name: &str) -> Option<&'a str> {
+    state.split(',').find_map(|entry| {
+        let (key, value) = entry.split_once('=')?;
+        let key = key.trim();
+        let key = key.split_once('@').map_or(key, |(key, _)| key);
+        let value = value.trim();
+        (key == name && !value.is_empty()).then_some(value)
+    })
+}
+
+/// The normalized status of a span. OTEL exporters set the status tag
+/// (and the error tag on errors); classic Jaeger clients only set the
+/// error tag, and some instrumentations only the HTTP status code.
+fn status_code(span: &Span) -> &'static str {
+    let tag = |name: &str| {
+        span.tags
+            .iter()
+            .find(|tag| tag.key == name)
+            .map(|tag| tag.value.as_ref())
+    };
+    let [otel_tag, error_tag, http_tag] = STATUS_CODE_TAGS;
+    let otel = || match tag(otel_tag)? {
+        TagValueRef::String(s) if s.eq_ignore_ascii_case("ok") => Some(STATUS_OK),
+        TagValueRef::String(s) if s.eq_ignore_ascii_case("error") => Some(STATUS_ERROR),
+        TagValueRef::String(s) if s.eq_ignore_ascii_case("unset") => Some(STATUS_UNSET),
+        TagValueRef::Int64(0) => Some(STATUS_UNSET),
+        TagValueRef::Int64(1) => Some(STATUS_OK),
+        TagValueRef::Int64(2) => Some(STATUS_ERROR),
+        _ => None,
+    };
+    let error = || match tag(error_tag)? {
+        TagValueRef::Bool(true) => Some(STATUS_ERROR),
+        TagValueRef::String(s) if s.eq_ignore_ascii_case("true") => Some(STATUS_ERROR),
+        _ => None,
+    };
+    let http = || {
+        let code = match tag(http_tag)? {
+            TagValueRef::Int64(n) => n,
+            TagValueRef::String(s) => s.parse().ok()?,
+            TagValueRef::Bool(_) => return None,
+        };
+        Some(if (200..=299).contains(&code) {
+            STATUS_UNSET
+        } else {
+            STATUS_ERROR
+        })
+    };
+    otel().or_else(error).or_else(http).unwrap_or(STATUS_UNSET)
+}
+
+impl Config {
+    /// Deep-merge a partial config into this one, following JSON merge
+    /// patch semantics (RFC 7386): objects (including maps) are merged
+    /// by key, `null` deletes a key and all other values (including
+    /// arrays) replace the current value. Since enums are represented
+    /// as objects, replacing an enum variant requires setting the old
+    /// variant to `null`.
+    pub fn merge(&self, patch: serde_json::Value) -> Result<Self, ConfigError> {
+        let mut value = serde_json::to_value(self).map_err(ConfigError::Serialize)?;
+        merge_json(&mut value, patch);
+        let config = serde_json::from_value::<Self>(value).map_err(ConfigError::Deserialize)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// The time between samples.
+    pub fn sample_interval(&self) -> Duration {
+        self.sample_interval.unwrap_or(self.query_interval)
+    }
+
+    /// Check the config for errors. Configs with warnings (see
+    /// `warnings`) are accepted, and the warnings are logged.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        self.warnings()
+            .into_iter()
+            .for_each(|warning| log::warn!("{warning}"));
+        let sample_seconds = self.sample_interval().to_time_delta().num_seconds();
+        if sample_seconds == 0
+            || self.query_interval.to_time_delta().num_seconds() % sample_seconds != 0
+        {
+            return Err(ConfigError::SampleInterval(
+                self.sample_interval(),
+                self.query_interval,
+            ));
+        }
+        let reserved = TraceConfig::trace_metrics_config_name();
+        if self.trace.configs.contains_key(&reserved) {
+            return Err(ConfigError::ReservedConfig(reserved));
+        }
+        if let Some(name) = self
+            .trace
+            .configs
+            .values()
+            .flat_map(|config| config.metrics.keys())
+            .chain(self.trace.trace_metrics.metrics.keys())
+            .find(|name| !is_valid_metric_name(&format!("trace_{name}")))
+        {
+            return Err(ConfigError::InvalidMetricName(name.clone()));
+        }
+        if let Some((name, q_stat)) = self
+            .trace
+            .configs
+            .values()
+            .flat_map(|config| {
+                config
+                    .metrics
+                    .iter()
+                    .map(|(name, metric)| (name, &metric.stats))
+            })
+            .chain(
+                self.trace
+                    .trace_metrics
+                    .metrics
+                    .iter()
+                    .map(|(name, metric)| (name, &metric.stats)),
+            )
+            .find_map(|(name, stats)| {
+                let q_stat = stats.anomaly_score.as_ref()?.q_stat()?;
+                (q_stat <= 0.0 || q_stat >= 1.0).then_some((name, q_stat))
+            })
+        {
+            return Err(ConfigError::InvalidQStat(name.clone(), q_stat));
+        }
+        if let Some((name, e)) = self.trace.pushdown_queries().find_map(Result::err) {
+            return Err(ConfigError::Pushdown(name, e));
+        }
+        if let Some((name, label)) = self.trace.configs.iter().find_map(|(name, config)| {
+            let key = config
+                .key
+                .iter()
+                .map(|key| key.label().into_string())
+                .collect::<BTreeSet<_>>();
+            config
+                .annotations
+                .iter()
+                .map(|key| key.label().into_string())
+                .find(|label| key.contains(label))
+                .map(|label| (name, label))
+        }) {
+            return Err(ConfigError::AnnotationInKey(name.clone(), label));
+        }
+        if let Some((name, key)) = self.trace.configs.iter().find_map(|(name, config)| {
+            config
+                .pseudonymize
+                .iter()
+                .find(|p| !config.key.contains(&p.key) && !config.annotations.contains(&p.key))
+                .map(|p| (name, p.key.label().into_string()))
+        }) {
+            return Err(ConfigError::UnusedPseudonymization(name.clone(), key));
+        }
+        if let Some((name, classifier, e)) = self.trace.configs.iter().find_map(|(name, config)| {
+            config
+                .classify
+                .iter()
+                .find_map(|(classifier, c)| Some((name, classifier, c.validate().err()?)))
+        }) {
+            return Err(ConfigError::InvalidClassifier(
+                name.clone(),
+                classifier.clone(),
+                e,
+            ));
+        }
+        if let Some((name, classifier)) = self
+            .trace
+            .configs
+            .iter()
+            .find_map(|(name, config)| {
+                config
+                    .key
+                    .iter()
+                    .chain(&config.carry_over)
+                    .chain(&config.annotations)
+                    .chain(
+                        config
+                            .metrics
+                            .values()
+                            .filter_map(|metric| metric.source.by()),
+                    )
+                    .filter_map(SpanKey::classifier)
+                    .find(|classifier| !config.classify.contains_key(*classifier))
+                    .map(|classifier| (name.clone(), classifier))
+            })
+            .or_else(|| {
+                // Trace-level metrics have no classifiers.
+                let classifier = self
+                    .trace
+                    .trace_metrics
+                    .key
+                    .iter()
+                    .find_map(SpanKey::classifier)?;
+                Some((TraceConfig::trace_metrics_config_name(), classifier))
+            })
+        {
+            return Err(ConfigError::UnknownClassifier(name, classifier.to_string()));
+        }
+        if let Some((i, e)) = self
+            .trace
+            .maintenance
+            .iter()
+            .enumerate()
+            .find_map(|(i, window)| Some((i, window.validate().err()?)))
+        {
+            return Err(ConfigError::InvalidMaintenance(i, e));
+        }
+        if let Some((name, e)) = self
+            .cached_queries
+            .iter()
+            .find_map(|query| Some((&query.name, query.expr.validate().err()?)))
+        {
+            return Err(ConfigError::InvalidCachedQuery(name.clone(), e));
+        }
+        if let Some((name, values)) = self
+            .trace
+            .configs
+            .iter()
+            .flat_map(|(name, config)| {
+                config
+                    .selectors()
+                    .map(move |selector| (Some(name), selector))
+            })
+            .chain(
+                self.trace
+                    .rules
+                    .iter()
+                    .flatten()
+                    .map(|rule| (None, &rule.select)),
+            )
+            .map(|(name, selector)| (name, selector.max_values()))
+            .find(|(_, values)| *values > self.max_selector_values)
+        {
+            return Err(match name {
+                Some(name) => ConfigError::ConfigSelectorValues(
+                    name.clone(),
+                    values,
+                    self.max_selector_values,
+                ),
+                None => ConfigError::RuleSelectorValues(values, self.max_selector_values),
+            });
+        }
+        if !self.ingest_logs {
+            if let Some(name) = self.trace.configs.iter().find_map(|(name, config)| {
+                (config
+                    .metrics
+                    .values()
+                    .any(|metric| metric.source.reads_logs())
+                    || config.selectors().any(SpanSelector::reads_logs))
+                .then_some(name)
+            }) {
+                return Err(ConfigError::LogsNotIngested(name.clone()));
+            }
+            if self
+                .trace
+                .rules
+                .iter()
+                .flatten()
+                .any(|rule| rule.select.reads_logs())
+            {
+                return Err(ConfigError::RuleLogsNotIngested);
+            }
+        }
+        self.trace
+            .rules
+            .iter()
+            .flatten()
+            .find(|rule| !self.trace.configs.contains_key(&rule.config))
+            .map_or(Ok(()), |rule| {
+                Err(ConfigError::UnknownConfig(rule.config.clone()))
+            })
+    }
+}
+
+impl Config {
+    /// Issues that do not make the config invalid, but probably do
+    /// not do what was intended: rule groups relying on the order of
+    /// rules with equal priority, and cached queries reading anomaly
+    /// score windows that are not computed.
+    pub fn warnings(&self) -> Vec<String> {
+        self.trace
+            .equal_rule_priorities()
+            .into_iter()
+            .map(|(group, priority)| {
+                format!(
+                    "rule group {group} has multiple rules with priority {priority}; \
+                     these are evaluated in order of appearance"
+                )
+            })
+            .chain(self.interval_warnings())
+            .collect()
+    }
+
+    /// Cross-check the anomaly score windows computed per metric with
+    /// the windows read by the cached queries. The intervals are the
+    /// lib's interval types on both sides, so every computed window
+    /// can be expressed; but a query can read a window that is not
+    /// computed for its metric, and a metric without immediate or
+    /// without reference windows yields no scores. Immediate windows
+    /// are also checked against the sample interval: samples should
+    /// fall on every bin boundary.
+    fn interval_warnings(&self) -> impl Iterator<Item = String> + '_ {
+        let metrics = self.trace.configs.iter().flat_map(|(config_name, config)| {
+            config.metrics.iter().filter_map(move |(name, metric)| {
+                let anomaly_score = metric.stats.anomaly_score.as_ref()?;
+                (!anomaly_score.computes_scores()).then(|| {
+                    format!(
+                        "metric {name} of config {config_name} computes no anomaly scores; \
+                         it needs both immediate and reference intervals"
+                    )
+                })
+            })
+        });
+        let sample_interval = self.sample_interval();
+        let sample_seconds = sample_interval.to_time_delta().num_seconds();
+        let bins = self
+            .trace
+            .configs
+            .iter()
+            .flat_map(move |(config_name, config)| {
+                config.metrics.iter().flat_map(move |(name, metric)| {
+                    metric
+                        .stats
+                        .anomaly_score
+                        .iter()
+                        .flat_map(|anomaly_score| anomaly_score.immediate_intervals())
+                        .filter(move |interval| {
+                            let bin_width = interval.window_config().bin_width.to_time_delta();
+                            sample_seconds > 0 && bin_width.num_seconds() % sample_seconds != 0
+                        })
+                        .map(move |interval| {
+                            format!(
+                                "the sample interval {sample_interval} does not divide the {} \
+                                 bins of the {interval} window of metric {name} of config \
+                                 {config_name}",
+                                interval.window_config().bin_width
+                            )
+                        })
+                })
+            });
+        let queries = self.cached_queries.iter().flat_map(|query| {
+            let metric = query.expr.metric();
+            let config_name = query.expr.aggr().config_name();
+            let stats = self
+                .trace
+                .configs
+                .get(&ConfigName::new(config_name))
+                .and_then(|config| config.metrics.get(&MetricName::new(metric.to_string())))
+                .map(|metric| &metric.stats);
+            let name = &query.name;
+            match stats.map(|stats| stats.anomaly_score.as_ref()) {
+                None => vec![format!(
+                    "cached query {name} reads metric {metric}, which config {config_name} \
+                     does not compute"
+                )],
+                Some(None) => vec![format!(
+                    "cached query {name} reads the anomaly score windows of metric {metric}, \
+                     which config {config_name} does not compute"
+                )],
+                Some(Some(anomaly_score)) => query
+                    .expr
+                    .aggr()
+                    .intervals()
+                    .into_iter()
+                    .filter(|interval| !anomaly_score.computes(*interval))
+                    .map(|interval| {
+                        format!(
+                            "cached query {name} reads the {interval} window of metric \
+                             {metric}, which config {config_name} does not compute"
+                        )
+                    })
+                    .collect(),
+            }
+        });
+        metrics.chain(bins).chain(queries)
+    }
+}
+
+fn merge_json(target: &mut serde_json::Value, patch: serde_json::Value) {
+    match patch {
+        serde_json::Value::Object(patch) => {
+            if !target.is_object() {
+                *target = serde_json::Value::Object(serde_json::Map::new());
+            }
+            if let serde_json::Value::Object(target) = target {
+                patch.into_iter().for_each(|(key, value)| {
+                    if value.is_null() {
+                        target.remove(&key);
+                    } else {
+                        merge_json(target.entry(key).or_insert(serde_json::Value::Null), value);
+                    }
+                });
+            }
+        }
+        patch => *target = patch,
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ConfigError {
+    #[error("failed to serialize config: {0}")]
+    Serialize(serde_json::Error),
+    #[error("invalid config: {0}")]
+    Deserialize(serde_json::Error),
+    #[error("rule refers to unknown config: {0}")]
+    UnknownConfig(ConfigName),
+    #[error("config name is reserved for trace-level metrics: {0}")]
+    ReservedConfig(ConfigName),
+    #[error("invalid metric name: {0}")]
+    InvalidMetricName(MetricName),
+    #[error("q_stat for metric {0} must be between 0 and 1 (exclusive): {1}")]
+    InvalidQStat(MetricName, f64),
+    #[error("config {0} does not support aggregation pushdown: {1}")]
+    Pushdown(ConfigName, PushdownError),
+    #[error("annotation label {1} of config {0} is also a key label")]
+    AnnotationInKey(ConfigName, String),
+    #[error("config {0} pseudonymizes {1}, which is neither a key nor an annotation")]
+    UnusedPseudonymization(ConfigName, String),
+    #[error("invalid classifier {1} of config {0}: {2}")]
+    InvalidClassifier(ConfigName, String, &'static str),
+    #[error("config {0} refers to unknown classifier: {1}")]
+    UnknownClassifier(ConfigName, String),
+    #[error("invalid maintenance window {0}: {1}")]
+    InvalidMaintenance(usize, &'static str),
+    #[error("sample interval {0} does not divide the query interval {1}")]
+    SampleInterval(Duration, Duration),
+    #[error("invalid cached query {0}: {1}")]
+    InvalidCachedQuery(String, TraceAggrError),
+    #[error("a selector of config {0} has {1} values, more than the maximum of {2}")]
+    ConfigSelectorValues(ConfigName, usize, usize),
+    #[error("a rule selector has {0} values, more than the maximum of {1}")]
+    RuleSelectorValues(usize, usize),
+    #[error("config {0} reads span logs, but ingest_logs is disabled")]
+    LogsNotIngested(ConfigName),
+    #[error("a rule selector reads span logs, but ingest_logs is disabled")]
+    RuleLogsNotIngested,
+}
+
+impl IngestFilter {
+    pub fn is_empty(&self) -> bool {
+        self.include_services.is_empty()
+            && self.exclude_services.is_empty()
+            && self.include_namespaces.is_empty()
+            && self.exclude_namespaces.is_empty()
+    }
+
+    pub(crate) fn matches(&self, span: &Span) -> bool {
+        let service = span.process.service_name.0.as_str();
+        let namespace = span
+            .process
+            .tags
+            .iter()
+            .find(|tag| tag.key == SERVICE_NAMESPACE_TAG)
+            .and_then(|tag| tag.value.as_str());
+        (self.include_services.is_empty()
+            || self.include_services.iter().any(|m| m.matches(service)))
+            && !self.exclude_services.iter().any(|m| m.matches(service))
+            && (self.include_namespaces.is_empty()
+                || namespace
+                    .is_some_and(|ns| self.include_namespaces.iter().any(|m| m.matches(ns))))
+            && !namespace.is_some_and(|ns| self.exclude_namespaces.iter().any(|m| m.matches(ns)))
+    }
+}
+
+impl ValueMatch {
+    pub fn matches(&self, s: &str) -> bool {
+        match self {
+            ValueMatch::Eq(value) => value == s,
+            ValueMatch::Match(re) => re.matches(s),
         }
     }
 }
@@ -364,22 +1406,457 @@ impl Default for Config {
         Self {
             trace: TraceConfig::default(),
             query_interval: Duration::Seconds(30),
+            sample_interval: None,
             max_history: Duration::Hours(1),
             delay: Duration::Minutes(2),
+            ingest_filter: IngestFilter::default(),
+            ingest_logs: false,
+            cached_queries: Vec::new(),
+            max_series: None,
+            prune_tags: true,
+            max_selector_values: 10_000,
         }
     }
 }
 
 #[cfg(test)]
 mod test {
+    use std::collections::BTreeSet;
+
+    use jaeger_anomaly_detection::{
+        Duration, ImmediateInterval, NoCombine, OperationKey, ReferenceInterval, ServiceKey,
+        TraceAggr, TraceExpr, TraceMetric, TraceObject,
+    };
     use serde_json::json;
 
-    use super::{KeyName, LowerBound, Range, Regex, SpanSelector, UpperBound};
-    use crate::{config::SpanKey, jaeger::Span};
+    use super::{
+        tracestate_entry, Ancestors, AnchoredRegex, CachedQuery, Config, ConfigError, ConfigName,
+        KeyName, LowerBound, MetricName, Range, Regex, SpanClass, SpanClassifier, SpanClassifiers,
+        SpanKind, SpanKindSelector, SpanSelector, UpperBound, ValueSet,
+    };
+    use chrono::DateTime;
+
+    use crate::{
+        config::SpanKey,
+        jaeger::{Span, Tag, TagValue, TagValueRef},
+        processor::{
+            pushdown::PushdownError,
+            sim::span,
+            source::{MetricSource, SourceProcessor},
+        },
+    };
 
     #[test]
-    fn match_error() {
-        let span = serde_json::from_value::<Span>(json!({
+    fn merge_add_metric() {
+        let config = Config::default()
+            .merge(json!({
+                "configs": {
+                    "default": {
+                        "metrics": {
+                            "self_time": {
+                                "source": "self_duration",
+                                "stats": {}
+                            }
+                        }
+                    }
+                }
+            }))
+            .unwrap();
+        let metrics = &config.trace.configs[&ConfigName::new("default")].metrics;
+        assert!(metrics.contains_key(&MetricName::new("self_time")));
+        assert!(metrics.contains_key(&MetricName::new("busy")));
+        assert_eq!(metrics.len(), 5);
+    }
+
+    #[test]
+    fn merge_delete_metric() {
+        let config = Config::default()
+            .merge(json!({
+                "configs": {
+                    "default": {
+                        "metrics": {
+                            "busy": null
+                        }
+                    }
+                }
+            }))
+            .unwrap();
+        let metrics = &config.trace.configs[&ConfigName::new("default")].metrics;
+        assert!(!metrics.contains_key(&MetricName::new("busy")));
+        assert!(metrics.contains_key(&MetricName::new("duration")));
+        assert_eq!(
+            config.trace.configs.len(),
+            Config::default().trace.configs.len()
+        );
+    }
+
+    #[test]
+    fn merge_replace_rules() {
+        let config = Config::default()
+            .merge(json!({
+                "rules": [[{ "select": { "all": [] }, "config": "default" }]]
+            }))
+            .unwrap();
+        assert_eq!(config.trace.rules.len(), 1);
+        assert_eq!(config.trace.rules[0].len(), 1);
+        assert_eq!(config.trace.rules[0][0].config, ConfigName::new("default"));
+        assert_eq!(config.trace.configs, Config::default().trace.configs);
+    }
+
+    #[test]
+    fn merge_rejects_unknown_config() {
+        assert!(Config::default()
+            .merge(json!({ "configs": { "service-relations": null } }))
+            .is_err());
+    }
+
+    #[test]
+    fn reject_invalid_metric_name() {
+        let err = Config::default()
+            .merge(json!({
+                "configs": {
+                    "default": {
+                        "metrics": {
+                            "error rate": {
+                                "source": "duration",
+                                "stats": {}
+                            }
+                        }
+                    }
+                }
+            }))
+            .unwrap_err();
+        assert!(
+            matches!(&err, ConfigError::InvalidMetricName(name) if name == &MetricName::new("error rate")),
+            "{err}"
+        );
+
+        // Configs posted as a whole are validated the same way.
+        let mut config = Config::default();
+        let metrics = &mut config.trace.trace_metrics.metrics;
+        let metric = metrics.values().next().unwrap().clone();
+        metrics.insert(MetricName::new("p99.9"), metric);
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::InvalidMetricName(_))
+        ));
+        assert!(Config::default().validate().is_ok());
+    }
+
+    #[test]
+    fn reject_invalid_q_stat() {
+        let quantile = |q_stat: f64| {
+            Config::default().merge(json!({
+                "configs": {
+                    "default": {
+                        "metrics": {
+                            "duration": {
+                                "stats": {
+                                    "anomaly_score": {
+                                        "algorithm": { "quantile": { "q_stat": q_stat } }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }))
+        };
+        assert!(quantile(0.95).is_ok());
+        assert!(matches!(
+            quantile(1.0),
+            Err(ConfigError::InvalidQStat(name, q)) if name == MetricName::new("duration") && q == 1.0
+        ));
+        assert!(matches!(
+            quantile(0.0),
+            Err(ConfigError::InvalidQStat(_, _))
+        ));
+    }
+
+    #[test]
+    fn reject_unsupported_pushdown() {
+        // The default config is keyed on process tags.
+        assert!(matches!(
+            Config::default().merge(json!({ "configs": { "default": { "pushdown": true } } })),
+            Err(ConfigError::Pushdown(name, PushdownError::Key(_))) if name == ConfigName::new("default")
+        ));
+    }
+
+    #[test]
+    fn reject_annotation_in_key() {
+        assert!(matches!(
+            Config::default().merge(json!({ "configs": { "default": {
+                "annotations": [{ "current": "service_name" }]
+            } } })),
+            Err(ConfigError::AnnotationInKey(name, label))
+                if name == ConfigName::new("default") && label == "service_name"
+        ));
+        assert!(Config::default()
+            .merge(json!({ "configs": { "default": {
+                "annotations": [{ "current": { "process_tag": "k8s.node.name" } }]
+            } } }))
+            .is_ok());
+    }
+
+    #[test]
+    fn reject_unused_pseudonymization() {
+        assert!(matches!(
+            Config::default().merge(json!({ "configs": { "default": {
+                "pseudonymize": [{ "key": { "current": { "span_tag": "user.id" } } }]
+            } } })),
+            Err(ConfigError::UnusedPseudonymization(name, label))
+                if name == ConfigName::new("default") && label == "user_id"
+        ));
+        assert!(Config::default()
+            .merge(json!({ "configs": { "default": {
+                "pseudonymize": [{
+                    "key": { "current": "operation_name" },
+                    "keep": ["GET /health"]
+                }]
+            } } }))
+            .is_ok());
+    }
+
+    #[test]
+    fn reject_complex_regex() {
+        let repeated = "(?:\\w{100}){100}";
+        let nested = format!("{}a{}", "(".repeat(40), ")".repeat(40));
+        assert!(Regex::new(repeated).is_err());
+        assert!(Regex::new(&nested).is_err());
+        assert!(AnchoredRegex::new(repeated).is_err());
+        assert!(serde_json::from_value::<Regex>(json!(repeated)).is_err());
+        assert!(Regex::new("^(?:GET|POST) /api/.*$").is_ok());
+        assert!(AnchoredRegex::new("[0-9a-f]{32}").is_ok());
+
+        // Long patterns are rejected before they are parsed.
+        let long = "a|".repeat(3000);
+        assert!(Regex::new(&long).is_err());
+        assert!(serde_json::from_value::<Regex>(json!(long)).is_err());
+    }
+
+    #[test]
+    fn reject_large_value_sets() {
+        let values = |n: usize| (0..n).map(|i| format!("service-{i}")).collect::<ValueSet>();
+        let mut config = Config::default();
+        config.trace.rules[0][0].select = SpanSelector::Any(vec![
+            SpanSelector::Has(SpanKey::Current(KeyName::OperationName)),
+            SpanSelector::NotIn(SpanKey::Current(KeyName::ServiceName), values(10_001)),
+        ]);
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::RuleSelectorValues(10_001, 10_000))
+        ));
+        config.max_selector_values = 20_000;
+        config.validate().unwrap();
+
+        let mut config = Config::default();
+        let (name, span_config) = config.trace.configs.iter_mut().next().unwrap();
+        let name = name.clone();
+        span_config.classify.insert(
+            String::from("tier"),
+            SpanClassifier {
+                classes: vec![SpanClass {
+                    value: String::from("known"),
+                    select: SpanSelector::In(SpanKey::Current(KeyName::ServiceName), values(101)),
+                }],
+                fallback: String::from("other"),
+            },
+        );
+        config.max_selector_values = 100;
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::ConfigSelectorValues(n, 101, 100)) if n == name
+        ));
+    }
+
+    /// Large sets are looked up in a hash set, with the same results.
+    #[test]
+    fn index_large_value_sets() {
+        let services = (0..5000)
+            .map(|i| format!("service-{i}"))
+            .collect::<BTreeSet<_>>();
+        let mut selector = SpanSelector::Not(Box::new(SpanSelector::In(
+            SpanKey::Current(KeyName::ServiceName),
+            services.clone().into(),
+        )));
+        let plain = selector.clone();
+        selector.index();
+        let SpanSelector::Not(inner) = &selector else {
+            panic!("unexpected selector: {selector}");
+        };
+        let SpanSelector::In(_, values) = inner.as_ref() else {
+            panic!("unexpected selector: {inner}");
+        };
+        assert!(values.is_indexed());
+        assert_eq!(selector, plain);
+
+        (4990..5010).for_each(|i| {
+            let span = span("t", "1", None, &format!("service-{i}"), "GET", 0, 1000);
+            assert_eq!(
+                selector.matches(&span, Ancestors::default()),
+                plain.matches(&span, Ancestors::default()),
+            );
+            assert_eq!(
+                selector.matches(&span, Ancestors::default()),
+                !services.contains(&format!("service-{i}"))
+            );
+        });
+
+        let mut small = SpanSelector::In(
+            SpanKey::Current(KeyName::ServiceName),
+            BTreeSet::from([String::from("frontend")]).into(),
+        );
+        small.index();
+        let SpanSelector::In(_, values) = &small else {
+            panic!("unexpected selector: {small}");
+        };
+        assert!(!values.is_indexed());
+    }
+
+    #[test]
+    fn sample_interval() {
+        let config = Config {
+            query_interval: Duration::Minutes(2),
+            sample_interval: Some(Duration::Seconds(30)),
+            ..Config::default()
+        };
+        assert!(config.validate().is_ok());
+        assert_eq!(config.warnings(), Vec::<String>::new());
+
+        let config = Config {
+            sample_interval: Some(Duration::Seconds(45)),
+            ..config
+        };
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::SampleInterval(
+                Duration::Seconds(45),
+                Duration::Minutes(2)
+            ))
+        ));
+
+        // Sampling every minute skips every other immediate window bin.
+        let config = Config {
+            sample_interval: Some(Duration::Minutes(1)),
+            ..config
+        };
+        assert!(config.validate().is_ok());
+        let warnings = config.warnings();
+        assert!(!warnings.is_empty());
+        assert!(
+            warnings
+                .iter()
+                .all(|warning| warning.starts_with("the sample interval 1m does not divide")),
+            "{warnings:?}"
+        );
+    }
+
+    #[test]
+    fn interval_warnings() {
+        assert_eq!(Config::default().warnings(), Vec::<String>::new());
+
+        // The default config computes the 7d reference windows for
+        // the duration only.
+        let default = Config::default();
+        let computes = |metric: &str, interval: ReferenceInterval| {
+            default.trace.configs[&ConfigName::new("default")].metrics[&MetricName::new(metric)]
+                .stats
+                .anomaly_score
+                .as_ref()
+                .unwrap()
+                .computes(interval.into())
+        };
+        assert!(computes("duration", ReferenceInterval::R7d));
+        assert!(!computes("call_rate", ReferenceInterval::R7d));
+        assert!(!computes("error_rate", ReferenceInterval::R7d));
+        assert!(computes("error_rate", ReferenceInterval::R30d));
+
+        let operation = TraceObject::<NoCombine>::builder()
+            .operation()
+            .single()
+            .item(OperationKey::new(ServiceKey::new("frontend"), "GET"));
+        let query = |name: &str, expr| CachedQuery {
+            name: String::from(name),
+            expr,
+            refresh: Duration::Minutes(1),
+        };
+        let config = Config {
+            cached_queries: vec![
+                query(
+                    "durations",
+                    TraceExpr::new(
+                        TraceMetric::Duration,
+                        TraceAggr::sufficiency(
+                            ImmediateInterval::I5m,
+                            ReferenceInterval::R7d,
+                            operation.clone(),
+                        ),
+                    ),
+                ),
+                query(
+                    "errors",
+                    TraceExpr::new(
+                        TraceMetric::ErrorRate,
+                        TraceAggr::mean(ReferenceInterval::R7d, operation.clone()),
+                    ),
+                ),
+                query(
+                    "custom",
+                    TraceExpr::new(
+                        TraceMetric::Busy,
+                        TraceAggr::count(ImmediateInterval::I5m, operation.config("custom")),
+                    ),
+                ),
+            ],
+            ..Config::default()
+        };
+        let warnings = config.warnings();
+        assert_eq!(warnings.len(), 2, "{warnings:?}");
+        assert!(warnings[0].starts_with("cached query errors reads the 7d window"));
+        assert!(warnings[1].starts_with("cached query custom"));
+        assert!(config.validate().is_ok());
+
+        // A comparison must look back past the window it compares.
+        let config = Config {
+            cached_queries: vec![query(
+                "week-over-week",
+                TraceExpr::new(
+                    TraceMetric::Duration,
+                    TraceAggr::mean_offset(
+                        ReferenceInterval::R7d,
+                        Duration::Days(1),
+                        CompareOp::Ratio,
+                        operation.clone(),
+                    ),
+                ),
+            )],
+            ..Config::default()
+        };
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::InvalidCachedQuery(name, _)) if name == "week-over-week"
+        ));
+
+        let config = Config::default()
+            .merge(json!({
+                "configs": {
+                    "default": {
+                        "metrics": {
+                            "duration": {
+                                "stats": { "anomaly_score": { "reference_intervals": [] } }
+                            }
+                        }
+                    }
+                }
+            }))
+            .unwrap();
+        assert_eq!(config.warnings().len(), 1);
+        assert!(config.warnings()[0].contains("computes no anomaly scores"));
+    }
+
+    /// A sample span, with a log entry.
+    fn sample_span() -> Span {
+        serde_json::from_value::<Span>(json!({
         "traceID": "0de61f1de7ee678bccb46f3dab804867",
         "spanID": "672633d1537fb110",
         "operationName": "GET",
@@ -566,7 +2043,12 @@ mod test {
               "value": "tenant-mdp"
             }
           ]
-        }})).unwrap();
+        }})).unwrap()
+    }
+
+    #[test]
+    fn match_error() {
+        let span = sample_span();
 
         let selector = SpanSelector::Any(vec![
             SpanSelector::Inside(
@@ -582,6 +2064,430 @@ mod test {
             ),
         ]);
 
-        assert!(selector.matches(&span, None));
+        assert!(selector.matches(&span, Ancestors::default()));
+    }
+
+    #[test]
+    fn match_log() {
+        let span = sample_span();
+        let has_log = |field: &str, re: &str| {
+            SpanSelector::HasLog(String::from(field), Regex::new(re).unwrap())
+                .matches(&span, Ancestors::default())
+        };
+        assert!(has_log("level", "^DEBUG$"));
+        assert!(has_log("event", "idle connection"));
+        assert!(!has_log("level", "^ERROR$"));
+        assert!(!has_log("message", ".*"));
+    }
+
+    #[test]
+    fn grandparent_key() {
+        let frontend = span("t", "1", None, "frontend", "GET", 0, 3000);
+        let backend = span("t", "2", Some("1"), "backend", "POST", 500, 2000);
+        let database = span("t", "3", Some("2"), "database", "SELECT", 1000, 500);
+        let ancestors = Ancestors {
+            parent: Some(&backend),
+            grandparent: Some(&frontend),
+        };
+
+        let key = SpanKey::Grandparent(KeyName::ServiceName);
+        assert_eq!(key.label().into_string(), "grandparent_service_name");
+        assert!(!key.is_required());
+        assert!(key.get(&database, ancestors) == Some(TagValueRef::String("frontend")));
+        assert!(
+            SpanKey::Parent(KeyName::ServiceName).get(&database, ancestors)
+                == Some(TagValueRef::String("backend"))
+        );
+
+        let selector = SpanSelector::In(
+            key.clone(),
+            BTreeSet::from([String::from("frontend")]).into(),
+        );
+        assert!(selector.matches(&database, ancestors));
+        assert!(!selector.matches(
+            &backend,
+            Ancestors {
+                parent: Some(&frontend),
+                grandparent: None,
+            }
+        ));
+        assert!(!SpanSelector::Has(key).matches(&frontend, Ancestors::default()));
+    }
+
+    #[test]
+    fn match_span_kind() {
+        let client = sample_span();
+        let missing = span("t", "1", None, "frontend", "GET", 0, 3000);
+        let matches = |selector: &str, span: &Span| {
+            serde_json::from_value::<SpanSelector>(json!({ "kind": selector }))
+                .unwrap()
+                .matches(span, Ancestors::default())
+        };
+        assert!(matches("client", &client));
+        assert!(!matches("server", &client));
+        assert!(!matches("client", &missing));
+
+        let any_of = |kinds: &[SpanKind]| {
+            SpanSelector::Kind(SpanKindSelector::AnyOf(kinds.iter().copied().collect()))
+        };
+        assert!(
+            any_of(&[SpanKind::Server, SpanKind::Client]).matches(&client, Ancestors::default())
+        );
+        assert!(
+            !any_of(&[SpanKind::Producer, SpanKind::Consumer, SpanKind::Internal])
+                .matches(&client, Ancestors::default())
+        );
+        assert!(!any_of(&[]).matches(&client, Ancestors::default()));
+        assert_eq!(
+            serde_json::from_value::<SpanSelector>(json!({ "kind": ["server", "client"] }))
+                .unwrap(),
+            any_of(&[SpanKind::Client, SpanKind::Server])
+        );
+    }
+
+    #[test]
+    fn span_kind_key() {
+        let key = SpanKey::Current(KeyName::SpanKind);
+        assert_eq!(key.label().into_string(), "span_kind");
+        assert!(!key.is_required());
+        assert!(
+            key.get(&sample_span(), Ancestors::default()) == Some(TagValueRef::String("client"))
+        );
+        let missing = span("t", "1", None, "frontend", "GET", 0, 3000);
+        assert!(key.get(&missing, Ancestors::default()).is_none());
+        assert_eq!(
+            SpanKey::Parent(KeyName::SpanKind).label().into_string(),
+            "parent_span_kind"
+        );
+    }
+
+    #[test]
+    fn status_code_key() {
+        let status = |tags: serde_json::Value| {
+            let mut span = span("t", "1", None, "frontend", "GET", 0, 3000);
+            span.tags = serde_json::from_value(tags).unwrap();
+            let key = SpanKey::Current(KeyName::StatusCode);
+            match key.get(&span, Ancestors::default()) {
+                Some(TagValueRef::String(s)) => s.to_string(),
+                _ => panic!("status is not a string"),
+            }
+        };
+
+        // OpenTelemetry rust, through the Jaeger OTLP receiver.
+        assert_eq!(
+            status(json!([
+                { "key": "otel.status_code", "type": "string", "value": "ERROR" },
+                { "key": "otel.status_description", "type": "string", "value": "timeout" },
+                { "key": "error", "type": "bool", "value": "true" }
+            ])),
+            "ERROR"
+        );
+        assert_eq!(
+            status(json!([{ "key": "otel.status_code", "type": "string", "value": "OK" }])),
+            "OK"
+        );
+        // OpenTelemetry js. The status takes precedence over the HTTP
+        // status code.
+        assert_eq!(
+            status(json!([
+                { "key": "otel.status_code", "type": "string", "value": "ERROR" },
+                { "key": "http.status_code", "type": "int64", "value": "200" }
+            ])),
+            "ERROR"
+        );
+        assert_eq!(
+            status(json!([{ "key": "otel.status_code", "type": "int64", "value": "2" }])),
+            "ERROR"
+        );
+        // Classic Jaeger clients.
+        assert_eq!(
+            status(json!([{ "key": "error", "type": "bool", "value": "true" }])),
+            "ERROR"
+        );
+        assert_eq!(
+            status(json!([{ "key": "error", "type": "bool", "value": "false" }])),
+            "UNSET"
+        );
+        // HTTP status codes only.
+        assert_eq!(
+            status(json!([{ "key": "http.status_code", "type": "string", "value": "503" }])),
+            "ERROR"
+        );
+        assert_eq!(
+            status(json!([{ "key": "http.status_code", "type": "int64", "value": "204" }])),
+            "UNSET"
+        );
+        assert_eq!(status(json!([])), "UNSET");
+
+        assert_eq!(
+            serde_json::from_value::<SpanKey>(json!({ "current": "status_code" })).unwrap(),
+            SpanKey::Current(KeyName::StatusCode)
+        );
+        assert_eq!(
+            SpanKey::Current(KeyName::StatusCode).label().into_string(),
+            "status_code"
+        );
+    }
+
+    #[test]
+    fn tracestate_entries() {
+        let state = "congo=t61rcWkgMzE, tenant@rojo=acme ,area=checkout";
+        assert_eq!(tracestate_entry(state, "congo"), Some("t61rcWkgMzE"));
+        assert_eq!(tracestate_entry(state, "tenant"), Some("acme"));
+        assert_eq!(tracestate_entry(state, "area"), Some("checkout"));
+        assert_eq!(tracestate_entry(state, "rojo"), None);
+        assert_eq!(tracestate_entry(state, "missing"), None);
+        assert_eq!(tracestate_entry("", "tenant"), None);
+
+        // Values may contain '='; the first matching entry wins.
+        assert_eq!(
+            tracestate_entry("tenant=a=b,tenant=c", "tenant"),
+            Some("a=b")
+        );
+
+        // Malformed entries are skipped.
+        assert_eq!(
+            tracestate_entry("tenant,=x,tenant=,,tenant=acme", "tenant"),
+            Some("acme")
+        );
+        assert_eq!(tracestate_entry("tenant;acme", "tenant"), None);
+    }
+
+    #[test]
+    fn baggage_key() {
+        let baggage = |tags: serde_json::Value| {
+            let mut span = span("t", "1", None, "frontend", "GET", 0, 3000);
+            span.tags = serde_json::from_value(tags).unwrap();
+            let key = SpanKey::Current(KeyName::Baggage(String::from("tenant")));
+            match key.get(&span, Ancestors::default()) {
+                Some(TagValueRef::String(s)) => Some(s.to_string()),
+                Some(_) => panic!("baggage is not a string"),
+                None => None,
+            }
+        };
+
+        let tag = json!({ "key": "baggage.tenant", "type": "string", "value": "acme" });
+        let state = json!({
+            "key": "w3c.tracestate",
+            "type": "string",
+            "value": "rojo=00f067aa0ba902b7,tenant@congo=initech"
+        });
+        assert_eq!(baggage(json!([tag])).as_deref(), Some("acme"));
+        assert_eq!(baggage(json!([state])).as_deref(), Some("initech"));
+        // The baggage tag takes precedence.
+        assert_eq!(baggage(json!([state, tag])).as_deref(), Some("acme"));
+        assert_eq!(baggage(json!([])), None);
+
+        assert_eq!(
+            serde_json::from_value::<SpanKey>(json!({ "current": { "baggage": "tenant" } }))
+                .unwrap(),
+            SpanKey::Current(KeyName::Baggage(String::from("tenant")))
+        );
+        assert_eq!(
+            SpanKey::Parent(KeyName::Baggage(String::from("product.area")))
+                .label()
+                .into_string(),
+            "parent_baggage_product_area"
+        );
+        assert!(!SpanKey::Current(KeyName::Baggage(String::from("tenant"))).is_required());
+    }
+
+    /// A classifier of error messages: timeouts, then any other
+    /// message, then "other".
+    fn error_classifier() -> SpanClassifier {
+        serde_json::from_value(json!({
+            "classes": [
+                {
+                    "value": "timeout",
+                    "select": { "match": [
+                        { "current": { "span_tag": "exception.message" } },
+                        "timed? ?out"
+                    ] }
+                },
+                {
+                    "value": "failed",
+                    "select": { "has": { "current": { "span_tag": "exception.message" } } }
+                }
+            ],
+            "fallback": "other"
+        }))
+        .unwrap()
+    }
+
+    fn error_span(message: Option<&str>) -> Span {
+        let mut span = span("1", "1", None, "frontend", "GET", 0, 1000);
+        span.tags.extend(message.map(|message| Tag {
+            key: String::from("exception.message"),
+            value: TagValue::String(String::from(message)),
+        }));
+        span
+    }
+
+    #[test]
+    fn classify_spans() {
+        let classifier = error_classifier();
+        let classify = |message| classifier.classify(&error_span(message), Ancestors::default());
+        assert_eq!(classify(Some("read timed out")), "timeout");
+        assert_eq!(classify(Some("connection refused")), "failed");
+        assert_eq!(classify(None), "other");
+
+        // The first matching class wins.
+        let mut reversed = error_classifier();
+        reversed.classes.reverse();
+        assert_eq!(
+            reversed.classify(&error_span(Some("read timed out")), Ancestors::default()),
+            "failed"
+        );
+
+        let classifiers = SpanClassifiers::from_iter([(String::from("error"), classifier)]);
+        let key = SpanKey::Current(KeyName::Class(String::from("error")));
+        assert_eq!(key.label().into_string(), "error");
+        assert!(key.is_required());
+        let span = error_span(Some("timeout"));
+        assert!(
+            key.get_with(&span, Ancestors::default(), &classifiers)
+                == Some(TagValueRef::String("timeout"))
+        );
+        // Classes are not resolved without the classifiers.
+        assert!(key.get(&span, Ancestors::default()).is_none());
+        let parent = SpanKey::Parent(KeyName::Class(String::from("error")));
+        let child = span("1", "2", Some("1"), "backend", "GET", 0, 500);
+        let ancestors = Ancestors {
+            parent: Some(&span),
+            grandparent: None,
+        };
+        assert!(
+            parent.get_with(&child, ancestors, &classifiers)
+                == Some(TagValueRef::String("timeout"))
+        );
+    }
+
+    #[test]
+    fn validate_classifiers() {
+        let config = |classifier: SpanClassifier, key: &str| {
+            let mut config = Config::default();
+            let span_config = config
+                .trace
+                .configs
+                .get_mut(&ConfigName::new("default"))
+                .unwrap();
+            span_config
+                .classify
+                .insert(String::from("error"), classifier);
+            span_config
+                .annotations
+                .insert(SpanKey::Current(KeyName::Class(String::from(key))));
+            config
+        };
+        assert!(config(error_classifier(), "error").validate().is_ok());
+        assert!(matches!(
+            config(error_classifier(), "errors").validate(),
+            Err(ConfigError::UnknownClassifier(name, classifier))
+                if name == ConfigName::new("default") && classifier == "errors"
+        ));
+
+        let duplicate = SpanClassifier {
+            fallback: String::from("failed"),
+            ..error_classifier()
+        };
+        assert!(matches!(
+            config(duplicate, "error").validate(),
+            Err(ConfigError::InvalidClassifier(_, classifier, "class values are not unique"))
+                if classifier == "error"
+        ));
+        let no_fallback = SpanClassifier {
+            fallback: String::new(),
+            ..error_classifier()
+        };
+        assert!(matches!(
+            config(no_fallback, "error").validate(),
+            Err(ConfigError::InvalidClassifier(
+                _,
+                _,
+                "the fallback value is empty"
+            ))
+        ));
+        // The fallback is required.
+        assert!(serde_json::from_value::<SpanClassifier>(json!({ "classes": [] })).is_err());
+    }
+
+    #[test]
+    fn log_rate_source() {
+        let span = sample_span();
+        let t = DateTime::from_timestamp_micros(span.start_time).unwrap();
+        let count = |source: MetricSource| {
+            let mut values = Vec::new();
+            SourceProcessor::new(t, &source).insert(
+                t,
+                &span,
+                Ancestors::default(),
+                &[],
+                &SpanClassifiers::new(),
+                1.0,
+                |v| values.push(v),
+            );
+            values
+        };
+        let log_rate = |field: &str, pattern: Option<&str>, level: Option<&str>| {
+            count(MetricSource::LogRate {
+                field: String::from(field),
+                pattern: pattern.map(|re| Regex::new(re).unwrap()),
+                level_field: None,
+                level: level.map(String::from),
+            })
+        };
+        assert_eq!(log_rate("event", None, None), [1.0]);
+        assert_eq!(log_rate("event", Some("idle"), Some("DEBUG")), [1.0]);
+        assert_eq!(log_rate("event", Some("idle"), Some("ERROR")), [0.0]);
+        assert_eq!(log_rate("level", Some("^ERROR$"), None), [0.0]);
+        assert_eq!(log_rate("exception.message", None, None), [0.0]);
+    }
+
+    #[test]
+    fn validate_logs_ingested() {
+        let has_log = SpanSelector::HasLog(String::from("event"), Regex::new("idle").unwrap());
+        let mut config = Config::default();
+        let (name, span_config) = config.trace.configs.iter_mut().next().unwrap();
+        let name = name.clone();
+        span_config.metrics.insert(
+            MetricName::new("idle"),
+            serde_json::from_value(json!({
+                "source": { "log_rate": { "field": "event" } },
+                "stats": {}
+            }))
+            .unwrap(),
+        );
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::LogsNotIngested(n)) if n == name
+        ));
+        config.ingest_logs = true;
+        config.validate().unwrap();
+
+        let mut config = Config::default();
+        config.trace.rules[0][0].select = SpanSelector::Not(Box::new(has_log));
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::RuleLogsNotIngested)
+        ));
+        config.ingest_logs = true;
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn skip_missing_logs() {
+        let span = serde_json::from_value::<Span>(json!({
+            "traceID": "0de61f1de7ee678bccb46f3dab804867",
+            "spanID": "672633d1537fb110",
+            "operationName": "GET",
+            "references": [],
+            "startTime": 1716537605749742i64,
+            "startTimeMillis": 1716537605749i64,
+            "duration": 1530,
+            "tags": [],
+            "process": { "serviceName": "relation-graph-engine", "tags": [] }
+        }))
+        .unwrap();
+        assert!(span.logs.is_empty());
     }
 }
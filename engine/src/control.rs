@@ -0,0 +1,339 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use std::{collections::BTreeMap, fmt::Debug, future::Future, pin::Pin};
+
+use apistos::ApiComponent;
+use reqwest::header::IF_MATCH;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tap::Pipe;
+use url::Url;
+
+use crate::{
+    config::{Config, ConfigName},
+    error::{Error, Result},
+    processor::{
+        baseline::{BaselineBundle, ImportReport},
+        bootstrap::BootstrapStatus,
+        cache::CachedResult,
+        command_stats::CommandReport,
+        expiry::ExpiryWebhookStatus,
+        ingest_stats::IngestReport,
+        label_values::{LabelValuesQuery, LabelValuesReport},
+        proc::Processor,
+        quarantine::ConfigFailures,
+        resolve::{ResolveObjectRequest, ResolvedObject},
+        rule_stats::RuleCounts,
+        schema_push::SchemaPushStatus,
+        series_limit::SeriesReport,
+        throttle::ThrottleLimits,
+        tick::TickReport,
+        top_movers::{TopMovers, TopMoversQuery},
+        trace_debug::TraceDebugReport,
+    },
+    state::SaveStats,
+};
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + 'a>>;
+
+/// The components to run.
+#[derive(clap::ValueEnum, Serialize, JsonSchema, PartialEq, Eq, Clone, Copy, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum Mode {
+    /// The processor and the full API.
+    All,
+    /// The API only. The config is read from and written to the
+    /// processor at `--processor-url`; other processor endpoints
+    /// return 501.
+    Web,
+    /// The processor, with only the health, status and config
+    /// endpoints.
+    Processor,
+}
+
+/// Where the web server reads and updates the config.
+pub trait ConfigStore: Debug + Send + Sync {
+    fn config(&self) -> BoxFuture<'_, Result<ConfigVersion>>;
+    /// Replace the config. The config is validated by the caller. When
+    /// `expected` is set, the update is rejected with
+    /// `Error::ConfigConflict` unless it is the current generation.
+    fn set_config(&self, config: Config, expected: Option<u64>) -> BoxFuture<'_, Result<()>>;
+}
+
+/// A config with its generation. The generation is incremented on
+/// every config update, so that clients can detect concurrent updates.
+#[derive(Serialize, Deserialize, JsonSchema, ApiComponent, Default, Clone, Debug)]
+pub struct ConfigVersion {
+    pub generation: u64,
+    #[serde(flatten)]
+    pub config: Config,
+}
+
+/// Check the generation an update was based on, if any, against the
+/// current generation.
+pub fn check_generation(expected: Option<u64>, current: u64) -> Result<()> {
+    match expected {
+        Some(expected) if expected != current => Err(Error::ConfigConflict { expected, current }),
+        _ => Ok(()),
+    }
+}
+
+/// The ETag of a config generation.
+pub fn generation_etag(generation: u64) -> String {
+    format!("\"{generation}\"")
+}
+
+/// The generation in an ETag or If-Match header. Returns `None` for
+/// `*`, which matches any generation.
+pub fn parse_generation_etag(value: &str) -> Result<Option<u64>> {
+    let value = value.trim();
+    if value == "*" {
+        return Ok(None);
+    }
+    value
+        .strip_prefix("W/")
+        .unwrap_or(value)
+        .trim_matches('"')
+        .parse()
+        .map(Some)
+        .map_err(|_| Error::InvalidGeneration(value.to_string()))
+}
+
+/// The processor endpoints other than the config. Only available
+/// where the processor runs.
+pub trait ProcessorControl: Debug + Send + Sync {
+    fn status(&self) -> Status;
+    /// Whether the processor is still waiting for its backends.
+    fn starting(&self) -> bool;
+    /// The report of the last tick, if any.
+    fn last_tick(&self) -> Option<TickReport>;
+    fn export_baselines(&self) -> BoxFuture<'_, Result<BaselineBundle>>;
+    fn import_baselines(&self, bundle: BaselineBundle) -> BoxFuture<'_, Result<ImportReport>>;
+    fn label_values(&self, query: LabelValuesQuery) -> BoxFuture<'_, Result<LabelValuesReport>>;
+    fn debug_trace<'a>(
+        &'a self,
+        trace_id: &'a str,
+    ) -> BoxFuture<'a, Result<Option<TraceDebugReport>>>;
+    fn cached_query(&self, name: &str) -> Option<CachedResult>;
+    fn resolve_object(
+        &self,
+        request: ResolveObjectRequest,
+    ) -> BoxFuture<'_, Result<ResolvedObject>>;
+    fn top_movers(&self, query: TopMoversQuery) -> BoxFuture<'_, Result<TopMovers>>;
+    /// Push the prometheus schema of the current config now.
+    fn push_schema(&self) -> BoxFuture<'_, Result<SchemaPushStatus>>;
+}
+
+#[derive(Serialize, JsonSchema, ApiComponent)]
+pub struct Status {
+    /// Rule evaluation counts for the last completed tick.
+    rules: Option<RuleCounts>,
+    /// Series counts for the last sample, including series dropped
+    /// because of the `max_series` limit.
+    series: Option<SeriesReport>,
+    /// Duration and size of the last state save.
+    last_save: Option<SaveStats>,
+    /// Trace sizes and backend latencies of the last tick, when
+    /// enabled with `--ingest-stats`.
+    ingest: Option<IngestReport>,
+    /// The number of series dropped since startup because the
+    /// remote-write backend rejected them as out-of-order.
+    dropped_series: u64,
+    /// The effective limits of the span queries, which shrink while
+    /// OpenSearch is under pressure.
+    throttle: ThrottleLimits,
+    /// The number of samples written to the metrics sink since
+    /// startup.
+    written_samples: u64,
+    /// The number of samples dropped since startup because a later
+    /// sample of the same series had the same timestamp.
+    duplicate_samples: u64,
+    /// The number of groups that could not be decoded from the state
+    /// at startup, and started over.
+    dropped_groups: u64,
+    /// The generation of the current config.
+    config_generation: u64,
+    /// The outcome of the schema pushes, when enabled with
+    /// `--schema-push-url`.
+    schema_push: Option<SchemaPushStatus>,
+    /// The outcome of the expiry notifications, when enabled with
+    /// `--expiry-webhook`.
+    expiry_webhook: Option<ExpiryWebhookStatus>,
+    /// The failures of the span configs since startup. Configs failing
+    /// in consecutive samples are quarantined until the next config
+    /// update.
+    config_failures: BTreeMap<ConfigName, ConfigFailures>,
+    /// The progress of the bootstrap, when started with
+    /// `--bootstrap-from`.
+    bootstrap: Option<BootstrapStatus>,
+    /// The depth of the command queue of the processor task and the
+    /// durations of the handled commands.
+    commands: CommandReport,
+    /// What the last ticks did, oldest first.
+    ticks: Vec<TickReport>,
+}
+
+impl ConfigStore for Processor {
+    fn config(&self) -> BoxFuture<'_, Result<ConfigVersion>> {
+        Box::pin(std::future::ready(Ok(self.config_version())))
+    }
+
+    fn set_config(&self, config: Config, expected: Option<u64>) -> BoxFuture<'_, Result<()>> {
+        Box::pin(std::future::ready(self.update_config(config, expected)))
+    }
+}
+
+impl ProcessorControl for Processor {
+    fn status(&self) -> Status {
+        Status {
+            rules: self.last_rule_counts(),
+            series: self.last_series(),
+            last_save: self.last_save(),
+            ingest: self.last_ingest(),
+            dropped_series: self.dropped_series(),
+            throttle: self.throttle_limits(),
+            written_samples: self.written_samples(),
+            duplicate_samples: self.duplicate_samples(),
+            dropped_groups: self.dropped_groups(),
+            config_generation: self.config_generation(),
+            schema_push: self.schema_push_status(),
+            expiry_webhook: self.expiry_webhook_status(),
+            config_failures: self.config_failures(),
+            bootstrap: self.bootstrap_status(),
+            commands: self.command_report(),
+            ticks: self.ticks(),
+        }
+    }
+
+    fn starting(&self) -> bool {
+        Processor::starting(self)
+    }
+
+    fn last_tick(&self) -> Option<TickReport> {
+        Processor::last_tick(self)
+    }
+
+    fn export_baselines(&self) -> BoxFuture<'_, Result<BaselineBundle>> {
+        Box::pin(Processor::export_baselines(self))
+    }
+
+    fn import_baselines(&self, bundle: BaselineBundle) -> BoxFuture<'_, Result<ImportReport>> {
+        Box::pin(Processor::import_baselines(self, bundle))
+    }
+
+    fn label_values(&self, query: LabelValuesQuery) -> BoxFuture<'_, Result<LabelValuesReport>> {
+        Box::pin(Processor::label_values(self, query))
+    }
+
+    fn debug_trace<'a>(
+        &'a self,
+        trace_id: &'a str,
+    ) -> BoxFuture<'a, Result<Option<TraceDebugReport>>> {
+        Box::pin(Processor::debug_trace(self, trace_id))
+    }
+
+    fn cached_query(&self, name: &str) -> Option<CachedResult> {
+        Processor::cached_query(self, name)
+    }
+
+    fn resolve_object(
+        &self,
+        request: ResolveObjectRequest,
+    ) -> BoxFuture<'_, Result<ResolvedObject>> {
+        Box::pin(Processor::resolve_object(self, request))
+    }
+
+    fn top_movers(&self, query: TopMoversQuery) -> BoxFuture<'_, Result<TopMovers>> {
+        Box::pin(Processor::top_movers(self, query))
+    }
+
+    fn push_schema(&self) -> BoxFuture<'_, Result<SchemaPushStatus>> {
+        Box::pin(Processor::push_schema(self))
+    }
+}
+
+/// The config of a processor running in another process, accessed
+/// through its config endpoint.
+#[derive(Debug)]
+pub struct RemoteProcessor {
+    client: reqwest::Client,
+    url: Url,
+}
+
+impl RemoteProcessor {
+    /// A remote processor with the API at `url`, including the prefix.
+    pub fn new(mut url: Url) -> Self {
+        if !url.path().ends_with('/') {
+            let path = format!("{}/", url.path());
+            url.set_path(&path);
+        }
+        Self {
+            client: reqwest::Client::new(),
+            url,
+        }
+    }
+
+    fn config_url(&self) -> Result<Url> {
+        self.url.join("config").map_err(Error::Url)
+    }
+}
+
+impl ConfigStore for RemoteProcessor {
+    fn config(&self) -> BoxFuture<'_, Result<ConfigVersion>> {
+        Box::pin(async move {
+            let res = self
+                .client
+                .get(self.config_url()?)
+                .send()
+                .await
+                .map_err(Error::RemoteProcessor)?;
+            check_status(res)
+                .await?
+                .json()
+                .await
+                .map_err(Error::RemoteProcessor)
+        })
+    }
+
+    fn set_config(&self, config: Config, expected: Option<u64>) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move {
+            let res = self
+                .client
+                .post(self.config_url()?)
+                .json(&config)
+                .pipe(|req| match expected {
+                    Some(generation) => req.header(IF_MATCH, generation_etag(generation)),
+                    None => req,
+                })
+                .send()
+                .await
+                .map_err(Error::RemoteProcessor)?;
+            if let (Some(expected), reqwest::StatusCode::PRECONDITION_FAILED) =
+                (expected, res.status())
+            {
+                let current = res
+                    .json::<ConfigVersion>()
+                    .await
+                    .map_err(Error::RemoteProcessor)?;
+                return Err(Error::ConfigConflict {
+                    expected,
+                    current: current.generation,
+                });
+            }
+            check_status(res).await?;
+            Ok(())
+        })
+    }
+}
+
+async fn check_status(res: reqwest::Response) -> Result<reqwest::Response> {
+    let status = res.status();
+    if status.is_success() {
+        Ok(res)
+    } else {
+        let body = res.text().await.unwrap_or_default();
+        Err(Error::RemoteProcessorStatus(status, body))
+    }
+}
@@ -0,0 +1,427 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+//! The `jadctl` client for the engine API, built as a separate binary
+//! (`src/bin/jadctl.rs`). It only talks to the API and does not use
+//! the engine's types: configs are handled as JSON values.
+
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use clap::{Parser, Subcommand};
+use reqwest::{
+    header::{ACCEPT, IF_MATCH},
+    Method, RequestBuilder, Response, StatusCode,
+};
+use serde::Serialize;
+use serde_json::Value;
+use tap::Pipe;
+use url::Url;
+
+const RED: &str = "31";
+const GREEN: &str = "32";
+const YELLOW: &str = "33";
+const BOLD: &str = "1";
+
+#[derive(Parser, Debug)]
+#[clap(
+    name = "jadctl",
+    about = "Manage a jaeger anomaly detection engine through its API"
+)]
+pub struct Cli {
+    /// The engine API, including the prefix.
+    #[clap(
+        long,
+        env = "JADCTL_URL",
+        default_value = "http://127.0.0.1:9999/api/jaeger-anomaly-detection/"
+    )]
+    url: Url,
+    /// Sent as bearer token, for engines behind an authenticating proxy.
+    #[clap(long, env = "JADCTL_TOKEN")]
+    token: Option<String>,
+    /// Print JSON instead of text.
+    #[clap(long)]
+    json: bool,
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Read or replace the config.
+    #[clap(subcommand)]
+    Config(ConfigCommand),
+    /// Read the prometheus schema of the written metrics.
+    #[clap(subcommand)]
+    Schema(SchemaCommand),
+    /// Show the processor status.
+    Status,
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigCommand {
+    /// Print the config, with its generation.
+    Get {
+        /// Print YAML instead of JSON.
+        #[clap(long)]
+        yaml: bool,
+    },
+    /// Replace the config by a JSON or YAML file. The update is
+    /// rejected if the config changed since the generation in the
+    /// file, as printed by `config get`.
+    Set {
+        file: PathBuf,
+        /// Ignore the generation in the file.
+        #[clap(long)]
+        force: bool,
+    },
+    /// Show the changes `config set` would make to the config.
+    Diff { file: PathBuf },
+}
+
+#[derive(Subcommand, Debug)]
+enum SchemaCommand {
+    /// Print the schema.
+    Get,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum CtlError {
+    #[error("failed to read {path}: {1}", path = .0.display())]
+    Read(PathBuf, std::io::Error),
+    #[error("failed to parse {path}: {1}", path = .0.display())]
+    Parse(PathBuf, serde_yaml::Error),
+    #[error("invalid url: {0}")]
+    Url(url::ParseError),
+    #[error("request failed: {0}")]
+    Request(reqwest::Error),
+    #[error("invalid response: {0}")]
+    Response(String),
+    #[error("{0}: {1}")]
+    Api(StatusCode, String),
+    #[error("the config changed since generation {expected}; it is at generation {current}")]
+    Conflict { expected: u64, current: u64 },
+    #[error("failed to write output: {0}")]
+    Output(std::io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, CtlError>;
+
+impl CtlError {
+    /// The exit code: 1 for local errors, 2 if the engine could not
+    /// be reached, 3 if it rejected the request and 4 if it failed.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Read(..) | Self::Parse(..) | Self::Url(_) | Self::Output(_) => 1,
+            Self::Request(_) => 2,
+            Self::Conflict { .. } => 3,
+            Self::Api(status, _) if status.is_client_error() => 3,
+            Self::Api(..) | Self::Response(_) => 4,
+        }
+    }
+}
+
+/// A change between two configs, at a JSON pointer.
+#[derive(Serialize, Debug)]
+#[serde(tag = "change", rename_all = "snake_case")]
+enum Change {
+    Added {
+        path: String,
+        value: Value,
+    },
+    Removed {
+        path: String,
+        value: Value,
+    },
+    Changed {
+        path: String,
+        old: Value,
+        new: Value,
+    },
+}
+
+struct Client {
+    http: reqwest::Client,
+    url: Url,
+    token: Option<String>,
+}
+
+/// The output, colored when `color` is set.
+struct Printer<W> {
+    out: W,
+    color: bool,
+    json: bool,
+}
+
+/// Run a command, writing its output to `out`.
+pub async fn run<W: Write>(cli: &Cli, out: W, color: bool) -> Result<()> {
+    let mut url = cli.url.clone();
+    if !url.path().ends_with('/') {
+        url.set_path(&format!("{}/", url.path()));
+    }
+    let client = Client {
+        http: reqwest::Client::new(),
+        url,
+        token: cli.token.clone(),
+    };
+    let mut out = Printer {
+        out,
+        color,
+        json: cli.json,
+    };
+    match &cli.command {
+        Command::Config(ConfigCommand::Get { yaml }) => config_get(&client, &mut out, *yaml).await,
+        Command::Config(ConfigCommand::Set { file, force }) => {
+            config_set(&client, &mut out, file, *force).await
+        }
+        Command::Config(ConfigCommand::Diff { file }) => config_diff(&client, &mut out, file).await,
+        Command::Schema(SchemaCommand::Get) => schema_get(&client, &mut out).await,
+        Command::Status => status(&client, &mut out).await,
+    }
+}
+
+async fn config_get<W: Write>(client: &Client, out: &mut Printer<W>, yaml: bool) -> Result<()> {
+    if yaml && !out.json {
+        let res = client
+            .request(Method::GET, "config")?
+            .header(ACCEPT, "application/yaml")
+            .pipe(send)
+            .await?;
+        out.text(&res.text().await.map_err(CtlError::Request)?)
+    } else {
+        let config = get_config(client).await?;
+        out.value(&config)
+    }
+}
+
+async fn config_set<W: Write>(
+    client: &Client,
+    out: &mut Printer<W>,
+    file: &Path,
+    force: bool,
+) -> Result<()> {
+    let mut config = read_config(file)?;
+    let expected = take_generation(&mut config).filter(|_| !force);
+    let res = client
+        .request(Method::POST, "config")?
+        .json(&config)
+        .pipe(|req| match expected {
+            Some(generation) => req.header(IF_MATCH, format!("\"{generation}\"")),
+            None => req,
+        })
+        .send()
+        .await
+        .map_err(CtlError::Request)?;
+    if let (Some(expected), StatusCode::PRECONDITION_FAILED) = (expected, res.status()) {
+        let mut current = res.json::<Value>().await.map_err(CtlError::Request)?;
+        return Err(CtlError::Conflict {
+            expected,
+            current: take_generation(&mut current).unwrap_or_default(),
+        });
+    }
+    check(res).await?;
+    let generation = take_generation(&mut get_config(client).await?);
+    if out.json {
+        out.value(&serde_json::json!({ "updated": true, "generation": generation }))
+    } else {
+        let generation = generation.map_or_else(|| String::from("?"), |g| g.to_string());
+        out.line(
+            Some(GREEN),
+            &format!("config updated to generation {generation}"),
+        )
+    }
+}
+
+async fn config_diff<W: Write>(client: &Client, out: &mut Printer<W>, file: &Path) -> Result<()> {
+    let mut new = read_config(file)?;
+    let mut current = get_config(client).await?;
+    take_generation(&mut new);
+    take_generation(&mut current);
+    let mut changes = Vec::new();
+    diff("", &current, &new, &mut changes);
+    if out.json {
+        return out.value(&serde_json::to_value(&changes).unwrap());
+    }
+    if changes.is_empty() {
+        return out.line(None, "no changes");
+    }
+    changes.iter().try_for_each(|change| match change {
+        Change::Added { path, value } => out.line(Some(GREEN), &format!("+ {path}: {value}")),
+        Change::Removed { path, value } => out.line(Some(RED), &format!("- {path}: {value}")),
+        Change::Changed { path, old, new } => {
+            out.line(Some(YELLOW), &format!("~ {path}: {old} -> {new}"))
+        }
+    })
+}
+
+async fn schema_get<W: Write>(client: &Client, out: &mut Printer<W>) -> Result<()> {
+    let schema = client
+        .request(Method::GET, "prometheus-schema")?
+        .pipe(send)
+        .await?
+        .text()
+        .await
+        .map_err(CtlError::Request)?;
+    if out.json {
+        let schema = serde_yaml::from_str::<Value>(&schema)
+            .map_err(|e| CtlError::Response(e.to_string()))?;
+        out.value(&schema)
+    } else {
+        out.text(&schema)
+    }
+}
+
+async fn status<W: Write>(client: &Client, out: &mut Printer<W>) -> Result<()> {
+    let status = client
+        .request(Method::GET, "status")?
+        .pipe(send)
+        .await?
+        .json::<Value>()
+        .await
+        .map_err(CtlError::Request)?;
+    out.yaml(&status)
+}
+
+impl Client {
+    fn request(&self, method: Method, path: &str) -> Result<RequestBuilder> {
+        let req = self
+            .http
+            .request(method, self.url.join(path).map_err(CtlError::Url)?);
+        Ok(match &self.token {
+            Some(token) => req.bearer_auth(token),
+            None => req,
+        })
+    }
+}
+
+impl<W: Write> Printer<W> {
+    fn line(&mut self, color: Option<&str>, text: &str) -> Result<()> {
+        match color.filter(|_| self.color) {
+            Some(color) => writeln!(self.out, "\x1b[{color}m{text}\x1b[0m"),
+            None => writeln!(self.out, "{text}"),
+        }
+        .map_err(CtlError::Output)
+    }
+
+    fn text(&mut self, text: &str) -> Result<()> {
+        self.line(None, text.strip_suffix('\n').unwrap_or(text))
+    }
+
+    /// Print a value as JSON: compact for `--json`, pretty otherwise.
+    fn value(&mut self, value: &Value) -> Result<()> {
+        let text = if self.json {
+            value.to_string()
+        } else {
+            serde_json::to_string_pretty(value).unwrap()
+        };
+        self.line(None, &text)
+    }
+
+    /// Print a value as YAML, with highlighted keys, unless `--json`
+    /// is given.
+    fn yaml(&mut self, value: &Value) -> Result<()> {
+        if self.json {
+            return self.value(value);
+        }
+        let text = serde_yaml::to_string(value).map_err(|e| CtlError::Response(e.to_string()))?;
+        text.lines()
+            .try_for_each(|line| match line.split_once(':') {
+                Some((key, rest)) if self.color && is_key(key) => {
+                    writeln!(self.out, "\x1b[{BOLD}m{key}\x1b[0m:{rest}").map_err(CtlError::Output)
+                }
+                _ => self.line(None, line),
+            })
+    }
+}
+
+async fn get_config(client: &Client) -> Result<Value> {
+    client
+        .request(Method::GET, "config")?
+        .pipe(send)
+        .await?
+        .json()
+        .await
+        .map_err(CtlError::Request)
+}
+
+async fn send(req: RequestBuilder) -> Result<Response> {
+    check(req.send().await.map_err(CtlError::Request)?).await
+}
+
+/// Turn error responses into errors, with the message of the
+/// `{"error": ...}` body if there is one.
+async fn check(res: Response) -> Result<Response> {
+    let status = res.status();
+    if status.is_success() {
+        return Ok(res);
+    }
+    let body = res.text().await.unwrap_or_default();
+    let message = serde_json::from_str::<Value>(&body)
+        .ok()
+        .and_then(|body| Some(body.get("error")?.as_str()?.to_string()))
+        .unwrap_or(body);
+    Err(CtlError::Api(status, message))
+}
+
+/// Read a config file. YAML is a superset of JSON, so both are read
+/// by the YAML parser.
+fn read_config(file: &Path) -> Result<Value> {
+    let data = std::fs::read_to_string(file).map_err(|e| CtlError::Read(file.to_path_buf(), e))?;
+    serde_yaml::from_str(&data).map_err(|e| CtlError::Parse(file.to_path_buf(), e))
+}
+
+/// Remove the generation from a config as returned by the API.
+fn take_generation(config: &mut Value) -> Option<u64> {
+    config.as_object_mut()?.remove("generation")?.as_u64()
+}
+
+/// The changes from `old` to `new`. Objects are compared by key, other
+/// values (including arrays) as a whole.
+fn diff(path: &str, old: &Value, new: &Value, changes: &mut Vec<Change>) {
+    match (old, new) {
+        (Value::Object(old), Value::Object(new)) => {
+            old.iter().for_each(|(key, value)| {
+                let path = pointer(path, key);
+                match new.get(key) {
+                    Some(new) => diff(&path, value, new, changes),
+                    None => changes.push(Change::Removed {
+                        path,
+                        value: value.clone(),
+                    }),
+                }
+            });
+            new.iter()
+                .filter(|(key, _)| !old.contains_key(*key))
+                .for_each(|(key, value)| {
+                    changes.push(Change::Added {
+                        path: pointer(path, key),
+                        value: value.clone(),
+                    })
+                });
+        }
+        _ if old == new => {}
+        _ => changes.push(Change::Changed {
+            path: match path {
+                "" => String::from("/"),
+                _ => path.to_string(),
+            },
+            old: old.clone(),
+            new: new.clone(),
+        }),
+    }
+}
+
+fn pointer(path: &str, key: &str) -> String {
+    format!("{path}/{}", key.replace('~', "~0").replace('/', "~1"))
+}
+
+fn is_key(key: &str) -> bool {
+    let key = key.trim_start().trim_start_matches("- ");
+    !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+}
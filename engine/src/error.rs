@@ -22,6 +22,8 @@ pub enum Error {
     WriteState(std::io::Error),
     #[error("failed to deserialize state: {0}")]
     DeserializeState(ciborium::de::Error<std::io::Error>),
+    #[error("failed to export config: {0}: {1}")]
+    ExportConfig(PathBuf, std::io::Error),
     #[error("url parse error: {0}")]
     Url(url::ParseError),
     #[error("opensearch request failed: {0}")]
@@ -33,6 +35,12 @@ pub enum Error {
     #[error("opensearch returned an unknown response: {}",
 			serde_json::to_string(.0).unwrap())]
     ElasticUnknown(serde_json::Value),
+    #[error("opensearch returned status {0}: {1}")]
+    ElasticStatus(reqwest::StatusCode, String),
+    #[error("failed to decode opensearch response: {0}")]
+    ElasticDecode(serde_json::Error),
+    #[error("failed to write metrics file: {0}: {1}")]
+    WriteMetricsFile(std::path::PathBuf, std::io::Error),
     #[error("opensearch response missing pit id")]
     ElasticMissingPitId,
     #[error("failed to build prometheus remote write request: {0}")]
@@ -41,6 +49,10 @@ pub enum Error {
     // BuildPromReqwest(reqwest::Error),
     #[error("prometheus remote write request failed: {0}")]
     Prometheus(reqwest::Error),
+    #[error("prometheus query failed: {0}")]
+    PromQuery(reqwest::Error),
+    #[error("prometheus query returned an error: {0}")]
+    PromQueryRes(String),
     #[error("invalid prometheus tenant: {0}")]
     InvalidPrometheusTenant(reqwest::header::InvalidHeaderValue),
     #[error("prometheus remote write request failed: {0}")]
@@ -51,10 +63,54 @@ pub enum Error {
     WebServer(std::io::Error),
     #[error("failed to shutdown processor: still in use")]
     ProcessorShutdown,
+    #[error("remote processor request failed: {0}")]
+    RemoteProcessor(reqwest::Error),
+    #[error("remote processor returned {0}: {1}")]
+    RemoteProcessorStatus(reqwest::StatusCode, String),
     #[error("DateTime error: {0}")]
     DateTimeBounds(chrono::OutOfRangeError),
     #[error("unspecified DateTime error")]
     DateTime,
+    #[error("processor task is not running")]
+    ProcessorStopped,
     #[error("failed to join processor task: {0}")]
     JoinProcessor(tokio::task::JoinError),
+    #[error("failed to join query cache task: {0}")]
+    JoinQueryCache(tokio::task::JoinError),
+    #[error("failed to serialize prometheus schema: {0}")]
+    SerializeSchema(serde_yaml::Error),
+    #[error("schema push failed: {0}")]
+    SchemaPush(reqwest::Error),
+    #[error("schema push returned {0}: {1}")]
+    SchemaPushStatus(reqwest::StatusCode, String),
+    #[error("schema push is not configured")]
+    SchemaPushDisabled,
+    #[error("schema push task is not running")]
+    SchemaPushStopped,
+    #[error("failed to join schema push task: {0}")]
+    JoinSchemaPush(tokio::task::JoinError),
+    #[error("expiry webhook request failed: {0}")]
+    ExpiryWebhook(reqwest::Error),
+    #[error("expiry webhook returned {0}: {1}")]
+    ExpiryWebhookStatus(reqwest::StatusCode, String),
+    #[error("failed to join expiry webhook task: {0}")]
+    JoinExpiryWebhook(tokio::task::JoinError),
+    #[error("{0} startup checks failed")]
+    ChecksFailed(usize),
+    #[error("backends not ready after {0}s: {1}")]
+    BackendsNotReady(u64, String),
+    #[error("processor failed to start: {0}")]
+    ProcessorStartup(String),
+    #[error("config generation {expected} is outdated: the current generation is {current}")]
+    ConfigConflict { expected: u64, current: u64 },
+    #[error("invalid config generation: {0}")]
+    InvalidGeneration(String),
+    #[error("pseudonymization key file is empty: {0}")]
+    EmptyPseudonymizationKey(PathBuf),
+    #[error("the config pseudonymizes label values, but no pseudonymization key was given")]
+    PseudonymizationKeyRequired,
+    #[error("invalid shard index {0}: the shard count is {1}")]
+    InvalidShard(u32, u32),
+    #[error("invalid top movers query: {0}")]
+    InvalidTopMovers(String),
 }
@@ -69,6 +69,9 @@ pub struct Span {
     pub start_time_millis: i64,
     pub duration: i64,
     pub tags: Vec<Tag>,
+    /// Span logs. These are only fetched when enabled in the config
+    /// (`ingest_logs`); otherwise the list is empty.
+    #[serde(default)]
     pub logs: Vec<Log>,
     pub process: Process,
 }
@@ -107,7 +110,19 @@ pub struct Tag {
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
-pub struct Log {}
+pub struct Log {
+    pub timestamp: i64,
+    pub fields: Vec<Tag>,
+}
+
+impl Log {
+    pub fn field(&self, key: &str) -> Option<&TagValue> {
+        self.fields
+            .iter()
+            .find(|field| field.key == key)
+            .map(|field| &field.value)
+    }
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
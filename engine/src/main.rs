@@ -4,25 +4,36 @@
 
 mod accum;
 pub mod config;
+mod control;
+// Built as the jadctl binary (src/bin/jadctl.rs); compiled here for its tests.
+#[cfg(test)]
+mod ctl;
 mod error;
 // mod graph;
+mod info;
 mod jaeger;
 pub mod metrics;
 mod opensearch;
 mod processor;
 mod schema;
+mod selector;
 pub mod state;
 mod web;
 mod welford;
 mod window;
 
-use std::{path::PathBuf, sync::Arc};
+#[cfg(not(any(feature = "native-tls", feature = "rustls")))]
+compile_error!("either the \"native-tls\" or the \"rustls\" feature must be enabled");
+
+use std::{num::NonZeroUsize, path::PathBuf, sync::Arc};
 
 use clap::Parser;
-use opensearch::EsKeepAlive;
-use processor::proc::Processor;
+use control::{ConfigStore, Mode, ProcessorControl, RemoteProcessor};
+use processor::{check, proc::Processor, sink::SinkKind};
 
 use error::{Error, Result};
+use info::EngineInfo;
+use jaeger_anomaly_detection::Duration;
 use url::Url;
 use web::{run_web_server, web_server_spec, AppData};
 
@@ -44,20 +55,178 @@ struct Args {
     prometheus_url: Url,
     #[clap(long, env)]
     prometheus_tenant: Option<String>,
+    /// Base url of the prometheus query api, used for cached queries.
+    #[clap(long, env, default_value = "https://localhost:8080/prometheus/")]
+    prometheus_query_url: Url,
     #[clap(long, env, default_value = "state.cbor")]
     state: PathBuf,
+    /// Also write the applied config to this file, as JSON, at startup
+    /// and after every config update. Unlike the state, it can be read
+    /// and re-applied by hand.
+    #[clap(long, env)]
+    config_export_path: Option<PathBuf>,
+    /// The secret for the label values pseudonymized by the config.
+    /// Changing it starts the pseudonymized series over.
+    #[clap(long, env)]
+    pseudonymization_key_file: Option<PathBuf>,
+    /// PUT the prometheus schema of the config, as YAML, to this url
+    /// at startup and whenever a config update changes it, e.g. to
+    /// keep the relation-graph engine in sync.
+    #[clap(long, env)]
+    schema_push_url: Option<Url>,
+    /// Bearer token for the schema push.
+    #[clap(long, env, requires = "schema_push_url")]
+    schema_push_token: Option<String>,
+    /// POST the groups removed because they were not seen for too
+    /// long, with their key labels, to this url as JSON, in batches.
+    #[clap(long, env)]
+    expiry_webhook: Option<Url>,
+    /// Before live processing, replay this much span history (e.g.
+    /// "7d") from OpenSearch to fill the baselines of a new
+    /// deployment. No metrics are written for the replayed range.
+    /// Ignored when the state file exists, unless it holds an
+    /// interrupted bootstrap, which is resumed.
+    #[clap(long, env)]
+    bootstrap_from: Option<Duration>,
+    /// The range replayed per chunk during a bootstrap. The state is
+    /// saved after every chunk.
+    #[clap(long, env, default_value = "1h")]
+    bootstrap_chunk: Duration,
+    /// Save the state at least every this many ticks, even when
+    /// nothing changed.
+    #[clap(long, env, default_value = "10")]
+    force_save_ticks: u32,
     #[clap(long, env, default_value = "10000")]
     metrics_per_request: usize,
+    /// The number of remote-write requests in flight at a time. The
+    /// samples of a series are always written in order.
+    #[clap(long, env, default_value = "1")]
+    write_concurrency: NonZeroUsize,
+    /// Where to write the generated metrics.
+    #[clap(long, env, value_enum, default_value = "remote-write")]
+    metrics_sink: SinkKind,
+    /// The file written by the file metrics sink.
+    #[clap(long, env, default_value = "metrics.jsonl")]
+    metrics_file: PathBuf,
+    /// The size at which the metrics file is rotated.
+    #[clap(long, env, default_value = "104857600")]
+    metrics_file_max_bytes: u64,
+    /// The number of rotated metrics files to keep.
+    #[clap(long, env, default_value = "3")]
+    metrics_file_keep: usize,
+    /// Insert spans for all configs on the processor task, instead of
+    /// processing configs in parallel.
+    #[clap(long, env)]
+    sequential_insert: bool,
+    /// The components to run in this process.
+    #[clap(long, env, value_enum, default_value = "all")]
+    mode: Mode,
+    /// Base url of the processor API, including the prefix. Used in
+    /// web mode to read and update the config.
+    #[clap(long, env)]
+    processor_url: Option<Url>,
     #[clap(long, env, default_value = "/api/jaeger-anomaly-detection")]
     prefix: String,
     #[clap(long, env, default_value = "127.0.0.1:9999")]
     bind: String,
+    /// Maximum size of JSON request bodies, in bytes.
+    #[clap(long, env, default_value = "52428800")]
+    max_json_payload: usize,
+    /// Maximum size of config update request bodies, in bytes.
+    #[clap(long, env, default_value = "2097152")]
+    max_config_payload: usize,
+    /// Maximum size of baseline bundle uploads, in bytes.
+    #[clap(long, env, default_value = "524288000")]
+    max_baseline_payload: usize,
+    /// Maximum size of an imported baseline bundle after
+    /// decompression, in bytes.
+    #[clap(long, env, default_value = "2147483648")]
+    max_baseline_size: u64,
+    /// How long the endpoints routed to the processor task wait for a
+    /// reply, in milliseconds, before responding with 504 Gateway
+    /// Timeout.
+    #[clap(long, env, default_value = "10000")]
+    command_timeout_ms: u64,
+    /// Disable the access log.
+    #[clap(long, env)]
+    no_access_log: bool,
+    /// Add the request-path-relations config to the initial config,
+    /// used when no state file exists.
+    #[clap(long, env)]
+    request_path_relations: bool,
+    /// Add the error_reasons metric, counting errors per HTTP status
+    /// code, to the initial config.
+    #[clap(long, env)]
+    error_reasons: bool,
+    /// Record trace sizes and OpenSearch and remote-write latencies per
+    /// tick, for the self-monitoring metrics and the status endpoint.
+    #[clap(long, env)]
+    ingest_stats: bool,
+    /// The smallest number of traces fetched per span query when
+    /// OpenSearch is under pressure.
+    #[clap(long, env, default_value = "5")]
+    opensearch_min_chunk_size: usize,
+    /// The largest delay between OpenSearch queries when under
+    /// pressure, and the largest retry backoff, in milliseconds.
+    #[clap(long, env, default_value = "10000")]
+    opensearch_max_delay_ms: u64,
+    /// OpenSearch queries taking longer than this signal pressure, in
+    /// milliseconds.
+    #[clap(long, env, default_value = "5000")]
+    opensearch_slow_request_ms: u64,
+    /// The number of retries of OpenSearch queries rejected because
+    /// the cluster is overloaded.
+    #[clap(long, env, default_value = "5")]
+    opensearch_max_retries: u32,
+    /// The largest keep-alive of the OpenSearch point in time, in
+    /// milliseconds. The keep-alive is twice the expected tick
+    /// duration, and at least a minute.
+    #[clap(long, env, default_value = "1800000")]
+    opensearch_max_keep_alive_ms: u64,
+    /// An index pattern holding the spans that the index lifecycle
+    /// policy moved out of `jaeger-span-*`. Queried, besides the
+    /// primary pattern, for ranges starting more than
+    /// `--opensearch-archive-after` ago.
+    #[clap(long, env)]
+    opensearch_archive_index: Option<String>,
+    /// The age after which spans may have moved to the archive index.
+    #[clap(long, env, default_value = "24h")]
+    opensearch_archive_after: Duration,
+    /// Do not check the backends and the state file at startup.
+    #[clap(long, env)]
+    skip_checks: bool,
+    /// How long to wait for the backends to become ready at startup,
+    /// in seconds, before giving up. Zero waits indefinitely.
+    #[clap(long, env, default_value = "300")]
+    startup_timeout_secs: u64,
+    /// Also wait for the remote-write endpoint at startup, not just
+    /// for OpenSearch.
+    #[clap(long, env)]
+    startup_wait_prometheus: bool,
+    /// Identifies this engine in the heartbeat series. Defaults to the
+    /// host name.
+    #[clap(long, env, default_value_t = hostname())]
+    instance_id: String,
+    /// The shard of the traces processed by this replica, when running
+    /// several replicas (see `--shard-count`). Each replica needs its
+    /// own state file.
+    #[clap(long, env, default_value = "0")]
+    shard_index: u32,
+    /// The number of replicas sharing the traces. Their series carry a
+    /// `shard` label. Changing it starts the groups over.
+    #[clap(long, env, default_value = "1")]
+    shard_count: u32,
+    /// Check the backends and the state file, print the results and
+    /// exit without processing. Exits non-zero when a check fails.
+    #[clap(long)]
+    check: bool,
     #[clap(long)]
     spec: bool,
 }
 
 const INDEX: &str = "jaeger-span-*";
-const KEEP_ALIVE: EsKeepAlive = EsKeepAlive::Minutes(5);
+/// The PIT keep-alive until the duration of a tick is known.
+const KEEP_ALIVE: std::time::Duration = std::time::Duration::from_secs(300);
 
 // Max number of roots to retrieve per query.
 const BATCH_SIZE: usize = 1000;
@@ -84,14 +253,54 @@ async fn run(args: &Args) -> Result<()> {
         return Ok(());
     }
 
+    if args.check {
+        let checks = check::run(args).await;
+        checks.iter().for_each(|check| println!("{check}"));
+        return check::ensure_passed(&checks);
+    }
+
+    if args.mode == Mode::Web {
+        let config = args
+            .processor_url
+            .as_ref()
+            .map(|url| Arc::new(RemoteProcessor::new(url.clone())) as Arc<dyn ConfigStore>);
+        return run_web_server(
+            args,
+            AppData {
+                config,
+                processor: None,
+                info: EngineInfo::new(args),
+                command_timeout: std::time::Duration::from_millis(args.command_timeout_ms),
+            },
+        )
+        .await;
+    }
+
+    if !args.skip_checks {
+        let checks = check::run(args).await;
+        checks.iter().for_each(|check| match check.outcome {
+            check::Outcome::Failed => log::error!("{check}"),
+            check::Outcome::Unavailable => log::warn!("{check}"),
+            _ => log::info!("{check}"),
+        });
+        check::ensure_startable(&checks)?;
+    }
+
     let processor = Arc::new(Processor::new(args).await?);
-    run_web_server(
-        args,
-        AppData {
-            processor: processor.clone(),
-        },
-    )
-    .await?;
+    tokio::select! {
+        result = run_web_server(
+            args,
+            AppData {
+                config: Some(processor.clone() as Arc<dyn ConfigStore>),
+                processor: Some(processor.clone() as Arc<dyn ProcessorControl>),
+                info: EngineInfo::new(args),
+                command_timeout: std::time::Duration::from_millis(args.command_timeout_ms),
+            },
+        ) => result?,
+        // The web server is up while the processor waits for the
+        // backends; give up when they do not come up in time.
+        Err(e) = processor.started() => return Err(e),
+    }
 
     if let Err(e) = Arc::try_unwrap(processor)
         .map_err(|_| Error::ProcessorShutdown)?
@@ -103,3 +312,13 @@ async fn run(args: &Args) -> Result<()> {
 
     Ok(())
 }
+
+/// The host name, as set by the container runtime or the kernel.
+fn hostname() -> String {
+    std::env::var("HOSTNAME")
+        .ok()
+        .or_else(|| std::fs::read_to_string("/proc/sys/kernel/hostname").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| String::from("localhost"))
+}
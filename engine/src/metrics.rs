@@ -2,27 +2,56 @@
  * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
  ******************************************************************************/
 
-use std::collections::BTreeMap;
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, BTreeSet},
+    hash::{Hash, Hasher},
+    sync::{Arc, Mutex},
+};
 
 use chrono::{DateTime, Utc};
 use jaeger_anomaly_detection::{ImmediateInterval, ReferenceInterval};
 use prometheus_remote_write::{Label, TimeSeries, WriteRequest};
 
 use crate::{
-    config::ConfigName,
+    config::{ConfigName, MetricName, SpanKey},
     jaeger::{Bool, TagValue},
-    processor::trace::MetricArgs,
+    processor::{
+        anomaly_score::Seasonality,
+        series_limit::{SeriesPriority, SeriesReport},
+        trace::MetricArgs,
+    },
 };
 
 #[derive(Default)]
 pub struct Metrics(BTreeMap<BTreeMap<String, String>, Vec<prometheus_remote_write::Sample>>);
 
+/// The labels of a group: the config label and the labels of the
+/// group key. Computed once when the group is created and shared by
+/// all of its series. Self-monitoring metrics use the empty set.
+#[derive(Clone, Default, Debug)]
+pub(crate) struct GroupLabels(Arc<BTreeMap<String, String>>);
+
 #[derive(Default)]
 pub struct Labels {
     pub q: Option<String>,
     pub le: Option<String>,
     pub immediate: Option<ImmediateInterval>,
     pub reference: Option<ReferenceInterval>,
+    /// The seasonality of the reference statistics, if any.
+    pub seasonal: Option<Seasonality>,
+    pub rule_group: Option<usize>,
+    /// The `by` value of a breakdown source (see `MetricSource::RateBy`).
+    pub reason: Option<String>,
+    /// The engine instance of the heartbeat series.
+    pub instance_id: Option<String>,
+    /// Set on samples of a reference window longer than the age of
+    /// the group (see `AnomalyScoreConfig::warm_up`).
+    pub warming_up: bool,
+    /// The span metric of a self-monitoring series.
+    pub metric: Option<MetricName>,
+    /// What happened to the values counted by a self-monitoring
+    /// series, e.g. "dropped".
+    pub action: Option<&'static str>,
 }
 
 impl Metrics {
@@ -38,21 +67,98 @@ impl Metrics {
         self.0.values().map(|samples| samples.len()).sum()
     }
 
+    /// The label sets of all series.
+    pub fn series(&self) -> impl Iterator<Item = &BTreeMap<String, String>> {
+        self.0.keys()
+    }
+
+    /// Split off whole series, up to `max` samples (but at least one
+    /// series). A series' samples are never divided over two requests.
     pub fn split_off(&mut self, max: usize) -> Self {
-        // TODO: use extract_if when stabilized, or find some other
-        // more efficient means of doing this
-        let metrics = std::mem::take(&mut self.0);
         let mut r = BTreeMap::new();
-        metrics.into_iter().enumerate().for_each(|(i, (k, v))| {
-            if i < max {
-                r.insert(k, v);
-            } else {
-                self.0.insert(k, v);
+        let mut n = 0;
+        while let Some(entry) = self.0.first_entry() {
+            let len = entry.get().len();
+            if !r.is_empty() && n + len > max {
+                break;
             }
-        });
+            n += len;
+            let (labels, samples) = entry.remove_entry();
+            r.insert(labels, samples);
+        }
         Self(r)
     }
 
+    /// Remove the given series. Returns the number of series removed.
+    pub fn remove_series(&mut self, series: &[BTreeMap<String, String>]) -> usize {
+        series
+            .iter()
+            .filter(|labels| self.0.remove(*labels).is_some())
+            .count()
+    }
+
+    /// Divide the series over `n` lanes by a hash of their labels, so
+    /// that a series always ends up in the same lane.
+    pub fn into_lanes(self, n: usize) -> Vec<Metrics> {
+        let mut lanes = (0..n.max(1)).map(|_| Metrics::new()).collect::<Vec<_>>();
+        let count = lanes.len() as u64;
+        self.0.into_iter().for_each(|(labels, samples)| {
+            let mut hasher = DefaultHasher::new();
+            labels.hash(&mut hasher);
+            lanes[(hasher.finish() % count) as usize]
+                .0
+                .insert(labels, samples);
+        });
+        lanes
+    }
+
+    /// Move all samples from `other` into these metrics.
+    pub fn append(&mut self, other: Metrics) {
+        other.0.into_iter().for_each(|(labels, samples)| {
+            self.0.entry(labels).or_default().extend(samples);
+        });
+    }
+
+    /// Add a label to all series, e.g. the shard of the engine.
+    pub fn add_label(&mut self, name: &str, value: &str) {
+        self.0 = std::mem::take(&mut self.0)
+            .into_iter()
+            .map(|(mut labels, samples)| {
+                labels.insert(name.to_string(), value.to_string());
+                (labels, samples)
+            })
+            .collect();
+    }
+
+    /// Limit the number of series to `max`, dropping the lowest priority
+    /// series first (see `SeriesPriority`). Within a priority class,
+    /// series are kept in label order, so that the same series are kept
+    /// from one sample to the next. Returns the series counts per config
+    /// before truncation and the number of dropped series.
+    pub fn truncate(&mut self, max: usize) -> SeriesReport {
+        let mut report = SeriesReport::default();
+        let mut ranked = self.0.keys().collect::<Vec<_>>();
+        ranked.sort_by_key(|labels| std::cmp::Reverse(series_priority(labels)));
+        ranked.iter().for_each(|labels| {
+            *report.series.entry(series_config(labels)).or_default() += 1;
+        });
+        let dropped = ranked
+            .into_iter()
+            .skip(max)
+            .filter(|labels| series_priority(labels) != SeriesPriority::SelfMonitoring)
+            .cloned()
+            .collect::<Vec<_>>();
+        dropped.iter().for_each(|labels| {
+            *report.dropped.entry(series_config(labels)).or_default() += 1;
+            *report
+                .dropped_by_priority
+                .entry(series_priority(labels))
+                .or_default() += 1;
+        });
+        self.remove_series(&dropped);
+        report
+    }
+
     pub fn insert(&mut self, labels: BTreeMap<String, String>, t: DateTime<Utc>, value: f64) {
         self.0
             .entry(labels)
@@ -63,17 +169,98 @@ impl Metrics {
             })
     }
 
-    pub fn into_write_request(self) -> WriteRequest {
+    /// Remove all samples, with their series labels.
+    pub fn drain(
+        &mut self,
+    ) -> impl Iterator<Item = (BTreeMap<String, String>, DateTime<Utc>, f64)> {
+        std::mem::take(&mut self.0)
+            .into_iter()
+            .flat_map(|(labels, samples)| {
+                samples.into_iter().map(move |sample| {
+                    (
+                        labels.clone(),
+                        DateTime::from_timestamp_millis(sample.timestamp).unwrap(),
+                        sample.value,
+                    )
+                })
+            })
+    }
+
+    /// Sort the samples of each series by timestamp, keeping only the
+    /// last inserted sample per timestamp, e.g. when a bin was
+    /// re-emitted after a correction. Returns the number of dropped
+    /// samples.
+    pub fn dedup(&mut self) -> usize {
+        self.0
+            .values_mut()
+            .map(|samples| {
+                let len = samples.len();
+                // The sort is stable: after reversing, the last
+                // inserted sample comes first among equal timestamps.
+                samples.reverse();
+                samples.sort_by_key(|sample| sample.timestamp);
+                samples.dedup_by_key(|sample| sample.timestamp);
+                len - samples.len()
+            })
+            .sum()
+    }
+
+    /// The samples as `name{label="value",...} value @timestamp`
+    /// lines, by series and timestamp, for comparison in tests.
+    #[cfg(test)]
+    pub fn to_debug_lines(&self) -> Vec<String> {
+        self.0
+            .iter()
+            .flat_map(|(labels, samples)| {
+                let name = labels.get("__name__").map_or("", String::as_str);
+                let labels = ordered_labels(labels)
+                    .filter(|(label, _)| *label != "__name__")
+                    .map(|(label, value)| {
+                        let value = value
+                            .replace('\\', "\\\\")
+                            .replace('"', "\\\"")
+                            .replace('\n', "\\n");
+                        format!("{label}=\"{value}\"")
+                    })
+                    .collect::<Vec<_>>()
+                    .join(",");
+                let mut samples = samples
+                    .iter()
+                    .map(|sample| (sample.timestamp, sample.value))
+                    .collect::<Vec<_>>();
+                samples.sort_by_key(|(timestamp, _)| *timestamp);
+                samples.into_iter().map(move |(timestamp, value)| {
+                    format!("{name}{{{labels}}} {value} @{timestamp}")
+                })
+            })
+            .collect()
+    }
+
+    /// Build a write request, with each series' samples sorted by
+    /// timestamp and its labels in `ordered_labels` order.
+    pub fn write_request(&self) -> WriteRequest {
         WriteRequest {
             timeseries: self
                 .0
-                .into_iter()
-                .map(|(labels, samples)| TimeSeries {
-                    labels: labels
-                        .into_iter()
-                        .map(|(name, value)| Label { name, value })
-                        .collect(),
-                    samples,
+                .iter()
+                .map(|(labels, samples)| {
+                    let mut samples = samples
+                        .iter()
+                        .map(|sample| prometheus_remote_write::Sample {
+                            value: sample.value,
+                            timestamp: sample.timestamp,
+                        })
+                        .collect::<Vec<_>>();
+                    samples.sort_by_key(|sample| sample.timestamp);
+                    TimeSeries {
+                        labels: ordered_labels(labels)
+                            .map(|(name, value)| Label {
+                                name: name.clone(),
+                                value: value.clone(),
+                            })
+                            .collect(),
+                        samples,
+                    }
                 })
                 .collect(),
         }
@@ -86,32 +273,647 @@ impl Metrics {
         t: DateTime<Utc>,
         value: f64,
     ) {
-        let mut labels = BTreeMap::new();
-        labels.insert(String::from("__name__"), metric.metric_name);
+        let mut labels = BTreeMap::clone(&metric.group.0);
+        labels.insert(
+            String::from("__name__"),
+            sanitize_metric_name(metric.metric_name),
+        );
         labels.insert(String::from("metric_type"), metric.metric_type.to_string());
-        labels.insert(String::from("config"), config_name.to_string());
-        for (name, value) in metric.key {
-            let label = name.label().into_string();
-            let value = match value {
-                TagValue::String(s) => s.to_string(),
-                TagValue::Int64(v) => format!("{}", v.0),
-                TagValue::Bool(Bool::True) => String::from("true"),
-                TagValue::Bool(Bool::False) => String::from("false"),
-            };
-            labels.insert(label, value);
-        }
+        labels
+            .entry(String::from("config"))
+            .or_insert_with(|| config_name.to_string());
         if let Some(interval) = metric.labels.immediate {
             labels.insert(String::from("immediate"), interval.to_string());
         }
         if let Some(interval) = metric.labels.reference {
             labels.insert(String::from("reference"), interval.to_string());
         }
+        if let Some(seasonality) = metric.labels.seasonal {
+            labels.insert(String::from("seasonal"), seasonality.to_string());
+        }
         if let Some(le) = metric.labels.le {
             labels.insert(String::from("le"), le);
         }
         if let Some(q) = metric.labels.q {
             labels.insert(String::from("quantile"), q);
         }
+        if let Some(group) = metric.labels.rule_group {
+            labels.insert(String::from("rule_group"), group.to_string());
+        }
+        if let Some(reason) = metric.labels.reason {
+            labels.insert(String::from("reason"), reason);
+        }
+        if let Some(instance_id) = metric.labels.instance_id {
+            labels.insert(String::from("instance_id"), instance_id);
+        }
+        if metric.labels.warming_up {
+            labels.insert(String::from("warming_up"), String::from("true"));
+        }
+        if let Some(name) = metric.labels.metric {
+            labels.insert(String::from("metric"), name.to_string());
+        }
+        if let Some(action) = metric.labels.action {
+            labels.insert(String::from("action"), action.to_string());
+        }
+        sanitize_labels(&mut labels);
         self.insert(labels, t, value);
     }
+
+    /// Remove the series for which `encodes` fails, by splitting the
+    /// series in halves until the failing series are isolated. Returns
+    /// the label sets of the removed series.
+    pub fn remove_failing<F: FnMut(&Metrics) -> bool>(
+        &mut self,
+        mut encodes: F,
+    ) -> Vec<BTreeMap<String, String>> {
+        let mut failing = Vec::new();
+        let mut parts = vec![std::mem::take(self)];
+        while let Some(mut part) = parts.pop() {
+            if encodes(&part) {
+                self.append(part);
+            } else if part.0.len() == 1 {
+                failing.extend(part.0.into_keys());
+            } else {
+                let half = part.0.keys().nth(part.0.len() / 2).unwrap().clone();
+                let other = Self(part.0.split_off(&half));
+                parts.push(part);
+                parts.push(other);
+            }
+        }
+        failing
+    }
+}
+
+impl GroupLabels {
+    pub(crate) fn new(config_name: &ConfigName, key: &BTreeMap<SpanKey, TagValue>) -> Self {
+        let mut labels = BTreeMap::from_iter([(String::from("config"), config_name.to_string())]);
+        labels.extend(key_labels(key));
+        Self(Arc::new(labels))
+    }
+
+    /// These labels with annotation labels added. Annotations never
+    /// override the group labels.
+    pub(crate) fn annotated(&self, annotations: &BTreeMap<SpanKey, TagValue>) -> Self {
+        if annotations.is_empty() {
+            return self.clone();
+        }
+        let mut labels = BTreeMap::clone(&self.0);
+        key_labels(annotations).for_each(|(label, value)| {
+            labels.entry(label).or_insert(value);
+        });
+        Self(Arc::new(labels))
+    }
+
+    #[cfg(test)]
+    pub(crate) fn get(&self, label: &str) -> Option<&str> {
+        self.0.get(label).map(String::as_str)
+    }
+}
+
+/// The labels for a group key.
+pub(crate) fn key_labels(
+    key: &BTreeMap<SpanKey, TagValue>,
+) -> impl Iterator<Item = (String, String)> + '_ {
+    key.iter().map(|(name, value)| {
+        let label = name.label().into_string();
+        let value = sanitize_label_value(&label, label_value(value));
+        (label, value)
+    })
+}
+
+/// The label value for a span key value.
+pub(crate) fn label_value(value: &TagValue) -> String {
+    match value {
+        TagValue::String(s) => s.to_string(),
+        TagValue::Int64(v) => format!("{}", v.0),
+        TagValue::Bool(Bool::True) => String::from("true"),
+        TagValue::Bool(Bool::False) => String::from("false"),
+    }
+}
+
+fn series_priority(labels: &BTreeMap<String, String>) -> SeriesPriority {
+    SeriesPriority::of(labels.get("metric_type").map_or("", String::as_str))
+}
+
+fn series_config(labels: &BTreeMap<String, String>) -> ConfigName {
+    ConfigName::new(labels.get("config").cloned().unwrap_or_default())
+}
+
+/// Whether `name` is a valid Prometheus metric name.
+pub(crate) fn is_valid_metric_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == ':')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+}
+
+/// Names already reported as sanitized, to log each of them only once.
+static SANITIZED: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+fn warn_once(key: String, msg: impl FnOnce() -> String) {
+    if SANITIZED.lock().unwrap().insert(key) {
+        log::warn!("{}", msg());
+    }
+}
+
+/// Replace characters not allowed in metric names by `_`. Invalid
+/// names are rejected when the config is applied, but a single bad
+/// name would fail the whole remote write request.
+fn sanitize_metric_name(name: String) -> String {
+    if is_valid_metric_name(&name) {
+        return name;
+    }
+    let sanitized = name
+        .chars()
+        .enumerate()
+        .map(|(i, c)| match c {
+            'a'..='z' | 'A'..='Z' | '_' | ':' => c,
+            '0'..='9' if i > 0 => c,
+            _ => '_',
+        })
+        .collect::<String>();
+    warn_once(format!("metric {name}"), || {
+        format!("invalid metric name {name:?}; sending as {sanitized:?}")
+    });
+    sanitized
+}
+
+/// Replace control characters (e.g. NUL bytes from corrupted tags) in
+/// label values by `_`.
+fn sanitize_label_value(label: &str, value: String) -> String {
+    if !value.contains(char::is_control) {
+        return value;
+    }
+    warn_once(format!("label {label}"), || {
+        format!("label {label} has values containing control characters; replacing them with '_'")
+    });
+    value.replace(char::is_control, "_")
+}
+
+/// Make sure a series' labels can be encoded: labels with an empty
+/// name are dropped and control characters in values replaced. Names
+/// and values are `String`s, so UTF-8 is already guaranteed.
+fn sanitize_labels(labels: &mut BTreeMap<String, String>) {
+    if labels.remove("").is_some() {
+        warn_once(String::from("empty label"), || {
+            String::from("dropping labels with an empty name")
+        });
+    }
+    labels.iter_mut().for_each(|(label, value)| {
+        if value.contains(char::is_control) {
+            *value = sanitize_label_value(label, std::mem::take(value));
+        }
+    });
+}
+
+/// The labels of a series in the order they are written: `__name__`
+/// first, as expected by some remote-write receivers, then the other
+/// labels in lexicographic order.
+fn ordered_labels(labels: &BTreeMap<String, String>) -> impl Iterator<Item = (&String, &String)> {
+    labels
+        .get_key_value("__name__")
+        .into_iter()
+        .chain(labels.iter().filter(|(label, _)| *label != "__name__"))
+}
+
+/// Extract the series rejected as out-of-order from a remote-write
+/// error response. Cortex and Mimir report these as "out of order
+/// sample" / "sample-out-of-order" errors, followed by the label set of
+/// the offending series.
+pub fn out_of_order_series(body: &str) -> Vec<BTreeMap<String, String>> {
+    let lower = body.to_ascii_lowercase();
+    let mut series = Vec::new();
+    let mut pos = 0;
+    while let Some(start) = ["out of order", "out-of-order"]
+        .iter()
+        .filter_map(|pat| lower[pos..].find(pat).map(|i| pos + i + pat.len()))
+        .min()
+    {
+        pos = start;
+        let Some(labels) = lower[pos..].find("series").map(|i| pos + i) else {
+            break;
+        };
+        let Some(open) = body[labels..].find('{').map(|i| labels + i) else {
+            break;
+        };
+        match parse_label_set(&body[open..]) {
+            Some((set, len)) => {
+                series.push(set);
+                pos = open + len;
+            }
+            None => pos = open + 1,
+        }
+    }
+    series
+}
+
+/// Parse a label set like `{__name__="x", a="b"}`. Returns the labels
+/// and the length of the parsed text.
+fn parse_label_set(text: &str) -> Option<(BTreeMap<String, String>, usize)> {
+    let mut labels = BTreeMap::new();
+    let mut chars = text.char_indices().peekable();
+    if chars.next()?.1 != '{' {
+        return None;
+    }
+    loop {
+        while chars
+            .next_if(|(_, c)| c.is_whitespace() || *c == ',')
+            .is_some()
+        {}
+        let (i, c) = chars.next()?;
+        if c == '}' {
+            return Some((labels, i + 1));
+        }
+        let mut name = String::from(c);
+        loop {
+            match chars.next()?.1 {
+                '=' => break,
+                c => name.push(c),
+            }
+        }
+        if chars.next()?.1 != '"' {
+            return None;
+        }
+        let mut value = String::new();
+        loop {
+            match chars.next()?.1 {
+                '"' => break,
+                '\\' => match chars.next()?.1 {
+                    'n' => value.push('\n'),
+                    c => value.push(c),
+                },
+                c => value.push(c),
+            }
+        }
+        labels.insert(name.trim().to_string(), value);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::BTreeMap, sync::Arc};
+
+    use chrono::{DateTime, TimeDelta};
+    use jaeger_anomaly_detection::{OperationKey, ServiceKey};
+
+    use super::{is_valid_metric_name, out_of_order_series, GroupLabels, Labels, Metrics};
+    use crate::{
+        config::{Ancestors, ConfigName, KeyName, SpanKey},
+        jaeger::{Tag, TagValue},
+        processor::{
+            series_limit::SeriesPriority,
+            sim::span,
+            trace::{MetricArgs, TraceConfig},
+        },
+    };
+
+    fn labels(name: &str) -> BTreeMap<String, String> {
+        BTreeMap::from_iter([(String::from("__name__"), name.to_string())])
+    }
+
+    #[test]
+    fn truncate_by_priority() {
+        let t = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let mut metrics = Metrics::new();
+        let series = |metric_type: &str, config: &str, i: usize| {
+            BTreeMap::from_iter([
+                (String::from("__name__"), format!("trace_{metric_type}_{i}")),
+                (String::from("metric_type"), metric_type.to_string()),
+                (String::from("config"), config.to_string()),
+            ])
+        };
+        for (metric_type, config) in [
+            ("anomaly_score", "default"),
+            ("count_sum", "default"),
+            ("welford", "default"),
+            ("summary", "operation-relations"),
+            ("histogram", "default"),
+            ("self_monitoring", "trace"),
+        ] {
+            for i in 0..2 {
+                metrics.insert(series(metric_type, config, i), t, 1.0);
+            }
+        }
+
+        let report = metrics.truncate(5);
+        assert_eq!(report.total(), 12);
+        assert_eq!(report.total_dropped(), 7);
+        assert_eq!(
+            report.dropped,
+            BTreeMap::from_iter([
+                (ConfigName::new("default"), 5),
+                (ConfigName::new("operation-relations"), 2)
+            ])
+        );
+        assert_eq!(
+            report.dropped_by_priority,
+            BTreeMap::from_iter([
+                (SeriesPriority::Histogram, 2),
+                (SeriesPriority::Summary, 2),
+                (SeriesPriority::Welford, 2),
+                (SeriesPriority::Count, 1),
+            ])
+        );
+        // The first count series (in label order) is kept.
+        let kept = metrics
+            .drain()
+            .map(|(labels, _, _)| labels["__name__"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kept,
+            [
+                "trace_anomaly_score_0",
+                "trace_anomaly_score_1",
+                "trace_count_sum_0",
+                "trace_self_monitoring_0",
+                "trace_self_monitoring_1"
+            ]
+        );
+
+        // Self-monitoring series are kept over the limit.
+        metrics.insert(series("self_monitoring", "trace", 0), t, 1.0);
+        metrics.insert(series("histogram", "default", 0), t, 1.0);
+        let report = metrics.truncate(0);
+        assert_eq!(metrics.len(), 1);
+        assert_eq!(
+            report.dropped_by_priority,
+            BTreeMap::from_iter([(SeriesPriority::Histogram, 1)])
+        );
+    }
+
+    #[test]
+    fn split_off_keeps_series_whole() {
+        let t = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let mut metrics = Metrics::new();
+        for i in 0..3 {
+            metrics.insert(labels("a"), t + TimeDelta::minutes(i), 1.0);
+            metrics.insert(labels("b"), t + TimeDelta::minutes(i), 1.0);
+        }
+        metrics.insert(labels("c"), t, 1.0);
+
+        let first = metrics.split_off(4);
+        assert_eq!(first.len(), 3);
+        let second = metrics.split_off(4);
+        assert_eq!(second.len(), 4);
+        assert!(metrics.is_empty());
+
+        // A series larger than the limit is still written whole.
+        let mut metrics = Metrics::new();
+        (0..3).for_each(|i| metrics.insert(labels("a"), t + TimeDelta::minutes(i), 1.0));
+        assert_eq!(metrics.split_off(2).len(), 3);
+    }
+
+    #[test]
+    fn add_label_to_all_series() {
+        let t = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let mut metrics = Metrics::new();
+        metrics.insert(labels("a"), t, 1.0);
+        metrics.insert(labels("a"), t + TimeDelta::minutes(1), 2.0);
+        metrics.insert(labels("b"), t, 3.0);
+        metrics.add_label("shard", "2");
+        assert_eq!(metrics.len(), 3);
+        assert!(metrics.series().all(|labels| labels["shard"] == "2"));
+    }
+
+    #[test]
+    fn write_request_sorts_samples() {
+        let t = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let mut metrics = Metrics::new();
+        metrics.insert(labels("a"), t + TimeDelta::minutes(1), 1.0);
+        metrics.insert(labels("a"), t, 2.0);
+        let req = metrics.write_request();
+        let timestamps = req.timeseries[0]
+            .samples
+            .iter()
+            .map(|sample| sample.timestamp)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            timestamps,
+            vec![t.timestamp_millis(), t.timestamp_millis() + 60_000]
+        );
+    }
+
+    #[test]
+    fn write_request_name_first() {
+        let t = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let mut metrics = Metrics::new();
+        let mut series = labels("a");
+        // Upper case labels sort before `__name__` in the map.
+        series.insert(String::from("Zone"), String::from("eu"));
+        series.insert(String::from("config"), String::from("default"));
+        metrics.insert(series, t, 1.0);
+        let req = metrics.write_request();
+        let names = req.timeseries[0]
+            .labels
+            .iter()
+            .map(|label| label.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["__name__", "Zone", "config"]);
+    }
+
+    #[test]
+    fn dedup_keeps_last_sample() {
+        let t = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let mut metrics = Metrics::new();
+        let mut series = labels("a");
+        series.insert(String::from("operation_name"), String::from("GET \"/\""));
+        metrics.insert(series.clone(), t + TimeDelta::minutes(1), 1.0);
+        metrics.insert(series.clone(), t, 2.0);
+        metrics.insert(series, t + TimeDelta::minutes(1), 3.0);
+        metrics.insert(labels("b"), t, 4.0);
+        assert_eq!(metrics.dedup(), 1);
+        assert_eq!(metrics.len(), 3);
+        assert_eq!(metrics.dedup(), 0);
+        assert_eq!(
+            metrics.to_debug_lines(),
+            [
+                "a{operation_name=\"GET \\\"/\\\"\"} 2 @1700000000000",
+                "a{operation_name=\"GET \\\"/\\\"\"} 3 @1700000060000",
+                "b{} 4 @1700000000000",
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_out_of_order_series() {
+        let cortex = "user=fake: err: out of order sample. timestamp=2023-11-14T22:13:20Z, \
+                      series={__name__=\"trace_duration_count\", config=\"default\", \
+                      service_name=\"a \\\"quoted\\\" name\"}";
+        assert_eq!(
+            out_of_order_series(cortex),
+            vec![BTreeMap::from_iter([
+                (
+                    String::from("__name__"),
+                    String::from("trace_duration_count")
+                ),
+                (String::from("config"), String::from("default")),
+                (
+                    String::from("service_name"),
+                    String::from("a \"quoted\" name")
+                ),
+            ])]
+        );
+
+        let mimir = "failed pushing to ingester: the sample has been rejected because another \
+                     sample with a more recent timestamp has already been ingested and \
+                     out-of-order samples are not allowed (err-mimir-sample-out-of-order). \
+                     The affected sample has timestamp 2023-11-14T22:13:20Z and is from \
+                     series {__name__=\"x\"}";
+        assert_eq!(out_of_order_series(mimir), vec![labels("x")]);
+
+        assert!(out_of_order_series("out of order sample").is_empty());
+        assert!(out_of_order_series("series={__name__=\"x\"}").is_empty());
+    }
+
+    #[test]
+    fn sanitize_emitted_names() {
+        assert!(is_valid_metric_name("trace_duration_mean"));
+        assert!(!is_valid_metric_name("trace_p99.9_mean"));
+        assert!(!is_valid_metric_name("1trace"));
+
+        let t = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let key = BTreeMap::from_iter([(
+            SpanKey::Current(KeyName::ServiceName),
+            TagValue::String(String::from("front\0end")),
+        )]);
+        let mut metrics = Metrics::new();
+        metrics.add_metric(
+            MetricArgs {
+                metric_name: String::from("trace_error rate_count"),
+                metric_type: "welford",
+                labels: Labels::default(),
+                group: &GroupLabels::new(&ConfigName::new("default"), &key),
+            },
+            &ConfigName::new("default"),
+            t,
+            1.0,
+        );
+        let (labels, _, _) = metrics.drain().next().unwrap();
+        assert_eq!(labels["__name__"], "trace_error_rate_count");
+        assert_eq!(labels["service_name"], "front_end");
+    }
+
+    #[test]
+    fn sanitize_label_sets() {
+        let t = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let group = GroupLabels(Arc::new(BTreeMap::from_iter([
+            (String::new(), String::from("corrupt")),
+            (
+                String::from("service_name"),
+                String::from("front\tend\u{7f}"),
+            ),
+        ])));
+        let mut metrics = Metrics::new();
+        metrics.add_metric(
+            MetricArgs {
+                metric_name: String::from("trace_duration_count"),
+                metric_type: "welford",
+                labels: Labels {
+                    reason: Some(String::from("time\0out")),
+                    ..Labels::default()
+                },
+                group: &group,
+            },
+            &ConfigName::new("default"),
+            t,
+            1.0,
+        );
+        let (labels, _, _) = metrics.drain().next().unwrap();
+        assert!(!labels.contains_key(""));
+        assert_eq!(labels["service_name"], "front_end_");
+        assert_eq!(labels["reason"], "time_out");
+    }
+
+    /// A poisoned series is isolated; the rest of the batch is kept.
+    #[test]
+    fn remove_failing_series() {
+        let t = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let mut metrics = Metrics::new();
+        (0..10).for_each(|i| metrics.insert(labels(&format!("m{i}")), t, i as f64));
+        let mut poisoned = labels("m3");
+        poisoned.insert(String::from("poison"), String::new());
+        metrics.insert(poisoned.clone(), t, 3.0);
+
+        let mut attempts = 0;
+        let failing = metrics.remove_failing(|part| {
+            attempts += 1;
+            part.series().all(|labels| !labels.contains_key("poison"))
+        });
+        assert_eq!(failing, [poisoned]);
+        assert_eq!(metrics.len(), 10);
+        assert!(metrics
+            .series()
+            .all(|labels| !labels.contains_key("poison")));
+        assert!(attempts < 10, "{attempts}");
+
+        assert!(metrics.remove_failing(|_| true).is_empty());
+        assert_eq!(metrics.len(), 10);
+    }
+
+    /// Contract with the lib: the keys used in its expressions must use
+    /// the label names emitted for the default configs.
+    #[test]
+    fn lib_key_labels() {
+        let t = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let config = TraceConfig::default();
+        let frontend = span("t", "1", None, "frontend", "GET", 0, 3000);
+        let mut backend = span("t", "2", Some("1"), "backend", "POST", 500, 2000);
+        backend.tags.push(Tag {
+            key: String::from("span.kind"),
+            value: TagValue::String(String::from("server")),
+        });
+        let ancestors = Ancestors {
+            parent: Some(&frontend),
+            grandparent: None,
+        };
+        let emitted = |config_name: &str| {
+            let config_name = ConfigName::new(config_name);
+            let key = config.configs[&config_name]
+                .key
+                .iter()
+                .filter_map(|key| Some((key.clone(), key.get(&backend, ancestors)?.to_owned())))
+                .collect::<BTreeMap<_, _>>();
+            let mut metrics = Metrics::new();
+            metrics.add_metric(
+                MetricArgs {
+                    metric_name: String::from("trace_duration_count"),
+                    metric_type: "welford",
+                    labels: Labels::default(),
+                    group: &GroupLabels::new(&config_name, &key),
+                },
+                &config_name,
+                t,
+                1.0,
+            );
+            metrics.drain().next().unwrap().0
+        };
+        let service = ServiceKey::new("backend")
+            .namespace("test")
+            .instance_id("test-0");
+        let operation = OperationKey::new(service.clone(), "POST").span_kind("server");
+
+        let labels = emitted("default");
+        assert_eq!(OperationKey::from_labels(&labels), Some(operation.clone()));
+        assert!(
+            operation
+                .to_label_map()
+                .iter()
+                .all(|(name, value)| labels.get(name) == Some(value)),
+            "{labels:?}"
+        );
+
+        let labels = emitted("service-relations");
+        assert_eq!(ServiceKey::from_labels(&labels), Some(service));
+        let parent = ServiceKey::new("frontend")
+            .namespace("test")
+            .instance_id("test-0");
+        assert!(
+            parent
+                .parent_labels()
+                .all(|(name, _)| labels.contains_key(&name.into_string())),
+            "{labels:?}"
+        );
+        assert_eq!(labels["parent_service_name"], "frontend");
+    }
 }
@@ -61,6 +61,21 @@ impl<T> EsResponse<T> {
     }
 }
 
+impl EsError {
+    /// Whether the request was rejected by a circuit breaker, because
+    /// the cluster is short on memory.
+    pub fn is_circuit_breaker(&self) -> bool {
+        self.error.reason.r#type == "circuit_breaking_exception"
+    }
+
+    /// Whether the request was rejected because of a script, e.g.
+    /// because inline scripts are disabled.
+    pub fn is_script_error(&self) -> bool {
+        self.error.reason.r#type == "script_exception"
+            || self.error.reason.reason.contains("script")
+    }
+}
+
 impl Display for EsError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "status #{}: {}", self.status, self.error)
@@ -91,6 +106,13 @@ pub struct EsSearchRequest<T, S> {
     pub sort: Option<Vec<EsSortField>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub search_after: Option<S>,
+    #[serde(rename = "_source", skip_serializing_if = "Option::is_none")]
+    pub source: Option<EsSourceFilter>,
+}
+
+#[derive(Serialize)]
+pub struct EsSourceFilter {
+    pub excludes: Vec<&'static str>,
 }
 
 pub struct EsSortField {
@@ -179,6 +201,44 @@ fn default_sort<S>() -> Option<S> {
     None
 }
 
+/* Aggregations */
+
+#[derive(Serialize)]
+pub struct EsAggRequest<T, A> {
+    pub query: T,
+    pub size: usize,
+    pub aggs: A,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct EsAggResponse<A> {
+    pub aggregations: A,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct EsCompositeAgg<K, B> {
+    #[serde(default = "default_after_key")]
+    pub after_key: Option<K>,
+    pub buckets: Vec<B>,
+}
+
+fn default_after_key<K>() -> Option<K> {
+    None
+}
+
+#[derive(Deserialize, Debug)]
+pub struct EsExtendedStats {
+    pub count: u64,
+    pub avg: Option<f64>,
+    /// Population variance.
+    pub variance: Option<f64>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct EsFilterAgg {
+    pub doc_count: u64,
+}
+
 /* Point-in-time */
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -192,13 +252,20 @@ pub struct EsPit {
 
 #[derive(SerializeDisplay, Clone, Copy, Debug)]
 pub enum EsKeepAlive {
-    Minutes(u64),
+    Seconds(u64),
+}
+
+impl From<std::time::Duration> for EsKeepAlive {
+    /// The duration, rounded up to whole seconds.
+    fn from(duration: std::time::Duration) -> Self {
+        Self::Seconds(duration.as_secs_f64().ceil() as u64)
+    }
 }
 
 impl Display for EsKeepAlive {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            EsKeepAlive::Minutes(n) => write!(f, "{n}m"),
+            EsKeepAlive::Seconds(n) => write!(f, "{n}s"),
         }
     }
 }
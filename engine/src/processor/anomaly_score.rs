@@ -2,22 +2,29 @@
  * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
  ******************************************************************************/
 
-use std::collections::{BTreeMap, BTreeSet};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Display,
+};
 
-use chrono::{DateTime, Utc};
-use jaeger_anomaly_detection::{ImmediateInterval, ReferenceInterval};
+use chrono::{DateTime, Datelike, TimeDelta, Timelike, Utc};
+use jaeger_anomaly_detection::{ImmediateInterval, Interval, ReferenceInterval};
 use ordered_float::NotNan;
 use rustc_apfloat::{ieee::Quad, Float};
 use serde::{Deserialize, Serialize};
+use tdigest::TDigest;
 
 use crate::{
-    accum::Accum,
+    accum::{Accum, MergeAcc},
     metrics::Labels,
     welford::{from_f64, to_f64, Welford},
     window::Window,
 };
 
-use super::metric::MetricArgs;
+use super::{
+    baseline::{AnomalyScoreBaseline, BaselineSkip},
+    metric::MetricArgs,
+};
 
 #[derive(Serialize, Deserialize, schemars::JsonSchema, PartialEq, Eq, Clone, Debug)]
 pub struct AnomalyScoreConfig {
@@ -27,6 +34,68 @@ pub struct AnomalyScoreConfig {
     offset: ordered_float::NotNan<f64>,
     #[schemars(with = "f64")]
     q: ordered_float::NotNan<f64>,
+    #[serde(default)]
+    algorithm: AnomalyScoreAlgorithm,
+    /// The minimum call rate over an immediate window, in calls per
+    /// minute, for its statistics and scores to be emitted. Windows
+    /// below the threshold only emit their count.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(with = "Option<f64>")]
+    min_rate: Option<NotNan<f64>>,
+    /// Compare the immediate windows to the reference statistics of
+    /// the same hour of the day or week, instead of the whole
+    /// reference window. Only applies to the mean/ci algorithm.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    seasonal: Option<Seasonality>,
+    /// Mark the groups that were first seen less than a reference
+    /// interval ago: their samples for that reference interval get a
+    /// `warming_up="true"` label, and the group emits the time it was
+    /// first seen as `trace_group_first_seen_timestamp_seconds`.
+    #[serde(default)]
+    warm_up: bool,
+    /// Emit the offset per reference interval, as
+    /// `trace_<metric>_offset`, so that dashboards can reconstruct
+    /// the thresholds the scores were computed with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    offset_series: Option<OffsetSeries>,
+}
+
+/// Where the offset series are emitted.
+#[derive(Serialize, Deserialize, schemars::JsonSchema, PartialEq, Eq, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum OffsetSeries {
+    /// With every group, so that they join trivially with the scores.
+    Group,
+    /// Once per config, without the group labels.
+    Config,
+}
+
+/// The seasonal bins of the reference statistics, in UTC.
+#[derive(Serialize, Deserialize, schemars::JsonSchema, PartialEq, Eq, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Seasonality {
+    /// 24 bins, one per hour of the day.
+    HourOfDay,
+    /// 168 bins, one per hour of the week.
+    HourOfWeek,
+}
+
+#[derive(
+    Serialize, Deserialize, schemars::JsonSchema, PartialEq, Eq, Clone, Copy, Default, Debug,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum AnomalyScoreAlgorithm {
+    /// Compare the lower bound of the confidence interval on the
+    /// immediate mean to the upper bound on the reference mean.
+    #[default]
+    MeanCi,
+    /// Compare the `q_stat` quantile of the immediate window to that
+    /// of the reference window. Better suited for heavily skewed
+    /// distributions, where the mean is dominated by the tail.
+    Quantile {
+        #[schemars(with = "f64")]
+        q_stat: NotNan<f64>,
+    },
 }
 
 pub type AnomalyScoreState = AnomalyScoreProcessor;
@@ -37,27 +106,98 @@ pub struct AnomalyScoreProcessor {
     config: AnomalyScoreConfig,
     immediate: BTreeMap<ImmediateInterval, Window<Welford<Quad>>>,
     reference: BTreeMap<ReferenceInterval, Window<Welford<Quad>>>,
+    /// The digest windows of the quantile algorithm. When set, the
+    /// welford windows above are left empty.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    quantile: Option<QuantileWindows>,
+    /// The seasonal reference bins, if seasonality is configured. The
+    /// reference windows above are then left empty.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    seasonal: Option<SeasonalReference>,
+}
+
+/// Per-bin digests for the immediate and reference windows.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct QuantileWindows {
+    immediate: BTreeMap<ImmediateInterval, Window<TDigest>>,
+    reference: BTreeMap<ReferenceInterval, Window<TDigest>>,
+}
+
+/// Per reference interval, an accumulator for every seasonal bin.
+/// Values are routed to the bin of their timestamp. Instead of
+/// sliding out of a window, old values are decayed exponentially, with
+/// the length of the reference interval as time constant.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct SeasonalReference {
+    seasonality: Seasonality,
+    /// The latest insert; its bin is compared to the immediate
+    /// windows, which end at the same time.
+    last: DateTime<Utc>,
+    reference: BTreeMap<ReferenceInterval, Vec<SeasonalBin>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct SeasonalBin {
+    /// The time up to which the accumulator has been decayed.
+    decayed: DateTime<Utc>,
+    welford: Welford<Quad>,
 }
 
 impl AnomalyScoreProcessor {
     pub fn new(t: DateTime<Utc>, config: &AnomalyScoreConfig) -> Self {
-        Self {
-            welford: Welford::default(),
-            config: config.clone(),
-            immediate: config
-                .immediate_intervals
-                .iter()
-                .map(|interval| (*interval, Window::new(t, &interval.window_config())))
-                .collect(),
-            reference: config
-                .reference_intervals
-                .iter()
-                .map(|interval| (*interval, Window::new(t, &interval.window_config())))
-                .collect(),
+        match config.algorithm {
+            AnomalyScoreAlgorithm::MeanCi => Self {
+                welford: Welford::default(),
+                config: config.clone(),
+                immediate: config
+                    .immediate_intervals
+                    .iter()
+                    .map(|interval| (*interval, Window::new(t, &interval.window_config())))
+                    .collect(),
+                reference: match config.seasonal {
+                    Some(_) => BTreeMap::new(),
+                    None => config
+                        .reference_intervals
+                        .iter()
+                        .map(|interval| (*interval, Window::new(t, &interval.window_config())))
+                        .collect(),
+                },
+                quantile: None,
+                seasonal: config
+                    .seasonal
+                    .map(|seasonality| SeasonalReference::new(t, seasonality, config)),
+            },
+            AnomalyScoreAlgorithm::Quantile { .. } => Self {
+                welford: Welford::default(),
+                config: config.clone(),
+                immediate: BTreeMap::new(),
+                reference: BTreeMap::new(),
+                quantile: Some(QuantileWindows::new(t, config)),
+                seasonal: None,
+            },
         }
     }
 
+    /// Apply a new config. Windows whose shape did not change are kept;
+    /// switching algorithms restarts all statistics, since the
+    /// accumulated state cannot be converted. Likewise, changing the
+    /// seasonality restarts the reference statistics.
     pub fn update(&self, t: DateTime<Utc>, config: &AnomalyScoreConfig) -> Self {
+        if std::mem::discriminant(&self.config.algorithm)
+            != std::mem::discriminant(&config.algorithm)
+        {
+            return Self::new(t, config);
+        }
+        if let Some(quantile) = &self.quantile {
+            return Self {
+                welford: self.welford.clone(),
+                config: config.clone(),
+                immediate: BTreeMap::new(),
+                reference: BTreeMap::new(),
+                quantile: Some(quantile.update(t, config)),
+                seasonal: None,
+            };
+        }
         Self {
             welford: self.welford.clone(),
             config: config.clone(),
@@ -86,6 +226,7 @@ impl AnomalyScoreProcessor {
             reference: config
                 .reference_intervals
                 .iter()
+                .filter(|_| config.seasonal.is_none())
                 .map(|interval| {
                     self.reference
                         .get(interval)
@@ -105,6 +246,11 @@ impl AnomalyScoreProcessor {
                         )
                 })
                 .collect(),
+            quantile: None,
+            seasonal: config.seasonal.map(|seasonality| match &self.seasonal {
+                Some(seasonal) if seasonal.seasonality == seasonality => seasonal.update(t, config),
+                _ => SeasonalReference::new(t, seasonality, config),
+            }),
         }
     }
 
@@ -116,9 +262,95 @@ impl AnomalyScoreProcessor {
         self.clone()
     }
 
+    /// The learned baseline. Not available for the quantile algorithm
+    /// or seasonal reference statistics.
+    pub fn baseline(&self) -> Option<AnomalyScoreBaseline> {
+        (self.quantile.is_none() && self.seasonal.is_none()).then(|| AnomalyScoreBaseline {
+            welford: self.welford.clone(),
+            reference: self.reference.clone(),
+        })
+    }
+
+    /// Check that every configured reference window is present in the
+    /// baseline, with the same shape.
+    pub fn check_baseline(&self, baseline: &AnomalyScoreBaseline) -> Result<(), BaselineSkip> {
+        if self.quantile.is_some() {
+            return Err(BaselineSkip::QuantileScore);
+        }
+        if self.seasonal.is_some() {
+            return Err(BaselineSkip::SeasonalScore);
+        }
+        self.reference.keys().try_for_each(|interval| {
+            let window = baseline
+                .reference
+                .get(interval)
+                .ok_or(BaselineSkip::MissingWindow(*interval))?;
+            if window.compatible_with(&interval.window_config()) {
+                Ok(())
+            } else {
+                Err(BaselineSkip::IncompatibleWindow(*interval))
+            }
+        })
+    }
+
+    /// Replace the accumulator and reference windows by the (checked)
+    /// baseline. Immediate windows are restarted from the imported
+    /// accumulator.
+    pub fn import_baseline(&mut self, t: DateTime<Utc>, mut baseline: AnomalyScoreBaseline) {
+        self.welford = baseline.welford;
+        self.reference.iter_mut().for_each(|(interval, window)| {
+            if let Some(reference) = baseline.reference.remove(interval) {
+                *window = reference;
+            }
+        });
+        self.immediate.iter_mut().for_each(|(interval, window)| {
+            *window = Window::new_init(t, |_| self.welford.clone(), &interval.window_config());
+        });
+    }
+
     pub fn insert(&mut self, t: DateTime<Utc>, value: f64) {
+        if let Some(quantile) = &mut self.quantile {
+            quantile.insert(t, value);
+            return;
+        }
         let prev = self.welford.clone();
         self.welford.insert(value);
+        if let Some(seasonal) = &mut self.seasonal {
+            seasonal.insert(t, &Welford::weighted(value, 1.0));
+        }
+        self.advance(t, prev);
+        self.correct(t, |bin| bin.insert(value));
+    }
+
+    /// Insert a value standing for `weight` values. The quantile
+    /// algorithm counts it once.
+    pub fn insert_weighted(&mut self, t: DateTime<Utc>, value: f64, weight: f64) {
+        if self.quantile.is_some() {
+            return self.insert(t, value);
+        }
+        self.insert_aggregate(t, &Welford::weighted(value, weight));
+    }
+
+    /// Insert a batch of values summarized by their accumulator, as
+    /// computed by the backend for aggregation pushdown. The quantile
+    /// algorithm needs the individual values; aggregates are ignored
+    /// in that mode.
+    pub fn insert_aggregate(&mut self, t: DateTime<Utc>, values: &Welford<Quad>) {
+        if self.quantile.is_some() {
+            return;
+        }
+        let prev = self.welford.clone();
+        self.welford.merge(values);
+        if let Some(seasonal) = &mut self.seasonal {
+            seasonal.insert(t, values);
+        }
+        self.advance(t, prev);
+        self.correct(t, |bin| bin.merge(values));
+    }
+
+    /// Advance the windows to `t`; bins ending at or before `t` see the
+    /// current accumulator, later bins see `prev`.
+    fn advance(&mut self, t: DateTime<Utc>, prev: Welford<Quad>) {
         let value = |end: DateTime<Utc>| {
             if t >= end {
                 self.welford.clone()
@@ -136,14 +368,48 @@ impl AnomalyScoreProcessor {
         });
     }
 
-    pub fn sample<F: FnMut(MetricArgs, f64)>(&self, mut metric: F) {
+    /// Add values inserted out-of-order, at `t`, to the window bins
+    /// started since: these were taken before the values arrived.
+    fn correct<F: FnMut(&mut Welford<Quad>)>(&mut self, t: DateTime<Utc>, mut f: F) {
+        self.immediate
+            .values_mut()
+            .chain(self.reference.values_mut())
+            .for_each(|window| window.correct_after(t, &mut f));
+    }
+
+    /// Emit the anomaly score metrics. Windows without valid
+    /// statistics (empty or regressed windows) are left out of the
+    /// output. Returns the number of regressed windows (see
+    /// `Window::regressed`).
+    pub fn sample<F: FnMut(MetricArgs, f64)>(&self, mut metric: F) -> u64 {
+        if self.config.offset_series == Some(OffsetSeries::Group) {
+            self.config.sample_offset(&mut metric);
+        }
+        if let (Some(quantile), AnomalyScoreAlgorithm::Quantile { q_stat }) =
+            (&self.quantile, self.config.algorithm)
+        {
+            // The digests are merged from the bins, so they cannot
+            // regress.
+            quantile.sample(&self.config, q_stat.into_inner(), metric);
+            return 0;
+        }
         let q = self.config.q.into_inner();
         let offset = from_f64(self.config.offset.into_inner());
+        let invalid = self
+            .immediate
+            .values()
+            .chain(self.reference.values())
+            .filter(|window| window.regressed())
+            .count() as u64;
+        let mut immediate_counts = Vec::new();
+        let mut reference_counts = Vec::new();
 
         let immediate = self
             .immediate
             .iter()
-            .map(|(immediate_interval, immediate)| {
+            .filter_map(|(immediate_interval, immediate)| {
+                let count = to_f64(immediate.count());
+                immediate_counts.push((*immediate_interval, count, immediate.minutes()));
                 metric(
                     MetricArgs {
                         metric_suffix: Some("count"),
@@ -153,42 +419,375 @@ impl AnomalyScoreProcessor {
                             ..Labels::default()
                         },
                     },
-                    to_f64(immediate.count()),
+                    count,
                 );
+                if self.config.below_min_rate(count, immediate.minutes()) {
+                    return None;
+                }
+                if let Some(mean) = immediate.mean() {
+                    metric(
+                        MetricArgs {
+                            metric_suffix: Some("mean"),
+                            metric_type: "anomaly_score",
+                            labels: Labels {
+                                immediate: Some(*immediate_interval),
+                                ..Labels::default()
+                            },
+                        },
+                        to_f64(mean),
+                    );
+                }
+                if let Some(ci) = immediate.confidence_interval(q) {
+                    metric(
+                        MetricArgs {
+                            metric_suffix: Some("ci"),
+                            metric_type: "anomaly_score",
+                            labels: Labels {
+                                immediate: Some(*immediate_interval),
+                                ..Labels::default()
+                            },
+                        },
+                        to_f64(ci),
+                    );
+                }
+                immediate
+                    .lower_bound_of_confidence_interval(q)
+                    .map(|bound| (*immediate_interval, bound.max(from_f64(0.0))))
+            })
+            .collect::<Vec<_>>();
+        let seasonal = self.seasonal.as_ref().map(|seasonal| seasonal.seasonality);
+        let references = self
+            .references()
+            .filter_map(|(reference_interval, reference, minutes)| {
+                let count = to_f64(reference.count);
+                reference_counts.push((reference_interval, count, minutes));
                 metric(
                     MetricArgs {
-                        metric_suffix: Some("mean"),
+                        metric_suffix: Some("count"),
                         metric_type: "anomaly_score",
                         labels: Labels {
-                            immediate: Some(*immediate_interval),
+                            reference: Some(reference_interval),
+                            seasonal,
                             ..Labels::default()
                         },
                     },
-                    to_f64(immediate.mean()),
+                    count,
                 );
+                if let Some(mean) = reference.valid_mean() {
+                    metric(
+                        MetricArgs {
+                            metric_suffix: Some("mean"),
+                            metric_type: "anomaly_score",
+                            labels: Labels {
+                                reference: Some(reference_interval),
+                                seasonal,
+                                ..Labels::default()
+                            },
+                        },
+                        to_f64(mean),
+                    );
+                }
+                if let Some(ci) = reference.confidence_interval(q) {
+                    metric(
+                        MetricArgs {
+                            metric_suffix: Some("ci"),
+                            metric_type: "anomaly_score",
+                            labels: Labels {
+                                reference: Some(reference_interval),
+                                seasonal,
+                                ..Labels::default()
+                            },
+                        },
+                        to_f64(ci),
+                    );
+                }
+                reference
+                    .upper_bound_of_confidence_interval(q)
+                    .map(|bound| (reference_interval, (bound + offset).value))
+            })
+            .collect::<Vec<_>>();
+
+        immediate
+            .iter()
+            .for_each(|(immediate_interval, immediate_lower_bound)| {
+                references
+                    .iter()
+                    .for_each(|(reference_interval, reference_upper_bound)| {
+                        metric(
+                            MetricArgs {
+                                metric_suffix: Some("score"),
+                                metric_type: "anomaly_score",
+                                labels: Labels {
+                                    immediate: Some(*immediate_interval),
+                                    reference: Some(*reference_interval),
+                                    seasonal,
+                                    ..Labels::default()
+                                },
+                            },
+                            to_f64((*immediate_lower_bound / *reference_upper_bound).value),
+                        );
+                    });
+            });
+        sample_sufficiency(&immediate_counts, &reference_counts, seasonal, &mut metric);
+
+        invalid
+    }
+
+    /// Emit the anomaly score metrics of a group first seen `age`
+    /// ago, if known, marking the samples of the reference windows
+    /// that are still warming up (if configured). See `sample`.
+    pub fn sample_aged<F: FnMut(MetricArgs, f64)>(
+        &self,
+        age: Option<TimeDelta>,
+        mut metric: F,
+    ) -> u64 {
+        match age.filter(|_| self.config.warm_up) {
+            Some(age) => self.sample(|mut args, value| {
+                args.labels.warming_up = args
+                    .labels
+                    .reference
+                    .is_some_and(|interval| age.num_seconds() < interval.window_config().seconds());
+                metric(args, value)
+            }),
+            None => self.sample(metric),
+        }
+    }
+
+    /// Whether the warm-up of the groups is marked.
+    pub fn marks_warm_up(&self) -> bool {
+        self.config.warm_up
+    }
+
+    /// The values of every reference window, with its length in
+    /// minutes. With seasonality, these are the bins of the latest
+    /// insert, with their effective length.
+    fn references(&self) -> Box<dyn Iterator<Item = (ReferenceInterval, Welford<Quad>, f64)> + '_> {
+        match &self.seasonal {
+            Some(seasonal) => Box::new(seasonal.current()),
+            None => Box::new(
+                self.reference
+                    .iter()
+                    .map(|(interval, window)| (*interval, window.values(), window.minutes())),
+            ),
+        }
+    }
+}
+
+impl Seasonality {
+    const fn num_bins(self) -> usize {
+        match self {
+            Self::HourOfDay => 24,
+            Self::HourOfWeek => 7 * 24,
+        }
+    }
+
+    /// The bin holding `t`.
+    fn bin(self, t: DateTime<Utc>) -> usize {
+        match self {
+            Self::HourOfDay => t.hour() as usize,
+            Self::HourOfWeek => {
+                t.weekday().num_days_from_monday() as usize * 24 + t.hour() as usize
+            }
+        }
+    }
+
+    /// The number of minutes between two visits of a bin.
+    fn period_minutes(self) -> f64 {
+        self.num_bins() as f64 * 60.0
+    }
+}
+
+impl Display for Seasonality {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::HourOfDay => write!(f, "hour_of_day"),
+            Self::HourOfWeek => write!(f, "hour_of_week"),
+        }
+    }
+}
+
+impl SeasonalReference {
+    fn new(t: DateTime<Utc>, seasonality: Seasonality, config: &AnomalyScoreConfig) -> Self {
+        Self {
+            seasonality,
+            last: t,
+            reference: config
+                .reference_intervals
+                .iter()
+                .map(|interval| (*interval, SeasonalBin::new_bins(t, seasonality)))
+                .collect(),
+        }
+    }
+
+    fn update(&self, t: DateTime<Utc>, config: &AnomalyScoreConfig) -> Self {
+        Self {
+            seasonality: self.seasonality,
+            last: self.last,
+            reference: config
+                .reference_intervals
+                .iter()
+                .map(|interval| {
+                    let bins = self
+                        .reference
+                        .get(interval)
+                        .map_or_else(|| SeasonalBin::new_bins(t, self.seasonality), Vec::clone);
+                    (*interval, bins)
+                })
+                .collect(),
+        }
+    }
+
+    fn insert(&mut self, t: DateTime<Utc>, values: &Welford<Quad>) {
+        let bin = self.seasonality.bin(t);
+        self.last = self.last.max(t);
+        self.reference.iter_mut().for_each(|(interval, bins)| {
+            bins[bin].insert(t, values, reference_minutes(*interval));
+        });
+    }
+
+    /// The bin of the latest insert for every reference interval, with
+    /// its effective length: the length of a bin, weighted by its
+    /// decay over the past periods.
+    fn current(&self) -> impl Iterator<Item = (ReferenceInterval, Welford<Quad>, f64)> + '_ {
+        let bin = self.seasonality.bin(self.last);
+        self.reference.iter().map(move |(interval, bins)| {
+            let decay = (-self.seasonality.period_minutes() / reference_minutes(*interval)).exp();
+            (*interval, bins[bin].welford.clone(), 60.0 / (1.0 - decay))
+        })
+    }
+}
+
+impl SeasonalBin {
+    fn new_bins(t: DateTime<Utc>, seasonality: Seasonality) -> Vec<Self> {
+        vec![
+            Self {
+                decayed: t,
+                welford: Welford::default(),
+            };
+            seasonality.num_bins()
+        ]
+    }
+
+    /// Decay the accumulator up to `t`, with time constant `minutes`,
+    /// and add `values`. Out-of-order values are added undecayed.
+    fn insert(&mut self, t: DateTime<Utc>, values: &Welford<Quad>, minutes: f64) {
+        let elapsed = (t - self.decayed).num_seconds();
+        if elapsed > 0 {
+            self.welford
+                .decay((-(elapsed as f64) / 60.0 / minutes).exp());
+            self.decayed = t;
+        }
+        self.welford.merge(values);
+    }
+}
+
+/// The length of a reference interval, in minutes.
+fn reference_minutes(interval: ReferenceInterval) -> f64 {
+    let config = interval.window_config();
+    config.bin_width.multiply(config.num_bins as u32).minutes()
+}
+
+impl QuantileWindows {
+    fn new(t: DateTime<Utc>, config: &AnomalyScoreConfig) -> Self {
+        Self {
+            immediate: config
+                .immediate_intervals
+                .iter()
+                .map(|interval| (*interval, Window::new(t, &interval.window_config())))
+                .collect(),
+            reference: config
+                .reference_intervals
+                .iter()
+                .map(|interval| (*interval, Window::new(t, &interval.window_config())))
+                .collect(),
+        }
+    }
+
+    fn update(&self, t: DateTime<Utc>, config: &AnomalyScoreConfig) -> Self {
+        Self {
+            immediate: config
+                .immediate_intervals
+                .iter()
+                .map(|interval| {
+                    let window_config = interval.window_config();
+                    let window = self
+                        .immediate
+                        .get(interval)
+                        .filter(|window| window.compatible_with(&window_config))
+                        .map_or_else(|| Window::new(t, &window_config), Window::clone);
+                    (*interval, window)
+                })
+                .collect(),
+            reference: config
+                .reference_intervals
+                .iter()
+                .map(|interval| {
+                    let window_config = interval.window_config();
+                    let window = self
+                        .reference
+                        .get(interval)
+                        .filter(|window| window.compatible_with(&window_config))
+                        .map_or_else(|| Window::new(t, &window_config), Window::clone);
+                    (*interval, window)
+                })
+                .collect(),
+        }
+    }
+
+    fn insert(&mut self, t: DateTime<Utc>, value: f64) {
+        self.immediate.values_mut().for_each(|window| {
+            window.advance_init(t, |_| TDigest::default());
+            window.bin_mut(t).insert(value);
+        });
+        self.reference.values_mut().for_each(|window| {
+            window.advance_init(t, |_| TDigest::default());
+            window.bin_mut(t).insert(value);
+        });
+    }
+
+    /// Emit the window counts and the score for every combination of
+    /// immediate and reference window. Windows without values are
+    /// left out.
+    fn sample<F: FnMut(MetricArgs, f64)>(
+        &self,
+        config: &AnomalyScoreConfig,
+        q_stat: f64,
+        mut metric: F,
+    ) {
+        let offset = config.offset.into_inner();
+        let mut immediate_counts = Vec::new();
+        let mut reference_counts = Vec::new();
+
+        let immediate = self
+            .immediate
+            .iter()
+            .filter_map(|(immediate_interval, immediate)| {
+                let digest = immediate.bins().merge();
+                immediate_counts.push((*immediate_interval, digest.count(), immediate.minutes()));
                 metric(
                     MetricArgs {
-                        metric_suffix: Some("ci"),
+                        metric_suffix: Some("count"),
                         metric_type: "anomaly_score",
                         labels: Labels {
                             immediate: Some(*immediate_interval),
                             ..Labels::default()
                         },
                     },
-                    to_f64(immediate.confidence_interval(q)),
+                    digest.count(),
                 );
-                (
-                    *immediate_interval,
-                    immediate
-                        .lower_bound_of_confidence_interval(q)
-                        .max(from_f64(0.0)),
-                )
+                if digest.is_empty() || config.below_min_rate(digest.count(), immediate.minutes()) {
+                    None
+                } else {
+                    Some((*immediate_interval, digest.estimate_quantile(q_stat)))
+                }
             })
             .collect::<Vec<_>>();
         let references = self
             .reference
             .iter()
-            .map(|(reference_interval, reference)| {
+            .filter_map(|(reference_interval, reference)| {
+                let digest = reference.bins().merge();
+                reference_counts.push((*reference_interval, digest.count(), reference.minutes()));
                 metric(
                     MetricArgs {
                         metric_suffix: Some("count"),
@@ -198,43 +797,23 @@ impl AnomalyScoreProcessor {
                             ..Labels::default()
                         },
                     },
-                    to_f64(reference.count()),
-                );
-                metric(
-                    MetricArgs {
-                        metric_suffix: Some("mean"),
-                        metric_type: "anomaly_score",
-                        labels: Labels {
-                            reference: Some(*reference_interval),
-                            ..Labels::default()
-                        },
-                    },
-                    to_f64(reference.mean()),
+                    digest.count(),
                 );
-                metric(
-                    MetricArgs {
-                        metric_suffix: Some("ci"),
-                        metric_type: "anomaly_score",
-                        labels: Labels {
-                            reference: Some(*reference_interval),
-                            ..Labels::default()
-                        },
-                    },
-                    to_f64(reference.confidence_interval(q)),
-                );
-                (
-                    *reference_interval,
-                    (reference.upper_bound_of_confidence_interval(q) + offset).value,
-                )
+                let bound = digest.estimate_quantile(q_stat) + offset;
+                if digest.is_empty() || bound <= 0.0 {
+                    None
+                } else {
+                    Some((*reference_interval, bound))
+                }
             })
             .collect::<Vec<_>>();
 
         immediate
             .iter()
-            .for_each(|(immediate_interval, immediate_lower_bound)| {
+            .for_each(|(immediate_interval, immediate_quantile)| {
                 references
                     .iter()
-                    .for_each(|(reference_interval, reference_upper_bound)| {
+                    .for_each(|(reference_interval, reference_bound)| {
                         metric(
                             MetricArgs {
                                 metric_suffix: Some("score"),
@@ -245,13 +824,51 @@ impl AnomalyScoreProcessor {
                                     ..Labels::default()
                                 },
                             },
-                            to_f64((*immediate_lower_bound / *reference_upper_bound).value),
+                            immediate_quantile / reference_bound,
                         );
                     });
             });
+        sample_sufficiency(&immediate_counts, &reference_counts, None, &mut metric);
     }
 }
 
+/// Emit the data sufficiency of every combination of immediate and
+/// reference window: the immediate count relative to the reference
+/// count scaled to the length of the immediate window. Values well
+/// below 1 mean the immediate window saw less traffic than usual,
+/// making its scores less comparable. Combinations with an empty
+/// reference window are left out.
+fn sample_sufficiency<F: FnMut(MetricArgs, f64)>(
+    immediate: &[(ImmediateInterval, f64, f64)],
+    reference: &[(ReferenceInterval, f64, f64)],
+    seasonal: Option<Seasonality>,
+    metric: &mut F,
+) {
+    immediate
+        .iter()
+        .for_each(|(immediate_interval, immediate_count, immediate_minutes)| {
+            reference
+                .iter()
+                .filter(|(_, reference_count, _)| *reference_count > 0.0)
+                .for_each(|(reference_interval, reference_count, reference_minutes)| {
+                    let expected = reference_count * immediate_minutes / reference_minutes;
+                    metric(
+                        MetricArgs {
+                            metric_suffix: Some("sufficiency"),
+                            metric_type: "anomaly_score",
+                            labels: Labels {
+                                immediate: Some(*immediate_interval),
+                                reference: Some(*reference_interval),
+                                seasonal,
+                                ..Labels::default()
+                            },
+                        },
+                        immediate_count / expected,
+                    );
+                });
+        });
+}
+
 impl Default for AnomalyScoreConfig {
     fn default() -> Self {
         Self {
@@ -265,6 +882,11 @@ impl Default for AnomalyScoreConfig {
             ]),
             offset: NotNan::new(0.0).unwrap(),
             q: NotNan::new(0.99).unwrap(),
+            algorithm: AnomalyScoreAlgorithm::MeanCi,
+            min_rate: None,
+            seasonal: None,
+            warm_up: false,
+            offset_series: None,
         }
     }
 }
@@ -276,4 +898,586 @@ impl AnomalyScoreConfig {
             ..Self::default()
         }
     }
+
+    /// Compute the windows for the given reference intervals only,
+    /// e.g. for metrics that do not need a monthly baseline.
+    pub fn with_reference_intervals<I>(self, intervals: I) -> Self
+    where
+        I: IntoIterator<Item = ReferenceInterval>,
+    {
+        Self {
+            reference_intervals: intervals.into_iter().collect(),
+            ..self
+        }
+    }
+
+    /// Whether the windows for `interval` are computed.
+    pub fn computes(&self, interval: Interval) -> bool {
+        match interval {
+            Interval::Immediate(interval) => self.immediate_intervals.contains(&interval),
+            Interval::Reference(interval) => self.reference_intervals.contains(&interval),
+        }
+    }
+
+    /// The immediate intervals whose windows are computed.
+    pub fn immediate_intervals(&self) -> impl Iterator<Item = ImmediateInterval> + '_ {
+        self.immediate_intervals.iter().copied()
+    }
+
+    /// Whether any anomaly scores are computed, which requires both
+    /// immediate and reference windows.
+    pub fn computes_scores(&self) -> bool {
+        !self.immediate_intervals.is_empty() && !self.reference_intervals.is_empty()
+    }
+
+    /// Emit the offset series per group or per config.
+    #[cfg(test)]
+    pub fn with_offset_series(self, offset_series: OffsetSeries) -> Self {
+        Self {
+            offset_series: Some(offset_series),
+            ..self
+        }
+    }
+
+    #[cfg(test)]
+    pub fn offset(&self) -> f64 {
+        self.offset.into_inner()
+    }
+
+    #[cfg(test)]
+    pub fn reference_intervals(&self) -> impl Iterator<Item = ReferenceInterval> + '_ {
+        self.reference_intervals.iter().copied()
+    }
+
+    /// Where the offset series are emitted, if at all.
+    pub fn offset_series(&self) -> Option<OffsetSeries> {
+        self.offset_series
+    }
+
+    /// Emit the offset for every reference interval.
+    pub fn sample_offset<F: FnMut(MetricArgs, f64)>(&self, mut metric: F) {
+        self.reference_intervals.iter().for_each(|interval| {
+            metric(
+                MetricArgs {
+                    metric_suffix: Some("offset"),
+                    metric_type: "anomaly_score",
+                    labels: Labels {
+                        reference: Some(*interval),
+                        ..Labels::default()
+                    },
+                },
+                self.offset.into_inner(),
+            );
+        });
+    }
+
+    /// Whether an immediate window holding `count` values over
+    /// `minutes` is below the configured minimum call rate.
+    fn below_min_rate(&self, count: f64, minutes: f64) -> bool {
+        self.min_rate
+            .is_some_and(|min_rate| count < min_rate.into_inner() * minutes)
+    }
+
+    /// The quantile compared by the quantile algorithm, if selected.
+    pub fn q_stat(&self) -> Option<f64> {
+        match self.algorithm {
+            AnomalyScoreAlgorithm::MeanCi => None,
+            AnomalyScoreAlgorithm::Quantile { q_stat } => Some(q_stat.into_inner()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::{DateTime, TimeDelta, Timelike, Utc};
+    use jaeger_anomaly_detection::{
+        anomaly_score, ImmediateInterval, ReferenceInterval, WelfordSummary, WindowConfig,
+    };
+    use ordered_float::NotNan;
+    use rustc_apfloat::ieee::Quad;
+    use tdigest::TDigest;
+
+    use super::{AnomalyScoreAlgorithm, AnomalyScoreConfig, AnomalyScoreProcessor, Seasonality};
+    use crate::{
+        accum::Accum,
+        welford::{to_f64, Welford},
+        window::Window,
+    };
+
+    fn welford(values: &[f64]) -> Welford<Quad> {
+        let mut acc = Welford::default();
+        values.iter().for_each(|v| acc.insert(*v));
+        acc
+    }
+
+    fn digest(values: &[f64]) -> TDigest {
+        TDigest::default().merge_unsorted(values.to_vec())
+    }
+
+    /// Pareto-distributed values (alpha = 1.1, minimum 10), scaled by
+    /// `scale` and spread evenly over the quantiles from `tail` to 1,
+    /// i.e. leaving out the top `tail` fraction.
+    fn pareto(n: usize, tail: f64, scale: f64) -> Vec<f64> {
+        (0..n)
+            .map(|i| {
+                let u = tail + (1.0 - tail) * (i as f64 + 0.5) / n as f64;
+                scale * 10.0 / u.powf(1.0 / 1.1)
+            })
+            .collect()
+    }
+
+    fn quantile_config(q_stat: f64) -> AnomalyScoreConfig {
+        AnomalyScoreConfig {
+            algorithm: AnomalyScoreAlgorithm::Quantile {
+                q_stat: NotNan::new(q_stat).unwrap(),
+            },
+            ..AnomalyScoreConfig::default()
+        }
+    }
+
+    fn samples(proc: &AnomalyScoreProcessor, suffix: &str) -> Vec<f64> {
+        let mut values = Vec::new();
+        proc.sample(|args, value| {
+            if args.metric_suffix == Some(suffix) {
+                values.push(value);
+            }
+        });
+        values
+    }
+
+    #[test]
+    fn regressed_windows_emit_no_negative_values() {
+        let start = DateTime::<Utc>::from_timestamp(1_699_999_200, 0).unwrap();
+        let mut proc = AnomalyScoreProcessor::new(start, &AnomalyScoreConfig::default());
+
+        // The current bin holds fewer values than the first bin.
+        let regressed = |interval: jaeger_anomaly_detection::WindowConfig| {
+            Window::new_init(
+                start,
+                |t| {
+                    if t == start {
+                        welford(&[10.0])
+                    } else {
+                        welford(&[10.0, 20.0, 30.0, 40.0])
+                    }
+                },
+                &interval,
+            )
+        };
+        proc.immediate = [ImmediateInterval::I5m, ImmediateInterval::I15m]
+            .into_iter()
+            .map(|interval| (interval, regressed(interval.window_config())))
+            .collect();
+        proc.reference = [ReferenceInterval::R7d, ReferenceInterval::R30d]
+            .into_iter()
+            .map(|interval| (interval, regressed(interval.window_config())))
+            .collect();
+
+        let mut values = Vec::new();
+        let invalid = proc.sample(|args, value| values.push((args.metric_suffix, value)));
+
+        assert_eq!(invalid, 4);
+        assert!(values
+            .iter()
+            .all(|(_, value)| !value.is_nan() && *value >= 0.0));
+        assert!(values.iter().all(|(suffix, _)| *suffix == Some("count")));
+    }
+
+    /// Empty windows and windows of a single value (idle groups) have
+    /// no statistics, but are not counted as regressed.
+    #[test]
+    fn idle_windows_are_not_regressed() {
+        let start = DateTime::<Utc>::from_timestamp(1_699_999_200, 0).unwrap();
+        for config in [AnomalyScoreConfig::default(), quantile_config(0.9)] {
+            let mut proc = AnomalyScoreProcessor::new(start, &config);
+            let mut suffixes = Vec::new();
+            assert_eq!(proc.sample(|args, _| suffixes.push(args.metric_suffix)), 0);
+            assert!(suffixes.iter().all(|suffix| *suffix == Some("count")));
+
+            proc.insert(start + TimeDelta::seconds(10), 100.0);
+            assert_eq!(proc.sample(|_, _| {}), 0);
+        }
+    }
+
+    /// The lib's f64 score must match the engine's Quad-based score.
+    #[test]
+    fn scores_match_lib() {
+        let start = DateTime::<Utc>::from_timestamp(1_699_999_200, 0).unwrap();
+        let mut proc = AnomalyScoreProcessor::new(
+            start,
+            &AnomalyScoreConfig::default_with_offset(NotNan::new(5.0).unwrap()),
+        );
+
+        // A window holding `values`, on top of `before`.
+        let window = |interval: WindowConfig, before: &[f64], values: &[f64]| {
+            Window::new_init(
+                start,
+                |t| {
+                    if t == start {
+                        welford(&[before, values].concat())
+                    } else {
+                        welford(before)
+                    }
+                },
+                &interval,
+            )
+        };
+        let background = (0..500)
+            .map(|i| 100.0 + (i * 37 % 50) as f64)
+            .collect::<Vec<_>>();
+        let recent = (0..40)
+            .map(|i| 130.0 + (i * 13 % 30) as f64)
+            .collect::<Vec<_>>();
+        proc.immediate = [ImmediateInterval::I5m, ImmediateInterval::I15m]
+            .into_iter()
+            .map(|interval| {
+                let window = window(interval.window_config(), &background, &recent);
+                (interval, window)
+            })
+            .collect();
+        proc.reference = [ReferenceInterval::R7d, ReferenceInterval::R30d]
+            .into_iter()
+            .map(|interval| (interval, window(interval.window_config(), &[], &background)))
+            .collect();
+
+        let summary = |window: &Window<Welford<Quad>>| WelfordSummary {
+            count: to_f64(window.count()),
+            mean: to_f64(window.mean().unwrap()),
+            m2: to_f64(window.values().valid_m2().unwrap()),
+        };
+
+        let mut scores = Vec::new();
+        let invalid = proc.sample(|args, value| {
+            if args.metric_suffix == Some("score") {
+                scores.push((
+                    args.labels.immediate.unwrap(),
+                    args.labels.reference.unwrap(),
+                    value,
+                ));
+            }
+        });
+        assert_eq!(invalid, 0);
+        assert_eq!(scores.len(), 4);
+        scores.iter().for_each(|(immediate, reference, value)| {
+            let expected = anomaly_score(
+                summary(&proc.immediate[immediate]),
+                summary(&proc.reference[reference]),
+                0.99,
+                5.0,
+            )
+            .unwrap();
+            assert!(*value > 1.0);
+            assert!((value - expected).abs() <= 1e-9 * expected.abs());
+        });
+    }
+
+    #[test]
+    fn sufficiency() {
+        let start = DateTime::<Utc>::from_timestamp(1_699_999_200, 0).unwrap();
+        let mut proc = AnomalyScoreProcessor::new(start, &AnomalyScoreConfig::default());
+        let window = |interval: WindowConfig, n: usize| {
+            Window::new_init(
+                start,
+                |t| {
+                    if t == start {
+                        welford(&vec![100.0; n])
+                    } else {
+                        Welford::default()
+                    }
+                },
+                &interval,
+            )
+        };
+        let sufficiency = |proc: &AnomalyScoreProcessor| {
+            let mut values = Vec::new();
+            proc.sample(|args, value| {
+                if args.metric_suffix == Some("sufficiency") {
+                    values.push((
+                        args.labels.immediate.unwrap(),
+                        args.labels.reference.unwrap(),
+                        value,
+                    ));
+                }
+            });
+            values
+        };
+
+        // 50 and 150 calls over 5 and 15 minutes, against 2 calls per
+        // minute over 7 days and 1 call per minute over 30 days.
+        proc.immediate = [(ImmediateInterval::I5m, 50), (ImmediateInterval::I15m, 150)]
+            .into_iter()
+            .map(|(interval, n)| (interval, window(interval.window_config(), n)))
+            .collect();
+        proc.reference = [
+            (ReferenceInterval::R7d, 2 * 7 * 24 * 60),
+            (ReferenceInterval::R30d, 30 * 24 * 60),
+        ]
+        .into_iter()
+        .map(|(interval, n)| (interval, window(interval.window_config(), n)))
+        .collect();
+        assert_eq!(
+            sufficiency(&proc),
+            vec![
+                (ImmediateInterval::I5m, ReferenceInterval::R7d, 5.0),
+                (ImmediateInterval::I5m, ReferenceInterval::R30d, 10.0),
+                (ImmediateInterval::I15m, ReferenceInterval::R7d, 5.0),
+                (ImmediateInterval::I15m, ReferenceInterval::R30d, 10.0),
+            ]
+        );
+
+        // An empty reference window has no sufficiency.
+        proc.reference.insert(
+            ReferenceInterval::R30d,
+            window(ReferenceInterval::R30d.window_config(), 0),
+        );
+        assert_eq!(
+            sufficiency(&proc),
+            vec![
+                (ImmediateInterval::I5m, ReferenceInterval::R7d, 5.0),
+                (ImmediateInterval::I15m, ReferenceInterval::R7d, 5.0),
+            ]
+        );
+    }
+
+    /// A doubled median without the tail goes unnoticed by the mean/CI
+    /// score, since the reference mean is dominated by the tail.
+    #[test]
+    fn heavy_tailed_scores() {
+        let start = DateTime::<Utc>::from_timestamp(1_699_999_200, 0).unwrap();
+        let reference = pareto(1000, 0.0, 1.0);
+        let immediate = pareto(100, 0.05, 2.0);
+
+        let mut mean_ci = AnomalyScoreProcessor::new(start, &AnomalyScoreConfig::default());
+        let welford_window = |interval: WindowConfig, values: &[f64]| {
+            Window::new_init(
+                start,
+                |t| {
+                    if t == start {
+                        welford(values)
+                    } else {
+                        Welford::default()
+                    }
+                },
+                &interval,
+            )
+        };
+        mean_ci.immediate = [ImmediateInterval::I5m, ImmediateInterval::I15m]
+            .into_iter()
+            .map(|interval| {
+                (
+                    interval,
+                    welford_window(interval.window_config(), &immediate),
+                )
+            })
+            .collect();
+        mean_ci.reference = [ReferenceInterval::R7d, ReferenceInterval::R30d]
+            .into_iter()
+            .map(|interval| {
+                (
+                    interval,
+                    welford_window(interval.window_config(), &reference),
+                )
+            })
+            .collect();
+
+        let mut quantile = AnomalyScoreProcessor::new(start, &quantile_config(0.5));
+        let digest_window = |interval: WindowConfig, values: &[f64]| {
+            Window::new_init(
+                start,
+                |t| {
+                    if t == start {
+                        digest(values)
+                    } else {
+                        TDigest::default()
+                    }
+                },
+                &interval,
+            )
+        };
+        let windows = quantile.quantile.as_mut().unwrap();
+        windows.immediate = [ImmediateInterval::I5m, ImmediateInterval::I15m]
+            .into_iter()
+            .map(|interval| {
+                (
+                    interval,
+                    digest_window(interval.window_config(), &immediate),
+                )
+            })
+            .collect();
+        windows.reference = [ReferenceInterval::R7d, ReferenceInterval::R30d]
+            .into_iter()
+            .map(|interval| {
+                (
+                    interval,
+                    digest_window(interval.window_config(), &reference),
+                )
+            })
+            .collect();
+
+        let mean_ci_scores = samples(&mean_ci, "score");
+        let quantile_scores = samples(&quantile, "score");
+        assert_eq!(mean_ci_scores.len(), 4);
+        assert_eq!(quantile_scores.len(), 4);
+        assert!(mean_ci_scores.iter().all(|score| *score < 1.0));
+        assert!(quantile_scores.iter().all(|score| *score > 1.5));
+    }
+
+    #[test]
+    fn min_rate_suppresses_scores() {
+        let start = DateTime::<Utc>::from_timestamp(1_699_999_200, 0).unwrap();
+        let background = (0..500)
+            .map(|i| 100.0 + (i * 37 % 50) as f64)
+            .collect::<Vec<_>>();
+        // 40 calls: 8 per minute over 5 minutes, 2.67 over 15 minutes.
+        let recent = (0..40)
+            .map(|i| 130.0 + (i * 13 % 30) as f64)
+            .collect::<Vec<_>>();
+        let window = |interval: WindowConfig, before: &[f64], values: &[f64]| {
+            Window::new_init(
+                start,
+                |t| {
+                    if t == start {
+                        welford(&[before, values].concat())
+                    } else {
+                        welford(before)
+                    }
+                },
+                &interval,
+            )
+        };
+        let proc = |min_rate: f64| {
+            let mut proc = AnomalyScoreProcessor::new(
+                start,
+                &AnomalyScoreConfig {
+                    min_rate: Some(NotNan::new(min_rate).unwrap()),
+                    ..AnomalyScoreConfig::default()
+                },
+            );
+            proc.immediate = [ImmediateInterval::I5m, ImmediateInterval::I15m]
+                .into_iter()
+                .map(|interval| {
+                    let window = window(interval.window_config(), &background, &recent);
+                    (interval, window)
+                })
+                .collect();
+            proc.reference = [ReferenceInterval::R7d, ReferenceInterval::R30d]
+                .into_iter()
+                .map(|interval| (interval, window(interval.window_config(), &[], &background)))
+                .collect();
+            proc
+        };
+        let immediate = |proc: &AnomalyScoreProcessor, suffix: &str| {
+            let mut intervals = Vec::new();
+            let invalid = proc.sample(|args, _| {
+                if args.metric_suffix == Some(suffix) {
+                    intervals.extend(args.labels.immediate);
+                }
+            });
+            assert_eq!(invalid, 0);
+            intervals
+        };
+
+        let high = proc(1.0);
+        assert_eq!(immediate(&high, "score").len(), 4);
+        assert_eq!(immediate(&high, "mean").len(), 2);
+
+        let partial = proc(5.0);
+        assert_eq!(
+            immediate(&partial, "score"),
+            [ImmediateInterval::I5m, ImmediateInterval::I5m]
+        );
+        assert_eq!(immediate(&partial, "mean"), [ImmediateInterval::I5m]);
+        assert_eq!(immediate(&partial, "ci"), [ImmediateInterval::I5m]);
+
+        // Below the threshold, only the counts remain.
+        let low = proc(10.0);
+        assert!(immediate(&low, "score").is_empty());
+        assert!(immediate(&low, "mean").is_empty());
+        assert!(immediate(&low, "ci").is_empty());
+        assert_eq!(
+            immediate(&low, "count"),
+            [ImmediateInterval::I5m, ImmediateInterval::I15m]
+        );
+        assert_eq!(samples(&low, "count").len(), 4);
+    }
+
+    #[test]
+    fn algorithm_switch_resets() {
+        let start = DateTime::<Utc>::from_timestamp(1_699_999_200, 0).unwrap();
+        let t = start + TimeDelta::minutes(1);
+        let mut proc = AnomalyScoreProcessor::new(start, &AnomalyScoreConfig::default());
+        (0..100).for_each(|i| proc.insert(start, i as f64));
+
+        let mut proc = proc.update(t, &quantile_config(0.95));
+        assert!(proc.baseline().is_none());
+        (0..10).for_each(|i| proc.insert(t, i as f64));
+        assert!(samples(&proc, "count").iter().all(|count| *count == 10.0));
+
+        // Changing q_stat keeps the digests.
+        let proc = proc.update(t, &quantile_config(0.99));
+        assert_eq!(samples(&proc, "count"), vec![10.0; 4]);
+        assert_eq!(samples(&proc, "score").len(), 4);
+
+        let proc = proc.update(t, &AnomalyScoreConfig::default());
+        assert!(proc.quantile.is_none());
+        assert_eq!(to_f64(proc.baseline().unwrap().welford.count), 0.0);
+    }
+
+    #[test]
+    fn seasonal_bins() {
+        // Monday 2023-11-13, 00:00 UTC.
+        let monday = DateTime::<Utc>::from_timestamp(1_699_833_600, 0).unwrap();
+        let t = monday + TimeDelta::days(2) + TimeDelta::minutes(13 * 60 + 59);
+        assert_eq!(Seasonality::HourOfDay.bin(t), 13);
+        assert_eq!(Seasonality::HourOfWeek.bin(t), 2 * 24 + 13);
+        assert_eq!(
+            Seasonality::HourOfWeek.bin(monday - TimeDelta::minutes(1)),
+            167
+        );
+    }
+
+    /// A daily peak from 12:00 to 13:00 UTC is anomalous compared to
+    /// the whole reference window, but not compared to the same hour
+    /// on previous days.
+    #[test]
+    fn seasonal_reference() {
+        let start = DateTime::<Utc>::from_timestamp(1_699_833_600, 0).unwrap();
+        let seasonal_config = AnomalyScoreConfig {
+            seasonal: Some(Seasonality::HourOfDay),
+            ..AnomalyScoreConfig::default()
+        };
+        let mut flat = AnomalyScoreProcessor::new(start, &AnomalyScoreConfig::default());
+        let mut seasonal = AnomalyScoreProcessor::new(start, &seasonal_config);
+        (0..14 * 24 * 60 + 12 * 60 + 30).for_each(|i| {
+            let t = start + TimeDelta::minutes(i);
+            let base = if t.hour() == 12 { 300.0 } else { 100.0 };
+            let value = base + (i % 5) as f64 * 5.0;
+            flat.insert(t, value);
+            seasonal.insert(t, value);
+        });
+
+        let max_score = |proc: &AnomalyScoreProcessor| {
+            let scores = samples(proc, "score");
+            assert_eq!(scores.len(), 4);
+            scores.into_iter().fold(0.0, f64::max)
+        };
+        assert!(max_score(&flat) > 2.0);
+        assert!(max_score(&seasonal) < 1.1);
+
+        let mut labels = Vec::new();
+        seasonal.sample(|args, _| {
+            if args.labels.reference.is_some() {
+                labels.push(args.labels.seasonal);
+            }
+        });
+        assert!(labels
+            .iter()
+            .all(|label| *label == Some(Seasonality::HourOfDay)));
+        assert!(seasonal.baseline().is_none());
+
+        // Switching back to flat reference windows restarts them.
+        let flat = seasonal.update(start, &AnomalyScoreConfig::default());
+        assert!(flat.seasonal.is_none());
+        assert!(samples(&flat, "score").is_empty());
+    }
 }
@@ -0,0 +1,186 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use std::{collections::BTreeMap, io::Read};
+
+use apistos::ApiComponent;
+use chrono::{DateTime, Utc};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use jaeger_anomaly_detection::ReferenceInterval;
+use rustc_apfloat::ieee::Quad;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::{ConfigName, MetricName, SpanKey},
+    jaeger::TagValue,
+    metrics::label_value,
+    welford::Welford,
+    window::Window,
+};
+
+/// The current baseline bundle format version.
+pub const BUNDLE_VERSION: u32 = 1;
+
+/// A portable snapshot of the learned baselines: the anomaly score
+/// reference windows and welford accumulators, without config and
+/// without immediate windows.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BaselineBundle {
+    pub version: u32,
+    pub exported: DateTime<Utc>,
+    pub entries: Vec<BaselineEntry>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BaselineEntry {
+    pub config: ConfigName,
+    pub key: BTreeMap<SpanKey, TagValue>,
+    pub metric: MetricName,
+    pub baseline: StatsBaseline,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StatsBaseline {
+    pub anomaly_score: Option<AnomalyScoreBaseline>,
+    pub welford: Option<Welford<Quad>>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AnomalyScoreBaseline {
+    pub welford: Welford<Quad>,
+    pub reference: BTreeMap<ReferenceInterval, Window<Welford<Quad>>>,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum BundleError {
+    #[error("failed to encode baseline bundle: {0}")]
+    Encode(ciborium::ser::Error<std::io::Error>),
+    #[error("failed to compress baseline bundle: {0}")]
+    Compress(std::io::Error),
+    #[error("failed to decode baseline bundle: {0}")]
+    Decode(ciborium::de::Error<std::io::Error>),
+    #[error("unsupported baseline bundle version: {0}")]
+    Version(u32),
+    #[error("baseline bundle exceeds {0} bytes when decompressed")]
+    TooLarge(u64),
+}
+
+/// Reasons for not applying a bundle entry.
+#[derive(thiserror::Error, Debug)]
+pub enum BaselineSkip {
+    #[error("unknown config")]
+    UnknownConfig,
+    #[error("unknown metric")]
+    UnknownMetric,
+    #[error("group key does not match the config key")]
+    IncompatibleKey,
+    #[error("anomaly score is not enabled for this metric")]
+    NoAnomalyScore,
+    #[error("missing reference window: {0}")]
+    MissingWindow(ReferenceInterval),
+    #[error("incompatible reference window shape: {0}")]
+    IncompatibleWindow(ReferenceInterval),
+    #[error("baselines are not supported for quantile-based anomaly scores")]
+    QuantileScore,
+    #[error("baselines are not supported for seasonal anomaly scores")]
+    SeasonalScore,
+    #[error("mean/stddev is not calculated with the welford algorithm")]
+    NoWelford,
+}
+
+#[derive(Serialize, schemars::JsonSchema, ApiComponent, Debug)]
+pub struct ImportReport {
+    /// The number of entries applied.
+    pub applied: usize,
+    /// The number of entries skipped.
+    pub skipped: usize,
+    pub entries: Vec<ImportEntry>,
+}
+
+#[derive(Serialize, schemars::JsonSchema, Debug)]
+pub struct ImportEntry {
+    pub config: ConfigName,
+    /// The group key, as emitted in the metric labels.
+    pub key: BTreeMap<String, String>,
+    pub metric: MetricName,
+    #[serde(flatten)]
+    pub status: ImportStatus,
+}
+
+#[derive(Serialize, schemars::JsonSchema, Debug)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum ImportStatus {
+    Applied,
+    Skipped { reason: String },
+}
+
+impl BaselineBundle {
+    pub fn new(exported: DateTime<Utc>, entries: Vec<BaselineEntry>) -> Self {
+        Self {
+            version: BUNDLE_VERSION,
+            exported,
+            entries,
+        }
+    }
+
+    /// Serialize to gzip-compressed CBOR.
+    pub fn encode(&self) -> Result<Vec<u8>, BundleError> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        ciborium::into_writer(self, &mut encoder).map_err(BundleError::Encode)?;
+        encoder.finish().map_err(BundleError::Compress)
+    }
+
+    /// Decompress and deserialize a bundle, reading at most `limit`
+    /// decompressed bytes.
+    pub fn decode(data: &[u8], limit: u64) -> Result<Self, BundleError> {
+        let mut reader = GzDecoder::new(data).take(limit.saturating_add(1));
+        let bundle: Result<Self, _> = ciborium::from_reader(&mut reader);
+        if reader.limit() == 0 {
+            return Err(BundleError::TooLarge(limit));
+        }
+        let bundle = bundle.map_err(BundleError::Decode)?;
+        if bundle.version != BUNDLE_VERSION {
+            return Err(BundleError::Version(bundle.version));
+        }
+        Ok(bundle)
+    }
+}
+
+impl ImportReport {
+    pub fn new(entries: Vec<ImportEntry>) -> Self {
+        let applied = entries
+            .iter()
+            .filter(|entry| matches!(entry.status, ImportStatus::Applied))
+            .count();
+        Self {
+            applied,
+            skipped: entries.len() - applied,
+            entries,
+        }
+    }
+}
+
+impl ImportEntry {
+    pub fn new(
+        config: ConfigName,
+        key: &BTreeMap<SpanKey, TagValue>,
+        metric: MetricName,
+        result: Result<(), BaselineSkip>,
+    ) -> Self {
+        Self {
+            config,
+            key: key
+                .iter()
+                .map(|(name, value)| (name.label().into_string(), label_value(value)))
+                .collect(),
+            metric,
+            status: match result {
+                Ok(()) => ImportStatus::Applied,
+                Err(e) => ImportStatus::Skipped {
+                    reason: e.to_string(),
+                },
+            },
+        }
+    }
+}
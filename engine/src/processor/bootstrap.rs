@@ -0,0 +1,131 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use apistos::ApiComponent;
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+
+/// The progress of a bootstrap: the replay of the span history in
+/// OpenSearch that fills the baselines of a new deployment before live
+/// processing starts. Saved with the state after every chunk, so that
+/// an interrupted bootstrap resumes where it left off.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug)]
+pub struct BootstrapProgress {
+    /// The start of the replayed range.
+    pub from: DateTime<Utc>,
+    /// The end of the replayed range. Moved forward when the replay
+    /// falls more than a chunk behind live processing.
+    pub to: DateTime<Utc>,
+    /// The traces started before this time have been replayed.
+    pub done: DateTime<Utc>,
+}
+
+/// The bootstrap progress, as reported by the status endpoint.
+#[derive(Serialize, schemars::JsonSchema, ApiComponent, PartialEq, Clone, Debug)]
+pub struct BootstrapStatus {
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    /// The traces started before this time have been replayed.
+    pub done: DateTime<Utc>,
+    /// The replayed fraction of the range.
+    pub progress: f64,
+    /// Whether live processing has started.
+    pub finished: bool,
+    /// The error of the last failed chunk, which is retried after the
+    /// query interval.
+    pub last_error: Option<String>,
+}
+
+impl BootstrapProgress {
+    /// A bootstrap replaying `range` up to `to`.
+    pub fn new(to: DateTime<Utc>, range: TimeDelta) -> Self {
+        Self {
+            from: to - range,
+            to,
+            done: to - range,
+        }
+    }
+
+    /// The next chunk to replay, or `None` when the replay has caught
+    /// up with `live` to within a chunk. The live loop picks up from
+    /// there.
+    pub fn next_chunk(
+        &mut self,
+        live: DateTime<Utc>,
+        chunk: TimeDelta,
+    ) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+        if self.done >= self.to && live - self.to > chunk {
+            self.to = live;
+        }
+        (self.done < self.to).then(|| (self.done, (self.done + chunk).min(self.to)))
+    }
+
+    pub fn status(&self, finished: bool, last_error: Option<String>) -> BootstrapStatus {
+        let total = (self.to - self.from).num_milliseconds();
+        BootstrapStatus {
+            from: self.from,
+            to: self.to,
+            done: self.done,
+            progress: if total > 0 {
+                (self.done - self.from).num_milliseconds() as f64 / total as f64
+            } else {
+                1.0
+            },
+            finished,
+            last_error,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::{DateTime, TimeDelta};
+
+    use super::BootstrapProgress;
+
+    #[test]
+    fn chunks_until_caught_up() {
+        let to = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let hour = TimeDelta::hours(1);
+        let mut progress = BootstrapProgress::new(to, TimeDelta::minutes(150));
+        assert_eq!(progress.status(false, None).progress, 0.0);
+
+        let mut chunks = Vec::new();
+        while let Some((from, to)) = progress.next_chunk(to, hour) {
+            chunks.push(((to - from).num_minutes(), to));
+            progress.done = to;
+        }
+        assert_eq!(
+            chunks,
+            [
+                (60, to - TimeDelta::minutes(90)),
+                (60, to - hour / 2),
+                (30, to)
+            ]
+        );
+        assert_eq!(progress.status(true, None).progress, 1.0);
+
+        // Live processing picks up less than a chunk behind.
+        assert_eq!(progress.next_chunk(to + hour, hour), None);
+        // A replay that fell further behind is extended.
+        let live = to + TimeDelta::minutes(90);
+        assert_eq!(progress.next_chunk(live, hour), Some((to, to + hour)));
+        assert_eq!(progress.to, live);
+    }
+
+    #[test]
+    fn resume_after_restart() {
+        let to = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let hour = TimeDelta::hours(1);
+        let mut progress = BootstrapProgress::new(to, TimeDelta::hours(3));
+        let (_, end) = progress.next_chunk(to, hour).unwrap();
+        progress.done = end;
+
+        let mut data = Vec::new();
+        ciborium::into_writer(&progress, &mut data).unwrap();
+        let mut resumed = ciborium::from_reader::<BootstrapProgress, _>(data.as_slice()).unwrap();
+        assert_eq!(resumed, progress);
+        assert_eq!(resumed.next_chunk(to, hour), Some((end, end + hour)));
+    }
+}
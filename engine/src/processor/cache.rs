@@ -0,0 +1,379 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{Arc, Mutex},
+};
+
+use chrono::{DateTime, Utc};
+use prometheus_api::InstantQueryParams;
+use serde::Deserialize;
+use tokio::{
+    sync::{oneshot, watch},
+    task::JoinHandle,
+    time::Instant,
+};
+use url::Url;
+
+use crate::{
+    config::{CachedQuery, Config},
+    error::{Error, Result},
+};
+
+/// Lower bound on the refresh interval of cached queries.
+const MIN_REFRESH: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Evaluates the configured cached queries on a background task and
+/// keeps the latest result of each, following config updates.
+#[derive(Debug)]
+pub struct QueryCache {
+    task: JoinHandle<()>,
+    term_sender: oneshot::Sender<()>,
+    results: Arc<Mutex<BTreeMap<String, CachedResult>>>,
+}
+
+#[derive(Clone, Debug)]
+pub struct CachedResult {
+    /// The time of the last successful refresh.
+    pub updated: DateTime<Utc>,
+    /// Set when the last refresh failed. The data is kept from the
+    /// last successful refresh.
+    pub stale: bool,
+    /// The "data" field of the prometheus query response.
+    pub data: serde_json::Value,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "status", rename_all = "snake_case")]
+enum QueryResponse {
+    Success { data: serde_json::Value },
+    Error { error: String },
+}
+
+/// The configured queries, with the time of their next refresh.
+#[derive(Default, Debug)]
+struct Schedule(BTreeMap<String, (CachedQuery, Instant)>);
+
+impl QueryCache {
+    pub fn new(
+        promclient: reqwest::Client,
+        url: Url,
+        mut config_receiver: watch::Receiver<Arc<Config>>,
+    ) -> Self {
+        let (term_sender, mut term_receiver) = oneshot::channel::<()>();
+        let results = Arc::new(Mutex::new(BTreeMap::new()));
+
+        let task_results = results.clone();
+        let task = tokio::spawn(async move {
+            let mut schedule = Schedule::default();
+            schedule.update(
+                &config_receiver.borrow_and_update().cached_queries,
+                Instant::now(),
+            );
+
+            loop {
+                let next = schedule.next();
+                tokio::select! {
+                    _ = async {
+                        match next {
+                            Some(t) => tokio::time::sleep_until(t).await,
+                            None => std::future::pending().await,
+                        }
+                    } => {
+                        for query in schedule.due(Instant::now()) {
+                            refresh(&promclient, &url, &query, &task_results).await;
+                        }
+                    }
+                    Ok(()) = config_receiver.changed() => {
+                        let config = config_receiver.borrow_and_update().clone();
+                        let unchanged = schedule.update(&config.cached_queries, Instant::now());
+                        task_results
+                            .lock()
+                            .unwrap()
+                            .retain(|name, _| unchanged.contains(name));
+                    }
+                    _ = &mut term_receiver => {
+                        break;
+                    }
+                }
+            }
+        });
+
+        Self {
+            task,
+            term_sender,
+            results,
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<CachedResult> {
+        self.results.lock().unwrap().get(name).cloned()
+    }
+
+    pub async fn shutdown(self) -> Result<()> {
+        let _ = self.term_sender.send(());
+        self.task.await.map_err(Error::JoinQueryCache)
+    }
+}
+
+impl Schedule {
+    /// Replace the configured queries. Unchanged queries keep their
+    /// schedule; new and changed queries are due immediately. Returns
+    /// the names of the unchanged queries.
+    fn update(&mut self, queries: &[CachedQuery], now: Instant) -> BTreeSet<String> {
+        let mut prev = std::mem::take(&mut self.0);
+        let mut unchanged = BTreeSet::new();
+        queries.iter().for_each(|query| {
+            let next = match prev.remove(&query.name) {
+                Some((prev, next)) if prev == *query => {
+                    unchanged.insert(query.name.clone());
+                    next
+                }
+                _ => {
+                    unchanged.remove(&query.name);
+                    now
+                }
+            };
+            self.0.insert(query.name.clone(), (query.clone(), next));
+        });
+        unchanged
+    }
+
+    fn next(&self) -> Option<Instant> {
+        self.0.values().map(|(_, next)| *next).min()
+    }
+
+    /// The queries due at `now`. These are rescheduled.
+    fn due(&mut self, now: Instant) -> Vec<CachedQuery> {
+        self.0
+            .values_mut()
+            .filter(|(_, next)| *next <= now)
+            .map(|(query, next)| {
+                *next = now + refresh_interval(query);
+                query.clone()
+            })
+            .collect()
+    }
+}
+
+fn refresh_interval(query: &CachedQuery) -> std::time::Duration {
+    query
+        .refresh
+        .to_time_delta()
+        .to_std()
+        .unwrap_or_default()
+        .max(MIN_REFRESH)
+}
+
+/// Evaluate a query and store the result. On failure, the previous
+/// result is kept and marked as stale.
+async fn refresh(
+    promclient: &reqwest::Client,
+    url: &Url,
+    query: &CachedQuery,
+    results: &Mutex<BTreeMap<String, CachedResult>>,
+) {
+    match evaluate(promclient, url, query).await {
+        Ok(data) => {
+            results.lock().unwrap().insert(
+                query.name.clone(),
+                CachedResult {
+                    updated: Utc::now(),
+                    stale: false,
+                    data,
+                },
+            );
+        }
+        Err(e) => {
+            log::warn!("failed to refresh cached query {}: {e}", query.name);
+            if let Some(result) = results.lock().unwrap().get_mut(&query.name) {
+                result.stale = true;
+            }
+        }
+    }
+}
+
+async fn evaluate(
+    promclient: &reqwest::Client,
+    url: &Url,
+    query: &CachedQuery,
+) -> Result<serde_json::Value> {
+    let expr = query
+        .expr
+        .expr(&InstantQueryParams { time: None })
+        .to_string();
+    let res = promclient
+        .post(url.join("api/v1/query").map_err(Error::Url)?)
+        .form(&[("query", expr)])
+        .send()
+        .await
+        .map_err(Error::PromQuery)?;
+    match res
+        .json::<QueryResponse>()
+        .await
+        .map_err(Error::PromQuery)?
+    {
+        QueryResponse::Success { data } => Ok(data),
+        QueryResponse::Error { error } => Err(Error::PromQueryRes(error)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::BTreeMap,
+        sync::{Arc, Mutex},
+    };
+
+    use jaeger_anomaly_detection::{
+        Duration, ImmediateInterval, NoCombine, OperationFilter, TraceAggr, TraceExpr, TraceMetric,
+        TraceObject,
+    };
+    use serde_json::json;
+    use tokio::time::Instant;
+
+    use super::{refresh, QueryCache, Schedule, MIN_REFRESH};
+    use crate::{
+        config::{CachedQuery, Config},
+        processor::fake_http::FakeHttp,
+    };
+
+    /// A prometheus query endpoint answering every request with the
+    /// current (status, body) response.
+    async fn stub_prometheus(response: Arc<Mutex<(u16, String)>>) -> FakeHttp {
+        FakeHttp::start("/prometheus/", move |request| {
+            assert_eq!(request.method, "POST");
+            assert_eq!(request.path(), "/prometheus/api/v1/query");
+            response.lock().unwrap().clone()
+        })
+        .await
+    }
+
+    fn success(value: &str) -> (u16, String) {
+        let body = json!({
+            "status": "success",
+            "data": {
+                "resultType": "vector",
+                "result": [{ "metric": {}, "value": [1700000000, value] }]
+            }
+        });
+        (200, body.to_string())
+    }
+
+    fn failure() -> (u16, String) {
+        let body = json!({ "status": "error", "errorType": "bad_data", "error": "failed" });
+        (400, body.to_string())
+    }
+
+    fn query(name: &str, top: u64) -> CachedQuery {
+        CachedQuery {
+            name: String::from(name),
+            expr: TraceExpr::new(
+                TraceMetric::Duration,
+                TraceAggr::mean(
+                    ImmediateInterval::I5m,
+                    TraceObject::<NoCombine>::builder()
+                        .operation()
+                        .multiple(Some(top))
+                        .item(OperationFilter::new()),
+                ),
+            ),
+            refresh: Duration::Minutes(1),
+        }
+    }
+
+    #[test]
+    fn schedule_follows_config() {
+        let now = Instant::now();
+        let mut schedule = Schedule::default();
+        schedule.update(&[query("a", 5), query("b", 5)], now);
+        assert_eq!(schedule.next(), Some(now));
+        assert_eq!(schedule.due(now).len(), 2);
+        assert_eq!(
+            schedule.next(),
+            Some(now + std::time::Duration::from_secs(60))
+        );
+
+        let later = now + MIN_REFRESH;
+        let unchanged = schedule.update(&[query("a", 5), query("b", 10), query("c", 5)], later);
+        assert_eq!(unchanged.into_iter().collect::<Vec<_>>(), ["a"]);
+        let due = schedule.due(later);
+        assert_eq!(
+            due.iter()
+                .map(|query| query.name.as_str())
+                .collect::<Vec<_>>(),
+            ["b", "c"]
+        );
+
+        schedule.update(&[query("c", 5)], later);
+        assert_eq!(schedule.0.keys().collect::<Vec<_>>(), ["c"]);
+    }
+
+    #[tokio::test]
+    async fn refresh_marks_stale_on_failure() {
+        let response = Arc::new(Mutex::new(success("1")));
+        let server = stub_prometheus(response.clone()).await;
+        let url = server.url().clone();
+        let client = reqwest::Client::new();
+        let results = Mutex::new(BTreeMap::new());
+        let query = query("top", 5);
+
+        refresh(&client, &url, &query, &results).await;
+        let first = results.lock().unwrap().get("top").cloned().unwrap();
+        assert!(!first.stale);
+        assert_eq!(first.data["result"][0]["value"][1], "1");
+
+        *response.lock().unwrap() = failure();
+        refresh(&client, &url, &query, &results).await;
+        let second = results.lock().unwrap().get("top").cloned().unwrap();
+        assert!(second.stale);
+        assert_eq!(second.data, first.data);
+        assert_eq!(second.updated, first.updated);
+
+        *response.lock().unwrap() = success("2");
+        refresh(&client, &url, &query, &results).await;
+        let third = results.lock().unwrap().get("top").cloned().unwrap();
+        assert!(!third.stale);
+        assert_eq!(third.data["result"][0]["value"][1], "2");
+    }
+
+    #[tokio::test]
+    async fn cache_follows_config() {
+        let response = Arc::new(Mutex::new(success("1")));
+        let server = stub_prometheus(response).await;
+        let config = |queries| {
+            Arc::new(Config {
+                cached_queries: queries,
+                ..Config::default()
+            })
+        };
+        let (config_sender, config_receiver) =
+            tokio::sync::watch::channel(config(vec![query("top", 5)]));
+        let cache = QueryCache::new(
+            reqwest::Client::new(),
+            server.url().clone(),
+            config_receiver,
+        );
+
+        async fn wait_for<F: Fn() -> bool>(f: F) {
+            let deadline = Instant::now() + std::time::Duration::from_secs(10);
+            while !f() {
+                assert!(Instant::now() < deadline, "timeout");
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        }
+
+        wait_for(|| cache.get("top").is_some()).await;
+
+        // Rename the query.
+        config_sender.send(config(vec![query("top5", 5)])).unwrap();
+        wait_for(|| cache.get("top").is_none() && cache.get("top5").is_some()).await;
+
+        config_sender.send(config(Vec::new())).unwrap();
+        wait_for(|| cache.get("top5").is_none()).await;
+
+        cache.shutdown().await.unwrap();
+    }
+}
@@ -0,0 +1,612 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use tap::Pipe;
+
+use crate::{
+    error::{Error, Result},
+    metrics::Metrics,
+    Args, INDEX,
+};
+
+use super::{proc::backend_clients, sink::SinkKind};
+
+/// Fields the span queries rely on, with the mapping type they need.
+const REQUIRED_FIELDS: [(&str, Option<&str>); 5] = [
+    ("startTime", None),
+    ("traceID", None),
+    ("references", Some("nested")),
+    ("references.refType", None),
+    ("process.tags", Some("nested")),
+];
+
+/// The first delay between readiness probes at startup. Doubled after
+/// every probe, up to `MAX_STARTUP_BACKOFF`.
+const INITIAL_STARTUP_BACKOFF: Duration = Duration::from_millis(250);
+const MAX_STARTUP_BACKOFF: Duration = Duration::from_secs(30);
+
+/// The result of a startup check.
+#[derive(Debug)]
+pub struct Check {
+    pub name: &'static str,
+    pub outcome: Outcome,
+    pub detail: String,
+}
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Outcome {
+    Passed,
+    Failed,
+    /// The backend did not respond (yet): the connection was refused
+    /// or timed out, or it answered 503 while starting up.
+    Unavailable,
+    /// Not applicable, or not possible after an earlier failure.
+    Skipped,
+}
+
+/// Check that the backends are reachable and usable and that the
+/// state file can be read and written, so that misconfigured
+/// deployments fail at startup rather than at the first tick.
+pub async fn run(args: &Args) -> Vec<Check> {
+    let mut checks = Vec::new();
+    match backend_clients(args).await {
+        Ok((esclient, promclient)) => {
+            checks.push(Check::passed("certificates", "loaded"));
+            let opensearch = check_opensearch(args, &esclient).await;
+            let reachable = opensearch.outcome == Outcome::Passed;
+            checks.push(opensearch);
+            if reachable {
+                checks.extend(check_index(args, &esclient).await);
+            } else {
+                checks.push(Check::skipped("index", "opensearch is not reachable"));
+                checks.push(Check::skipped("mapping", "opensearch is not reachable"));
+            }
+            checks.push(check_remote_write(args, &promclient).await);
+        }
+        Err(e) => {
+            checks.push(Check::failed("certificates", e.to_string()));
+            ["opensearch", "index", "mapping", "remote-write"]
+                .into_iter()
+                .for_each(|name| checks.push(Check::skipped(name, "no client")));
+        }
+    }
+    checks.push(check_state(&args.state).await);
+    checks
+}
+
+/// Fail if any of the checks failed, or found a backend unavailable.
+pub fn ensure_passed(checks: &[Check]) -> Result<()> {
+    match checks
+        .iter()
+        .filter(|check| matches!(check.outcome, Outcome::Failed | Outcome::Unavailable))
+        .count()
+    {
+        0 => Ok(()),
+        n => Err(Error::ChecksFailed(n)),
+    }
+}
+
+/// Fail if any of the checks failed. Unavailable backends are waited
+/// for by the processor (see `wait_ready`).
+pub fn ensure_startable(checks: &[Check]) -> Result<()> {
+    match checks
+        .iter()
+        .filter(|check| check.outcome == Outcome::Failed)
+        .count()
+    {
+        0 => Ok(()),
+        n => Err(Error::ChecksFailed(n)),
+    }
+}
+
+/// Wait for OpenSearch, and with `--startup-wait-prometheus` for the
+/// remote-write endpoint, to respond, probing with exponential
+/// backoff. Gives up after `--startup-timeout-secs`, unless zero.
+pub async fn wait_ready(
+    args: &Args,
+    esclient: &reqwest::Client,
+    promclient: &reqwest::Client,
+) -> Result<()> {
+    let start = Instant::now();
+    let timeout =
+        (args.startup_timeout_secs > 0).then(|| Duration::from_secs(args.startup_timeout_secs));
+    let mut backoff = INITIAL_STARTUP_BACKOFF;
+    loop {
+        let pending = match check_opensearch(args, esclient).await {
+            check if check.outcome != Outcome::Passed => Some(check),
+            _ if args.startup_wait_prometheus => Some(check_remote_write(args, promclient).await)
+                .filter(|check| matches!(check.outcome, Outcome::Failed | Outcome::Unavailable)),
+            _ => None,
+        };
+        let Some(pending) = pending else {
+            log::info!(
+                "backends are ready after {:.1}s",
+                start.elapsed().as_secs_f64()
+            );
+            return Ok(());
+        };
+        if timeout.is_some_and(|timeout| start.elapsed() + backoff > timeout) {
+            return Err(Error::BackendsNotReady(
+                start.elapsed().as_secs(),
+                pending.to_string(),
+            ));
+        }
+        log::info!("waiting for the backends ({pending}); retrying in {backoff:?}");
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_STARTUP_BACKOFF);
+    }
+}
+
+impl Check {
+    fn passed(name: &'static str, detail: impl Into<String>) -> Self {
+        Self {
+            name,
+            outcome: Outcome::Passed,
+            detail: detail.into(),
+        }
+    }
+
+    fn failed(name: &'static str, detail: impl Into<String>) -> Self {
+        Self {
+            name,
+            outcome: Outcome::Failed,
+            detail: detail.into(),
+        }
+    }
+
+    fn skipped(name: &'static str, detail: impl Into<String>) -> Self {
+        Self {
+            name,
+            outcome: Outcome::Skipped,
+            detail: detail.into(),
+        }
+    }
+
+    fn from_result(name: &'static str, result: Result<String>) -> Self {
+        match result {
+            Ok(detail) => Self::passed(name, detail),
+            Err(e) if is_unavailable(&e) => Self {
+                name,
+                outcome: Outcome::Unavailable,
+                detail: e.to_string(),
+            },
+            Err(e) => Self::failed(name, e.to_string()),
+        }
+    }
+}
+
+impl Display for Check {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[{}] {}: {}", self.outcome, self.name, self.detail)
+    }
+}
+
+impl Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Outcome::Passed => write!(f, "ok"),
+            Outcome::Failed => write!(f, "FAILED"),
+            Outcome::Unavailable => write!(f, "UNAVAILABLE"),
+            Outcome::Skipped => write!(f, "skipped"),
+        }
+    }
+}
+
+/// Whether `e` means the backend is not up (yet), rather than
+/// misconfigured.
+fn is_unavailable(e: &Error) -> bool {
+    match e {
+        Error::Elastic(e) | Error::Prometheus(e) => e.is_connect() || e.is_timeout(),
+        Error::ElasticStatus(status, _) => *status == reqwest::StatusCode::SERVICE_UNAVAILABLE,
+        Error::PromRes(res) => res.starts_with("503 "),
+        _ => false,
+    }
+}
+
+async fn check_opensearch(args: &Args, client: &reqwest::Client) -> Check {
+    let result = opensearch_get(args, client, "").await.map(|info| {
+        let field = |path: &str| {
+            info.pointer(path)
+                .and_then(|value| value.as_str())
+                .unwrap_or("unknown")
+                .to_string()
+        };
+        format!(
+            "connected to {} (cluster {}, version {})",
+            args.opensearch_url,
+            field("/cluster_name"),
+            field("/version/number")
+        )
+    });
+    Check::from_result("opensearch", result)
+}
+
+/// Check that the index pattern matches at least one index and that
+/// the mappings of the matching indices have the required fields.
+async fn check_index(args: &Args, client: &reqwest::Client) -> [Check; 2] {
+    let mappings = match opensearch_get(args, client, &format!("{INDEX}/_mapping")).await {
+        Ok(serde_json::Value::Object(mappings)) => mappings,
+        Ok(other) => {
+            return [
+                Check::failed("index", format!("unexpected mapping response: {other}")),
+                Check::skipped("mapping", "no mappings"),
+            ]
+        }
+        Err(e) => {
+            return [
+                Check::failed("index", e.to_string()),
+                Check::skipped("mapping", "no mappings"),
+            ]
+        }
+    };
+    if mappings.is_empty() {
+        return [
+            Check::failed("index", format!("no index matches {INDEX}")),
+            Check::skipped("mapping", "no index"),
+        ];
+    }
+
+    let problems = mappings
+        .iter()
+        .flat_map(|(index, mapping)| {
+            let mapping = mapping.get("mappings").unwrap_or(mapping);
+            REQUIRED_FIELDS.iter().filter_map(move |(path, required)| {
+                let Some(field) = mapping_field(mapping, path) else {
+                    return Some(format!("{index}: {path} is missing"));
+                };
+                let actual = field.get("type").and_then(|t| t.as_str());
+                match required {
+                    Some(required) if actual != Some(*required) => Some(format!(
+                        "{index}: {path} is {} instead of {required}",
+                        actual.unwrap_or("an object")
+                    )),
+                    _ => None,
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    [
+        Check::passed("index", format!("{} indices match {INDEX}", mappings.len())),
+        if problems.is_empty() {
+            Check::passed("mapping", "all required fields are mapped")
+        } else {
+            Check::failed("mapping", problems.join("; "))
+        },
+    ]
+}
+
+/// Look up a (dotted) field in an index mapping.
+fn mapping_field<'a>(mapping: &'a serde_json::Value, path: &str) -> Option<&'a serde_json::Value> {
+    path.split('.').try_fold(mapping, |mapping, name| {
+        mapping.get("properties")?.get(name)
+    })
+}
+
+async fn opensearch_get(
+    args: &Args,
+    client: &reqwest::Client,
+    path: &str,
+) -> Result<serde_json::Value> {
+    let res = client
+        .get(args.opensearch_url.join(path).map_err(Error::Url)?)
+        .pipe(|c| match &args.opensearch_user {
+            Some(username) => c.basic_auth(username, args.opensearch_password.as_ref()),
+            None => c,
+        })
+        .send()
+        .await
+        .map_err(Error::Elastic)?;
+    let status = res.status();
+    let body = res.bytes().await.map_err(Error::Elastic)?;
+    if !status.is_success() {
+        return Err(Error::ElasticStatus(
+            status,
+            String::from_utf8_lossy(&body).into_owned(),
+        ));
+    }
+    serde_json::from_slice(&body).map_err(Error::ElasticDecode)
+}
+
+/// Check that the remote-write endpoint accepts an empty write.
+async fn check_remote_write(args: &Args, client: &reqwest::Client) -> Check {
+    if args.metrics_sink != SinkKind::RemoteWrite {
+        return Check::skipped("remote-write", "metrics are not written to prometheus");
+    }
+    Check::from_result("remote-write", empty_write(args, client).await)
+}
+
+async fn empty_write(args: &Args, client: &reqwest::Client) -> Result<String> {
+    let req = Metrics::new()
+        .write_request()
+        .build_http_request(&args.prometheus_url, "ContinuousC")
+        .map_err(Error::BuildPromRequest)?;
+    let res = client
+        .execute(reqwest::Request::try_from(req).map_err(Error::Prometheus)?)
+        .await
+        .map_err(Error::Prometheus)?;
+    let status = res.status();
+    let body = res.text().await.map_err(Error::Prometheus)?;
+    if !status.is_success() {
+        return Err(Error::PromRes(format!("{status}: {body}")));
+    }
+    Ok(format!("{} accepted an empty write", args.prometheus_url))
+}
+
+/// Check that the state file can be read and written, or created when
+/// it does not exist yet. Existing state is not modified.
+async fn check_state(path: &Path) -> Check {
+    Check::from_result("state", probe_state(path).await)
+}
+
+async fn probe_state(path: &Path) -> Result<String> {
+    if path.exists() {
+        let len = tokio::fs::read(path).await.map_err(Error::ReadState)?.len();
+        tokio::fs::OpenOptions::new()
+            .append(true)
+            .open(path)
+            .await
+            .map_err(Error::WriteState)?;
+        Ok(format!(
+            "{} is readable and writable ({len} bytes)",
+            path.display()
+        ))
+    } else {
+        let mut probe = path.as_os_str().to_owned();
+        probe.push(".check");
+        let probe = PathBuf::from(probe);
+        tokio::fs::write(&probe, b"")
+            .await
+            .map_err(Error::WriteState)?;
+        tokio::fs::remove_file(&probe)
+            .await
+            .map_err(Error::WriteState)?;
+        Ok(format!(
+            "{} does not exist yet and can be created",
+            path.display()
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use clap::Parser;
+    use tokio::net::TcpListener;
+
+    use super::{
+        check_index, check_opensearch, check_remote_write, check_state, ensure_passed, run,
+        wait_ready, Outcome,
+    };
+    use crate::{error::Error, processor::fake_http::FakeHttp, Args};
+
+    /// A server answering one request per response.
+    async fn mock_server(responses: Vec<(u16, &'static str)>) -> (String, FakeHttp) {
+        let server = FakeHttp::responses("/", responses).await;
+        (server.url().to_string(), server)
+    }
+
+    /// The method and target of every request, once all responses
+    /// were used.
+    async fn request_lines(server: &FakeHttp) -> Vec<String> {
+        server
+            .finished()
+            .await
+            .iter()
+            .map(|request| format!("{} {}", request.method, request.target))
+            .collect()
+    }
+
+    fn args(url: &str) -> Args {
+        Args::parse_from([
+            "jaeger-anomaly-detection-engine",
+            "--opensearch-url",
+            url,
+            "--prometheus-url",
+            format!("{url}api/v1/push").as_str(),
+        ])
+    }
+
+    const MAPPING: &str = r#"{
+        "jaeger-span-2024-01-01": {
+            "mappings": {
+                "properties": {
+                    "startTime": { "type": "long" },
+                    "traceID": { "type": "keyword" },
+                    "references": {
+                        "type": "nested",
+                        "properties": { "refType": { "type": "keyword" } }
+                    },
+                    "process": {
+                        "properties": {
+                            "tags": { "type": "nested" }
+                        }
+                    }
+                }
+            }
+        }
+    }"#;
+
+    #[tokio::test]
+    async fn opensearch_connectivity() {
+        let (url, server) = mock_server(vec![(
+            200,
+            r#"{"cluster_name": "traces", "version": {"number": "2.11.0"}}"#,
+        )])
+        .await;
+        let check = check_opensearch(&args(&url), &reqwest::Client::new()).await;
+        assert_eq!(check.outcome, Outcome::Passed, "{check}");
+        assert!(check.detail.contains("2.11.0"), "{check}");
+        assert_eq!(request_lines(&server).await, ["GET /"]);
+
+        let (url, server) = mock_server(vec![(401, "unauthorized")]).await;
+        let check = check_opensearch(&args(&url), &reqwest::Client::new()).await;
+        assert_eq!(check.outcome, Outcome::Failed);
+        assert!(check.detail.contains("401"), "{check}");
+        server.finished().await;
+
+        // Nothing listening.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        drop(listener);
+        let check = check_opensearch(&args(&url), &reqwest::Client::new()).await;
+        assert_eq!(check.outcome, Outcome::Unavailable);
+        assert!(ensure_passed(&[check]).is_err());
+    }
+
+    #[tokio::test]
+    async fn wait_for_late_opensearch() {
+        let (url, server) = mock_server(vec![
+            (503, "starting"),
+            (503, "starting"),
+            (
+                200,
+                r#"{"cluster_name": "traces", "version": {"number": "2.11.0"}}"#,
+            ),
+        ])
+        .await;
+        let client = reqwest::Client::new();
+        wait_ready(&args(&url), &client, &client).await.unwrap();
+        assert_eq!(server.finished().await.len(), 3);
+
+        // Nothing listening.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        drop(listener);
+        let mut args = args(&url);
+        args.startup_timeout_secs = 1;
+        let start = std::time::Instant::now();
+        let res = wait_ready(&args, &client, &client).await;
+        assert!(matches!(res, Err(Error::BackendsNotReady(_, _))));
+        assert!(start.elapsed() < std::time::Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn index_and_mapping() {
+        let (url, server) = mock_server(vec![(200, MAPPING)]).await;
+        let [index, mapping] = check_index(&args(&url), &reqwest::Client::new()).await;
+        assert_eq!(index.outcome, Outcome::Passed, "{index}");
+        assert_eq!(mapping.outcome, Outcome::Passed, "{mapping}");
+        assert_eq!(
+            request_lines(&server).await,
+            ["GET /jaeger-span-*/_mapping"]
+        );
+    }
+
+    #[tokio::test]
+    async fn no_matching_index() {
+        let (url, server) = mock_server(vec![(200, "{}")]).await;
+        let [index, mapping] = check_index(&args(&url), &reqwest::Client::new()).await;
+        assert_eq!(index.outcome, Outcome::Failed);
+        assert!(index.detail.contains("jaeger-span-*"), "{index}");
+        assert_eq!(mapping.outcome, Outcome::Skipped);
+        server.finished().await;
+    }
+
+    #[tokio::test]
+    async fn missing_mapping_fields() {
+        let (url, server) = mock_server(vec![(
+            200,
+            r#"{
+                "jaeger-span-2024-01-01": {
+                    "mappings": {
+                        "properties": {
+                            "startTime": { "type": "long" },
+                            "traceID": { "type": "keyword" },
+                            "references": {
+                                "properties": { "refType": { "type": "keyword" } }
+                            }
+                        }
+                    }
+                }
+            }"#,
+        )])
+        .await;
+        let [index, mapping] = check_index(&args(&url), &reqwest::Client::new()).await;
+        assert_eq!(index.outcome, Outcome::Passed);
+        assert_eq!(mapping.outcome, Outcome::Failed);
+        assert_eq!(
+            mapping.detail,
+            "jaeger-span-2024-01-01: references is an object instead of nested; \
+             jaeger-span-2024-01-01: process.tags is missing"
+        );
+        server.finished().await;
+    }
+
+    #[tokio::test]
+    async fn remote_write() {
+        let (url, server) = mock_server(vec![(200, ""), (400, "invalid tenant")]).await;
+        let args = args(&url);
+        let check = check_remote_write(&args, &reqwest::Client::new()).await;
+        assert_eq!(check.outcome, Outcome::Passed, "{check}");
+        let check = check_remote_write(&args, &reqwest::Client::new()).await;
+        assert_eq!(check.outcome, Outcome::Failed);
+        assert!(check.detail.contains("invalid tenant"), "{check}");
+        assert_eq!(
+            request_lines(&server).await,
+            ["POST /api/v1/push", "POST /api/v1/push"]
+        );
+
+        let args = Args::parse_from(["jaeger-anomaly-detection-engine", "--metrics-sink", "null"]);
+        let check = check_remote_write(&args, &reqwest::Client::new()).await;
+        assert_eq!(check.outcome, Outcome::Skipped);
+    }
+
+    #[tokio::test]
+    async fn state_file() {
+        let dir = std::env::temp_dir().join(format!(
+            "jaeger-anomaly-detection-check-{}",
+            std::process::id()
+        ));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+
+        let path = dir.join("state.cbor");
+        let check = check_state(&path).await;
+        assert_eq!(check.outcome, Outcome::Passed, "{check}");
+        assert!(!path.exists());
+        assert!(!dir.join("state.cbor.check").exists());
+
+        tokio::fs::write(&path, b"state").await.unwrap();
+        let check = check_state(&path).await;
+        assert_eq!(check.outcome, Outcome::Passed, "{check}");
+        assert_eq!(tokio::fs::read(&path).await.unwrap(), b"state");
+
+        let check = check_state(&dir.join("missing").join("state.cbor")).await;
+        assert_eq!(check.outcome, Outcome::Failed);
+
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn missing_certificates() {
+        let checks = run(&Args::parse_from([
+            "jaeger-anomaly-detection-engine",
+            "--opensearch-ca",
+            "/nonexistent/ca.crt",
+            "--state",
+            "/nonexistent/state.cbor",
+        ]))
+        .await;
+        let outcomes = checks
+            .iter()
+            .map(|check| (check.name, check.outcome))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            outcomes,
+            [
+                ("certificates", Outcome::Failed),
+                ("opensearch", Outcome::Skipped),
+                ("index", Outcome::Skipped),
+                ("mapping", Outcome::Skipped),
+                ("remote-write", Outcome::Skipped),
+                ("state", Outcome::Failed),
+            ]
+        );
+    }
+}
@@ -0,0 +1,98 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use std::{collections::BTreeMap, sync::Mutex, time::Duration};
+
+use serde::Serialize;
+
+/// Bucket bounds of the command duration histograms, in seconds.
+const DURATION_BOUNDS: [f64; 9] = [0.001, 0.01, 0.1, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+/// The durations of the commands handled by the processor task, per
+/// command type. Shared between the processor task, which records
+/// them, and the status endpoint.
+#[derive(Default, Debug)]
+pub struct CommandStats(Mutex<BTreeMap<&'static str, CommandDurations>>);
+
+/// The state of the command channel.
+#[derive(Serialize, schemars::JsonSchema, PartialEq, Clone, Debug)]
+pub struct CommandReport {
+    /// The number of commands waiting for the processor task.
+    pub queued: usize,
+    /// The time from enqueueing a command until the processor task
+    /// handled it, per command type.
+    pub durations: BTreeMap<String, CommandDurations>,
+}
+
+#[derive(Serialize, schemars::JsonSchema, PartialEq, Clone, Debug)]
+pub struct CommandDurations {
+    /// The number of commands handled since startup.
+    pub count: u64,
+    /// The total duration, in seconds.
+    pub sum: f64,
+    /// The number of commands handled within `le` seconds.
+    pub buckets: BTreeMap<String, u64>,
+}
+
+impl CommandStats {
+    pub fn record(&self, command: &'static str, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let mut durations = self.0.lock().unwrap();
+        let durations = durations
+            .entry(command)
+            .or_insert_with(|| CommandDurations {
+                count: 0,
+                sum: 0.0,
+                buckets: DURATION_BOUNDS
+                    .iter()
+                    .map(|bound| (bound.to_string(), 0))
+                    .collect(),
+            });
+        durations.count += 1;
+        durations.sum += seconds;
+        DURATION_BOUNDS
+            .iter()
+            .filter(|bound| seconds <= **bound)
+            .for_each(|bound| *durations.buckets.get_mut(&bound.to_string()).unwrap() += 1);
+    }
+
+    /// The report, with the current queue depth.
+    pub fn report(&self, queued: usize) -> CommandReport {
+        CommandReport {
+            queued,
+            durations: self
+                .0
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(command, durations)| (command.to_string(), durations.clone()))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::CommandStats;
+
+    #[test]
+    fn command_durations() {
+        let stats = CommandStats::default();
+        stats.record("import_baselines", Duration::from_millis(50));
+        stats.record("import_baselines", Duration::from_secs(3));
+
+        let report = stats.report(2);
+        assert_eq!(report.queued, 2);
+        let durations = &report.durations["import_baselines"];
+        assert_eq!(durations.count, 2);
+        assert!((durations.sum - 3.05).abs() < 1e-9);
+        assert_eq!(durations.buckets["0.01"], 0);
+        assert_eq!(durations.buckets["0.1"], 1);
+        assert_eq!(durations.buckets["2.5"], 1);
+        assert_eq!(durations.buckets["5"], 2);
+        assert_eq!(durations.buckets["30"], 2);
+    }
+}
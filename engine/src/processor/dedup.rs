@@ -0,0 +1,121 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use std::collections::{BTreeSet, VecDeque};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use jaeger_anomaly_detection::Duration;
+use serde::{Deserialize, Serialize};
+
+use crate::jaeger::{Span, SpanId, TraceId};
+
+/// Number of bins the ttl is divided in. Ids expire one bin at a time.
+const NUM_BINS: i32 = 4;
+
+/// Skip spans that were already inserted recently. Seen ids are kept in
+/// memory only and are lost on restart.
+#[derive(Serialize, Deserialize, schemars::JsonSchema, PartialEq, Clone, Debug)]
+pub struct DedupConfig {
+    /// How long span ids are remembered (in trace time).
+    pub ttl: Duration,
+    /// Maximum number of span ids to remember. When exceeded, the
+    /// oldest ids are forgotten first.
+    pub capacity: usize,
+}
+
+/// A time-windowed set of recently seen (trace_id, span_id) pairs,
+/// kept as one set per bin so that expiry drops whole bins.
+pub struct DedupSet {
+    config: DedupConfig,
+    bins: VecDeque<(DateTime<Utc>, BTreeSet<(TraceId, SpanId)>)>,
+    len: usize,
+}
+
+impl Default for DedupConfig {
+    fn default() -> Self {
+        Self {
+            ttl: Duration::Minutes(10),
+            capacity: 1_000_000,
+        }
+    }
+}
+
+impl DedupSet {
+    pub fn new(config: &DedupConfig) -> Self {
+        Self {
+            config: config.clone(),
+            bins: VecDeque::new(),
+            len: 0,
+        }
+    }
+
+    pub fn update(self, config: &DedupConfig) -> Self {
+        if &self.config == config {
+            self
+        } else {
+            Self::new(config)
+        }
+    }
+
+    fn bin_width(&self) -> TimeDelta {
+        (self.config.ttl.to_time_delta() / NUM_BINS).max(TimeDelta::microseconds(1))
+    }
+
+    /// Record the span as seen at time `t`. Returns true if it was
+    /// already seen within the ttl.
+    pub fn check(&mut self, t: DateTime<Utc>, span: &Span) -> bool {
+        let id = (span.trace_id.clone(), span.span_id.clone());
+        self.expire(t);
+        if self.bins.iter().any(|(_, ids)| ids.contains(&id)) {
+            return true;
+        }
+
+        let bin_width = self.bin_width();
+        if !self
+            .bins
+            .back()
+            .is_some_and(|(start, _)| t < *start + bin_width)
+        {
+            self.bins.push_back((t, BTreeSet::new()));
+        }
+        while self.len >= self.config.capacity && !self.bins.is_empty() {
+            self.evict_oldest();
+        }
+        if self.config.capacity > 0 {
+            if self.bins.is_empty() {
+                self.bins.push_back((t, BTreeSet::new()));
+            }
+            if let Some((_, ids)) = self.bins.back_mut() {
+                ids.insert(id);
+                self.len += 1;
+            }
+        }
+        false
+    }
+
+    /// Record all spans in the trace as seen at time `t`. Returns the
+    /// spans that were already seen, including repeated occurrences
+    /// within the trace itself.
+    pub fn duplicates<'a>(&mut self, t: DateTime<Utc>, trace: &'a [Span]) -> Vec<&'a Span> {
+        trace.iter().filter(|span| self.check(t, span)).collect()
+    }
+
+    fn expire(&mut self, t: DateTime<Utc>) {
+        let bin_width = self.bin_width();
+        let ttl = self.config.ttl.to_time_delta();
+        while self
+            .bins
+            .front()
+            .is_some_and(|(start, _)| *start + bin_width + ttl <= t)
+        {
+            self.evict_oldest();
+        }
+    }
+
+    fn evict_oldest(&mut self) {
+        if let Some((_, ids)) = self.bins.pop_front() {
+            self.len -= ids.len();
+        }
+    }
+}
@@ -0,0 +1,352 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+use apistos::ApiComponent;
+use serde::Serialize;
+use tokio::{
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+};
+use url::Url;
+
+use crate::{
+    config::{ConfigName, SpanKey},
+    error::{Error, Result},
+    jaeger::TagValue,
+    metrics::key_labels,
+};
+
+/// The number of expired groups waiting for the webhook. Groups
+/// expiring while the queue is full are dropped.
+const QUEUE_CAPACITY: usize = 10000;
+/// The maximum number of expired groups per notification.
+const BATCH_SIZE: usize = 500;
+/// The number of attempts per notification.
+const MAX_ATTEMPTS: u32 = 5;
+/// The delay before the first retry of a failed notification. The
+/// delay doubles with every retry, up to `MAX_BACKOFF`.
+const MIN_BACKOFF: std::time::Duration = std::time::Duration::from_secs(1);
+const MAX_BACKOFF: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// A group removed by the cleanup, with its baselines.
+#[derive(Serialize, PartialEq, Clone, Debug)]
+pub struct ExpiredGroup {
+    pub config: ConfigName,
+    /// The key labels of the group's series.
+    pub labels: BTreeMap<String, String>,
+}
+
+/// The body of a notification.
+#[derive(Serialize, Debug)]
+struct Notification<'a> {
+    expired: &'a [ExpiredGroup],
+}
+
+/// The outcome of the expiry notifications.
+#[derive(Serialize, schemars::JsonSchema, ApiComponent, PartialEq, Clone, Default, Debug)]
+pub struct ExpiryWebhookStatus {
+    /// The number of expired groups notified since startup.
+    pub delivered: u64,
+    /// The number of expired groups whose notification failed, after
+    /// retrying.
+    pub failed: u64,
+    /// The number of expired groups dropped because the queue was
+    /// full.
+    pub dropped: u64,
+    /// The error of the last failed notification.
+    pub last_error: Option<String>,
+}
+
+/// Where the processor queues the expired groups, without waiting
+/// for the webhook.
+#[derive(Clone, Debug)]
+pub struct ExpiryQueue {
+    sender: mpsc::Sender<ExpiredGroup>,
+    status: Arc<Mutex<ExpiryWebhookStatus>>,
+}
+
+/// Posts the groups removed by the cleanup to a webhook, in batches,
+/// on a background task. Failed notifications are retried with
+/// exponential backoff, unless the webhook rejected the request.
+#[derive(Debug)]
+pub struct ExpiryWebhook {
+    task: JoinHandle<()>,
+    term_sender: oneshot::Sender<()>,
+    queue: ExpiryQueue,
+}
+
+impl ExpiredGroup {
+    pub fn new(config: &ConfigName, key: &BTreeMap<SpanKey, TagValue>) -> Self {
+        Self {
+            config: config.clone(),
+            labels: key_labels(key).collect(),
+        }
+    }
+}
+
+impl ExpiryQueue {
+    /// Queue expired groups for notification. Groups that do not fit
+    /// in the queue are dropped.
+    pub fn push(&self, groups: impl IntoIterator<Item = ExpiredGroup>) {
+        let dropped = groups
+            .into_iter()
+            .map(|group| self.sender.try_send(group))
+            .filter(Result::is_err)
+            .count();
+        if dropped > 0 {
+            log::warn!("expiry webhook queue is full; dropped {dropped} expired groups");
+            self.status.lock().unwrap().dropped += dropped as u64;
+        }
+    }
+}
+
+impl ExpiryWebhook {
+    pub fn new(client: reqwest::Client, url: Url) -> Self {
+        Self::with_limits(client, url, QUEUE_CAPACITY, BATCH_SIZE, MIN_BACKOFF)
+    }
+
+    fn with_limits(
+        client: reqwest::Client,
+        url: Url,
+        capacity: usize,
+        batch_size: usize,
+        min_backoff: std::time::Duration,
+    ) -> Self {
+        let (term_sender, mut term_receiver) = oneshot::channel::<()>();
+        let (sender, mut receiver) = mpsc::channel(capacity);
+        let status = Arc::new(Mutex::new(ExpiryWebhookStatus::default()));
+
+        let task_status = status.clone();
+        let task = tokio::spawn(async move {
+            let mut batch = Vec::with_capacity(batch_size);
+            loop {
+                tokio::select! {
+                    n = receiver.recv_many(&mut batch, batch_size) => if n == 0 {
+                        break;
+                    },
+                    _ = &mut term_receiver => break,
+                }
+                let result = tokio::select! {
+                    result = notify(&client, &url, &batch, min_backoff) => result,
+                    _ = &mut term_receiver => break,
+                };
+                {
+                    let mut status = task_status.lock().unwrap();
+                    match result {
+                        Ok(()) => status.delivered += batch.len() as u64,
+                        Err(e) => {
+                            log::warn!("failed to notify {} expired groups: {e}", batch.len());
+                            status.failed += batch.len() as u64;
+                            status.last_error = Some(e.to_string());
+                        }
+                    }
+                }
+                batch.clear();
+            }
+        });
+
+        Self {
+            task,
+            term_sender,
+            queue: ExpiryQueue { sender, status },
+        }
+    }
+
+    pub fn queue(&self) -> ExpiryQueue {
+        self.queue.clone()
+    }
+
+    pub fn status(&self) -> ExpiryWebhookStatus {
+        self.queue.status.lock().unwrap().clone()
+    }
+
+    /// Stop the background task. Queued notifications are dropped.
+    pub async fn shutdown(self) -> Result<()> {
+        let _ = self.term_sender.send(());
+        self.task.await.map_err(Error::JoinExpiryWebhook)
+    }
+}
+
+/// Post a batch, retrying failed attempts.
+async fn notify(
+    client: &reqwest::Client,
+    url: &Url,
+    groups: &[ExpiredGroup],
+    min_backoff: std::time::Duration,
+) -> Result<()> {
+    let mut backoff = min_backoff;
+    let mut attempt = 1;
+    loop {
+        match post(client, url, groups).await {
+            Err(e) if attempt < MAX_ATTEMPTS && is_retryable(&e) => {
+                log::warn!("{e}; retrying in {}s", backoff.as_secs_f64());
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+async fn post(client: &reqwest::Client, url: &Url, groups: &[ExpiredGroup]) -> Result<()> {
+    let res = client
+        .post(url.clone())
+        .json(&Notification { expired: groups })
+        .send()
+        .await
+        .map_err(Error::ExpiryWebhook)?;
+    let status = res.status();
+    if status.is_success() {
+        Ok(())
+    } else {
+        let body = res.text().await.unwrap_or_default();
+        Err(Error::ExpiryWebhookStatus(status, body))
+    }
+}
+
+/// Connection errors, timeouts, server errors and rate limiting are
+/// retried; other rejections will not succeed on retry.
+fn is_retryable(e: &Error) -> bool {
+    match e {
+        Error::ExpiryWebhook(_) => true,
+        Error::ExpiryWebhookStatus(status, _) => {
+            status.is_server_error() || *status == reqwest::StatusCode::TOO_MANY_REQUESTS
+        }
+        _ => false,
+    }
+}
+
+/// A queue without a webhook, and its receiving end.
+#[cfg(test)]
+pub(crate) fn test_queue(capacity: usize) -> (ExpiryQueue, mpsc::Receiver<ExpiredGroup>) {
+    let (sender, receiver) = mpsc::channel(capacity);
+    let queue = ExpiryQueue {
+        sender,
+        status: Arc::default(),
+    };
+    (queue, receiver)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use url::Url;
+
+    use super::{test_queue, ExpiredGroup, ExpiryWebhook, MAX_ATTEMPTS};
+    use crate::{config::ConfigName, processor::fake_http::FakeHttp};
+
+    /// A server answering the requests with the given statuses, and
+    /// with 200 when they run out.
+    async fn stub_webhook(statuses: Vec<u16>) -> FakeHttp {
+        let mut statuses = statuses.into_iter();
+        FakeHttp::start("/hooks/expired", move |_| {
+            (statuses.next().unwrap_or(200), String::new())
+        })
+        .await
+    }
+
+    fn webhook(url: Url, capacity: usize) -> ExpiryWebhook {
+        ExpiryWebhook::with_limits(
+            reqwest::Client::new(),
+            url,
+            capacity,
+            2,
+            std::time::Duration::from_millis(10),
+        )
+    }
+
+    fn group(operation: &str) -> ExpiredGroup {
+        ExpiredGroup {
+            config: ConfigName::new("default"),
+            labels: BTreeMap::from([
+                (String::from("service_name"), String::from("frontend")),
+                (String::from("operation_name"), String::from(operation)),
+            ]),
+        }
+    }
+
+    async fn wait_for<F: Fn() -> bool>(f: F) {
+        let deadline = tokio::time::Instant::now() + std::time::Duration::from_secs(10);
+        while !f() {
+            assert!(tokio::time::Instant::now() < deadline, "timeout");
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn batched_notifications() {
+        let server = stub_webhook(Vec::new()).await;
+        let webhook = webhook(server.url().clone(), 100);
+        webhook
+            .queue()
+            .push(["a", "b", "c", "d", "e"].into_iter().map(group));
+
+        wait_for(|| webhook.status().delivered == 5).await;
+        let bodies = server
+            .requests()
+            .iter()
+            .map(|request| serde_json::from_slice::<serde_json::Value>(&request.body).unwrap())
+            .collect::<Vec<_>>();
+        let batches = bodies
+            .iter()
+            .map(|body| {
+                body["expired"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|group| {
+                        assert_eq!(group["config"], "default");
+                        assert_eq!(group["labels"]["service_name"], "frontend");
+                        group["labels"]["operation_name"].as_str().unwrap()
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(batches, [vec!["a", "b"], vec!["c", "d"], vec!["e"]]);
+
+        webhook.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn failed_notifications() {
+        // The first batch fails on every attempt, the second is
+        // rejected and the third is delivered after a retry.
+        let mut statuses = vec![503; MAX_ATTEMPTS as usize];
+        statuses.extend([400, 429]);
+        let server = stub_webhook(statuses).await;
+        let webhook = webhook(server.url().clone(), 100);
+        webhook
+            .queue()
+            .push(["a", "b", "c", "d", "e", "f"].into_iter().map(group));
+
+        wait_for(|| {
+            let status = webhook.status();
+            status.delivered + status.failed == 6
+        })
+        .await;
+        let status = webhook.status();
+        assert_eq!(status.delivered, 2);
+        assert_eq!(status.failed, 4);
+        assert!(status.last_error.unwrap().contains("400"));
+        assert_eq!(server.requests().len(), MAX_ATTEMPTS as usize + 3);
+
+        webhook.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn full_queue() {
+        let (queue, mut receiver) = test_queue(2);
+        queue.push(["a", "b", "c"].into_iter().map(group));
+        assert_eq!(queue.status.lock().unwrap().dropped, 1);
+        assert_eq!(receiver.recv().await, Some(group("a")));
+        assert_eq!(receiver.recv().await, Some(group("b")));
+    }
+}
@@ -0,0 +1,244 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+//! A minimal in-process HTTP server, for tests. Every connection
+//! carries a single request, which is read whole, answered by a
+//! handler and recorded; the connection is closed after the response.
+//! The fake backends are built on it, as are the stubs of the other
+//! endpoints the engine talks to.
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+};
+use url::Url;
+
+/// A running server. It stops when dropped.
+pub struct FakeHttp {
+    url: Url,
+    requests: Arc<Mutex<Vec<HttpRequest>>>,
+    /// The number of prepared responses, for `finished`.
+    expected: usize,
+    server: JoinHandle<()>,
+}
+
+/// A request received by the server.
+#[derive(Clone, Debug)]
+pub struct HttpRequest {
+    pub method: String,
+    /// The request target: the path and query string.
+    pub target: String,
+    headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl FakeHttp {
+    /// Start a server answering every request with the (status, body)
+    /// returned by `respond`. The returned url points to `path` on the
+    /// server.
+    pub async fn start<F>(path: &str, mut respond: F) -> Self
+    where
+        F: FnMut(&HttpRequest) -> (u16, String) + Send + 'static,
+    {
+        Self::serve(path, 0, move |request| Some(respond(request))).await
+    }
+
+    /// Start a server answering the requests with `responses`, in
+    /// order. Once they run out, connections are closed without an
+    /// answer, as if the server went away.
+    pub async fn responses<B: Into<String>>(path: &str, responses: Vec<(u16, B)>) -> Self {
+        let expected = responses.len();
+        let mut responses = responses
+            .into_iter()
+            .map(|(status, body)| (status, body.into()))
+            .collect::<VecDeque<_>>();
+        Self::serve(path, expected, move |_| responses.pop_front()).await
+    }
+
+    async fn serve<F>(path: &str, expected: usize, respond: F) -> Self
+    where
+        F: FnMut(&HttpRequest) -> Option<(u16, String)> + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}{path}", listener.local_addr().unwrap())).unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let server_requests = requests.clone();
+        let respond = Arc::new(Mutex::new(respond));
+        let server = tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let requests = server_requests.clone();
+                let respond = respond.clone();
+                tokio::spawn(async move {
+                    let Some(request) = read_request(&mut stream).await else {
+                        return;
+                    };
+                    let Some((status, body)) = (respond.lock().unwrap())(&request) else {
+                        return;
+                    };
+                    requests.lock().unwrap().push(request);
+                    write_response(&mut stream, status, &body).await;
+                });
+            }
+        });
+        Self {
+            url,
+            requests,
+            expected,
+            server,
+        }
+    }
+
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+
+    /// The requests answered so far, in the order they were answered.
+    pub fn requests(&self) -> Vec<HttpRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// Wait until all responses passed to `responses` were used, and
+    /// return the requests.
+    pub async fn finished(&self) -> Vec<HttpRequest> {
+        let deadline = tokio::time::Instant::now() + std::time::Duration::from_secs(10);
+        while self.requests.lock().unwrap().len() < self.expected {
+            assert!(tokio::time::Instant::now() < deadline, "timeout");
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        self.requests()
+    }
+}
+
+impl Drop for FakeHttp {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+impl HttpRequest {
+    /// The path, without the query string.
+    pub fn path(&self) -> &str {
+        self.target
+            .split_once('?')
+            .map_or(self.target.as_str(), |(path, _)| path)
+    }
+
+    /// The query string, or an empty string if there is none.
+    pub fn query(&self) -> &str {
+        self.target.split_once('?').map_or("", |(_, query)| query)
+    }
+
+    /// The value of a header; names are case insensitive.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The body, as text.
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+}
+
+/// Read a request with its body, if the client sent one.
+async fn read_request(stream: &mut TcpStream) -> Option<HttpRequest> {
+    let mut buf = Vec::new();
+    let mut chunk = [0; 4096];
+    let header_len = loop {
+        let n = stream.read(&mut chunk).await.ok()?;
+        if n == 0 {
+            return None;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if let Some(i) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break i + 4;
+        }
+    };
+    let head = String::from_utf8_lossy(&buf[..header_len]).into_owned();
+    let mut lines = head.lines();
+    let mut request_line = lines.next()?.split(' ');
+    let method = request_line.next()?.to_string();
+    let target = request_line.next()?.to_string();
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.to_string(), value.trim().to_string()))
+        .collect::<Vec<_>>();
+    let content_length = headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+        .map_or(0, |(_, len)| len.parse().unwrap());
+    while buf.len() < header_len + content_length {
+        let n = stream.read(&mut chunk).await.ok()?;
+        if n == 0 {
+            return None;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+    Some(HttpRequest {
+        method,
+        target,
+        headers,
+        body: buf[header_len..header_len + content_length].to_vec(),
+    })
+}
+
+/// Answer with a JSON body and close the connection.
+async fn write_response(stream: &mut TcpStream, status: u16, body: &str) {
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "Unknown",
+    };
+    let _ = stream
+        .write_all(
+            format!(
+                "HTTP/1.1 {status} {reason}\r\ncontent-type: application/json\r\n\
+                 content-length: {}\r\nconnection: close\r\n\r\n{body}",
+                body.len()
+            )
+            .as_bytes(),
+        )
+        .await;
+    let _ = stream.shutdown().await;
+}
+
+#[cfg(test)]
+mod test {
+    use super::FakeHttp;
+
+    #[tokio::test]
+    async fn answer_in_order() {
+        let server = FakeHttp::responses("/api/", vec![(503, "starting"), (200, "")]).await;
+        let client = reqwest::Client::new();
+        let url = server.url().join("v1/query?time=1").unwrap();
+        let res = client.post(url.clone()).body("a=1").send().await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.text().await.unwrap(), "starting");
+        let res = client.post(url.clone()).send().await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        // Without responses left, the connection is closed.
+        assert!(client.post(url).send().await.is_err());
+
+        let requests = server.finished().await;
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].method, "POST");
+        assert_eq!(requests[0].path(), "/api/v1/query");
+        assert_eq!(requests[0].query(), "time=1");
+        assert_eq!(requests[0].header("Content-Length"), Some("3"));
+        assert_eq!(requests[0].text(), "a=1");
+    }
+}
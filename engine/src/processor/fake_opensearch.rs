@@ -0,0 +1,759 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+//! An in-process fake of the OpenSearch endpoints used to fetch
+//! traces, for tests: point-in-time creation and deletion, and
+//! searches with `search_after` over an in-memory span corpus.
+//!
+//! Queries are evaluated for the clauses the engine sends (`bool`,
+//! `range`, `term`, `terms`, `regexp` and `nested`). Anything else is
+//! rejected the way OpenSearch rejects a malformed query, so that an
+//! unsupported query fails the test rather than matching nothing.
+//! Scripts are rejected as on a cluster with inline scripts disabled.
+//!
+//! Documents belong to an index; searches and points in time cover the
+//! indices matching the (comma-separated) patterns of their path.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use regex::Regex;
+use serde_json::json;
+use url::Url;
+
+use super::fake_http::{FakeHttp, HttpRequest};
+
+/// A running fake. The server stops when the fake is dropped.
+pub struct FakeOpenSearch {
+    state: Arc<Mutex<FakeState>>,
+    server: FakeHttp,
+}
+
+/// A request received by the fake.
+#[derive(Clone, Debug)]
+pub struct FakeRequest {
+    pub method: String,
+    /// The path, without the query string.
+    pub path: String,
+    /// The JSON body, or `Null` if there was none.
+    pub body: serde_json::Value,
+}
+
+/// The index of the documents passed to `FakeOpenSearch::start`.
+pub const FAKE_INDEX: &str = "jaeger-span-fake";
+
+#[derive(Default)]
+struct FakeState {
+    /// The documents, with their index.
+    docs: Vec<(String, serde_json::Value)>,
+    /// The index patterns of the open points in time.
+    pits: BTreeMap<String, Vec<String>>,
+    next_pit: u64,
+    requests: Vec<FakeRequest>,
+    failures: VecDeque<(u16, serde_json::Value)>,
+    reject_multi_index_pits: bool,
+}
+
+type Failure = (u16, serde_json::Value);
+
+impl FakeOpenSearch {
+    /// Start a fake holding the span documents `docs`, given as their
+    /// `_source`, in `FAKE_INDEX`.
+    pub async fn start(docs: Vec<serde_json::Value>) -> Self {
+        let state = Arc::new(Mutex::new(FakeState {
+            docs: docs
+                .into_iter()
+                .map(|doc| (FAKE_INDEX.to_string(), doc))
+                .collect(),
+            ..FakeState::default()
+        }));
+        let server_state = state.clone();
+        let server = FakeHttp::start("/", move |request| {
+            let (status, body) = server_state.lock().unwrap().handle(request);
+            (status, body.to_string())
+        })
+        .await;
+        Self { state, server }
+    }
+
+    /// The base url, to be passed as `--opensearch-url`.
+    pub fn url(&self) -> &Url {
+        self.server.url()
+    }
+
+    /// Add the span documents `docs` to `index`.
+    pub fn add_index(&self, index: &str, docs: Vec<serde_json::Value>) {
+        self.state
+            .lock()
+            .unwrap()
+            .docs
+            .extend(docs.into_iter().map(|doc| (index.to_string(), doc)));
+    }
+
+    /// Reject points in time over more than one index pattern, as a
+    /// cluster not supporting them would.
+    pub fn reject_multi_index_pits(&self) {
+        self.state.lock().unwrap().reject_multi_index_pits = true;
+    }
+
+    /// The requests received so far.
+    pub fn requests(&self) -> Vec<FakeRequest> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// The points in time that were created and not deleted.
+    pub fn open_pits(&self) -> usize {
+        self.state.lock().unwrap().pits.len()
+    }
+
+    /// Answer the next search, whatever it is, with `status` and
+    /// `body`. Failures queue up, one per search.
+    pub fn fail_next(&self, status: u16, body: serde_json::Value) {
+        self.state
+            .lock()
+            .unwrap()
+            .failures
+            .push_back((status, body));
+    }
+}
+
+impl FakeState {
+    fn handle(&mut self, request: &HttpRequest) -> (u16, serde_json::Value) {
+        let (path, query) = (request.path(), request.query());
+        let body = if request.body.is_empty() {
+            serde_json::Value::Null
+        } else {
+            match serde_json::from_slice(&request.body) {
+                Ok(body) => body,
+                Err(e) => return error(400, "parse_exception", &e.to_string()),
+            }
+        };
+        self.requests.push(FakeRequest {
+            method: request.method.clone(),
+            path: path.to_string(),
+            body: body.clone(),
+        });
+        match request.method.as_str() {
+            "GET" if path == "/" => (
+                200,
+                json!({ "cluster_name": "fake", "version": { "number": "2.11.0" } }),
+            ),
+            "POST" if path.ends_with("/_search/point_in_time") => self.create_pit(path, query),
+            "DELETE" if path == "/_search/point_in_time" => self.delete_pit(&body),
+            "POST" if path.ends_with("/_search") => match self.failures.pop_front() {
+                Some(failure) => failure,
+                None => match self.search(path, &body) {
+                    Ok(res) => (200, res),
+                    Err(failure) => failure,
+                },
+            },
+            method => error(
+                404,
+                "resource_not_found_exception",
+                &format!("no handler for {method} {path}"),
+            ),
+        }
+    }
+
+    fn create_pit(&mut self, path: &str, query: &str) -> (u16, serde_json::Value) {
+        if !query
+            .split('&')
+            .any(|param| param.starts_with("keep_alive="))
+        {
+            return error(
+                400,
+                "action_request_validation_exception",
+                "keep_alive is missing",
+            );
+        }
+        let patterns = path_patterns(path);
+        if self.reject_multi_index_pits && patterns.len() > 1 {
+            return error(
+                400,
+                "illegal_argument_exception",
+                "point in time over multiple indices is not supported",
+            );
+        }
+        self.next_pit += 1;
+        let pit_id = format!("pit-{}", self.next_pit);
+        self.pits.insert(pit_id.clone(), patterns);
+        (200, json!({ "pit_id": pit_id, "creation_time": 0 }))
+    }
+
+    fn delete_pit(&mut self, body: &serde_json::Value) -> (u16, serde_json::Value) {
+        let pit_id = body["pit_id"].as_str().unwrap_or_default();
+        if self.pits.remove(pit_id).is_none() {
+            return error(
+                404,
+                "search_context_missing_exception",
+                &format!("No search context found for id [{pit_id}]"),
+            );
+        }
+        (
+            200,
+            json!({ "pits": [{ "successful": true, "pit_id": pit_id }] }),
+        )
+    }
+
+    fn search(&self, path: &str, body: &serde_json::Value) -> Result<serde_json::Value, Failure> {
+        if body.get("aggs").is_some() {
+            return Err(error(
+                400,
+                "illegal_argument_exception",
+                "aggregations are not supported by the fake",
+            ));
+        }
+        let (pit_id, patterns) = match body.get("pit") {
+            Some(pit) => {
+                if path != "/_search" {
+                    return Err(error(
+                        400,
+                        "illegal_argument_exception",
+                        "[indices] cannot be used with point in time",
+                    ));
+                }
+                let pit_id = pit["id"].as_str().unwrap_or_default();
+                let Some(patterns) = self.pits.get(pit_id) else {
+                    return Err(error(
+                        404,
+                        "search_context_missing_exception",
+                        &format!("No search context found for id [{pit_id}]"),
+                    ));
+                };
+                (Some(pit_id), patterns.clone())
+            }
+            None => (None, path_patterns(path)),
+        };
+
+        let query = body
+            .get("query")
+            .cloned()
+            .unwrap_or_else(|| json!({ "match_all": {} }));
+        let mut hits = Vec::new();
+        for (index, doc) in &self.docs {
+            let searched = patterns.is_empty()
+                || patterns
+                    .iter()
+                    .any(|pattern| pattern_matches(pattern, index));
+            if searched && matches(&query, doc)? {
+                hits.push((index, doc));
+            }
+        }
+
+        // Only a single, numeric sort field is supported.
+        let sort = body
+            .get("sort")
+            .and_then(|sort| sort.as_array())
+            .and_then(|sort| sort.first())
+            .and_then(|sort| sort.as_object())
+            .and_then(|sort| sort.iter().next())
+            .map(|(field, opts)| (field.as_str(), opts["order"].as_str() == Some("desc")));
+        if let Some((field, desc)) = sort {
+            hits.sort_by_key(|(_, doc)| sort_key(doc, field));
+            if desc {
+                hits.reverse();
+            }
+            if let Some(after) = body.pointer("/search_after/0").and_then(|v| v.as_i64()) {
+                hits.retain(|(_, doc)| {
+                    sort_key(doc, field).is_some_and(|key| match desc {
+                        false => key > after,
+                        true => key < after,
+                    })
+                });
+            }
+        }
+
+        let total = hits.len();
+        let size = body
+            .get("size")
+            .and_then(|size| size.as_u64())
+            .map_or(10, |size| size as usize);
+        let excludes = body
+            .pointer("/_source/excludes")
+            .and_then(|excludes| excludes.as_array())
+            .map_or_else(Vec::new, |excludes| {
+                excludes.iter().filter_map(|e| e.as_str()).collect()
+            });
+        let hits = hits
+            .into_iter()
+            .take(size)
+            .map(|(index, doc)| {
+                let mut source = doc.clone();
+                if let Some(source) = source.as_object_mut() {
+                    excludes.iter().for_each(|field| {
+                        source.remove(*field);
+                    });
+                }
+                let mut hit = json!({ "_index": index, "_source": source });
+                if let Some((field, _)) = sort {
+                    hit["sort"] = json!([sort_key(doc, field)]);
+                }
+                hit
+            })
+            .collect::<Vec<_>>();
+
+        let mut res = json!({
+            "took": 1,
+            "timed_out": false,
+            "hits": {
+                "total": { "value": total, "relation": "eq" },
+                "hits": hits
+            }
+        });
+        if let Some(pit_id) = pit_id {
+            res["pit_id"] = json!(pit_id);
+        }
+        Ok(res)
+    }
+}
+
+/// The index patterns of a search or point in time path. An empty list
+/// covers all indices.
+fn path_patterns(path: &str) -> Vec<String> {
+    let indices = path
+        .trim_start_matches('/')
+        .split_once('/')
+        .map_or("", |(indices, _)| indices);
+    indices
+        .split(',')
+        .filter(|pattern| !pattern.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Whether `index` matches a pattern with `*` wildcards.
+fn pattern_matches(pattern: &str, index: &str) -> bool {
+    let pattern = pattern
+        .split('*')
+        .map(regex::escape)
+        .collect::<Vec<_>>()
+        .join(".*");
+    Regex::new(&format!("^{pattern}$")).unwrap().is_match(index)
+}
+
+/// The value of a numeric sort field.
+fn sort_key(doc: &serde_json::Value, field: &str) -> Option<i64> {
+    field_values(doc, field)
+        .first()
+        .and_then(|value| value.as_i64())
+}
+
+/// Whether `doc` matches `query`.
+fn matches(query: &serde_json::Value, doc: &serde_json::Value) -> Result<bool, Failure> {
+    let (kind, clause) = single(query)?;
+    match kind {
+        "match_all" => Ok(true),
+        "bool" => {
+            let clauses = |name: &str| match clause.get(name) {
+                Some(serde_json::Value::Array(queries)) => queries.iter().collect(),
+                Some(query) => vec![query],
+                None => Vec::new(),
+            };
+            let required = clauses("must")
+                .into_iter()
+                .chain(clauses("filter"))
+                .collect::<Vec<_>>();
+            for query in &required {
+                if !matches(query, doc)? {
+                    return Ok(false);
+                }
+            }
+            for query in clauses("must_not") {
+                if matches(query, doc)? {
+                    return Ok(false);
+                }
+            }
+            let should = clauses("should");
+            let min = clause
+                .get("minimum_should_match")
+                .and_then(|min| min.as_u64())
+                .unwrap_or(u64::from(required.is_empty() && !should.is_empty()));
+            let mut n = 0;
+            for query in should {
+                if matches(query, doc)? {
+                    n += 1;
+                }
+            }
+            Ok(n >= min)
+        }
+        "term" => {
+            let (field, value) = single(clause)?;
+            let value = value.get("value").unwrap_or(value);
+            Ok(field_values(doc, field).contains(&value))
+        }
+        "terms" => {
+            let (field, values) = single(clause)?;
+            let values = values
+                .as_array()
+                .ok_or_else(|| parse_error("[terms] query requires an array"))?;
+            Ok(field_values(doc, field)
+                .iter()
+                .any(|value| values.contains(value)))
+        }
+        "range" => {
+            let (field, bounds) = single(clause)?;
+            let bounds = bounds
+                .as_object()
+                .ok_or_else(|| parse_error("[range] query requires an object"))?
+                .iter()
+                .map(|(op, bound)| {
+                    let bound = bound
+                        .as_f64()
+                        .ok_or_else(|| parse_error("[range] bounds must be numbers"))?;
+                    match op.as_str() {
+                        "gt" | "gte" | "lt" | "lte" => Ok((op.as_str(), bound)),
+                        _ => Err(parse_error(&format!("unknown range operator [{op}]"))),
+                    }
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(field_values(doc, field).iter().any(|value| {
+                value.as_f64().is_some_and(|value| {
+                    bounds.iter().all(|(op, bound)| match *op {
+                        "gt" => value > *bound,
+                        "gte" => value >= *bound,
+                        "lt" => value < *bound,
+                        _ => value <= *bound,
+                    })
+                })
+            }))
+        }
+        "regexp" => {
+            let (field, pattern) = single(clause)?;
+            let pattern = pattern
+                .get("value")
+                .unwrap_or(pattern)
+                .as_str()
+                .ok_or_else(|| parse_error("[regexp] query requires a string"))?;
+            let re =
+                Regex::new(&format!("^(?:{pattern})$")).map_err(|e| parse_error(&e.to_string()))?;
+            Ok(field_values(doc, field)
+                .iter()
+                .any(|value| value.as_str().is_some_and(|value| re.is_match(value))))
+        }
+        "nested" => {
+            let path = clause["path"]
+                .as_str()
+                .ok_or_else(|| parse_error("[nested] requires a path"))?;
+            // Each nested object is matched on its own, with its
+            // fields at their full path.
+            for object in field_values(doc, path) {
+                let scoped = path
+                    .rsplit('.')
+                    .fold(object.clone(), |value, name| json!({ name: value }));
+                if matches(&clause["query"], &scoped)? {
+                    return Ok(true);
+                }
+            }
+            Ok(false)
+        }
+        "script" => Err(error(
+            400,
+            "illegal_argument_exception",
+            "cannot execute [inline] scripts",
+        )),
+        _ => Err(parse_error(&format!("unknown query [{kind}]"))),
+    }
+}
+
+/// The only key and value of a query clause.
+fn single(query: &serde_json::Value) -> Result<(&str, &serde_json::Value), Failure> {
+    match query.as_object() {
+        Some(query) if query.len() == 1 => {
+            let (key, value) = query.iter().next().unwrap();
+            Ok((key.as_str(), value))
+        }
+        _ => Err(parse_error(&format!("malformed query: {query}"))),
+    }
+}
+
+/// The values of the field at the dotted `path`. Arrays are
+/// flattened, like OpenSearch does for object fields.
+fn field_values<'a>(doc: &'a serde_json::Value, path: &str) -> Vec<&'a serde_json::Value> {
+    let flatten = |values: Vec<&'a serde_json::Value>| {
+        values
+            .into_iter()
+            .flat_map(|value| match value {
+                serde_json::Value::Array(values) => values.iter().collect(),
+                value => vec![value],
+            })
+            .collect::<Vec<_>>()
+    };
+    flatten(path.split('.').fold(vec![doc], |values, name| {
+        flatten(values)
+            .into_iter()
+            .filter_map(|value| value.get(name))
+            .collect()
+    }))
+}
+
+fn parse_error(reason: &str) -> Failure {
+    error(400, "parsing_exception", reason)
+}
+
+fn error(status: u16, r#type: &str, reason: &str) -> Failure {
+    (
+        status,
+        json!({
+            "status": status,
+            "error": {
+                "root_cause": [{ "type": r#type, "reason": reason }],
+                "type": r#type,
+                "reason": reason
+            }
+        }),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::{matches, path_patterns, pattern_matches, FakeOpenSearch, FAKE_INDEX};
+    use crate::processor::sim::{span_doc, start};
+
+    fn doc() -> serde_json::Value {
+        span_doc(
+            "0de61f1de7ee678bccb46f3dab804867",
+            "2",
+            Some("1"),
+            "frontend",
+            "GET",
+            start().timestamp_micros(),
+            1000,
+        )
+    }
+
+    #[test]
+    fn queries() {
+        let doc = doc();
+        let t = start().timestamp_micros();
+        for (query, expected) in [
+            (json!({ "match_all": {} }), true),
+            (
+                json!({ "range": { "startTime": { "gte": t, "lt": t + 1 } } }),
+                true,
+            ),
+            (json!({ "range": { "startTime": { "gt": t } } }), false),
+            (
+                json!({ "term": { "process.serviceName": "frontend" } }),
+                true,
+            ),
+            (
+                json!({ "term": { "process.serviceName": { "value": "backend" } } }),
+                false,
+            ),
+            (
+                json!({ "terms": { "traceID": ["other", "0de61f1de7ee678bccb46f3dab804867"] } }),
+                true,
+            ),
+            (
+                json!({ "regexp": { "process.serviceName": "front.*" } }),
+                true,
+            ),
+            (
+                json!({ "regexp": { "process.serviceName": "front" } }),
+                false,
+            ),
+            (
+                json!({
+                    "nested": {
+                        "path": "process.tags",
+                        "query": { "bool": { "must": [
+                            { "term": { "process.tags.key": "service.namespace" } },
+                            { "term": { "process.tags.value": "test" } }
+                        ] } }
+                    }
+                }),
+                true,
+            ),
+            // Both terms must hold for the same nested object.
+            (
+                json!({
+                    "nested": {
+                        "path": "process.tags",
+                        "query": { "bool": { "must": [
+                            { "term": { "process.tags.key": "service.namespace" } },
+                            { "term": { "process.tags.value": "test-0" } }
+                        ] } }
+                    }
+                }),
+                false,
+            ),
+            (
+                json!({ "bool": { "must_not": {
+                    "nested": {
+                        "path": "references",
+                        "query": { "term": { "references.refType": { "value": "CHILD_OF" } } }
+                    }
+                } } }),
+                false,
+            ),
+            (
+                json!({ "bool": {
+                    "should": [
+                        { "term": { "process.serviceName": "backend" } },
+                        { "term": { "operationName": "GET" } }
+                    ],
+                    "minimum_should_match": 1
+                } }),
+                true,
+            ),
+        ] {
+            assert_eq!(matches(&query, &doc).unwrap(), expected, "{query}");
+        }
+
+        let (status, _) = matches(&json!({ "script": {} }), &doc).unwrap_err();
+        assert_eq!(status, 400);
+        let (status, _) = matches(&json!({ "wildcard": {} }), &doc).unwrap_err();
+        assert_eq!(status, 400);
+    }
+
+    #[tokio::test]
+    async fn pit_search_after() {
+        let t = start().timestamp_micros();
+        let docs = (0..5)
+            .map(|i| {
+                span_doc(
+                    &format!("{i:032x}"),
+                    "1",
+                    None,
+                    "frontend",
+                    "GET",
+                    t + i,
+                    1000,
+                )
+            })
+            .collect();
+        let fake = FakeOpenSearch::start(docs).await;
+        let client = reqwest::Client::new();
+        let pit = client
+            .post(
+                fake.url()
+                    .join("jaeger-span-*/_search/point_in_time?keep_alive=1m")
+                    .unwrap(),
+            )
+            .send()
+            .await
+            .unwrap()
+            .json::<serde_json::Value>()
+            .await
+            .unwrap()["pit_id"]
+            .clone();
+        assert_eq!(fake.open_pits(), 1);
+
+        let search = |after: Option<i64>| {
+            let mut body = json!({
+                "query": { "range": { "startTime": { "gte": t + 1 } } },
+                "size": 2,
+                "pit": { "id": pit, "keep_alive": "1m" },
+                "sort": [{ "startTime": { "order": "asc" } }],
+                "_source": { "excludes": ["logs"] }
+            });
+            if let Some(after) = after {
+                body["search_after"] = json!([after]);
+            }
+            client
+                .post(fake.url().join("_search").unwrap())
+                .json(&body)
+                .send()
+        };
+        let res = search(None)
+            .await
+            .unwrap()
+            .json::<serde_json::Value>()
+            .await
+            .unwrap();
+        assert_eq!(res["pit_id"], pit);
+        assert_eq!(res["hits"]["total"]["value"], 4);
+        let hits = res["hits"]["hits"].as_array().unwrap();
+        assert_eq!(hits.len(), 2);
+        assert_eq!(hits[1]["sort"], json!([t + 2]));
+        assert!(hits[0]["_source"].get("logs").is_none());
+        let res = search(Some(t + 2))
+            .await
+            .unwrap()
+            .json::<serde_json::Value>()
+            .await
+            .unwrap();
+        assert_eq!(res["hits"]["hits"][0]["sort"], json!([t + 3]));
+
+        fake.fail_next(429, json!({}));
+        assert_eq!(search(None).await.unwrap().status(), 429);
+
+        let res = client
+            .delete(fake.url().join("_search/point_in_time").unwrap())
+            .json(&json!({ "pit_id": pit }))
+            .send()
+            .await
+            .unwrap();
+        assert!(res.status().is_success());
+        assert_eq!(fake.open_pits(), 0);
+        assert_eq!(search(None).await.unwrap().status(), 404);
+        assert_eq!(fake.requests().len(), 6);
+    }
+
+    #[tokio::test]
+    async fn index_patterns() {
+        assert_eq!(path_patterns("/_search"), Vec::<String>::new());
+        assert_eq!(
+            path_patterns("/archive-*,jaeger-span-*/_search/point_in_time"),
+            vec!["archive-*", "jaeger-span-*"]
+        );
+        assert!(pattern_matches("jaeger-span-*", FAKE_INDEX));
+        assert!(!pattern_matches("archive-*", FAKE_INDEX));
+        assert!(pattern_matches("archive-*", "archive-2023.11.14"));
+
+        let fake = FakeOpenSearch::start(vec![doc()]).await;
+        fake.add_index("archive-2023.11.14", vec![doc()]);
+        let client = reqwest::Client::new();
+        let indices = |path: &str| {
+            let request = client
+                .post(fake.url().join(path).unwrap())
+                .json(&json!({ "query": { "match_all": {} } }))
+                .send();
+            async move {
+                let res = request
+                    .await
+                    .unwrap()
+                    .json::<serde_json::Value>()
+                    .await
+                    .unwrap();
+                res["hits"]["hits"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|hit| hit["_index"].as_str().unwrap().to_string())
+                    .collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(indices("jaeger-span-*/_search").await, vec![FAKE_INDEX]);
+        assert_eq!(
+            indices("archive-*/_search").await,
+            vec!["archive-2023.11.14"]
+        );
+        assert_eq!(indices("archive-*,jaeger-span-*/_search").await.len(), 2);
+
+        fake.reject_multi_index_pits();
+        let status = |path: &str| {
+            client
+                .post(fake.url().join(path).unwrap())
+                .query(&[("keep_alive", "1m")])
+                .send()
+        };
+        assert_eq!(
+            status("archive-*,jaeger-span-*/_search/point_in_time")
+                .await
+                .unwrap()
+                .status(),
+            400
+        );
+        assert!(status("archive-*/_search/point_in_time")
+            .await
+            .unwrap()
+            .status()
+            .is_success());
+    }
+}
@@ -0,0 +1,274 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+//! An in-process remote-write receiver, for tests. Requests are
+//! decoded (snappy block format, then the `WriteRequest` protobuf) and
+//! the samples collected, so that tests can compare what was written
+//! rather than request sizes.
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+use chrono::DateTime;
+use url::Url;
+
+use crate::metrics::Metrics;
+
+use super::{fake_http::FakeHttp, sim::Sample};
+
+/// A running receiver. The server stops when it is dropped.
+pub struct FakeRemoteWrite {
+    state: Arc<Mutex<ReceiverState>>,
+    server: FakeHttp,
+}
+
+#[derive(Default)]
+struct ReceiverState {
+    requests: usize,
+    samples: Vec<Sample>,
+}
+
+/// A protobuf field value, by wire type.
+enum Field<'a> {
+    Varint(u64),
+    Fixed64(u64),
+    Bytes(&'a [u8]),
+    Fixed32,
+}
+
+impl FakeRemoteWrite {
+    pub async fn start() -> Self {
+        let state = Arc::new(Mutex::new(ReceiverState::default()));
+        let server_state = state.clone();
+        let server = FakeHttp::start("/api/v1/push", move |request| {
+            let mut state = server_state.lock().unwrap();
+            state.requests += 1;
+            match decode_write_request(&request.body) {
+                Ok(samples) => {
+                    state.samples.extend(samples);
+                    (200, String::new())
+                }
+                Err(e) => (400, e),
+            }
+        })
+        .await;
+        Self { state, server }
+    }
+
+    /// The push url, to be passed as `--prometheus-url`.
+    pub fn url(&self) -> &Url {
+        self.server.url()
+    }
+
+    /// The number of requests received.
+    pub fn requests(&self) -> usize {
+        self.state.lock().unwrap().requests
+    }
+
+    /// The samples received so far, in the order they arrived.
+    pub fn samples(&self) -> Vec<Sample> {
+        self.state.lock().unwrap().samples.clone()
+    }
+
+    /// The samples received so far.
+    pub fn metrics(&self) -> Metrics {
+        let mut metrics = Metrics::new();
+        self.state
+            .lock()
+            .unwrap()
+            .samples
+            .iter()
+            .for_each(|(labels, t, value)| metrics.insert(labels.clone(), *t, *value));
+        metrics
+    }
+}
+
+fn decode_write_request(body: &[u8]) -> Result<Vec<Sample>, String> {
+    let buf = snappy_decompress(body)?;
+    let mut samples = Vec::new();
+    for (field, value) in fields(&buf)? {
+        let (1, Field::Bytes(series)) = (field, value) else {
+            continue;
+        };
+        let mut labels = BTreeMap::new();
+        let mut values = Vec::new();
+        for (field, value) in fields(series)? {
+            match (field, value) {
+                (1, Field::Bytes(label)) => {
+                    let (mut name, mut value) = (String::new(), String::new());
+                    for (field, s) in fields(label)? {
+                        match (field, s) {
+                            (1, Field::Bytes(s)) => name = utf8(s)?,
+                            (2, Field::Bytes(s)) => value = utf8(s)?,
+                            _ => {}
+                        }
+                    }
+                    labels.insert(name, value);
+                }
+                (2, Field::Bytes(sample)) => {
+                    let (mut value, mut timestamp) = (0.0, 0);
+                    for (field, v) in fields(sample)? {
+                        match (field, v) {
+                            (1, Field::Fixed64(bits)) => value = f64::from_bits(bits),
+                            (2, Field::Varint(t)) => timestamp = t as i64,
+                            _ => {}
+                        }
+                    }
+                    values.push((timestamp, value));
+                }
+                _ => {}
+            }
+        }
+        for (timestamp, value) in values {
+            let t = DateTime::from_timestamp_millis(timestamp).ok_or("invalid timestamp")?;
+            samples.push((labels.clone(), t, value));
+        }
+    }
+    Ok(samples)
+}
+
+fn utf8(s: &[u8]) -> Result<String, String> {
+    String::from_utf8(s.to_vec()).map_err(|e| e.to_string())
+}
+
+/// Split a protobuf message into its fields.
+fn fields(mut buf: &[u8]) -> Result<Vec<(u64, Field<'_>)>, String> {
+    let mut fields = Vec::new();
+    while !buf.is_empty() {
+        let key = varint(&mut buf)?;
+        let value = match key & 7 {
+            0 => Field::Varint(varint(&mut buf)?),
+            1 => Field::Fixed64(u64::from_le_bytes(take(&mut buf, 8)?.try_into().unwrap())),
+            2 => {
+                let len = varint(&mut buf)? as usize;
+                Field::Bytes(take(&mut buf, len)?)
+            }
+            5 => {
+                take(&mut buf, 4)?;
+                Field::Fixed32
+            }
+            wire_type => return Err(format!("unsupported wire type {wire_type}")),
+        };
+        fields.push((key >> 3, value));
+    }
+    Ok(fields)
+}
+
+fn varint(buf: &mut &[u8]) -> Result<u64, String> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = buf.split_first().ok_or("truncated varint")?;
+        *buf = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(String::from("varint too long"))
+}
+
+fn take<'a>(buf: &mut &'a [u8], n: usize) -> Result<&'a [u8], String> {
+    if buf.len() < n {
+        return Err(String::from("truncated input"));
+    }
+    let (head, rest) = buf.split_at(n);
+    *buf = rest;
+    Ok(head)
+}
+
+/// Decompress the snappy block format: the uncompressed length,
+/// followed by literals and back-references.
+fn snappy_decompress(mut buf: &[u8]) -> Result<Vec<u8>, String> {
+    let len = varint(&mut buf)? as usize;
+    let mut out = Vec::with_capacity(len);
+    while let Some((&tag, rest)) = buf.split_first() {
+        buf = rest;
+        let (n, offset) = match tag & 3 {
+            0 => {
+                let mut n = usize::from(tag >> 2);
+                if n >= 60 {
+                    n = take(&mut buf, n - 59)?
+                        .iter()
+                        .rev()
+                        .fold(0, |n, byte| (n << 8) | usize::from(*byte));
+                }
+                out.extend_from_slice(take(&mut buf, n + 1)?);
+                continue;
+            }
+            1 => {
+                let byte = take(&mut buf, 1)?[0];
+                (
+                    4 + usize::from((tag >> 2) & 7),
+                    (usize::from(tag >> 5) << 8) | usize::from(byte),
+                )
+            }
+            2 => {
+                let bytes = take(&mut buf, 2)?;
+                (
+                    1 + usize::from(tag >> 2),
+                    usize::from(u16::from_le_bytes([bytes[0], bytes[1]])),
+                )
+            }
+            _ => {
+                let bytes = take(&mut buf, 4)?;
+                (
+                    1 + usize::from(tag >> 2),
+                    u32::from_le_bytes(bytes.try_into().unwrap()) as usize,
+                )
+            }
+        };
+        if offset == 0 || offset > out.len() {
+            return Err(String::from("invalid back-reference"));
+        }
+        for _ in 0..n {
+            let byte = out[out.len() - offset];
+            out.push(byte);
+        }
+    }
+    if out.len() != len {
+        return Err(format!("expected {len} bytes, got {}", out.len()));
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use chrono::DateTime;
+
+    use super::FakeRemoteWrite;
+    use crate::metrics::Metrics;
+
+    #[tokio::test]
+    async fn decodes_write_requests() {
+        let receiver = FakeRemoteWrite::start().await;
+        let t = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let mut metrics = Metrics::new();
+        ["a", "b", "c"].iter().enumerate().for_each(|(i, name)| {
+            // Repeated label values compress to back-references.
+            let labels = BTreeMap::from_iter([
+                (String::from("__name__"), name.to_string()),
+                (String::from("config"), "default".repeat(20)),
+            ]);
+            metrics.insert(labels.clone(), t, i as f64 + 0.5);
+            metrics.insert(labels, t + chrono::TimeDelta::seconds(10), f64::NAN);
+        });
+        let expected = metrics.to_debug_lines();
+
+        let req = metrics
+            .write_request()
+            .build_http_request(receiver.url(), "ContinuousC")
+            .unwrap();
+        let res = reqwest::Client::new()
+            .execute(reqwest::Request::try_from(req).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        assert_eq!(receiver.requests(), 1);
+        assert_eq!(receiver.metrics().to_debug_lines(), expected);
+    }
+}
@@ -20,6 +20,7 @@ pub struct HistogramState {
     sum: f64,
 }
 
+#[derive(Clone)]
 pub struct HistogramProcessor {
     bounds: Vec<f64>,
     bins: Vec<f64>,
@@ -74,7 +75,7 @@ impl HistogramProcessor {
             .iter()
             .copied()
             .zip(&mut self.bins)
-            .take_while(|(bound, _)| value <= *bound)
+            .filter(|(bound, _)| value <= *bound)
             .for_each(|(_, count)| *count += 1.0);
     }
 
@@ -110,3 +111,23 @@ impl HistogramProcessor {
         });
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{HistogramConfig, HistogramProcessor};
+
+    #[test]
+    fn cumulative_buckets() {
+        let mut histogram = HistogramProcessor::new(&HistogramConfig {
+            bounds: vec![10.0, 100.0, 1000.0],
+        });
+        histogram.insert(5.0);
+        histogram.insert(50.0);
+        histogram.insert(500.0);
+        histogram.insert(5000.0);
+
+        let state = histogram.save();
+        assert_eq!(state.bins, [1.0, 2.0, 3.0]);
+        assert_eq!(state.count, 4);
+    }
+}
@@ -0,0 +1,208 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use std::{
+    ops::Range,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use chrono::{DateTime, TimeDelta, Utc};
+
+/// The index patterns holding the spans: the primary pattern, and an
+/// archive pattern for the spans that the index lifecycle policy moved
+/// out of it, if configured.
+///
+/// The traces of a range are fetched through a single point in time
+/// over all selected patterns, so that they are merged in start time
+/// order, until OpenSearch rejects a point in time over several
+/// indices; from then on, every pattern is fetched in passes of its
+/// own (see `passes`). Spans moved to the archive can be found in both
+/// patterns for a while, so the traces of a range are de-duplicated by
+/// trace id when several patterns are selected.
+#[derive(Debug)]
+pub struct SpanIndices {
+    primary: String,
+    archive: Option<ArchiveIndex>,
+    combined_pit: AtomicBool,
+}
+
+#[derive(Debug)]
+struct ArchiveIndex {
+    pattern: String,
+    /// The age after which spans may have moved to the archive.
+    after: TimeDelta,
+}
+
+impl SpanIndices {
+    pub fn new(primary: &str, archive: Option<(&str, TimeDelta)>) -> Self {
+        Self {
+            primary: primary.to_string(),
+            archive: archive.map(|(pattern, after)| ArchiveIndex {
+                pattern: pattern.to_string(),
+                after,
+            }),
+            combined_pit: AtomicBool::new(true),
+        }
+    }
+
+    /// The patterns to query for a range of spans starting at `from`,
+    /// oldest first. The archive is only queried for ranges starting
+    /// before the cutoff age. The primary pattern is always queried:
+    /// spans older than the cutoff stay there until the lifecycle
+    /// policy gets to them.
+    pub fn select(&self, now: DateTime<Utc>, from: DateTime<Utc>) -> Vec<&str> {
+        self.archive
+            .as_ref()
+            .filter(|archive| from < now - archive.after)
+            .map(|archive| archive.pattern.as_str())
+            .into_iter()
+            .chain(std::iter::once(self.primary.as_str()))
+            .collect()
+    }
+
+    /// The passes fetching `range` one pattern at a time, in start time
+    /// order as far as possible: the part of the range before the
+    /// cutoff age from the patterns selected for it, archive first,
+    /// then the rest of the range from the primary pattern.
+    pub fn passes(
+        &self,
+        now: DateTime<Utc>,
+        range: Range<DateTime<Utc>>,
+    ) -> Vec<(&str, Range<DateTime<Utc>>)> {
+        match self
+            .archive
+            .as_ref()
+            .map(|archive| (archive, now - archive.after))
+            .filter(|(_, cutoff)| range.start < *cutoff)
+        {
+            Some((archive, cutoff)) => {
+                let split = cutoff.min(range.end);
+                let mut passes = vec![
+                    (archive.pattern.as_str(), range.start..split),
+                    (self.primary.as_str(), range.start..split),
+                ];
+                if split < range.end {
+                    passes.push((self.primary.as_str(), split..range.end));
+                }
+                passes
+            }
+            None => vec![(self.primary.as_str(), range)],
+        }
+    }
+
+    /// All patterns, for lookups of spans of unknown age.
+    pub fn all(&self) -> Vec<&str> {
+        self.archive
+            .as_ref()
+            .map(|archive| archive.pattern.as_str())
+            .into_iter()
+            .chain(std::iter::once(self.primary.as_str()))
+            .collect()
+    }
+
+    /// Whether to fetch several patterns through a single point in
+    /// time.
+    pub fn combined_pit(&self) -> bool {
+        self.combined_pit.load(Ordering::Relaxed)
+    }
+
+    /// Switch to a pass per pattern, after OpenSearch rejected a point
+    /// in time over `indices`. Returns whether the fetch should be
+    /// retried that way.
+    pub fn fall_back(&self, indices: &[&str], status: reqwest::StatusCode) -> bool {
+        let rejected = indices.len() > 1
+            && status.is_client_error()
+            && self.combined_pit.swap(false, Ordering::Relaxed);
+        if rejected {
+            log::warn!(
+                "opensearch rejected a point in time over {} ({status}); \
+                 fetching the index patterns one by one",
+                indices.join(",")
+            );
+        }
+        rejected
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::{DateTime, TimeDelta};
+    use reqwest::StatusCode;
+
+    use super::SpanIndices;
+
+    #[test]
+    fn select_by_range() {
+        let now = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let indices = SpanIndices::new(
+            "jaeger-span-*",
+            Some(("archive-jaeger-span-*", TimeDelta::hours(24))),
+        );
+        assert_eq!(
+            indices.select(now, now - TimeDelta::minutes(1)),
+            vec!["jaeger-span-*"]
+        );
+        assert_eq!(
+            indices.select(now, now - TimeDelta::hours(25)),
+            vec!["archive-jaeger-span-*", "jaeger-span-*"]
+        );
+        assert_eq!(
+            indices.all(),
+            vec!["archive-jaeger-span-*", "jaeger-span-*"]
+        );
+
+        let primary = SpanIndices::new("jaeger-span-*", None);
+        assert_eq!(
+            primary.select(now, now - TimeDelta::days(7)),
+            vec!["jaeger-span-*"]
+        );
+    }
+
+    #[test]
+    fn passes_split_at_cutoff() {
+        let now = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let cutoff = now - TimeDelta::hours(24);
+        let indices = SpanIndices::new(
+            "jaeger-span-*",
+            Some(("archive-jaeger-span-*", TimeDelta::hours(24))),
+        );
+
+        let from = cutoff - TimeDelta::minutes(10);
+        let to = cutoff + TimeDelta::minutes(5);
+        assert_eq!(
+            indices.passes(now, from..to),
+            vec![
+                ("archive-jaeger-span-*", from..cutoff),
+                ("jaeger-span-*", from..cutoff),
+                ("jaeger-span-*", cutoff..to),
+            ]
+        );
+
+        let to = cutoff - TimeDelta::minutes(5);
+        assert_eq!(
+            indices.passes(now, from..to),
+            vec![
+                ("archive-jaeger-span-*", from..to),
+                ("jaeger-span-*", from..to)
+            ]
+        );
+
+        let from = now - TimeDelta::minutes(1);
+        assert_eq!(
+            indices.passes(now, from..now),
+            vec![("jaeger-span-*", from..now)]
+        );
+    }
+
+    #[test]
+    fn fall_back_once() {
+        let indices = SpanIndices::new("a-*", Some(("b-*", TimeDelta::hours(1))));
+        assert!(!indices.fall_back(&["a-*"], StatusCode::BAD_REQUEST));
+        assert!(!indices.fall_back(&["b-*", "a-*"], StatusCode::SERVICE_UNAVAILABLE));
+        assert!(indices.combined_pit());
+        assert!(indices.fall_back(&["b-*", "a-*"], StatusCode::BAD_REQUEST));
+        assert!(!indices.combined_pit());
+        assert!(!indices.fall_back(&["b-*", "a-*"], StatusCode::BAD_REQUEST));
+    }
+}
@@ -0,0 +1,175 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use std::{collections::BTreeMap, sync::Mutex, time::Duration};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use super::histogram::{HistogramConfig, HistogramProcessor};
+
+/// Bucket bounds of the spans-per-trace histogram.
+const SPANS_PER_TRACE_BOUNDS: [f64; 12] = [
+    1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0, 200.0, 500.0, 1000.0, 2000.0, 5000.0,
+];
+
+/// The latency quantiles reported per request kind.
+const LATENCY_QUANTILES: [f64; 3] = [0.5, 0.9, 0.99];
+
+/// Backend requests whose latency is recorded.
+#[derive(Clone, Copy, Debug)]
+pub enum Request {
+    /// OpenSearch queries for a batch of root spans.
+    RootQuery,
+    /// OpenSearch queries for the spans of a chunk of traces.
+    SpanQuery,
+    /// Prometheus remote-write requests.
+    RemoteWrite,
+}
+
+/// Collects ingest statistics during a single tick. Shared by reference
+/// between the trace query loop and the metrics writer.
+pub struct IngestRecorder(Mutex<IngestCounts>);
+
+struct IngestCounts {
+    spans_per_trace: HistogramProcessor,
+    traces: u64,
+    spans: u64,
+    root_queries: Vec<f64>,
+    span_queries: Vec<f64>,
+    remote_writes: Vec<f64>,
+}
+
+/// Ingest statistics for a single tick.
+#[derive(Serialize, schemars::JsonSchema, PartialEq, Clone, Debug)]
+pub struct IngestReport {
+    /// The end of the tick.
+    pub time: DateTime<Utc>,
+    /// The number of traces (root spans) handled.
+    pub traces: u64,
+    /// The number of spans handled.
+    pub spans: u64,
+    /// The number of traces with at most `le` spans.
+    pub spans_per_trace: BTreeMap<String, u64>,
+    /// Latency of the root span queries.
+    pub root_queries: LatencySummary,
+    /// Latency of the span queries.
+    pub span_queries: LatencySummary,
+    /// Latency of the remote-write requests.
+    pub remote_writes: LatencySummary,
+}
+
+#[derive(Serialize, schemars::JsonSchema, PartialEq, Clone, Debug)]
+pub struct LatencySummary {
+    /// The number of requests.
+    pub count: u64,
+    /// Request latency in seconds, by quantile.
+    pub quantiles: BTreeMap<String, f64>,
+}
+
+impl Default for IngestRecorder {
+    fn default() -> Self {
+        Self(Mutex::new(IngestCounts {
+            spans_per_trace: HistogramProcessor::new(&HistogramConfig {
+                bounds: SPANS_PER_TRACE_BOUNDS.to_vec(),
+            }),
+            traces: 0,
+            spans: 0,
+            root_queries: Vec::new(),
+            span_queries: Vec::new(),
+            remote_writes: Vec::new(),
+        }))
+    }
+}
+
+impl IngestRecorder {
+    pub fn record_trace(&self, spans: usize) {
+        let mut counts = self.0.lock().unwrap();
+        counts.traces += 1;
+        counts.spans += spans as u64;
+        counts.spans_per_trace.insert(spans as f64);
+    }
+
+    pub fn record_request(&self, request: Request, duration: Duration) {
+        let mut counts = self.0.lock().unwrap();
+        let latencies = match request {
+            Request::RootQuery => &mut counts.root_queries,
+            Request::SpanQuery => &mut counts.span_queries,
+            Request::RemoteWrite => &mut counts.remote_writes,
+        };
+        latencies.push(duration.as_secs_f64());
+    }
+
+    /// Finish the tick ending at `time`.
+    pub fn finish(self, time: DateTime<Utc>) -> IngestReport {
+        let counts = self.0.into_inner().unwrap();
+        let mut spans_per_trace = BTreeMap::new();
+        counts.spans_per_trace.sample(|args, n| {
+            if let Some(le) = args.labels.le {
+                spans_per_trace.insert(le, n as u64);
+            }
+        });
+        IngestReport {
+            time,
+            traces: counts.traces,
+            spans: counts.spans,
+            spans_per_trace,
+            root_queries: LatencySummary::new(counts.root_queries),
+            span_queries: LatencySummary::new(counts.span_queries),
+            remote_writes: LatencySummary::new(counts.remote_writes),
+        }
+    }
+}
+
+impl LatencySummary {
+    fn new(mut latencies: Vec<f64>) -> Self {
+        latencies.sort_by(f64::total_cmp);
+        Self {
+            count: latencies.len() as u64,
+            quantiles: match latencies.len() {
+                0 => BTreeMap::new(),
+                n => LATENCY_QUANTILES
+                    .iter()
+                    .map(|q| {
+                        let i = ((n - 1) as f64 * q).round() as usize;
+                        (format!("{q:.2}"), latencies[i])
+                    })
+                    .collect(),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use chrono::DateTime;
+
+    use super::{IngestRecorder, Request};
+
+    #[test]
+    fn ingest_report() {
+        let recorder = IngestRecorder::default();
+        [1, 3, 3, 40]
+            .into_iter()
+            .for_each(|n| recorder.record_trace(n));
+        (1..=100)
+            .for_each(|ms| recorder.record_request(Request::SpanQuery, Duration::from_millis(ms)));
+        recorder.record_request(Request::RootQuery, Duration::from_millis(20));
+
+        let report = recorder.finish(DateTime::from_timestamp(1_700_000_000, 0).unwrap());
+        assert_eq!(report.traces, 4);
+        assert_eq!(report.spans, 47);
+        assert_eq!(report.spans_per_trace["1"], 1);
+        assert_eq!(report.spans_per_trace["5"], 3);
+        assert_eq!(report.spans_per_trace["50"], 4);
+        assert_eq!(report.span_queries.count, 100);
+        assert_eq!(report.span_queries.quantiles["0.50"], 0.051);
+        assert_eq!(report.span_queries.quantiles["0.99"], 0.099);
+        assert_eq!(report.root_queries.quantiles["0.90"], 0.02);
+        assert_eq!(report.remote_writes.count, 0);
+        assert!(report.remote_writes.quantiles.is_empty());
+    }
+}
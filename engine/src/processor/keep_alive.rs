@@ -0,0 +1,197 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::opensearch::EsKeepAlive;
+
+/// The shortest keep-alive of a point in time.
+const MIN_KEEP_ALIVE: Duration = Duration::from_secs(60);
+/// The weight of the last tick in the expected tick duration.
+const SMOOTHING: f64 = 0.3;
+/// A tick came near expiry if the PIT went unextended for this
+/// fraction of its keep-alive.
+const NEAR_EXPIRY: f64 = 0.9;
+
+/// Chooses the keep-alive of the OpenSearch point in time (PIT) of a
+/// tick: twice the expected tick duration, an exponentially weighted
+/// average of the durations of past ticks, between one minute and
+/// `max`. A short keep-alive frees the PIT resources of small
+/// clusters early; a long one keeps the PIT alive through a backlog.
+#[derive(Debug)]
+pub struct KeepAlive {
+    max: Duration,
+    /// The keep-alive until the first tick completes.
+    initial: Duration,
+    /// The expected tick duration, in seconds.
+    expected: Option<f64>,
+    near_expiry_ticks: u64,
+}
+
+/// The PIT keep-alive of the last tick, for the self-monitoring
+/// metrics.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct PitReport {
+    /// The keep-alive used in the last tick, in seconds.
+    pub keep_alive_seconds: f64,
+    /// The longest time the PIT went without being extended in the
+    /// last tick, in seconds.
+    pub longest_gap_seconds: f64,
+    /// The number of ticks since startup in which the PIT came within
+    /// 10% of expiring.
+    pub near_expiry_ticks: u64,
+}
+
+/// The PIT of a single tick. Tracks how long the PIT goes without
+/// being extended by a request.
+#[derive(Debug)]
+pub struct PitLease {
+    keep_alive: Duration,
+    /// The last extension and the longest gap between extensions.
+    extended: Mutex<(Instant, Duration)>,
+}
+
+impl KeepAlive {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            max: max.max(MIN_KEEP_ALIVE),
+            initial,
+            expected: None,
+            near_expiry_ticks: 0,
+        }
+    }
+
+    /// The keep-alive for the next tick.
+    pub fn get(&self) -> Duration {
+        self.expected
+            .map_or(self.initial, |expected| {
+                Duration::from_secs_f64(2.0 * expected)
+            })
+            .clamp(MIN_KEEP_ALIVE, self.max)
+    }
+
+    /// A lease for the next tick.
+    pub fn lease(&self) -> PitLease {
+        PitLease::new(self.get())
+    }
+
+    /// Record a completed tick, with the lease it used.
+    pub fn record(&mut self, tick: Duration, lease: &PitLease) -> PitReport {
+        self.record_gap(tick, lease.keep_alive, lease.longest_gap())
+    }
+
+    fn record_gap(&mut self, tick: Duration, keep_alive: Duration, gap: Duration) -> PitReport {
+        let tick = tick.as_secs_f64();
+        self.expected = Some(self.expected.map_or(tick, |expected| {
+            SMOOTHING * tick + (1.0 - SMOOTHING) * expected
+        }));
+        if gap.as_secs_f64() >= NEAR_EXPIRY * keep_alive.as_secs_f64() {
+            log::warn!(
+                "the opensearch point in time went unextended for {gap:?}, \
+                 close to its keep-alive of {keep_alive:?}"
+            );
+            self.near_expiry_ticks += 1;
+        }
+        PitReport {
+            keep_alive_seconds: keep_alive.as_secs_f64(),
+            longest_gap_seconds: gap.as_secs_f64(),
+            near_expiry_ticks: self.near_expiry_ticks,
+        }
+    }
+}
+
+impl PitLease {
+    pub fn new(keep_alive: Duration) -> Self {
+        Self {
+            keep_alive,
+            extended: Mutex::new((Instant::now(), Duration::ZERO)),
+        }
+    }
+
+    /// The keep-alive to send with a request. The PIT must also survive
+    /// the throttling `pause` before the next request, so it is added.
+    pub fn keep_alive(&self, pause: Duration) -> EsKeepAlive {
+        EsKeepAlive::from(self.keep_alive + pause)
+    }
+
+    /// Record a request extending the PIT.
+    pub fn extend(&self) {
+        let mut extended = self.extended.lock().unwrap();
+        let now = Instant::now();
+        extended.1 = extended.1.max(now - extended.0);
+        extended.0 = now;
+    }
+
+    /// The longest time the PIT went without being extended, including
+    /// the time since the last extension.
+    pub fn longest_gap(&self) -> Duration {
+        let extended = self.extended.lock().unwrap();
+        extended.1.max(extended.0.elapsed())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{KeepAlive, PitLease};
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn keep_alive_follows_tick_duration() {
+        let mut keep_alive = KeepAlive::new(5 * MINUTE, 30 * MINUTE);
+        assert_eq!(keep_alive.get(), 5 * MINUTE);
+
+        // Short ticks are clamped to the minimum.
+        keep_alive.record_gap(Duration::from_secs(5), 5 * MINUTE, Duration::ZERO);
+        assert_eq!(keep_alive.get(), MINUTE);
+
+        // The expected duration follows longer ticks gradually.
+        let secs = |keep_alive: &KeepAlive| keep_alive.get().as_secs_f64();
+        keep_alive.record_gap(5 * MINUTE + Duration::from_secs(5), MINUTE, Duration::ZERO);
+        assert!((secs(&keep_alive) - 2.0 * (5.0 + 90.0)).abs() < 1e-6);
+        (0..20).for_each(|_| {
+            keep_alive.record_gap(5 * MINUTE, MINUTE, Duration::ZERO);
+        });
+        assert!((secs(&keep_alive) - 600.0).abs() < 1.0);
+
+        // Backlogs are clamped to the maximum.
+        (0..5).for_each(|_| {
+            keep_alive.record_gap(60 * MINUTE, MINUTE, Duration::ZERO);
+        });
+        assert_eq!(keep_alive.get(), 30 * MINUTE);
+
+        // The maximum is never below the minimum.
+        assert_eq!(KeepAlive::new(5 * MINUTE, Duration::ZERO).get(), MINUTE);
+    }
+
+    #[test]
+    fn near_expiry() {
+        let mut keep_alive = KeepAlive::new(5 * MINUTE, 30 * MINUTE);
+        let report = keep_alive.record_gap(MINUTE, 5 * MINUTE, 4 * MINUTE);
+        assert_eq!(report.near_expiry_ticks, 0);
+        assert_eq!(report.keep_alive_seconds, 300.0);
+        let report = keep_alive.record_gap(MINUTE, 5 * MINUTE, Duration::from_secs(271));
+        assert_eq!(report.near_expiry_ticks, 1);
+        assert_eq!(report.longest_gap_seconds, 271.0);
+        let report = keep_alive.record_gap(MINUTE, MINUTE, Duration::from_secs(10));
+        assert_eq!(report.near_expiry_ticks, 1);
+    }
+
+    #[test]
+    fn lease_covers_pauses() {
+        let lease = PitLease::new(MINUTE);
+        assert_eq!(lease.keep_alive(Duration::ZERO).to_string(), "60s");
+        assert_eq!(
+            lease.keep_alive(Duration::from_millis(2500)).to_string(),
+            "63s"
+        );
+        lease.extend();
+        assert!(lease.longest_gap() < MINUTE);
+    }
+}
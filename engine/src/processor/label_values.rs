@@ -0,0 +1,180 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use std::collections::BTreeMap;
+
+use apistos::ApiComponent;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::{ConfigName, SpanKey},
+    jaeger::TagValue,
+    metrics::key_labels,
+};
+
+/// Paging of the observed values of each label.
+#[derive(Deserialize, schemars::JsonSchema, ApiComponent, PartialEq, Eq, Clone, Copy, Debug)]
+pub struct LabelValuesQuery {
+    /// The number of values to skip, per label.
+    #[serde(default)]
+    pub offset: usize,
+    /// The maximum number of values to return, per label.
+    #[serde(default = "default_limit")]
+    pub limit: usize,
+}
+
+/// The observed values of the group key labels, per config and label.
+#[derive(Serialize, schemars::JsonSchema, ApiComponent, PartialEq, Default, Debug)]
+pub struct LabelValuesReport {
+    pub configs: BTreeMap<ConfigName, BTreeMap<String, LabelValues>>,
+}
+
+/// A page of the observed values of a label.
+#[derive(Serialize, schemars::JsonSchema, PartialEq, Eq, Debug)]
+pub struct LabelValues {
+    /// The number of distinct values, before paging.
+    pub total: usize,
+    /// The values on this page, in label value order.
+    pub values: Vec<LabelValue>,
+}
+
+#[derive(Serialize, schemars::JsonSchema, PartialEq, Eq, Debug)]
+pub struct LabelValue {
+    /// The label value, as emitted in the metric labels.
+    pub value: String,
+    /// The number of groups with this value.
+    pub groups: u64,
+}
+
+const fn default_limit() -> usize {
+    100
+}
+
+impl LabelValuesReport {
+    pub fn new() -> Self {
+        Self {
+            configs: BTreeMap::new(),
+        }
+    }
+
+    /// Add the values of the group keys of a config.
+    pub fn add<'a>(
+        &mut self,
+        config: &ConfigName,
+        keys: impl Iterator<Item = &'a BTreeMap<SpanKey, TagValue>>,
+        query: LabelValuesQuery,
+    ) {
+        let mut counts = BTreeMap::<String, BTreeMap<String, u64>>::new();
+        keys.flat_map(key_labels).for_each(|(label, value)| {
+            *counts.entry(label).or_default().entry(value).or_default() += 1;
+        });
+        self.configs.insert(
+            config.clone(),
+            counts
+                .into_iter()
+                .map(|(label, values)| {
+                    let page = LabelValues {
+                        total: values.len(),
+                        values: values
+                            .into_iter()
+                            .skip(query.offset)
+                            .take(query.limit)
+                            .map(|(value, groups)| LabelValue { value, groups })
+                            .collect(),
+                    };
+                    (label, page)
+                })
+                .collect(),
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::DateTime;
+
+    use super::{LabelValue, LabelValues, LabelValuesQuery};
+    use crate::{
+        config::{ConfigName, IngestFilter},
+        processor::{
+            sim::{span, start, synthetic_traces},
+            trace::{TraceConfig, TraceProcessor},
+        },
+    };
+
+    fn values(values: &[(&str, u64)]) -> Vec<LabelValue> {
+        values
+            .iter()
+            .map(|(value, groups)| LabelValue {
+                value: value.to_string(),
+                groups: *groups,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn observed_label_values() {
+        let mut proc = TraceProcessor::new(&TraceConfig::default());
+        let mut traces = synthetic_traces(start(), 10);
+        traces.push(vec![span(
+            "put",
+            "1",
+            None,
+            "frontend",
+            "PUT",
+            start().timestamp_micros() + 2_000_000,
+            1000,
+        )]);
+        let batch = traces
+            .iter()
+            .map(|trace| {
+                let t = DateTime::from_timestamp_micros(trace[0].start_time).unwrap();
+                (t, trace.as_slice())
+            })
+            .collect::<Vec<_>>();
+        proc.insert_batch(&batch, &IngestFilter::default());
+        let snapshot = proc.snapshot(start());
+
+        let report = snapshot.label_values(LabelValuesQuery {
+            offset: 0,
+            limit: 100,
+        });
+        let default = &report.configs[&ConfigName::new("default")];
+        assert_eq!(
+            default["service_name"],
+            LabelValues {
+                total: 3,
+                values: values(&[("backend", 1), ("database", 1), ("frontend", 2)])
+            }
+        );
+        assert_eq!(
+            default["operation_name"].values,
+            values(&[("GET", 1), ("POST", 1), ("PUT", 1), ("SELECT", 1)])
+        );
+        assert_eq!(default["service_namespace"].values, values(&[("test", 4)]));
+        let trace = &report.configs[&TraceConfig::trace_metrics_config_name()];
+        assert_eq!(trace["service_name"].values, values(&[("frontend", 2)]));
+
+        // Values are paged per label.
+        let report = snapshot.label_values(LabelValuesQuery {
+            offset: 1,
+            limit: 1,
+        });
+        let default = &report.configs[&ConfigName::new("default")];
+        assert_eq!(
+            default["service_name"],
+            LabelValues {
+                total: 3,
+                values: values(&[("database", 1)])
+            }
+        );
+        assert_eq!(
+            default["service_namespace"],
+            LabelValues {
+                total: 1,
+                values: Vec::new()
+            }
+        );
+    }
+}
@@ -0,0 +1,284 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use apistos::ApiComponent;
+use chrono::{DateTime, TimeDelta, Utc};
+use jaeger_anomaly_detection::Duration;
+use serde::{Deserialize, Serialize};
+
+use crate::{config::IngestFilter, jaeger::Span};
+
+/// A period during which the spans of matching services are left out
+/// of the anomaly score windows, e.g. during a rollout, so that the
+/// expected latency spikes do not end up in the reference windows.
+/// The spans are still counted in the other statistics.
+#[derive(
+    Serialize, Deserialize, schemars::JsonSchema, ApiComponent, PartialEq, Eq, Clone, Debug,
+)]
+pub struct MaintenanceWindow {
+    /// A description, e.g. the rollout.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// The services and namespaces the window applies to. An empty
+    /// filter matches all spans.
+    #[serde(default)]
+    pub filter: IngestFilter,
+    pub schedule: MaintenanceSchedule,
+    /// Also leave the spans out of summaries and histograms.
+    #[serde(default)]
+    pub exclude_distributions: bool,
+}
+
+#[derive(Serialize, Deserialize, schemars::JsonSchema, PartialEq, Eq, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum MaintenanceSchedule {
+    /// A single period, from `start` (inclusive) to `end` (exclusive).
+    Once {
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    },
+    /// A period of `duration`, repeated every `every` from `start`,
+    /// e.g. a daily deploy window.
+    Recurring {
+        start: DateTime<Utc>,
+        every: Duration,
+        duration: Duration,
+    },
+}
+
+/// The statistics a span is left out of because of the active
+/// maintenance windows.
+#[derive(PartialEq, Eq, Clone, Copy, Default, Debug)]
+pub struct Muting {
+    pub anomaly_score: bool,
+    pub distributions: bool,
+}
+
+impl MaintenanceWindow {
+    /// Whether the window is active at `t`, in trace time.
+    pub fn is_active(&self, t: DateTime<Utc>) -> bool {
+        match &self.schedule {
+            MaintenanceSchedule::Once { start, end } => *start <= t && t < *end,
+            MaintenanceSchedule::Recurring {
+                start,
+                every,
+                duration,
+            } => {
+                t >= *start
+                    && (t - *start)
+                        .num_seconds()
+                        .checked_rem(every.to_time_delta().num_seconds())
+                        .is_some_and(|offset| TimeDelta::seconds(offset) < duration.to_time_delta())
+            }
+        }
+    }
+
+    /// Whether the window ended at or before `t` and will not be active
+    /// again.
+    pub fn has_ended(&self, t: DateTime<Utc>) -> bool {
+        match &self.schedule {
+            MaintenanceSchedule::Once { end, .. } => *end <= t,
+            MaintenanceSchedule::Recurring { .. } => false,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), &'static str> {
+        match &self.schedule {
+            MaintenanceSchedule::Once { start, end } if end <= start => {
+                Err("end must be after start")
+            }
+            MaintenanceSchedule::Recurring {
+                every, duration, ..
+            } if every.to_time_delta() <= TimeDelta::zero()
+                || duration.to_time_delta() <= TimeDelta::zero() =>
+            {
+                Err("every and duration must be positive")
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Muting {
+    /// The statistics a span starting at `t` is left out of.
+    pub fn of(windows: &[MaintenanceWindow], t: DateTime<Utc>, span: &Span) -> Self {
+        windows
+            .iter()
+            .filter(|window| window.is_active(t) && window.filter.matches(span))
+            .fold(Self::default(), |muting, window| Self {
+                anomaly_score: true,
+                distributions: muting.distributions || window.exclude_distributions,
+            })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use chrono::{DateTime, TimeDelta, Utc};
+    use jaeger_anomaly_detection::Duration;
+
+    use super::{MaintenanceSchedule, MaintenanceWindow};
+    use crate::{
+        config::{ConfigName, IngestFilter, MetricName, ValueMatch},
+        processor::{
+            anomaly_score::AnomalyScoreConfig,
+            mean_stddev::MeanStddevConfig,
+            sim::{span, start},
+            stats::StatsConfig,
+            summary::SummaryConfig,
+            trace::{TraceConfig, TraceProcessor},
+        },
+    };
+
+    /// A window for the frontend service.
+    fn frontend(schedule: MaintenanceSchedule, exclude_distributions: bool) -> MaintenanceWindow {
+        MaintenanceWindow {
+            reason: Some(String::from("rollout")),
+            filter: IngestFilter {
+                include_services: vec![ValueMatch::Eq(String::from("frontend"))],
+                ..IngestFilter::default()
+            },
+            schedule,
+            exclude_distributions,
+        }
+    }
+
+    fn once(start: DateTime<Utc>, minutes: i64) -> MaintenanceSchedule {
+        MaintenanceSchedule::Once {
+            start,
+            end: start + TimeDelta::minutes(minutes),
+        }
+    }
+
+    /// Insert single-span traces for the frontend and backend services
+    /// during the first minute, and return the counts per service and
+    /// statistic (anomaly score, welford, summary) at the end of it.
+    fn sample_counts(maintenance: Vec<MaintenanceWindow>) -> BTreeMap<(String, &'static str), f64> {
+        let mut config = TraceConfig {
+            maintenance,
+            ..TraceConfig::default()
+        };
+        config.rules.truncate(1);
+        config
+            .configs
+            .retain(|name, _| name == &ConfigName::new("default"));
+        config.configs.values_mut().for_each(|config| {
+            config
+                .metrics
+                .retain(|name, _| name == &MetricName::new("duration"));
+            config.metrics.values_mut().for_each(|metric| {
+                metric.stats = StatsConfig {
+                    anomaly_score: Some(AnomalyScoreConfig::default()),
+                    mean_stddev: Some(MeanStddevConfig::default()),
+                    summary: Some(SummaryConfig::default()),
+                    histogram: None,
+                    ..StatsConfig::default()
+                };
+            });
+        });
+
+        let mut proc = TraceProcessor::new(&config);
+        (0..60).for_each(|i| {
+            let t = start() + TimeDelta::seconds(i);
+            ["frontend", "backend"].into_iter().for_each(|service| {
+                let span = span(
+                    &format!("{service}-{i}"),
+                    "1",
+                    None,
+                    service,
+                    "GET",
+                    t.timestamp_micros(),
+                    1000,
+                );
+                proc.insert(t, &[span], &IngestFilter::default());
+            });
+        });
+
+        let mut counts = BTreeMap::new();
+        proc.sample(
+            start() + TimeDelta::minutes(1),
+            |args, config_name, value| {
+                let service = args.group.get("service_name");
+                if config_name != &ConfigName::new("default")
+                    || args.metric_name != "trace_duration_count"
+                    || service.is_none()
+                {
+                    return;
+                }
+                let count = counts
+                    .entry((service.unwrap().to_string(), args.metric_type))
+                    .or_insert(0.0);
+                *count = value.max(*count);
+            },
+        );
+        counts
+    }
+
+    fn count(
+        counts: &BTreeMap<(String, &'static str), f64>,
+        service: &str,
+        metric_type: &'static str,
+    ) -> f64 {
+        counts[&(service.to_string(), metric_type)]
+    }
+
+    #[test]
+    fn active_window() {
+        let counts = sample_counts(vec![frontend(once(start(), 5), false)]);
+        assert_eq!(count(&counts, "frontend", "anomaly_score"), 0.0);
+        assert_eq!(count(&counts, "frontend", "welford"), 60.0);
+        assert_eq!(count(&counts, "frontend", "summary"), 60.0);
+        assert!(count(&counts, "backend", "anomaly_score") > 0.0);
+
+        let counts = sample_counts(vec![frontend(once(start(), 5), true)]);
+        assert_eq!(count(&counts, "frontend", "anomaly_score"), 0.0);
+        assert_eq!(count(&counts, "frontend", "welford"), 60.0);
+        assert_eq!(count(&counts, "frontend", "summary"), 0.0);
+        assert_eq!(count(&counts, "backend", "summary"), 60.0);
+    }
+
+    #[test]
+    fn expired_window() {
+        let window = frontend(once(start() - TimeDelta::minutes(10), 5), true);
+        assert!(window.has_ended(start()));
+        let counts = sample_counts(vec![window]);
+        assert_eq!(
+            count(&counts, "frontend", "anomaly_score"),
+            count(&counts, "backend", "anomaly_score")
+        );
+        assert_eq!(count(&counts, "frontend", "summary"), 60.0);
+    }
+
+    #[test]
+    fn recurring_window() {
+        let window = frontend(
+            MaintenanceSchedule::Recurring {
+                start: start(),
+                every: Duration::Days(1),
+                duration: Duration::Minutes(30),
+            },
+            false,
+        );
+        assert!(!window.is_active(start() - TimeDelta::minutes(1)));
+        assert!(window.is_active(start()));
+        assert!(window.is_active(start() + TimeDelta::seconds(29 * 60 + 59)));
+        assert!(!window.is_active(start() + TimeDelta::minutes(30)));
+        assert!(window.is_active(start() + TimeDelta::days(3) + TimeDelta::minutes(10)));
+        assert!(!window.has_ended(start() + TimeDelta::days(365)));
+        assert!(window.validate().is_ok());
+
+        let window = frontend(
+            MaintenanceSchedule::Recurring {
+                start: start(),
+                every: Duration::Days(0),
+                duration: Duration::Minutes(30),
+            },
+            false,
+        );
+        assert!(window.validate().is_err());
+        assert!(!window.is_active(start()));
+    }
+}
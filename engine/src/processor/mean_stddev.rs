@@ -59,6 +59,21 @@ impl MeanStddevProcessor {
         self.clone()
     }
 
+    /// The welford accumulator, if that algorithm is used.
+    pub fn welford(&self) -> Option<&Welford<Quad>> {
+        match self {
+            MeanStddevProcessor::CountSum(_, _) => None,
+            MeanStddevProcessor::Welford(welford) => Some(welford),
+        }
+    }
+
+    pub fn welford_mut(&mut self) -> Option<&mut Welford<Quad>> {
+        match self {
+            MeanStddevProcessor::CountSum(_, _) => None,
+            MeanStddevProcessor::Welford(welford) => Some(welford),
+        }
+    }
+
     pub fn insert(&mut self, value: f64) {
         match self {
             MeanStddevProcessor::CountSum(count, sum) => {
@@ -69,6 +84,18 @@ impl MeanStddevProcessor {
         }
     }
 
+    /// Insert a batch of values summarized by their accumulator.
+    pub fn insert_aggregate(&mut self, values: &Welford<Quad>) {
+        match self {
+            MeanStddevProcessor::CountSum(count, sum) => {
+                let values = values.extract();
+                *count += values.count as u64;
+                *sum += values.count * values.mean;
+            }
+            MeanStddevProcessor::Welford(acc) => acc.merge(values),
+        }
+    }
+
     pub fn sample<F: FnMut(MetricArgs, f64)>(&self, mut metric: F) {
         match self {
             MeanStddevProcessor::CountSum(count, sum) => {
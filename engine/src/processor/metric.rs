@@ -3,19 +3,33 @@
  ******************************************************************************/
 
 use chrono::{DateTime, Utc};
+use ordered_float::NotNan;
 use serde::{Deserialize, Serialize};
 
-use crate::{jaeger::Span, metrics::Labels};
+use crate::{
+    config::{Ancestors, MetricName, SpanClassifiers},
+    jaeger::Span,
+    metrics::Labels,
+};
 
 use super::{
-    source::{MetricSource, SourceProcessor, SourceState},
-    stats::{StatsConfig, StatsProcessor, StatsState},
+    baseline::{BaselineSkip, StatsBaseline},
+    maintenance::Muting,
+    pushdown::SpanAggregate,
+    source::{AggregateValue, MetricSource, SourceProcessor, SourceState},
+    span::default_enabled,
+    stats::{MaxValueAction, StatsConfig, StatsProcessor, StatsState},
 };
 
 #[derive(Serialize, Deserialize, schemars::JsonSchema, PartialEq, Clone, Debug)]
 pub struct MetricConfig {
     pub source: MetricSource,
     pub stats: StatsConfig,
+    /// Emit the series of this metric. When false, the statistics are
+    /// still updated, but no series are written until the metric is
+    /// enabled again.
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -24,9 +38,25 @@ pub struct MetricState {
     stats: StatsState,
 }
 
+#[derive(Clone)]
 pub struct MetricProcessor {
     source: SourceProcessor,
     stats: StatsProcessor,
+    guard: Option<ValueGuard>,
+}
+
+/// The upper bound on the inserted values (see `StatsConfig::max_value`).
+#[derive(Clone, Copy)]
+struct ValueGuard {
+    max_value: NotNan<f64>,
+    action: MaxValueAction,
+}
+
+/// The number of values dropped or clamped by the upper bound.
+#[derive(PartialEq, Eq, Clone, Copy, Default, Debug)]
+pub struct GuardCounts {
+    pub clamped: u64,
+    pub dropped: u64,
 }
 
 impl MetricProcessor {
@@ -34,6 +64,7 @@ impl MetricProcessor {
         Self {
             source: SourceProcessor::new(t, &config.source),
             stats: StatsProcessor::new(t, &config.stats),
+            guard: ValueGuard::new(&config.stats),
         }
     }
 
@@ -42,6 +73,7 @@ impl MetricProcessor {
             MetricProcessor {
                 source,
                 stats: self.stats.update(t, &config.stats),
+                guard: ValueGuard::new(&config.stats),
             }
         } else {
             MetricProcessor::new(t, config)
@@ -52,6 +84,7 @@ impl MetricProcessor {
         Self {
             source: SourceProcessor::load(t, state.source, &config.source),
             stats: StatsProcessor::load(t, state.stats, &config.stats),
+            guard: ValueGuard::new(&config.stats),
         }
     }
 
@@ -66,16 +99,121 @@ impl MetricProcessor {
         &mut self,
         t: DateTime<Utc>,
         span: &Span,
-        parent: Option<&Span>,
+        ancestors: Ancestors,
         children: &[&Span],
-    ) {
+        classifiers: &SpanClassifiers,
+        muting: Muting,
+        weight: f64,
+    ) -> GuardCounts {
+        let value_weight = if self.source.weighs_values() {
+            weight
+        } else {
+            1.0
+        };
+        let guard = self.guard;
+        let mut counts = GuardCounts::default();
+        self.source
+            .insert(t, span, ancestors, children, classifiers, weight, |v| {
+                if let Some(v) = ValueGuard::apply(guard, v, &mut counts) {
+                    self.stats.insert_weighted(t, v, value_weight, muting)
+                }
+            });
+        counts
+    }
+
+    /// Insert the backend aggregate of a group's spans. Batches of
+    /// values cannot be bounded and are inserted as they are.
+    pub fn insert_aggregate(
+        &mut self,
+        t: DateTime<Utc>,
+        metric: &MetricName,
+        aggregate: &SpanAggregate,
+    ) -> GuardCounts {
+        let guard = self.guard;
+        let mut counts = GuardCounts::default();
         self.source
-            .insert(t, span, parent, children, |v| self.stats.insert(t, v))
+            .insert_aggregate(t, metric, aggregate, |value| match value {
+                AggregateValue::Value(v) => {
+                    if let Some(v) = ValueGuard::apply(guard, v, &mut counts) {
+                        self.stats.insert(t, v)
+                    }
+                }
+                AggregateValue::Values(values) => self.stats.insert_aggregate(t, &values),
+            });
+        counts
     }
 
-    pub fn sample<F: FnMut(MetricArgs, f64)>(&self, t: DateTime<Utc>, mut metric: F) {
+    /// Drop the summary and histogram statistics.
+    pub fn compact(&mut self) {
+        self.stats.compact();
+    }
+
+    /// Recreate the statistics dropped by `compact`.
+    pub fn restore(&mut self, t: DateTime<Utc>, config: &MetricConfig) {
+        self.stats.restore(t, &config.stats);
+    }
+
+    pub fn baseline(&self) -> Option<StatsBaseline> {
+        self.stats.baseline()
+    }
+
+    pub fn import_baseline(
+        &mut self,
+        t: DateTime<Utc>,
+        baseline: StatsBaseline,
+    ) -> Result<(), BaselineSkip> {
+        self.stats.import_baseline(t, baseline)
+    }
+
+    pub fn marks_warm_up(&self) -> bool {
+        self.stats.marks_warm_up()
+    }
+
+    pub fn sample<F: FnMut(MetricArgs, f64)>(
+        &self,
+        t: DateTime<Utc>,
+        first_seen: DateTime<Utc>,
+        mut metric: F,
+    ) -> u64 {
         self.source.sample(t, &mut metric);
-        self.stats.sample(t, &mut metric);
+        self.stats.sample(t, Some(first_seen), &mut metric)
+    }
+}
+
+impl ValueGuard {
+    fn new(config: &StatsConfig) -> Option<Self> {
+        Some(Self {
+            max_value: config.max_value?,
+            action: config.on_max_value,
+        })
+    }
+
+    /// The value to insert, if any.
+    fn apply(guard: Option<Self>, value: f64, counts: &mut GuardCounts) -> Option<f64> {
+        match guard {
+            Some(guard) if value > *guard.max_value => match guard.action {
+                MaxValueAction::Drop => {
+                    counts.dropped += 1;
+                    None
+                }
+                MaxValueAction::Clamp => {
+                    counts.clamped += 1;
+                    Some(*guard.max_value)
+                }
+            },
+            _ => Some(value),
+        }
+    }
+}
+
+impl GuardCounts {
+    pub fn is_empty(&self) -> bool {
+        self.clamped == 0 && self.dropped == 0
+    }
+
+    pub fn add(&mut self, other: GuardCounts) {
+        self.clamped += other.clamped;
+        self.dropped += other.dropped;
     }
 }
 
@@ -3,12 +3,51 @@
  ******************************************************************************/
 
 pub mod anomaly_score;
+pub mod baseline;
+pub mod bootstrap;
+pub mod cache;
+pub mod check;
+pub mod command_stats;
+pub mod dedup;
+pub mod expiry;
+#[cfg(test)]
+pub mod fake_http;
+#[cfg(test)]
+pub mod fake_opensearch;
+#[cfg(test)]
+pub mod fake_remote_write;
 pub mod histogram;
+pub mod indices;
+pub mod ingest_stats;
+pub mod keep_alive;
+pub mod label_values;
+pub mod maintenance;
 pub mod mean_stddev;
 pub mod metric;
 pub mod proc;
+pub mod pseudonymize;
+pub mod pushdown;
+pub mod quarantine;
+pub mod resolve;
+pub mod rule_stats;
+pub mod sampling;
+pub mod schema_push;
+pub mod series_limit;
+pub mod shard;
+#[cfg(test)]
+pub mod sim;
+pub mod sink;
+pub mod snapshot;
 pub mod source;
 pub mod span;
+pub mod staleness;
 pub mod stats;
 pub mod summary;
+pub mod tag_allowlist;
+pub mod throttle;
+pub mod tick;
+pub mod top_movers;
 pub mod trace;
+pub mod trace_debug;
+pub mod trace_level;
+pub mod write_stats;
@@ -2,108 +2,334 @@
  * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
  ******************************************************************************/
 
-use std::{collections::BTreeMap, path::Path, sync::Arc};
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    future::Future,
+    ops::Range,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::Poll,
+    time::Instant,
+};
 
 use chrono::{DateTime, TimeDelta, Utc};
-use reqwest::header::{HeaderMap, HeaderValue};
+use reqwest::{
+    header::{HeaderMap, HeaderValue},
+    StatusCode,
+};
+use serde::de::DeserializeOwned;
 use tap::Pipe;
-use tokio::task::JoinHandle;
+use tokio::{runtime::RuntimeFlavor, task::JoinHandle};
 use url::Url;
 
 use crate::{
-    config::Config,
+    config::{Config, ConfigName, IngestFilter, ValueMatch},
+    control::{check_generation, ConfigVersion},
     error::{Error, Result},
-    jaeger::Span,
-    metrics::Metrics,
+    jaeger::{Span, TraceId},
+    metrics::{out_of_order_series, GroupLabels, Labels, Metrics},
     opensearch::{
-        EsCreatePitQuery, EsCreatePitResponse, EsDeletePitRequest, EsDeletePitResponse, EsPit,
-        EsRel, EsResponse, EsSearchRequest, EsSearchResponse, EsSortField, EsSortOpts, EsSortOrder,
+        EsAggResponse, EsCreatePitQuery, EsCreatePitResponse, EsDeletePitRequest,
+        EsDeletePitResponse, EsError, EsPit, EsPitId, EsRel, EsResponse, EsSearchRequest,
+        EsSearchResponse, EsSortField, EsSortOpts, EsSortOrder, EsSourceFilter,
     },
-    state::State,
+    state::{ConfigExport, SaveSchedule, SaveStats, State},
     Args, BATCH_SIZE, CHUNK_SIZE, INDEX, KEEP_ALIVE, MAX_SPANS,
 };
 
-use super::trace::TraceProcessor;
+use super::{
+    baseline::{BaselineBundle, ImportReport},
+    bootstrap::{BootstrapProgress, BootstrapStatus},
+    cache::{CachedResult, QueryCache},
+    check,
+    command_stats::{CommandReport, CommandStats},
+    expiry::{ExpiryWebhook, ExpiryWebhookStatus},
+    indices::SpanIndices,
+    ingest_stats::{IngestRecorder, IngestReport, Request},
+    keep_alive::{KeepAlive, PitLease},
+    label_values::{LabelValuesQuery, LabelValuesReport},
+    pseudonymize::PseudonymizationKey,
+    pushdown::{Aggregations, GroupKey, PushdownQuery, SpanAggregate},
+    quarantine::{ConfigFailures, QuarantineStats},
+    resolve::{ObjectResolver, ResolveObjectRequest, ResolvedObject},
+    rule_stats::{RuleCounts, RuleStats},
+    sampling::{cleanup_time, sample_metrics, Sampler},
+    schema_push::{SchemaPushStatus, SchemaPushTarget, SchemaPusher},
+    series_limit::{SeriesReport, SeriesStats},
+    shard::{Shard, ShardFilter},
+    sink::{FileSink, MetricsSink, SinkKind},
+    snapshot::TraceSnapshot,
+    span::decode_lenient,
+    tag_allowlist::TagAllowlist,
+    throttle::{Throttle, ThrottleConfig, ThrottleLimits},
+    tick::{push_tick, TickReport},
+    top_movers::{TopMoverFinder, TopMovers, TopMoversQuery},
+    trace::{MetricArgs, TraceConfig, TraceProcessor},
+    trace_debug::{parse_spans, TraceDebugReport},
+    write_stats::WriteRecorder,
+};
 
 #[derive(Debug)]
 pub struct Processor {
     processor: JoinHandle<Result<()>>,
     term_sender: tokio::sync::oneshot::Sender<()>,
     config_sender: tokio::sync::watch::Sender<Arc<Config>>,
+    /// The generation of the config sent last. Locked while checking
+    /// and sending an update.
+    config_generation: Arc<Mutex<u64>>,
+    command_sender: tokio::sync::mpsc::Sender<Envelope>,
+    command_stats: Arc<CommandStats>,
+    /// The reports of the last ticks, oldest first.
+    ticks: tokio::sync::watch::Receiver<VecDeque<TickReport>>,
+    startup: tokio::sync::watch::Receiver<Startup>,
+    rule_stats: Arc<RuleStats>,
+    series_stats: Arc<SeriesStats>,
+    quarantine_stats: Arc<QuarantineStats>,
+    save_stats: Arc<Mutex<Option<SaveStats>>>,
+    ingest_stats: Arc<Mutex<Option<IngestReport>>>,
+    bootstrap: Arc<Mutex<Option<BootstrapStatus>>>,
+    dropped_series: Arc<AtomicU64>,
+    written_samples: Arc<AtomicU64>,
+    duplicate_samples: Arc<AtomicU64>,
+    dropped_groups: u64,
+    cache: QueryCache,
+    resolver: ObjectResolver,
+    movers: TopMoverFinder,
+    schema_push: Option<SchemaPusher>,
+    expiry_webhook: Option<ExpiryWebhook>,
+    spans: SpanClient,
+    throttle: Arc<Throttle>,
+    pseudonymization: Option<PseudonymizationKey>,
 }
 
-impl Processor {
-    pub async fn new(args: &Args) -> Result<Self> {
-        let ca = reqwest::tls::Certificate::from_pem_bundle(
-            &tokio::fs::read(&args.opensearch_ca)
-                .await
-                .map_err(|e| Error::ReadFile(args.opensearch_ca.clone(), e))?,
-        )
-        .map_err(|e| Error::LoadCa(args.opensearch_ca.clone(), e))?;
+/// The OpenSearch client, for span queries outside of the processor
+/// task.
+struct SpanClient {
+    args: Args,
+    client: reqwest::Client,
+}
 
-        let id = reqwest::tls::Identity::from_pkcs8_pem(
-            &tokio::fs::read(&args.opensearch_cert)
-                .await
-                .map_err(|e| Error::ReadFile(args.opensearch_cert.clone(), e))?,
-            &tokio::fs::read(&args.opensearch_key)
-                .await
-                .map_err(|e| Error::ReadFile(args.opensearch_key.clone(), e))?,
-        )
-        .map_err(|e| {
-            Error::LoadCert(args.opensearch_cert.clone(), args.opensearch_key.clone(), e)
-        })?;
-
-        let esclient = reqwest::Client::builder()
-            .timeout(std::time::Duration::from_secs(60))
-            .pipe(|client| {
-                ca.iter().fold(client, |client, cert| {
-                    client.add_root_certificate(cert.clone())
-                })
-            })
-            // .danger_accept_invalid_hostnames(true)
-            .identity(id)
-            .build()
-            .map_err(Error::Elastic)?;
+impl std::fmt::Debug for SpanClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SpanClient")
+            .field("opensearch_url", &self.args.opensearch_url.as_str())
+            .finish_non_exhaustive()
+    }
+}
 
-        let promclient = reqwest::Client::builder()
-            .timeout(std::time::Duration::from_secs(60))
-            .pipe(|client| {
-                ca.into_iter()
-                    .fold(client, |client, cert| client.add_root_certificate(cert))
-            })
-            // .danger_accept_invalid_hostnames(true)
-            .default_headers({
-                let mut headers = HeaderMap::new();
-                if let Some(tenant) = &args.prometheus_tenant {
-                    headers.insert(
-                        "X-Scope-OrgID",
-                        HeaderValue::try_from(tenant).map_err(Error::InvalidPrometheusTenant)?,
-                    );
-                }
-                headers
-            })
-            .build()
-            .map_err(Error::Prometheus)?;
+/// The startup phase of the processor task.
+#[derive(PartialEq, Eq, Debug)]
+enum Startup {
+    /// Waiting for the backends to become ready.
+    Waiting,
+    Running,
+    /// The backends did not become ready in time.
+    Failed(String),
+}
+
+/// Requests handled by the processor task between ticks. Each carries
+/// a oneshot channel for the reply.
+#[derive(Debug)]
+enum Command {
+    ImportBaselines(BaselineBundle, tokio::sync::oneshot::Sender<ImportReport>),
+    /// Take a snapshot for the reader to query. It is not kept by the
+    /// processor task: while a snapshot is alive, every group changed
+    /// since is copied on write.
+    Snapshot(tokio::sync::oneshot::Sender<TraceSnapshot>),
+}
+
+impl Command {
+    /// The command type, as reported in the command durations.
+    fn name(&self) -> &'static str {
+        match self {
+            Command::ImportBaselines(..) => "import_baselines",
+            Command::Snapshot(..) => "snapshot",
+        }
+    }
+}
 
-        let (mut config, state, last) = if args.state.exists() {
+/// A command with the time it was queued, so that the processor task
+/// can measure how long it took to handle.
+#[derive(Debug)]
+struct Envelope {
+    command: Command,
+    enqueued: Instant,
+}
+
+impl Processor {
+    pub async fn new(args: &Args) -> Result<Self> {
+        let shard = Shard::new(args.shard_index, args.shard_count)?;
+        let (esclient, promclient) = backend_clients(args).await?;
+
+        let mut dropped_groups = 0;
+        let (mut config, generation, state, last, resume) = if args.state.exists() {
             let data = tokio::fs::read(&args.state)
                 .await
                 .map_err(Error::ReadState)?;
-            let state = ciborium::from_reader::<State, _>(data.as_slice())
+            let decode = || ciborium::from_reader::<State, _>(data.as_slice());
+            let mut state = decode()
+                .or_else(|_| decode_lenient(decode))
                 .map_err(Error::DeserializeState)?;
-            (state.config, Some(state.state), Some(state.last))
+            state.state.dropped_groups().for_each(|(config, group)| {
+                log::warn!("{config}: dropped undecodable group from state: {group}");
+                dropped_groups += 1;
+            });
+            if dropped_groups > 0 {
+                log::warn!(
+                    "dropped {dropped_groups} undecodable groups from state; these start over"
+                );
+            }
+            // The replica sees a different share of the traces of every
+            // group, so the baselines no longer apply. This is also the
+            // case when only the index changed, e.g. after replicas were
+            // renumbered.
+            if state.shard != shard {
+                let groups = state.state.drop_groups();
+                let show = |shard: Option<Shard>| {
+                    shard.map_or_else(|| String::from("none"), |shard| shard.to_string())
+                };
+                log::warn!(
+                    "the shard changed from {} to {}; dropped {groups} groups from state, \
+                     which start over",
+                    show(state.shard),
+                    show(shard)
+                );
+            }
+            (
+                state.config,
+                state.generation,
+                Some(state.state),
+                Some(state.last),
+                state.bootstrap,
+            )
         } else {
-            (Config::default(), None, None)
+            let mut config = Config::default();
+            if args.request_path_relations {
+                config.trace = config.trace.with_request_path_relations();
+            }
+            if args.error_reasons {
+                config.trace = config.trace.with_error_reasons();
+            }
+            (config, 0, None, None, None)
+        };
+
+        let pseudonymization = match &args.pseudonymization_key_file {
+            Some(path) => Some(PseudonymizationKey::load(path).await?),
+            None => None,
         };
+        check_pseudonymization(&config.trace, pseudonymization.as_ref())?;
 
         let orig_trace_config = std::mem::take(&mut config.trace);
 
         let (term_sender, mut term_receiver) = tokio::sync::oneshot::channel::<()>();
         let (config_sender, mut config_receiver) = tokio::sync::watch::channel(Arc::new(config));
+        let config_generation = Arc::new(Mutex::new(generation));
+        let (command_sender, mut command_receiver) = tokio::sync::mpsc::channel::<Envelope>(4);
+        let (tick_sender, ticks) = tokio::sync::watch::channel(VecDeque::new());
+        let (startup_sender, startup) = tokio::sync::watch::channel(Startup::Waiting);
+
+        let rule_stats = Arc::new(RuleStats::default());
+        let series_stats = Arc::new(SeriesStats::default());
+        let quarantine_stats = Arc::new(QuarantineStats::default());
+        let save_stats = Arc::new(Mutex::new(None));
+        let ingest_stats = Arc::new(Mutex::new(None));
+        let bootstrap = Arc::new(Mutex::new(None));
+        let command_stats = Arc::new(CommandStats::default());
+        let dropped_series = Arc::new(AtomicU64::new(0));
+        let written_samples = Arc::new(AtomicU64::new(0));
+        let duplicate_samples = Arc::new(AtomicU64::new(0));
+
+        let cache = QueryCache::new(
+            promclient.clone(),
+            args.prometheus_query_url.clone(),
+            config_sender.subscribe(),
+        );
+        let resolver = ObjectResolver::new(promclient.clone(), args.prometheus_query_url.clone());
+        let movers = TopMoverFinder::new(promclient.clone(), args.prometheus_query_url.clone());
+
+        let schema_push = match &args.schema_push_url {
+            Some(url) => Some(SchemaPusher::new(
+                tls_client(&load_ca(&args.opensearch_ca).await?)
+                    .build()
+                    .map_err(Error::SchemaPush)?,
+                SchemaPushTarget {
+                    url: url.clone(),
+                    token: args.schema_push_token.clone(),
+                },
+                config_sender.subscribe(),
+            )),
+            None => None,
+        };
+
+        let expiry_webhook = match &args.expiry_webhook {
+            Some(url) => Some(ExpiryWebhook::new(
+                tls_client(&load_ca(&args.opensearch_ca).await?)
+                    .build()
+                    .map_err(Error::ExpiryWebhook)?,
+                url.clone(),
+            )),
+            None => None,
+        };
+
+        let throttle = Arc::new(Throttle::new(throttle_config(args)));
+        let sink = metrics_sink(args, &promclient);
+        log::info!(
+            "writing metrics in requests of at most {} samples, {} at a time",
+            args.metrics_per_request,
+            args.write_concurrency
+        );
+
+        let spans = SpanClient {
+            args: args.clone(),
+            client: esclient.clone(),
+        };
 
         let args = args.clone();
+        let task_rule_stats = rule_stats.clone();
+        let task_series_stats = series_stats.clone();
+        let task_quarantine_stats = quarantine_stats.clone();
+        let task_save_stats = save_stats.clone();
+        let task_ingest_stats = ingest_stats.clone();
+        let task_bootstrap = bootstrap.clone();
+        let task_command_stats = command_stats.clone();
+        let task_dropped_series = dropped_series.clone();
+        let task_written_samples = written_samples.clone();
+        let task_duplicate_samples = duplicate_samples.clone();
+        let task_throttle = throttle.clone();
+        let task_config_generation = config_generation.clone();
+        let task_pseudonymization = pseudonymization.clone();
+        let task_expiry_queue = expiry_webhook.as_ref().map(ExpiryWebhook::queue);
+        let shard_filter = shard.map(ShardFilter::new);
+        let indices = span_indices(&args);
         let processor = tokio::spawn(async move {
-            let mut config = config_receiver.borrow_and_update().clone();
+            // The web server is already up; the first range is only
+            // computed once the backends respond, so that it is still
+            // capped to max_history after a long wait.
+            tokio::select! {
+                ready = check::wait_ready(&args, &esclient, &promclient) => {
+                    if let Err(e) = ready {
+                        startup_sender.send_replace(Startup::Failed(e.to_string()));
+                        return Err(e);
+                    }
+                    startup_sender.send_replace(Startup::Running);
+                }
+                _ = &mut term_receiver => return Ok(()),
+            }
+
+            let state_file = StateFile {
+                path: &args.state,
+                shard,
+                save_stats: &task_save_stats,
+            };
+
+            // The config may have been updated while waiting.
+            let (mut config, mut generation) = {
+                let current = task_config_generation.lock().unwrap();
+                (config_receiver.borrow_and_update().clone(), *current)
+            };
 
             let mut interval = tokio::time::interval(
                 config
@@ -113,45 +339,193 @@ impl Processor {
                     .map_err(Error::DateTimeBounds)?,
             );
 
+            let mut save_schedule = SaveSchedule::new(args.force_save_ticks);
+            let mut keep_alive = KeepAlive::new(
+                KEEP_ALIVE,
+                std::time::Duration::from_millis(args.opensearch_max_keep_alive_ms),
+            );
+
+            export_config(args.config_export_path.as_deref(), generation, &config).await;
+
+            // A new deployment (without state) bootstraps if asked to;
+            // an interrupted bootstrap is resumed.
+            let mut bootstrap = resume.or_else(|| {
+                let range = args.bootstrap_from.filter(|_| last.is_none())?;
+                let to = Utc::now() - config.delay.to_time_delta();
+                Some(BootstrapProgress::new(to, range.to_time_delta()))
+            });
             let mut from = Utc::now() - config.max_history.to_time_delta();
             if let Some(last) = last {
                 from = from.max(last);
             }
+            if let Some(progress) = &bootstrap {
+                from = progress.done;
+            }
 
-            let mut processor = state.map_or_else(
-                || TraceProcessor::new(&config.trace),
-                |state| {
-                    let proc = TraceProcessor::load(from, state, &orig_trace_config);
-                    proc.update(from, &config.trace)
-                },
-            );
+            let mut processor = state
+                .map_or_else(
+                    || TraceProcessor::new(&config.trace),
+                    |state| {
+                        let proc = TraceProcessor::load(from, state, &orig_trace_config);
+                        proc.update(from, &config.trace)
+                    },
+                )
+                .with_rule_stats(task_rule_stats.clone())
+                .with_series_stats(task_series_stats)
+                .with_quarantine_stats(task_quarantine_stats)
+                .with_pseudonymization(task_pseudonymization)
+                .with_expiry_queue(task_expiry_queue);
+            let mut processed = false;
+
+            if let Some(progress) = &mut bootstrap {
+                let chunk = args.bootstrap_chunk.to_time_delta();
+                log::info!(
+                    "bootstrapping from {} to {} in chunks of {}",
+                    progress.from,
+                    progress.to,
+                    args.bootstrap_chunk
+                );
+                *task_bootstrap.lock().unwrap() = Some(progress.status(false, None));
+                loop {
+                    let live = Utc::now() - config.delay.to_time_delta();
+                    let tick_start = Instant::now();
+                    let pit = keep_alive.lease();
+                    // A chunk interrupted by shutdown is not saved, so
+                    // that it is replayed in full on restart.
+                    let result = tokio::select! {
+                        result = bootstrap_chunk(
+                            &args,
+                            &config,
+                            &EsClient {
+                                client: &esclient,
+                                throttle: &task_throttle,
+                                pit: &pit,
+                                shard: shard_filter.as_ref(),
+                                indices: &indices,
+                            },
+                            &mut processor,
+                            progress,
+                            live,
+                            chunk,
+                        ) => Some(result),
+                        _ = &mut term_receiver => None,
+                    };
+                    let Some(result) = result else {
+                        return Ok(());
+                    };
+                    task_rule_stats.end_tick();
+                    processor.record_pit(keep_alive.record(tick_start.elapsed(), &pit));
+                    match result {
+                        Ok(true) => {
+                            *task_bootstrap.lock().unwrap() = Some(progress.status(false, None));
+                            write_state(
+                                &mut processor,
+                                &config,
+                                generation,
+                                progress.done,
+                                Some(*progress),
+                                &state_file,
+                            )
+                            .await;
+                        }
+                        Ok(false) => break,
+                        Err(e) => {
+                            log::error!("bootstrap: {e}");
+                            *task_bootstrap.lock().unwrap() =
+                                Some(progress.status(false, Some(e.to_string())));
+                            tokio::select! {
+                                _ = tokio::time::sleep(interval.period()) => {}
+                                _ = &mut term_receiver => return Ok(()),
+                            }
+                        }
+                    }
+                }
+                log::info!("bootstrap finished at {}", progress.to);
+                *task_bootstrap.lock().unwrap() = Some(progress.status(true, None));
+                // Live processing continues where the replay ended;
+                // samples older than the backdating limit of
+                // `process_traces` are not written.
+                from = progress.to;
+                processed = true;
+            }
 
             loop {
                 tokio::select! {
                     _ = interval.tick() => {
                         let to = Utc::now() - config.delay.to_time_delta();
+                        if !processed {
+                            // Until the first successful tick, however long
+                            // the backends have been failing.
+                            from = from.max(to - config.max_history.to_time_delta());
+                        }
 
                         log::info!("processing traces from {from} to {to}...");
-                        if let Err(e) = process_traces(
+                        let ingest = args.ingest_stats.then(IngestRecorder::default);
+                        let writes = WriteRecorder::default();
+                        let tick_start = Instant::now();
+                        let pit = keep_alive.lease();
+                        let result = process_traces(
                             &args,
                             &config,
-                            &esclient,
-                            &promclient,
+                            &EsClient {
+                                client: &esclient,
+                                throttle: &task_throttle,
+                                pit: &pit,
+                                shard: shard_filter.as_ref(),
+                                indices: &indices,
+                            },
+                            Some(&MetricsWriter {
+                                sink: &sink,
+                                metrics_per_request: args.metrics_per_request,
+                                write_concurrency: args.write_concurrency.get(),
+                                dropped_series: &task_dropped_series,
+                                written_samples: &task_written_samples,
+                                duplicate_samples: &task_duplicate_samples,
+                                ingest: ingest.as_ref(),
+                                writes: Some(&writes),
+                                shard,
+                            }),
                             from,
                             to,
                             &mut processor,
                         )
-                        .await
-                        {
-                            log::error!("{e}");
-                        } else {
-                            from = to;
+                        .await;
+                        match result {
+                            Ok(report) => {
+                                tick_sender.send_modify(|ticks| push_tick(ticks, report));
+                                from = to;
+                                processed = true;
+                            }
+                            Err(e) => {
+                                log::error!("{e}");
+                                let report = TickReport::failed(from, to, tick_start.elapsed(), &e);
+                                tick_sender.send_modify(|ticks| push_tick(ticks, report));
+                            }
+                        }
+                        task_rule_stats.end_tick();
+                        processor.record_pit(keep_alive.record(tick_start.elapsed(), &pit));
+                        processor.record_writes(writes.finish(args.metrics_per_request));
+                        if let Some(ingest) = ingest {
+                            let report = ingest.finish(to);
+                            processor.record_ingest(report.clone());
+                            *task_ingest_stats.lock().unwrap() = Some(report);
                         }
 
-                        write_state(&processor, &config, to, &args.state).await;
+                        if save_schedule.tick(processor.is_dirty()) {
+                            write_state(&mut processor, &config, generation, to, None, &state_file)
+                                .await;
+                        } else {
+                            log::info!("state unchanged -- skipping save");
+                        }
                     }
                     _ = config_receiver.changed() => {
-                        let new = config_receiver.borrow_and_update().clone();
+                        // The generation is incremented with the update, so
+                        // read both under the generation lock.
+                        let new = {
+                            let current = task_config_generation.lock().unwrap();
+                            generation = *current;
+                            config_receiver.borrow_and_update().clone()
+                        };
                         if config == new {
                             log::info!("config unchanged -- skipping update");
                              continue;
@@ -161,9 +535,46 @@ impl Processor {
                         interval =
                             tokio::time::interval(config.query_interval.to_time_delta().to_std().map_err(Error::DateTimeBounds)?);
                         processor = processor.update(from, &config.trace);
-                        write_state(&processor, &config, from, &args.state).await;
+                        write_state(&mut processor, &config, generation, from, None, &state_file).await;
+                        export_config(args.config_export_path.as_deref(), generation, &config).await;
+                    }
+                    Some(Envelope { command, enqueued }) = command_receiver.recv() => {
+                        let name = command.name();
+                        match command {
+                            Command::ImportBaselines(bundle, sender) => {
+                                log::info!("importing {} baseline entries", bundle.entries.len());
+                                let report = processor.import_baselines(from, bundle);
+                                log::info!(
+                                    "imported baselines: {} applied, {} skipped",
+                                    report.applied,
+                                    report.skipped
+                                );
+                                let _ = sender.send(report);
+                                write_state(&mut processor, &config, generation, from, None, &state_file).await;
+                            }
+                            Command::Snapshot(sender) => {
+                                let _ = sender.send(processor.snapshot(from));
+                            }
+                        }
+                        task_command_stats.record(name, enqueued.elapsed());
                     }
                     _ = &mut term_receiver => {
+                        let mut stale = processor.shutdown();
+                        log::info!("marking {} series as stale", stale.len());
+                        MetricsWriter {
+                            sink: &sink,
+                            metrics_per_request: args.metrics_per_request,
+                            write_concurrency: args.write_concurrency.get(),
+                            dropped_series: &task_dropped_series,
+                            written_samples: &task_written_samples,
+                            duplicate_samples: &task_duplicate_samples,
+                            ingest: None,
+                            writes: None,
+                            shard,
+                        }
+                        .flush(&mut stale)
+                        .await;
+                        write_state(&mut processor, &config, generation, from, None, &state_file).await;
                         break;
                     }
                 }
@@ -176,6 +587,29 @@ impl Processor {
             processor,
             term_sender,
             config_sender,
+            config_generation,
+            command_sender,
+            command_stats,
+            ticks,
+            startup,
+            rule_stats,
+            series_stats,
+            quarantine_stats,
+            save_stats,
+            ingest_stats,
+            bootstrap,
+            dropped_series,
+            written_samples,
+            duplicate_samples,
+            dropped_groups,
+            cache,
+            resolver,
+            movers,
+            schema_push,
+            expiry_webhook,
+            spans,
+            throttle,
+            pseudonymization,
         })
     }
 
@@ -183,154 +617,640 @@ impl Processor {
         self.config_sender.borrow().clone()
     }
 
-    pub fn update_config(&self, config: Config) {
+    /// The current config, with its generation.
+    pub fn config_version(&self) -> ConfigVersion {
+        let generation = self.config_generation.lock().unwrap();
+        ConfigVersion {
+            generation: *generation,
+            config: Config::clone(&self.config_sender.borrow()),
+        }
+    }
+
+    /// The generation of the current config.
+    pub fn config_generation(&self) -> u64 {
+        *self.config_generation.lock().unwrap()
+    }
+
+    /// Whether the processor is still waiting for the backends.
+    pub fn starting(&self) -> bool {
+        *self.startup.borrow() == Startup::Waiting
+    }
+
+    /// Wait until the processor has started. Fails when the backends
+    /// did not become ready in time.
+    pub async fn started(&self) -> Result<()> {
+        let mut receiver = self.startup.clone();
+        let startup = receiver
+            .wait_for(|startup| *startup != Startup::Waiting)
+            .await
+            .map_err(|_| Error::ProcessorStopped)?;
+        match &*startup {
+            Startup::Failed(e) => Err(Error::ProcessorStartup(e.clone())),
+            _ => Ok(()),
+        }
+    }
+
+    /// Rule evaluation counts for the last completed tick.
+    pub fn last_rule_counts(&self) -> Option<RuleCounts> {
+        self.rule_stats.last_tick()
+    }
+
+    /// Series counts for the last sample.
+    pub fn last_series(&self) -> Option<SeriesReport> {
+        self.series_stats.last()
+    }
+
+    /// The failures of the span configs since startup, as of the last
+    /// sample, with the configs quarantined because of them.
+    pub fn config_failures(&self) -> BTreeMap<ConfigName, ConfigFailures> {
+        self.quarantine_stats.last()
+    }
+
+    /// Duration and size of the last state save.
+    pub fn last_save(&self) -> Option<SaveStats> {
+        *self.save_stats.lock().unwrap()
+    }
+
+    /// Trace sizes and backend latencies of the last tick, if enabled.
+    pub fn last_ingest(&self) -> Option<IngestReport> {
+        self.ingest_stats.lock().unwrap().clone()
+    }
+
+    /// The report of the last tick, failed or not.
+    pub fn last_tick(&self) -> Option<TickReport> {
+        self.ticks.borrow().back().cloned()
+    }
+
+    /// The reports of the last ticks, oldest first.
+    pub fn ticks(&self) -> Vec<TickReport> {
+        self.ticks.borrow().iter().cloned().collect()
+    }
+
+    /// The progress of the bootstrap, if one ran since startup.
+    pub fn bootstrap_status(&self) -> Option<BootstrapStatus> {
+        self.bootstrap.lock().unwrap().clone()
+    }
+
+    /// The depth of the command queue and the durations of the
+    /// handled commands.
+    pub fn command_report(&self) -> CommandReport {
+        self.command_stats
+            .report(self.command_sender.max_capacity() - self.command_sender.capacity())
+    }
+
+    /// The number of series dropped since startup because the
+    /// remote-write backend rejected them as out-of-order.
+    pub fn dropped_series(&self) -> u64 {
+        self.dropped_series.load(Ordering::Relaxed)
+    }
+
+    /// The number of samples written to the metrics sink since
+    /// startup (or discarded, for the null sink).
+    pub fn written_samples(&self) -> u64 {
+        self.written_samples.load(Ordering::Relaxed)
+    }
+
+    /// The number of samples dropped since startup because a later
+    /// sample of the same series had the same timestamp.
+    pub fn duplicate_samples(&self) -> u64 {
+        self.duplicate_samples.load(Ordering::Relaxed)
+    }
+
+    /// The number of groups that could not be decoded from the state
+    /// at startup.
+    pub fn dropped_groups(&self) -> u64 {
+        self.dropped_groups
+    }
+
+    /// Export the learned baselines, as of the last tick.
+    pub async fn export_baselines(&self) -> Result<BaselineBundle> {
+        Ok(self.snapshot().await?.export_baselines())
+    }
+
+    /// Merge a baseline bundle into the running processor.
+    pub async fn import_baselines(&self, bundle: BaselineBundle) -> Result<ImportReport> {
+        let (sender, receiver) = tokio::sync::oneshot::channel();
+        self.command(Command::ImportBaselines(bundle, sender))
+            .await?;
+        receiver.await.map_err(|_| Error::ProcessorStopped)
+    }
+
+    /// The observed values of the group key labels, as of the last
+    /// tick.
+    pub async fn label_values(&self, query: LabelValuesQuery) -> Result<LabelValuesReport> {
+        Ok(self.snapshot().await?.label_values(query))
+    }
+
+    /// The groups currently selected by a trace object, looked up in
+    /// prometheus.
+    pub async fn resolve_object(&self, request: ResolveObjectRequest) -> Result<ResolvedObject> {
+        self.resolver.resolve(&request).await
+    }
+
+    /// The groups whose score increased most over the lookback,
+    /// looked up in prometheus.
+    pub async fn top_movers(&self, query: TopMoversQuery) -> Result<TopMovers> {
+        self.movers.find(&query, Utc::now()).await
+    }
+
+    /// A snapshot taken by the processor task between ticks, for the
+    /// caller to query and drop. Waits for the end of the current tick,
+    /// or of the bootstrap.
+    async fn snapshot(&self) -> Result<TraceSnapshot> {
+        let (sender, receiver) = tokio::sync::oneshot::channel();
+        self.command(Command::Snapshot(sender)).await?;
+        receiver.await.map_err(|_| Error::ProcessorStopped)
+    }
+
+    /// The current limits of the span query throttling.
+    pub fn throttle_limits(&self) -> ThrottleLimits {
+        self.throttle.limits()
+    }
+
+    /// Fetch a trace and report how its spans would be processed with
+    /// the current config, without touching the processor state.
+    /// Returns `None` if the trace is not found.
+    pub async fn debug_trace(&self, trace_id: &str) -> Result<Option<TraceDebugReport>> {
+        let config = self.get_config();
+        let docs = fetch_trace(
+            &self.spans.args,
+            &self.spans.client,
+            trace_id,
+            config.ingest_logs,
+        )
+        .await?;
+        if docs.is_empty() {
+            return Ok(None);
+        }
+        let (spans, failed) = parse_spans(docs);
+        let mut report = TraceProcessor::new(&config.trace)
+            .with_pseudonymization(self.pseudonymization.clone())
+            .debug_trace(&spans, &config.ingest_filter);
+        report.extend(failed);
+        Ok(Some(TraceDebugReport {
+            trace_id: trace_id.to_string(),
+            spans: report,
+        }))
+    }
+
+    /// The latest result of a cached query.
+    pub fn cached_query(&self, name: &str) -> Option<CachedResult> {
+        self.cache.get(name)
+    }
+
+    async fn command(&self, command: Command) -> Result<()> {
+        self.command_sender
+            .send(Envelope {
+                command,
+                enqueued: Instant::now(),
+            })
+            .await
+            .map_err(|_| Error::ProcessorStopped)
+    }
+
+    /// The outcome of the schema pushes, if enabled.
+    pub fn schema_push_status(&self) -> Option<SchemaPushStatus> {
+        self.schema_push.as_ref().map(SchemaPusher::status)
+    }
+
+    /// The outcome of the expiry notifications, if enabled.
+    pub fn expiry_webhook_status(&self) -> Option<ExpiryWebhookStatus> {
+        self.expiry_webhook.as_ref().map(ExpiryWebhook::status)
+    }
+
+    /// Push the prometheus schema of the current config now.
+    pub async fn push_schema(&self) -> Result<SchemaPushStatus> {
+        self.schema_push
+            .as_ref()
+            .ok_or(Error::SchemaPushDisabled)?
+            .push()
+            .await
+    }
+
+    /// Replace the config, if `expected` (when set) is the current
+    /// generation. The processor task applies the update on its next
+    /// iteration.
+    pub fn update_config(&self, config: Config, expected: Option<u64>) -> Result<()> {
+        check_pseudonymization(&config.trace, self.pseudonymization.as_ref())?;
+        let mut generation = self.config_generation.lock().unwrap();
+        check_generation(expected, *generation)?;
+        *generation += 1;
         self.config_sender.send(Arc::new(config)).unwrap();
+        Ok(())
     }
 
     pub async fn shutdown(self) -> Result<()> {
-        self.term_sender.send(()).unwrap();
+        if let Err(e) = self.cache.shutdown().await {
+            log::warn!("{e}");
+        }
+        if let Some(schema_push) = self.schema_push {
+            if let Err(e) = schema_push.shutdown().await {
+                log::warn!("{e}");
+            }
+        }
+        if let Some(expiry_webhook) = self.expiry_webhook {
+            if let Err(e) = expiry_webhook.shutdown().await {
+                log::warn!("{e}");
+            }
+        }
+        // The task may have stopped at startup already.
+        let _ = self.term_sender.send(());
         self.processor.await.map_err(Error::JoinProcessor)?
     }
 }
 
+/// Refuse configs that pseudonymize label values without a key, which
+/// would put all values in one group.
+fn check_pseudonymization(config: &TraceConfig, key: Option<&PseudonymizationKey>) -> Result<()> {
+    if config.pseudonymizes() && key.is_none() {
+        return Err(Error::PseudonymizationKeyRequired);
+    }
+    Ok(())
+}
+
+/// The OpenSearch and prometheus clients.
+pub(super) async fn backend_clients(args: &Args) -> Result<(reqwest::Client, reqwest::Client)> {
+    let ca = load_ca(&args.opensearch_ca).await?;
+    let id = load_identity(&args.opensearch_cert, &args.opensearch_key).await?;
+
+    let esclient = tls_client(&ca)
+        .identity(id)
+        .build()
+        .map_err(Error::Elastic)?;
+
+    let promclient = tls_client(&ca)
+        .default_headers({
+            let mut headers = HeaderMap::new();
+            if let Some(tenant) = &args.prometheus_tenant {
+                headers.insert(
+                    "X-Scope-OrgID",
+                    HeaderValue::try_from(tenant).map_err(Error::InvalidPrometheusTenant)?,
+                );
+            }
+            headers
+        })
+        .build()
+        .map_err(Error::Prometheus)?;
+
+    Ok((esclient, promclient))
+}
+
+/// Load the CA bundle used to verify the backends.
+async fn load_ca(path: &Path) -> Result<Vec<reqwest::tls::Certificate>> {
+    reqwest::tls::Certificate::from_pem_bundle(
+        &tokio::fs::read(path)
+            .await
+            .map_err(|e| Error::ReadFile(path.to_path_buf(), e))?,
+    )
+    .map_err(|e| Error::LoadCa(path.to_path_buf(), e))
+}
+
+/// Load the client identity for OpenSearch from a PEM certificate and
+/// a PKCS#8 PEM key.
+async fn load_identity(cert: &Path, key: &Path) -> Result<reqwest::tls::Identity> {
+    let cert_pem = tokio::fs::read(cert)
+        .await
+        .map_err(|e| Error::ReadFile(cert.to_path_buf(), e))?;
+    let key_pem = tokio::fs::read(key)
+        .await
+        .map_err(|e| Error::ReadFile(key.to_path_buf(), e))?;
+
+    // Rustls takes the key and certificate chain from a single buffer.
+    #[cfg(feature = "rustls")]
+    let id = reqwest::tls::Identity::from_pem(&[key_pem, cert_pem].concat());
+    #[cfg(not(feature = "rustls"))]
+    let id = reqwest::tls::Identity::from_pkcs8_pem(&cert_pem, &key_pem);
+
+    id.map_err(|e| Error::LoadCert(cert.to_path_buf(), key.to_path_buf(), e))
+}
+
+/// A client builder trusting the given CA certificates, using rustls
+/// when the "rustls" feature is enabled.
+fn tls_client(ca: &[reqwest::tls::Certificate]) -> reqwest::ClientBuilder {
+    let client = reqwest::Client::builder().timeout(std::time::Duration::from_secs(60));
+    // Note: danger_accept_invalid_hostnames is only available with
+    // native-tls.
+    #[cfg(feature = "rustls")]
+    let client = client.use_rustls_tls();
+    ca.iter().fold(client, |client, cert| {
+        client.add_root_certificate(cert.clone())
+    })
+}
+
+/// Where the state is saved, and the replica it is saved for.
+struct StateFile<'a> {
+    path: &'a Path,
+    shard: Option<Shard>,
+    save_stats: &'a Mutex<Option<SaveStats>>,
+}
+
 async fn write_state(
-    processor: &TraceProcessor,
+    processor: &mut TraceProcessor,
     config: &Config,
+    generation: u64,
     last: DateTime<Utc>,
-    path: &Path,
+    bootstrap: Option<BootstrapProgress>,
+    file: &StateFile<'_>,
 ) {
+    let start = Instant::now();
     let state = processor.save();
     let mut data = Vec::new();
     ciborium::into_writer(
         &State {
             config: (*config).clone(),
+            generation,
             last,
             state,
+            bootstrap,
+            shard: file.shard,
         },
         &mut data,
     )
     .unwrap();
+    let stats = SaveStats {
+        time: Utc::now(),
+        duration_seconds: start.elapsed().as_secs_f64(),
+        bytes: data.len() as u64,
+    };
 
-    if let Err(e) = tokio::fs::write(path, data)
+    if let Err(e) = tokio::fs::write(file.path, data)
         .await
         .map_err(Error::WriteState)
     {
         log::warn!("{e}");
     } else {
-        log::info!("state saved")
+        log::info!(
+            "state saved ({} bytes, serialized in {:.3}s)",
+            stats.bytes,
+            stats.duration_seconds
+        );
+        processor.record_save(stats);
+        *file.save_stats.lock().unwrap() = Some(stats);
     }
 }
 
+/// Write the applied config to the export path, if any. Failures are
+/// logged: the export must not hold up processing.
+async fn export_config(path: Option<&Path>, generation: u64, config: &Config) {
+    let Some(path) = path else {
+        return;
+    };
+    let export = ConfigExport {
+        generation,
+        applied: Utc::now(),
+        config: config.clone(),
+    };
+    match export.write(path).await {
+        Ok(()) => log::info!(
+            "config generation {generation} exported to {}",
+            path.display()
+        ),
+        Err(e) => log::warn!("{e}"),
+    }
+}
+
+/// Replay the next chunk of a bootstrap, without writing metrics.
+/// Returns `false` when the bootstrap has caught up with `live`.
+async fn bootstrap_chunk(
+    args: &Args,
+    config: &Config,
+    es: &EsClient<'_>,
+    processor: &mut TraceProcessor,
+    progress: &mut BootstrapProgress,
+    live: DateTime<Utc>,
+    chunk: TimeDelta,
+) -> Result<bool> {
+    let Some((from, to)) = progress.next_chunk(live, chunk) else {
+        return Ok(false);
+    };
+    log::info!("bootstrap: replaying traces from {from} to {to}...");
+    process_traces(args, config, es, None, from, to, processor).await?;
+    progress.done = to;
+    Ok(true)
+}
+
+/// Process the traces started in `(from, to]`. Without a writer, the
+/// statistics are updated but no samples are taken and no metrics are
+/// written, as when replaying history. Returns what the tick did.
 async fn process_traces(
     args: &Args,
     config: &Config,
-    esclient: &reqwest::Client,
-    promclient: &reqwest::Client,
+    es: &EsClient<'_>,
+    writer: Option<&MetricsWriter<'_>>,
     from: DateTime<Utc>,
     to: DateTime<Utc>,
     processor: &mut TraceProcessor,
-) -> Result<()> {
-    let sample_interval = config.query_interval.to_time_delta();
-    let mut next_sample = from + sample_interval;
+) -> Result<TickReport> {
+    let start = Instant::now();
+    let mut report = TickReport::new(from, to);
+    let mut sampler = Sampler::new(
+        from,
+        processor.last_sample(),
+        config.sample_interval().to_time_delta(),
+    );
     let mut metrics = Metrics::new();
     let min_timestamp = Utc::now() - TimeDelta::hours(1);
+    // Pushdown aggregates cover all spans; the first shard writes them.
+    let pushdown = match es.shard {
+        Some(filter) if filter.shard().index > 0 => Vec::new(),
+        _ => processor.pushdown_queries().to_vec(),
+    };
+    let tags = config.prune_tags.then(|| processor.tag_allowlist().clone());
 
     struct Handler<'a> {
         args: &'a Args,
-        promclient: &'a reqwest::Client,
-        sample_interval: TimeDelta,
-        next_sample: &'a mut DateTime<Utc>,
+        es: &'a EsClient<'a>,
+        pushdown: &'a [PushdownQuery],
+        writer: Option<&'a MetricsWriter<'a>>,
+        sampler: &'a mut Sampler,
         metrics: &'a mut Metrics,
         processor: &'a mut TraceProcessor,
         min_timestamp: DateTime<Utc>,
+        filter: &'a IngestFilter,
+        tags: Option<&'a TagAllowlist>,
+        max_series: Option<usize>,
+        report: &'a mut TickReport,
     }
 
-    impl TraceHandler for Handler<'_> {
-        async fn handle(&mut self, root: &Span, spans: &[Span]) -> Result<()> {
-            let t = DateTime::from_timestamp_micros(root.start_time).ok_or(Error::DateTime)?;
-            while *self.next_sample < t {
-                if *self.next_sample >= self.min_timestamp {
-                    self.processor
-                        .sample(*self.next_sample, |metric_args, config_name, value| {
-                            self.metrics.add_metric(
-                                metric_args,
-                                config_name,
-                                *self.next_sample,
-                                value,
-                            );
-                        });
+    impl Handler<'_> {
+        fn insert(&mut self, batch: &[(DateTime<Utc>, &[Span])]) {
+            let start = Instant::now();
+            blocking(|| {
+                if self.args.sequential_insert {
+                    batch.iter().for_each(|(t, spans)| {
+                        self.processor.insert(*t, spans, self.filter);
+                    });
+                } else {
+                    self.processor.insert_batch(batch, self.filter);
                 }
-                *self.next_sample += self.sample_interval;
+            });
+            self.report.inserted(start.elapsed());
+        }
+    }
 
-                while self.metrics.len() > self.args.metrics_per_request {
-                    if let Err(e) = write_metrics(
-                        self.metrics.split_off(self.args.metrics_per_request),
-                        self.promclient,
-                        &self.args.prometheus_url,
+    impl TraceHandler for Handler<'_> {
+        async fn handle(&mut self, traces: &[(&Span, &[Span])]) -> Result<()> {
+            let mut batch = Vec::new();
+            for (root, spans) in traces {
+                let t = DateTime::from_timestamp_micros(root.start_time).ok_or(Error::DateTime)?;
+                if self.sampler.is_due(t) {
+                    // Synchronization point: all pending inserts must be
+                    // done before sampling.
+                    self.insert(&batch);
+                    batch.clear();
+                }
+                while let Some(sample_time) = self.sampler.take_due(t) {
+                    let start = Instant::now();
+                    insert_pushdown(
+                        self.args,
+                        self.es,
+                        self.pushdown,
+                        self.filter,
+                        self.processor,
+                        sample_time - self.sampler.interval(),
+                        sample_time,
                     )
-                    .await
-                    {
-                        log::warn!("{e}");
+                    .await;
+                    self.report.fetched(start.elapsed());
+                    if let Some(writer) = self.writer {
+                        if sample_time >= self.min_timestamp {
+                            let start = Instant::now();
+                            let before = self.metrics.len();
+                            sample_metrics(
+                                self.processor,
+                                sample_time,
+                                self.metrics,
+                                self.max_series,
+                            );
+                            self.report
+                                .sampled(self.metrics.len() - before, start.elapsed());
+                        }
+                        let start = Instant::now();
+                        let written = writer.write(self.metrics).await;
+                        self.report.wrote(written, start.elapsed());
                     }
                 }
-            }
 
-            self.processor.insert(t, spans);
+                batch.push((t, *spans));
+            }
+            self.insert(&batch);
             Ok(())
         }
+
+        fn ingest_stats(&self) -> Option<&IngestRecorder> {
+            self.writer.and_then(|writer| writer.ingest)
+        }
+
+        fn tag_allowlist(&self) -> Option<&TagAllowlist> {
+            self.tags
+        }
+
+        fn tick_report(&mut self) -> Option<&mut TickReport> {
+            Some(&mut *self.report)
+        }
     }
 
     for_traces(
         args,
-        esclient,
+        es,
         from,
         to,
+        &config.ingest_filter,
+        config.ingest_logs,
         Handler {
             args,
-            promclient,
-            sample_interval,
-            next_sample: &mut next_sample,
+            es,
+            pushdown: &pushdown,
+            writer,
+            sampler: &mut sampler,
             metrics: &mut metrics,
             processor,
             min_timestamp,
+            filter: &config.ingest_filter,
+            tags: tags.as_ref(),
+            max_series: config.max_series,
+            report: &mut report,
         },
     )
     .await?;
 
-    while next_sample < to {
-        processor.sample(next_sample, |metric_args, config_name, value| {
-            metrics.add_metric(metric_args, config_name, next_sample, value);
-        });
-        next_sample += sample_interval;
-
-        while metrics.len() > args.metrics_per_request {
-            if let Err(e) = write_metrics(
-                metrics.split_off(args.metrics_per_request),
-                promclient,
-                &args.prometheus_url,
-            )
-            .await
-            {
-                log::warn!("{e}");
-            }
+    while let Some(sample_time) = sampler.take_due(to) {
+        let start = Instant::now();
+        insert_pushdown(
+            args,
+            es,
+            &pushdown,
+            &config.ingest_filter,
+            processor,
+            sample_time - sampler.interval(),
+            sample_time,
+        )
+        .await;
+        report.fetched(start.elapsed());
+        if let Some(writer) = writer {
+            let start = Instant::now();
+            let before = metrics.len();
+            sample_metrics(processor, sample_time, &mut metrics, config.max_series);
+            report.sampled(metrics.len() - before, start.elapsed());
+            let start = Instant::now();
+            let written = writer.write(&mut metrics).await;
+            report.wrote(written, start.elapsed());
         }
     }
 
-    while !metrics.is_empty() {
-        if let Err(e) = write_metrics(
-            metrics.split_off(args.metrics_per_request),
-            promclient,
-            &args.prometheus_url,
-        )
-        .await
-        {
-            log::warn!("{e}");
-        }
+    if let Some(writer) = writer {
+        metrics.append(heartbeat_metrics(&args.instance_id, Utc::now(), to));
+        let start = Instant::now();
+        let written = writer.flush(&mut metrics).await;
+        report.wrote(written, start.elapsed());
     }
 
-    processor.cleanup(to - TimeDelta::days(30));
+    let mut stale = processor.cleanup(cleanup_time(to));
+    processor.compact_idle(to);
+    if let Some(writer) = writer.filter(|_| !stale.is_empty()) {
+        log::info!("marking {} series of removed groups as stale", stale.len());
+        let start = Instant::now();
+        let written = writer.flush(&mut stale).await;
+        report.wrote(written, start.elapsed());
+    }
 
-    Ok(())
+    report.durations.total_seconds = start.elapsed().as_secs_f64();
+    log::info!("finished processing traces: {report}");
+    Ok(report)
+}
+
+/// Self-monitoring: the heartbeat, holding the time it was written,
+/// and the end of the processed interval. Written at the end of every
+/// successful tick, through the same sink as the other metrics, so that
+/// a missing heartbeat means the engine stopped writing.
+fn heartbeat_metrics(instance_id: &str, now: DateTime<Utc>, to: DateTime<Utc>) -> Metrics {
+    let mut metrics = Metrics::new();
+    let no_group = GroupLabels::default();
+    [
+        ("jaeger_anomaly_detection_heartbeat", now),
+        (
+            "jaeger_anomaly_detection_last_processed_timestamp_seconds",
+            to,
+        ),
+    ]
+    .into_iter()
+    .for_each(|(metric_name, t)| {
+        metrics.add_metric(
+            MetricArgs {
+                metric_name: String::from(metric_name),
+                metric_type: "self_monitoring",
+                labels: Labels {
+                    instance_id: Some(instance_id.to_string()),
+                    ..Labels::default()
+                },
+                group: &no_group,
+            },
+            &TraceConfig::trace_metrics_config_name(),
+            now,
+            t.timestamp_millis() as f64 / 1000.0,
+        );
+    });
+    metrics
 }
 
 // struct ShowLabels<'a>(
@@ -369,48 +1289,477 @@ async fn process_traces(
 //     }
 // }
 
-async fn write_metrics(
-    metrics: Metrics,
-    promclient: &reqwest::Client,
-    prom_url: &Url,
-) -> Result<()> {
-    log::info!("writing {} metrics", metrics.len());
-    let req = metrics
-        .into_write_request()
-        .build_http_request(prom_url, "ContinuousC")
-        .map_err(Error::BuildPromRequest)?;
-    let res = promclient
-        .execute(reqwest::Request::try_from(req).map_err(Error::Prometheus)?)
-        .await
-        //.and_then(|r| r.error_for_status())
-        .map_err(Error::Prometheus)?
-        .text()
-        .await
-        .map_err(Error::Prometheus)?;
-    res.is_empty()
-        .then_some(())
-        .ok_or_else(|| Error::PromRes(res))
+/// Writes metrics to the sink, in requests of at most
+/// `metrics_per_request` samples. Up to `write_concurrency` requests
+/// are in flight at a time: the series are divided over that many lanes
+/// by their labels (see `Metrics::into_lanes`), and the requests of a
+/// lane are written one at a time, so that the samples of a series are
+/// written in order.
+struct MetricsWriter<'a> {
+    sink: &'a MetricsSink,
+    metrics_per_request: usize,
+    write_concurrency: usize,
+    dropped_series: &'a AtomicU64,
+    written_samples: &'a AtomicU64,
+    /// Counts the samples dropped as duplicates (see `Metrics::dedup`).
+    duplicate_samples: &'a AtomicU64,
+    ingest: Option<&'a IngestRecorder>,
+    writes: Option<&'a WriteRecorder>,
+    /// Added to the series as `shard` label, if sharded.
+    shard: Option<Shard>,
 }
 
-trait TraceHandler {
-    async fn handle(&mut self, root: &Span, spans: &[Span]) -> Result<()>;
-}
+impl MetricsWriter<'_> {
+    /// Write full requests, keeping the remainder of each lane for
+    /// later. Returns the number of samples written.
+    async fn write(&self, metrics: &mut Metrics) -> usize {
+        self.write_lanes(metrics, false).await
+    }
 
-async fn for_traces<T: TraceHandler>(
+    /// Write all remaining metrics. Returns the number of samples
+    /// written.
+    async fn flush(&self, metrics: &mut Metrics) -> usize {
+        self.write_lanes(metrics, true).await
+    }
+
+    async fn write_lanes(&self, metrics: &mut Metrics, flush: bool) -> usize {
+        if let Some(writes) = self.writes {
+            writes.record_buffer(metrics.len());
+        }
+        let mut lanes = std::mem::take(metrics).into_lanes(self.write_concurrency);
+        let written: usize = join_all(lanes.iter_mut().map(|lane| self.write_lane(lane, flush)))
+            .await
+            .into_iter()
+            .sum();
+        lanes.into_iter().for_each(|lane| metrics.append(lane));
+        written
+    }
+
+    async fn write_lane(&self, lane: &mut Metrics, flush: bool) -> usize {
+        let mut written = 0;
+        while lane.len() > self.metrics_per_request || (flush && !lane.is_empty()) {
+            written += self
+                .write_one(lane.split_off(self.metrics_per_request))
+                .await;
+        }
+        written
+    }
+
+    /// Write a single request. Returns the number of samples written,
+    /// which excludes duplicates and series dropped by the backend.
+    async fn write_one(&self, mut metrics: Metrics) -> usize {
+        if let Some(shard) = self.shard {
+            metrics.add_label("shard", &shard.index.to_string());
+        }
+        let duplicates = metrics.dedup();
+        if duplicates > 0 {
+            log::debug!("dropped {duplicates} duplicate samples");
+            self.duplicate_samples
+                .fetch_add(duplicates as u64, Ordering::Relaxed);
+        }
+        let start = Instant::now();
+        let samples = metrics.len();
+        if let Some(writes) = self.writes {
+            writes.record_request(samples);
+        }
+        let res = match self.sink {
+            MetricsSink::RemoteWrite { client, url } => write_metrics(metrics, client, url).await,
+            MetricsSink::File(file) => file.lock().unwrap().write(metrics).map(|()| 0),
+            MetricsSink::Null => {
+                log::info!("discarding {samples} metrics");
+                Ok(0)
+            }
+        };
+        if let Some(ingest) = self.ingest {
+            ingest.record_request(Request::RemoteWrite, start.elapsed());
+        }
+        match res {
+            Ok(dropped) => {
+                let written = samples.saturating_sub(dropped);
+                self.written_samples
+                    .fetch_add(written as u64, Ordering::Relaxed);
+                if dropped > 0 {
+                    self.dropped_series
+                        .fetch_add(dropped as u64, Ordering::Relaxed);
+                }
+                written
+            }
+            Err(e) => {
+                log::warn!("{e}");
+                0
+            }
+        }
+    }
+}
+
+/// Poll the futures concurrently on the current task. Returns their
+/// outputs in order.
+async fn join_all<F: Future>(futures: impl IntoIterator<Item = F>) -> Vec<F::Output> {
+    let mut futures = futures
+        .into_iter()
+        .map(|future| Some(Box::pin(future)))
+        .collect::<Vec<_>>();
+    let mut outputs = futures.iter().map(|_| None).collect::<Vec<_>>();
+    std::future::poll_fn(|cx| {
+        let mut pending = false;
+        for (future, output) in futures.iter_mut().zip(&mut outputs) {
+            if let Some(f) = future {
+                match f.as_mut().poll(cx) {
+                    Poll::Ready(value) => {
+                        *output = Some(value);
+                        *future = None;
+                    }
+                    Poll::Pending => pending = true,
+                }
+            }
+        }
+        if pending {
+            Poll::Pending
+        } else {
+            Poll::Ready(())
+        }
+    })
+    .await;
+    outputs.into_iter().map(Option::unwrap).collect()
+}
+
+/// The sink selected on the command line.
+fn metrics_sink(args: &Args, promclient: &reqwest::Client) -> MetricsSink {
+    match args.metrics_sink {
+        SinkKind::RemoteWrite => MetricsSink::RemoteWrite {
+            client: promclient.clone(),
+            url: args.prometheus_url.clone(),
+        },
+        SinkKind::File => MetricsSink::File(Mutex::new(FileSink::new(
+            args.metrics_file.clone(),
+            args.metrics_file_max_bytes,
+            args.metrics_file_keep,
+        ))),
+        SinkKind::Null => MetricsSink::Null,
+    }
+}
+
+/// Write metrics to prometheus. Series rejected with an out-of-order
+/// error, or that fail to encode, are dropped and the rest is retried.
+/// Returns the number of dropped series.
+async fn write_metrics(
+    mut metrics: Metrics,
+    promclient: &reqwest::Client,
+    prom_url: &Url,
+) -> Result<usize> {
+    let mut dropped = 0;
+    loop {
+        log::info!("writing {} metrics", metrics.len());
+        let req = match metrics
+            .write_request()
+            .build_http_request(prom_url, "ContinuousC")
+        {
+            Ok(req) => req,
+            Err(e) => {
+                let encodes = |metrics: &Metrics| {
+                    metrics
+                        .write_request()
+                        .build_http_request(prom_url, "ContinuousC")
+                        .is_ok()
+                };
+                // If an empty request fails too, the data is not to blame.
+                if !encodes(&Metrics::new()) {
+                    return Err(Error::BuildPromRequest(e));
+                }
+                let failing = metrics.remove_failing(encodes);
+                if failing.is_empty() {
+                    return Err(Error::BuildPromRequest(e));
+                }
+                log::warn!(
+                    "dropped {} series that failed to encode ({e}): {failing:?}",
+                    failing.len()
+                );
+                dropped += failing.len();
+                if metrics.is_empty() {
+                    return Ok(dropped);
+                }
+                continue;
+            }
+        };
+        let res = promclient
+            .execute(reqwest::Request::try_from(req).map_err(Error::Prometheus)?)
+            .await
+            .map_err(Error::Prometheus)?;
+        let status = res.status();
+        let body = res.text().await.map_err(Error::Prometheus)?;
+        if body.is_empty() {
+            return Ok(dropped);
+        }
+        if status != reqwest::StatusCode::BAD_REQUEST {
+            return Err(Error::PromRes(body));
+        }
+        let removed = metrics.remove_series(&out_of_order_series(&body));
+        if removed == 0 {
+            return Err(Error::PromRes(body));
+        }
+        log::warn!("dropped {removed} series rejected as out-of-order: {body}");
+        dropped += removed;
+        if metrics.is_empty() {
+            return Ok(dropped);
+        }
+    }
+}
+
+/// Query the aggregates of the pushdown configs for the sample
+/// interval `(from, to]`. They are inserted at the last microsecond of
+/// the interval, so that they end up in the window bins of the spans
+/// they summarize rather than in the next ones.
+///
+/// The aggregations do not run in the tick's point in time: it is
+/// opened per index pattern and already deleted for the samples due
+/// at the end of the tick. A failed query is therefore logged and its
+/// config skipped for the interval, rather than failing the tick and
+/// discarding the traces inserted so far.
+async fn insert_pushdown(
     args: &Args,
-    client: &reqwest::Client,
+    es: &EsClient<'_>,
+    queries: &[PushdownQuery],
+    filter: &IngestFilter,
+    processor: &mut TraceProcessor,
     from: DateTime<Utc>,
     to: DateTime<Utc>,
+) {
+    let t = to - TimeDelta::microseconds(1);
+    let indices = es.indices.select(Utc::now(), from).join(",");
+    for query in queries {
+        match query_pushdown(args, es, &indices, query, filter, from, to).await {
+            Ok(aggregates) => processor.insert_aggregates(t, &query.config, aggregates),
+            Err(e) => log::warn!(
+                "{}: pushdown aggregation up to {to} failed: {e}",
+                query.config
+            ),
+        }
+    }
+}
+
+/// Fetch all pages of a pushdown aggregation, so that a failure
+/// leaves none of them inserted.
+async fn query_pushdown(
+    args: &Args,
+    es: &EsClient<'_>,
+    indices: &str,
+    query: &PushdownQuery,
+    filter: &IngestFilter,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Result<Vec<(GroupKey, SpanAggregate)>> {
+    let mut aggregates = Vec::new();
+    let mut after = None;
+    loop {
+        let res = es
+            .client
+            .post(
+                args.opensearch_url
+                    .join(&format!("{indices}/_search"))
+                    .map_err(Error::Url)?,
+            )
+            .json(&query.request(from, to, ingest_filter_query(filter), after.as_ref()))
+            .pipe(|c| match &args.opensearch_user {
+                Some(username) => c.basic_auth(username, args.opensearch_password.as_ref()),
+                None => c,
+            })
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(Error::Elastic)?
+            .json::<EsResponse<EsAggResponse<Aggregations>>>()
+            .await
+            .map_err(Error::Elastic)?
+            .into_result()?;
+        let (page, next) = query.aggregates(res.aggregations);
+        aggregates.extend(page);
+        match next {
+            Some(next) => after = Some(next),
+            None => return Ok(aggregates),
+        }
+    }
+}
+
+/// The OpenSearch client, with the throttle applied to the trace
+/// queries.
+#[derive(Clone, Copy)]
+struct EsClient<'a> {
+    client: &'a reqwest::Client,
+    throttle: &'a Throttle,
+    pit: &'a PitLease,
+    /// Restricts the root spans to the shard, if sharded.
+    shard: Option<&'a ShardFilter>,
+    /// The index patterns to query, by range.
+    indices: &'a SpanIndices,
+}
+
+fn span_indices(args: &Args) -> SpanIndices {
+    SpanIndices::new(
+        INDEX,
+        args.opensearch_archive_index
+            .as_deref()
+            .map(|pattern| (pattern, args.opensearch_archive_after.to_time_delta())),
+    )
+}
+
+fn throttle_config(args: &Args) -> ThrottleConfig {
+    ThrottleConfig {
+        max_chunk_size: CHUNK_SIZE,
+        min_chunk_size: args.opensearch_min_chunk_size,
+        max_delay: std::time::Duration::from_millis(args.opensearch_max_delay_ms),
+        slow_request: std::time::Duration::from_millis(args.opensearch_slow_request_ms),
+        max_retries: args.opensearch_max_retries,
+    }
+}
+
+/// Send a search request, recording its latency in the throttle.
+/// Requests rejected because OpenSearch is overloaded (HTTP 429 or a
+/// circuit-breaker error) are retried with backoff.
+async fn throttled_search<T: DeserializeOwned>(
+    throttle: &Throttle,
+    request: reqwest::RequestBuilder,
+) -> Result<T> {
+    let mut attempt = 0;
+    loop {
+        throttle.wait().await;
+        let start = Instant::now();
+        let res = request
+            .try_clone()
+            .expect("search requests have a buffered body")
+            .send()
+            .await
+            .map_err(Error::Elastic)?;
+        let status = res.status();
+        let body = res.bytes().await.map_err(Error::Elastic)?;
+        let error = (!status.is_success())
+            .then(|| serde_json::from_slice::<EsError>(&body).ok())
+            .flatten();
+        let rejected = status == StatusCode::TOO_MANY_REQUESTS
+            || error.as_ref().is_some_and(EsError::is_circuit_breaker);
+        throttle.record(start.elapsed(), rejected);
+        if rejected {
+            if let Some(backoff) = throttle.retry(attempt) {
+                log::warn!("opensearch is overloaded ({status}); retrying in {backoff:?}");
+                tokio::time::sleep(backoff).await;
+                attempt += 1;
+                continue;
+            }
+        }
+        return match error {
+            Some(e) => Err(Error::ElasticErr(e)),
+            None if !status.is_success() => Err(Error::ElasticStatus(
+                status,
+                String::from_utf8_lossy(&body).into_owned(),
+            )),
+            None => serde_json::from_slice::<EsResponse<T>>(&body)
+                .map_err(Error::ElasticDecode)?
+                .into_result(),
+        };
+    }
+}
+
+/// Leave out span logs unless needed.
+fn source_filter(ingest_logs: bool) -> Option<EsSourceFilter> {
+    (!ingest_logs).then(|| EsSourceFilter {
+        excludes: vec!["logs"],
+    })
+}
+
+trait TraceHandler {
+    /// Handle a chunk of traces, given as (root, spans) pairs ordered
+    /// by the start time of the root span.
+    async fn handle(&mut self, traces: &[(&Span, &[Span])]) -> Result<()>;
+
+    /// Where to record trace sizes and query latencies, if enabled.
+    fn ingest_stats(&self) -> Option<&IngestRecorder>;
+
+    /// The tags to keep when parsing spans, if pruning is enabled.
+    fn tag_allowlist(&self) -> Option<&TagAllowlist>;
+
+    /// Where to count the fetched traces, if reporting on the tick.
+    fn tick_report(&mut self) -> Option<&mut TickReport>;
+}
+
+/// Fetch the traces whose root span started in `[from, to)`, from the
+/// index patterns selected for the range (see `SpanIndices`).
+async fn for_traces<T: TraceHandler>(
+    args: &Args,
+    es: &EsClient<'_>,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    filter: &IngestFilter,
+    ingest_logs: bool,
     mut handler: T,
 ) -> Result<()> {
-    let mut pit_id = client
+    let indices = es.indices.select(Utc::now(), from);
+    if let Some(report) = handler.tick_report() {
+        report.index_patterns = indices.iter().map(|index| index.to_string()).collect();
+    }
+
+    // The trace ids seen so far, when the spans of a trace can be found
+    // in several patterns.
+    let mut seen = (indices.len() > 1).then(BTreeSet::new);
+
+    // A single point in time over all patterns merges their traces in
+    // start time order.
+    if indices.len() > 1 && es.indices.combined_pit() {
+        match create_pit(args, es, &indices.join(",")).await {
+            Ok(pit_id) => {
+                return for_traces_in(
+                    args,
+                    es,
+                    pit_id,
+                    from..to,
+                    filter,
+                    seen.as_mut(),
+                    ingest_logs,
+                    &mut handler,
+                )
+                .await;
+            }
+            Err(Error::Elastic(e))
+                if e.status()
+                    .is_some_and(|status| es.indices.fall_back(&indices, status)) => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    // Otherwise, the range is split at the cutoff age, so that the
+    // traces are only out of order before it, between the patterns.
+    for (index, range) in es.indices.passes(Utc::now(), from..to) {
+        let pit_id = create_pit(args, es, index).await?;
+        for_traces_in(
+            args,
+            es,
+            pit_id,
+            range,
+            filter,
+            seen.as_mut(),
+            ingest_logs,
+            &mut handler,
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// Create a point in time over `indices`, a comma-separated list of
+/// index patterns.
+async fn create_pit(args: &Args, es: &EsClient<'_>, indices: &str) -> Result<EsPitId> {
+    let EsClient {
+        client,
+        throttle,
+        pit,
+        ..
+    } = *es;
+    let pit_id = client
         .post(
             args.opensearch_url
-                .join(&format!("{}/_search/point_in_time", INDEX))
+                .join(&format!("{indices}/_search/point_in_time"))
                 .map_err(Error::Url)?,
         )
         .query(&EsCreatePitQuery {
-            keep_alive: KEEP_ALIVE,
+            keep_alive: pit.keep_alive(throttle.delay()),
             allow_partial_pit_creation: false,
         })
         .pipe(|c| match &args.opensearch_user {
@@ -426,56 +1775,106 @@ async fn for_traces<T: TraceHandler>(
         .map_err(Error::Elastic)?
         .into_result()?
         .pit_id;
+    pit.extend();
+    Ok(pit_id)
+}
 
+/// Fetch the traces whose root span started in `range`, through the
+/// point in time `pit_id`, which is deleted afterwards. With `seen`,
+/// the traces whose id is in it are skipped, the others are added,
+/// and spans found more than once in a trace are kept once.
+#[allow(clippy::too_many_arguments)]
+async fn for_traces_in<T: TraceHandler>(
+    args: &Args,
+    es: &EsClient<'_>,
+    mut pit_id: EsPitId,
+    range: Range<DateTime<Utc>>,
+    filter: &IngestFilter,
+    mut seen: Option<&mut BTreeSet<TraceId>>,
+    ingest_logs: bool,
+    handler: &mut T,
+) -> Result<()> {
+    let EsClient {
+        client,
+        throttle,
+        pit,
+        shard,
+        ..
+    } = *es;
+    let Range {
+        start: from,
+        end: to,
+    } = range;
     let mut last = None;
 
-    let query = serde_json::json!({
-        "bool": {
-            "must": [
-                {
+    let root_query = || {
+        serde_json::json!({
+            "bool": {
+                "must": std::iter::once(serde_json::json!({
                     "range": {
                         "startTime": {
                             "gte": from.timestamp_micros(),
                             "lt": to.timestamp_micros()
                         }
                     }
-                },
-                find_root_spans()
-            ]
-        }
-    });
+                }))
+                .chain(std::iter::once(find_root_spans()))
+                .chain(ingest_filter_query(filter))
+                .chain(shard.and_then(ShardFilter::query))
+                .collect::<Vec<_>>()
+            }
+        })
+    };
+    let mut query = root_query();
 
     let res = async {
         loop {
-            let res = client
-                .post(args.opensearch_url.join("_search").map_err(Error::Url)?)
-                .json(&EsSearchRequest {
-                    query: &query,
-                    size: BATCH_SIZE,
-                    pit: Some(EsPit {
-                        id: pit_id.clone(),
-                        keep_alive: KEEP_ALIVE,
-                    }),
-                    sort: Some(vec![EsSortField {
-                        field: String::from("startTime"),
-                        opts: EsSortOpts {
-                            order: EsSortOrder::Asc,
-                        },
-                    }]),
-                    search_after: last,
-                })
-                .pipe(|c| match &args.opensearch_user {
-                    Some(username) => c.basic_auth(username, args.opensearch_password.as_ref()),
-                    None => c,
-                })
-                .send()
-                .await
-                .and_then(|r| r.error_for_status())
-                .map_err(Error::Elastic)?
-                .json::<EsResponse<EsSearchResponse<Span, (i64,)>>>()
-                .await
-                .map_err(Error::Elastic)?
-                .into_result()?;
+            let start = Instant::now();
+            let request = |query: &serde_json::Value| -> Result<_> {
+                Ok(client
+                    .post(args.opensearch_url.join("_search").map_err(Error::Url)?)
+                    .json(&EsSearchRequest {
+                        query,
+                        size: BATCH_SIZE,
+                        pit: Some(EsPit {
+                            id: pit_id.clone(),
+                            keep_alive: pit.keep_alive(throttle.delay()),
+                        }),
+                        sort: Some(vec![EsSortField {
+                            field: String::from("startTime"),
+                            opts: EsSortOpts {
+                                order: EsSortOrder::Asc,
+                            },
+                        }]),
+                        search_after: last,
+                        source: source_filter(ingest_logs),
+                    })
+                    .pipe(|c| match &args.opensearch_user {
+                        Some(username) => c.basic_auth(username, args.opensearch_password.as_ref()),
+                        None => c,
+                    }))
+            };
+            let res = match throttled_search::<EsSearchResponse<Span, (i64,)>>(
+                throttle,
+                request(&query)?,
+            )
+            .await
+            {
+                // Without scripts, the root spans are filtered here.
+                Err(Error::ElasticErr(e)) if shard.is_some_and(|shard| shard.fall_back(&e)) => {
+                    query = root_query();
+                    throttled_search::<EsSearchResponse<Span, (i64,)>>(throttle, request(&query)?)
+                        .await?
+                }
+                res => res?,
+            };
+            pit.extend();
+            if let Some(ingest) = handler.ingest_stats() {
+                ingest.record_request(Request::RootQuery, start.elapsed());
+            }
+            if let Some(report) = handler.tick_report() {
+                report.fetched(start.elapsed());
+            }
 
             pit_id = res.pit_id.ok_or(Error::ElasticMissingPitId)?;
 
@@ -485,8 +1884,24 @@ async fn for_traces<T: TraceHandler>(
 
             last = res.hits.hits.last().unwrap().sort;
 
-            for roots in res.hits.hits.chunks(CHUNK_SIZE) {
-                let res = client
+            let hits = res
+                .hits
+                .hits
+                .iter()
+                .filter(|hit| shard.map_or(true, |shard| shard.keeps(&hit.source.trace_id)))
+                .filter(|hit| {
+                    seen.as_deref_mut()
+                        .map_or(true, |seen| seen.insert(hit.source.trace_id.clone()))
+                })
+                .collect::<Vec<_>>();
+
+            // The chunk size shrinks while OpenSearch is under pressure.
+            let mut rest = hits.as_slice();
+            while !rest.is_empty() {
+                let (roots, tail) = rest.split_at(throttle.chunk_size().min(rest.len()));
+                rest = tail;
+                let start = Instant::now();
+                let request = client
                     .post(args.opensearch_url.join("_search").map_err(Error::Url)?)
                     .json(&EsSearchRequest::<_, ()> {
                         query: serde_json::json!({
@@ -500,7 +1915,7 @@ async fn for_traces<T: TraceHandler>(
                         size: MAX_SPANS,
                         pit: Some(EsPit {
                             id: pit_id.clone(),
-                            keep_alive: KEEP_ALIVE,
+                            keep_alive: pit.keep_alive(throttle.delay()),
                         }),
                         sort: Some(vec![EsSortField {
                             field: String::from("startTime"),
@@ -509,41 +1924,69 @@ async fn for_traces<T: TraceHandler>(
                             },
                         }]),
                         search_after: None,
+                        source: source_filter(ingest_logs),
                     })
                     .pipe(|c| match &args.opensearch_user {
                         Some(username) => c.basic_auth(username, args.opensearch_password.as_ref()),
                         None => c,
-                    })
-                    .send()
-                    .await
-                    .and_then(|r| r.error_for_status())
-                    .map_err(Error::Elastic)?
-                    .json::<EsResponse<EsSearchResponse<Span, (i64,)>>>()
-                    .await
-                    .map_err(Error::Elastic)?
-                    .into_result()?;
+                    });
+                let res =
+                    throttled_search::<EsSearchResponse<Span, (i64,)>>(throttle, request).await?;
+                pit.extend();
+                if let Some(ingest) = handler.ingest_stats() {
+                    ingest.record_request(Request::SpanQuery, start.elapsed());
+                }
+                if let Some(report) = handler.tick_report() {
+                    report.fetched(start.elapsed());
+                }
 
                 assert!(res.hits.total.relation == EsRel::Eq);
                 pit_id = res.pit_id.ok_or(Error::ElasticMissingPitId)?;
 
-                let traces =
-                    res.hits
-                        .hits
-                        .into_iter()
-                        .fold(BTreeMap::<_, Vec<_>>::new(), |mut map, hit| {
-                            map.entry(hit.source.trace_id.clone())
-                                .or_default()
-                                .push(hit.source);
-                            map
-                        });
-
-                for root in roots {
-                    if let Some(spans) = traces.get(&root.source.trace_id) {
-                        handler.handle(&root.source, spans).await?;
-                    } else {
-                        eprintln!("warning: no spans found for {}", root.source.trace_id);
-                    }
+                let mut span_ids = BTreeSet::new();
+                let traces = res
+                    .hits
+                    .hits
+                    .into_iter()
+                    .filter(|hit| {
+                        seen.is_none()
+                            || span_ids
+                                .insert((hit.source.trace_id.clone(), hit.source.span_id.clone()))
+                    })
+                    .fold(BTreeMap::<_, Vec<_>>::new(), |mut map, hit| {
+                        let mut span = hit.source;
+                        if let Some(tags) = handler.tag_allowlist() {
+                            tags.prune(&mut span);
+                        }
+                        map.entry(span.trace_id.clone()).or_default().push(span);
+                        map
+                    });
+
+                let traces = roots
+                    .iter()
+                    .filter_map(|root| match traces.get(&root.source.trace_id) {
+                        Some(spans) => Some((&root.source, spans.as_slice())),
+                        None => {
+                            eprintln!("warning: no spans found for {}", root.source.trace_id);
+                            None
+                        }
+                    })
+                    .collect::<Vec<_>>();
+                if let Some(ingest) = handler.ingest_stats() {
+                    traces
+                        .iter()
+                        .for_each(|(_, spans)| ingest.record_trace(spans.len()));
                 }
+                if let Some(report) = handler.tick_report() {
+                    report.roots += roots.len() as u64;
+                    report.traces += traces.len() as u64;
+                    report.spans += traces
+                        .iter()
+                        .map(|(_, spans)| spans.len() as u64)
+                        .sum::<u64>();
+                    report.skipped_traces += (roots.len() - traces.len()) as u64;
+                }
+                handler.handle(&traces).await?;
             }
         }
 
@@ -571,53 +2014,56 @@ async fn for_traces<T: TraceHandler>(
         .map_err(Error::Elastic)?
         .into_result()?;
 
-    match res {
-        Ok(()) => {
-            log::info!("finished processing traces");
-            Ok(())
-        }
-        Err(e) => Err(e),
-    }
-}
-
-// async fn get_spans(args: &Args, client: &reqwest::Client, trace_id: &TraceId) -> Result<Vec<Span>> {
-//     let res = client
-//         .post(args.es_url.join("_search").unwrap())
-//         .json(&EsSearchRequest::<_, ()> {
-//             query: serde_json::json!({
-//                 "term": {
-//                     "traceID": trace_id
-//                 }
-//             }),
-//             size: MAX_SPANS,
-//             pit: None,
-//             sort: Some(vec![EsSortField {
-//                 field: String::from("startTime"),
-//                 opts: EsSortOpts {
-//                     order: EsSortOrder::Asc,
-//                 },
-//             }]),
-//             search_after: None,
-//         })
-//         .basic_auth(&args.user, Some(&args.password))
-//         .send()
-//         .await
-//         .and_then(|r| r.error_for_status())
-//         .map_err(Error::Elastic)?
-//         .json::<EsResponse<EsSearchResponse<Span, (i64,)>>>()
-//         .await
-//         .map_err(Error::Elastic)?
-//         .into_result()?;
-
-//     assert!(res.hits.total.relation == EsRel::Eq);
-
-//     Ok(res
-//         .hits
-//         .hits
-//         .into_iter()
-//         .map(|hit| hit.source)
-//         .collect::<Vec<_>>())
-// }
+    res
+}
+
+/// Fetch the spans of a single trace. The documents are returned
+/// unparsed, so that spans failing to parse can be reported.
+async fn fetch_trace(
+    args: &Args,
+    client: &reqwest::Client,
+    trace_id: &str,
+    ingest_logs: bool,
+) -> Result<Vec<serde_json::Value>> {
+    // The age of the trace is not known.
+    let indices = span_indices(args).all().join(",");
+    let res = client
+        .post(
+            args.opensearch_url
+                .join(&format!("{indices}/_search"))
+                .map_err(Error::Url)?,
+        )
+        .json(&EsSearchRequest::<_, ()> {
+            query: serde_json::json!({
+                "terms": {
+                    "traceID": [trace_id]
+                }
+            }),
+            size: MAX_SPANS,
+            pit: None,
+            sort: Some(vec![EsSortField {
+                field: String::from("startTime"),
+                opts: EsSortOpts {
+                    order: EsSortOrder::Asc,
+                },
+            }]),
+            search_after: None,
+            source: source_filter(ingest_logs),
+        })
+        .pipe(|c| match &args.opensearch_user {
+            Some(username) => c.basic_auth(username, args.opensearch_password.as_ref()),
+            None => c,
+        })
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .map_err(Error::Elastic)?
+        .json::<EsResponse<EsSearchResponse<serde_json::Value, (i64,)>>>()
+        .await
+        .map_err(Error::Elastic)?
+        .into_result()?;
+    Ok(res.hits.hits.into_iter().map(|hit| hit.source).collect())
+}
 
 fn find_root_spans() -> serde_json::Value {
     serde_json::json!({
@@ -637,3 +2083,1806 @@ fn find_root_spans() -> serde_json::Value {
         }
     })
 }
+
+/// Run CPU-bound work from an async task. On a multi-threaded runtime,
+/// the other tasks of this worker are moved to other threads in the
+/// meantime; the current-thread runtime of the tests just runs it.
+fn blocking<T>(f: impl FnOnce() -> T) -> T {
+    match tokio::runtime::Handle::try_current().map(|handle| handle.runtime_flavor()) {
+        Ok(RuntimeFlavor::MultiThread) => tokio::task::block_in_place(f),
+        _ => f(),
+    }
+}
+
+/// Translate the ingest filter into a query on the root spans, so
+/// that excluded traces are never fetched.
+fn ingest_filter_query(filter: &IngestFilter) -> Option<serde_json::Value> {
+    if filter.is_empty() {
+        return None;
+    }
+
+    let service = |matches: &[ValueMatch]| value_match_query("process.serviceName", matches);
+    let namespace = |matches: &[ValueMatch]| {
+        serde_json::json!({
+            "nested": {
+                "path": "process.tags",
+                "query": {
+                    "bool": {
+                        "must": [
+                            {
+                                "term": {
+                                    "process.tags.key": "service.namespace"
+                                }
+                            },
+                            value_match_query("process.tags.value", matches)
+                        ]
+                    }
+                }
+            }
+        })
+    };
+
+    let include = (!filter.include_services.is_empty())
+        .then(|| service(&filter.include_services))
+        .into_iter()
+        .chain(
+            (!filter.include_namespaces.is_empty()).then(|| namespace(&filter.include_namespaces)),
+        )
+        .collect::<Vec<_>>();
+    let exclude = (!filter.exclude_services.is_empty())
+        .then(|| service(&filter.exclude_services))
+        .into_iter()
+        .chain(
+            (!filter.exclude_namespaces.is_empty()).then(|| namespace(&filter.exclude_namespaces)),
+        )
+        .collect::<Vec<_>>();
+
+    Some(serde_json::json!({
+        "bool": {
+            "filter": include,
+            "must_not": exclude
+        }
+    }))
+}
+
+fn value_match_query(field: &str, matches: &[ValueMatch]) -> serde_json::Value {
+    serde_json::json!({
+        "bool": {
+            "should": matches
+                .iter()
+                .map(|m| match m {
+                    ValueMatch::Eq(value) => serde_json::json!({ "term": { field: value } }),
+                    ValueMatch::Match(re) => serde_json::json!({ "regexp": { field: re.as_str() } }),
+                })
+                .collect::<Vec<_>>(),
+            "minimum_should_match": 1
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::{BTreeMap, BTreeSet},
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc, Mutex,
+        },
+        time::Duration,
+    };
+
+    use chrono::{DateTime, TimeDelta, Utc};
+    use clap::Parser;
+    use rcgen::{BasicConstraints, CertificateParams, ExtendedKeyUsagePurpose, IsCa, KeyPair};
+    use rustls::{
+        pki_types::PrivatePkcs8KeyDer, server::WebPkiClientVerifier, RootCertStore, ServerConfig,
+    };
+    use serde_json::json;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+    use tokio_rustls::TlsAcceptor;
+    use url::Url;
+
+    use super::{
+        bootstrap_chunk, fetch_trace, for_traces, ingest_filter_query, load_ca, load_identity,
+        process_traces, span_indices, throttled_search, tls_client, write_metrics, EsClient,
+        MetricsWriter, Processor, TraceHandler,
+    };
+    use crate::{
+        config::{
+            AnchoredRegex, Config, ConfigName, IngestFilter, MetricName, SpanSelector, ValueMatch,
+        },
+        error::{Error, Result},
+        jaeger::Span,
+        metrics::Metrics,
+        processor::{
+            bootstrap::BootstrapProgress,
+            fake_http::FakeHttp,
+            fake_opensearch::FakeOpenSearch,
+            fake_remote_write::FakeRemoteWrite,
+            histogram::HistogramConfig,
+            ingest_stats::IngestRecorder,
+            keep_alive::PitLease,
+            mean_stddev::{MeanStddevAlgorithm, MeanStddevConfig},
+            shard::{Shard, ShardFilter},
+            sim::{
+                assert_golden, fixtures, load_fixture, parse_traces, start, synthetic_docs,
+                trace_config, PipelineSim,
+            },
+            sink::{FileSink, MetricsSink},
+            source::MetricSource,
+            stats::StatsConfig,
+            tag_allowlist::TagAllowlist,
+            throttle::{test_config, Throttle},
+            tick::TickReport,
+            trace::{TraceConfig, TraceProcessor},
+            trace_debug::{parse_spans, SkipReason},
+            write_stats::WriteRecorder,
+        },
+        state::State,
+        Args, INDEX,
+    };
+
+    /// A remote-write endpoint answering requests with the given
+    /// responses, one connection per request.
+    async fn mock_server<B: Into<String>>(responses: Vec<(u16, B)>) -> (Url, FakeHttp) {
+        let server = FakeHttp::responses("/api/v1/push", responses).await;
+        (server.url().clone(), server)
+    }
+
+    fn metrics(names: &[&str]) -> Metrics {
+        let t = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let mut metrics = Metrics::new();
+        names.iter().for_each(|name| {
+            let labels = BTreeMap::from_iter([
+                (String::from("__name__"), name.to_string()),
+                (String::from("config"), String::from("default")),
+            ]);
+            metrics.insert(labels, t, 1.0);
+        });
+        metrics
+    }
+
+    #[tokio::test]
+    async fn write_metrics_retries_without_out_of_order_series() {
+        let (url, server) = mock_server(vec![
+            (
+                400,
+                "user=fake: err: out of order sample. timestamp=2023-11-14T22:13:20Z, \
+                 series={__name__=\"b\", config=\"default\"}",
+            ),
+            (200, ""),
+        ])
+        .await;
+        let dropped = write_metrics(metrics(&["a", "b", "c"]), &reqwest::Client::new(), &url)
+            .await
+            .unwrap();
+        assert_eq!(dropped, 1);
+        let sizes = server
+            .finished()
+            .await
+            .iter()
+            .map(|request| request.body.len())
+            .collect::<Vec<_>>();
+        assert_eq!(sizes.len(), 2);
+        assert!(sizes[1] < sizes[0]);
+    }
+
+    #[tokio::test]
+    async fn write_metrics_fails_on_other_errors() {
+        let (url, server) = mock_server(vec![(400, "invalid request")]).await;
+        assert!(
+            write_metrics(metrics(&["a", "b"]), &reqwest::Client::new(), &url)
+                .await
+                .is_err()
+        );
+        assert_eq!(server.finished().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn metrics_writer_records_latency() {
+        let (url, server) = mock_server(vec![(200, ""), (200, "")]).await;
+        let client = reqwest::Client::new();
+        let dropped_series = AtomicU64::new(0);
+        let ingest = IngestRecorder::default();
+        let written_samples = AtomicU64::new(0);
+        let duplicate_samples = AtomicU64::new(0);
+        let sink = MetricsSink::RemoteWrite { client, url };
+        let writer = MetricsWriter {
+            sink: &sink,
+            metrics_per_request: 2,
+            write_concurrency: 1,
+            dropped_series: &dropped_series,
+            written_samples: &written_samples,
+            duplicate_samples: &duplicate_samples,
+            ingest: Some(&ingest),
+            writes: None,
+            shard: None,
+        };
+        writer.flush(&mut metrics(&["a", "b", "c"])).await;
+        assert_eq!(server.finished().await.len(), 2);
+        assert_eq!(written_samples.load(Ordering::Relaxed), 3);
+
+        let report = ingest.finish(DateTime::from_timestamp(1_700_000_000, 0).unwrap());
+        assert_eq!(report.remote_writes.count, 2);
+        assert_eq!(report.remote_writes.quantiles.len(), 3);
+        assert_eq!(report.root_queries.count, 0);
+    }
+
+    #[tokio::test]
+    async fn concurrent_writes_keep_series_in_order() {
+        let receiver = FakeRemoteWrite::start().await;
+        let sink = MetricsSink::RemoteWrite {
+            client: reqwest::Client::new(),
+            url: receiver.url().clone(),
+        };
+        let dropped_series = AtomicU64::new(0);
+        let written_samples = AtomicU64::new(0);
+        let duplicate_samples = AtomicU64::new(0);
+        let writes = WriteRecorder::default();
+        let writer = MetricsWriter {
+            sink: &sink,
+            metrics_per_request: 10,
+            write_concurrency: 4,
+            dropped_series: &dropped_series,
+            written_samples: &written_samples,
+            duplicate_samples: &duplicate_samples,
+            ingest: None,
+            writes: Some(&writes),
+            shard: None,
+        };
+
+        // A sample per series per tick; the lanes hold back what does
+        // not fill a request, so series are spread over several
+        // requests.
+        let mut metrics = Metrics::new();
+        let mut expected = Metrics::new();
+        for i in 0..20 {
+            let t = start() + TimeDelta::seconds(10 * i);
+            (0..25).for_each(|j| {
+                let labels = BTreeMap::from_iter([(String::from("__name__"), format!("s{j}"))]);
+                metrics.insert(labels.clone(), t, i as f64);
+                expected.insert(labels, t, i as f64);
+            });
+            writer.write(&mut metrics).await;
+        }
+        writer.flush(&mut metrics).await;
+        assert!(metrics.is_empty());
+        assert_eq!(written_samples.load(Ordering::Relaxed), 500);
+        assert_eq!(
+            receiver.metrics().to_debug_lines(),
+            expected.to_debug_lines()
+        );
+
+        // In the order received, each series' timestamps increase.
+        let mut last = BTreeMap::new();
+        receiver.samples().into_iter().for_each(|(labels, t, _)| {
+            if let Some(prev) = last.insert(labels.clone(), t) {
+                assert!(prev < t, "{labels:?}: {t} after {prev}");
+            }
+        });
+        assert_eq!(last.len(), 25);
+
+        let report = writes.finish(10);
+        assert_eq!(report.requests as usize, receiver.requests());
+        assert!(report.requests > 4);
+        assert!(report.max_buffered >= 25);
+        assert!(report.utilization > 0.0);
+    }
+
+    #[tokio::test]
+    async fn client_certificate_auth() {
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+        let signed = |names: Vec<String>, usage| {
+            let key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(names).unwrap();
+            params.extended_key_usages = vec![usage];
+            (params.signed_by(&key, &ca, &ca_key).unwrap(), key)
+        };
+        let (server_cert, server_key) = signed(
+            vec![String::from("localhost")],
+            ExtendedKeyUsagePurpose::ServerAuth,
+        );
+        let (client_cert, client_key) = signed(Vec::new(), ExtendedKeyUsagePurpose::ClientAuth);
+
+        // An https endpoint requiring a client certificate signed by
+        // the CA.
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut roots = RootCertStore::empty();
+        roots.add(ca.der().clone()).unwrap();
+        let verifier =
+            WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+                .build()
+                .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(
+            ServerConfig::builder_with_provider(provider)
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_client_cert_verifier(verifier)
+                .with_single_cert(
+                    vec![server_cert.der().clone()],
+                    PrivatePkcs8KeyDer::from(server_key.serialize_der()).into(),
+                )
+                .unwrap(),
+        ));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let url = Url::parse(&format!("https://localhost:{}/", addr.port())).unwrap();
+        let server = tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let Ok(mut stream) = acceptor.accept(stream).await else {
+                    continue;
+                };
+                let mut buf = Vec::new();
+                let mut chunk = [0; 4096];
+                while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+                    let n = stream.read(&mut chunk).await.unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    buf.extend_from_slice(&chunk[..n]);
+                }
+                let _ = stream
+                    .write_all(
+                        b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\nconnection: close\r\n\r\nok",
+                    )
+                    .await;
+                let _ = stream.shutdown().await;
+            }
+        });
+
+        let dir = std::env::temp_dir().join(format!(
+            "jaeger-anomaly-detection-tls-{}",
+            std::process::id()
+        ));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        for (name, pem) in [
+            ("ca.crt", ca.pem()),
+            ("tls.crt", client_cert.pem()),
+            ("tls.key", client_key.serialize_pem()),
+        ] {
+            tokio::fs::write(dir.join(name), pem).await.unwrap();
+        }
+        let ca = load_ca(&dir.join("ca.crt")).await.unwrap();
+        let id = load_identity(&dir.join("tls.crt"), &dir.join("tls.key"))
+            .await
+            .unwrap();
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+
+        let client = tls_client(&ca)
+            .resolve("localhost", addr)
+            .identity(id)
+            .build()
+            .unwrap();
+        let res = client.get(url.clone()).send().await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        assert_eq!(res.text().await.unwrap(), "ok");
+
+        // Without the client certificate, the connection is refused.
+        let client = tls_client(&ca).resolve("localhost", addr).build().unwrap();
+        assert!(client.get(url).send().await.is_err());
+
+        server.abort();
+    }
+
+    /// A span document, as stored in OpenSearch.
+    fn span_doc(span_id: &str, parent: Option<&str>) -> serde_json::Value {
+        trace_span_doc("0de61f1de7ee678bccb46f3dab804867", span_id, parent)
+    }
+
+    fn trace_span_doc(trace_id: &str, span_id: &str, parent: Option<&str>) -> serde_json::Value {
+        json!({
+            "_source": {
+                "traceID": trace_id,
+                "spanID": span_id,
+                "operationName": "GET",
+                "references": parent.map_or_else(Vec::new, |parent| vec![json!({
+                    "refType": "CHILD_OF",
+                    "traceID": trace_id,
+                    "spanID": parent
+                })]),
+                "startTime": 1_700_000_000_000_000i64,
+                "startTimeMillis": 1_700_000_000_000i64,
+                "duration": 1000,
+                "tags": [],
+                "process": { "serviceName": "frontend", "tags": [] }
+            },
+            "sort": [1_700_000_000_000_000i64]
+        })
+    }
+
+    struct RecordingHandler<'a>(&'a IngestRecorder);
+
+    impl TraceHandler for RecordingHandler<'_> {
+        async fn handle(&mut self, _traces: &[(&Span, &[Span])]) -> Result<()> {
+            Ok(())
+        }
+
+        fn ingest_stats(&self) -> Option<&IngestRecorder> {
+            Some(self.0)
+        }
+
+        fn tag_allowlist(&self) -> Option<&TagAllowlist> {
+            None
+        }
+
+        fn tick_report(&mut self) -> Option<&mut TickReport> {
+            None
+        }
+    }
+
+    #[tokio::test]
+    async fn for_traces_records_queries() {
+        let hits = |hits: Vec<serde_json::Value>| {
+            json!({
+                "pit_id": "pit",
+                "hits": { "total": { "relation": "eq" }, "hits": hits }
+            })
+            .to_string()
+        };
+        let (url, server) = mock_server(vec![
+            (200, json!({ "pit_id": "pit" }).to_string()),
+            (200, hits(vec![span_doc("1", None)])),
+            (
+                200,
+                hits(vec![span_doc("1", None), span_doc("2", Some("1"))]),
+            ),
+            (200, hits(Vec::new())),
+            (200, json!({}).to_string()),
+        ])
+        .await;
+        let args = Args::parse_from([
+            "jaeger-anomaly-detection",
+            "--opensearch-url",
+            url.join("/").unwrap().as_str(),
+        ]);
+        let to = DateTime::from_timestamp(1_700_000_060, 0).unwrap();
+        let ingest = IngestRecorder::default();
+        let throttle = Throttle::new(test_config());
+        for_traces(
+            &args,
+            &EsClient {
+                client: &reqwest::Client::new(),
+                throttle: &throttle,
+                pit: &PitLease::new(Duration::from_secs(60)),
+                shard: None,
+                indices: &span_indices(&args),
+            },
+            to - TimeDelta::minutes(1),
+            to,
+            &IngestFilter::default(),
+            false,
+            RecordingHandler(&ingest),
+        )
+        .await
+        .unwrap();
+        assert_eq!(server.finished().await.len(), 5);
+
+        let report = ingest.finish(to);
+        assert_eq!(report.traces, 1);
+        assert_eq!(report.spans, 2);
+        assert_eq!(report.spans_per_trace["2"], 1);
+        assert_eq!(report.root_queries.count, 2);
+        assert_eq!(report.span_queries.count, 1);
+        assert_eq!(report.remote_writes.count, 0);
+    }
+
+    /// When OpenSearch rejects the shard script, the root spans are
+    /// fetched again and filtered here.
+    #[tokio::test]
+    async fn for_traces_shard_fallback() {
+        let hits = |hits: Vec<serde_json::Value>| {
+            json!({
+                "pit_id": "pit",
+                "hits": { "total": { "relation": "eq" }, "hits": hits }
+            })
+            .to_string()
+        };
+        let (url, server) = mock_server(vec![
+            (200, json!({ "pit_id": "pit" }).to_string()),
+            (
+                400,
+                json!({
+                    "status": 400,
+                    "error": {
+                        "type": "illegal_argument_exception",
+                        "reason": "cannot execute [inline] scripts"
+                    }
+                })
+                .to_string(),
+            ),
+            (
+                200,
+                hits(vec![
+                    trace_span_doc("0de61f1de7ee678bccb46f3dab804867", "1", None),
+                    trace_span_doc("1de61f1de7ee678bccb46f3dab804867", "1", None),
+                ]),
+            ),
+            (200, hits(vec![span_doc("1", None)])),
+            (200, hits(Vec::new())),
+            (200, json!({}).to_string()),
+        ])
+        .await;
+        let args = Args::parse_from([
+            "jaeger-anomaly-detection",
+            "--opensearch-url",
+            url.join("/").unwrap().as_str(),
+        ]);
+        let to = DateTime::from_timestamp(1_700_000_060, 0).unwrap();
+        let ingest = IngestRecorder::default();
+        let throttle = Throttle::new(test_config());
+        let shard = ShardFilter::new(Shard { index: 0, count: 2 });
+        for_traces(
+            &args,
+            &EsClient {
+                client: &reqwest::Client::new(),
+                throttle: &throttle,
+                pit: &PitLease::new(Duration::from_secs(60)),
+                shard: Some(&shard),
+                indices: &span_indices(&args),
+            },
+            to - TimeDelta::minutes(1),
+            to,
+            &IngestFilter::default(),
+            false,
+            RecordingHandler(&ingest),
+        )
+        .await
+        .unwrap();
+
+        let bodies = server
+            .finished()
+            .await
+            .iter()
+            .map(|request| request.text())
+            .collect::<Vec<_>>();
+        assert_eq!(bodies.len(), 6);
+        assert!(bodies[1].contains("script"));
+        assert!(!bodies[2].contains("script"));
+        assert!(bodies[3].contains("0de61f1de7ee678bccb46f3dab804867"));
+        assert!(!bodies[3].contains("1de61f1de7ee678bccb46f3dab804867"));
+        assert!(!bodies[4].contains("script"));
+        assert_eq!(ingest.finish(to).traces, 1);
+    }
+
+    #[tokio::test]
+    async fn sample_interval_below_query_interval() {
+        let hits = |hits: Vec<serde_json::Value>| {
+            json!({
+                "pit_id": "pit",
+                "hits": { "total": { "relation": "eq" }, "hits": hits }
+            })
+            .to_string()
+        };
+        let (url, server) = mock_server(vec![
+            (200, json!({ "pit_id": "pit" }).to_string()),
+            (200, hits(vec![span_doc("1", None)])),
+            (200, hits(vec![span_doc("1", None)])),
+            (200, hits(Vec::new())),
+            (200, json!({}).to_string()),
+        ])
+        .await;
+        let args = Args::parse_from([
+            "jaeger-anomaly-detection",
+            "--opensearch-url",
+            url.join("/").unwrap().as_str(),
+        ]);
+        let path = std::env::temp_dir().join(format!(
+            "jaeger-anomaly-detection-sample-interval-{}.jsonl",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let sink = MetricsSink::File(Mutex::new(FileSink::new(path.clone(), u64::MAX, 0)));
+
+        // One query of two minutes, sampled every 30 seconds.
+        let config = Config {
+            query_interval: jaeger_anomaly_detection::Duration::Minutes(2),
+            sample_interval: Some(jaeger_anomaly_detection::Duration::Seconds(30)),
+            ..Config::default()
+        };
+        config.validate().unwrap();
+        let from = DateTime::from_timestamp(1_699_999_985, 0).unwrap();
+        let to = from + TimeDelta::minutes(2);
+        let throttle = Throttle::new(test_config());
+        let dropped_series = AtomicU64::new(0);
+        let written_samples = AtomicU64::new(0);
+        let duplicate_samples = AtomicU64::new(0);
+        let mut processor = TraceProcessor::new(&config.trace);
+        let report = process_traces(
+            &args,
+            &config,
+            &EsClient {
+                client: &reqwest::Client::new(),
+                throttle: &throttle,
+                pit: &PitLease::new(Duration::from_secs(60)),
+                shard: None,
+                indices: &span_indices(&args),
+            },
+            Some(&MetricsWriter {
+                sink: &sink,
+                metrics_per_request: args.metrics_per_request,
+                write_concurrency: 1,
+                dropped_series: &dropped_series,
+                written_samples: &written_samples,
+                duplicate_samples: &duplicate_samples,
+                ingest: None,
+                writes: None,
+                shard: None,
+            }),
+            from,
+            to,
+            &mut processor,
+        )
+        .await
+        .unwrap();
+        assert_eq!(server.finished().await.len(), 5);
+
+        // One trace of one span, sampled four times; the heartbeat
+        // adds two samples.
+        let lines = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(report.from, from);
+        assert_eq!(report.to, to);
+        assert_eq!(report.roots, 1);
+        assert_eq!(report.traces, 1);
+        assert_eq!(report.spans, 1);
+        assert_eq!(report.skipped_traces, 0);
+        assert_eq!(report.samples, 4);
+        assert_eq!(report.written, lines.lines().count() as u64);
+        assert_eq!(
+            report.written + duplicate_samples.load(Ordering::Relaxed),
+            report.sampled_series + 2
+        );
+        assert_eq!(report.error, None);
+
+        // The heartbeat is written at the current time.
+        let samples = lines
+            .lines()
+            .filter(|line| !line.contains("instance_id"))
+            .map(|line| {
+                let sample = serde_json::from_str::<serde_json::Value>(line).unwrap();
+                serde_json::from_value::<DateTime<Utc>>(sample["timestamp"].clone()).unwrap()
+            })
+            .collect::<BTreeSet<_>>();
+        let expected = [1_700_000_010, 1_700_000_040, 1_700_000_070, 1_700_000_100]
+            .map(|t| DateTime::from_timestamp(t, 0).unwrap());
+        assert_eq!(samples, BTreeSet::from(expected));
+        assert_eq!(processor.last_sample(), Some(expected[3]));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// A bootstrap replays the history in chunks, filling the
+    /// statistics without writing metrics.
+    #[tokio::test]
+    async fn bootstrap_without_metrics() {
+        let hits = |hits: Vec<serde_json::Value>| {
+            json!({
+                "pit_id": "pit",
+                "hits": { "total": { "relation": "eq" }, "hits": hits }
+            })
+            .to_string()
+        };
+        let chunk = || {
+            [
+                (200, json!({ "pit_id": "pit" }).to_string()),
+                (200, hits(vec![span_doc("1", None)])),
+                (200, hits(vec![span_doc("1", None)])),
+                (200, hits(Vec::new())),
+                (200, json!({}).to_string()),
+            ]
+        };
+        let (url, server) = mock_server(chunk().into_iter().chain(chunk()).collect()).await;
+        let args = Args::parse_from([
+            "jaeger-anomaly-detection",
+            "--opensearch-url",
+            url.join("/").unwrap().as_str(),
+        ]);
+        let config = Config::default();
+        let throttle = Throttle::new(test_config());
+        let live = DateTime::from_timestamp(1_700_000_000, 0).unwrap() + TimeDelta::minutes(30);
+        let mut progress = BootstrapProgress::new(live, TimeDelta::hours(2));
+        let mut processor = TraceProcessor::new(&config.trace);
+        let mut chunks = 0;
+        while bootstrap_chunk(
+            &args,
+            &config,
+            &EsClient {
+                client: &reqwest::Client::new(),
+                throttle: &throttle,
+                pit: &PitLease::new(Duration::from_secs(60)),
+                shard: None,
+                indices: &span_indices(&args),
+            },
+            &mut processor,
+            &mut progress,
+            live,
+            TimeDelta::hours(1),
+        )
+        .await
+        .unwrap()
+        {
+            chunks += 1;
+        }
+        assert_eq!(chunks, 2);
+        assert_eq!(server.finished().await.len(), 10);
+        assert_eq!(progress.done, live);
+        assert_eq!(progress.status(true, None).progress, 1.0);
+
+        // Nothing was sampled, but the replayed spans are in the
+        // statistics.
+        assert_eq!(processor.last_sample(), None);
+        let count = |processor: &TraceProcessor| {
+            let mut n = 0;
+            processor.snapshot(live).sample(|_, _, _| n += 1);
+            n
+        };
+        assert!(count(&processor) > count(&TraceProcessor::new(&config.trace)));
+    }
+
+    /// OpenSearch comes up after the processor: the processor waits
+    /// for it, and the first range is capped to max_history despite
+    /// the ancient state.
+    #[tokio::test]
+    async fn late_opensearch_start() {
+        let (url, server) = mock_server(vec![
+            (503, json!({ "error": "starting" }).to_string()),
+            (
+                200,
+                json!({ "cluster_name": "traces", "version": { "number": "2.11.0" } }).to_string(),
+            ),
+            (200, json!({ "pit_id": "pit" }).to_string()),
+            (
+                200,
+                json!({
+                    "pit_id": "pit",
+                    "hits": { "total": { "relation": "eq" }, "hits": [] }
+                })
+                .to_string(),
+            ),
+            (200, json!({}).to_string()),
+        ])
+        .await;
+
+        let dir = std::env::temp_dir().join(format!(
+            "jaeger-anomaly-detection-startup-{}",
+            std::process::id()
+        ));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+        let client_key = KeyPair::generate().unwrap();
+        let client_cert = CertificateParams::new(Vec::<String>::new())
+            .unwrap()
+            .signed_by(&client_key, &ca, &ca_key)
+            .unwrap();
+        for (name, pem) in [
+            ("ca.crt", ca.pem()),
+            ("tls.crt", client_cert.pem()),
+            ("tls.key", client_key.serialize_pem()),
+        ] {
+            tokio::fs::write(dir.join(name), pem).await.unwrap();
+        }
+
+        let config = Config::default();
+        let mut state = Vec::new();
+        ciborium::into_writer(
+            &State {
+                config: config.clone(),
+                generation: 0,
+                state: TraceProcessor::new(&config.trace).save(),
+                last: DateTime::from_timestamp(1_600_000_000, 0).unwrap(),
+                bootstrap: None,
+                shard: None,
+            },
+            &mut state,
+        )
+        .unwrap();
+        tokio::fs::write(dir.join("state.cbor"), state)
+            .await
+            .unwrap();
+
+        let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+        let args = Args::parse_from([
+            String::from("jaeger-anomaly-detection"),
+            String::from("--opensearch-url"),
+            url.join("/").unwrap().to_string(),
+            String::from("--opensearch-ca"),
+            path("ca.crt"),
+            String::from("--opensearch-cert"),
+            path("tls.crt"),
+            String::from("--opensearch-key"),
+            path("tls.key"),
+            String::from("--state"),
+            path("state.cbor"),
+            String::from("--metrics-sink"),
+            String::from("null"),
+        ]);
+
+        let start = Utc::now();
+        let processor = Processor::new(&args).await.unwrap();
+        assert!(processor.starting());
+        processor.started().await.unwrap();
+        assert!(!processor.starting());
+
+        let requests = server.finished().await;
+        assert_eq!(requests.len(), 5);
+        let search = serde_json::from_slice::<serde_json::Value>(&requests[3].body).unwrap();
+        let from = search
+            .pointer("/query/bool/must/0/range/startTime/gte")
+            .and_then(|from| from.as_i64())
+            .unwrap();
+        assert!(from >= (start - config.max_history.to_time_delta()).timestamp_micros());
+
+        processor.shutdown().await.unwrap();
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn process_traces_without_prometheus() {
+        let hits = |hits: Vec<serde_json::Value>| {
+            json!({
+                "pit_id": "pit",
+                "hits": { "total": { "relation": "eq" }, "hits": hits }
+            })
+            .to_string()
+        };
+        let (url, server) = mock_server(vec![
+            (200, json!({ "pit_id": "pit" }).to_string()),
+            (200, hits(vec![span_doc("1", None)])),
+            (200, hits(vec![span_doc("1", None)])),
+            (200, hits(Vec::new())),
+            (200, json!({}).to_string()),
+        ])
+        .await;
+        let args = Args::parse_from([
+            "jaeger-anomaly-detection",
+            "--opensearch-url",
+            url.join("/").unwrap().as_str(),
+            "--prometheus-url",
+            "http://127.0.0.1:1/api/v1/push",
+            "--metrics-sink",
+            "null",
+        ]);
+        let config = Config::default();
+        let to = DateTime::from_timestamp(1_700_000_060, 0).unwrap();
+        let throttle = Throttle::new(test_config());
+        let dropped_series = AtomicU64::new(0);
+        let written_samples = AtomicU64::new(0);
+        let duplicate_samples = AtomicU64::new(0);
+        let mut processor = TraceProcessor::new(&config.trace);
+        process_traces(
+            &args,
+            &config,
+            &EsClient {
+                client: &reqwest::Client::new(),
+                throttle: &throttle,
+                pit: &PitLease::new(Duration::from_secs(60)),
+                shard: None,
+                indices: &span_indices(&args),
+            },
+            Some(&MetricsWriter {
+                sink: &MetricsSink::Null,
+                metrics_per_request: args.metrics_per_request,
+                write_concurrency: 1,
+                dropped_series: &dropped_series,
+                written_samples: &written_samples,
+                duplicate_samples: &duplicate_samples,
+                ingest: None,
+                writes: None,
+                shard: None,
+            }),
+            to - TimeDelta::minutes(1),
+            to,
+            &mut processor,
+        )
+        .await
+        .unwrap();
+        assert_eq!(server.finished().await.len(), 5);
+        assert_eq!(dropped_series.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn heartbeat_in_empty_tick() {
+        let (url, server) = mock_server(vec![
+            (200, json!({ "pit_id": "pit" }).to_string()),
+            (
+                200,
+                json!({
+                    "pit_id": "pit",
+                    "hits": { "total": { "relation": "eq" }, "hits": [] }
+                })
+                .to_string(),
+            ),
+            (200, json!({}).to_string()),
+        ])
+        .await;
+        let args = Args::parse_from([
+            "jaeger-anomaly-detection",
+            "--opensearch-url",
+            url.join("/").unwrap().as_str(),
+            "--instance-id",
+            "engine-0",
+        ]);
+        let path = std::env::temp_dir().join(format!(
+            "jaeger-anomaly-detection-heartbeat-{}.jsonl",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let sink = MetricsSink::File(Mutex::new(FileSink::new(path.clone(), u64::MAX, 0)));
+
+        let config = Config::default();
+        let to = DateTime::from_timestamp(1_700_000_060, 0).unwrap();
+        let throttle = Throttle::new(test_config());
+        let dropped_series = AtomicU64::new(0);
+        let written_samples = AtomicU64::new(0);
+        let duplicate_samples = AtomicU64::new(0);
+        let mut processor = TraceProcessor::new(&config.trace);
+        process_traces(
+            &args,
+            &config,
+            &EsClient {
+                client: &reqwest::Client::new(),
+                throttle: &throttle,
+                pit: &PitLease::new(Duration::from_secs(60)),
+                shard: None,
+                indices: &span_indices(&args),
+            },
+            Some(&MetricsWriter {
+                sink: &sink,
+                metrics_per_request: args.metrics_per_request,
+                write_concurrency: 1,
+                dropped_series: &dropped_series,
+                written_samples: &written_samples,
+                duplicate_samples: &duplicate_samples,
+                ingest: None,
+                writes: None,
+                shard: None,
+            }),
+            to - TimeDelta::minutes(1),
+            to,
+            &mut processor,
+        )
+        .await
+        .unwrap();
+        assert_eq!(server.finished().await.len(), 3);
+
+        // Read the written samples back into the request the
+        // remote-write sink would have sent.
+        let mut metrics = Metrics::new();
+        std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .for_each(|line| {
+                let sample = serde_json::from_str::<serde_json::Value>(line).unwrap();
+                metrics.insert(
+                    serde_json::from_value(sample["labels"].clone()).unwrap(),
+                    serde_json::from_value(sample["timestamp"].clone()).unwrap(),
+                    sample["value"].as_f64().unwrap_or(f64::NAN),
+                );
+            });
+        std::fs::remove_file(&path).unwrap();
+        let request = metrics.write_request();
+        let series = |name: &str| {
+            request
+                .timeseries
+                .iter()
+                .find(|series| {
+                    series
+                        .labels
+                        .iter()
+                        .any(|label| label.name == "__name__" && label.value == name)
+                })
+                .unwrap_or_else(|| panic!("missing series {name}"))
+        };
+
+        let heartbeat = series("jaeger_anomaly_detection_heartbeat");
+        assert!(heartbeat
+            .labels
+            .iter()
+            .any(|label| label.name == "instance_id" && label.value == "engine-0"));
+        assert_eq!(
+            heartbeat.samples[0].value,
+            heartbeat.samples[0].timestamp as f64 / 1000.0
+        );
+        let last_processed = series("jaeger_anomaly_detection_last_processed_timestamp_seconds");
+        assert_eq!(last_processed.samples[0].value, 1_700_000_060.0);
+    }
+
+    fn circuit_breaker() -> String {
+        json!({
+            "status": 429,
+            "error": {
+                "type": "circuit_breaking_exception",
+                "reason": "[parent] Data too large"
+            }
+        })
+        .to_string()
+    }
+
+    #[tokio::test]
+    async fn throttled_search_retries_circuit_breaker() {
+        let (url, server) = mock_server(vec![
+            (429, circuit_breaker()),
+            (429, String::from("Too Many Requests")),
+            (200, json!({ "hits": [] }).to_string()),
+        ])
+        .await;
+        let throttle = Throttle::new(test_config());
+        let res = throttled_search::<serde_json::Value>(
+            &throttle,
+            reqwest::Client::new().post(url).json(&json!({})),
+        )
+        .await
+        .unwrap();
+        assert_eq!(res, json!({ "hits": [] }));
+        assert_eq!(server.finished().await.len(), 3);
+
+        // Shrunk twice, then grown by one.
+        let limits = throttle.limits();
+        assert_eq!(limits.chunk_size, 13);
+        assert_eq!(limits.pressure_events, 2);
+        assert_eq!(limits.retries, 2);
+        assert_eq!(limits.delay_ms, 100);
+    }
+
+    #[tokio::test]
+    async fn throttled_search_gives_up_after_retries() {
+        let (url, server) = mock_server(vec![
+            (429, circuit_breaker()),
+            (429, circuit_breaker()),
+            (429, circuit_breaker()),
+        ])
+        .await;
+        let throttle = Throttle::new(test_config());
+        let res = throttled_search::<serde_json::Value>(
+            &throttle,
+            reqwest::Client::new().post(url).json(&json!({})),
+        )
+        .await;
+        assert!(matches!(res, Err(Error::ElasticErr(e)) if e.is_circuit_breaker()));
+        assert_eq!(server.finished().await.len(), 3);
+        assert_eq!(throttle.limits().chunk_size, 6);
+
+        // Other errors are not retried.
+        let (url, server) = mock_server(vec![(400, "bad request")]).await;
+        let res = throttled_search::<serde_json::Value>(
+            &throttle,
+            reqwest::Client::new().post(url).json(&json!({})),
+        )
+        .await;
+        assert!(matches!(res, Err(Error::ElasticStatus(status, _)) if status == 400));
+        assert_eq!(server.finished().await.len(), 1);
+        assert_eq!(throttle.chunk_size(), 7);
+    }
+
+    #[tokio::test]
+    async fn debug_trace_report() {
+        let hits = |hits: Vec<serde_json::Value>| {
+            json!({ "hits": { "total": { "relation": "eq" }, "hits": hits } }).to_string()
+        };
+        let (url, server) = mock_server(vec![
+            (
+                200,
+                hits(vec![
+                    span_doc("1", None),
+                    span_doc("2", Some("1")),
+                    json!({ "_source": { "spanID": "3" } }),
+                ]),
+            ),
+            (200, hits(Vec::new())),
+        ])
+        .await;
+        let args = Args::parse_from([
+            "jaeger-anomaly-detection",
+            "--opensearch-url",
+            url.join("/").unwrap().as_str(),
+        ]);
+        let client = reqwest::Client::new();
+        let trace_id = "0de61f1de7ee678bccb46f3dab804867";
+
+        let (spans, failed) =
+            parse_spans(fetch_trace(&args, &client, trace_id, false).await.unwrap());
+        assert_eq!(spans.len(), 2);
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].span_id, "3");
+        assert!(matches!(failed[0].skipped, Some(SkipReason::Parse(_))));
+
+        let proc = TraceProcessor::new(&TraceConfig::default());
+        let report = proc.debug_trace(&spans, &IngestFilter::default());
+        assert_eq!(report.len(), 2);
+
+        let root = &report[0];
+        assert_eq!(root.span_id, "1");
+        assert_eq!(root.service_name.as_deref(), Some("frontend"));
+        assert_eq!(root.skipped, None);
+        assert_eq!(root.matches.len(), 1);
+        let default = &root.matches[0];
+        assert_eq!(default.rule_group, 0);
+        assert_eq!(default.config, ConfigName::new("default"));
+        assert_eq!(default.select, "all()");
+        assert_eq!(default.key["service_name"], "frontend");
+        assert_eq!(default.key["operation_name"], "GET");
+        assert_eq!(default.missing_keys, ["service_namespace"]);
+        // The child covers the whole root span.
+        assert_eq!(default.values[&MetricName::new("duration")], [0.0]);
+        assert!(default.values[&MetricName::new("busy")].is_empty());
+        assert_eq!(default.skipped, None);
+
+        let child = &report[1];
+        assert_eq!(
+            child
+                .matches
+                .iter()
+                .map(|m| (m.rule_group, m.config.to_string()))
+                .collect::<Vec<_>>(),
+            [
+                (0, String::from("default")),
+                (1, String::from("operation-relations"))
+            ]
+        );
+        assert_eq!(child.matches[1].key["parent_service_name"], "frontend");
+
+        let filter = IngestFilter {
+            exclude_services: vec![ValueMatch::Eq(String::from("frontend"))],
+            ..IngestFilter::default()
+        };
+        let report = proc.debug_trace(&spans, &filter);
+        assert!(report
+            .iter()
+            .all(|span| span.skipped == Some(SkipReason::IngestFilter) && span.matches.is_empty()));
+
+        // An unknown trace yields no documents.
+        assert!(fetch_trace(&args, &client, "unknown", false)
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(server.finished().await.len(), 2);
+    }
+
+    #[test]
+    fn empty_ingest_filter_query() {
+        assert_eq!(ingest_filter_query(&IngestFilter::default()), None);
+    }
+
+    #[test]
+    fn ingest_filter_query_with_names_and_regexes() {
+        let filter = IngestFilter {
+            include_services: vec![
+                ValueMatch::Eq(String::from("frontend")),
+                ValueMatch::Match(AnchoredRegex::new("cart-.*").unwrap()),
+            ],
+            exclude_services: vec![ValueMatch::Match(AnchoredRegex::new(".*-test").unwrap())],
+            include_namespaces: vec![ValueMatch::Eq(String::from("continuousc"))],
+            exclude_namespaces: Vec::new(),
+        };
+        assert_eq!(
+            ingest_filter_query(&filter),
+            Some(json!({
+                "bool": {
+                    "filter": [
+                        {
+                            "bool": {
+                                "should": [
+                                    { "term": { "process.serviceName": "frontend" } },
+                                    { "regexp": { "process.serviceName": "cart-.*" } }
+                                ],
+                                "minimum_should_match": 1
+                            }
+                        },
+                        {
+                            "nested": {
+                                "path": "process.tags",
+                                "query": {
+                                    "bool": {
+                                        "must": [
+                                            { "term": { "process.tags.key": "service.namespace" } },
+                                            {
+                                                "bool": {
+                                                    "should": [
+                                                        { "term": { "process.tags.value": "continuousc" } }
+                                                    ],
+                                                    "minimum_should_match": 1
+                                                }
+                                            }
+                                        ]
+                                    }
+                                }
+                            }
+                        }
+                    ],
+                    "must_not": [
+                        {
+                            "bool": {
+                                "should": [
+                                    { "regexp": { "process.serviceName": ".*-test" } }
+                                ],
+                                "minimum_should_match": 1
+                            }
+                        }
+                    ]
+                }
+            }))
+        );
+    }
+
+    /// A histogram of the span durations per service, sampled every
+    /// 10 seconds, without the other statistics.
+    fn histogram_config() -> Config {
+        Config {
+            trace: trace_config(
+                SpanSelector::All(Vec::new()),
+                MetricSource::Duration,
+                StatsConfig {
+                    anomaly_score: None,
+                    mean_stddev: None,
+                    summary: None,
+                    histogram: Some(HistogramConfig {
+                        bounds: vec![1000.0, 2000.0, 5000.0],
+                    }),
+                    ..StatsConfig::default()
+                },
+            ),
+            sample_interval: Some(jaeger_anomaly_detection::Duration::Seconds(10)),
+            ..Config::default()
+        }
+    }
+
+    /// Run a tick over `[from, to)`, fetching the traces from the fake
+    /// OpenSearch and writing to the fake remote-write receiver.
+    async fn fake_tick(
+        opensearch: &FakeOpenSearch,
+        remote_write: &FakeRemoteWrite,
+        config: &Config,
+        processor: &mut TraceProcessor,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<TickReport> {
+        let args = Args::parse_from([
+            "jaeger-anomaly-detection",
+            "--opensearch-url",
+            opensearch.url().as_str(),
+        ]);
+        fake_tick_with(&args, remote_write, config, processor, from, to).await
+    }
+
+    /// Run a tick as `fake_tick`, with the command line `args`.
+    async fn fake_tick_with(
+        args: &Args,
+        remote_write: &FakeRemoteWrite,
+        config: &Config,
+        processor: &mut TraceProcessor,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<TickReport> {
+        let throttle = Throttle::new(test_config());
+        let sink = MetricsSink::RemoteWrite {
+            client: reqwest::Client::new(),
+            url: remote_write.url().clone(),
+        };
+        let dropped_series = AtomicU64::new(0);
+        let written_samples = AtomicU64::new(0);
+        let duplicate_samples = AtomicU64::new(0);
+        process_traces(
+            args,
+            config,
+            &EsClient {
+                client: &reqwest::Client::new(),
+                throttle: &throttle,
+                pit: &PitLease::new(Duration::from_secs(60)),
+                shard: None,
+                indices: &span_indices(args),
+            },
+            Some(&MetricsWriter {
+                sink: &sink,
+                metrics_per_request: args.metrics_per_request,
+                write_concurrency: 1,
+                dropped_series: &dropped_series,
+                written_samples: &written_samples,
+                duplicate_samples: &duplicate_samples,
+                ingest: None,
+                writes: None,
+                shard: None,
+            }),
+            from,
+            to,
+            processor,
+        )
+        .await
+    }
+
+    /// The written samples, leaving out the self-monitoring series
+    /// (which include the heartbeat, written at the current time).
+    fn written_lines(metrics: &Metrics) -> Vec<String> {
+        let mut lines = metrics
+            .to_debug_lines()
+            .into_iter()
+            .filter(|line| !line.contains("metric_type=\"self_monitoring\""))
+            .collect::<Vec<_>>();
+        lines.dedup();
+        lines
+    }
+
+    /// The span fixtures go through OpenSearch queries, processing and
+    /// remote-write, ending up as the histograms of the golden file.
+    #[tokio::test]
+    async fn fake_backends_golden_histograms() {
+        let docs = load_fixture("traces.json").as_array().unwrap().clone();
+        let opensearch = FakeOpenSearch::start(docs).await;
+        let remote_write = FakeRemoteWrite::start().await;
+        let config = histogram_config();
+        let mut processor = TraceProcessor::new(&config.trace);
+        let report = fake_tick(
+            &opensearch,
+            &remote_write,
+            &config,
+            &mut processor,
+            start(),
+            start() + TimeDelta::seconds(11),
+        )
+        .await
+        .unwrap();
+
+        // The traces before and after the interval are not fetched.
+        assert_eq!(report.roots, 6);
+        assert_eq!(report.traces, 6);
+        assert_eq!(report.spans, 13);
+        assert_eq!(report.samples, 1);
+        assert_golden("histograms.txt", &written_lines(&remote_write.metrics()));
+
+        // A single point in time, deleted at the end.
+        let requests = opensearch.requests();
+        assert!(requests[0].path.ends_with("/_search/point_in_time"));
+        assert_eq!(requests.last().unwrap().method, "DELETE");
+        assert_eq!(opensearch.open_pits(), 0);
+    }
+
+    /// The pushdown aggregations, which the fake rejects, skip their
+    /// config for the interval without failing the tick.
+    #[tokio::test]
+    async fn failed_pushdown_keeps_tick() {
+        let docs = load_fixture("traces.json").as_array().unwrap().clone();
+        let opensearch = FakeOpenSearch::start(docs).await;
+        let remote_write = FakeRemoteWrite::start().await;
+        let mut config = Config {
+            trace: trace_config(
+                SpanSelector::All(Vec::new()),
+                MetricSource::Duration,
+                StatsConfig {
+                    anomaly_score: None,
+                    mean_stddev: Some(MeanStddevConfig {
+                        algorithm: MeanStddevAlgorithm::Welford,
+                    }),
+                    summary: None,
+                    histogram: None,
+                    ..StatsConfig::default()
+                },
+            ),
+            sample_interval: Some(jaeger_anomaly_detection::Duration::Seconds(10)),
+            ..Config::default()
+        };
+        config
+            .trace
+            .configs
+            .values_mut()
+            .for_each(|config| config.pushdown = true);
+        let mut processor = TraceProcessor::new(&config.trace);
+        assert_eq!(processor.pushdown_queries().len(), 1);
+
+        let report = fake_tick(
+            &opensearch,
+            &remote_write,
+            &config,
+            &mut processor,
+            start(),
+            start() + TimeDelta::seconds(11),
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.traces, 6);
+        assert!(opensearch
+            .requests()
+            .iter()
+            .any(|request| request.body.get("aggs").is_some()));
+        assert_eq!(opensearch.open_pits(), 0);
+    }
+
+    /// The index pattern of the archive in the tests.
+    const ARCHIVE: &str = "archive-jaeger-span-*";
+
+    /// The span fixtures, with the even traces moved to an archive
+    /// index not matched by the primary pattern.
+    async fn fake_archive() -> FakeOpenSearch {
+        let (archived, current): (Vec<_>, Vec<_>) = load_fixture("traces.json")
+            .as_array()
+            .unwrap()
+            .iter()
+            .cloned()
+            .partition(|doc| {
+                doc["traceID"]
+                    .as_str()
+                    .unwrap()
+                    .ends_with(|c: char| c.to_digit(16).is_some_and(|d| d % 2 == 0))
+            });
+        let opensearch = FakeOpenSearch::start(current).await;
+        opensearch.add_index("archive-jaeger-span-2023.11.14", archived);
+        opensearch
+    }
+
+    fn archive_args(opensearch: &FakeOpenSearch) -> Args {
+        Args::parse_from([
+            "jaeger-anomaly-detection",
+            "--opensearch-url",
+            opensearch.url().as_str(),
+            "--opensearch-archive-index",
+            ARCHIVE,
+        ])
+    }
+
+    /// The start times of the fixture root spans in `docs`, in order.
+    fn root_times<'a>(docs: impl IntoIterator<Item = &'a serde_json::Value>) -> Vec<i64> {
+        let mut times = docs
+            .into_iter()
+            .filter(|doc| doc["references"].as_array().map_or(true, Vec::is_empty))
+            .map(|doc| doc["startTime"].as_i64().unwrap())
+            .collect::<Vec<_>>();
+        times.sort();
+        times
+    }
+
+    /// Collects the start times of the handled root spans.
+    struct RootTimes<'a>(&'a mut Vec<i64>);
+
+    impl TraceHandler for RootTimes<'_> {
+        async fn handle(&mut self, traces: &[(&Span, &[Span])]) -> Result<()> {
+            self.0
+                .extend(traces.iter().map(|(root, _)| root.start_time));
+            Ok(())
+        }
+
+        fn ingest_stats(&self) -> Option<&IngestRecorder> {
+            None
+        }
+
+        fn tag_allowlist(&self) -> Option<&TagAllowlist> {
+            None
+        }
+
+        fn tick_report(&mut self) -> Option<&mut TickReport> {
+            None
+        }
+    }
+
+    /// Ranges older than the archive cutoff are fetched from both
+    /// patterns through a single point in time, with the same result
+    /// as from a single index.
+    #[tokio::test]
+    async fn fake_backends_merge_archive() {
+        let opensearch = fake_archive().await;
+        let remote_write = FakeRemoteWrite::start().await;
+        let config = histogram_config();
+        let mut processor = TraceProcessor::new(&config.trace);
+        let report = fake_tick_with(
+            &archive_args(&opensearch),
+            &remote_write,
+            &config,
+            &mut processor,
+            start(),
+            start() + TimeDelta::seconds(11),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(report.index_patterns, vec![ARCHIVE, INDEX]);
+        assert_eq!(report.roots, 6);
+        assert_eq!(report.traces, 6);
+        assert_eq!(report.spans, 13);
+        assert_golden("histograms.txt", &written_lines(&remote_write.metrics()));
+        assert_eq!(
+            opensearch.requests()[0].path,
+            format!("/{ARCHIVE},{INDEX}/_search/point_in_time")
+        );
+        assert_eq!(opensearch.open_pits(), 0);
+
+        // Recent ranges only query the primary pattern.
+        let mut processor = TraceProcessor::new(&config.trace);
+        let now = Utc::now();
+        let report = fake_tick_with(
+            &archive_args(&opensearch),
+            &remote_write,
+            &config,
+            &mut processor,
+            now - TimeDelta::minutes(1),
+            now,
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.index_patterns, vec![INDEX]);
+        assert_eq!(report.roots, 0);
+    }
+
+    /// The merged root spans are in start time order. When OpenSearch
+    /// rejects the combined point in time, the patterns are fetched
+    /// one by one, each in order, oldest pattern first.
+    #[tokio::test]
+    async fn for_traces_archive_order() {
+        let fixtures = load_fixture("traces.json");
+        let fixtures = fixtures.as_array().unwrap();
+        let (from, to) = (
+            start() - TimeDelta::minutes(1),
+            start() + TimeDelta::minutes(1),
+        );
+        let throttle = Throttle::new(test_config());
+        let fetch = |opensearch: &FakeOpenSearch| {
+            let args = archive_args(opensearch);
+            let throttle = &throttle;
+            async move {
+                let indices = span_indices(&args);
+                let mut roots = Vec::new();
+                for_traces(
+                    &args,
+                    &EsClient {
+                        client: &reqwest::Client::new(),
+                        throttle,
+                        pit: &PitLease::new(Duration::from_secs(60)),
+                        shard: None,
+                        indices: &indices,
+                    },
+                    from,
+                    to,
+                    &IngestFilter::default(),
+                    false,
+                    RootTimes(&mut roots),
+                )
+                .await
+                .unwrap();
+                (roots, indices.combined_pit())
+            }
+        };
+
+        let opensearch = fake_archive().await;
+        let (roots, combined) = fetch(&opensearch).await;
+        assert_eq!(roots, root_times(fixtures));
+        assert!(combined);
+        assert_eq!(opensearch.open_pits(), 0);
+
+        let opensearch = fake_archive().await;
+        opensearch.reject_multi_index_pits();
+        let (roots, combined) = fetch(&opensearch).await;
+        let (archived, current): (Vec<_>, Vec<_>) = fixtures.iter().partition(|doc| {
+            doc["traceID"]
+                .as_str()
+                .unwrap()
+                .ends_with(|c: char| c.to_digit(16).is_some_and(|d| d % 2 == 0))
+        });
+        assert_eq!(roots, [root_times(archived), root_times(current)].concat());
+        assert!(!combined);
+        assert_eq!(
+            opensearch
+                .requests()
+                .iter()
+                .filter(|request| {
+                    request.method == "POST" && request.path.ends_with("/_search/point_in_time")
+                })
+                .map(|request| request.path.as_str())
+                .collect::<Vec<_>>(),
+            vec![
+                format!("/{ARCHIVE},{INDEX}/_search/point_in_time"),
+                format!("/{ARCHIVE}/_search/point_in_time"),
+                format!("/{INDEX}/_search/point_in_time"),
+            ]
+        );
+        assert_eq!(opensearch.open_pits(), 0);
+    }
+
+    /// Collects the start times and span counts of the handled traces.
+    struct TraceSizes<'a>(&'a mut Vec<(i64, usize)>);
+
+    impl TraceHandler for TraceSizes<'_> {
+        async fn handle(&mut self, traces: &[(&Span, &[Span])]) -> Result<()> {
+            self.0.extend(
+                traces
+                    .iter()
+                    .map(|(root, spans)| (root.start_time, spans.len())),
+            );
+            Ok(())
+        }
+
+        fn ingest_stats(&self) -> Option<&IngestRecorder> {
+            None
+        }
+
+        fn tag_allowlist(&self) -> Option<&TagAllowlist> {
+            None
+        }
+
+        fn tick_report(&mut self) -> Option<&mut TickReport> {
+            None
+        }
+    }
+
+    /// Traces found in both patterns, e.g. while the lifecycle policy
+    /// moves them, are handled once, with each span once.
+    #[tokio::test]
+    async fn for_traces_archive_duplicates() {
+        let fixtures = load_fixture("traces.json");
+        let fixtures = fixtures.as_array().unwrap();
+        let mut expected = fixtures
+            .iter()
+            .fold(BTreeMap::<_, Vec<_>>::new(), |mut traces, doc| {
+                traces
+                    .entry(doc["traceID"].as_str().unwrap())
+                    .or_default()
+                    .push(doc);
+                traces
+            })
+            .into_values()
+            .map(|docs| (root_times(docs.iter().copied())[0], docs.len()))
+            .collect::<Vec<_>>();
+        expected.sort();
+        let (from, to) = (
+            start() - TimeDelta::minutes(1),
+            start() + TimeDelta::minutes(1),
+        );
+        let throttle = Throttle::new(test_config());
+        let fetch = |opensearch: &FakeOpenSearch| {
+            let args = archive_args(opensearch);
+            let throttle = &throttle;
+            async move {
+                let indices = span_indices(&args);
+                let mut traces = Vec::new();
+                for_traces(
+                    &args,
+                    &EsClient {
+                        client: &reqwest::Client::new(),
+                        throttle,
+                        pit: &PitLease::new(Duration::from_secs(60)),
+                        shard: None,
+                        indices: &indices,
+                    },
+                    from,
+                    to,
+                    &IngestFilter::default(),
+                    false,
+                    TraceSizes(&mut traces),
+                )
+                .await
+                .unwrap();
+                traces.sort();
+                traces
+            }
+        };
+        let duplicated = || async {
+            let opensearch = FakeOpenSearch::start(fixtures.clone()).await;
+            opensearch.add_index("archive-jaeger-span-2023.11.14", fixtures.clone());
+            opensearch
+        };
+
+        let opensearch = duplicated().await;
+        assert_eq!(fetch(&opensearch).await, expected);
+
+        let opensearch = duplicated().await;
+        opensearch.reject_multi_index_pits();
+        assert_eq!(fetch(&opensearch).await, expected);
+        assert_eq!(opensearch.open_pits(), 0);
+    }
+
+    /// Over more traces than fit in a page of root spans, the written
+    /// samples are those of the simulation.
+    #[tokio::test]
+    async fn fake_backends_match_simulation() {
+        // Recent enough for every sample to be written.
+        let now = Utc::now();
+        let from = DateTime::from_timestamp(now.timestamp() - now.timestamp() % 10, 0).unwrap()
+            - TimeDelta::minutes(10);
+        let to = from + TimeDelta::seconds(121);
+        let docs = synthetic_docs(from, 1200);
+        let opensearch = FakeOpenSearch::start(docs.iter().flatten().cloned().collect()).await;
+        let remote_write = FakeRemoteWrite::start().await;
+        let config = histogram_config();
+        let mut processor = TraceProcessor::new(&config.trace);
+        let report = fake_tick(
+            &opensearch,
+            &remote_write,
+            &config,
+            &mut processor,
+            from,
+            to,
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.traces, 1200);
+        assert_eq!(report.spans, 3600);
+        assert_eq!(report.samples, 12);
+
+        let mut sim = PipelineSim::new(
+            &config.trace,
+            from,
+            TimeDelta::seconds(10),
+            fixtures(parse_traces(&docs.concat())),
+        );
+        sim.tick(to);
+        let mut expected = Metrics::new();
+        sim.samples()
+            .iter()
+            .for_each(|(labels, t, value)| expected.insert(labels.clone(), *t, *value));
+        let written = written_lines(&remote_write.metrics());
+        assert!(!written.is_empty());
+        assert_eq!(written, written_lines(&expected));
+
+        // Two pages of root spans, then an empty one; the spans are
+        // fetched in chunks of 50 traces.
+        let requests = opensearch.requests();
+        let roots = requests
+            .iter()
+            .filter(|req| req.body.pointer("/query/bool").is_some())
+            .count();
+        let chunks = requests
+            .iter()
+            .filter(|req| req.body.pointer("/query/terms/traceID").is_some())
+            .count();
+        assert_eq!((roots, chunks), (3, 24));
+        assert_eq!(opensearch.open_pits(), 0);
+    }
+
+    /// Overloaded searches are retried, and a failed tick still deletes
+    /// its point in time.
+    #[tokio::test]
+    async fn fake_backends_errors() {
+        let docs = load_fixture("traces.json").as_array().unwrap().clone();
+        let opensearch = FakeOpenSearch::start(docs).await;
+        let remote_write = FakeRemoteWrite::start().await;
+        let config = histogram_config();
+        let to = start() + TimeDelta::seconds(11);
+
+        let overloaded = json!({
+            "status": 429,
+            "error": { "type": "es_rejected_execution_exception", "reason": "queue is full" }
+        });
+        opensearch.fail_next(429, overloaded);
+        let mut processor = TraceProcessor::new(&config.trace);
+        let report = fake_tick(
+            &opensearch,
+            &remote_write,
+            &config,
+            &mut processor,
+            start(),
+            to,
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.traces, 6);
+        let requests = opensearch.requests();
+        assert_eq!(requests[1].body, requests[2].body);
+        assert_eq!(opensearch.open_pits(), 0);
+
+        opensearch.fail_next(
+            500,
+            json!({
+                "status": 500,
+                "error": { "type": "exception", "reason": "shard failure" }
+            }),
+        );
+        let mut processor = TraceProcessor::new(&config.trace);
+        assert!(matches!(
+            fake_tick(
+                &opensearch,
+                &remote_write,
+                &config,
+                &mut processor,
+                start(),
+                to
+            )
+            .await,
+            Err(Error::ElasticErr(_))
+        ));
+        assert_eq!(opensearch.requests().last().unwrap().method, "DELETE");
+        assert_eq!(opensearch.open_pits(), 0);
+    }
+}
@@ -0,0 +1,128 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use std::{collections::BTreeSet, path::Path, sync::Arc};
+
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::{
+    config::SpanKey,
+    error::{Error, Result},
+    jaeger::TagValue,
+    metrics::label_value,
+};
+
+/// A key whose values are replaced by a keyed hash before grouping.
+#[derive(Serialize, Deserialize, schemars::JsonSchema, PartialEq, Clone, Debug)]
+pub struct Pseudonymize {
+    pub key: SpanKey,
+    /// Values kept in clear, e.g. well-known operation names.
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub keep: BTreeSet<String>,
+}
+
+/// The secret for the pseudonyms, read from
+/// `--pseudonymization-key-file`.
+#[derive(Clone)]
+pub struct PseudonymizationKey(Arc<[u8]>);
+
+/// Stands in for all pseudonymized values when no key is loaded. The
+/// processor refuses such configs, but values must never fall back to
+/// clear text.
+const NO_KEY: &str = "p-unavailable";
+
+impl PseudonymizationKey {
+    pub fn new(key: &[u8]) -> Self {
+        Self(Arc::from(key))
+    }
+
+    /// Read the key from a file. Trailing whitespace is not part of
+    /// the key.
+    pub async fn load(path: &Path) -> Result<Self> {
+        let data = tokio::fs::read(path)
+            .await
+            .map_err(|e| Error::ReadFile(path.to_path_buf(), e))?;
+        match data.trim_ascii_end() {
+            [] => Err(Error::EmptyPseudonymizationKey(path.to_path_buf())),
+            key => Ok(Self::new(key)),
+        }
+    }
+
+    /// The pseudonym for a value: the HMAC-SHA256 of the value,
+    /// truncated to 64 bits. The prefix keeps pseudonyms from being
+    /// taken for numbers.
+    pub fn pseudonym(&self, value: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.0).expect("any key length is valid");
+        mac.update(value.as_bytes());
+        let hash = mac.finalize().into_bytes();
+        let hex = hash[..8]
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect::<String>();
+        format!("p-{hex}")
+    }
+}
+
+impl std::fmt::Debug for PseudonymizationKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("PseudonymizationKey(..)")
+    }
+}
+
+impl Pseudonymize {
+    /// The value to use in place of `value`.
+    pub fn apply(&self, secret: Option<&PseudonymizationKey>, value: TagValue) -> TagValue {
+        let value = label_value(&value);
+        if self.keep.contains(&value) {
+            return TagValue::String(value);
+        }
+        TagValue::String(secret.map_or_else(|| NO_KEY.to_string(), |key| key.pseudonym(&value)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeSet;
+
+    use crate::{
+        config::{KeyName, SpanKey},
+        jaeger::TagValue,
+    };
+
+    use super::{PseudonymizationKey, Pseudonymize};
+
+    #[test]
+    fn pseudonyms() {
+        let key = PseudonymizationKey::new(b"secret");
+        let pseudonym = key.pseudonym("GET /customers/1234");
+        assert_eq!(pseudonym, key.pseudonym("GET /customers/1234"));
+        assert_eq!(pseudonym.len(), 18);
+        assert!(pseudonym.starts_with("p-"));
+        assert_ne!(pseudonym, key.pseudonym("GET /customers/1235"));
+        assert_ne!(
+            pseudonym,
+            PseudonymizationKey::new(b"other").pseudonym("GET /customers/1234")
+        );
+
+        let config = Pseudonymize {
+            key: SpanKey::Current(KeyName::OperationName),
+            keep: BTreeSet::from([String::from("GET /health")]),
+        };
+        let value = |s: &str| TagValue::String(s.to_string());
+        assert_eq!(
+            config.apply(Some(&key), value("GET /health")),
+            value("GET /health")
+        );
+        assert_eq!(
+            config.apply(Some(&key), value("GET /customers/1234")),
+            value(&pseudonym)
+        );
+        assert_eq!(
+            config.apply(None, value("GET /customers/1234")),
+            value("p-unavailable")
+        );
+    }
+}
@@ -0,0 +1,762 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+//! Aggregation pushdown: for configs that only need per-group counts,
+//! means and variances, let OpenSearch compute these per sample
+//! interval with a composite aggregation, instead of processing the
+//! spans one by one.
+//!
+//! Results differ from the span-by-span path in a few ways: spans are
+//! selected by their own start time rather than that of their trace's
+//! root span, dedup is not applied, and tag selectors match any tag
+//! with the given key rather than only the first one.
+
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use rustc_apfloat::ieee::Quad;
+use serde::Deserialize;
+
+use crate::{
+    config::{ConfigName, KeyName, MetricName, SpanKey, SpanKind, SpanSelector, SPAN_KIND_TAG},
+    jaeger::TagValue,
+    opensearch::{EsAggRequest, EsCompositeAgg, EsExtendedStats, EsFilterAgg},
+    welford::{from_f64, Welford},
+};
+
+use super::{source::MetricSource, span::SpanConfig, trace::Rule};
+
+/// The number of groups per aggregation request.
+const PAGE_SIZE: usize = 1000;
+
+pub type GroupKey = BTreeMap<SpanKey, TagValue>;
+pub type AfterKey = BTreeMap<String, serde_json::Value>;
+
+#[derive(thiserror::Error, PartialEq, Eq, Debug)]
+pub enum PushdownError {
+    #[error("the key has no components")]
+    EmptyKey,
+    #[error("key component {0} is not supported; only the current service and operation name can be aggregated on")]
+    Key(SpanKey),
+    #[error("metric {0} needs the individual spans; only duration, rate and count sources are supported")]
+    Source(MetricName),
+    #[error("metric {0} needs the individual values; summaries, histograms and the quantile anomaly score are not supported")]
+    Stats(MetricName),
+    #[error("the selector of metric {0} cannot be translated into a query: {1}")]
+    RateSelector(MetricName, SpanSelector),
+    #[error("the config must be selected by a single rule, which is the only rule in its group")]
+    Rules,
+    #[error("the selector of the rule cannot be translated into a query: {0}")]
+    RuleSelector(SpanSelector),
+}
+
+/// The aggregation query for a config with pushdown enabled.
+#[derive(Clone, Debug)]
+pub struct PushdownQuery {
+    pub config: ConfigName,
+    /// Span filter translated from the rule selecting the config.
+    select: serde_json::Value,
+    /// Composite aggregation sources: label, key component and field.
+    sources: Vec<(String, SpanKey, &'static str)>,
+    /// Filter aggregations for the rate metrics.
+    rates: BTreeMap<MetricName, serde_json::Value>,
+}
+
+/// Statistics of the spans of one group in one sample interval, as
+/// computed by the backend.
+#[derive(Clone, Debug)]
+pub struct SpanAggregate {
+    pub count: u64,
+    pub duration: Welford<Quad>,
+    /// The number of spans matching the selector of each rate metric.
+    pub matches: BTreeMap<MetricName, u64>,
+}
+
+/// The `aggregations` object of an aggregation response.
+#[derive(Deserialize, Debug)]
+pub struct Aggregations {
+    groups: EsCompositeAgg<AfterKey, GroupBucket>,
+}
+
+#[derive(Deserialize, Debug)]
+struct GroupBucket {
+    key: BTreeMap<String, String>,
+    doc_count: u64,
+    duration: EsExtendedStats,
+    #[serde(flatten)]
+    rates: BTreeMap<String, EsFilterAgg>,
+}
+
+impl PushdownQuery {
+    /// Build the query, checking that the config can be computed from
+    /// aggregates.
+    pub fn new(
+        name: &ConfigName,
+        config: &SpanConfig,
+        rules: &[Vec<Rule>],
+    ) -> Result<Self, PushdownError> {
+        if config.key.is_empty() {
+            return Err(PushdownError::EmptyKey);
+        }
+        let sources = config
+            .key
+            .iter()
+            .map(|key| match key {
+                SpanKey::Current(KeyName::ServiceName) => Ok((
+                    key.label().into_string(),
+                    key.clone(),
+                    "process.serviceName",
+                )),
+                SpanKey::Current(KeyName::OperationName) => {
+                    Ok((key.label().into_string(), key.clone(), "operationName"))
+                }
+                _ => Err(PushdownError::Key(key.clone())),
+            })
+            .collect::<Result<_, _>>()?;
+
+        let mut rates = BTreeMap::new();
+        for (metric, metric_config) in &config.metrics {
+            let stats = &metric_config.stats;
+            if stats.summary.is_some()
+                || stats.histogram.is_some()
+                || stats
+                    .anomaly_score
+                    .as_ref()
+                    .is_some_and(|config| config.q_stat().is_some())
+            {
+                return Err(PushdownError::Stats(metric.clone()));
+            }
+            match &metric_config.source {
+                MetricSource::Duration | MetricSource::Count { .. } => {}
+                MetricSource::Rate { select } => {
+                    let query = selector_query(select).ok_or_else(|| {
+                        PushdownError::RateSelector(metric.clone(), select.clone())
+                    })?;
+                    rates.insert(metric.clone(), query);
+                }
+                _ => return Err(PushdownError::Source(metric.clone())),
+            }
+        }
+
+        let mut selecting = rules
+            .iter()
+            .filter(|group| group.iter().any(|rule| &rule.config == name));
+        let rule = match (selecting.next().map(Vec::as_slice), selecting.next()) {
+            (Some([rule]), None) => rule,
+            _ => return Err(PushdownError::Rules),
+        };
+        let select = selector_query(&rule.select)
+            .ok_or_else(|| PushdownError::RuleSelector(rule.select.clone()))?;
+
+        Ok(Self {
+            config: name.clone(),
+            select,
+            sources,
+            rates,
+        })
+    }
+
+    /// The aggregation request for the spans started in `(from, to]`,
+    /// continuing after the given page.
+    pub fn request(
+        &self,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        filter: Option<serde_json::Value>,
+        after: Option<&AfterKey>,
+    ) -> EsAggRequest<serde_json::Value, serde_json::Value> {
+        let mut composite = serde_json::json!({
+            "size": PAGE_SIZE,
+            "sources": self
+                .sources
+                .iter()
+                .map(|(label, _, field)| {
+                    serde_json::json!({ label: { "terms": { "field": field } } })
+                })
+                .collect::<Vec<_>>()
+        });
+        if let Some(after) = after {
+            composite["after"] = serde_json::json!(after);
+        }
+        let mut aggs = serde_json::json!({
+            "duration": {
+                "extended_stats": {
+                    "field": "duration"
+                }
+            }
+        });
+        self.rates.iter().for_each(|(metric, query)| {
+            aggs[format!("rate_{metric}")] = serde_json::json!({ "filter": query });
+        });
+
+        EsAggRequest {
+            query: serde_json::json!({
+                "bool": {
+                    "filter": std::iter::once(serde_json::json!({
+                        "range": {
+                            "startTime": {
+                                "gt": from.timestamp_micros(),
+                                "lte": to.timestamp_micros()
+                            }
+                        }
+                    }))
+                    .chain(std::iter::once(self.select.clone()))
+                    .chain(filter)
+                    .collect::<Vec<_>>()
+                }
+            }),
+            size: 0,
+            aggs: serde_json::json!({
+                "groups": {
+                    "composite": composite,
+                    "aggs": aggs
+                }
+            }),
+        }
+    }
+
+    /// Convert a page of the aggregation response. Returns the
+    /// aggregates by group and the key to continue after, if the
+    /// page was not the last one.
+    pub fn aggregates(
+        &self,
+        response: Aggregations,
+    ) -> (Vec<(GroupKey, SpanAggregate)>, Option<AfterKey>) {
+        let groups = response.groups;
+        let after = groups.after_key.filter(|_| !groups.buckets.is_empty());
+        let aggregates = groups
+            .buckets
+            .into_iter()
+            .map(|bucket| {
+                let key = self
+                    .sources
+                    .iter()
+                    .filter_map(|(label, key, _)| {
+                        Some((
+                            key.clone(),
+                            TagValue::String(bucket.key.get(label)?.clone()),
+                        ))
+                    })
+                    .collect();
+                let count = bucket.duration.count as f64;
+                let aggregate = SpanAggregate {
+                    count: bucket.doc_count,
+                    duration: welford(
+                        count,
+                        bucket.duration.avg.unwrap_or(0.0),
+                        bucket.duration.variance.unwrap_or(0.0) * count,
+                    ),
+                    matches: self
+                        .rates
+                        .keys()
+                        .map(|metric| {
+                            let agg = bucket.rates.get(&format!("rate_{metric}"));
+                            (metric.clone(), agg.map_or(0, |agg| agg.doc_count))
+                        })
+                        .collect(),
+                };
+                (key, aggregate)
+            })
+            .collect();
+        (aggregates, after)
+    }
+}
+
+impl SpanAggregate {
+    /// The summarized values of a rate source: 1 for every matching
+    /// span and 0 for every other span.
+    pub fn rate(&self, metric: &MetricName) -> Option<Welford<Quad>> {
+        let matches = *self.matches.get(metric)? as f64;
+        (self.count > 0).then(|| {
+            let count = self.count as f64;
+            let mean = matches / count;
+            welford(count, mean, matches * (1.0 - mean))
+        })
+    }
+}
+
+fn welford(count: f64, mean: f64, m2: f64) -> Welford<Quad> {
+    Welford {
+        count: from_f64(count),
+        mean: from_f64(mean),
+        m2: from_f64(m2),
+    }
+}
+
+/// The span document field holding a key, if it is not a tag.
+fn field(key: &KeyName) -> Option<&'static str> {
+    match key {
+        KeyName::ServiceName => Some("process.serviceName"),
+        KeyName::OperationName => Some("operationName"),
+        KeyName::Duration => Some("duration"),
+        KeyName::ProcessTag(_)
+        | KeyName::SpanTag(_)
+        | KeyName::SpanKind
+        | KeyName::StatusCode
+        | KeyName::Baggage(_)
+        | KeyName::Class(_) => None,
+    }
+}
+
+/// A query on a nested tag with the given key, type and value. Keys
+/// derived from several tags are not supported.
+fn tag_query(
+    key: &KeyName,
+    r#type: Option<&str>,
+    value: Option<serde_json::Value>,
+) -> Option<serde_json::Value> {
+    let (path, name) = match key {
+        KeyName::ProcessTag(name) => ("process.tags", name.as_str()),
+        KeyName::SpanTag(name) => ("tags", name.as_str()),
+        KeyName::SpanKind => ("tags", SPAN_KIND_TAG),
+        KeyName::StatusCode | KeyName::Baggage(_) | KeyName::Class(_) => return None,
+        KeyName::ServiceName | KeyName::OperationName | KeyName::Duration => {
+            return Some(serde_json::json!({ "match_none": {} }))
+        }
+    };
+    let (key_field, type_field, value_field) = (
+        format!("{path}.key"),
+        format!("{path}.type"),
+        format!("{path}.value"),
+    );
+    let filter = std::iter::once(serde_json::json!({ "term": { key_field: name } }))
+        .chain(r#type.map(|t| serde_json::json!({ "term": { type_field: t } })))
+        .chain(value.map(|v| {
+            let op = if v.is_array() { "terms" } else { "term" };
+            serde_json::json!({ op: { value_field: v } })
+        }))
+        .collect::<Vec<_>>();
+    Some(serde_json::json!({
+        "nested": {
+            "path": path,
+            "query": {
+                "bool": {
+                    "filter": filter
+                }
+            }
+        }
+    }))
+}
+
+/// Translate a selector into a query on the span documents. Only
+/// selectors on the current span without regexes or ranges are
+/// supported. Follows the typing of `SpanSelector::matches`: string
+/// selectors never match numbers or booleans and vice versa.
+fn selector_query(select: &SpanSelector) -> Option<serde_json::Value> {
+    let none = || serde_json::json!({ "match_none": {} });
+    match select {
+        SpanSelector::All(sels) => Some(serde_json::json!({
+            "bool": {
+                "filter": sels.iter().map(selector_query).collect::<Option<Vec<_>>>()?
+            }
+        })),
+        SpanSelector::Any(sels) if sels.is_empty() => Some(none()),
+        SpanSelector::Any(sels) => Some(serde_json::json!({
+            "bool": {
+                "should": sels.iter().map(selector_query).collect::<Option<Vec<_>>>()?,
+                "minimum_should_match": 1
+            }
+        })),
+        SpanSelector::Not(sel) => Some(serde_json::json!({
+            "bool": {
+                "must_not": [selector_query(sel)?]
+            }
+        })),
+        SpanSelector::Has(SpanKey::Current(key)) => Some(match field(key) {
+            Some(_) => serde_json::json!({ "match_all": {} }),
+            None => tag_query(key, None, None)?,
+        }),
+        SpanSelector::In(SpanKey::Current(key), values) => Some(match (key, field(key)) {
+            (KeyName::Duration, _) => none(),
+            (_, Some(field)) => serde_json::json!({ "terms": { field: values } }),
+            (_, None) => tag_query(key, Some("string"), Some(serde_json::json!(values)))?,
+        }),
+        SpanSelector::Eq(SpanKey::Current(key), n) => Some(match (key, field(key)) {
+            (KeyName::Duration, Some(field)) => serde_json::json!({ "term": { field: n } }),
+            (_, Some(_)) => none(),
+            (_, None) => tag_query(key, Some("int64"), Some(serde_json::json!(n.to_string())))?,
+        }),
+        SpanSelector::IsTrue(SpanKey::Current(key)) => Some(match field(key) {
+            Some(_) => none(),
+            None => tag_query(key, Some("bool"), Some(serde_json::json!("true")))?,
+        }),
+        SpanSelector::IsFalse(SpanKey::Current(key)) => Some(match field(key) {
+            Some(_) => none(),
+            None => tag_query(key, Some("bool"), Some(serde_json::json!("false")))?,
+        }),
+        SpanSelector::Kind(kinds) => tag_query(
+            &KeyName::SpanKind,
+            Some("string"),
+            Some(serde_json::json!(kinds
+                .kinds()
+                .iter()
+                .map(SpanKind::as_str)
+                .collect::<Vec<_>>())),
+        ),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::{BTreeMap, BTreeSet};
+
+    use chrono::{DateTime, TimeDelta, Utc};
+    use jaeger_anomaly_detection::WindowConfig;
+    use serde_json::json;
+
+    use super::{selector_query, Aggregations, PushdownError, PushdownQuery};
+    use crate::{
+        config::{
+            Ancestors, ConfigName, IngestFilter, KeyName, MetricName, Regex, SpanKey, SpanKind,
+            SpanKindSelector, SpanSelector,
+        },
+        jaeger::Span,
+        processor::{
+            anomaly_score::AnomalyScoreConfig,
+            mean_stddev::{MeanStddevAlgorithm, MeanStddevConfig},
+            metric::MetricConfig,
+            sim::{load_fixture, parse_traces, start, synthetic_traces},
+            source::MetricSource,
+            span::{default_carry_over_age, default_max_sampling_weight, MissingKey, SpanConfig},
+            stats::StatsConfig,
+            summary::SummaryConfig,
+            trace::{Rule, TraceConfig, TraceProcessor},
+        },
+    };
+
+    fn errors() -> SpanSelector {
+        SpanSelector::IsTrue(SpanKey::Current(KeyName::SpanTag(String::from("error"))))
+    }
+
+    fn span_config(pushdown: bool) -> SpanConfig {
+        let stats = StatsConfig {
+            anomaly_score: Some(AnomalyScoreConfig::default()),
+            mean_stddev: Some(MeanStddevConfig {
+                algorithm: MeanStddevAlgorithm::Welford,
+            }),
+            summary: None,
+            histogram: None,
+            ..StatsConfig::default()
+        };
+        SpanConfig {
+            key: BTreeSet::from_iter([
+                SpanKey::Current(KeyName::ServiceName),
+                SpanKey::Current(KeyName::OperationName),
+            ]),
+            metrics: BTreeMap::from_iter([
+                (
+                    MetricName::new("duration"),
+                    MetricConfig {
+                        source: MetricSource::Duration,
+                        stats: stats.clone(),
+                        enabled: true,
+                    },
+                ),
+                (
+                    MetricName::new("errors"),
+                    MetricConfig {
+                        source: MetricSource::Rate { select: errors() },
+                        stats: stats.clone(),
+                        enabled: true,
+                    },
+                ),
+                (
+                    MetricName::new("calls"),
+                    MetricConfig {
+                        source: MetricSource::Count {
+                            window: WindowConfig::default(),
+                        },
+                        stats,
+                        enabled: true,
+                    },
+                ),
+            ]),
+            carry_over: BTreeSet::new(),
+            carry_over_age: default_carry_over_age(),
+            pushdown,
+            normalize_numbers: false,
+            annotations: BTreeSet::new(),
+            idle_after: None,
+            respect_sampling: false,
+            max_sampling_weight: default_max_sampling_weight(),
+            classify: BTreeMap::new(),
+            pseudonymize: Vec::new(),
+            late_bins: None,
+            missing_key: MissingKey::Omit,
+            enabled: true,
+        }
+    }
+
+    /// The same config twice, once processed span by span and once by
+    /// pushdown.
+    fn trace_config() -> TraceConfig {
+        let rule = |name: &str| {
+            Vec::from([Rule {
+                select: SpanSelector::All(Vec::new()),
+                config: ConfigName::new(name),
+                priority: None,
+                stop: true,
+            }])
+        };
+        TraceConfig {
+            rules: Vec::from([rule("spans"), rule("pushdown")]),
+            configs: BTreeMap::from_iter([
+                (ConfigName::new("spans"), span_config(false)),
+                (ConfigName::new("pushdown"), span_config(true)),
+            ]),
+            ..TraceConfig::default()
+        }
+    }
+
+    /// Synthetic traces, with an error on every third backend span.
+    fn traces(start: DateTime<Utc>, n: usize) -> Vec<Vec<Span>> {
+        let mut traces = synthetic_traces(start, n);
+        traces.iter_mut().step_by(3).for_each(|trace| {
+            trace[1].tags.push(
+                serde_json::from_value(json!({ "key": "error", "type": "bool", "value": "true" }))
+                    .unwrap(),
+            )
+        });
+        traces
+    }
+
+    /// The aggregation response OpenSearch would return for the spans.
+    fn es_response(traces: &[Vec<Span>]) -> Aggregations {
+        let groups =
+            traces
+                .iter()
+                .flatten()
+                .fold(BTreeMap::<_, Vec<_>>::new(), |mut groups, span| {
+                    groups
+                        .entry((&span.process.service_name.0, &span.operation_name.0))
+                        .or_default()
+                        .push(span);
+                    groups
+                });
+        let buckets = groups
+            .into_iter()
+            .map(|((service, operation), spans)| {
+                let n = spans.len() as f64;
+                let avg = spans.iter().map(|span| span.duration as f64).sum::<f64>() / n;
+                let variance = spans
+                    .iter()
+                    .map(|span| (span.duration as f64 - avg).powi(2))
+                    .sum::<f64>()
+                    / n;
+                let errors = spans
+                    .iter()
+                    .filter(|span| errors().matches(span, Ancestors::default()))
+                    .count();
+                json!({
+                    "key": { "service_name": service, "operation_name": operation },
+                    "doc_count": spans.len(),
+                    "duration": { "count": spans.len(), "avg": avg, "variance": variance },
+                    "rate_errors": { "doc_count": errors }
+                })
+            })
+            .collect::<Vec<_>>();
+        serde_json::from_value(json!({ "groups": { "buckets": buckets } })).unwrap()
+    }
+
+    /// Sampled values by config and series.
+    fn sample(
+        proc: &mut TraceProcessor,
+        t: DateTime<Utc>,
+    ) -> BTreeMap<String, BTreeMap<String, f64>> {
+        let mut output = BTreeMap::<_, BTreeMap<_, _>>::new();
+        proc.sample(t, |args, config_name, value| {
+            if args.metric_type != "self_monitoring" {
+                output.entry(config_name.to_string()).or_default().insert(
+                    format!(
+                        "{} {:?} {:?} {:?}",
+                        args.metric_name, args.key, args.labels.immediate, args.labels.reference
+                    ),
+                    value,
+                );
+            }
+        });
+        output
+    }
+
+    #[test]
+    fn pushdown_matches_span_path() {
+        let config = trace_config();
+        let mut proc = TraceProcessor::new(&config);
+        let query = proc.pushdown_queries().to_vec();
+        assert_eq!(query.len(), 1);
+
+        // Insert the spans of each sample interval (of one window bin)
+        // at its last microsecond, as the aggregates are.
+        let interval = TimeDelta::seconds(30);
+        for i in 1..=10 {
+            let t = start() + interval * i - TimeDelta::microseconds(1);
+            let traces = traces(start() + interval * (i - 1), 300);
+            traces
+                .iter()
+                .for_each(|trace| proc.insert(t, trace, &IngestFilter::default()));
+            let (aggregates, after) = query[0].aggregates(es_response(&traces));
+            assert_eq!(after, None);
+            proc.insert_aggregates(t, &query[0].config, aggregates);
+        }
+        assert_same_samples(&mut proc, start() + interval * 10);
+    }
+
+    /// The same on the span fixtures, whose traces differ in depth and
+    /// duration: those that started in the first sample interval.
+    #[test]
+    fn pushdown_matches_span_path_on_fixtures() {
+        let config = trace_config();
+        let mut proc = TraceProcessor::new(&config);
+        let query = proc.pushdown_queries().to_vec();
+
+        let interval = TimeDelta::seconds(30);
+        let range = start().timestamp_micros()..(start() + interval).timestamp_micros();
+        let traces = parse_traces(load_fixture("traces.json").as_array().unwrap())
+            .into_iter()
+            .filter(|trace| range.contains(&trace[0].start_time))
+            .collect::<Vec<_>>();
+        assert_eq!(traces.len(), 7);
+
+        let t = start() + interval - TimeDelta::microseconds(1);
+        traces
+            .iter()
+            .for_each(|trace| proc.insert(t, trace, &IngestFilter::default()));
+        let (aggregates, after) = query[0].aggregates(es_response(&traces));
+        assert_eq!(after, None);
+        proc.insert_aggregates(t, &query[0].config, aggregates);
+        assert_same_samples(&mut proc, start() + interval);
+    }
+
+    /// Both configs sampled the same series with the same values, up
+    /// to rounding.
+    fn assert_same_samples(proc: &mut TraceProcessor, t: DateTime<Utc>) {
+        let mut output = sample(proc, t);
+        let spans = output.remove("spans").unwrap();
+        let pushdown = output.remove("pushdown").unwrap();
+        assert_eq!(
+            spans.keys().collect::<Vec<_>>(),
+            pushdown.keys().collect::<Vec<_>>()
+        );
+        spans.iter().for_each(|(series, a)| {
+            let b = pushdown[series];
+            assert!(
+                (a - b).abs() <= 1e-6 * a.abs().max(1.0),
+                "{series}: {a} != {b}"
+            );
+        });
+    }
+
+    #[test]
+    fn unsupported_configs() {
+        let config = trace_config();
+        let check = |config: &SpanConfig, rules: &[Vec<Rule>]| {
+            PushdownQuery::new(&ConfigName::new("pushdown"), config, rules).map(|_| ())
+        };
+        assert_eq!(check(&span_config(true), &config.rules), Ok(()));
+
+        let mut summary = span_config(true);
+        summary
+            .metrics
+            .get_mut(&MetricName::new("duration"))
+            .unwrap()
+            .stats
+            .summary = Some(SummaryConfig::default());
+        assert_eq!(
+            check(&summary, &config.rules),
+            Err(PushdownError::Stats(MetricName::new("duration")))
+        );
+
+        let mut self_duration = span_config(true);
+        self_duration
+            .metrics
+            .get_mut(&MetricName::new("duration"))
+            .unwrap()
+            .source = MetricSource::SelfDuration;
+        assert_eq!(
+            check(&self_duration, &config.rules),
+            Err(PushdownError::Source(MetricName::new("duration")))
+        );
+
+        let mut parent_key = span_config(true);
+        parent_key.key.insert(SpanKey::Parent(KeyName::ServiceName));
+        assert_eq!(
+            check(&parent_key, &config.rules),
+            Err(PushdownError::Key(SpanKey::Parent(KeyName::ServiceName)))
+        );
+
+        // Spans matched by an earlier rule in the group never reach
+        // the config.
+        let shared = Vec::from([config.rules.concat()]);
+        assert_eq!(
+            check(&span_config(true), &shared),
+            Err(PushdownError::Rules)
+        );
+    }
+
+    #[test]
+    fn selector_translation() {
+        assert_eq!(
+            selector_query(&errors()),
+            Some(json!({
+                "nested": {
+                    "path": "tags",
+                    "query": {
+                        "bool": {
+                            "filter": [
+                                { "term": { "tags.key": "error" } },
+                                { "term": { "tags.type": "bool" } },
+                                { "term": { "tags.value": "true" } }
+                            ]
+                        }
+                    }
+                }
+            }))
+        );
+        assert_eq!(
+            selector_query(&SpanSelector::In(
+                SpanKey::Current(KeyName::ServiceName),
+                BTreeSet::from_iter([String::from("frontend")]).into()
+            )),
+            Some(json!({ "terms": { "process.serviceName": ["frontend"] } }))
+        );
+        assert_eq!(
+            selector_query(&SpanSelector::Match(
+                SpanKey::Current(KeyName::OperationName),
+                Regex::new("^GET").unwrap()
+            )),
+            None
+        );
+        assert_eq!(
+            selector_query(&SpanSelector::Has(SpanKey::Parent(KeyName::Duration))),
+            None
+        );
+        assert_eq!(
+            selector_query(&SpanSelector::In(
+                SpanKey::Current(KeyName::StatusCode),
+                BTreeSet::from_iter([String::from("ERROR")]).into()
+            )),
+            None
+        );
+        assert_eq!(
+            selector_query(&SpanSelector::Kind(SpanKindSelector::AnyOf(
+                BTreeSet::from_iter([SpanKind::Server, SpanKind::Consumer])
+            ))),
+            Some(json!({
+                "nested": {
+                    "path": "tags",
+                    "query": {
+                        "bool": {
+                            "filter": [
+                                { "term": { "tags.key": "span.kind" } },
+                                { "term": { "tags.type": "string" } },
+                                { "terms": { "tags.value": ["server", "consumer"] } }
+                            ]
+                        }
+                    }
+                }
+            }))
+        );
+    }
+}
@@ -0,0 +1,199 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use std::{
+    any::Any,
+    collections::{BTreeMap, BTreeSet},
+    panic::AssertUnwindSafe,
+    sync::Mutex,
+};
+
+use serde::Serialize;
+
+use crate::config::ConfigName;
+
+/// The number of consecutive sample intervals with failures after
+/// which a config is quarantined.
+pub const MAX_FAILED_SAMPLES: u64 = 3;
+
+/// Failures of the span configs. A config that fails (panics) is
+/// started over; a config failing in `MAX_FAILED_SAMPLES` consecutive
+/// sample intervals is quarantined, i.e. skipped until the next config
+/// update, while the other configs keep running.
+#[derive(Default, Debug)]
+pub struct Quarantine {
+    configs: BTreeMap<ConfigName, ConfigFailures>,
+    /// The configs that failed since the last sample.
+    failed: BTreeSet<ConfigName>,
+}
+
+#[derive(Serialize, schemars::JsonSchema, PartialEq, Eq, Clone, Debug)]
+pub struct ConfigFailures {
+    /// The number of failures since startup.
+    pub total: u64,
+    /// The number of consecutive sample intervals with failures.
+    pub failed_samples: u64,
+    /// Set when the config is skipped until the next config update.
+    pub quarantined: bool,
+    /// The panic message of the last failure.
+    pub last_error: String,
+}
+
+/// The config failures, shared between the processor task and the web
+/// server. Updated at every sample.
+#[derive(Default, Debug)]
+pub struct QuarantineStats(Mutex<BTreeMap<ConfigName, ConfigFailures>>);
+
+impl Quarantine {
+    pub fn is_quarantined(&self, config: &ConfigName) -> bool {
+        self.configs
+            .get(config)
+            .is_some_and(|failures| failures.quarantined)
+    }
+
+    pub fn configs(&self) -> &BTreeMap<ConfigName, ConfigFailures> {
+        &self.configs
+    }
+
+    /// Run `f` for `config`, recording a failure if it panics.
+    pub fn guard<T, F: FnOnce() -> T>(&mut self, config: &ConfigName, f: F) -> Option<T> {
+        self.record(config, catch_failure(f))
+    }
+
+    /// Record the outcome of work done for `config`.
+    pub fn record<T>(&mut self, config: &ConfigName, result: Result<T, String>) -> Option<T> {
+        match result {
+            Ok(value) => Some(value),
+            Err(error) => {
+                log::error!("config {config} failed: {error}");
+                let failures =
+                    self.configs
+                        .entry(config.clone())
+                        .or_insert_with(|| ConfigFailures {
+                            total: 0,
+                            failed_samples: 0,
+                            quarantined: false,
+                            last_error: String::new(),
+                        });
+                failures.total += 1;
+                failures.last_error = error;
+                self.failed.insert(config.clone());
+                None
+            }
+        }
+    }
+
+    /// Finish the current sample interval, quarantining the configs
+    /// that failed in too many consecutive intervals.
+    pub fn end_sample(&mut self) {
+        let failed = std::mem::take(&mut self.failed);
+        self.configs.iter_mut().for_each(|(config, failures)| {
+            if failed.contains(config) {
+                failures.failed_samples += 1;
+                if failures.failed_samples >= MAX_FAILED_SAMPLES && !failures.quarantined {
+                    log::warn!(
+                        "config {config} failed in {} consecutive samples; \
+                         skipping it until the next config update",
+                        failures.failed_samples
+                    );
+                    failures.quarantined = true;
+                }
+            } else {
+                failures.failed_samples = 0;
+            }
+        });
+    }
+
+    /// Lift the quarantines after a config update. Totals are kept
+    /// for the configs that still exist.
+    pub fn update<F: Fn(&ConfigName) -> bool>(self, has_config: F) -> Self {
+        Self {
+            configs: self
+                .configs
+                .into_iter()
+                .filter(|(config, _)| has_config(config))
+                .map(|(config, failures)| {
+                    (
+                        config,
+                        ConfigFailures {
+                            failed_samples: 0,
+                            quarantined: false,
+                            ..failures
+                        },
+                    )
+                })
+                .collect(),
+            failed: BTreeSet::new(),
+        }
+    }
+}
+
+impl QuarantineStats {
+    pub fn set(&self, configs: BTreeMap<ConfigName, ConfigFailures>) {
+        *self.0.lock().unwrap() = configs;
+    }
+
+    /// The failures per config, as of the last sample.
+    pub fn last(&self) -> BTreeMap<ConfigName, ConfigFailures> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+/// Run `f`, returning the panic message if it panics.
+pub fn catch_failure<T, F: FnOnce() -> T>(f: F) -> Result<T, String> {
+    std::panic::catch_unwind(AssertUnwindSafe(f)).map_err(panic_message)
+}
+
+/// The message of a panic, from the payload of `catch_unwind` or of a
+/// joined thread.
+pub fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|msg| msg.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| String::from("unknown panic"))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::ConfigName;
+
+    use super::{Quarantine, MAX_FAILED_SAMPLES};
+
+    #[test]
+    fn quarantine_after_consecutive_failures() {
+        let bad = ConfigName::new("bad");
+        let good = ConfigName::new("good");
+        let mut quarantine = Quarantine::default();
+
+        assert_eq!(quarantine.guard(&good, || 1), Some(1));
+        assert_eq!(quarantine.guard(&bad, || panic!("boom")), None::<()>);
+        quarantine.end_sample();
+        assert_eq!(quarantine.configs()[&bad].failed_samples, 1);
+
+        // A sample interval without failures resets the count.
+        quarantine.end_sample();
+        assert_eq!(quarantine.configs()[&bad].failed_samples, 0);
+
+        for _ in 0..MAX_FAILED_SAMPLES {
+            assert!(!quarantine.is_quarantined(&bad));
+            assert_eq!(
+                quarantine.guard(&bad, || panic!("failed {}", 42)),
+                None::<()>
+            );
+            quarantine.end_sample();
+        }
+        assert!(quarantine.is_quarantined(&bad));
+        assert!(!quarantine.is_quarantined(&good));
+        let failures = &quarantine.configs()[&bad];
+        assert_eq!(failures.total, MAX_FAILED_SAMPLES + 1);
+        assert_eq!(failures.last_error, "failed 42");
+
+        let quarantine = quarantine.update(|config| *config == bad);
+        assert!(!quarantine.is_quarantined(&bad));
+        assert_eq!(quarantine.configs()[&bad].total, MAX_FAILED_SAMPLES + 1);
+        let quarantine = quarantine.update(|_| false);
+        assert!(quarantine.configs().is_empty());
+    }
+}
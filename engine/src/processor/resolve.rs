@@ -0,0 +1,258 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use apistos::ApiComponent;
+use chrono::{TimeDelta, Utc};
+use jaeger_anomaly_detection::{CombineScores, NoCombine, TraceAggrKind, TraceMetric, TraceObject};
+use prometheus_expr::MetricSelector;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::error::{Error, Result};
+
+/// How far back the series of an object are looked up.
+const LOOKBACK: TimeDelta = TimeDelta::hours(1);
+/// The upper bound on the requested number of groups.
+const MAX_LIMIT: usize = 10000;
+/// The time allowed for the series query.
+const TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// A trace object to resolve into the groups it currently selects.
+#[derive(Deserialize, schemars::JsonSchema, ApiComponent, Debug)]
+pub struct ResolveObjectRequest {
+    pub metric: TraceMetric,
+    /// The aggregation whose series are looked up.
+    #[serde(default = "default_aggr")]
+    pub aggr: TraceAggrKind,
+    pub object: ResolveObject,
+    /// The maximum number of groups to return, at most 10000.
+    #[serde(default = "default_limit")]
+    pub limit: usize,
+}
+
+/// The object, as used in expressions without (`no_combine`) or with
+/// (`combine_scores`) combined service scores. The top N selection is
+/// ignored.
+#[derive(Deserialize, schemars::JsonSchema, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ResolveObject {
+    NoCombine(#[schemars(with = "serde_json::Value")] TraceObject<NoCombine>),
+    CombineScores(#[schemars(with = "serde_json::Value")] TraceObject<CombineScores>),
+}
+
+/// The groups selected by a trace object.
+#[derive(Serialize, schemars::JsonSchema, ApiComponent, PartialEq, Debug)]
+pub struct ResolvedObject {
+    /// The selector used to look up the series.
+    pub selector: String,
+    /// The distinct group labels of the series seen in the last hour,
+    /// in label order.
+    pub groups: Vec<BTreeMap<String, String>>,
+    /// Set when more groups matched than the limit.
+    pub truncated: bool,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "status", rename_all = "snake_case")]
+enum SeriesResponse {
+    Success { data: Vec<BTreeMap<String, String>> },
+    Error { error: String },
+}
+
+/// Looks up the series of trace objects in prometheus.
+#[derive(Clone, Debug)]
+pub struct ObjectResolver {
+    client: reqwest::Client,
+    url: Url,
+}
+
+const fn default_aggr() -> TraceAggrKind {
+    TraceAggrKind::Score
+}
+
+const fn default_limit() -> usize {
+    1000
+}
+
+impl ResolveObject {
+    fn selector(&self, metric: TraceMetric, aggr: TraceAggrKind) -> MetricSelector {
+        match self {
+            ResolveObject::NoCombine(object) => object.selector(metric, aggr),
+            ResolveObject::CombineScores(object) => object.selector(metric, aggr),
+        }
+    }
+
+    fn group_labels(&self) -> BTreeSet<String> {
+        let labels = match self {
+            ResolveObject::NoCombine(object) => object.group_labels(),
+            ResolveObject::CombineScores(object) => object.group_labels(),
+        };
+        labels.iter().map(|label| label.to_string()).collect()
+    }
+}
+
+impl ObjectResolver {
+    pub fn new(client: reqwest::Client, url: Url) -> Self {
+        Self { client, url }
+    }
+
+    /// The groups of the series matching the object, deduplicated on
+    /// the object's group labels.
+    pub async fn resolve(&self, request: &ResolveObjectRequest) -> Result<ResolvedObject> {
+        let selector = request
+            .object
+            .selector(request.metric, request.aggr)
+            .to_string();
+        let group_labels = request.object.group_labels();
+        let limit = request.limit.min(MAX_LIMIT);
+        let end = Utc::now();
+        let start = end - LOOKBACK;
+        let res = self
+            .client
+            .post(self.url.join("api/v1/series").map_err(Error::Url)?)
+            .timeout(TIMEOUT)
+            .form(&[
+                ("match[]", selector.clone()),
+                ("start", start.timestamp().to_string()),
+                ("end", end.timestamp().to_string()),
+            ])
+            .send()
+            .await
+            .map_err(Error::PromQuery)?;
+        let series = match res
+            .json::<SeriesResponse>()
+            .await
+            .map_err(Error::PromQuery)?
+        {
+            SeriesResponse::Success { data } => data,
+            SeriesResponse::Error { error } => return Err(Error::PromQueryRes(error)),
+        };
+        let mut groups = series
+            .into_iter()
+            .map(|labels| {
+                labels
+                    .into_iter()
+                    .filter(|(label, _)| group_labels.contains(label))
+                    .collect::<BTreeMap<_, _>>()
+            })
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+        let truncated = groups.len() > limit;
+        groups.truncate(limit);
+        Ok(ResolvedObject {
+            selector,
+            groups,
+            truncated,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use jaeger_anomaly_detection::{
+        NoCombine, OperationFilter, ServiceFilter, TraceAggrKind, TraceMetric, TraceObject,
+    };
+    use serde_json::json;
+
+    use super::{ObjectResolver, ResolveObject, ResolveObjectRequest};
+    use crate::processor::fake_http::FakeHttp;
+
+    /// A prometheus series endpoint answering every request with the
+    /// given (status, body) response.
+    async fn stub_prometheus(status: u16, body: String) -> FakeHttp {
+        FakeHttp::start("/prometheus/", move |request| {
+            assert_eq!(request.method, "POST");
+            assert_eq!(request.path(), "/prometheus/api/v1/series");
+            (status, body.clone())
+        })
+        .await
+    }
+
+    fn series(operation: &str, immediate: &str) -> serde_json::Value {
+        json!({
+            "__name__": "trace_duration_score",
+            "config": "default",
+            "metric_type": "anomaly_score",
+            "service_name": "frontend",
+            "service_namespace": "continuousc",
+            "operation_name": operation,
+            "immediate": immediate,
+            "reference": "1d",
+        })
+    }
+
+    fn request(limit: usize) -> ResolveObjectRequest {
+        ResolveObjectRequest {
+            metric: TraceMetric::Duration,
+            aggr: TraceAggrKind::Score,
+            object: ResolveObject::NoCombine(
+                TraceObject::<NoCombine>::builder()
+                    .operation()
+                    .multiple(Some(5))
+                    .item(
+                        OperationFilter::new()
+                            .service(ServiceFilter::new().service_name("frontend")),
+                    ),
+            ),
+            limit,
+        }
+    }
+
+    #[tokio::test]
+    async fn resolve_operations() {
+        let body = json!({
+            "status": "success",
+            "data": [
+                series("GET", "5m"),
+                series("GET", "15m"),
+                series("POST", "5m"),
+                series("DELETE", "5m"),
+            ]
+        });
+        let server = stub_prometheus(200, body.to_string()).await;
+        let resolver = ObjectResolver::new(reqwest::Client::new(), server.url().clone());
+
+        let resolved = resolver.resolve(&request(10)).await.unwrap();
+        assert!(resolved.selector.starts_with("trace_duration_score{"));
+        assert!(resolved.selector.contains("service_name=\"frontend\""));
+        let operations = resolved
+            .groups
+            .iter()
+            .map(|group| {
+                assert_eq!(
+                    group.keys().map(String::as_str).collect::<Vec<_>>(),
+                    ["operation_name", "service_name", "service_namespace"]
+                );
+                group["operation_name"].as_str()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(operations, ["DELETE", "GET", "POST"]);
+        assert!(!resolved.truncated);
+
+        let form = server.requests()[0].text();
+        let params = url::form_urlencoded::parse(form.as_bytes())
+            .into_owned()
+            .collect::<BTreeMap<_, _>>();
+        assert_eq!(params["match[]"], resolved.selector);
+        assert!(params["start"] < params["end"]);
+
+        let resolved = resolver.resolve(&request(2)).await.unwrap();
+        assert_eq!(resolved.groups.len(), 2);
+        assert!(resolved.truncated);
+    }
+
+    #[tokio::test]
+    async fn resolve_error() {
+        let body = json!({ "status": "error", "errorType": "bad_data", "error": "failed" });
+        let server = stub_prometheus(400, body.to_string()).await;
+        let resolver = ObjectResolver::new(reqwest::Client::new(), server.url().clone());
+        let e = resolver.resolve(&request(10)).await.unwrap_err();
+        assert!(e.to_string().contains("failed"), "{e}");
+    }
+}
@@ -0,0 +1,93 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use serde::Serialize;
+
+use crate::config::ConfigName;
+
+/// Rule evaluation counters, shared between the processor task and the
+/// web server. Counts accumulate while traces are inserted and are
+/// moved to `last_tick` at the end of every tick.
+#[derive(Default, Debug)]
+pub struct RuleStats {
+    evaluated: AtomicU64,
+    unmatched: AtomicU64,
+    matched: Mutex<BTreeMap<usize, BTreeMap<ConfigName, u64>>>,
+    last_tick: Mutex<Option<RuleCounts>>,
+}
+
+#[derive(Serialize, schemars::JsonSchema, PartialEq, Eq, Default, Clone, Debug)]
+pub struct RuleCounts {
+    /// The number of spans evaluated against the rules.
+    pub evaluated: u64,
+    /// The number of spans not matched by any rule.
+    pub unmatched: u64,
+    /// The number of matched spans, per rule group index and config.
+    pub matched: BTreeMap<usize, BTreeMap<ConfigName, u64>>,
+}
+
+impl RuleCounts {
+    pub fn add(&mut self, other: &RuleCounts) {
+        self.evaluated += other.evaluated;
+        self.unmatched += other.unmatched;
+        add_matched(&mut self.matched, &other.matched);
+    }
+
+    pub(crate) fn count_match(&mut self, group: usize, config: &ConfigName) {
+        *self
+            .matched
+            .entry(group)
+            .or_default()
+            .entry(config.clone())
+            .or_default() += 1;
+    }
+}
+
+impl RuleStats {
+    pub fn add(&self, counts: &RuleCounts) {
+        self.evaluated
+            .fetch_add(counts.evaluated, Ordering::Relaxed);
+        self.unmatched
+            .fetch_add(counts.unmatched, Ordering::Relaxed);
+        if !counts.matched.is_empty() {
+            add_matched(&mut self.matched.lock().unwrap(), &counts.matched);
+        }
+    }
+
+    /// Finish the current tick, making its counts available through
+    /// `last_tick`.
+    pub fn end_tick(&self) {
+        let counts = RuleCounts {
+            evaluated: self.evaluated.swap(0, Ordering::Relaxed),
+            unmatched: self.unmatched.swap(0, Ordering::Relaxed),
+            matched: std::mem::take(&mut *self.matched.lock().unwrap()),
+        };
+        *self.last_tick.lock().unwrap() = Some(counts);
+    }
+
+    /// The counts for the last completed tick.
+    pub fn last_tick(&self) -> Option<RuleCounts> {
+        self.last_tick.lock().unwrap().clone()
+    }
+}
+
+fn add_matched(
+    matched: &mut BTreeMap<usize, BTreeMap<ConfigName, u64>>,
+    other: &BTreeMap<usize, BTreeMap<ConfigName, u64>>,
+) {
+    other.iter().for_each(|(group, configs)| {
+        let group = matched.entry(*group).or_default();
+        configs.iter().for_each(|(config, n)| {
+            *group.entry(config.clone()).or_default() += n;
+        });
+    });
+}
@@ -0,0 +1,203 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use chrono::{DateTime, DurationRound, TimeDelta, Utc};
+
+use crate::metrics::Metrics;
+
+use super::trace::TraceProcessor;
+
+/// Processor state older than this is cleaned up after every tick.
+pub(crate) fn cleanup_time(to: DateTime<Utc>) -> DateTime<Utc> {
+    to - TimeDelta::days(30)
+}
+
+/// The sample schedule of a processing tick. The processor is sampled
+/// at every multiple of the sample interval (since the epoch) after the
+/// start of the tick, once all traces starting before that time have
+/// been inserted. Samples are never taken at or before the last sample
+/// of a previous tick, even if the interval changed in between or the
+/// previous tick is retried.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Sampler {
+    interval: TimeDelta,
+    next: DateTime<Utc>,
+}
+
+impl Sampler {
+    pub fn new(from: DateTime<Utc>, last: Option<DateTime<Utc>>, interval: TimeDelta) -> Self {
+        let next = match last {
+            Some(last) => next_boundary(last, interval)
+                .max(next_boundary(from - TimeDelta::microseconds(1), interval)),
+            None => next_boundary(from, interval),
+        };
+        Self { interval, next }
+    }
+
+    /// The time between samples.
+    pub fn interval(&self) -> TimeDelta {
+        self.interval
+    }
+
+    /// Whether a sample has to be taken before a trace starting at
+    /// `t` can be inserted.
+    pub fn is_due(&self, t: DateTime<Utc>) -> bool {
+        self.next < t
+    }
+
+    /// Return the next sample time and advance the schedule, if the
+    /// sample is due before `t`.
+    pub fn take_due(&mut self, t: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.is_due(t).then(|| {
+            let sample = self.next;
+            self.next += self.interval;
+            sample
+        })
+    }
+}
+
+/// The first multiple of `interval` after `t`.
+fn next_boundary(t: DateTime<Utc>, interval: TimeDelta) -> DateTime<Utc> {
+    t.duration_trunc(interval).unwrap_or(t) + interval
+}
+
+/// Sample the processor at `t`, adding the results to `metrics`, after
+/// the corrections of earlier samples. When the sample has more than
+/// `max_series` series, the lowest priority series are dropped. The
+/// written series are registered with the processor, to mark them
+/// stale when they end.
+pub(crate) fn sample_metrics(
+    processor: &mut TraceProcessor,
+    t: DateTime<Utc>,
+    metrics: &mut Metrics,
+    max_series: Option<usize>,
+) {
+    let mut sampled = Metrics::new();
+    processor.sample_corrections(|bin, metric_args, config_name, value| {
+        sampled.add_metric(metric_args, config_name, bin, value);
+    });
+    processor.sample(t, |metric_args, config_name, value| {
+        sampled.add_metric(metric_args, config_name, t, value);
+    });
+    let report = sampled.truncate(max_series.unwrap_or(usize::MAX));
+    if let Some(max_series) = max_series.filter(|_| !report.dropped.is_empty()) {
+        log::error!(
+            "series limit exceeded at {t}: sampled {} series, limit is {max_series}; \
+             dropped {} series per config {:?}, per priority {:?}",
+            report.total(),
+            report.total_dropped(),
+            report.dropped,
+            report.dropped_by_priority
+        );
+    }
+    processor.record_series(report);
+    processor.register_series(t, &sampled);
+    metrics.append(sampled);
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::{DateTime, TimeDelta, Utc};
+
+    use super::Sampler;
+
+    #[test]
+    fn samples_before_trace() {
+        let from = DateTime::from_timestamp(1_699_999_200, 0).unwrap();
+        let interval = TimeDelta::seconds(10);
+        let mut sampler = Sampler::new(from, None, interval);
+
+        // Nothing is due before the first interval has passed.
+        assert!(!sampler.is_due(from + interval));
+        assert_eq!(sampler.take_due(from + interval), None);
+
+        // All intervals ending before the trace are sampled, in order.
+        let t = from + TimeDelta::seconds(35);
+        assert!(sampler.is_due(t));
+        let samples = std::iter::from_fn(|| sampler.take_due(t)).collect::<Vec<_>>();
+        assert_eq!(samples, [10, 20, 30].map(|s| from + TimeDelta::seconds(s)));
+        assert!(!sampler.is_due(t));
+        assert_eq!(
+            sampler.take_due(from + TimeDelta::seconds(41)),
+            Some(from + TimeDelta::seconds(40))
+        );
+    }
+
+    /// Run ticks over `ticks` (end time and interval in seconds),
+    /// starting at `from`, returning the sample times.
+    fn run_ticks(from: DateTime<Utc>, ticks: &[(i64, i64)]) -> Vec<DateTime<Utc>> {
+        let mut from = from;
+        let mut last = None;
+        let mut samples = Vec::new();
+        for (to, interval) in ticks {
+            let to = DateTime::from_timestamp(*to, 0).unwrap();
+            let mut sampler = Sampler::new(from, last, TimeDelta::seconds(*interval));
+            while let Some(t) = sampler.take_due(to) {
+                samples.push(t);
+                last = Some(t);
+            }
+            from = to;
+        }
+        samples
+    }
+
+    #[test]
+    fn samples_on_interval_grid() {
+        let from = DateTime::from_timestamp(1_699_999_207, 0).unwrap();
+        let mut sampler = Sampler::new(from, None, TimeDelta::seconds(10));
+        let samples = std::iter::from_fn(|| sampler.take_due(from + TimeDelta::seconds(30)))
+            .map(|t| t.timestamp())
+            .collect::<Vec<_>>();
+        assert_eq!(samples, [1_699_999_210, 1_699_999_220, 1_699_999_230]);
+    }
+
+    #[test]
+    fn interval_change_between_ticks() {
+        let from = DateTime::from_timestamp(1_699_999_205, 0).unwrap();
+        let samples = run_ticks(
+            from,
+            &[
+                (1_699_999_297, 30),
+                (1_699_999_351, 60),
+                (1_699_999_391, 30),
+                (1_699_999_440, 60),
+                (1_699_999_500, 10),
+            ],
+        );
+        assert!(samples.windows(2).all(|w| w[0] < w[1]), "{samples:?}");
+        assert_eq!(
+            samples.iter().map(|t| t.timestamp()).collect::<Vec<_>>(),
+            [
+                1_699_999_230,
+                1_699_999_260,
+                1_699_999_290,
+                1_699_999_320,
+                1_699_999_380,
+                1_699_999_440,
+                1_699_999_450,
+                1_699_999_460,
+                1_699_999_470,
+                1_699_999_480,
+                1_699_999_490,
+            ]
+        );
+    }
+
+    #[test]
+    fn retried_tick_does_not_resample() {
+        let from = DateTime::from_timestamp(1_699_999_200, 0).unwrap();
+        let to = from + TimeDelta::seconds(65);
+        let mut sampler = Sampler::new(from, None, TimeDelta::seconds(30));
+        let last = std::iter::from_fn(|| sampler.take_due(to)).last();
+        assert_eq!(last, Some(from + TimeDelta::seconds(60)));
+
+        // The tick failed, so it is retried from the same start with
+        // a longer interval.
+        let mut sampler = Sampler::new(from, last, TimeDelta::seconds(60));
+        assert_eq!(
+            sampler.take_due(from + TimeDelta::seconds(125)),
+            Some(from + TimeDelta::seconds(120))
+        );
+    }
+}
@@ -0,0 +1,358 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use std::sync::{Arc, Mutex};
+
+use apistos::ApiComponent;
+use chrono::{DateTime, Utc};
+use reqwest::header::CONTENT_TYPE;
+use serde::Serialize;
+use tap::Pipe;
+use tokio::{
+    sync::{mpsc, oneshot, watch},
+    task::JoinHandle,
+    time::Instant,
+};
+use url::Url;
+
+use crate::{
+    config::Config,
+    error::{Error, Result},
+    schema::get_prom_schema,
+};
+
+/// The delay before the first retry of a failed push. The delay
+/// doubles with every retry, up to `MAX_BACKOFF`.
+const MIN_BACKOFF: std::time::Duration = std::time::Duration::from_secs(1);
+const MAX_BACKOFF: std::time::Duration = std::time::Duration::from_secs(300);
+
+/// Where the prometheus schema is pushed.
+#[derive(Clone, Debug)]
+pub struct SchemaPushTarget {
+    pub url: Url,
+    /// Sent as a bearer token, if set.
+    pub token: Option<String>,
+}
+
+/// The outcome of the schema pushes.
+#[derive(Serialize, schemars::JsonSchema, ApiComponent, PartialEq, Clone, Default, Debug)]
+pub struct SchemaPushStatus {
+    /// The time of the last successful push.
+    pub last_push: Option<DateTime<Utc>>,
+    /// The error of the last attempt, if it failed.
+    pub last_error: Option<String>,
+    /// The number of failed attempts since the last successful push.
+    pub failures: u32,
+}
+
+/// Pushes the prometheus schema of the config at startup and whenever
+/// the schema changes, on a background task. Failed pushes are retried
+/// with exponential backoff, unless the target rejected the request.
+#[derive(Debug)]
+pub struct SchemaPusher {
+    task: JoinHandle<()>,
+    term_sender: oneshot::Sender<()>,
+    push_sender: mpsc::Sender<oneshot::Sender<SchemaPushStatus>>,
+    status: Arc<Mutex<SchemaPushStatus>>,
+}
+
+impl SchemaPusher {
+    pub fn new(
+        client: reqwest::Client,
+        target: SchemaPushTarget,
+        config_receiver: watch::Receiver<Arc<Config>>,
+    ) -> Self {
+        Self::with_backoff(client, target, config_receiver, MIN_BACKOFF, MAX_BACKOFF)
+    }
+
+    fn with_backoff(
+        client: reqwest::Client,
+        target: SchemaPushTarget,
+        mut config_receiver: watch::Receiver<Arc<Config>>,
+        min_backoff: std::time::Duration,
+        max_backoff: std::time::Duration,
+    ) -> Self {
+        let (term_sender, mut term_receiver) = oneshot::channel::<()>();
+        let (push_sender, mut push_receiver) = mpsc::channel(4);
+        let status = Arc::new(Mutex::new(SchemaPushStatus::default()));
+
+        let task_status = status.clone();
+        let task = tokio::spawn(async move {
+            // The schema last pushed successfully, to skip config
+            // updates that do not change it.
+            let mut pushed = None;
+            let mut next = Some(Instant::now());
+            let mut backoff = min_backoff;
+            let mut waiting = Vec::<oneshot::Sender<SchemaPushStatus>>::new();
+
+            loop {
+                let deadline = next;
+                tokio::select! {
+                    _ = async {
+                        match deadline {
+                            Some(t) => tokio::time::sleep_until(t).await,
+                            None => std::future::pending().await,
+                        }
+                    } => {
+                        let config = config_receiver.borrow_and_update().clone();
+                        let result = match render(&config) {
+                            Ok(schema) => push(&client, &target, &schema).await.map(|()| schema),
+                            Err(e) => Err(e),
+                        };
+                        let status = {
+                            let mut status = task_status.lock().unwrap();
+                            match result {
+                                Ok(schema) => {
+                                    log::info!("prometheus schema pushed to {}", target.url);
+                                    pushed = Some(schema);
+                                    next = None;
+                                    backoff = min_backoff;
+                                    status.last_push = Some(Utc::now());
+                                    status.last_error = None;
+                                    status.failures = 0;
+                                }
+                                Err(e) => {
+                                    next = is_retryable(&e).then(|| Instant::now() + backoff);
+                                    match next {
+                                        Some(_) => log::warn!(
+                                            "{e}; retrying in {}s",
+                                            backoff.as_secs_f64()
+                                        ),
+                                        None => log::warn!("{e}"),
+                                    }
+                                    backoff = (backoff * 2).min(max_backoff);
+                                    status.last_error = Some(e.to_string());
+                                    status.failures += 1;
+                                }
+                            }
+                            status.clone()
+                        };
+                        waiting.drain(..).for_each(|sender| {
+                            let _ = sender.send(status.clone());
+                        });
+                    }
+                    Ok(()) = config_receiver.changed() => {
+                        let config = config_receiver.borrow_and_update().clone();
+                        if render(&config).ok() != pushed {
+                            next = Some(Instant::now());
+                            backoff = min_backoff;
+                        }
+                    }
+                    Some(sender) = push_receiver.recv() => {
+                        waiting.push(sender);
+                        next = Some(Instant::now());
+                        backoff = min_backoff;
+                    }
+                    _ = &mut term_receiver => {
+                        break;
+                    }
+                }
+            }
+        });
+
+        Self {
+            task,
+            term_sender,
+            push_sender,
+            status,
+        }
+    }
+
+    pub fn status(&self) -> SchemaPushStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    /// Push the schema of the current config now, even if it did not
+    /// change. Returns the status after the attempt.
+    pub async fn push(&self) -> Result<SchemaPushStatus> {
+        let (sender, receiver) = oneshot::channel();
+        self.push_sender
+            .send(sender)
+            .await
+            .map_err(|_| Error::SchemaPushStopped)?;
+        receiver.await.map_err(|_| Error::SchemaPushStopped)
+    }
+
+    pub async fn shutdown(self) -> Result<()> {
+        let _ = self.term_sender.send(());
+        self.task.await.map_err(Error::JoinSchemaPush)
+    }
+}
+
+/// The prometheus schema of the config, as YAML.
+fn render(config: &Config) -> Result<String> {
+    serde_yaml::to_string(&get_prom_schema(config)).map_err(Error::SerializeSchema)
+}
+
+async fn push(client: &reqwest::Client, target: &SchemaPushTarget, schema: &str) -> Result<()> {
+    let res = client
+        .put(target.url.clone())
+        .header(CONTENT_TYPE, "application/yaml")
+        .body(schema.to_string())
+        .pipe(|req| match &target.token {
+            Some(token) => req.bearer_auth(token),
+            None => req,
+        })
+        .send()
+        .await
+        .map_err(Error::SchemaPush)?;
+    let status = res.status();
+    if status.is_success() {
+        Ok(())
+    } else {
+        let body = res.text().await.unwrap_or_default();
+        Err(Error::SchemaPushStatus(status, body))
+    }
+}
+
+/// Connection errors, timeouts, server errors and rate limiting are
+/// retried; other rejections need a config or credentials change.
+fn is_retryable(e: &Error) -> bool {
+    match e {
+        Error::SchemaPush(_) => true,
+        Error::SchemaPushStatus(status, _) => {
+            status.is_server_error() || *status == reqwest::StatusCode::TOO_MANY_REQUESTS
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use url::Url;
+
+    use super::{SchemaPushTarget, SchemaPusher};
+    use crate::{config::Config, processor::fake_http::FakeHttp};
+
+    /// A server answering the requests with the given statuses, and
+    /// with 200 when they run out.
+    async fn stub_target(statuses: Vec<u16>) -> FakeHttp {
+        let mut statuses = statuses.into_iter();
+        FakeHttp::start("/api/schema/jaeger", move |_| {
+            (statuses.next().unwrap_or(200), String::new())
+        })
+        .await
+    }
+
+    fn pusher(
+        url: Url,
+        token: Option<&str>,
+        config_receiver: tokio::sync::watch::Receiver<Arc<Config>>,
+    ) -> SchemaPusher {
+        SchemaPusher::with_backoff(
+            reqwest::Client::new(),
+            SchemaPushTarget {
+                url,
+                token: token.map(String::from),
+            },
+            config_receiver,
+            std::time::Duration::from_millis(10),
+            std::time::Duration::from_millis(40),
+        )
+    }
+
+    async fn wait_for<F: Fn() -> bool>(f: F) {
+        let deadline = tokio::time::Instant::now() + std::time::Duration::from_secs(10);
+        while !f() {
+            assert!(tokio::time::Instant::now() < deadline, "timeout");
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn push_yaml() {
+        let server = stub_target(Vec::new()).await;
+        let (_config_sender, config_receiver) =
+            tokio::sync::watch::channel(Arc::new(Config::default()));
+        let pusher = pusher(server.url().clone(), Some("s3cr3t"), config_receiver);
+
+        wait_for(|| pusher.status().last_push.is_some()).await;
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        let request = &requests[0];
+        assert_eq!(request.method, "PUT");
+        assert_eq!(request.path(), "/api/schema/jaeger");
+        assert_eq!(request.header("content-type"), Some("application/yaml"));
+        assert_eq!(request.header("authorization"), Some("Bearer s3cr3t"));
+        assert_eq!(
+            request.text(),
+            serde_yaml::to_string(&crate::schema::get_prom_schema(&Config::default())).unwrap()
+        );
+
+        pusher.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn retry_with_backoff() {
+        let server = stub_target(vec![503, 502, 429]).await;
+        let (_config_sender, config_receiver) =
+            tokio::sync::watch::channel(Arc::new(Config::default()));
+        let pusher = pusher(server.url().clone(), None, config_receiver);
+
+        wait_for(|| pusher.status().last_push.is_some()).await;
+        let status = pusher.status();
+        assert_eq!(status.failures, 0);
+        assert_eq!(status.last_error, None);
+        let requests = server.requests();
+        assert_eq!(requests.len(), 4);
+        assert!(requests
+            .iter()
+            .all(|request| request.body == requests[0].body));
+        assert_eq!(requests[0].header("authorization"), None);
+
+        pusher.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejected_push() {
+        let server = stub_target(vec![400]).await;
+        let (_config_sender, config_receiver) =
+            tokio::sync::watch::channel(Arc::new(Config::default()));
+        let pusher = pusher(server.url().clone(), None, config_receiver);
+
+        wait_for(|| pusher.status().failures == 1).await;
+        // Client errors are not retried...
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert_eq!(server.requests().len(), 1);
+        assert!(pusher.status().last_error.unwrap().contains("400"));
+
+        // ... until the push is forced.
+        let status = pusher.push().await.unwrap();
+        assert!(status.last_push.is_some());
+        assert_eq!(status.failures, 0);
+        assert_eq!(server.requests().len(), 2);
+
+        pusher.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn push_on_schema_change() {
+        let server = stub_target(Vec::new()).await;
+        let (config_sender, config_receiver) =
+            tokio::sync::watch::channel(Arc::new(Config::default()));
+        let pusher = pusher(server.url().clone(), None, config_receiver);
+        wait_for(|| server.requests().len() == 1).await;
+
+        // Changes that do not affect the schema are not pushed.
+        config_sender
+            .send(Arc::new(Config {
+                max_series: Some(100),
+                ..Config::default()
+            }))
+            .unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert_eq!(server.requests().len(), 1);
+
+        let mut config = Config::default();
+        config.trace = config.trace.with_request_path_relations();
+        config_sender.send(Arc::new(config)).unwrap();
+        wait_for(|| server.requests().len() == 2).await;
+        let requests = server.requests();
+        assert_ne!(requests[0].body, requests[1].body);
+
+        pusher.shutdown().await.unwrap();
+    }
+}
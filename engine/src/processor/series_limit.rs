@@ -0,0 +1,81 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use std::{collections::BTreeMap, sync::Mutex};
+
+use serde::Serialize;
+
+use crate::config::ConfigName;
+
+/// Priority classes of emitted series, from lowest to highest. When a
+/// sample exceeds the `max_series` limit, series are dropped lowest
+/// class first: histogram buckets, then summary quantiles, then
+/// welford internals, then counts and sums, keeping anomaly scores
+/// last. Self-monitoring series are never dropped.
+#[derive(Serialize, schemars::JsonSchema, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum SeriesPriority {
+    Histogram,
+    Summary,
+    Welford,
+    Count,
+    AnomalyScore,
+    SelfMonitoring,
+}
+
+impl SeriesPriority {
+    /// The priority class of a series, given its "metric_type" label.
+    pub fn of(metric_type: &str) -> Self {
+        match metric_type {
+            "histogram" => Self::Histogram,
+            "summary" => Self::Summary,
+            "welford" => Self::Welford,
+            "anomaly_score" => Self::AnomalyScore,
+            "self_monitoring" => Self::SelfMonitoring,
+            // "count_sum", "source_count"
+            _ => Self::Count,
+        }
+    }
+}
+
+/// Series counts for a single sample.
+#[derive(Serialize, schemars::JsonSchema, PartialEq, Eq, Default, Clone, Debug)]
+pub struct SeriesReport {
+    /// The number of distinct series sampled, per config, before
+    /// applying the series limit.
+    pub series: BTreeMap<ConfigName, u64>,
+    /// The number of series dropped to stay within the series limit,
+    /// per config.
+    pub dropped: BTreeMap<ConfigName, u64>,
+    /// The number of series dropped, per priority class.
+    pub dropped_by_priority: BTreeMap<SeriesPriority, u64>,
+}
+
+impl SeriesReport {
+    pub fn total(&self) -> u64 {
+        self.series.values().sum()
+    }
+
+    pub fn total_dropped(&self) -> u64 {
+        self.dropped.values().sum()
+    }
+}
+
+/// The series report of the last sample, shared between the processor
+/// task and the web server.
+#[derive(Default, Debug)]
+pub struct SeriesStats {
+    last: Mutex<Option<SeriesReport>>,
+}
+
+impl SeriesStats {
+    pub fn set(&self, report: SeriesReport) {
+        *self.last.lock().unwrap() = Some(report);
+    }
+
+    /// The report for the last sample.
+    pub fn last(&self) -> Option<SeriesReport> {
+        self.last.lock().unwrap().clone()
+    }
+}
@@ -0,0 +1,230 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use std::{
+    fmt::Display,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::{Error, Result},
+    opensearch::EsError,
+};
+
+/// The multiplier of the jump consistent hash (Lamping and Veach).
+const JUMP_MULTIPLIER: u64 = 2862933555777941757;
+
+/// The root span filter, in painless. It must compute the same bucket
+/// as `Shard::bucket`: the Java hash code of the trace id, sign
+/// extended, as key of a jump consistent hash.
+const SHARD_SCRIPT: &str = "long key = doc['traceID'].value.hashCode(); \
+     long b = -1; long j = 0; \
+     while (j < params.count) { \
+         b = j; key = key * 2862933555777941757L + 1; \
+         j = (long) ((b + 1) * ((double) (1L << 31) / (double) ((key >>> 33) + 1))); \
+     } \
+     return b == params.index;";
+
+/// The part of the traces processed by this replica, when the engine
+/// runs as several replicas. Traces are assigned to shards by a hash
+/// of their trace id, so every replica sees (part of) every group; the
+/// written series are told apart by a `shard` label.
+///
+/// The hash is a jump consistent hash, so that a change of the shard
+/// count moves as few traces as possible. The assignment must never
+/// change for a given count: the replicas agree on it without talking
+/// to each other, and OpenSearch computes it in `SHARD_SCRIPT`.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug)]
+pub struct Shard {
+    pub index: u32,
+    pub count: u32,
+}
+
+/// The shard filter of the root span queries. Filtering happens in
+/// OpenSearch, by a script query, until OpenSearch rejects the script
+/// (e.g. because inline scripts are disabled); from then on, all root
+/// spans are fetched and filtered here.
+#[derive(Debug)]
+pub struct ShardFilter {
+    shard: Shard,
+    server_side: AtomicBool,
+}
+
+impl Shard {
+    /// The shard given on the command line, if the engine is sharded.
+    pub fn new(index: u32, count: u32) -> Result<Option<Self>> {
+        if index >= count {
+            return Err(Error::InvalidShard(index, count));
+        }
+        Ok((count > 1).then_some(Self { index, count }))
+    }
+
+    /// The shard of a trace, out of `count` shards.
+    pub fn bucket(trace_id: &str, count: u32) -> u32 {
+        jump_hash(java_hash(trace_id) as i64 as u64, count)
+    }
+
+    pub fn contains(&self, trace_id: &str) -> bool {
+        Self::bucket(trace_id, self.count) == self.index
+    }
+
+    /// The root span query selecting the traces of the shard.
+    pub fn query(&self) -> serde_json::Value {
+        serde_json::json!({
+            "script": {
+                "script": {
+                    "lang": "painless",
+                    "source": SHARD_SCRIPT,
+                    "params": { "index": self.index, "count": self.count }
+                }
+            }
+        })
+    }
+}
+
+impl Display for Shard {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.index, self.count)
+    }
+}
+
+impl ShardFilter {
+    pub fn new(shard: Shard) -> Self {
+        Self {
+            shard,
+            server_side: AtomicBool::new(true),
+        }
+    }
+
+    pub fn shard(&self) -> Shard {
+        self.shard
+    }
+
+    /// The filter to add to the root span query, while OpenSearch
+    /// accepts it.
+    pub fn query(&self) -> Option<serde_json::Value> {
+        self.server_side
+            .load(Ordering::Relaxed)
+            .then(|| self.shard.query())
+    }
+
+    /// Switch to filtering here if `error` is OpenSearch rejecting
+    /// the script. Returns whether the query should be retried.
+    pub fn fall_back(&self, error: &EsError) -> bool {
+        let rejected = error.is_script_error() && self.server_side.swap(false, Ordering::Relaxed);
+        if rejected {
+            log::warn!(
+                "opensearch rejected the shard filter ({error}); \
+                 filtering root spans of shard {} after fetching",
+                self.shard
+            );
+        }
+        rejected
+    }
+
+    /// Whether a fetched root span belongs to the shard.
+    pub fn keeps(&self, trace_id: &str) -> bool {
+        self.server_side.load(Ordering::Relaxed) || self.shard.contains(trace_id)
+    }
+}
+
+/// `String.hashCode()` in Java (and painless): the UTF-16 code units
+/// as base-31 digits, wrapping.
+fn java_hash(s: &str) -> i32 {
+    s.encode_utf16()
+        .fold(0i32, |h, c| h.wrapping_mul(31).wrapping_add(c as i32))
+}
+
+/// The jump consistent hash of `key` into `buckets` buckets. Growing
+/// the number of buckets only moves keys into the new buckets.
+fn jump_hash(mut key: u64, buckets: u32) -> u32 {
+    let (mut b, mut j) = (-1i64, 0i64);
+    while j < i64::from(buckets) {
+        b = j;
+        key = key.wrapping_mul(JUMP_MULTIPLIER).wrapping_add(1);
+        j = ((b + 1) as f64 * ((1i64 << 31) as f64 / ((key >> 33) + 1) as f64)) as i64;
+    }
+    b as u32
+}
+
+#[cfg(test)]
+mod test {
+    use crate::opensearch::EsError;
+
+    use super::{java_hash, Shard, ShardFilter};
+
+    /// The assignment of traces to shards is shared with OpenSearch
+    /// and with the other replicas; it must not change.
+    #[test]
+    fn stable_hash() {
+        assert_eq!(java_hash("672633d1537fb110"), -1094997747);
+        assert_eq!(java_hash("0de61f1de7ee678bccb46f3dab804867"), 1531379547);
+        for (trace_id, buckets) in [
+            ("0de61f1de7ee678bccb46f3dab804867", [0, 0, 2, 2, 4]),
+            ("1de61f1de7ee678bccb46f3dab804867", [0, 1, 2, 3, 3]),
+            ("2de61f1de7ee678bccb46f3dab804867", [0, 1, 2, 2, 2]),
+            ("672633d1537fb110", [0, 0, 2, 2, 2]),
+        ] {
+            for (count, bucket) in (1..).zip(buckets) {
+                assert_eq!(Shard::bucket(trace_id, count), bucket, "{trace_id}/{count}");
+            }
+        }
+    }
+
+    #[test]
+    fn balanced_and_consistent() {
+        let trace_ids = (0..3000).map(|i| format!("{i:032x}")).collect::<Vec<_>>();
+        let mut counts = [0; 3];
+        trace_ids
+            .iter()
+            .for_each(|trace_id| counts[Shard::bucket(trace_id, 3) as usize] += 1);
+        assert!(counts.iter().all(|n| (900..1100).contains(n)), "{counts:?}");
+        // Adding a shard only moves traces to the new shard.
+        assert!(trace_ids.iter().all(|trace_id| {
+            let bucket = Shard::bucket(trace_id, 4);
+            bucket == 3 || bucket == Shard::bucket(trace_id, 3)
+        }));
+    }
+
+    #[test]
+    fn shard_args() {
+        assert_eq!(Shard::new(0, 1).unwrap(), None);
+        assert_eq!(
+            Shard::new(2, 3).unwrap(),
+            Some(Shard { index: 2, count: 3 })
+        );
+        assert!(Shard::new(3, 3).is_err());
+        assert!(Shard::new(0, 0).is_err());
+    }
+
+    #[test]
+    fn client_side_fallback() {
+        let filter = ShardFilter::new(Shard { index: 0, count: 2 });
+        let query = filter.query().unwrap();
+        assert_eq!(query["script"]["script"]["params"]["count"], 2);
+        // Filtering is left to OpenSearch.
+        assert!(filter.keeps("1de61f1de7ee678bccb46f3dab804867"));
+
+        let error = |r#type: &str, reason: &str| {
+            serde_json::from_value::<EsError>(serde_json::json!({
+                "status": 400,
+                "error": { "type": r#type, "reason": reason }
+            }))
+            .unwrap()
+        };
+        assert!(!filter.fall_back(&error("parsing_exception", "unknown query")));
+        assert!(filter.query().is_some());
+        assert!(filter.fall_back(&error(
+            "illegal_argument_exception",
+            "cannot execute [inline] scripts"
+        )));
+        assert!(filter.query().is_none());
+        assert!(filter.keeps("0de61f1de7ee678bccb46f3dab804867"));
+        assert!(!filter.keeps("1de61f1de7ee678bccb46f3dab804867"));
+        // Only the first rejection is retried.
+        assert!(!filter.fall_back(&error("script_exception", "compile error")));
+    }
+}
@@ -0,0 +1,645 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+//! A deterministic simulation of the processing loop, for tests.
+//! Trace fixtures are fed to the trace processor exactly like
+//! `process_traces` does, on a virtual clock and without opensearch
+//! or prometheus.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::Path,
+};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use serde_json::json;
+
+use crate::{
+    config::{ConfigName, IngestFilter, KeyName, MetricName, SpanKey, SpanSelector},
+    jaeger::Span,
+    metrics::Metrics,
+};
+
+use super::{
+    metric::MetricConfig,
+    sampling::{cleanup_time, sample_metrics, Sampler},
+    source::MetricSource,
+    span::{default_carry_over_age, default_max_sampling_weight, MissingKey, SpanConfig},
+    stats::StatsConfig,
+    trace::{Rule, TraceConfig, TraceProcessor},
+    trace_level::TraceMetricsConfig,
+};
+
+/// An emitted sample: series labels, timestamp and value.
+pub type Sample = (BTreeMap<String, String>, DateTime<Utc>, f64);
+
+pub struct PipelineSim {
+    processor: TraceProcessor,
+    filter: IngestFilter,
+    interval: TimeDelta,
+    sequential_insert: bool,
+    max_series: Option<usize>,
+    from: DateTime<Utc>,
+    traces: Vec<(DateTime<Utc>, Vec<Span>)>,
+    samples: Vec<Sample>,
+}
+
+impl PipelineSim {
+    /// Simulate processing starting at `from`, sampling every
+    /// `interval`. Traces are given with the start time of their root
+    /// span.
+    pub fn new(
+        config: &TraceConfig,
+        from: DateTime<Utc>,
+        interval: TimeDelta,
+        mut traces: Vec<(DateTime<Utc>, Vec<Span>)>,
+    ) -> Self {
+        traces.sort_by_key(|(t, _)| *t);
+        Self {
+            processor: TraceProcessor::new(config),
+            filter: IngestFilter::default(),
+            interval,
+            sequential_insert: false,
+            max_series: None,
+            from,
+            traces,
+            samples: Vec::new(),
+        }
+    }
+
+    pub fn with_filter(mut self, filter: IngestFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    pub fn with_sequential_insert(mut self) -> Self {
+        self.sequential_insert = true;
+        self
+    }
+
+    pub fn with_max_series(mut self, max_series: usize) -> Self {
+        self.max_series = Some(max_series);
+        self
+    }
+
+    /// Run one processing tick from the end of the previous tick up
+    /// to `to`. Unlike `process_traces`, samples are never skipped for
+    /// being too old, since there is no wall clock.
+    pub fn tick(&mut self, to: DateTime<Utc>) {
+        let from = std::mem::replace(&mut self.from, to);
+        let n = self.traces.partition_point(|(t, _)| *t < to);
+        let traces = self
+            .traces
+            .drain(..n)
+            .filter(|(t, _)| *t >= from)
+            .collect::<Vec<_>>();
+
+        let mut sampler = Sampler::new(from, self.processor.last_sample(), self.interval);
+        let mut metrics = Metrics::new();
+        let mut batch = Vec::new();
+        for (t, trace) in &traces {
+            if sampler.is_due(*t) {
+                self.insert(&batch);
+                batch.clear();
+            }
+            while let Some(sample_time) = sampler.take_due(*t) {
+                sample_metrics(
+                    &mut self.processor,
+                    sample_time,
+                    &mut metrics,
+                    self.max_series,
+                );
+            }
+            batch.push((*t, trace.as_slice()));
+        }
+        self.insert(&batch);
+
+        while let Some(sample_time) = sampler.take_due(to) {
+            sample_metrics(
+                &mut self.processor,
+                sample_time,
+                &mut metrics,
+                self.max_series,
+            );
+        }
+
+        self.samples.extend(metrics.drain());
+        self.samples
+            .extend(self.processor.cleanup(cleanup_time(to)).drain());
+        self.processor.compact_idle(to);
+    }
+
+    fn insert(&mut self, batch: &[(DateTime<Utc>, &[Span])]) {
+        if self.sequential_insert {
+            batch.iter().for_each(|(t, spans)| {
+                self.processor.insert(*t, spans, &self.filter);
+            });
+        } else {
+            self.processor.insert_batch(batch, &self.filter);
+        }
+    }
+
+    /// All samples emitted so far.
+    pub fn samples(&self) -> &[Sample] {
+        &self.samples
+    }
+
+    /// The samples of the named metric with the given labels, ordered
+    /// by timestamp.
+    pub fn series(&self, name: &str, labels: &[(&str, &str)]) -> Vec<(DateTime<Utc>, f64)> {
+        let mut series = self
+            .samples
+            .iter()
+            .filter(|(series_labels, _, _)| {
+                series_labels.get("__name__").map(String::as_str) == Some(name)
+                    && labels.iter().all(|(label, value)| {
+                        series_labels.get(*label).map(String::as_str) == Some(*value)
+                    })
+            })
+            .map(|(_, t, value)| (*t, *value))
+            .collect::<Vec<_>>();
+        series.sort_by_key(|(t, _)| *t);
+        series
+    }
+}
+
+/// The fixed start time used by the processor tests.
+pub fn start() -> DateTime<Utc> {
+    DateTime::from_timestamp(1_699_999_200, 0).unwrap()
+}
+
+pub fn span(
+    trace_id: &str,
+    span_id: &str,
+    parent: Option<&str>,
+    service: &str,
+    operation: &str,
+    start_time: i64,
+    duration: i64,
+) -> Span {
+    serde_json::from_value(span_doc(
+        trace_id, span_id, parent, service, operation, start_time, duration,
+    ))
+    .unwrap()
+}
+
+/// The document of a span, as stored in OpenSearch.
+pub fn span_doc(
+    trace_id: &str,
+    span_id: &str,
+    parent: Option<&str>,
+    service: &str,
+    operation: &str,
+    start_time: i64,
+    duration: i64,
+) -> serde_json::Value {
+    json!({
+        "traceID": trace_id,
+        "spanID": span_id,
+        "operationName": operation,
+        "references": parent.map_or_else(Vec::new, |parent| vec![json!({
+            "refType": "CHILD_OF",
+            "traceID": trace_id,
+            "spanID": parent
+        })]),
+        "startTime": start_time,
+        "startTimeMillis": start_time / 1000,
+        "duration": duration,
+        "tags": [
+            { "key": "busy_ns", "type": "int64", "value": (duration * 400).to_string() },
+            { "key": "thread.id", "type": "int64", "value": "1" }
+        ],
+        "logs": [],
+        "process": {
+            "serviceName": service,
+            "tags": [
+                { "key": "service.namespace", "type": "string", "value": "test" },
+                { "key": "service.instance.id", "type": "string", "value": "test-0" }
+            ]
+        }
+    })
+}
+
+/// Synthetic three-level traces (frontend -> backend -> database),
+/// one every 100ms starting at `start`. Trace `i` has a frontend span
+/// of `3d`, a backend span of `2d` and a database span of `d/2`, with
+/// `d = 1000 + (i * 7919) % 5000`.
+pub fn synthetic_traces(start: DateTime<Utc>, n: usize) -> Vec<Vec<Span>> {
+    synthetic_docs(start, n)
+        .into_iter()
+        .map(|trace| {
+            trace
+                .into_iter()
+                .map(|doc| serde_json::from_value(doc).unwrap())
+                .collect()
+        })
+        .collect()
+}
+
+/// The documents of `synthetic_traces`, as stored in OpenSearch.
+pub fn synthetic_docs(start: DateTime<Utc>, n: usize) -> Vec<Vec<serde_json::Value>> {
+    (0..n)
+        .map(|i| {
+            let trace_id = format!("{i:032x}");
+            let t = start.timestamp_micros() + i as i64 * 100_000;
+            let d = 1000 + (i as i64 * 7919) % 5000;
+            vec![
+                span_doc(&trace_id, "1", None, "frontend", "GET", t, 3 * d),
+                span_doc(
+                    &trace_id,
+                    "2",
+                    Some("1"),
+                    "backend",
+                    "POST",
+                    t + d / 2,
+                    2 * d,
+                ),
+                span_doc(
+                    &trace_id,
+                    "3",
+                    Some("2"),
+                    "database",
+                    "SELECT",
+                    t + d,
+                    d / 2,
+                ),
+            ]
+        })
+        .collect()
+}
+
+/// Group span documents into traces, root span first, in the order
+/// of the root spans.
+pub fn parse_traces(docs: &[serde_json::Value]) -> Vec<Vec<Span>> {
+    let mut traces = BTreeMap::<String, Vec<Span>>::new();
+    docs.iter().for_each(|doc| {
+        let span = serde_json::from_value::<Span>(doc.clone()).unwrap();
+        traces
+            .entry(span.trace_id.to_string())
+            .or_default()
+            .push(span);
+    });
+    let mut traces = traces
+        .into_values()
+        .map(|mut trace| {
+            trace.sort_by_key(|span| (!span.references.is_empty(), span.start_time));
+            trace
+        })
+        .collect::<Vec<_>>();
+    traces.sort_by_key(|trace| trace[0].start_time);
+    traces
+}
+
+/// Read a JSON fixture from `engine/tests/fixtures`.
+pub fn load_fixture(name: &str) -> serde_json::Value {
+    serde_json::from_slice(&read_fixture(name)).unwrap()
+}
+
+/// Read a fixture from `engine/tests/fixtures`, as bytes.
+pub fn read_fixture(name: &str) -> Vec<u8> {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(name);
+    std::fs::read(&path).unwrap_or_else(|e| panic!("failed to read {}: {e}", path.display()))
+}
+
+/// Compare `lines` to the golden file `engine/tests/golden/<name>`.
+/// With `UPDATE_GOLDEN` set, the golden file is rewritten instead.
+pub fn assert_golden(name: &str, lines: &[String]) {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/golden")
+        .join(name);
+    let actual = lines
+        .iter()
+        .map(|line| format!("{line}\n"))
+        .collect::<String>();
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        std::fs::write(&path, actual).unwrap();
+        return;
+    }
+    let expected = std::fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("failed to read {}: {e}", path.display()));
+    assert_eq!(
+        actual,
+        expected,
+        "{} differs; rerun with UPDATE_GOLDEN=1 to update it",
+        path.display()
+    );
+}
+
+/// Compare `data` to the fixture `engine/tests/fixtures/<name>`. With
+/// `UPDATE_GOLDEN` set, the fixture is rewritten instead.
+pub fn assert_fixture(name: &str, data: &[u8]) {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(name);
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        std::fs::write(&path, data).unwrap();
+        return;
+    }
+    let expected = std::fs::read(&path).unwrap_or_default();
+    assert!(
+        expected == data,
+        "{} differs or is missing; rerun with UPDATE_GOLDEN=1 to update it",
+        path.display()
+    );
+}
+
+/// A config with a single rule sending the spans matching `select`
+/// to the "default" config, grouped by service, with a "duration"
+/// metric from `source`.
+pub fn trace_config(select: SpanSelector, source: MetricSource, stats: StatsConfig) -> TraceConfig {
+    TraceConfig {
+        rules: vec![vec![Rule {
+            select,
+            config: ConfigName::new("default"),
+            priority: None,
+            stop: true,
+        }]],
+        configs: BTreeMap::from_iter([(
+            ConfigName::new("default"),
+            SpanConfig {
+                key: BTreeSet::from_iter([SpanKey::Current(KeyName::ServiceName)]),
+                metrics: BTreeMap::from_iter([(
+                    MetricName::new("duration"),
+                    MetricConfig { source, stats, enabled: true },
+                )]),
+                carry_over: BTreeSet::new(),
+                carry_over_age: default_carry_over_age(),
+                pushdown: false,
+                normalize_numbers: false,
+                annotations: BTreeSet::new(),
+                idle_after: None,
+                respect_sampling: false,
+                max_sampling_weight: default_max_sampling_weight(),
+                classify: BTreeMap::new(),
+                pseudonymize: Vec::new(),
+                late_bins: None,
+                missing_key: MissingKey::Omit,
+                enabled: true,
+            },
+        )]),
+        trace_metrics: TraceMetricsConfig {
+            key: BTreeSet::new(),
+            metrics: BTreeMap::new(),
+        },
+        dedup: None,
+        maintenance: Vec::new(),
+    }
+}
+
+/// Attach the root span start time to each trace. The root span must
+/// come first.
+pub fn fixtures(traces: Vec<Vec<Span>>) -> Vec<(DateTime<Utc>, Vec<Span>)> {
+    traces
+        .into_iter()
+        .map(|trace| {
+            let t = DateTime::from_timestamp_micros(trace[0].start_time).unwrap();
+            (t, trace)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use chrono::TimeDelta;
+
+    use super::{fixtures, start, synthetic_traces, trace_config, PipelineSim};
+    use crate::{
+        config::{IngestFilter, KeyName, SpanKey, SpanSelector, ValueMatch},
+        processor::{
+            histogram::HistogramConfig, mean_stddev::MeanStddevConfig, source::MetricSource,
+            stats::StatsConfig,
+        },
+    };
+
+    fn mean_stddev() -> StatsConfig {
+        StatsConfig {
+            anomaly_score: None,
+            mean_stddev: Some(MeanStddevConfig::default()),
+            summary: None,
+            histogram: None,
+            ..StatsConfig::default()
+        }
+    }
+
+    /// The `d` parameter of the synthetic traces.
+    fn d(i: usize) -> i64 {
+        1000 + (i as i64 * 7919) % 5000
+    }
+
+    fn mean(values: impl Iterator<Item = i64>) -> f64 {
+        let (n, sum) = values.fold((0, 0), |(n, sum), v| (n + 1, sum + v));
+        sum as f64 / n as f64
+    }
+
+    #[test]
+    fn self_duration() {
+        let config = trace_config(
+            SpanSelector::All(Vec::new()),
+            MetricSource::SelfDuration,
+            mean_stddev(),
+        );
+        let traces = || fixtures(synthetic_traces(start(), 600));
+        let mut sim = PipelineSim::new(&config, start(), TimeDelta::seconds(10), traces());
+        sim.tick(start() + TimeDelta::seconds(61));
+
+        let mut sequential = PipelineSim::new(&config, start(), TimeDelta::seconds(10), traces())
+            .with_sequential_insert();
+        sequential.tick(start() + TimeDelta::seconds(61));
+        assert_eq!(sequential.samples(), sim.samples());
+
+        // Traces starting exactly at a sample time are inserted before
+        // that sample is taken.
+        let counts = sim.series(
+            "trace_duration_count",
+            &[("config", "default"), ("service_name", "frontend")],
+        );
+        assert_eq!(
+            counts,
+            [
+                (10, 101.0),
+                (20, 201.0),
+                (30, 301.0),
+                (40, 401.0),
+                (50, 501.0),
+                (60, 600.0)
+            ]
+            .map(|(s, n)| (start() + TimeDelta::seconds(s), n))
+        );
+
+        // Time spent in child spans is not counted.
+        for (service, expected) in [
+            ("frontend", mean((0..600).map(d))),
+            ("backend", mean((0..600).map(|i| 2 * d(i) - d(i) / 2))),
+            ("database", mean((0..600).map(|i| d(i) / 2))),
+        ] {
+            let series = sim.series(
+                "trace_duration_mean",
+                &[("config", "default"), ("service_name", service)],
+            );
+            let (_, value) = series.last().unwrap();
+            assert!(
+                (value - expected).abs() < 1e-6,
+                "{service}: {value} != {expected}"
+            );
+        }
+    }
+
+    #[test]
+    fn selector() {
+        let config = trace_config(
+            SpanSelector::In(
+                SpanKey::Current(KeyName::ServiceName),
+                ["backend", "database"]
+                    .iter()
+                    .map(|s| s.to_string())
+                    .collect(),
+            ),
+            MetricSource::Duration,
+            mean_stddev(),
+        );
+        let traces = || fixtures(synthetic_traces(start(), 100));
+        let counts = |sim: &PipelineSim| {
+            sim.samples()
+                .iter()
+                .filter(|(labels, _, _)| labels["__name__"] == "trace_duration_count")
+                .map(|(labels, _, value)| (labels["service_name"].clone(), *value))
+                .collect::<BTreeMap<_, _>>()
+        };
+
+        let mut sim = PipelineSim::new(&config, start(), TimeDelta::seconds(10), traces());
+        sim.tick(start() + TimeDelta::seconds(11));
+        assert_eq!(
+            counts(&sim),
+            BTreeMap::from_iter([
+                (String::from("backend"), 100.0),
+                (String::from("database"), 100.0)
+            ])
+        );
+
+        // The ingest filter applies to the root span of the trace.
+        let mut filtered = PipelineSim::new(&config, start(), TimeDelta::seconds(10), traces())
+            .with_filter(IngestFilter {
+                exclude_services: vec![ValueMatch::Eq(String::from("frontend"))],
+                ..IngestFilter::default()
+            });
+        filtered.tick(start() + TimeDelta::seconds(11));
+        assert!(counts(&filtered).is_empty());
+    }
+
+    #[test]
+    fn histogram() {
+        let mut sim = PipelineSim::new(
+            &trace_config(
+                SpanSelector::All(Vec::new()),
+                MetricSource::Duration,
+                StatsConfig {
+                    anomaly_score: None,
+                    mean_stddev: None,
+                    summary: None,
+                    histogram: Some(HistogramConfig {
+                        bounds: vec![1000.0, 2000.0, 5000.0],
+                    }),
+                    ..StatsConfig::default()
+                },
+            ),
+            start(),
+            TimeDelta::seconds(10),
+            fixtures(synthetic_traces(start(), 100)),
+        );
+        sim.tick(start() + TimeDelta::seconds(11));
+
+        // Buckets are cumulative.
+        let durations = (0..100).map(|i| d(i) / 2).collect::<Vec<_>>();
+        for le in [1000, 2000, 5000] {
+            let series = sim.series(
+                "trace_duration_buckets",
+                &[
+                    ("service_name", "database"),
+                    ("le", le.to_string().as_str()),
+                ],
+            );
+            let expected = durations.iter().filter(|d| **d <= le).count() as f64;
+            assert_eq!(series, [(start() + TimeDelta::seconds(10), expected)]);
+        }
+        assert_eq!(
+            sim.series("trace_duration_count", &[("service_name", "database")]),
+            [(start() + TimeDelta::seconds(10), 100.0)]
+        );
+        assert_eq!(
+            sim.series("trace_duration_sum", &[("service_name", "database")]),
+            [(
+                start() + TimeDelta::seconds(10),
+                durations.iter().sum::<i64>() as f64
+            )]
+        );
+    }
+
+    #[test]
+    fn max_series_drop_order() {
+        let config = trace_config(
+            SpanSelector::All(Vec::new()),
+            MetricSource::Duration,
+            StatsConfig {
+                histogram: Some(HistogramConfig {
+                    bounds: vec![1000.0, 2000.0, 5000.0],
+                }),
+                ..StatsConfig::default()
+            },
+        );
+        // Series per metric type, for a tick with a single sample.
+        let run = |max_series: Option<usize>| {
+            let sim = PipelineSim::new(
+                &config,
+                start(),
+                TimeDelta::seconds(10),
+                fixtures(synthetic_traces(start(), 100)),
+            );
+            let mut sim = match max_series {
+                Some(max_series) => sim.with_max_series(max_series),
+                None => sim,
+            };
+            sim.tick(start() + TimeDelta::seconds(11));
+            let mut counts = BTreeMap::<String, usize>::new();
+            sim.samples().iter().for_each(|(labels, _, _)| {
+                *counts.entry(labels["metric_type"].clone()).or_default() += 1;
+            });
+            counts
+        };
+
+        let all = run(None);
+        for metric_type in ["histogram", "summary", "welford", "self_monitoring"] {
+            assert!(all.contains_key(metric_type), "{metric_type}: {all:?}");
+        }
+        let total = all.values().sum::<usize>();
+
+        // Lowest priority series go first, one class at a time.
+        let limited = run(Some(total - 1));
+        assert_eq!(limited["histogram"], all["histogram"] - 1);
+        let mut removed = Vec::new();
+        for metric_type in ["histogram", "summary", "welford"] {
+            removed.push(metric_type);
+            let max_series = all
+                .iter()
+                .filter(|(t, _)| !removed.contains(&t.as_str()))
+                .map(|(_, n)| n)
+                .sum::<usize>();
+            let expected = all
+                .iter()
+                .filter(|(t, _)| !removed.contains(&t.as_str()))
+                .map(|(t, n)| (t.clone(), *n))
+                .collect::<BTreeMap<_, _>>();
+            assert_eq!(run(Some(max_series)), expected, "without {removed:?}");
+        }
+
+        // Self-monitoring series are never dropped.
+        assert_eq!(
+            run(Some(0)),
+            BTreeMap::from_iter([(String::from("self_monitoring"), all["self_monitoring"])])
+        );
+    }
+}
@@ -0,0 +1,203 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use url::Url;
+
+use crate::{
+    error::{Error, Result},
+    metrics::Metrics,
+};
+
+/// Where to write the samples.
+#[derive(clap::ValueEnum, Serialize, schemars::JsonSchema, PartialEq, Eq, Clone, Copy, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum SinkKind {
+    /// Prometheus remote write, to `--prometheus-url`.
+    RemoteWrite,
+    /// JSON lines appended to `--metrics-file`.
+    File,
+    /// Nowhere: samples are counted and discarded (dry run).
+    Null,
+}
+
+#[derive(Debug)]
+pub enum MetricsSink {
+    RemoteWrite { client: reqwest::Client, url: Url },
+    File(Mutex<FileSink>),
+    Null,
+}
+
+/// Appends samples to a file, one JSON object per line. When the file
+/// would grow beyond `max_bytes`, it is rotated: `<path>` is renamed
+/// to `<path>.1`, `<path>.1` to `<path>.2` and so on, keeping at most
+/// `keep` rotated files.
+#[derive(Debug)]
+pub struct FileSink {
+    path: PathBuf,
+    max_bytes: u64,
+    keep: usize,
+    file: Option<BufWriter<File>>,
+    size: u64,
+}
+
+#[derive(Serialize)]
+struct Sample<'a> {
+    timestamp: DateTime<Utc>,
+    labels: &'a BTreeMap<String, String>,
+    /// Staleness markers (NaN) are written as null.
+    value: f64,
+}
+
+impl FileSink {
+    pub fn new(path: PathBuf, max_bytes: u64, keep: usize) -> Self {
+        Self {
+            path,
+            max_bytes,
+            keep,
+            file: None,
+            size: 0,
+        }
+    }
+
+    pub fn write(&mut self, mut metrics: Metrics) -> Result<()> {
+        self.write_samples(&mut metrics)
+            .map_err(|e| Error::WriteMetricsFile(self.path.clone(), e))
+    }
+
+    fn write_samples(&mut self, metrics: &mut Metrics) -> std::io::Result<()> {
+        for (labels, timestamp, value) in metrics.drain() {
+            let mut line = serde_json::to_vec(&Sample {
+                timestamp,
+                labels: &labels,
+                value,
+            })?;
+            line.push(b'\n');
+            self.open()?;
+            if self.size > 0 && self.size + line.len() as u64 > self.max_bytes {
+                self.rotate()?;
+            }
+            self.open()?.write_all(&line)?;
+            self.size += line.len() as u64;
+        }
+        if let Some(file) = &mut self.file {
+            file.flush()?;
+        }
+        Ok(())
+    }
+
+    fn open(&mut self) -> std::io::Result<&mut BufWriter<File>> {
+        if self.file.is_none() {
+            let file = File::options().create(true).append(true).open(&self.path)?;
+            self.size = file.metadata()?.len();
+            self.file = Some(BufWriter::new(file));
+        }
+        Ok(self.file.as_mut().unwrap())
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        if let Some(mut file) = self.file.take() {
+            file.flush()?;
+        }
+        if self.keep == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            for i in (1..self.keep).rev() {
+                let from = rotated(&self.path, i);
+                if from.exists() {
+                    std::fs::rename(from, rotated(&self.path, i + 1))?;
+                }
+            }
+            std::fs::rename(&self.path, rotated(&self.path, 1))?;
+        }
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn rotated(path: &Path, i: usize) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(format!(".{i}"));
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use chrono::DateTime;
+
+    use super::{rotated, FileSink};
+    use crate::metrics::Metrics;
+
+    fn metrics(n: usize) -> Metrics {
+        let t = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let mut metrics = Metrics::new();
+        (0..n).for_each(|i| {
+            let labels = BTreeMap::from_iter([
+                (String::from("__name__"), String::from("m")),
+                (String::from("i"), format!("{i:03}")),
+            ]);
+            metrics.insert(labels, t, i as f64);
+        });
+        metrics
+    }
+
+    fn lines(path: &std::path::Path) -> Vec<serde_json::Value> {
+        std::fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn file_sink_rotation() {
+        let dir = std::env::temp_dir().join(format!("metrics-sink-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("metrics.jsonl");
+        let _ = std::fs::remove_file(&path);
+        (1..=3).for_each(|i| {
+            let _ = std::fs::remove_file(rotated(&path, i));
+        });
+
+        let mut sink = FileSink::new(path.clone(), 10, 0);
+        sink.write(metrics(1)).unwrap();
+        let line = std::fs::read(&path).unwrap().len() as u64;
+        let sample = &lines(&path)[0];
+        assert_eq!(sample["labels"]["i"], "000");
+        assert_eq!(sample["value"], 0.0);
+        assert_eq!(sample["timestamp"], "2023-11-14T22:13:20Z");
+        std::fs::remove_file(&path).unwrap();
+
+        // Three lines per file, keeping two rotated files.
+        let mut sink = FileSink::new(path.clone(), 3 * line, 2);
+        sink.write(metrics(5)).unwrap();
+        assert_eq!(lines(&path).len(), 2);
+        assert_eq!(lines(&rotated(&path, 1)).len(), 3);
+        sink.write(metrics(4)).unwrap();
+        assert_eq!(lines(&path).len(), 3);
+        assert_eq!(lines(&rotated(&path, 1)).len(), 3);
+        assert_eq!(lines(&rotated(&path, 2)).len(), 3);
+        assert!(!rotated(&path, 3).exists());
+
+        // An existing file is appended to.
+        let mut sink = FileSink::new(path.clone(), 3 * line, 2);
+        sink.write(metrics(1)).unwrap();
+        assert_eq!(lines(&path).len(), 1);
+        assert_eq!(lines(&rotated(&path, 1)).len(), 3);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
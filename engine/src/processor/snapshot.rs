@@ -0,0 +1,281 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+
+use crate::config::ConfigName;
+
+use super::{
+    baseline::{BaselineBundle, BaselineEntry},
+    label_values::{LabelValuesQuery, LabelValuesReport},
+    quarantine::catch_failure,
+    span::SpanSnapshot,
+    trace::{MetricArgs, TraceConfig},
+    trace_level::TraceLevelSnapshot,
+};
+
+/// A frozen view of the groups of a trace processor, taken at a sample
+/// boundary. Sampling and the export endpoints read a snapshot, so they
+/// never see a partially applied insert. Groups are shared with the
+/// processor until it changes them: a snapshot is cheap to take and to
+/// clone, and while it is kept, it costs a copy of the group maps and
+/// of the groups changed since.
+#[derive(Clone)]
+pub struct TraceSnapshot {
+    t: DateTime<Utc>,
+    groups: BTreeMap<ConfigName, SpanSnapshot>,
+    trace_metrics: TraceLevelSnapshot,
+}
+
+impl TraceSnapshot {
+    pub(super) fn new(
+        t: DateTime<Utc>,
+        groups: BTreeMap<ConfigName, SpanSnapshot>,
+        trace_metrics: TraceLevelSnapshot,
+    ) -> Self {
+        Self {
+            t,
+            groups,
+            trace_metrics,
+        }
+    }
+
+    /// The time the snapshot was taken.
+    pub fn time(&self) -> DateTime<Utc> {
+        self.t
+    }
+
+    /// Emit metrics for all groups, at the time of the snapshot.
+    /// Returns the number of regressed windows, which were skipped, per
+    /// config, or the panic message of the span configs that failed. A
+    /// failed config does not keep the others from being sampled.
+    pub fn sample<F: FnMut(MetricArgs<'_>, &ConfigName, f64)>(
+        &self,
+        mut metric: F,
+    ) -> BTreeMap<ConfigName, Result<u64, String>> {
+        let mut invalid = self
+            .groups
+            .iter()
+            .map(|(config_name, proc)| {
+                let n = catch_failure(|| {
+                    proc.sample(self.t, |metric_args, value| {
+                        metric(metric_args, config_name, value);
+                    })
+                });
+                (config_name.clone(), n)
+            })
+            .collect::<BTreeMap<_, _>>();
+
+        let trace_config_name = TraceConfig::trace_metrics_config_name();
+        let n = self.trace_metrics.sample(self.t, |metric_args, value| {
+            metric(metric_args, &trace_config_name, value);
+        });
+        invalid.insert(trace_config_name, Ok(n));
+        invalid
+    }
+
+    /// The observed values of the group key labels, per config.
+    pub fn label_values(&self, query: LabelValuesQuery) -> LabelValuesReport {
+        let mut report = LabelValuesReport::new();
+        self.groups.iter().for_each(|(config_name, proc)| {
+            report.add(config_name, proc.group_keys(), query);
+        });
+        report.add(
+            &TraceConfig::trace_metrics_config_name(),
+            self.trace_metrics.group_keys(),
+            query,
+        );
+        report
+    }
+
+    /// Export the learned baselines for all span configs and the
+    /// trace-level metrics.
+    pub fn export_baselines(&self) -> BaselineBundle {
+        let trace_config = TraceConfig::trace_metrics_config_name();
+        let entries = self
+            .groups
+            .iter()
+            .flat_map(|(config, proc)| proc.baselines().map(move |baseline| (config, baseline)))
+            .chain(
+                self.trace_metrics
+                    .baselines()
+                    .map(|baseline| (&trace_config, baseline)),
+            )
+            .map(|(config, (key, metric, baseline))| BaselineEntry {
+                config: config.clone(),
+                key: key.clone(),
+                metric: metric.clone(),
+                baseline,
+            })
+            .collect();
+        BaselineBundle::new(self.t, entries)
+    }
+}
+
+impl std::fmt::Debug for TraceSnapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TraceSnapshot")
+            .field("t", &self.t)
+            .field("configs", &self.groups.keys().collect::<Vec<_>>())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::BTreeMap, sync::mpsc, thread};
+
+    use chrono::{DateTime, TimeDelta, Utc};
+
+    use super::TraceSnapshot;
+    use crate::{
+        config::{ConfigName, IngestFilter, MetricName},
+        processor::{
+            mean_stddev::MeanStddevConfig,
+            sim::{span, start},
+            stats::StatsConfig,
+            summary::SummaryConfig,
+            trace::{TraceConfig, TraceProcessor},
+        },
+    };
+
+    /// The default span config only, with welford and summary
+    /// statistics on the duration.
+    fn config() -> TraceConfig {
+        let mut config = TraceConfig::default();
+        config.rules.truncate(1);
+        config
+            .configs
+            .retain(|name, _| name == &ConfigName::new("default"));
+        config.configs.values_mut().for_each(|config| {
+            config
+                .metrics
+                .retain(|name, _| name == &MetricName::new("duration"));
+            config.metrics.values_mut().for_each(|metric| {
+                metric.stats = StatsConfig {
+                    anomaly_score: None,
+                    mean_stddev: Some(MeanStddevConfig::default()),
+                    summary: Some(SummaryConfig::default()),
+                    histogram: None,
+                    ..StatsConfig::default()
+                };
+            });
+        });
+        config
+    }
+
+    /// Insert a single-span trace for one of three services.
+    fn insert(proc: &mut TraceProcessor, i: u64) -> DateTime<Utc> {
+        let t = start() + TimeDelta::milliseconds(i as i64);
+        let service = ["frontend", "backend", "database"][i as usize % 3];
+        let span = span(
+            &i.to_string(),
+            "1",
+            None,
+            service,
+            "GET",
+            t.timestamp_micros(),
+            1000 + (i as i64 % 7) * 100,
+        );
+        proc.insert(t, &[span], &IngestFilter::default());
+        t
+    }
+
+    #[derive(Default, Debug)]
+    struct GroupStats {
+        welford_count: f64,
+        welford_mean: f64,
+        summary_count: f64,
+        summary_sum: f64,
+    }
+
+    /// The duration statistics of the default config, per service.
+    fn duration_stats(snapshot: &TraceSnapshot) -> BTreeMap<String, GroupStats> {
+        let mut groups = BTreeMap::<String, GroupStats>::new();
+        snapshot.sample(|args, config_name, value| {
+            if config_name != &ConfigName::new("default") {
+                return;
+            }
+            let Some(service) = args.group.get("service_name") else {
+                return;
+            };
+            let stats = groups.entry(service.to_string()).or_default();
+            match (args.metric_name.as_str(), args.metric_type) {
+                ("trace_duration_count", "welford") => stats.welford_count = value,
+                ("trace_duration_mean", "welford") => stats.welford_mean = value,
+                ("trace_duration_count", "summary") => stats.summary_count = value,
+                ("trace_duration_sum", "summary") => stats.summary_sum = value,
+                _ => {}
+            }
+        });
+        groups
+    }
+
+    /// Check that the statistics of a snapshot taken after `n` inserts
+    /// agree with each other and with the number of inserts.
+    fn check_consistent(n: u64, snapshot: &TraceSnapshot) {
+        let groups = duration_stats(snapshot);
+        let total = groups
+            .values()
+            .map(|stats| stats.welford_count)
+            .sum::<f64>();
+        assert_eq!(total, n as f64, "snapshot at {}", snapshot.time());
+        groups.iter().for_each(|(service, stats)| {
+            assert_eq!(stats.welford_count, stats.summary_count, "{service}");
+            let sum = stats.welford_count * stats.welford_mean;
+            assert!(
+                (sum - stats.summary_sum).abs() <= 1e-9 * stats.summary_sum,
+                "{service}: count × mean = {sum}, sum = {}",
+                stats.summary_sum
+            );
+        });
+    }
+
+    #[test]
+    fn snapshot_is_frozen() {
+        let mut proc = TraceProcessor::new(&config());
+        let t = (0..30).fold(start(), |_, i| insert(&mut proc, i));
+        let snapshot = proc.snapshot(t);
+        let before = duration_stats(&snapshot);
+
+        (30..60).for_each(|i| {
+            insert(&mut proc, i);
+        });
+        check_consistent(30, &snapshot);
+        assert_eq!(
+            format!("{before:?}"),
+            format!("{:?}", duration_stats(&snapshot))
+        );
+        check_consistent(60, &proc.snapshot(t));
+    }
+
+    #[test]
+    fn concurrent_insert_and_sample() {
+        let (sender, receiver) = mpsc::sync_channel::<(u64, TraceSnapshot)>(4);
+        let inserter = thread::spawn(move || {
+            let mut proc = TraceProcessor::new(&config());
+            (0..3000).for_each(|i| {
+                let t = insert(&mut proc, i);
+                if i % 25 == 24 {
+                    sender.send((i + 1, proc.snapshot(t))).unwrap();
+                }
+            });
+        });
+        let sampler = thread::spawn(move || {
+            let mut snapshots = 0;
+            receiver.into_iter().for_each(|(n, snapshot)| {
+                // Sample twice, racing with the inserts of the next
+                // batch: both samples see exactly `n` inserts.
+                check_consistent(n, &snapshot);
+                check_consistent(n, &snapshot.clone());
+                snapshots += 1;
+            });
+            snapshots
+        });
+        inserter.join().unwrap();
+        assert_eq!(sampler.join().unwrap(), 120);
+    }
+}
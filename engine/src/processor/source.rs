@@ -2,19 +2,23 @@
  * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
  ******************************************************************************/
 
+use std::collections::BTreeMap;
+
 use chrono::{DateTime, Utc};
 use jaeger_anomaly_detection::WindowConfig;
+use rustc_apfloat::ieee::Quad;
 use serde::{Deserialize, Serialize};
 
 use crate::{
     accum::{Accum, Count, MergeAcc},
-    config::SpanSelector,
-    jaeger::Span,
-    metrics::Labels,
+    config::{Ancestors, MetricName, Regex, SpanClassifiers, SpanKey, SpanSelector},
+    jaeger::{Log, Span},
+    metrics::{label_value, Labels},
+    welford::Welford,
     window::Window,
 };
 
-use super::metric::MetricArgs;
+use super::{metric::MetricArgs, pushdown::SpanAggregate};
 
 #[derive(Serialize, Deserialize, schemars::JsonSchema, PartialEq, Eq, Clone, Debug)]
 #[serde(rename_all = "snake_case")]
@@ -22,16 +26,119 @@ pub enum MetricSource {
     Tag(String),
     Duration,
     SelfDuration,
-    TagExcept { tag: String, key: String },
-    Rate { select: SpanSelector },
-    Count { window: WindowConfig },
+    TagExcept {
+        tag: String,
+        key: String,
+    },
+    Rate {
+        select: SpanSelector,
+    },
+    /// Like `Rate`, but also counts the matching spans per value of
+    /// `by`, emitted with a `reason` label. Reasons beyond the first
+    /// `max_reasons` are counted as "other"; spans without `by` as
+    /// "unknown".
+    RateBy {
+        select: SpanSelector,
+        by: SpanKey,
+        #[serde(default = "default_max_reasons")]
+        max_reasons: usize,
+    },
+    Count {
+        window: WindowConfig,
+    },
+    /// The number of log entries per span with the given field,
+    /// optionally matching `pattern`. If `level` is set, only entries
+    /// whose `level_field` (default "level") equals `level` are
+    /// counted. Requires `ingest_logs`.
+    LogRate {
+        field: String,
+        pattern: Option<Regex>,
+        level_field: Option<String>,
+        level: Option<String>,
+    },
+}
+
+impl MetricSource {
+    /// The selector of `rate` and `rate_by` sources.
+    pub fn select(&self) -> Option<&SpanSelector> {
+        match self {
+            MetricSource::Rate { select } | MetricSource::RateBy { select, .. } => Some(select),
+            MetricSource::Tag(_)
+            | MetricSource::SelfDuration
+            | MetricSource::Duration
+            | MetricSource::TagExcept { .. }
+            | MetricSource::Count { .. }
+            | MetricSource::LogRate { .. } => None,
+        }
+    }
+
+    pub(crate) fn select_mut(&mut self) -> Option<&mut SpanSelector> {
+        match self {
+            MetricSource::Rate { select } | MetricSource::RateBy { select, .. } => Some(select),
+            MetricSource::Tag(_)
+            | MetricSource::SelfDuration
+            | MetricSource::Duration
+            | MetricSource::TagExcept { .. }
+            | MetricSource::Count { .. }
+            | MetricSource::LogRate { .. } => None,
+        }
+    }
+
+    /// Whether the source reads span logs, which are only fetched
+    /// with `ingest_logs`.
+    pub fn reads_logs(&self) -> bool {
+        match self {
+            MetricSource::LogRate { .. } => true,
+            MetricSource::Rate { select } | MetricSource::RateBy { select, .. } => {
+                select.reads_logs()
+            }
+            MetricSource::Tag(_)
+            | MetricSource::SelfDuration
+            | MetricSource::Duration
+            | MetricSource::TagExcept { .. }
+            | MetricSource::Count { .. } => false,
+        }
+    }
+
+    /// The key matching spans are counted by, for `rate_by` sources.
+    pub fn by(&self) -> Option<&SpanKey> {
+        match self {
+            MetricSource::RateBy { by, .. } => Some(by),
+            MetricSource::Tag(_)
+            | MetricSource::SelfDuration
+            | MetricSource::Duration
+            | MetricSource::TagExcept { .. }
+            | MetricSource::Rate { .. }
+            | MetricSource::Count { .. }
+            | MetricSource::LogRate { .. } => None,
+        }
+    }
 }
 
+pub(crate) const fn default_max_reasons() -> usize {
+    20
+}
+
+/// The reason label of matching spans beyond `max_reasons`.
+const OTHER_REASON: &str = "other";
+/// The reason label of matching spans without the `by` key.
+const UNKNOWN_REASON: &str = "unknown";
+
 #[derive(Serialize, Deserialize, Debug)]
 pub enum SourceState {
     Count { window: Window<Count>, count: u64 },
+    RateBy { counts: BTreeMap<String, f64> },
+}
+
+/// A value produced by a source from a span aggregate.
+pub enum AggregateValue {
+    /// A single value.
+    Value(f64),
+    /// A batch of values, summarized by their accumulator.
+    Values(Welford<Quad>),
 }
 
+#[derive(Clone)]
 pub enum SourceProcessor {
     /* Numeric sources.  */
     SelfDuration,
@@ -39,9 +146,28 @@ pub enum SourceProcessor {
     Tag(String),
     TagExcept(String, String),
     Rate(SpanSelector),
+    RateBy {
+        select: SpanSelector,
+        by: SpanKey,
+        max_reasons: usize,
+        /// The (weighted) number of matching spans per reason.
+        counts: BTreeMap<String, f64>,
+    },
+    LogRate {
+        field: String,
+        pattern: Option<Regex>,
+        level_field: Option<String>,
+        level: Option<String>,
+    },
 
     /* Windowed sources. */
-    Count { window: Window<Count>, count: u64 },
+    Count {
+        window: Window<Count>,
+        count: u64,
+        /// The fractional part of the sampling weights, carried over
+        /// to the next span.
+        carry: f64,
+    },
 }
 
 impl SourceProcessor {
@@ -54,9 +180,31 @@ impl SourceProcessor {
                 SourceProcessor::TagExcept(tag.clone(), key.clone())
             }
             MetricSource::Rate { select } => SourceProcessor::Rate(select.clone()),
+            MetricSource::RateBy {
+                select,
+                by,
+                max_reasons,
+            } => SourceProcessor::RateBy {
+                select: select.clone(),
+                by: by.clone(),
+                max_reasons: *max_reasons,
+                counts: BTreeMap::new(),
+            },
+            MetricSource::LogRate {
+                field,
+                pattern,
+                level_field,
+                level,
+            } => SourceProcessor::LogRate {
+                field: field.clone(),
+                pattern: pattern.clone(),
+                level_field: level_field.clone(),
+                level: level.clone(),
+            },
             MetricSource::Count { window } => SourceProcessor::Count {
                 window: Window::new(t, window),
                 count: 0,
+                carry: 0.0,
             },
         }
     }
@@ -81,13 +229,61 @@ impl SourceProcessor {
                 Some(SourceProcessor::Rate(prev_select))
             }
             (
-                SourceProcessor::Count { window, count },
+                SourceProcessor::RateBy {
+                    select: prev_select,
+                    by: prev_by,
+                    counts,
+                    ..
+                },
+                MetricSource::RateBy {
+                    select,
+                    by,
+                    max_reasons,
+                },
+            ) if select == &prev_select && by == &prev_by => Some(SourceProcessor::RateBy {
+                select: prev_select,
+                by: prev_by,
+                max_reasons: *max_reasons,
+                counts,
+            }),
+            (
+                SourceProcessor::LogRate {
+                    field: prev_field,
+                    pattern: prev_pattern,
+                    level_field: prev_level_field,
+                    level: prev_level,
+                },
+                MetricSource::LogRate {
+                    field,
+                    pattern,
+                    level_field,
+                    level,
+                },
+            ) if field == &prev_field
+                && pattern == &prev_pattern
+                && level_field == &prev_level_field
+                && level == &prev_level =>
+            {
+                Some(SourceProcessor::LogRate {
+                    field: prev_field,
+                    pattern: prev_pattern,
+                    level_field: prev_level_field,
+                    level: prev_level,
+                })
+            }
+            (
+                SourceProcessor::Count {
+                    window,
+                    count,
+                    carry,
+                },
                 MetricSource::Count {
                     window: window_config,
                 },
             ) if window.compatible_with(window_config) => Some(SourceProcessor::Count {
                 window: window.clone(),
                 count,
+                carry,
             }),
             _ => None,
         }
@@ -103,8 +299,25 @@ impl SourceProcessor {
             ) if window_config.bin_width.to_time_delta() == window.bin_width()
                 && window_config.num_bins == window.num_bins() =>
             {
-                Self::Count { window, count }
+                Self::Count {
+                    window,
+                    count,
+                    carry: 0.0,
+                }
             }
+            (
+                MetricSource::RateBy {
+                    select,
+                    by,
+                    max_reasons,
+                },
+                Some(SourceState::RateBy { counts }),
+            ) => Self::RateBy {
+                select: select.clone(),
+                by: by.clone(),
+                max_reasons: *max_reasons,
+                counts,
+            },
             _ => Self::new(t, config),
         }
     }
@@ -115,20 +328,29 @@ impl SourceProcessor {
             | SourceProcessor::Duration
             | SourceProcessor::Tag(_)
             | SourceProcessor::TagExcept(_, _)
-            | SourceProcessor::Rate(_) => None,
-            SourceProcessor::Count { window, count } => Some(SourceState::Count {
+            | SourceProcessor::Rate(_)
+            | SourceProcessor::LogRate { .. } => None,
+            SourceProcessor::Count { window, count, .. } => Some(SourceState::Count {
                 window: window.clone(),
                 count: *count,
             }),
+            SourceProcessor::RateBy { counts, .. } => Some(SourceState::RateBy {
+                counts: counts.clone(),
+            }),
         }
     }
 
+    /// Insert a span, passing the resulting values to `f`. The span
+    /// counts as `weight` spans (see `SpanConfig::respect_sampling`)
+    /// for the count source; rate values are weighted by the caller.
     pub fn insert<F: FnMut(f64)>(
         &mut self,
         t: DateTime<Utc>,
         span: &Span,
-        parent: Option<&Span>,
+        ancestors: Ancestors,
         children: &[&Span],
+        classifiers: &SpanClassifiers,
+        weight: f64,
         mut f: F,
     ) {
         match self {
@@ -198,24 +420,111 @@ impl SourceProcessor {
                     f((n - cn) as f64)
                 }
             }
-            Self::Rate(select) => f(if select.matches(span, parent) {
+            Self::Rate(select) => f(if select.matches(span, ancestors) {
                 1.0
             } else {
                 0.0
             }),
+            Self::RateBy {
+                select,
+                by,
+                max_reasons,
+                counts,
+            } => {
+                if !select.matches(span, ancestors) {
+                    return f(0.0);
+                }
+                let reason = by.get_with(span, ancestors, classifiers).map_or_else(
+                    || String::from(UNKNOWN_REASON),
+                    |value| label_value(&value.to_owned()),
+                );
+                let reasons = counts.len() - usize::from(counts.contains_key(OTHER_REASON));
+                let reason = if counts.contains_key(&reason) || reasons < *max_reasons {
+                    reason
+                } else {
+                    String::from(OTHER_REASON)
+                };
+                *counts.entry(reason).or_default() += weight;
+                f(1.0)
+            }
+            Self::LogRate {
+                field,
+                pattern,
+                level_field,
+                level,
+            } => f(span
+                .logs
+                .iter()
+                .filter(|log| {
+                    log_matches(
+                        log,
+                        field,
+                        pattern.as_ref(),
+                        level_field.as_deref().unwrap_or("level"),
+                        level.as_deref(),
+                    )
+                })
+                .count() as f64),
 
-            Self::Count { window, count } => {
+            Self::Count {
+                window,
+                count,
+                carry,
+            } => {
                 window
                     .advance_with(t, |window| {
                         window.bins().merge().extract() as f64 / window.minutes()
                     })
                     .for_each(f);
-                *count += 1;
-                window.current_mut().insert(());
+                let weight = weight + *carry;
+                let n = weight.floor();
+                *carry = weight - n;
+                *count += n as u64;
+                window.current_mut().merge(&Count::from(n as u64));
+            }
+        }
+    }
+
+    /// Like `insert`, for the spans of a group summarized by the
+    /// backend (aggregation pushdown). Sources that need the individual
+    /// spans are rejected by the pushdown check and ignored here.
+    pub fn insert_aggregate<F: FnMut(AggregateValue)>(
+        &mut self,
+        t: DateTime<Utc>,
+        metric: &MetricName,
+        aggregate: &SpanAggregate,
+        mut f: F,
+    ) {
+        match self {
+            Self::Duration => f(AggregateValue::Values(aggregate.duration.clone())),
+            Self::Rate(_) => {
+                if let Some(values) = aggregate.rate(metric) {
+                    f(AggregateValue::Values(values))
+                }
             }
+            Self::Count { window, count, .. } => {
+                window
+                    .advance_with(t, |window| {
+                        window.bins().merge().extract() as f64 / window.minutes()
+                    })
+                    .for_each(|v| f(AggregateValue::Value(v)));
+                *count += aggregate.count;
+                window.current_mut().merge(&Count::from(aggregate.count));
+            }
+            Self::SelfDuration
+            | Self::Tag(_)
+            | Self::TagExcept(_, _)
+            | Self::RateBy { .. }
+            | Self::LogRate { .. } => {}
         }
     }
 
+    /// Whether the values of this source are per-span indicators,
+    /// which are weighted for head-sampled traces.
+    pub fn weighs_values(&self) -> bool {
+        matches!(self, Self::Rate(_) | Self::RateBy { .. })
+    }
+
     pub fn sample<F: for<'b> FnMut(MetricArgs, f64)>(&self, _t: DateTime<Utc>, mut metric: F) {
         match self {
             Self::Count { count, .. } => {
@@ -228,11 +537,43 @@ impl SourceProcessor {
                     *count as f64,
                 );
             }
+            Self::RateBy { counts, .. } => counts.iter().for_each(|(reason, n)| {
+                metric(
+                    MetricArgs {
+                        metric_suffix: Some("total"),
+                        metric_type: "source_count",
+                        labels: Labels {
+                            reason: Some(reason.clone()),
+                            ..Labels::default()
+                        },
+                    },
+                    *n,
+                )
+            }),
             Self::SelfDuration
             | Self::Duration
             | Self::Tag(_)
             | Self::TagExcept(_, _)
-            | Self::Rate(_) => {}
+            | Self::Rate(_)
+            | Self::LogRate { .. } => {}
         }
     }
 }
+
+fn log_matches(
+    log: &Log,
+    field: &str,
+    pattern: Option<&Regex>,
+    level_field: &str,
+    level: Option<&str>,
+) -> bool {
+    let field_matches = log.field(field).is_some_and(|value| {
+        pattern.map_or(true, |re| value.as_str().is_some_and(|s| re.matches(s)))
+    });
+    let level_matches = level.map_or(true, |level| {
+        log.field(level_field)
+            .and_then(|value| value.as_str())
+            .is_some_and(|value| value == level)
+    });
+    field_matches && level_matches
+}
@@ -2,18 +2,35 @@
  * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
  ******************************************************************************/
 
-use std::collections::{BTreeMap, BTreeSet};
+use std::{
+    cell::Cell,
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    marker::PhantomData,
+    sync::Arc,
+};
 
 use chrono::{DateTime, TimeDelta, Utc};
-use serde::{Deserialize, Serialize};
+use serde::{
+    de::{DeserializeOwned, Error as _, IgnoredAny, MapAccess, Visitor},
+    Deserialize, Deserializer, Serialize,
+};
+
+use jaeger_anomaly_detection::Duration;
 
 use crate::{
-    config::{MetricName, SpanKey},
+    config::{Ancestors, ConfigName, MetricName, SpanClassifiers, SpanKey, SpanSelector},
     jaeger::{Span, TagValue},
+    metrics::{GroupLabels, Labels},
 };
 
 use super::{
-    metric::{MetricConfig, MetricProcessor, MetricState},
+    anomaly_score::AnomalyScoreConfig,
+    baseline::{BaselineSkip, StatsBaseline},
+    maintenance::{MaintenanceWindow, Muting},
+    metric::{GuardCounts, MetricConfig, MetricProcessor, MetricState},
+    pseudonymize::{PseudonymizationKey, Pseudonymize},
+    pushdown::SpanAggregate,
+    stats::StatsConfig,
     trace::MetricArgs,
 };
 
@@ -21,11 +38,156 @@ use super::{
 pub struct SpanConfig {
     pub key: BTreeSet<SpanKey>,
     pub metrics: BTreeMap<MetricName, MetricConfig>,
+    /// Emit the series of this config. When false, spans are still
+    /// processed, so that the baselines stay warm, but no series are
+    /// written until the config is enabled again.
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// Key components whose change should not restart the baselines,
+    /// e.g. the instance id of a redeployed service. A new group that
+    /// differs from a recently seen group only in these components
+    /// starts from a copy of that group's statistics.
+    #[serde(default)]
+    pub carry_over: BTreeSet<SpanKey>,
+    /// How recently the previous group must have been seen for its
+    /// statistics to be carried over. Groups that were carried over
+    /// are removed once they have not been seen for this long.
+    #[serde(default = "default_carry_over_age")]
+    pub carry_over_age: Duration,
+    /// Compute the statistics with OpenSearch aggregations per sample
+    /// interval instead of from the individual spans. Only supported
+    /// for keys on the current service and operation name, duration,
+    /// rate and count sources, and mean/stddev or mean/ci anomaly
+    /// score statistics; other configs are processed span by span.
+    #[serde(default)]
+    pub pushdown: bool,
+    /// Normalize numeric tag values in the group key: integers
+    /// reported as Int64 or as strings ("200", "+200") end up in the
+    /// same group, and decimal strings lose trailing zeros ("1.50"
+    /// becomes "1.5").
+    #[serde(default)]
+    pub normalize_numbers: bool,
+    /// Keys attached to the emitted series as labels without being
+    /// part of the group key, e.g. the node or pod a service runs on.
+    /// The label holds the most recently seen value for the group, so
+    /// it can change over a series' lifetime. Not available with
+    /// aggregation pushdown.
+    #[serde(default)]
+    pub annotations: BTreeSet<SpanKey>,
+    /// Drop the summary and histogram statistics of groups that have
+    /// not been seen for this long, keeping the anomaly score and
+    /// mean/stddev statistics until the group is removed. The dropped
+    /// statistics start over when the group is seen again.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idle_after: Option<Duration>,
+    /// Count the spans of head-sampled traces once per sampled trace
+    /// they stand for, using the probability in the `sampler.param`
+    /// tag of a root span with `sampler.type` "probabilistic". This
+    /// corrects the count and rate metrics of services whose traces
+    /// are sampled at a different rate; duration metrics are not
+    /// affected.
+    #[serde(default)]
+    pub respect_sampling: bool,
+    /// The maximum weight of a span of a sampled trace, limiting the
+    /// effect of very low (or misreported) sampling probabilities.
+    #[serde(default = "default_max_sampling_weight")]
+    pub max_sampling_weight: f64,
+    /// Classifiers mapping spans onto a fixed set of values, referred
+    /// to as `class` keys in the group key, the annotations or the
+    /// `by` key of `rate_by` sources.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub classify: SpanClassifiers,
+    /// Keys whose values are replaced by a keyed hash before grouping,
+    /// in the group key as well as the annotations. The clear values
+    /// never reach the emitted series or the saved state. Requires
+    /// `--pseudonymization-key-file`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pseudonymize: Vec<Pseudonymize>,
+    /// Keep the groups as of the last `late_bins` samples, so that
+    /// spans inserted after their sample interval was written (e.g.
+    /// spans indexed late, picked up again by a retried query with
+    /// dedup enabled) correct the written samples: the affected groups
+    /// are sampled again, at the original sample time, with the next
+    /// sample. Groups changed since one of these samples are kept in
+    /// memory more than once. Not applied to aggregation pushdown.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub late_bins: Option<usize>,
+    /// How spans lacking some of the key components are handled, e.g.
+    /// spans of services whose SDK does not set `service.namespace`.
+    #[serde(default)]
+    pub missing_key: MissingKey,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// The handling of spans lacking some of the key components.
+#[derive(Serialize, Deserialize, schemars::JsonSchema, PartialEq, Eq, Clone, Default, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum MissingKey {
+    /// Leave the missing components out of the group key. The span may
+    /// end up in the same group as spans of unrelated services.
+    #[default]
+    Omit,
+    /// Do not process the span for this config.
+    Skip,
+    /// Use a placeholder for the missing components, given by their
+    /// label, e.g. `{ service_namespace: unknown }`. Missing
+    /// components without a placeholder are left out, as with `omit`.
+    Placeholder(BTreeMap<String, String>),
+}
+
+impl SpanConfig {
+    /// The selectors of the classifiers and the metric sources.
+    pub fn selectors(&self) -> impl Iterator<Item = &SpanSelector> {
+        self.classify
+            .values()
+            .flat_map(|classifier| classifier.classes.iter().map(|class| &class.select))
+            .chain(
+                self.metrics
+                    .values()
+                    .filter_map(|metric| metric.source.select()),
+            )
+    }
+
+    /// Whether the series of `metric` are emitted: both the config and
+    /// the metric must be enabled.
+    pub fn is_emitted(&self, metric: &MetricConfig) -> bool {
+        self.enabled && metric.enabled
+    }
+
+    /// The config with the large value sets of its selectors indexed
+    /// (see `SpanSelector::index`).
+    fn indexed(&self) -> Self {
+        let mut config = self.clone();
+        config
+            .classify
+            .values_mut()
+            .flat_map(|classifier| classifier.classes.iter_mut())
+            .for_each(|class| class.select.index());
+        config
+            .metrics
+            .values_mut()
+            .filter_map(|metric| metric.source.select_mut())
+            .for_each(SpanSelector::index);
+        config
+    }
+}
+
+type GroupKey = BTreeMap<SpanKey, TagValue>;
+
+/// The version of the saved span state. States saved before the
+/// version field was introduced are "legacy" states, whose groups can
+/// be in either the V0 or the V1 format.
+const SPAN_STATE_VERSION: u32 = 1;
+
+/// The saved state of a span config. New saves store all groups in the
+/// V1 format.
+#[derive(Serialize, Debug)]
 pub struct SpanState {
+    version: u32,
     groups: BTreeMap<BTreeMap<SpanKey, TagValue>, MetricsState>,
+    /// Descriptions of the groups that could not be decoded, and were
+    /// left out of the loaded state.
+    #[serde(skip)]
+    dropped: Vec<String>,
 }
 
 #[derive(Serialize, Debug)]
@@ -38,7 +200,170 @@ pub enum MetricsState {
 #[derive(Serialize, Deserialize, Debug)]
 pub struct MetricsStateV1 {
     last_seen: DateTime<Utc>,
+    /// Missing in states saved before it was tracked; these groups
+    /// count as first seen when the state is loaded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    first_seen: Option<DateTime<Utc>>,
     metrics: BTreeMap<MetricName, MetricState>,
+    #[serde(default)]
+    superseded: bool,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    annotations: GroupKey,
+}
+
+// The version field is serialized first, so that the group format is
+// known before the groups are read. Only legacy states (without a
+// version) take the slow path through `MetricsState`.
+//
+// V1 groups are decoded in one pass. When that fails, the state is
+// read again through `decode_lenient`, which decodes the groups one by
+// one: a group that fails to decode is dropped (and starts over),
+// instead of failing the whole state.
+
+thread_local! {
+    static LENIENT: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Run `decode` with the groups of the span states it reads decoded one
+/// by one, dropping the groups that fail to decode. This is slower than
+/// the default decoding, and meant to retry a state that failed to
+/// decode.
+pub fn decode_lenient<T>(decode: impl FnOnce() -> T) -> T {
+    let lenient = LENIENT.replace(true);
+    let result = decode();
+    LENIENT.set(lenient);
+    result
+}
+
+impl<'de> Deserialize<'de> for SpanState {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        struct SpanStateVisitor;
+
+        impl<'de> Visitor<'de> for SpanStateVisitor {
+            type Value = SpanState;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                write!(f, "a span state")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<SpanState, A::Error> {
+                let mut version = None;
+                let mut groups = None;
+                while let Some(field) = map.next_key::<String>()? {
+                    match field.as_str() {
+                        "version" => version = Some(map.next_value::<u32>()?),
+                        "groups" => {
+                            groups = Some(match version {
+                                None => map.next_value::<LenientGroups<MetricsState>>()?,
+                                Some(SPAN_STATE_VERSION) if !LENIENT.get() => LenientGroups {
+                                    groups: map
+                                        .next_value::<BTreeMap<GroupKey, MetricsStateV1>>()?
+                                        .into_iter()
+                                        .map(|(key, state)| (key, MetricsState::V1(state)))
+                                        .collect(),
+                                    dropped: Vec::new(),
+                                },
+                                Some(SPAN_STATE_VERSION) => {
+                                    let groups =
+                                        map.next_value::<LenientGroups<MetricsStateV1>>()?;
+                                    LenientGroups {
+                                        groups: groups
+                                            .groups
+                                            .into_iter()
+                                            .map(|(key, state)| (key, MetricsState::V1(state)))
+                                            .collect(),
+                                        dropped: groups.dropped,
+                                    }
+                                }
+                                Some(version) => {
+                                    return Err(A::Error::custom(format!(
+                                        "unsupported span state version {version}"
+                                    )))
+                                }
+                            })
+                        }
+                        _ => {
+                            map.next_value::<IgnoredAny>()?;
+                        }
+                    }
+                }
+                let groups = groups.ok_or_else(|| A::Error::missing_field("groups"))?;
+                Ok(SpanState {
+                    version: version.unwrap_or(SPAN_STATE_VERSION),
+                    groups: groups.groups,
+                    dropped: groups.dropped,
+                })
+            }
+        }
+
+        deserializer.deserialize_map(SpanStateVisitor)
+    }
+}
+
+/// A value decoded on its own, so that a failure does not fail the
+/// enclosing value.
+struct Lenient<T>(Result<T, String>);
+
+impl<'de, T: DeserializeOwned> Deserialize<'de> for Lenient<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = ciborium::Value::deserialize(deserializer)?;
+        Ok(Self(value.deserialized().map_err(|e| e.to_string())))
+    }
+}
+
+/// The groups of a span state, without the groups that failed to
+/// decode.
+struct LenientGroups<T> {
+    groups: BTreeMap<GroupKey, T>,
+    dropped: Vec<String>,
+}
+
+impl<'de, T: DeserializeOwned> Deserialize<'de> for LenientGroups<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct GroupsVisitor<T>(PhantomData<T>);
+
+        impl<'de, T: DeserializeOwned> Visitor<'de> for GroupsVisitor<T> {
+            type Value = LenientGroups<T>;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                write!(f, "a map of groups")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+                let mut groups = BTreeMap::new();
+                let mut dropped = Vec::new();
+                while let Some((Lenient(key), Lenient(state))) =
+                    map.next_entry::<Lenient<GroupKey>, Lenient<T>>()?
+                {
+                    match (key, state) {
+                        (Ok(key), Ok(state)) => {
+                            groups.insert(key, state);
+                        }
+                        (Ok(key), Err(e)) => dropped.push(format!("{key:?}: {e}")),
+                        (Err(e), _) => dropped.push(format!("invalid key: {e}")),
+                    }
+                }
+                Ok(LenientGroups { groups, dropped })
+            }
+        }
+
+        deserializer.deserialize_map(GroupsVisitor(PhantomData))
+    }
+}
+
+impl SpanState {
+    /// Descriptions of the groups that could not be decoded when the
+    /// state was loaded.
+    pub fn dropped_groups(&self) -> &[String] {
+        &self.dropped
+    }
+
+    pub fn group_count(&self) -> usize {
+        self.groups.len()
+    }
 }
 
 // Manual 'untagged' deserialization impl while
@@ -58,31 +383,125 @@ impl<'de> Deserialize<'de> for MetricsState {
     }
 }
 
+/// The groups of a span config. The map and the groups are shared with
+/// the snapshots taken since they last changed, and copied on write.
+type Groups = BTreeMap<GroupKey, Arc<MetricsProcessor>>;
+
 pub struct SpanProcessor {
+    name: ConfigName,
     config: SpanConfig,
-    groups: BTreeMap<BTreeMap<SpanKey, TagValue>, MetricsProcessor>,
+    groups: Arc<Groups>,
+    /// Group keys by their projection without the carry-over
+    /// components. Empty if no carry-over components are configured.
+    index: BTreeMap<GroupKey, BTreeSet<GroupKey>>,
+    pseudonymization: Option<PseudonymizationKey>,
+    /// The values dropped or clamped by the metrics' `max_value`,
+    /// since startup.
+    guarded: BTreeMap<MetricName, GuardCounts>,
+    /// The groups as of the last samples, oldest first (see
+    /// `SpanConfig::late_bins`).
+    sampled: VecDeque<SampledGroups>,
+    /// The spans skipped for lacking key components, since startup.
+    skipped_spans: u64,
 }
 
+/// The groups of a span config at the time of a sample, with the keys
+/// of the groups corrected since.
+struct SampledGroups {
+    t: DateTime<Utc>,
+    groups: Arc<Groups>,
+    corrected: BTreeSet<GroupKey>,
+}
+
+/// A frozen view of the groups of a span config.
+#[derive(Clone)]
+pub struct SpanSnapshot {
+    groups: Arc<Groups>,
+    /// The anomaly score configs whose offset is emitted once per
+    /// config, by metric.
+    offsets: Vec<(MetricName, AnomalyScoreConfig)>,
+    /// The metrics that are not emitted (see `SpanConfig::enabled`).
+    paused: BTreeSet<MetricName>,
+}
+
+#[derive(Clone)]
 pub struct MetricsProcessor {
+    /// The config and group key labels.
+    base_labels: GroupLabels,
+    /// The labels of the emitted series: the base labels and the
+    /// annotations.
+    labels: GroupLabels,
+    /// The most recently seen annotation values.
+    annotations: GroupKey,
     last_seen: DateTime<Utc>,
+    /// When the group was created, or the group it carried over was.
+    first_seen: DateTime<Utc>,
     metrics: BTreeMap<MetricName, MetricProcessor>,
+    /// Set when a newer group carried over this group's statistics.
+    superseded: bool,
+    /// Set when the summary and histogram statistics were dropped
+    /// because the group was idle.
+    compacted: bool,
+}
+
+pub(crate) const fn default_enabled() -> bool {
+    true
+}
+
+pub(crate) const fn default_carry_over_age() -> Duration {
+    Duration::Hours(1)
+}
+
+pub(crate) const fn default_max_sampling_weight() -> f64 {
+    100.0
 }
 
 impl SpanProcessor {
-    pub fn new(config: &SpanConfig) -> Self {
+    pub fn new(name: &ConfigName, config: &SpanConfig) -> Self {
+        let config = &config.indexed();
         Self {
+            name: name.clone(),
             config: config.clone(),
-            groups: BTreeMap::new(),
+            groups: Arc::default(),
+            index: BTreeMap::new(),
+            pseudonymization: None,
+            guarded: BTreeMap::new(),
+            sampled: VecDeque::new(),
+            skipped_spans: 0,
         }
     }
 
+    /// Use `key` for the pseudonymized values.
+    pub fn set_pseudonymization(&mut self, key: Option<PseudonymizationKey>) {
+        self.pseudonymization = key;
+    }
+
     pub fn update(self, t: DateTime<Utc>, config: &SpanConfig) -> SpanProcessor {
-        SpanProcessor {
+        let config = &config.indexed();
+        let mut proc = SpanProcessor {
+            index: BTreeMap::new(),
+            name: self.name,
             config: config.clone(),
-            groups: if self.config.key == config.key {
-                self.groups
+            pseudonymization: self.pseudonymization,
+            guarded: self
+                .guarded
+                .into_iter()
+                .filter(|(name, _)| config.metrics.contains_key(name))
+                .collect(),
+            sampled: VecDeque::new(),
+            skipped_spans: self.skipped_spans,
+            groups: if self.config.key == config.key
+                && self.config.normalize_numbers == config.normalize_numbers
+                && self.config.pseudonymize == config.pseudonymize
+            {
+                let groups = unshared(self.groups)
                     .into_iter()
-                    .map(|(key, mut metrics)| {
+                    .map(|(key, metrics)| {
+                        let mut metrics = unshared(metrics);
+                        metrics
+                            .annotations
+                            .retain(|name, _| config.annotations.contains(name));
+                        metrics.labels = metrics.base_labels.annotated(&metrics.annotations);
                         metrics.metrics = config
                             .metrics
                             .iter()
@@ -94,47 +513,99 @@ impl SpanProcessor {
                                 }
                             })
                             .collect();
-                        (key, metrics)
+                        // Updating recreates the dropped statistics.
+                        if metrics.compacted && config.idle_after.is_some() {
+                            metrics.compact();
+                        } else {
+                            metrics.compacted = false;
+                        }
+                        (key, Arc::new(metrics))
                     })
-                    .collect()
+                    .collect();
+                Arc::new(groups)
             } else {
-                BTreeMap::new()
+                Arc::default()
             },
-        }
+        };
+        proc.build_index();
+        proc
     }
 
-    pub fn load(t: DateTime<Utc>, state: SpanState, config: &SpanConfig) -> Self {
-        Self {
+    pub fn load(
+        t: DateTime<Utc>,
+        name: &ConfigName,
+        state: SpanState,
+        config: &SpanConfig,
+    ) -> Self {
+        let config = &config.indexed();
+        let mut proc = Self {
+            name: name.clone(),
             config: config.clone(),
-            groups: state
-                .groups
-                .into_iter()
-                .map(|(key, proc)| {
-                    let (last_seen, mut metrics) = match proc {
-                        MetricsState::V1(MetricsStateV1 { last_seen, metrics }) => {
-                            (last_seen, metrics)
+            groups: Arc::new(
+                state
+                    .groups
+                    .into_iter()
+                    .map(|(key, proc)| {
+                        let (last_seen, first_seen, mut metrics, superseded, mut annotations) =
+                            match proc {
+                                MetricsState::V1(MetricsStateV1 {
+                                    last_seen,
+                                    first_seen,
+                                    metrics,
+                                    superseded,
+                                    annotations,
+                                }) => (last_seen, first_seen, metrics, superseded, annotations),
+                                MetricsState::V0(metrics) => (
+                                    t - TimeDelta::days(29),
+                                    None,
+                                    metrics,
+                                    false,
+                                    GroupKey::new(),
+                                ),
+                            };
+                        annotations.retain(|name, _| config.annotations.contains(name));
+                        let metrics = config
+                            .metrics
+                            .iter()
+                            .map(|(name, config)| {
+                                let proc = metrics.remove(name).map_or_else(
+                                    || MetricProcessor::new(t, config),
+                                    |state| MetricProcessor::load(t, state, config),
+                                );
+                                (name.clone(), proc)
+                            })
+                            .collect();
+                        let base_labels = GroupLabels::new(name, &key);
+                        let mut group = MetricsProcessor {
+                            labels: base_labels.annotated(&annotations),
+                            base_labels,
+                            annotations,
+                            last_seen,
+                            first_seen: first_seen.unwrap_or(t),
+                            metrics,
+                            superseded,
+                            compacted: false,
+                        };
+                        if is_idle(config, &group, t) {
+                            group.compact();
                         }
-                        MetricsState::V0(metrics) => (t - TimeDelta::days(29), metrics),
-                    };
-                    let metrics = config
-                        .metrics
-                        .iter()
-                        .map(|(name, config)| {
-                            let proc = metrics.remove(name).map_or_else(
-                                || MetricProcessor::new(t, config),
-                                |state| MetricProcessor::load(t, state, config),
-                            );
-                            (name.clone(), proc)
-                        })
-                        .collect();
-                    (key, MetricsProcessor { last_seen, metrics })
-                })
-                .collect(),
-        }
+                        (key, Arc::new(group))
+                    })
+                    .collect(),
+            ),
+            index: BTreeMap::new(),
+            pseudonymization: None,
+            guarded: BTreeMap::new(),
+            sampled: VecDeque::new(),
+            skipped_spans: 0,
+        };
+        proc.build_index();
+        proc
     }
 
     pub fn save(&self) -> SpanState {
         SpanState {
+            version: SPAN_STATE_VERSION,
             groups: self
                 .groups
                 .iter()
@@ -152,11 +623,15 @@ impl SpanProcessor {
                         key,
                         MetricsState::V1(MetricsStateV1 {
                             last_seen: proc.last_seen,
+                            first_seen: Some(proc.first_seen),
                             metrics,
+                            superseded: proc.superseded,
+                            annotations: proc.annotations.clone(),
                         }),
                     )
                 })
                 .collect(),
+            dropped: Vec::new(),
         }
     }
 
@@ -164,41 +639,428 @@ impl SpanProcessor {
         &mut self,
         t: DateTime<Utc>,
         span: &Span,
-        parent: Option<&Span>,
+        ancestors: Ancestors,
         children: &[&Span],
+        sampling: Option<f64>,
+        maintenance: &[MaintenanceWindow],
     ) {
-        let key = self
-            .config
+        let muting = Muting::of(maintenance, t, span);
+        let weight = sampling
+            .filter(|_| self.config.respect_sampling)
+            .map_or(1.0, |p| p.recip().min(self.config.max_sampling_weight));
+        let Some(key) = self.span_key(span, ancestors) else {
+            self.skipped_spans += 1;
+            return;
+        };
+        let classifiers = &self.config.classify;
+        let annotations = self.pseudonymized(
+            self.normalized(
+                self.config
+                    .annotations
+                    .iter()
+                    .filter_map(|key| {
+                        let value = key.get_with(span, ancestors, classifiers)?;
+                        Some((key.clone(), value.to_owned()))
+                    })
+                    .collect(),
+            ),
+        );
+        let key = self.touch_group(t, key);
+        // Borrow the groups only, leaving the classifiers available
+        // to the metric sources.
+        let group = owned_group(&mut self.groups, &key).unwrap();
+        // Spans older than the last one seen do not hold the most
+        // recent annotation values.
+        if group.last_seen == t {
+            group.annotate(annotations);
+        }
+        let classifiers = &self.config.classify;
+        let guarded = &mut self.guarded;
+        group.metrics.iter_mut().for_each(|(name, proc)| {
+            let counts = proc.insert(t, span, ancestors, children, classifiers, muting, weight);
+            if !counts.is_empty() {
+                guarded.entry(name.clone()).or_default().add(counts);
+            }
+        });
+        // Spans of an interval that was already sampled also correct
+        // the groups kept for that sample and the ones after it.
+        let (name, config) = (&self.name, &self.config);
+        self.sampled
+            .iter_mut()
+            .filter(|sampled| t <= sampled.t)
+            .for_each(|sampled| {
+                let group = sampled.group_mut(&key, || {
+                    MetricsProcessor::new(t, config, GroupLabels::new(name, &key))
+                });
+                group.metrics.values_mut().for_each(|proc| {
+                    proc.insert(t, span, ancestors, children, classifiers, muting, weight);
+                });
+            });
+    }
+
+    /// Insert the backend aggregate of the spans of a group
+    /// (aggregation pushdown).
+    pub fn insert_aggregate(&mut self, t: DateTime<Utc>, key: GroupKey, aggregate: &SpanAggregate) {
+        let guarded = self
+            .group_mut(t, key)
+            .metrics
+            .iter_mut()
+            .map(|(name, proc)| (name.clone(), proc.insert_aggregate(t, name, aggregate)))
+            .filter(|(_, counts)| !counts.is_empty())
+            .collect::<Vec<_>>();
+        guarded.into_iter().for_each(|(name, counts)| {
+            self.guarded.entry(name).or_default().add(counts);
+        });
+    }
+
+    /// The values dropped or clamped by the metrics' `max_value`.
+    pub fn guarded(&self) -> &BTreeMap<MetricName, GuardCounts> {
+        &self.guarded
+    }
+
+    /// Keep the groups as sampled at `t`, if the config asks for it.
+    /// Sampled groups are shared with the processor until they change.
+    pub fn record_sample(&mut self, t: DateTime<Utc>) {
+        let Some(late_bins) = self.config.late_bins.filter(|n| *n > 0) else {
+            return;
+        };
+        self.sampled.push_back(SampledGroups {
+            t,
+            groups: self.groups.clone(),
+            corrected: BTreeSet::new(),
+        });
+        while self.sampled.len() > late_bins {
+            self.sampled.pop_front();
+        }
+    }
+
+    /// The groups corrected since they were sampled, by sample time,
+    /// to be sampled again. Every correction is returned once.
+    pub fn take_corrections(&mut self) -> Vec<(DateTime<Utc>, SpanSnapshot)> {
+        let paused = self.paused_metrics();
+        self.sampled
+            .iter_mut()
+            .filter(|sampled| !sampled.corrected.is_empty())
+            .map(|sampled| {
+                let groups = std::mem::take(&mut sampled.corrected)
+                    .into_iter()
+                    .filter_map(|key| {
+                        let group = sampled.groups.get(&key)?.clone();
+                        Some((key, group))
+                    })
+                    .collect();
+                let snapshot = SpanSnapshot {
+                    groups: Arc::new(groups),
+                    offsets: Vec::new(),
+                    paused: paused.clone(),
+                };
+                (sampled.t, snapshot)
+            })
+            .collect()
+    }
+
+    /// The group for `key`, created (or carried over) if needed and
+    /// marked as seen at `t`.
+    fn group_mut(&mut self, t: DateTime<Utc>, key: GroupKey) -> &mut MetricsProcessor {
+        let key = self.touch_group(t, key);
+        owned_group(&mut self.groups, &key).unwrap()
+    }
+
+    /// Create (or carry over) the group for `key` if needed and mark
+    /// it as seen at `t`. Returns the group key.
+    fn touch_group(&mut self, t: DateTime<Utc>, key: GroupKey) -> GroupKey {
+        let key = self.group_key(key);
+        if !self.groups.contains_key(&key) {
+            let group = self
+                .carry_over(t, &key)
+                .unwrap_or_else(|| self.new_group(t, &key));
+            self.add_group(key.clone(), group);
+        }
+        let group = owned_group(&mut self.groups, &key).unwrap();
+        group.last_seen = group.last_seen.max(t);
+        if group.compacted {
+            group.restore(t, &self.config);
+        }
+        key
+    }
+
+    /// Copy the statistics of the most recently seen group that differs
+    /// from `key` only in the carry-over components, marking that group
+    /// as superseded.
+    fn carry_over(&mut self, t: DateTime<Utc>, key: &GroupKey) -> Option<MetricsProcessor> {
+        if self.config.carry_over.is_empty() {
+            return None;
+        }
+        let min_last_seen = t - self.config.carry_over_age.to_time_delta();
+        let previous = self
+            .index
+            .get(&projected(&self.config.carry_over, key))?
+            .iter()
+            .filter_map(|prev| Some((prev, self.groups.get(prev)?)))
+            .filter(|(_, group)| !group.superseded && group.last_seen >= min_last_seen)
+            .max_by_key(|(_, group)| group.last_seen)?
+            .0
+            .clone();
+        let group = owned_group(&mut self.groups, &previous)?;
+        group.superseded = true;
+        let base_labels = GroupLabels::new(&self.name, key);
+        Some(MetricsProcessor {
+            labels: base_labels.annotated(&group.annotations),
+            base_labels,
+            annotations: group.annotations.clone(),
+            last_seen: t,
+            first_seen: group.first_seen,
+            metrics: group
+                .metrics
+                .iter()
+                .filter_map(|(name, proc)| {
+                    let config = self.config.metrics.get(name)?;
+                    Some((name.clone(), MetricProcessor::load(t, proc.save(), config)))
+                })
+                .collect(),
+            superseded: false,
+            compacted: false,
+        })
+    }
+
+    fn new_group(&self, t: DateTime<Utc>, key: &GroupKey) -> MetricsProcessor {
+        MetricsProcessor::new(t, &self.config, GroupLabels::new(&self.name, key))
+    }
+
+    /// The group key with numeric values normalized, if enabled.
+    fn normalized(&self, key: GroupKey) -> GroupKey {
+        if !self.config.normalize_numbers {
+            return key;
+        }
+        key.into_iter()
+            .map(|(name, value)| (name, normalized_number(value)))
+            .collect()
+    }
+
+    /// The key values of a span, with the missing components handled
+    /// as configured. Returns `None` if the span is to be skipped.
+    pub fn span_key(&self, span: &Span, ancestors: Ancestors) -> Option<GroupKey> {
+        let classifiers = &self.config.classify;
+        self.config
             .key
             .iter()
-            .filter_map(|key| Some((key.clone(), key.get(span, parent)?.to_owned())))
-            .collect();
-        self.groups
-            .entry(key)
-            .or_insert_with(|| {
-                let metrics = self
-                    .config
+            .filter_map(|key| match key.get_with(span, ancestors, classifiers) {
+                Some(value) => Some(Some((key.clone(), value.to_owned()))),
+                None => match &self.config.missing_key {
+                    MissingKey::Omit => None,
+                    MissingKey::Skip => Some(None),
+                    MissingKey::Placeholder(values) => values
+                        .get(&key.label().into_string())
+                        .map(|value| Some((key.clone(), TagValue::String(value.clone())))),
+                },
+            })
+            .collect()
+    }
+
+    /// The spans skipped for lacking key components.
+    pub fn skipped_spans(&self) -> u64 {
+        self.skipped_spans
+    }
+
+    /// The group key for the key values of a span: normalized and
+    /// pseudonymized, as configured.
+    pub fn group_key(&self, key: GroupKey) -> GroupKey {
+        self.pseudonymized(self.normalized(key))
+    }
+
+    /// The group key with the configured values pseudonymized.
+    fn pseudonymized(&self, key: GroupKey) -> GroupKey {
+        if self.config.pseudonymize.is_empty() {
+            return key;
+        }
+        key.into_iter()
+            .map(|(name, value)| {
+                let value = match self.pseudonymizer(&name) {
+                    Some(config) => config.apply(self.pseudonymization.as_ref(), value),
+                    None => value,
+                };
+                (name, value)
+            })
+            .collect()
+    }
+
+    /// The pseudonym for a value of `key`, if the config pseudonymizes
+    /// it.
+    pub fn pseudonym(&self, key: &SpanKey, value: TagValue) -> Option<TagValue> {
+        let config = self.pseudonymizer(key)?;
+        Some(config.apply(self.pseudonymization.as_ref(), value))
+    }
+
+    fn pseudonymizer(&self, key: &SpanKey) -> Option<&Pseudonymize> {
+        self.config.pseudonymize.iter().find(|p| &p.key == key)
+    }
+
+    fn add_group(&mut self, key: GroupKey, group: MetricsProcessor) {
+        if !self.config.carry_over.is_empty() {
+            self.index
+                .entry(projected(&self.config.carry_over, &key))
+                .or_default()
+                .insert(key.clone());
+        }
+        Arc::make_mut(&mut self.groups).insert(key, Arc::new(group));
+    }
+
+    fn build_index(&mut self) {
+        self.index.clear();
+        if !self.config.carry_over.is_empty() {
+            self.groups.keys().for_each(|key| {
+                self.index
+                    .entry(projected(&self.config.carry_over, key))
+                    .or_default()
+                    .insert(key.clone());
+            });
+        }
+    }
+
+    /// Apply a baseline to a group, creating the group if needed. The
+    /// key is taken as exported, with its values already
+    /// pseudonymized.
+    pub fn import_baseline(
+        &mut self,
+        t: DateTime<Utc>,
+        key: BTreeMap<SpanKey, TagValue>,
+        metric: &MetricName,
+        baseline: StatsBaseline,
+    ) -> Result<(), BaselineSkip> {
+        if !key.keys().all(|name| self.config.key.contains(name)) {
+            return Err(BaselineSkip::IncompatibleKey);
+        }
+        let key = self.normalized(key);
+        match owned_group(&mut self.groups, &key) {
+            Some(group) => group
+                .metrics
+                .get_mut(metric)
+                .ok_or(BaselineSkip::UnknownMetric)?
+                .import_baseline(t, baseline),
+            None => {
+                let mut group = self.new_group(t, &key);
+                group
+                    .metrics
+                    .get_mut(metric)
+                    .ok_or(BaselineSkip::UnknownMetric)?
+                    .import_baseline(t, baseline)?;
+                self.add_group(key, group);
+                Ok(())
+            }
+        }
+    }
+
+    /// Remove the superseded groups that are no longer needed for a
+    /// carry-over at `t`.
+    pub fn remove_superseded(&mut self, t: DateTime<Utc>) {
+        let min_last_seen = t - self.config.carry_over_age.to_time_delta();
+        self.retain(|group| !group.superseded || group.last_seen >= min_last_seen);
+    }
+
+    /// Drop the summary and histogram statistics of the groups that
+    /// became idle at `t`. Returns the number of groups compacted.
+    pub fn compact_idle(&mut self, t: DateTime<Utc>) -> u64 {
+        let idle = self
+            .groups
+            .iter()
+            .filter(|(_, group)| !group.compacted && is_idle(&self.config, group, t))
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        idle.iter().for_each(|key| {
+            if let Some(group) = owned_group(&mut self.groups, key) {
+                group.compact();
+            }
+        });
+        idle.len() as u64
+    }
+
+    /// A frozen view of the groups. Taking a snapshot does not copy
+    /// any groups; the processor copies a group when it changes while
+    /// the group is still shared with a snapshot.
+    pub fn snapshot(&self) -> SpanSnapshot {
+        SpanSnapshot {
+            groups: self.groups.clone(),
+            offsets: config_offsets(
+                self.config
                     .metrics
                     .iter()
-                    .map(|(name, config)| (name.clone(), MetricProcessor::new(t, config)))
-                    .collect();
-                MetricsProcessor {
-                    last_seen: t,
-                    metrics,
-                }
-            })
+                    .filter(|(_, config)| self.config.is_emitted(config))
+                    .map(|(name, config)| (name, &config.stats)),
+            ),
+            paused: self.paused_metrics(),
+        }
+    }
+
+    fn paused_metrics(&self) -> BTreeSet<MetricName> {
+        self.config
             .metrics
-            .values_mut()
-            .for_each(|proc| {
-                proc.insert(t, span, parent, children);
-            });
+            .iter()
+            .filter(|(_, config)| !self.config.is_emitted(config))
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    /// The components of the group keys.
+    pub fn config(&self) -> &SpanConfig {
+        &self.config
+    }
+
+    pub fn key(&self) -> &BTreeSet<SpanKey> {
+        &self.config.key
+    }
+
+    /// Remove the groups not seen since `t`. Returns the keys of the
+    /// removed groups.
+    pub fn cleanup(&mut self, t: DateTime<Utc>) -> Vec<GroupKey> {
+        let removed = self
+            .groups
+            .iter()
+            .filter(|(_, group)| group.last_seen < t)
+            .map(|(key, _)| key.clone())
+            .collect();
+        self.retain(|group| group.last_seen >= t);
+        removed
     }
 
-    pub fn sample<F: FnMut(MetricArgs<'_>, f64)>(&mut self, t: DateTime<Utc>, mut metric: F) {
-        self.groups.iter_mut().for_each(|(key, metrics)| {
-            metrics.metrics.iter_mut().for_each(|(name, proc)| {
-                proc.sample(
+    fn retain<F: FnMut(&MetricsProcessor) -> bool>(&mut self, mut f: F) {
+        // Avoid copying a shared map when nothing is removed.
+        if self.groups.values().all(|group| f(group)) {
+            return;
+        }
+        Arc::make_mut(&mut self.groups).retain(|_, group| f(group));
+        self.build_index();
+    }
+}
+
+impl SpanSnapshot {
+    /// Emit metrics for all groups. Returns the number of regressed
+    /// windows, which were skipped.
+    pub fn sample<F: FnMut(MetricArgs<'_>, f64)>(&self, t: DateTime<Utc>, mut metric: F) -> u64 {
+        let mut invalid = 0;
+        self.groups.values().for_each(|group| {
+            let group_labels = &group.labels;
+            let emitted = || {
+                group
+                    .metrics
+                    .iter()
+                    .filter(|(name, _)| !self.paused.contains(*name))
+            };
+            // Shares the priority of the scores it is joined with.
+            if emitted().any(|(_, proc)| proc.marks_warm_up()) {
+                metric(
+                    MetricArgs {
+                        metric_name: String::from("trace_group_first_seen_timestamp_seconds"),
+                        metric_type: "anomaly_score",
+                        labels: Labels::default(),
+                        group: group_labels,
+                    },
+                    group.first_seen.timestamp_millis() as f64 / 1000.0,
+                );
+            }
+            emitted().for_each(|(name, proc)| {
+                invalid += proc.sample(
                     t,
+                    group.first_seen,
                     |super::metric::MetricArgs {
                          metric_suffix,
                          metric_type,
@@ -212,7 +1074,7 @@ impl SpanProcessor {
                                 metric_name: format!("trace_{name}"),
                                 metric_type,
                                 labels,
-                                key,
+                                group: group_labels,
                             },
                             value,
                         )
@@ -220,9 +1082,1289 @@ impl SpanProcessor {
                 );
             });
         });
+        sample_offsets(&self.offsets, metric);
+        invalid
+    }
+
+    /// The learned baselines for all groups and metrics.
+    pub fn baselines(
+        &self,
+    ) -> impl Iterator<Item = (&BTreeMap<SpanKey, TagValue>, &MetricName, StatsBaseline)> {
+        self.groups.iter().flat_map(|(key, group)| {
+            group
+                .metrics
+                .iter()
+                .filter_map(move |(name, proc)| Some((key, name, proc.baseline()?)))
+        })
+    }
+
+    /// The keys of all groups.
+    pub fn group_keys(&self) -> impl Iterator<Item = &GroupKey> {
+        self.groups.keys()
+    }
+}
+
+/// The anomaly score configs whose offset is emitted once per config.
+pub(super) fn config_offsets<'a, I>(metrics: I) -> Vec<(MetricName, AnomalyScoreConfig)>
+where
+    I: Iterator<Item = (&'a MetricName, &'a StatsConfig)>,
+{
+    metrics
+        .filter_map(|(name, stats)| Some((name.clone(), stats.config_offset()?.clone())))
+        .collect()
+}
+
+/// Emit the offset series of a config, without group labels.
+pub(super) fn sample_offsets<F: FnMut(MetricArgs<'_>, f64)>(
+    offsets: &[(MetricName, AnomalyScoreConfig)],
+    mut metric: F,
+) {
+    let no_group = GroupLabels::default();
+    offsets.iter().for_each(|(name, config)| {
+        config.sample_offset(
+            |super::metric::MetricArgs {
+                 metric_suffix,
+                 metric_type,
+                 labels,
+             },
+             value| {
+                let name = metric_suffix
+                    .map_or_else(|| name.to_string(), |suffix| format!("{name}_{suffix}"));
+                metric(
+                    MetricArgs {
+                        metric_name: format!("trace_{name}"),
+                        metric_type,
+                        labels,
+                        group: &no_group,
+                    },
+                    value,
+                )
+            },
+        );
+    });
+}
+
+impl SampledGroups {
+    /// The group for `key`, marked as corrected. Groups created after
+    /// the sample are created by `new`.
+    fn group_mut<F: FnOnce() -> MetricsProcessor>(
+        &mut self,
+        key: &GroupKey,
+        new: F,
+    ) -> &mut MetricsProcessor {
+        self.corrected.insert(key.clone());
+        let group = Arc::make_mut(&mut self.groups)
+            .entry(key.clone())
+            .or_insert_with(|| Arc::new(new()));
+        Arc::make_mut(group)
+    }
+}
+
+/// The group for `key`, copied first if it is shared with a snapshot.
+fn owned_group<'a>(
+    groups: &'a mut Arc<Groups>,
+    key: &GroupKey,
+) -> Option<&'a mut MetricsProcessor> {
+    Arc::make_mut(groups).get_mut(key).map(Arc::make_mut)
+}
+
+/// The value, copied if it is shared with a snapshot.
+pub(super) fn unshared<T: Clone>(value: Arc<T>) -> T {
+    Arc::try_unwrap(value).unwrap_or_else(|value| T::clone(&value))
+}
+
+/// Whether the group has not been seen for the config's `idle_after`
+/// at `t`.
+fn is_idle(config: &SpanConfig, group: &MetricsProcessor, t: DateTime<Utc>) -> bool {
+    config
+        .idle_after
+        .is_some_and(|idle_after| group.last_seen < t - idle_after.to_time_delta())
+}
+
+/// The group key without the carry-over components.
+fn projected(carry_over: &BTreeSet<SpanKey>, key: &GroupKey) -> GroupKey {
+    key.iter()
+        .filter(|(name, _)| !carry_over.contains(*name))
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect()
+}
+
+/// The canonical form of a numeric tag value. Integers, whether
+/// reported as Int64 or as a string, become the decimal string without
+/// sign or leading zeros. Decimal strings are formatted as the shortest
+/// representation of their value. Other values are left as-is.
+fn normalized_number(value: TagValue) -> TagValue {
+    match value {
+        TagValue::Int64(v) => TagValue::String(v.0.to_string()),
+        TagValue::String(s) => match canonical_number(&s) {
+            Some(n) => TagValue::String(n),
+            None => TagValue::String(s),
+        },
+        value => value,
+    }
+}
+
+fn canonical_number(s: &str) -> Option<String> {
+    if let Ok(n) = s.parse::<i64>() {
+        return Some(n.to_string());
+    }
+    let unsigned = s.strip_prefix(['+', '-']).unwrap_or(s);
+    let (int, frac) = unsigned.split_once('.')?;
+    let digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    if !digits(int) || !digits(frac) {
+        return None;
+    }
+    let n = s.parse::<f64>().ok()?;
+    Some(if n == 0.0 { 0.0 } else { n }.to_string())
+}
+
+impl MetricsProcessor {
+    /// Update the annotation values. Annotations absent on the span
+    /// keep their previous value.
+    fn annotate(&mut self, annotations: GroupKey) {
+        let mut changed = false;
+        annotations.into_iter().for_each(|(name, value)| {
+            if self.annotations.get(&name) != Some(&value) {
+                self.annotations.insert(name, value);
+                changed = true;
+            }
+        });
+        if changed {
+            self.labels = self.base_labels.annotated(&self.annotations);
+        }
+    }
+
+    /// Drop the summary and histogram statistics.
+    fn compact(&mut self) {
+        self.metrics.values_mut().for_each(MetricProcessor::compact);
+        self.compacted = true;
+    }
+
+    /// Start the dropped statistics over, when the group is seen again.
+    fn restore(&mut self, t: DateTime<Utc>, config: &SpanConfig) {
+        self.metrics.iter_mut().for_each(|(name, proc)| {
+            if let Some(config) = config.metrics.get(name) {
+                proc.restore(t, config);
+            }
+        });
+        self.compacted = false;
+    }
+
+    fn new(t: DateTime<Utc>, config: &SpanConfig, labels: GroupLabels) -> Self {
+        Self {
+            base_labels: labels.clone(),
+            labels,
+            annotations: GroupKey::new(),
+            last_seen: t,
+            first_seen: t,
+            metrics: config
+                .metrics
+                .iter()
+                .map(|(name, config)| (name.clone(), MetricProcessor::new(t, config)))
+                .collect(),
+            superseded: false,
+            compacted: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::{BTreeMap, BTreeSet},
+        sync::Arc,
+        time::Instant,
+    };
+
+    use chrono::{DateTime, TimeDelta, Utc};
+    use jaeger_anomaly_detection::Duration;
+    use serde::Serialize;
+
+    use super::{
+        canonical_number, decode_lenient, GroupKey, MetricsProcessor, MetricsState, MetricsStateV1,
+        MissingKey, SpanConfig, SpanProcessor, SpanSnapshot, SpanState, SPAN_STATE_VERSION,
+    };
+    use crate::{
+        config::{Ancestors, ConfigName, KeyName, MetricName, SpanClass, SpanClassifier, SpanKey},
+        jaeger::{Int64, Span, Tag, TagValue},
+        processor::{
+            anomaly_score::AnomalyScoreConfig,
+            histogram::HistogramConfig,
+            mean_stddev::MeanStddevConfig,
+            metric::GuardCounts,
+            pseudonymize::{PseudonymizationKey, Pseudonymize},
+            sim::{assert_fixture, read_fixture, span, start},
+            stats::{MaxValueAction, StatsConfig},
+            summary::SummaryConfig,
+            trace::TraceConfig,
+        },
+    };
+
+    /// The span state as saved before the version field was added.
+    #[derive(Serialize)]
+    struct LegacySpanState {
+        groups: BTreeMap<GroupKey, MetricsState>,
+    }
+
+    /// A span state with a different version.
+    #[derive(Serialize)]
+    struct FutureSpanState {
+        version: u32,
+        groups: BTreeMap<GroupKey, MetricsState>,
+    }
+
+    fn name() -> ConfigName {
+        ConfigName::new("default")
+    }
+
+    fn config() -> SpanConfig {
+        TraceConfig::default().configs[&name()].clone()
+    }
+
+    /// A processor with one group per operation.
+    fn processor(t: DateTime<Utc>, operations: usize) -> SpanProcessor {
+        let mut proc = SpanProcessor::new(&name(), &config());
+        for i in 0..operations {
+            let span = span(
+                "1",
+                &i.to_string(),
+                None,
+                "frontend",
+                &format!("op-{i}"),
+                t.timestamp_micros(),
+                1000,
+            );
+            proc.insert(t, &span, Ancestors::default(), &[], None, &[]);
+        }
+        proc
+    }
+
+    /// A processor with one group per operation, each receiving a span
+    /// of varying duration every minute for `minutes` minutes, so that
+    /// the windows of their statistics are populated.
+    fn populated(operations: usize, minutes: i64) -> SpanProcessor {
+        let mut proc = SpanProcessor::new(&name(), &config());
+        for m in 0..minutes {
+            let t = start() + TimeDelta::minutes(m);
+            for i in 0..operations {
+                let span = span(
+                    &m.to_string(),
+                    &i.to_string(),
+                    None,
+                    "frontend",
+                    &format!("op-{i}"),
+                    t.timestamp_micros(),
+                    1000 + (7 * i as i64 + 13 * m) % 500,
+                );
+                proc.insert(t, &span, Ancestors::default(), &[], None, &[]);
+            }
+        }
+        proc
+    }
+
+    fn encode<T: Serialize>(state: &T) -> Vec<u8> {
+        let mut data = Vec::new();
+        ciborium::into_writer(state, &mut data).unwrap();
+        data
+    }
+
+    fn decode(data: &[u8]) -> Result<SpanState, ciborium::de::Error<std::io::Error>> {
+        ciborium::from_reader(data)
+    }
+
+    /// Convert a saved state to the legacy format, with the first group
+    /// in the V0 format and the others in the V1 format.
+    fn legacy(state: SpanState) -> LegacySpanState {
+        LegacySpanState {
+            groups: state
+                .groups
+                .into_iter()
+                .enumerate()
+                .map(|(i, (key, state))| match state {
+                    MetricsState::V1(MetricsStateV1 { metrics, .. }) if i == 0 => {
+                        (key, MetricsState::V0(metrics))
+                    }
+                    state => (key, state),
+                })
+                .collect(),
+        }
+    }
+
+    fn last_seen(proc: &SpanProcessor) -> Vec<DateTime<Utc>> {
+        proc.groups.values().map(|group| group.last_seen).collect()
+    }
+
+    /// Insert a span for operation `i` at `t`.
+    fn insert_op(proc: &mut SpanProcessor, t: DateTime<Utc>, i: usize) {
+        let span = span(
+            "2",
+            &i.to_string(),
+            None,
+            "frontend",
+            &format!("op-{i}"),
+            t.timestamp_micros(),
+            1000,
+        );
+        proc.insert(t, &span, Ancestors::default(), &[], None, &[]);
+    }
+
+    /// The last-seen times (in the processor and in the snapshot) of
+    /// the groups no longer shared with the snapshot.
+    fn copied(
+        proc: &SpanProcessor,
+        snapshot: &SpanSnapshot,
+    ) -> Vec<(DateTime<Utc>, DateTime<Utc>)> {
+        assert!(proc.groups.keys().eq(snapshot.groups.keys()));
+        proc.groups
+            .values()
+            .zip(snapshot.groups.values())
+            .filter(|(group, old)| !Arc::ptr_eq(group, old))
+            .map(|(group, old)| (group.last_seen, old.last_seen))
+            .collect()
+    }
+
+    #[test]
+    fn versioned_state() {
+        let t = start();
+        let proc = processor(t, 3);
+        let data = encode(&proc.save());
+
+        let state = decode(&data).unwrap();
+        assert_eq!(state.version, SPAN_STATE_VERSION);
+        assert!(state
+            .groups
+            .values()
+            .all(|state| matches!(state, MetricsState::V1(_))));
+
+        let loaded = SpanProcessor::load(t, &name(), state, &config());
+        assert!(loaded.groups.keys().eq(proc.groups.keys()));
+        assert_eq!(last_seen(&loaded), vec![t; 3]);
+        let state = decode(&encode(&loaded.save())).unwrap();
+        assert!(state.groups.keys().eq(proc.groups.keys()));
+    }
+
+    fn first_seen(proc: &SpanProcessor) -> Vec<DateTime<Utc>> {
+        proc.groups.values().map(|group| group.first_seen).collect()
+    }
+
+    #[test]
+    fn first_seen_state() {
+        let t = start();
+        let mut proc = processor(t, 2);
+        insert_op(&mut proc, t + TimeDelta::hours(1), 0);
+        let later = t + TimeDelta::hours(2);
+        let loaded = SpanProcessor::load(
+            later,
+            &name(),
+            decode(&encode(&proc.save())).unwrap(),
+            &config(),
+        );
+        assert_eq!(first_seen(&loaded), vec![t; 2]);
+
+        // Groups saved before the first-seen time was tracked count
+        // as first seen when the state is loaded.
+        let mut state = proc.save();
+        state.groups.values_mut().for_each(|state| {
+            if let MetricsState::V1(state) = state {
+                state.first_seen = None;
+            }
+        });
+        let loaded =
+            SpanProcessor::load(later, &name(), decode(&encode(&state)).unwrap(), &config());
+        assert_eq!(first_seen(&loaded), vec![later; 2]);
+        let loaded = SpanProcessor::load(
+            later,
+            &name(),
+            decode(&encode(&legacy(proc.save()))).unwrap(),
+            &config(),
+        );
+        assert_eq!(first_seen(&loaded), vec![later, t]);
+    }
+
+    /// The config with warm-up marking enabled for all anomaly scores.
+    fn warm_up_config() -> SpanConfig {
+        let mut config = config();
+        config.metrics.values_mut().for_each(|metric| {
+            if let Some(anomaly_score) = &mut metric.stats.anomaly_score {
+                let mut value = serde_json::to_value(&*anomaly_score).unwrap();
+                value["warm_up"] = serde_json::Value::Bool(true);
+                *anomaly_score = serde_json::from_value(value).unwrap();
+            }
+        });
+        config
+    }
+
+    /// The emitted first-seen times, and the reference intervals of
+    /// the samples labeled as warming up.
+    fn warm_up(proc: &SpanProcessor, t: DateTime<Utc>) -> (BTreeSet<i64>, BTreeSet<String>) {
+        let mut first_seen = BTreeSet::new();
+        let mut warming_up = BTreeSet::new();
+        proc.snapshot().sample(t, |args, value| {
+            if args.metric_name == "trace_group_first_seen_timestamp_seconds" {
+                first_seen.insert(value as i64);
+            }
+            if args.labels.warming_up {
+                warming_up.insert(args.labels.reference.unwrap().to_string());
+            }
+        });
+        (first_seen, warming_up)
+    }
+
+    #[test]
+    fn warm_up_label() {
+        let t = start();
+        let first_seen = BTreeSet::from([t.timestamp()]);
+        let mut proc = SpanProcessor::new(&name(), &warm_up_config());
+        let mut warm_up_after = |days: i64| {
+            let t = t + TimeDelta::days(days);
+            insert_op(&mut proc, t, 0);
+            let (first, warming_up) = warm_up(&proc, t);
+            assert_eq!(first, first_seen);
+            warming_up
+        };
+        assert_eq!(
+            warm_up_after(0),
+            BTreeSet::from([String::from("7d"), String::from("30d")])
+        );
+        assert_eq!(
+            warm_up_after(1),
+            BTreeSet::from([String::from("7d"), String::from("30d")])
+        );
+        assert_eq!(warm_up_after(8), BTreeSet::from([String::from("30d")]));
+        assert_eq!(warm_up_after(31), BTreeSet::new());
+
+        // The first-seen time survives a restart.
+        let later = t + TimeDelta::days(8);
+        let loaded = SpanProcessor::load(
+            later,
+            &name(),
+            decode(&encode(&proc.save())).unwrap(),
+            &warm_up_config(),
+        );
+        assert_eq!(
+            warm_up(&loaded, later),
+            (first_seen, BTreeSet::from([String::from("30d")]))
+        );
+
+        // Without the flag, nothing is marked.
+        let proc = processor(t, 1);
+        assert_eq!(warm_up(&proc, t), (BTreeSet::new(), BTreeSet::new()));
+    }
+
+    /// The samples of the bounded metrics (duration and busy), by
+    /// series, as bits to compare NaN values.
+    fn bounded_samples(proc: &SpanProcessor, t: DateTime<Utc>) -> BTreeMap<String, u64> {
+        let mut samples = BTreeMap::new();
+        proc.snapshot().sample(t, |args, value| {
+            if args.metric_name.starts_with("trace_duration")
+                || args.metric_name.starts_with("trace_busy")
+            {
+                let series = format!(
+                    "{} {} {:?} {:?} {:?} {:?}",
+                    args.metric_name,
+                    args.metric_type,
+                    args.labels.q,
+                    args.labels.le,
+                    args.labels.immediate,
+                    args.labels.reference
+                );
+                samples.insert(series, value.to_bits());
+            }
+        });
+        samples
+    }
+
+    #[test]
+    fn max_value_guard() {
+        let t = start();
+        let outlier = span(
+            "3",
+            "outlier",
+            None,
+            "frontend",
+            "op-0",
+            t.timestamp_micros(),
+            TimeDelta::days(3).num_microseconds().unwrap(),
+        );
+        let insert_outlier = |config: &SpanConfig| {
+            let mut proc = SpanProcessor::new(&name(), config);
+            insert_op(&mut proc, t, 0);
+            proc.insert(t, &outlier, Ancestors::default(), &[], None, &[]);
+            proc
+        };
+        let mut expected = SpanProcessor::new(&name(), &config());
+        insert_op(&mut expected, t, 0);
+        let expected = bounded_samples(&expected, t);
+        assert!(!expected.is_empty());
+
+        let proc = insert_outlier(&config());
+        assert_eq!(bounded_samples(&proc, t), expected);
+        let dropped = GuardCounts {
+            clamped: 0,
+            dropped: 1,
+        };
+        assert_eq!(
+            proc.guarded(),
+            &BTreeMap::from([
+                (MetricName::new("busy"), dropped),
+                (MetricName::new("duration"), dropped),
+            ])
+        );
+
+        // Clamped values reach the statistics at the bound.
+        let mut clamp = config();
+        clamp
+            .metrics
+            .values_mut()
+            .for_each(|metric| metric.stats.on_max_value = MaxValueAction::Clamp);
+        let proc = insert_outlier(&clamp);
+        assert_ne!(bounded_samples(&proc, t), expected);
+        assert_eq!(proc.guarded()[&MetricName::new("duration")].clamped, 1);
+
+        // Without the bound, the outlier is inserted as it is.
+        let mut unbounded = config();
+        unbounded
+            .metrics
+            .values_mut()
+            .for_each(|metric| metric.stats.max_value = None);
+        let proc = insert_outlier(&unbounded);
+        assert_ne!(bounded_samples(&proc, t), expected);
+        assert!(proc.guarded().is_empty());
+
+        // The counts survive config updates, for the remaining metrics.
+        let mut proc = insert_outlier(&config());
+        let mut config = config();
+        config
+            .metrics
+            .retain(|name, _| name == &MetricName::new("duration"));
+        proc = proc.update(t, &config);
+        assert_eq!(
+            proc.guarded(),
+            &BTreeMap::from([(MetricName::new("duration"), dropped)])
+        );
+    }
+
+    /// The metric types emitted per operation.
+    fn metric_types(
+        proc: &SpanProcessor,
+        t: DateTime<Utc>,
+    ) -> BTreeMap<String, BTreeSet<&'static str>> {
+        let mut types = BTreeMap::<String, BTreeSet<&'static str>>::new();
+        proc.snapshot().sample(t, |args, _| {
+            if let Some(operation) = args.group.get("operation_name") {
+                types
+                    .entry(operation.to_string())
+                    .or_default()
+                    .insert(args.metric_type);
+            }
+        });
+        types
+    }
+
+    #[test]
+    fn idle_groups_are_compacted() {
+        let mut config = config();
+        config.idle_after = Some(Duration::Days(1));
+        config
+            .metrics
+            .retain(|name, _| name == &MetricName::new("duration"));
+        config.metrics.values_mut().for_each(|metric| {
+            metric.stats = StatsConfig {
+                anomaly_score: Some(AnomalyScoreConfig::default()),
+                mean_stddev: Some(MeanStddevConfig::default()),
+                summary: Some(SummaryConfig::default()),
+                histogram: Some(HistogramConfig {
+                    bounds: vec![500.0, 2000.0],
+                }),
+                ..StatsConfig::default()
+            };
+        });
+
+        let t = start();
+        let later = t + TimeDelta::days(2);
+        let mut proc = SpanProcessor::new(&name(), &config);
+        insert_op(&mut proc, t, 0);
+        insert_op(&mut proc, t, 1);
+        insert_op(&mut proc, later, 1);
+        let full_size = encode(&proc.save()).len();
+        assert_eq!(proc.compact_idle(t + TimeDelta::hours(1)), 0);
+        assert_eq!(proc.compact_idle(later), 1);
+        assert_eq!(proc.compact_idle(later), 0);
+        assert!(encode(&proc.save()).len() < full_size);
+
+        let state = decode(&encode(&proc.save())).unwrap();
+        let mut loaded = SpanProcessor::load(later, &name(), state, &config);
+        let types = metric_types(&loaded, later);
+        assert!(!types["op-0"].contains("summary"));
+        assert!(!types["op-0"].contains("histogram"));
+        assert!(types["op-1"].contains("summary"));
+        assert!(types["op-1"].contains("histogram"));
+        let baselines = loaded.snapshot().baselines().count();
+        assert_eq!(baselines, 2);
+        assert!(loaded
+            .snapshot()
+            .baselines()
+            .all(|(_, _, baseline)| baseline
+                .anomaly_score
+                .is_some_and(|baseline| !baseline.reference.is_empty())));
+
+        // The statistics start over when the group is seen again.
+        insert_op(&mut loaded, later, 0);
+        let types = metric_types(&loaded, later);
+        assert!(types["op-0"].contains("summary"));
+        assert!(types["op-0"].contains("histogram"));
+        assert_eq!(loaded.compact_idle(later), 0);
+    }
+
+    #[test]
+    fn legacy_state() {
+        let t = start();
+        let proc = processor(t, 3);
+        let data = encode(&legacy(proc.save()));
+
+        let state = decode(&data).unwrap();
+        assert!(matches!(
+            state.groups.values().next(),
+            Some(MetricsState::V0(_))
+        ));
+
+        // Groups without a last-seen time are kept for another day.
+        let now = t + TimeDelta::hours(1);
+        let loaded = SpanProcessor::load(now, &name(), state, &config());
+        assert!(loaded.groups.keys().eq(proc.groups.keys()));
+        assert_eq!(last_seen(&loaded), vec![now - TimeDelta::days(29), t, t]);
+
+        // The state is saved in the current format.
+        let state = decode(&encode(&loaded.save())).unwrap();
+        assert_eq!(state.version, SPAN_STATE_VERSION);
+    }
+
+    #[test]
+    fn undecodable_groups_are_dropped() {
+        let t = start();
+        let proc = processor(t, 3);
+        let mut state = ciborium::Value::serialized(&proc.save()).unwrap();
+        let groups = state
+            .as_map_mut()
+            .unwrap()
+            .iter_mut()
+            .find(|(field, _)| field.as_text() == Some("groups"))
+            .and_then(|(_, groups)| groups.as_map_mut())
+            .unwrap();
+        groups[1].1 = ciborium::Value::Text(String::from("corrupt"));
+        groups[2].0 = ciborium::Value::Integer(1.into());
+        let data = encode(&state);
+
+        assert!(decode(&data).is_err());
+        let state = decode_lenient(|| decode(&data)).unwrap();
+        assert_eq!(state.groups.len(), 1);
+        assert_eq!(state.dropped_groups().len(), 2);
+        assert!(state.dropped_groups()[0].contains("op-1"));
+        assert!(state.dropped_groups()[1].starts_with("invalid key"));
+
+        let loaded = SpanProcessor::load(t, &name(), state, &config());
+        assert!(loaded.groups.keys().eq(proc.groups.keys().take(1)));
+    }
+
+    #[test]
+    fn unsupported_state_version() {
+        let data = encode(&FutureSpanState {
+            version: SPAN_STATE_VERSION + 1,
+            groups: processor(start(), 1).save().groups,
+        });
+        assert!(decode(&data)
+            .unwrap_err()
+            .to_string()
+            .contains("unsupported span state version"));
+    }
+
+    #[test]
+    fn normalized_numeric_tags() {
+        let t = start();
+        let spans = [
+            TagValue::Int64(Int64(200)),
+            TagValue::String(String::from("200")),
+            TagValue::String(String::from("+200")),
+            TagValue::String(String::from("200.0")),
+        ]
+        .into_iter()
+        .enumerate()
+        .map(|(i, value)| {
+            let mut span = span(
+                "1",
+                &i.to_string(),
+                None,
+                "frontend",
+                "GET",
+                t.timestamp_micros(),
+                1000,
+            );
+            span.tags.push(Tag {
+                key: String::from("http.status_code"),
+                value,
+            });
+            span
+        })
+        .collect::<Vec<_>>();
+        let processor = |normalize_numbers| {
+            let mut config = config();
+            config
+                .key
+                .insert(SpanKey::Current(KeyName::SpanTag(String::from(
+                    "http.status_code",
+                ))));
+            config.normalize_numbers = normalize_numbers;
+            let mut proc = SpanProcessor::new(&name(), &config);
+            spans
+                .iter()
+                .for_each(|span| proc.insert(t, span, Ancestors::default(), &[], None, &[]));
+            proc
+        };
+
+        // Without normalization, each type and format is a separate
+        // group.
+        assert_eq!(processor(false).groups.len(), 4);
+
+        let proc = processor(true);
+        assert_eq!(proc.groups.len(), 1);
+        let group = proc.groups.values().next().unwrap();
+        assert_eq!(group.labels.get("http_status_code"), Some("200"));
+        assert_eq!(group.labels.get("config"), Some("default"));
+    }
+
+    #[test]
+    fn baggage_key() {
+        let t = start();
+        let spans = [
+            ("baggage.tenant", "acme"),
+            ("w3c.tracestate", "rojo=00f067aa0ba902b7,tenant=initech"),
+            ("w3c.tracestate", "tenant@congo=acme"),
+        ]
+        .into_iter()
+        .enumerate()
+        .map(|(i, (key, value))| {
+            let mut span = span(
+                "1",
+                &i.to_string(),
+                None,
+                "frontend",
+                "GET",
+                t.timestamp_micros(),
+                1000,
+            );
+            span.tags.push(Tag {
+                key: String::from(key),
+                value: TagValue::String(String::from(value)),
+            });
+            span
+        })
+        .collect::<Vec<_>>();
+        let mut config = config();
+        config
+            .key
+            .insert(SpanKey::Current(KeyName::Baggage(String::from("tenant"))));
+        let mut proc = SpanProcessor::new(&name(), &config);
+        spans
+            .iter()
+            .for_each(|span| proc.insert(t, span, Ancestors::default(), &[], None, &[]));
+
+        let mut tenants = proc
+            .groups
+            .values()
+            .map(|group| group.labels.get("baggage_tenant"))
+            .collect::<Vec<_>>();
+        tenants.sort();
+        assert_eq!(tenants, [Some("acme"), Some("initech")]);
+    }
+
+    #[test]
+    fn class_key() {
+        let t = start();
+        let message = SpanKey::tag("exception.message");
+        let mut config = config();
+        config.classify.insert(
+            String::from("error"),
+            SpanClassifier {
+                classes: Vec::from([
+                    SpanClass {
+                        value: String::from("timeout"),
+                        select: message.clone().one_of(["timeout", "deadline exceeded"]),
+                    },
+                    SpanClass {
+                        value: String::from("failed"),
+                        select: message.clone().has(),
+                    },
+                ]),
+                fallback: String::from("other"),
+            },
+        );
+        config
+            .key
+            .insert(SpanKey::Current(KeyName::Class(String::from("error"))));
+        let mut proc = SpanProcessor::new(&name(), &config);
+        [
+            Some("timeout"),
+            Some("deadline exceeded"),
+            Some("refused"),
+            None,
+        ]
+        .into_iter()
+        .enumerate()
+        .for_each(|(i, value)| {
+            let mut span = span(
+                "1",
+                &i.to_string(),
+                None,
+                "frontend",
+                "GET",
+                t.timestamp_micros(),
+                1000,
+            );
+            span.tags.extend(value.map(|value| Tag {
+                key: String::from("exception.message"),
+                value: TagValue::String(String::from(value)),
+            }));
+            proc.insert(t, &span, Ancestors::default(), &[], None, &[]);
+        });
+
+        let mut classes = proc
+            .groups
+            .values()
+            .map(|group| group.labels.get("error"))
+            .collect::<Vec<_>>();
+        classes.sort();
+        assert_eq!(classes, [Some("failed"), Some("other"), Some("timeout")]);
     }
 
-    pub fn cleanup(&mut self, t: DateTime<Utc>) {
-        self.groups.retain(|_, proc| proc.last_seen >= t);
+    #[test]
+    fn pseudonymized_key() {
+        let t = start();
+        let mut config = config();
+        config.pseudonymize.push(Pseudonymize {
+            key: SpanKey::Current(KeyName::OperationName),
+            keep: BTreeSet::from([String::from("op-0")]),
+        });
+        let operations = |key: &PseudonymizationKey| {
+            let mut proc = SpanProcessor::new(&name(), &config);
+            proc.set_pseudonymization(Some(key.clone()));
+            (0..2).for_each(|i| insert_op(&mut proc, t, i));
+            let mut operations = proc
+                .groups
+                .values()
+                .map(|group| group.labels.get("operation_name").unwrap().to_string())
+                .collect::<Vec<_>>();
+            operations.sort();
+            operations
+        };
+
+        let key = PseudonymizationKey::new(b"secret");
+        let other = PseudonymizationKey::new(b"other");
+        assert_eq!(
+            operations(&key),
+            [String::from("op-0"), key.pseudonym("op-1")]
+        );
+        assert_eq!(
+            operations(&other),
+            [String::from("op-0"), other.pseudonym("op-1")]
+        );
+        assert_ne!(key.pseudonym("op-1"), other.pseudonym("op-1"));
+    }
+
+    #[test]
+    fn canonical_numbers() {
+        assert_eq!(canonical_number("+200").as_deref(), Some("200"));
+        assert_eq!(canonical_number("007").as_deref(), Some("7"));
+        assert_eq!(canonical_number("1.50").as_deref(), Some("1.5"));
+        assert_eq!(canonical_number("-0.0").as_deref(), Some("0"));
+        assert_eq!(canonical_number("1e3"), None);
+        assert_eq!(canonical_number("1."), None);
+        assert_eq!(canonical_number("v1.2"), None);
+    }
+
+    #[test]
+    fn annotation_labels() {
+        let t = start();
+        let pod = |name: &str| {
+            let mut span = span(
+                "1",
+                "1",
+                None,
+                "frontend",
+                "GET",
+                t.timestamp_micros(),
+                1000,
+            );
+            span.process.tags.push(Tag {
+                key: String::from("k8s.pod.name"),
+                value: TagValue::String(name.to_string()),
+            });
+            span
+        };
+        let mut config = config();
+        config
+            .annotations
+            .insert(SpanKey::Current(KeyName::ProcessTag(String::from(
+                "k8s.pod.name",
+            ))));
+        let mut proc = SpanProcessor::new(&name(), &config);
+        let annotation = |proc: &SpanProcessor| {
+            assert_eq!(proc.groups.len(), 1);
+            let group = proc.groups.values().next().unwrap();
+            group.labels.get("k8s_pod_name").map(String::from)
+        };
+
+        proc.insert(
+            t,
+            &span("1", "1", None, "frontend", "GET", 0, 1000),
+            Ancestors::default(),
+            &[],
+            None,
+            &[],
+        );
+        assert_eq!(annotation(&proc), None);
+        proc.insert(t, &pod("frontend-1"), Ancestors::default(), &[], None, &[]);
+        assert_eq!(annotation(&proc).as_deref(), Some("frontend-1"));
+
+        // A newer span updates the label, an older one does not.
+        let later = t + TimeDelta::seconds(1);
+        proc.insert(
+            later,
+            &pod("frontend-2"),
+            Ancestors::default(),
+            &[],
+            None,
+            &[],
+        );
+        assert_eq!(annotation(&proc).as_deref(), Some("frontend-2"));
+        proc.insert(t, &pod("frontend-1"), Ancestors::default(), &[], None, &[]);
+        assert_eq!(annotation(&proc).as_deref(), Some("frontend-2"));
+
+        // The group key labels are unaffected, and the annotation is
+        // kept across a save.
+        let group = proc.groups.values().next().unwrap();
+        assert_eq!(group.labels.get("service_name"), Some("frontend"));
+        assert_eq!(group.base_labels.get("k8s_pod_name"), None);
+        let loaded = SpanProcessor::load(
+            later,
+            &name(),
+            decode(&encode(&proc.save())).unwrap(),
+            &config,
+        );
+        assert_eq!(annotation(&loaded).as_deref(), Some("frontend-2"));
+    }
+
+    /// A span without namespace is left out of the namespace label,
+    /// skipped, or given the placeholder namespace.
+    #[test]
+    fn missing_key_policies() {
+        let t = start();
+        let mut span = span(
+            "1",
+            "1",
+            None,
+            "frontend",
+            "GET",
+            t.timestamp_micros(),
+            1000,
+        );
+        span.process
+            .tags
+            .retain(|tag| tag.key != "service.namespace");
+        let namespace = SpanKey::Current(KeyName::ProcessTag(String::from("service.namespace")));
+        let instance_id =
+            SpanKey::Current(KeyName::ProcessTag(String::from("service.instance.id")));
+        let keys = |span: &Span, missing_key: MissingKey| {
+            let config = SpanConfig {
+                missing_key,
+                ..config()
+            };
+            let mut proc = SpanProcessor::new(&name(), &config);
+            proc.insert(t, span, Ancestors::default(), &[], None, &[]);
+            let keys = proc.snapshot().group_keys().cloned().collect::<Vec<_>>();
+            (keys, proc.skipped_spans())
+        };
+        let placeholders = |labels: &[&str]| {
+            MissingKey::Placeholder(
+                labels
+                    .iter()
+                    .map(|label| (label.to_string(), String::from("unknown")))
+                    .collect(),
+            )
+        };
+
+        let (omitted, skipped) = keys(&span, MissingKey::Omit);
+        assert_eq!(skipped, 0);
+        assert_eq!(omitted.len(), 1);
+        assert!(!omitted[0].contains_key(&namespace));
+        assert_eq!(
+            omitted[0].get(&SpanKey::Current(KeyName::ServiceName)),
+            Some(&TagValue::String(String::from("frontend")))
+        );
+
+        let (skipped_keys, skipped) = keys(&span, MissingKey::Skip);
+        assert!(skipped_keys.is_empty());
+        assert_eq!(skipped, 1);
+
+        let (placeholder, skipped) = keys(&span, placeholders(&["service_namespace"]));
+        assert_eq!(skipped, 0);
+        assert_eq!(placeholder.len(), 1);
+        assert_eq!(
+            placeholder[0].get(&namespace),
+            Some(&TagValue::String(String::from("unknown")))
+        );
+        // Present components are not replaced.
+        assert_eq!(
+            placeholder[0].get(&instance_id),
+            Some(&TagValue::String(String::from("test-0")))
+        );
+
+        // Missing components without a placeholder are left out.
+        span.process
+            .tags
+            .retain(|tag| tag.key != "service.instance.id");
+        let (placeholder, skipped) = keys(&span, placeholders(&["service_namespace"]));
+        assert_eq!(skipped, 0);
+        assert_eq!(placeholder.len(), 1);
+        assert_eq!(
+            placeholder[0].get(&namespace),
+            Some(&TagValue::String(String::from("unknown")))
+        );
+        assert!(!placeholder[0].contains_key(&instance_id));
+
+        // The built-in config has a placeholder for the service
+        // components that not every SDK sets.
+        assert_eq!(
+            config().missing_key,
+            placeholders(&["service_namespace", "service_instance_id"])
+        );
+    }
+
+    #[test]
+    fn snapshot_shares_unchanged_groups() {
+        let t = start();
+        let mut proc = processor(t, 3);
+        let snapshot = proc.snapshot();
+        assert!(Arc::ptr_eq(&proc.groups, &snapshot.groups));
+
+        // Only the changed group is copied; the snapshot keeps the
+        // group as it was.
+        let later = t + TimeDelta::seconds(1);
+        insert_op(&mut proc, later, 1);
+        assert_eq!(copied(&proc, &snapshot), vec![(later, t)]);
+        insert_op(&mut proc, later, 1);
+        assert_eq!(copied(&proc, &snapshot), vec![(later, t)]);
+
+        // Without a snapshot, nothing is copied.
+        drop(snapshot);
+        let group = Arc::as_ptr(proc.groups.values().nth(1).unwrap());
+        insert_op(&mut proc, later, 1);
+        assert_eq!(Arc::as_ptr(proc.groups.values().nth(1).unwrap()), group);
+    }
+
+    /// The growth of the resident set while all of 100,000 groups
+    /// change, with and without a snapshot kept. Not measured yet; run
+    /// with `cargo test --release -- --ignored bench_snapshot_memory
+    /// --nocapture` (on Linux, where it is read from `/proc`).
+    #[test]
+    #[ignore]
+    fn bench_snapshot_memory() {
+        let resident = || {
+            let statm = std::fs::read_to_string("/proc/self/statm").unwrap();
+            let pages = statm.split_whitespace().nth(1).unwrap();
+            pages.parse::<u64>().unwrap() * 4096
+        };
+        let t = start();
+        let later = t + TimeDelta::seconds(1);
+        for keep in [false, true] {
+            let mut proc = processor(t, 100_000);
+            let before = resident();
+            let snapshot = keep.then(|| proc.snapshot());
+            (0..100_000).for_each(|i| insert_op(&mut proc, later, i));
+            let grown = resident().saturating_sub(before);
+            println!(
+                "snapshot kept: {keep}, resident set grew by {} MiB",
+                grown >> 20
+            );
+            drop(snapshot);
+        }
+    }
+
+    /// The names of the emitted metrics.
+    fn emitted(proc: &SpanProcessor, t: DateTime<Utc>) -> BTreeSet<String> {
+        let mut names = BTreeSet::new();
+        proc.snapshot().sample(t, |args, _| {
+            names.insert(args.metric_name);
+        });
+        names
+    }
+
+    #[test]
+    fn pause_and_resume() {
+        let t = start();
+        let paused = SpanConfig {
+            enabled: false,
+            ..config()
+        };
+        let mut expected = SpanProcessor::new(&name(), &config());
+        let mut proc = SpanProcessor::new(&name(), &config());
+        for i in 0..3 {
+            insert_op(&mut expected, t, i);
+            insert_op(&mut proc, t, i);
+        }
+
+        // Paused configs keep processing spans without emitting.
+        proc = proc.update(t, &paused);
+        let later = t + TimeDelta::minutes(1);
+        for i in 0..3 {
+            insert_op(&mut expected, later, i);
+            insert_op(&mut proc, later, i);
+        }
+        assert!(emitted(&proc, later).is_empty());
+        assert_eq!(proc.snapshot().paused.len(), config().metrics.len());
+
+        // Resuming continues from the kept statistics.
+        proc = proc.update(later, &config());
+        assert!(!bounded_samples(&proc, later).is_empty());
+        assert_eq!(
+            bounded_samples(&proc, later),
+            bounded_samples(&expected, later)
+        );
+        assert_eq!(emitted(&proc, later), emitted(&expected, later));
+
+        // Paused metrics are left out of the other series.
+        let mut config = config();
+        config
+            .metrics
+            .get_mut(&MetricName::new("duration"))
+            .unwrap()
+            .enabled = false;
+        proc = proc.update(later, &config);
+        let names = emitted(&proc, later);
+        assert!(!names.is_empty());
+        assert!(names.iter().all(|name| !name.starts_with("trace_duration")));
+        assert_eq!(
+            names,
+            emitted(&expected, later)
+                .into_iter()
+                .filter(|name| !name.starts_with("trace_duration"))
+                .collect()
+        );
+    }
+
+    #[test]
+    fn enabled_defaults_to_true() {
+        let mut value = serde_json::to_value(config()).unwrap();
+        value.as_object_mut().unwrap().remove("enabled");
+        value["metrics"]["duration"]
+            .as_object_mut()
+            .unwrap()
+            .remove("enabled");
+        let config: SpanConfig = serde_json::from_value(value).unwrap();
+        assert!(config.enabled);
+        assert!(config.metrics[&MetricName::new("duration")].enabled);
+
+        let paused = SpanConfig {
+            enabled: false,
+            ..config
+        };
+        let value = serde_json::to_value(&paused).unwrap();
+        assert_eq!(value["enabled"], serde_json::Value::Bool(false));
+        assert_eq!(serde_json::from_value::<SpanConfig>(value).unwrap(), paused);
+    }
+
+    /// Measure the overhead of keeping a snapshot of 100k groups while
+    /// 1% of the groups are updated. Run with `cargo test --release --
+    /// --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_snapshot() {
+        let t = start();
+        let mut proc = processor(t, 100_000);
+
+        let t0 = Instant::now();
+        let snapshot = proc.snapshot();
+        let snapshot_time = t0.elapsed();
+
+        let later = t + TimeDelta::seconds(1);
+        let t0 = Instant::now();
+        (0..100_000)
+            .step_by(100)
+            .for_each(|i| insert_op(&mut proc, later, i));
+        let insert_time = t0.elapsed();
+
+        let copied = copied(&proc, &snapshot).len();
+        assert_eq!(copied, 1000);
+        let map_bytes =
+            proc.groups.len() * std::mem::size_of::<(GroupKey, Arc<MetricsProcessor>)>();
+        let group_bytes = copied * std::mem::size_of::<MetricsProcessor>();
+        println!(
+            "snapshot: {snapshot_time:?}, 1000 inserts: {insert_time:?}, \
+             copied groups: {copied} of {}, overhead (excluding heap data): \
+             {} KiB map entries, {} KiB groups",
+            proc.groups.len(),
+            map_bytes / 1024,
+            group_bytes / 1024
+        );
+    }
+
+    /// The checked-in states are saved by `SpanProcessor::save` from 20
+    /// populated groups: once as is, and once in the legacy format,
+    /// without a version and with the first group in the V0 format.
+    /// Rerun with `UPDATE_GOLDEN=1` to regenerate them.
+    #[test]
+    fn state_fixtures() {
+        let proc = populated(20, 60);
+        assert_fixture("span-state-v1.cbor", &encode(&proc.save()));
+        assert_fixture("span-state-legacy.cbor", &encode(&legacy(proc.save())));
+
+        let now = start() + TimeDelta::hours(1);
+        let state = decode(&read_fixture("span-state-v1.cbor")).unwrap();
+        assert_eq!(state.version, SPAN_STATE_VERSION);
+        let loaded = SpanProcessor::load(now, &name(), state, &config());
+        assert_eq!(encode(&loaded.save()), encode(&proc.save()));
+
+        let state = decode(&read_fixture("span-state-legacy.cbor")).unwrap();
+        assert!(matches!(
+            state.groups.values().next(),
+            Some(MetricsState::V0(_))
+        ));
+        let loaded = SpanProcessor::load(now, &name(), state, &config()).save();
+        let saved = proc.save();
+        assert!(loaded.groups.keys().eq(saved.groups.keys()));
+        // Only the V0 group, which has no last-seen time, differs.
+        assert!(loaded
+            .groups
+            .values()
+            .zip(saved.groups.values())
+            .skip(1)
+            .all(|(a, b)| encode(a) == encode(b)));
+    }
+
+    /// Compare the load time of legacy and versioned states of 10,000
+    /// groups with an hour of spans each. Not measured yet; run with
+    /// `cargo test --release -- --ignored bench_state_load --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_state_load() {
+        let proc = populated(10_000, 60);
+        let versioned = encode(&proc.save());
+        let legacy = encode(&legacy(proc.save()));
+
+        let now = start() + TimeDelta::hours(1);
+        let load = |data: &[u8]| {
+            let t0 = Instant::now();
+            SpanProcessor::load(now, &name(), decode(data).unwrap(), &config());
+            t0.elapsed()
+        };
+        let legacy_time = load(&legacy);
+        let versioned_time = load(&versioned);
+
+        println!(
+            "legacy: {} bytes in {legacy_time:?}, versioned: {} bytes in {versioned_time:?}, \
+             speedup: {:.2}x",
+            legacy.len(),
+            versioned.len(),
+            legacy_time.as_secs_f64() / versioned_time.as_secs_f64()
+        );
     }
 }
@@ -0,0 +1,216 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::{ConfigName, SpanKey},
+    jaeger::TagValue,
+    metrics::{key_labels, Metrics},
+};
+
+use super::series_limit::SeriesPriority;
+
+/// The value Prometheus uses to mark a series as stale: a NaN with a
+/// bit pattern that is never produced by arithmetic.
+pub const STALE_NAN: u64 = 0x7ff0_0000_0000_0002;
+
+/// Series not written for this long are considered stale by
+/// Prometheus anyway (the default lookback delta).
+fn lookback() -> TimeDelta {
+    TimeDelta::minutes(5)
+}
+
+/// The series written within the lookback delta, with the time of
+/// their last sample. When a group is removed or the engine shuts
+/// down, staleness markers are written for its series, so that they
+/// end immediately instead of showing their last value for another
+/// five minutes. Older series are forgotten, which bounds the
+/// registry to the series of the last few samples.
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct SeriesRegistry {
+    series: BTreeMap<BTreeMap<String, String>, DateTime<Utc>>,
+}
+
+impl SeriesRegistry {
+    /// Register the series of a sample taken at `t`.
+    pub fn register(&mut self, t: DateTime<Utc>, metrics: &Metrics) {
+        metrics.series().for_each(|labels| {
+            if let Some(last) = self.series.get_mut(labels) {
+                *last = (*last).max(t);
+            } else {
+                self.series.insert(labels.clone(), t);
+            }
+        });
+        let min = t - lookback();
+        self.series.retain(|_, last| *last >= min);
+    }
+
+    /// Remove the series of the given groups of a config, adding
+    /// staleness markers for them to `stale`. Self-monitoring series
+    /// are not tied to a group and are kept.
+    pub fn remove_groups(
+        &mut self,
+        config: &ConfigName,
+        key: &BTreeSet<SpanKey>,
+        groups: &[BTreeMap<SpanKey, TagValue>],
+        stale: &mut Metrics,
+    ) {
+        if groups.is_empty() {
+            return;
+        }
+        let config = config.to_string();
+        let names = key
+            .iter()
+            .map(|name| name.label().into_string())
+            .collect::<BTreeSet<_>>();
+        let groups = groups
+            .iter()
+            .map(|key| key_labels(key).collect::<BTreeMap<_, _>>())
+            .collect::<BTreeSet<_>>();
+        self.remove(stale, |labels| {
+            labels.get("config") == Some(&config)
+                && SeriesPriority::of(labels.get("metric_type").map_or("", String::as_str))
+                    != SeriesPriority::SelfMonitoring
+                && groups.contains(
+                    &labels
+                        .iter()
+                        .filter(|(name, _)| names.contains(*name))
+                        .map(|(name, value)| (name.clone(), value.clone()))
+                        .collect::<BTreeMap<_, _>>(),
+                )
+        });
+    }
+
+    /// Remove all series, adding staleness markers for them to `stale`.
+    pub fn remove_all(&mut self, stale: &mut Metrics) {
+        self.remove(stale, |_| true);
+    }
+
+    fn remove<F: Fn(&BTreeMap<String, String>) -> bool>(&mut self, stale: &mut Metrics, f: F) {
+        let removed = self
+            .series
+            .keys()
+            .filter(|labels| f(labels))
+            .cloned()
+            .collect::<Vec<_>>();
+        removed.into_iter().for_each(|labels| {
+            if let Some(last) = self.series.remove(&labels) {
+                // Just after the last sample, so that the marker is
+                // never rejected as out-of-order.
+                stale.insert(
+                    labels,
+                    last + TimeDelta::milliseconds(1),
+                    f64::from_bits(STALE_NAN),
+                );
+            }
+        });
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.series.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::{BTreeMap, BTreeSet};
+
+    use chrono::TimeDelta;
+
+    use super::{SeriesRegistry, STALE_NAN};
+    use crate::{
+        config::{ConfigName, KeyName, SpanKey},
+        jaeger::TagValue,
+        metrics::{GroupLabels, Labels, Metrics},
+        processor::{sim::start, trace::MetricArgs},
+    };
+
+    #[test]
+    fn stale_markers_for_removed_groups() {
+        let service = SpanKey::Current(KeyName::ServiceName);
+        let namespace = SpanKey::Current(KeyName::ProcessTag(String::from("service.namespace")));
+        let key_set = BTreeSet::from_iter([service.clone(), namespace.clone()]);
+        let key = |service_name: &str, ns: Option<&str>| {
+            std::iter::once((service.clone(), TagValue::String(service_name.to_string())))
+                .chain(ns.map(|ns| (namespace.clone(), TagValue::String(ns.to_string()))))
+                .collect::<BTreeMap<_, _>>()
+        };
+        let groups = [
+            key("frontend", Some("test")),
+            key("frontend", None),
+            key("backend", Some("test")),
+        ];
+        let config = ConfigName::new("default");
+        let other = ConfigName::new("other");
+
+        let sample = |t| {
+            let mut metrics = Metrics::new();
+            for (config_name, key) in groups
+                .iter()
+                .flat_map(|key| [(&config, key), (&other, key)])
+            {
+                metrics.add_metric(
+                    MetricArgs {
+                        metric_name: String::from("trace_duration_score"),
+                        metric_type: "anomaly_score",
+                        labels: Labels::default(),
+                        group: &GroupLabels::new(config_name, key),
+                    },
+                    config_name,
+                    t,
+                    1.0,
+                );
+            }
+            metrics.add_metric(
+                MetricArgs {
+                    metric_name: String::from("jaeger_anomaly_detection_invalid_windows_total"),
+                    metric_type: "self_monitoring",
+                    labels: Labels::default(),
+                    group: &GroupLabels::default(),
+                },
+                &config,
+                t,
+                0.0,
+            );
+            metrics
+        };
+
+        let t = start();
+        let mut registry = SeriesRegistry::default();
+        registry.register(t, &sample(t));
+        assert_eq!(registry.len(), 7);
+
+        // Only the series of the removed group in the given config are
+        // marked stale; a group without the optional namespace is a
+        // different group.
+        let mut stale = Metrics::new();
+        registry.remove_groups(&config, &key_set, &groups[..1], &mut stale);
+        let stale = stale.drain().collect::<Vec<_>>();
+        assert_eq!(stale.len(), 1);
+        let (labels, ts, value) = &stale[0];
+        assert_eq!(labels["config"], "default");
+        assert_eq!(labels["service_name"], "frontend");
+        assert_eq!(labels["service_namespace"], "test");
+        assert_eq!(*ts, t + TimeDelta::milliseconds(1));
+        assert_eq!(value.to_bits(), STALE_NAN);
+        assert_eq!(registry.len(), 6);
+
+        // Series not written within the lookback delta are forgotten.
+        let later = t + TimeDelta::minutes(10);
+        registry.register(later, &Metrics::new());
+        assert_eq!(registry.len(), 0);
+
+        // On shutdown, all series are marked stale.
+        registry.register(later, &sample(later));
+        let mut stale = Metrics::new();
+        registry.remove_all(&mut stale);
+        assert_eq!(stale.len(), 7);
+        assert_eq!(registry.len(), 0);
+    }
+}
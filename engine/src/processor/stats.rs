@@ -3,12 +3,18 @@
  ******************************************************************************/
 
 use chrono::{DateTime, Utc};
+use jaeger_anomaly_detection::ReferenceInterval;
 use ordered_float::NotNan;
+use rustc_apfloat::ieee::Quad;
 use serde::{Deserialize, Serialize};
 
+use crate::welford::Welford;
+
 use super::{
-    anomaly_score::{AnomalyScoreConfig, AnomalyScoreProcessor, AnomalyScoreState},
+    anomaly_score::{AnomalyScoreConfig, AnomalyScoreProcessor, AnomalyScoreState, OffsetSeries},
+    baseline::{BaselineSkip, StatsBaseline},
     histogram::{HistogramConfig, HistogramProcessor, HistogramState},
+    maintenance::Muting,
     mean_stddev::{MeanStddevConfig, MeanStddevProcessor, MeanStddevState},
     metric::MetricArgs,
     summary::{SummaryConfig, SummaryProcessor, SummaryState},
@@ -20,6 +26,28 @@ pub struct StatsConfig {
     pub mean_stddev: Option<MeanStddevConfig>,
     pub summary: Option<SummaryConfig>,
     pub histogram: Option<HistogramConfig>,
+    /// The upper bound on the values of the metric. Values above it,
+    /// such as span durations of days caused by clock bugs, are
+    /// dropped or clamped (see `on_max_value`) before they reach the
+    /// statistics. Only applies to span metrics.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(with = "Option<f64>")]
+    pub max_value: Option<NotNan<f64>>,
+    #[serde(default)]
+    pub on_max_value: MaxValueAction,
+}
+
+/// What happens to values above `StatsConfig::max_value`.
+#[derive(
+    Serialize, Deserialize, schemars::JsonSchema, PartialEq, Eq, Clone, Copy, Default, Debug,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum MaxValueAction {
+    /// Leave the value out of the statistics.
+    #[default]
+    Drop,
+    /// Insert `max_value` instead.
+    Clamp,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -30,6 +58,7 @@ pub struct StatsState {
     histogram: Option<HistogramState>,
 }
 
+#[derive(Clone)]
 pub struct StatsProcessor {
     anomaly_score: Option<AnomalyScoreProcessor>,
     mean_stddev: Option<MeanStddevProcessor>,
@@ -120,25 +149,163 @@ impl StatsProcessor {
         }
     }
 
+    /// Drop the summary and histogram statistics, which take most of
+    /// the memory of an idle group.
+    pub fn compact(&mut self) {
+        self.summary = None;
+        self.histogram = None;
+    }
+
+    /// Recreate the summary and histogram statistics dropped by
+    /// `compact`.
+    pub fn restore(&mut self, t: DateTime<Utc>, config: &StatsConfig) {
+        if self.summary.is_none() {
+            self.summary = config
+                .summary
+                .as_ref()
+                .map(|config| SummaryProcessor::new(t, config));
+        }
+        if self.histogram.is_none() {
+            self.histogram = config.histogram.as_ref().map(HistogramProcessor::new);
+        }
+    }
+
     pub fn insert(&mut self, t: DateTime<Utc>, value: f64) {
-        if let Some(acc) = &mut self.anomaly_score {
-            acc.insert(t, value);
+        self.insert_muted(t, value, Muting::default())
+    }
+
+    /// Insert a value standing for `weight` spans, e.g. a span of a
+    /// head-sampled trace. The anomaly score (mean/ci algorithm) and
+    /// mean/stddev statistics count it `weight` times, summaries and
+    /// histograms once.
+    pub fn insert_weighted(&mut self, t: DateTime<Utc>, value: f64, weight: f64, muting: Muting) {
+        if weight == 1.0 {
+            return self.insert_muted(t, value, muting);
+        }
+        if let Some(acc) = self
+            .anomaly_score
+            .as_mut()
+            .filter(|_| !muting.anomaly_score)
+        {
+            acc.insert_weighted(t, value, weight);
         }
         if let Some(acc) = &mut self.mean_stddev {
+            acc.insert_aggregate(&Welford::weighted(value, weight));
+        }
+        if let Some(acc) = self.summary.as_mut().filter(|_| !muting.distributions) {
+            acc.insert(t, value);
+        }
+        if let Some(acc) = self.histogram.as_mut().filter(|_| !muting.distributions) {
             acc.insert(value);
         }
-        if let Some(acc) = &mut self.summary {
+    }
+
+    /// Insert a value, leaving it out of the statistics muted by an
+    /// active maintenance window.
+    pub fn insert_muted(&mut self, t: DateTime<Utc>, value: f64, muting: Muting) {
+        if let Some(acc) = self
+            .anomaly_score
+            .as_mut()
+            .filter(|_| !muting.anomaly_score)
+        {
+            acc.insert(t, value);
+        }
+        if let Some(acc) = &mut self.mean_stddev {
             acc.insert(value);
         }
-        if let Some(acc) = &mut self.histogram {
+        if let Some(acc) = self.summary.as_mut().filter(|_| !muting.distributions) {
+            acc.insert(t, value);
+        }
+        if let Some(acc) = self.histogram.as_mut().filter(|_| !muting.distributions) {
             acc.insert(value);
         }
     }
 
-    pub fn sample<F: FnMut(MetricArgs, f64)>(&self, _t: DateTime<Utc>, mut metric: F) {
-        if let Some(proc) = self.anomaly_score.as_ref() {
-            proc.sample(&mut metric)
+    /// Insert a batch of values summarized by their accumulator. Only
+    /// the anomaly score (mean/ci algorithm) and mean/stddev statistics
+    /// can be updated this way; summaries and histograms need the
+    /// individual values and are left untouched.
+    pub fn insert_aggregate(&mut self, t: DateTime<Utc>, values: &Welford<Quad>) {
+        if let Some(acc) = &mut self.anomaly_score {
+            acc.insert_aggregate(t, values);
         }
+        if let Some(acc) = &mut self.mean_stddev {
+            acc.insert_aggregate(values);
+        }
+    }
+
+    /// The learned baseline, if any statistics with a baseline are
+    /// enabled.
+    pub fn baseline(&self) -> Option<StatsBaseline> {
+        let anomaly_score = self.anomaly_score.as_ref().and_then(|proc| proc.baseline());
+        let welford = self
+            .mean_stddev
+            .as_ref()
+            .and_then(|proc| proc.welford())
+            .cloned();
+        (anomaly_score.is_some() || welford.is_some()).then_some(StatsBaseline {
+            anomaly_score,
+            welford,
+        })
+    }
+
+    /// Apply a baseline. Nothing is changed unless all of its parts are
+    /// compatible with the current config.
+    pub fn import_baseline(
+        &mut self,
+        t: DateTime<Utc>,
+        baseline: StatsBaseline,
+    ) -> Result<(), BaselineSkip> {
+        if let Some(anomaly_score) = &baseline.anomaly_score {
+            self.anomaly_score
+                .as_ref()
+                .ok_or(BaselineSkip::NoAnomalyScore)?
+                .check_baseline(anomaly_score)?;
+        }
+        if baseline.welford.is_some()
+            && self
+                .mean_stddev
+                .as_ref()
+                .and_then(|proc| proc.welford())
+                .is_none()
+        {
+            return Err(BaselineSkip::NoWelford);
+        }
+
+        if let (Some(proc), Some(anomaly_score)) = (&mut self.anomaly_score, baseline.anomaly_score)
+        {
+            proc.import_baseline(t, anomaly_score);
+        }
+        if let (Some(acc), Some(welford)) = (
+            self.mean_stddev
+                .as_mut()
+                .and_then(|proc| proc.welford_mut()),
+            baseline.welford,
+        ) {
+            *acc = welford;
+        }
+        Ok(())
+    }
+
+    /// Whether the anomaly score marks the warm-up of the group.
+    pub fn marks_warm_up(&self) -> bool {
+        self.anomaly_score
+            .as_ref()
+            .is_some_and(AnomalyScoreProcessor::marks_warm_up)
+    }
+
+    /// Emit the configured statistics, for a group first seen at
+    /// `first_seen` (if tracked). Returns the number of regressed
+    /// windows, which were skipped.
+    pub fn sample<F: FnMut(MetricArgs, f64)>(
+        &self,
+        t: DateTime<Utc>,
+        first_seen: Option<DateTime<Utc>>,
+        mut metric: F,
+    ) -> u64 {
+        let invalid = self.anomaly_score.as_ref().map_or(0, |proc| {
+            proc.sample_aged(first_seen.map(|first_seen| t - first_seen), &mut metric)
+        });
         if let Some(proc) = self.mean_stddev.as_ref() {
             proc.sample(&mut metric)
         }
@@ -148,6 +315,7 @@ impl StatsProcessor {
         if let Some(proc) = self.histogram.as_ref() {
             proc.sample(&mut metric)
         }
+        invalid
     }
 }
 
@@ -158,6 +326,8 @@ impl Default for StatsConfig {
             mean_stddev: Some(MeanStddevConfig::default()),
             summary: Some(SummaryConfig::default()),
             histogram: None,
+            max_value: None,
+            on_max_value: MaxValueAction::default(),
         }
     }
 }
@@ -169,6 +339,49 @@ impl StatsConfig {
             mean_stddev: Some(MeanStddevConfig::default()),
             summary: Some(SummaryConfig::default()),
             histogram: None,
+            max_value: None,
+            on_max_value: MaxValueAction::default(),
+        }
+    }
+
+    /// The anomaly score config, if its offset series are emitted once
+    /// per config instead of with every group.
+    pub fn config_offset(&self) -> Option<&AnomalyScoreConfig> {
+        self.anomaly_score
+            .as_ref()
+            .filter(|config| config.offset_series() == Some(OffsetSeries::Config))
+    }
+
+    /// Emit the anomaly score offset series per group or per config.
+    #[cfg(test)]
+    pub fn with_offset_series(self, offset_series: OffsetSeries) -> Self {
+        Self {
+            anomaly_score: self
+                .anomaly_score
+                .map(|config| config.with_offset_series(offset_series)),
+            ..self
+        }
+    }
+
+    /// Drop the values above `max_value`.
+    pub fn with_max_value(self, max_value: NotNan<f64>) -> Self {
+        Self {
+            max_value: Some(max_value),
+            ..self
+        }
+    }
+
+    /// Compute the anomaly score windows for the given reference
+    /// intervals only.
+    pub fn with_reference_intervals<I>(self, intervals: I) -> Self
+    where
+        I: IntoIterator<Item = ReferenceInterval>,
+    {
+        Self {
+            anomaly_score: self
+                .anomaly_score
+                .map(|config| config.with_reference_intervals(intervals)),
+            ..self
         }
     }
 }
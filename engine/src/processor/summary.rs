@@ -3,7 +3,7 @@
  ******************************************************************************/
 
 use chrono::{DateTime, Utc};
-use jaeger_anomaly_detection::WindowConfig;
+use jaeger_anomaly_detection::{ReferenceInterval, WindowConfig};
 use serde::{Deserialize, Serialize};
 use tdigest::TDigest;
 
@@ -15,6 +15,11 @@ use super::metric::MetricArgs;
 pub struct SummaryConfig {
     pub window: WindowConfig,
     pub percentiles: Vec<f64>,
+    /// Also emit the ratio of every percentile over the window to the
+    /// same percentile over this reference interval, as
+    /// `trace_<metric>_drift`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub emit_drift: Option<ReferenceInterval>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -22,13 +27,18 @@ pub struct SummaryState {
     window: Window<TDigest>,
     count: u64,
     sum: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    drift: Option<Window<TDigest>>,
 }
 
+#[derive(Clone)]
 pub struct SummaryProcessor {
     percentiles: Vec<f64>,
     window: Window<TDigest>,
     count: u64,
     sum: f64,
+    /// The reference window of the drift ratios.
+    drift: Option<Window<TDigest>>,
 }
 
 impl SummaryProcessor {
@@ -38,6 +48,7 @@ impl SummaryProcessor {
             window: Window::new(t, &config.window),
             count: 0,
             sum: 0.0,
+            drift: drift_window(t, None, config),
         }
     }
 
@@ -48,6 +59,7 @@ impl SummaryProcessor {
                 window: self.window.clone(),
                 count: self.count,
                 sum: self.sum,
+                drift: drift_window(t, self.drift.clone(), config),
             }
         } else {
             SummaryProcessor::new(t, config)
@@ -61,6 +73,7 @@ impl SummaryProcessor {
                 window: state.window,
                 count: state.count,
                 sum: state.sum,
+                drift: drift_window(t, state.drift, config),
             }
         } else {
             Self::new(t, config)
@@ -72,14 +85,18 @@ impl SummaryProcessor {
             window: self.window.clone(),
             count: self.count,
             sum: self.sum,
+            drift: self.drift.clone(),
         }
     }
 
-    pub fn insert(&mut self, value: f64) {
+    pub fn insert(&mut self, t: DateTime<Utc>, value: f64) {
         self.count += 1;
         self.sum += value;
-        let tdigest = self.window.current_mut();
-        *tdigest = tdigest.merge_sorted(vec![value]);
+        for window in std::iter::once(&mut self.window).chain(&mut self.drift) {
+            window.advance_init(t, |_| TDigest::default());
+            let tdigest = window.current_mut();
+            *tdigest = tdigest.merge_sorted(vec![value]);
+        }
     }
 
     pub fn sample<F: FnMut(MetricArgs, f64)>(&self, mut metric: F) {
@@ -113,14 +130,123 @@ impl SummaryProcessor {
                 tdigest.estimate_quantile(*q),
             );
         }
+        let Some(reference) = self
+            .drift
+            .as_ref()
+            .map(|window| window.bins().merge())
+            .filter(|reference| !reference.is_empty() && !tdigest.is_empty())
+        else {
+            return;
+        };
+        for q in &self.percentiles {
+            let base = reference.estimate_quantile(*q);
+            if base > 0.0 {
+                metric(
+                    MetricArgs {
+                        metric_suffix: Some("drift"),
+                        metric_type: "summary",
+                        labels: Labels {
+                            q: Some(format!("{q:.2}")),
+                            ..Labels::default()
+                        },
+                    },
+                    tdigest.estimate_quantile(*q) / base,
+                );
+            }
+        }
     }
 }
 
+/// The reference window of the drift ratios, if enabled. The previous
+/// window is kept if it is compatible with the config.
+fn drift_window(
+    t: DateTime<Utc>,
+    window: Option<Window<TDigest>>,
+    config: &SummaryConfig,
+) -> Option<Window<TDigest>> {
+    let window_config = config.emit_drift?.window_config();
+    Some(
+        window
+            .filter(|window| window.compatible_with(&window_config))
+            .unwrap_or_else(|| Window::new(t, &window_config)),
+    )
+}
+
 impl Default for SummaryConfig {
     fn default() -> Self {
         Self {
             window: WindowConfig::default(),
             percentiles: vec![0.5, 0.95, 0.99],
+            emit_drift: None,
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use chrono::{DateTime, TimeDelta};
+    use jaeger_anomaly_detection::{Duration, ReferenceInterval, WindowConfig};
+
+    use super::{SummaryConfig, SummaryProcessor};
+
+    fn sample(proc: &SummaryProcessor) -> (Option<f64>, Option<f64>) {
+        let mut quantile = None;
+        let mut drift = None;
+        proc.sample(|args, value| match args.metric_suffix {
+            None => quantile = Some(value),
+            Some("drift") => drift = Some(value),
+            _ => {}
+        });
+        (quantile, drift)
+    }
+
+    #[test]
+    fn drift_after_step_change() {
+        let config = SummaryConfig {
+            window: WindowConfig {
+                bin_width: Duration::Minutes(1),
+                num_bins: 10,
+            },
+            percentiles: vec![0.95],
+            emit_drift: Some(ReferenceInterval::R7d),
+        };
+        let start = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let mut proc = SummaryProcessor::new(start, &config);
+        let minute = |i| start + TimeDelta::minutes(i);
+
+        // A day at a steady latency.
+        (0..24 * 60).for_each(|i| proc.insert(minute(i), 100.0));
+        let (quantile, drift) = sample(&proc);
+        assert_eq!(quantile, Some(100.0));
+        assert_eq!(drift, Some(1.0));
+
+        // The latency doubles: the window follows within its length,
+        // while the reference barely moves.
+        (24 * 60..24 * 60 + 20).for_each(|i| proc.insert(minute(i), 200.0));
+        let (quantile, drift) = sample(&proc);
+        assert_eq!(quantile, Some(200.0));
+        let drift = drift.unwrap();
+        assert!((1.9..=2.0).contains(&drift), "{drift}");
+
+        // The reference window is saved, and restarted when the
+        // reference interval changes.
+        let loaded = SummaryProcessor::load(minute(24 * 60 + 20), proc.save(), &config);
+        assert_eq!(sample(&loaded).1, Some(drift));
+        let updated = loaded.update(
+            minute(24 * 60 + 20),
+            &SummaryConfig {
+                emit_drift: Some(ReferenceInterval::R30d),
+                ..config.clone()
+            },
+        );
+        assert_eq!(sample(&updated), (Some(200.0), None));
+        let disabled = updated.update(
+            minute(24 * 60 + 20),
+            &SummaryConfig {
+                emit_drift: None,
+                ..config
+            },
+        );
+        assert_eq!(sample(&disabled), (Some(200.0), None));
+    }
+}
@@ -0,0 +1,286 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use std::collections::BTreeSet;
+
+use crate::{
+    config::{
+        KeyName, SpanKey, SpanSelector, BAGGAGE_TAG_PREFIX, SERVICE_NAMESPACE_TAG, SPAN_KIND_TAG,
+        STATUS_CODE_TAGS, TRACESTATE_TAG,
+    },
+    jaeger::Span,
+};
+
+use super::{
+    source::MetricSource,
+    trace::{TraceConfig, SAMPLER_PARAM_TAG, SAMPLER_TYPE_TAG},
+};
+
+/// The span and process tags referenced by a trace config. Spans are
+/// pruned to these tags right after parsing, so that tags no rule, key
+/// or source looks at (URLs, user agents, ...) are not held in memory
+/// while a chunk of traces is processed.
+#[derive(PartialEq, Eq, Clone, Default, Debug)]
+pub struct TagAllowlist {
+    span_tags: BTreeSet<String>,
+    process_tags: BTreeSet<String>,
+}
+
+impl TagAllowlist {
+    pub fn new(config: &TraceConfig) -> Self {
+        let mut allowlist = Self::default();
+        // Read by the ingest filter and maintenance windows.
+        allowlist
+            .process_tags
+            .insert(SERVICE_NAMESPACE_TAG.to_string());
+        config
+            .rules
+            .iter()
+            .flatten()
+            .for_each(|rule| allowlist.add_selector(&rule.select));
+        config.configs.values().for_each(|config| {
+            config
+                .key
+                .iter()
+                .chain(&config.carry_over)
+                .chain(&config.annotations)
+                .for_each(|key| allowlist.add_key(key));
+            config
+                .metrics
+                .values()
+                .for_each(|metric| allowlist.add_source(&metric.source));
+            config
+                .classify
+                .values()
+                .flat_map(|classifier| &classifier.classes)
+                .for_each(|class| allowlist.add_selector(&class.select));
+            if config.respect_sampling {
+                allowlist.add_span_tags([SAMPLER_TYPE_TAG, SAMPLER_PARAM_TAG]);
+            }
+        });
+        config
+            .trace_metrics
+            .key
+            .iter()
+            .for_each(|key| allowlist.add_key(key));
+        allowlist
+    }
+
+    /// Drop the tags of the span that are not on the list.
+    pub fn prune(&self, span: &mut Span) {
+        span.tags.retain(|tag| self.span_tags.contains(&tag.key));
+        span.process
+            .tags
+            .retain(|tag| self.process_tags.contains(&tag.key));
+    }
+
+    fn add_span_tags<I: IntoIterator<Item = T>, T: Into<String>>(&mut self, tags: I) {
+        self.span_tags.extend(tags.into_iter().map(Into::into));
+    }
+
+    fn add_selector(&mut self, selector: &SpanSelector) {
+        match selector {
+            SpanSelector::All(sels) | SpanSelector::Any(sels) => {
+                sels.iter().for_each(|sel| self.add_selector(sel))
+            }
+            SpanSelector::Not(sel) => self.add_selector(sel),
+            SpanSelector::Has(key)
+            | SpanSelector::In(key, _)
+            | SpanSelector::NotIn(key, _)
+            | SpanSelector::Match(key, _)
+            | SpanSelector::NoMatch(key, _)
+            | SpanSelector::Eq(key, _)
+            | SpanSelector::Ne(key, _)
+            | SpanSelector::Inside(key, _)
+            | SpanSelector::Outside(key, _)
+            | SpanSelector::IsTrue(key)
+            | SpanSelector::IsFalse(key) => self.add_key(key),
+            SpanSelector::KeyEq(a, b) | SpanSelector::KeyNe(a, b) => {
+                self.add_key(a);
+                self.add_key(b);
+            }
+            // Logs are not pruned.
+            SpanSelector::HasLog(_, _) => {}
+            SpanSelector::Kind(_) => self.add_span_tags([SPAN_KIND_TAG]),
+        }
+    }
+
+    fn add_source(&mut self, source: &MetricSource) {
+        match source {
+            MetricSource::Tag(name) => self.add_span_tags([name]),
+            MetricSource::TagExcept { tag, key } => self.add_span_tags([tag, key]),
+            MetricSource::Rate { select } => self.add_selector(select),
+            MetricSource::RateBy { select, by, .. } => {
+                self.add_selector(select);
+                self.add_key(by);
+            }
+            MetricSource::Duration
+            | MetricSource::SelfDuration
+            | MetricSource::Count { .. }
+            | MetricSource::LogRate { .. } => {}
+        }
+    }
+
+    fn add_key(&mut self, key: &SpanKey) {
+        match key {
+            SpanKey::Current(name) | SpanKey::Parent(name) | SpanKey::Grandparent(name) => {
+                self.add_key_name(name)
+            }
+        }
+    }
+
+    fn add_key_name(&mut self, name: &KeyName) {
+        match name {
+            // Classes only read the tags of their selectors.
+            KeyName::OperationName
+            | KeyName::ServiceName
+            | KeyName::Duration
+            | KeyName::Class(_) => {}
+            KeyName::ProcessTag(tag) => {
+                self.process_tags.insert(tag.clone());
+            }
+            KeyName::SpanTag(tag) => self.add_span_tags([tag]),
+            KeyName::SpanKind => self.add_span_tags([SPAN_KIND_TAG]),
+            KeyName::StatusCode => self.add_span_tags(STATUS_CODE_TAGS),
+            KeyName::Baggage(name) => self.add_span_tags([
+                format!("{BAGGAGE_TAG_PREFIX}{name}"),
+                TRACESTATE_TAG.to_string(),
+            ]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        config::{ConfigName, KeyName, SpanKey, SpanKind, SpanKindSelector, SpanSelector},
+        jaeger::{Bool, Int64, Span, Tag, TagValue},
+        processor::{
+            sim::{span, start},
+            trace::{Rule, TraceConfig, TraceProcessor},
+        },
+    };
+
+    use super::TagAllowlist;
+
+    fn tag(key: &str, value: TagValue) -> Tag {
+        Tag {
+            key: String::from(key),
+            value,
+        }
+    }
+
+    fn string(s: &str) -> TagValue {
+        TagValue::String(String::from(s))
+    }
+
+    /// A three-level trace with the noise real instrumentations add.
+    fn noisy_trace(i: i64) -> Vec<Span> {
+        let t = start().timestamp_micros() + i * 100_000;
+        let trace_id = format!("noisy-{i}");
+        let mut frontend = span(&trace_id, "1", None, "frontend", "GET", t, 3000);
+        frontend.tags.extend([
+            tag("span.kind", string("server")),
+            tag(
+                "baggage.tenant",
+                string(if i % 2 == 0 { "acme" } else { "initech" }),
+            ),
+            tag(
+                "http.url",
+                string(&format!("https://example.com/{}", "x".repeat(200))),
+            ),
+            tag("http.user_agent", string("Mozilla/5.0 (X11; Linux x86_64)")),
+        ]);
+        let mut backend = span(&trace_id, "2", Some("1"), "backend", "GET", t, 2000);
+        backend.tags.extend([
+            tag("span.kind", string("client")),
+            tag("http.status_code", TagValue::Int64(Int64(500 + i % 4))),
+        ]);
+        let mut database = span(&trace_id, "3", Some("2"), "database", "SELECT", t, 500);
+        database.tags.extend([
+            tag("db.statement", string("SELECT * FROM t WHERE id = ?")),
+            tag("exception.message", string("timeout")),
+        ]);
+        if i % 3 == 0 {
+            database.tags.push(tag("error", TagValue::Bool(Bool::True)));
+        }
+        [&mut frontend, &mut backend, &mut database]
+            .into_iter()
+            .for_each(|span| {
+                span.process.tags.extend([
+                    tag("host.name", string("node-1")),
+                    tag("process.command_line", string("/usr/bin/service --verbose")),
+                ])
+            });
+        vec![frontend, backend, database]
+    }
+
+    fn config() -> TraceConfig {
+        let mut config = TraceConfig::default()
+            .with_request_path_relations()
+            .with_error_reasons();
+        config.rules.insert(
+            0,
+            Vec::from([Rule {
+                select: SpanSelector::Kind(SpanKindSelector::One(SpanKind::Server)).and(
+                    SpanKey::Current(KeyName::Baggage(String::from("tenant"))).one_of(["acme"]),
+                ),
+                config: ConfigName::new("default"),
+                priority: None,
+                stop: true,
+            }]),
+        );
+        config
+    }
+
+    #[test]
+    fn prune_unreferenced_tags() {
+        let allowlist = TagAllowlist::new(&config());
+        let mut trace = noisy_trace(0);
+        trace.iter_mut().for_each(|span| allowlist.prune(span));
+
+        let keys = |tags: &[Tag]| tags.iter().map(|tag| tag.key.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            keys(&trace[0].tags),
+            ["busy_ns", "thread.id", "span.kind", "baggage.tenant"]
+        );
+        assert_eq!(
+            keys(&trace[1].tags),
+            ["busy_ns", "thread.id", "span.kind", "http.status_code"]
+        );
+        assert_eq!(
+            keys(&trace[2].tags),
+            ["busy_ns", "thread.id", "exception.message", "error"]
+        );
+        trace.iter().for_each(|span| {
+            assert_eq!(
+                keys(&span.process.tags),
+                ["service.namespace", "service.instance.id"]
+            )
+        });
+    }
+
+    #[test]
+    fn rule_matching_unchanged() {
+        let config = config();
+        let allowlist = TagAllowlist::new(&config);
+        let processor = TraceProcessor::new(&config);
+        let filter = Default::default();
+
+        let mut matched = 0;
+        (0..12).for_each(|i| {
+            let full = noisy_trace(i);
+            let mut pruned = noisy_trace(i);
+            pruned.iter_mut().for_each(|span| allowlist.prune(span));
+            let report = processor.debug_trace(&full, &filter);
+            assert_eq!(processor.debug_trace(&pruned, &filter), report);
+            matched += report
+                .iter()
+                .flat_map(|span| &span.matches)
+                .filter(|m| m.rule_group == 0)
+                .count();
+        });
+        assert_eq!(matched, 6);
+    }
+}
@@ -0,0 +1,223 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use std::{sync::Mutex, time::Duration};
+
+use serde::Serialize;
+use tokio::time::Instant;
+
+/// The smallest non-zero delay between requests.
+const MIN_DELAY: Duration = Duration::from_millis(100);
+/// The backoff before the first retry of a rejected request.
+const BASE_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Bounds of the adaptive throttling of span queries.
+#[derive(Clone, Copy, Debug)]
+pub struct ThrottleConfig {
+    /// The number of traces fetched per span query when healthy.
+    pub max_chunk_size: usize,
+    /// The number of traces fetched per span query under pressure.
+    pub min_chunk_size: usize,
+    /// The largest delay between requests, and the largest backoff.
+    pub max_delay: Duration,
+    /// Requests taking longer than this signal pressure.
+    pub slow_request: Duration,
+    /// The number of retries of requests rejected under pressure.
+    pub max_retries: u32,
+}
+
+/// Adaptive throttling of OpenSearch requests. Under pressure (slow
+/// requests, HTTP 429 or circuit-breaker errors), the chunk size is
+/// halved and the delay between requests is doubled. Every healthy
+/// request grows the chunk size by one and halves the delay.
+#[derive(Debug)]
+pub struct Throttle {
+    config: ThrottleConfig,
+    state: Mutex<ThrottleState>,
+}
+
+#[derive(Debug)]
+struct ThrottleState {
+    chunk_size: usize,
+    delay: Duration,
+    /// When the next request may be sent: a token bucket holding a
+    /// single request, refilled every `delay`.
+    next: Option<Instant>,
+    pressure_events: u64,
+    retries: u64,
+}
+
+/// The current effective limits.
+#[derive(Serialize, schemars::JsonSchema, PartialEq, Eq, Clone, Copy, Debug)]
+pub struct ThrottleLimits {
+    /// The number of traces fetched per span query.
+    pub chunk_size: usize,
+    /// The delay between requests, in milliseconds.
+    pub delay_ms: u64,
+    /// The number of requests since startup that signaled pressure.
+    pub pressure_events: u64,
+    /// The number of retries since startup.
+    pub retries: u64,
+}
+
+impl Throttle {
+    pub fn new(config: ThrottleConfig) -> Self {
+        let config = ThrottleConfig {
+            max_chunk_size: config.max_chunk_size.max(1),
+            min_chunk_size: config.min_chunk_size.clamp(1, config.max_chunk_size.max(1)),
+            ..config
+        };
+        Self {
+            config,
+            state: Mutex::new(ThrottleState {
+                chunk_size: config.max_chunk_size,
+                delay: Duration::ZERO,
+                next: None,
+                pressure_events: 0,
+                retries: 0,
+            }),
+        }
+    }
+
+    /// The number of traces to fetch per span query.
+    pub fn chunk_size(&self) -> usize {
+        self.state.lock().unwrap().chunk_size
+    }
+
+    /// The current delay between requests.
+    pub fn delay(&self) -> Duration {
+        self.state.lock().unwrap().delay
+    }
+
+    /// Wait until the next request may be sent.
+    pub async fn wait(&self) {
+        let at = {
+            let mut state = self.state.lock().unwrap();
+            let now = Instant::now();
+            let at = state.next.map_or(now, |next| next.max(now));
+            state.next = Some(at + state.delay);
+            at
+        };
+        tokio::time::sleep_until(at).await;
+    }
+
+    /// Record the outcome of a request: its latency, and whether it
+    /// was rejected because OpenSearch is overloaded.
+    pub fn record(&self, latency: Duration, rejected: bool) {
+        let mut state = self.state.lock().unwrap();
+        if rejected || latency > self.config.slow_request {
+            state.pressure_events += 1;
+            state.chunk_size = (state.chunk_size / 2).max(self.config.min_chunk_size);
+            state.delay = (state.delay * 2).max(MIN_DELAY).min(self.config.max_delay);
+        } else {
+            state.chunk_size = (state.chunk_size + 1).min(self.config.max_chunk_size);
+            state.delay /= 2;
+            if state.delay < MIN_DELAY {
+                state.delay = Duration::ZERO;
+            }
+        }
+    }
+
+    /// The backoff before retry number `attempt` (starting at zero)
+    /// of a rejected request, or `None` when out of retries.
+    pub fn retry(&self, attempt: u32) -> Option<Duration> {
+        if attempt >= self.config.max_retries {
+            return None;
+        }
+        self.state.lock().unwrap().retries += 1;
+        Some(
+            BASE_BACKOFF
+                .saturating_mul(1 << attempt.min(16))
+                .min(self.config.max_delay),
+        )
+    }
+
+    pub fn limits(&self) -> ThrottleLimits {
+        let state = self.state.lock().unwrap();
+        ThrottleLimits {
+            chunk_size: state.chunk_size,
+            delay_ms: state.delay.as_millis() as u64,
+            pressure_events: state.pressure_events,
+            retries: state.retries,
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn test_config() -> ThrottleConfig {
+    ThrottleConfig {
+        max_chunk_size: 50,
+        min_chunk_size: 5,
+        max_delay: Duration::from_millis(400),
+        slow_request: Duration::from_secs(5),
+        max_retries: 2,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{test_config, Throttle, ThrottleLimits};
+
+    #[test]
+    fn shrink_under_pressure_and_grow_back() {
+        let throttle = Throttle::new(test_config());
+        let healthy = Duration::from_millis(10);
+        let slow = Duration::from_secs(10);
+
+        throttle.record(healthy, false);
+        assert_eq!(throttle.chunk_size(), 50);
+
+        throttle.record(healthy, true);
+        assert_eq!(
+            throttle.limits(),
+            ThrottleLimits {
+                chunk_size: 25,
+                delay_ms: 100,
+                pressure_events: 1,
+                retries: 0,
+            }
+        );
+        throttle.record(slow, false);
+        throttle.record(slow, false);
+        throttle.record(slow, false);
+        throttle.record(slow, false);
+        assert_eq!(throttle.chunk_size(), 5);
+        assert_eq!(throttle.limits().delay_ms, 400);
+
+        // Growing back is gradual.
+        throttle.record(healthy, false);
+        assert_eq!(throttle.chunk_size(), 6);
+        assert_eq!(throttle.limits().delay_ms, 200);
+        throttle.record(healthy, false);
+        throttle.record(healthy, false);
+        assert_eq!(throttle.limits().delay_ms, 0);
+        (0..100).for_each(|_| throttle.record(healthy, false));
+        assert_eq!(throttle.chunk_size(), 50);
+    }
+
+    #[test]
+    fn retry_backoff() {
+        let throttle = Throttle::new(test_config());
+        assert_eq!(throttle.retry(0), Some(Duration::from_millis(400)));
+        assert_eq!(throttle.retry(1), Some(Duration::from_millis(400)));
+        assert_eq!(throttle.retry(2), None);
+        assert_eq!(throttle.limits().retries, 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn delay_between_requests() {
+        let throttle = Throttle::new(test_config());
+        let start = tokio::time::Instant::now();
+        throttle.wait().await;
+        throttle.wait().await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        throttle.record(Duration::ZERO, true);
+        throttle.wait().await;
+        throttle.wait().await;
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+}
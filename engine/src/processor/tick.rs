@@ -0,0 +1,176 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use std::{collections::VecDeque, fmt::Display, time::Duration};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+/// The number of tick reports kept for the status endpoint.
+pub const TICK_HISTORY: usize = 10;
+
+/// What a tick did: the traces fetched and handled, the samples taken
+/// and written, and the time spent per phase. Collected by the
+/// processor task only, so plain counters suffice.
+#[derive(Serialize, schemars::JsonSchema, PartialEq, Clone, Debug)]
+pub struct TickReport {
+    /// The start of the processed interval.
+    pub from: DateTime<Utc>,
+    /// The end of the processed interval.
+    pub to: DateTime<Utc>,
+    /// The index patterns the traces were fetched from.
+    pub index_patterns: Vec<String>,
+    /// The root spans fetched from OpenSearch.
+    pub roots: u64,
+    /// The traces handed to the processor.
+    pub traces: u64,
+    /// The spans of these traces.
+    pub spans: u64,
+    /// The root spans whose trace had no spans in the span query.
+    pub skipped_traces: u64,
+    /// The sample times taken.
+    pub samples: u64,
+    /// The series emitted at these sample times.
+    pub sampled_series: u64,
+    /// The samples written to the metrics sink, including the
+    /// heartbeat and the stale markers.
+    pub written: u64,
+    /// The time spent per phase.
+    pub durations: TickDurations,
+    /// Why the tick failed, if it did. The counters of a failed tick
+    /// are not collected.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// The time spent per phase of a tick.
+#[derive(Serialize, schemars::JsonSchema, PartialEq, Clone, Default, Debug)]
+pub struct TickDurations {
+    /// The span queries and pushdown aggregations.
+    pub fetch_seconds: f64,
+    /// Inserting the spans.
+    pub insert_seconds: f64,
+    /// Sampling the statistics.
+    pub sample_seconds: f64,
+    /// Writing to the metrics sink.
+    pub write_seconds: f64,
+    /// The whole tick.
+    pub total_seconds: f64,
+}
+
+impl TickReport {
+    pub fn new(from: DateTime<Utc>, to: DateTime<Utc>) -> Self {
+        Self {
+            from,
+            to,
+            index_patterns: Vec::new(),
+            roots: 0,
+            traces: 0,
+            spans: 0,
+            skipped_traces: 0,
+            samples: 0,
+            sampled_series: 0,
+            written: 0,
+            durations: TickDurations::default(),
+            error: None,
+        }
+    }
+
+    /// The report of a tick that failed with `error`.
+    pub fn failed<E: Display>(
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        elapsed: Duration,
+        error: E,
+    ) -> Self {
+        let mut report = Self::new(from, to);
+        report.durations.total_seconds = elapsed.as_secs_f64();
+        report.error = Some(error.to_string());
+        report
+    }
+
+    pub fn fetched(&mut self, elapsed: Duration) {
+        self.durations.fetch_seconds += elapsed.as_secs_f64();
+    }
+
+    pub fn inserted(&mut self, elapsed: Duration) {
+        self.durations.insert_seconds += elapsed.as_secs_f64();
+    }
+
+    pub fn sampled(&mut self, series: usize, elapsed: Duration) {
+        self.samples += 1;
+        self.sampled_series += series as u64;
+        self.durations.sample_seconds += elapsed.as_secs_f64();
+    }
+
+    pub fn wrote(&mut self, samples: usize, elapsed: Duration) {
+        self.written += samples as u64;
+        self.durations.write_seconds += elapsed.as_secs_f64();
+    }
+}
+
+impl Display for TickReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} roots, {} traces with {} spans ({} skipped), {} samples with {} series, \
+             {} samples written in {:.3}s (fetch {:.3}s, insert {:.3}s, sample {:.3}s, \
+             write {:.3}s)",
+            self.roots,
+            self.traces,
+            self.spans,
+            self.skipped_traces,
+            self.samples,
+            self.sampled_series,
+            self.written,
+            self.durations.total_seconds,
+            self.durations.fetch_seconds,
+            self.durations.insert_seconds,
+            self.durations.sample_seconds,
+            self.durations.write_seconds
+        )
+    }
+}
+
+/// Add a report to the history, dropping the oldest beyond
+/// `TICK_HISTORY`.
+pub fn push_tick(ticks: &mut VecDeque<TickReport>, report: TickReport) {
+    ticks.push_back(report);
+    while ticks.len() > TICK_HISTORY {
+        ticks.pop_front();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::VecDeque, time::Duration};
+
+    use chrono::{DateTime, TimeDelta};
+
+    use super::{push_tick, TickReport, TICK_HISTORY};
+
+    #[test]
+    fn history_keeps_the_last_ticks() {
+        let t = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let mut ticks = VecDeque::new();
+        (0..TICK_HISTORY as i64 + 3).for_each(|i| {
+            let from = t + TimeDelta::minutes(i);
+            push_tick(
+                &mut ticks,
+                TickReport::new(from, from + TimeDelta::minutes(1)),
+            );
+        });
+        assert_eq!(ticks.len(), TICK_HISTORY);
+        assert_eq!(ticks.front().unwrap().from, t + TimeDelta::minutes(3));
+        assert_eq!(
+            ticks.back().unwrap().from,
+            t + TimeDelta::minutes(TICK_HISTORY as i64 + 2)
+        );
+
+        let failed = TickReport::failed(t, t, Duration::from_millis(1500), "unreachable");
+        assert_eq!(failed.error.as_deref(), Some("unreachable"));
+        assert_eq!(failed.durations.total_seconds, 1.5);
+        assert_eq!(failed.roots, 0);
+    }
+}
@@ -0,0 +1,417 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use apistos::ApiComponent;
+use chrono::{DateTime, TimeDelta, Utc};
+use jaeger_anomaly_detection::{
+    CombineScores, Duration, ImmediateInterval, OperationFilter, ReferenceInterval, ServiceFilter,
+    TraceAggr, TraceExpr, TraceMetric, TraceObject,
+};
+use ordered_float::NotNan;
+use prometheus_api::InstantQueryParams;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::error::{Error, Result};
+
+/// The upper bound on the requested number of groups.
+const MAX_TOP: usize = 1000;
+/// The upper bound on the lookback.
+const MAX_LOOKBACK: TimeDelta = TimeDelta::days(7);
+/// The time allowed for each query.
+const TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+/// The score of a group without series at one of the two times. The
+/// score expression is clamped to this minimum.
+const MIN_SCORE: f64 = 1.0;
+
+/// The operations whose anomaly score increased most over the
+/// lookback.
+#[derive(Deserialize, schemars::JsonSchema, ApiComponent, Debug)]
+pub struct TopMoversQuery {
+    pub metric: TraceMetric,
+    pub immediate: ImmediateInterval,
+    pub reference: ReferenceInterval,
+    /// How far back the earlier scores are taken, at most 7 days.
+    #[serde(default = "default_lookback")]
+    pub lookback: Duration,
+    /// The maximum number of groups to return, at most 1000.
+    #[serde(default = "default_top")]
+    pub top: usize,
+    /// Only the operations of this service.
+    pub service_name: Option<String>,
+    /// The engine config of the scores, if not the default.
+    pub config: Option<String>,
+    /// Only groups scoring above this. Lower scores count as the
+    /// minimum score.
+    pub threshold: Option<f64>,
+}
+
+/// The groups with the largest score increase, largest first.
+#[derive(Serialize, schemars::JsonSchema, ApiComponent, PartialEq, Debug)]
+pub struct TopMovers {
+    /// The score expression.
+    pub expr: String,
+    /// The time of the earlier scores.
+    pub before: DateTime<Utc>,
+    /// The time of the current scores.
+    pub after: DateTime<Utc>,
+    pub movers: Vec<Mover>,
+}
+
+#[derive(Serialize, schemars::JsonSchema, PartialEq, Debug)]
+pub struct Mover {
+    /// The group labels of the operation.
+    pub labels: BTreeMap<String, String>,
+    /// The earlier score, if the group had one.
+    pub before: Option<f64>,
+    /// The current score, if the group has one.
+    pub after: Option<f64>,
+    /// The score increase. A missing score counts as the minimum
+    /// score of 1.
+    pub delta: f64,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "status", rename_all = "snake_case")]
+enum QueryResponse {
+    Success { data: QueryData },
+    Error { error: String },
+}
+
+#[derive(Deserialize, Debug)]
+struct QueryData {
+    result: Vec<VectorSample>,
+}
+
+#[derive(Deserialize, Debug)]
+struct VectorSample {
+    metric: BTreeMap<String, String>,
+    value: (f64, String),
+}
+
+/// Looks up the score changes in prometheus.
+#[derive(Clone, Debug)]
+pub struct TopMoverFinder {
+    client: reqwest::Client,
+    url: Url,
+}
+
+const fn default_lookback() -> Duration {
+    Duration::Hours(1)
+}
+
+const fn default_top() -> usize {
+    10
+}
+
+impl TopMoversQuery {
+    /// Check the limits on `top`, `lookback` and `threshold`.
+    pub fn validate(&self) -> Result<()> {
+        if self.top == 0 || self.top > MAX_TOP {
+            return Err(Error::InvalidTopMovers(format!(
+                "top must be between 1 and {MAX_TOP}"
+            )));
+        }
+        let lookback = self.lookback.to_time_delta();
+        if lookback <= TimeDelta::zero() || lookback > MAX_LOOKBACK {
+            return Err(Error::InvalidTopMovers(String::from(
+                "lookback must be positive and at most 7d",
+            )));
+        }
+        if self.threshold.is_some_and(f64::is_nan) {
+            return Err(Error::InvalidTopMovers(String::from(
+                "threshold must be a number",
+            )));
+        }
+        Ok(())
+    }
+
+    fn object(&self) -> TraceObject<CombineScores> {
+        let filter = match &self.service_name {
+            Some(service_name) => {
+                OperationFilter::new().service(ServiceFilter::new().service_name(service_name))
+            }
+            None => OperationFilter::new(),
+        };
+        TraceObject::<CombineScores>::builder()
+            .operation()
+            .multiple(None)
+            .item(filter)
+            .opt_config(self.config.as_deref())
+    }
+}
+
+impl TopMoverFinder {
+    pub fn new(client: reqwest::Client, url: Url) -> Self {
+        Self { client, url }
+    }
+
+    /// The score changes between `now - lookback` and `now`. Both
+    /// snapshots are joined on the group labels of the operations.
+    pub async fn find(&self, query: &TopMoversQuery, now: DateTime<Utc>) -> Result<TopMovers> {
+        let object = query.object();
+        let group_labels = object
+            .group_labels()
+            .iter()
+            .map(|label| label.to_string())
+            .collect::<BTreeSet<_>>();
+        let expr = TraceExpr::new(
+            query.metric,
+            TraceAggr::score(query.immediate, query.reference, object)
+                .threshold(query.threshold.and_then(|t| NotNan::new(t).ok())),
+        )
+        .expr(&InstantQueryParams { time: None })
+        .to_string();
+        let before_t = now - query.lookback.to_time_delta();
+        let before = self.snapshot(&expr, before_t, &group_labels).await?;
+        let after = self.snapshot(&expr, now, &group_labels).await?;
+        Ok(TopMovers {
+            expr,
+            before: before_t,
+            after: now,
+            movers: movers(before, after, query.top),
+        })
+    }
+
+    /// The scores at `t`, by group. Groups with several series (e.g.
+    /// per shard) take the highest score.
+    async fn snapshot(
+        &self,
+        expr: &str,
+        t: DateTime<Utc>,
+        group_labels: &BTreeSet<String>,
+    ) -> Result<BTreeMap<BTreeMap<String, String>, f64>> {
+        let res = self
+            .client
+            .post(self.url.join("api/v1/query").map_err(Error::Url)?)
+            .timeout(TIMEOUT)
+            .form(&[
+                ("query", expr.to_string()),
+                ("time", t.timestamp().to_string()),
+            ])
+            .send()
+            .await
+            .map_err(Error::PromQuery)?;
+        let samples = match res
+            .json::<QueryResponse>()
+            .await
+            .map_err(Error::PromQuery)?
+        {
+            QueryResponse::Success { data } => data.result,
+            QueryResponse::Error { error } => return Err(Error::PromQueryRes(error)),
+        };
+        let mut scores = BTreeMap::new();
+        samples.into_iter().for_each(|sample| {
+            let Ok(score) = sample.value.1.parse::<f64>() else {
+                return;
+            };
+            if score.is_nan() {
+                return;
+            }
+            let labels = sample
+                .metric
+                .into_iter()
+                .filter(|(label, _)| group_labels.contains(label))
+                .collect();
+            scores
+                .entry(labels)
+                .and_modify(|current: &mut f64| *current = current.max(score))
+                .or_insert(score);
+        });
+        Ok(scores)
+    }
+}
+
+/// The `top` groups with the largest score increase. Groups present in
+/// only one snapshot count as having the minimum score in the other.
+fn movers(
+    mut before: BTreeMap<BTreeMap<String, String>, f64>,
+    after: BTreeMap<BTreeMap<String, String>, f64>,
+    top: usize,
+) -> Vec<Mover> {
+    let mut movers = after
+        .into_iter()
+        .map(|(labels, after)| {
+            let before = before.remove(&labels);
+            Mover {
+                delta: after - before.unwrap_or(MIN_SCORE),
+                labels,
+                before,
+                after: Some(after),
+            }
+        })
+        .collect::<Vec<_>>();
+    movers.extend(before.into_iter().map(|(labels, before)| Mover {
+        delta: MIN_SCORE - before,
+        labels,
+        before: Some(before),
+        after: None,
+    }));
+    movers.retain(|mover| mover.delta > 0.0);
+    movers.sort_by(|a, b| {
+        b.delta
+            .total_cmp(&a.delta)
+            .then_with(|| a.labels.cmp(&b.labels))
+    });
+    movers.truncate(top);
+    movers
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use chrono::DateTime;
+    use jaeger_anomaly_detection::{Duration, ImmediateInterval, ReferenceInterval, TraceMetric};
+    use serde_json::json;
+
+    use super::{TopMoverFinder, TopMoversQuery};
+    use crate::processor::fake_http::FakeHttp;
+
+    /// A prometheus query endpoint answering with the body for the
+    /// `time` parameter of the request.
+    async fn stub_prometheus(bodies: BTreeMap<String, String>) -> FakeHttp {
+        FakeHttp::start("/prometheus/", move |request| {
+            assert_eq!(request.method, "POST");
+            assert_eq!(request.path(), "/prometheus/api/v1/query");
+            (200, bodies[&form(&request.body)["time"]].clone())
+        })
+        .await
+    }
+
+    fn form(body: &[u8]) -> BTreeMap<String, String> {
+        url::form_urlencoded::parse(body).into_owned().collect()
+    }
+
+    fn scores(scores: &[(&str, &str, &str)]) -> String {
+        let result = scores
+            .iter()
+            .map(|(operation, shard, score)| {
+                json!({
+                    "metric": {
+                        "config": "default",
+                        "service_name": "frontend",
+                        "service_namespace": "continuousc",
+                        "operation_name": operation,
+                        "shard": shard,
+                    },
+                    "value": [1700000000, score],
+                })
+            })
+            .collect::<Vec<_>>();
+        json!({
+            "status": "success",
+            "data": { "resultType": "vector", "result": result }
+        })
+        .to_string()
+    }
+
+    fn query(top: usize) -> TopMoversQuery {
+        TopMoversQuery {
+            metric: TraceMetric::Duration,
+            immediate: ImmediateInterval::I15m,
+            reference: ReferenceInterval::R30d,
+            lookback: Duration::Hours(1),
+            top,
+            service_name: Some(String::from("frontend")),
+            config: None,
+            threshold: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn join_snapshots() {
+        let now = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let bodies = BTreeMap::from_iter([
+            (
+                String::from("1699996400"),
+                scores(&[
+                    ("GET", "0", "1.5"),
+                    ("POST", "0", "4"),
+                    ("DELETE", "0", "3"),
+                    ("PUT", "0", "2"),
+                ]),
+            ),
+            (
+                String::from("1700000000"),
+                scores(&[
+                    // The highest score of the shards counts.
+                    ("GET", "0", "2"),
+                    ("GET", "1", "5.5"),
+                    ("POST", "0", "2"),
+                    ("PUT", "0", "2"),
+                    // Only in the current snapshot.
+                    ("HEAD", "0", "3"),
+                    ("PATCH", "0", "NaN"),
+                ]),
+            ),
+        ]);
+        let server = stub_prometheus(bodies).await;
+        let finder = TopMoverFinder::new(reqwest::Client::new(), server.url().clone());
+
+        let movers = finder.find(&query(10), now).await.unwrap();
+        assert_eq!(movers.before, now - chrono::TimeDelta::hours(1));
+        assert!(
+            movers.expr.contains("service_name=\"frontend\""),
+            "{}",
+            movers.expr
+        );
+        assert!(movers.expr.contains("immediate=\"15m\""), "{}", movers.expr);
+        let summary = movers
+            .movers
+            .iter()
+            .map(|mover| {
+                assert!(!mover.labels.contains_key("shard"));
+                (
+                    mover.labels["operation_name"].as_str(),
+                    mover.before,
+                    mover.after,
+                    mover.delta,
+                )
+            })
+            .collect::<Vec<_>>();
+        // POST and DELETE decreased, PUT did not change.
+        assert_eq!(
+            summary,
+            [
+                ("GET", Some(1.5), Some(5.5), 4.0),
+                ("HEAD", None, Some(3.0), 2.0),
+            ]
+        );
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert!(requests
+            .iter()
+            .all(|request| form(&request.body)["query"] == movers.expr));
+
+        let movers = finder.find(&query(1), now).await.unwrap();
+        assert_eq!(movers.movers.len(), 1);
+
+        let thresholded = TopMoversQuery {
+            threshold: Some(2.5),
+            ..query(10)
+        };
+        let movers = finder.find(&thresholded, now).await.unwrap();
+        assert!(movers.expr.ends_with(" > 2.5"), "{}", movers.expr);
+    }
+
+    #[test]
+    fn query_limits() {
+        assert!(query(10).validate().is_ok());
+        assert!(query(0).validate().is_err());
+        assert!(query(1001).validate().is_err());
+        let nan = TopMoversQuery {
+            threshold: Some(f64::NAN),
+            ..query(10)
+        };
+        assert!(nan.validate().is_err());
+        let query = TopMoversQuery {
+            lookback: Duration::Days(8),
+            ..query(10)
+        };
+        assert!(query.validate().is_err());
+    }
+}
@@ -2,27 +2,52 @@
  * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
  ******************************************************************************/
 
-use std::collections::{BTreeMap, BTreeSet};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+};
 
 use chrono::{DateTime, Utc};
-use jaeger_anomaly_detection::WindowConfig;
+use jaeger_anomaly_detection::{ReferenceInterval, WindowConfig};
 use ordered_float::NotNan;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
 use serde::{Deserialize, Serialize};
 
 use crate::{
     config::{
-        ConfigName, KeyName, LowerBound, MetricName, Range, Regex, SpanKey, SpanSelector,
-        UpperBound,
+        Ancestors, ConfigName, IngestFilter, KeyName, MetricName, SpanKey, SpanSelector,
+        STATUS_ERROR,
     },
-    jaeger::{RefType, Span, TagValue},
-    metrics::Labels,
+    jaeger::{RefType, Span, SpanId, TagValue},
+    metrics::{key_labels, label_value, GroupLabels, Labels, Metrics},
+    state::SaveStats,
 };
 
 use super::{
+    baseline::{BaselineBundle, BaselineSkip, ImportEntry, ImportReport},
+    dedup::{DedupConfig, DedupSet},
+    expiry::{ExpiredGroup, ExpiryQueue},
+    ingest_stats::IngestReport,
+    keep_alive::PitReport,
+    maintenance::MaintenanceWindow,
     metric::MetricConfig,
-    source::MetricSource,
-    span::{SpanConfig, SpanProcessor, SpanState},
+    pseudonymize::PseudonymizationKey,
+    pushdown::{PushdownError, PushdownQuery, SpanAggregate},
+    quarantine::{catch_failure, Quarantine, QuarantineStats},
+    rule_stats::{RuleCounts, RuleStats},
+    series_limit::{SeriesReport, SeriesStats},
+    snapshot::TraceSnapshot,
+    source::{default_max_reasons, MetricSource, SourceProcessor},
+    span::{
+        default_carry_over_age, default_max_sampling_weight, MissingKey, SpanConfig, SpanProcessor,
+        SpanState,
+    },
+    staleness::SeriesRegistry,
     stats::StatsConfig,
+    tag_allowlist::TagAllowlist,
+    trace_debug::{RuleMatch, SkipReason, SpanDebug},
+    trace_level::{TraceLevelProcessor, TraceLevelState, TraceMetricsConfig},
+    write_stats::WriteReport,
 };
 
 #[derive(Serialize, Deserialize, schemars::JsonSchema, PartialEq, Clone, Debug)]
@@ -30,19 +55,40 @@ use super::{
 pub struct TraceConfig {
     pub rules: Vec<Vec<Rule>>,
     pub configs: BTreeMap<ConfigName, SpanConfig>,
+    pub trace_metrics: TraceMetricsConfig,
+    pub dedup: Option<DedupConfig>,
+    /// Periods during which matching spans are left out of the anomaly
+    /// score windows.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub maintenance: Vec<MaintenanceWindow>,
 }
 
 #[derive(Serialize, Deserialize, schemars::JsonSchema, PartialEq, Clone, Debug)]
 pub struct Rule {
     pub select: SpanSelector,
     pub config: ConfigName,
+    /// The evaluation priority within the rule group: lower values are
+    /// evaluated first. Rules with equal priority (0 when unset) are
+    /// evaluated in order of appearance.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub priority: Option<i32>,
+    /// Stop evaluating the rule group when this rule matches (the
+    /// default). When false, matching spans are also passed on to the
+    /// next matching rule in the group, feeding them into multiple
+    /// configs.
+    #[serde(default = "default_stop")]
+    pub stop: bool,
+}
+
+const fn default_stop() -> bool {
+    true
 }
 
 pub(crate) struct MetricArgs<'a> {
     pub(crate) metric_name: String,
     pub(crate) metric_type: &'static str,
     pub(crate) labels: Labels,
-    pub(crate) key: &'a BTreeMap<SpanKey, TagValue>,
+    pub(crate) group: &'a GroupLabels,
 }
 
 impl Default for TraceConfig {
@@ -52,44 +98,40 @@ impl Default for TraceConfig {
                 Vec::from([Rule {
                     select: SpanSelector::All(Vec::new()),
                     config: ConfigName::new("default"),
+                    priority: None,
+                    stop: true,
                 }]),
                 Vec::from([Rule {
-                    select: SpanSelector::Has(SpanKey::Parent(KeyName::Duration)),
+                    select: SpanKey::Parent(KeyName::Duration).has(),
                     config: ConfigName::new("operation-relations"),
+                    priority: None,
+                    stop: true,
                 }]),
                 Vec::from([Rule {
-                    select: SpanSelector::All(Vec::from_iter([
-                        SpanSelector::Has(SpanKey::Parent(KeyName::Duration)),
-                        SpanSelector::Any(Vec::from_iter([
-                            SpanSelector::KeyNe(
-                                SpanKey::Current(KeyName::ServiceName),
-                                SpanKey::Parent(KeyName::ServiceName),
+                    select: SpanKey::Parent(KeyName::Duration)
+                        .has()
+                        .and(SpanSelector::any([
+                            SpanKey::Current(KeyName::ServiceName)
+                                .differs_from(SpanKey::Parent(KeyName::ServiceName)),
+                            SpanKey::process_tag("service.namespace").differs_from(
+                                SpanKey::Parent(KeyName::process_tag("service.namespace")),
                             ),
-                            SpanSelector::KeyNe(
-                                SpanKey::Current(KeyName::ProcessTag(String::from(
-                                    "service.namespace",
-                                ))),
-                                SpanKey::Parent(KeyName::ProcessTag(String::from(
-                                    "service.namespace",
-                                ))),
-                            ),
-                            SpanSelector::KeyNe(
-                                SpanKey::Current(KeyName::ProcessTag(String::from(
-                                    "service.instance.id",
-                                ))),
-                                SpanKey::Parent(KeyName::ProcessTag(String::from(
-                                    "service.instance.id",
-                                ))),
+                            SpanKey::process_tag("service.instance.id").differs_from(
+                                SpanKey::Parent(KeyName::process_tag("service.instance.id")),
                             ),
                         ])),
-                    ])),
                     config: ConfigName::new("service-relations"),
+                    priority: None,
+                    stop: true,
                 }]),
             ]),
             configs: BTreeMap::from_iter([
                 (
                     ConfigName::new("default"),
                     SpanConfig {
+                        // The span kind keeps client and server spans
+                        // of the same operation (e.g. self-calls) in
+                        // separate groups.
                         key: BTreeSet::from_iter([
                             SpanKey::Current(KeyName::ServiceName),
                             SpanKey::Current(KeyName::OperationName),
@@ -99,6 +141,7 @@ impl Default for TraceConfig {
                             SpanKey::Current(KeyName::ProcessTag(String::from(
                                 "service.instance.id",
                             ))),
+                            SpanKey::Current(KeyName::SpanKind),
                         ]),
                         metrics: BTreeMap::from_iter([
                             (
@@ -107,7 +150,9 @@ impl Default for TraceConfig {
                                     source: MetricSource::SelfDuration,
                                     stats: StatsConfig::default_with_offset(
                                         NotNan::new(1000.0).unwrap(),
-                                    ),
+                                    )
+                                    .with_max_value(max_duration()),
+                                    enabled: true,
                                 },
                             ),
                             (
@@ -119,7 +164,9 @@ impl Default for TraceConfig {
                                     },
                                     stats: StatsConfig::default_with_offset(
                                         NotNan::new(1_000_000.0).unwrap(),
-                                    ),
+                                    )
+                                    .with_max_value(max_busy()),
+                                    enabled: true,
                                 },
                             ),
                             (
@@ -130,43 +177,53 @@ impl Default for TraceConfig {
                                     },
                                     stats: StatsConfig::default_with_offset(
                                         NotNan::new(1.0).unwrap(),
-                                    ),
+                                    )
+                                    .with_reference_intervals([ReferenceInterval::R30d]),
+                                    enabled: true,
                                 },
                             ),
                             (
                                 MetricName::new("error_rate"),
                                 MetricConfig {
                                     source: MetricSource::Rate {
-                                        select: SpanSelector::Any(vec![
-                                            SpanSelector::IsTrue(SpanKey::Current(
-                                                KeyName::SpanTag(String::from("error")),
-                                            )),
-                                            SpanSelector::Has(SpanKey::Current(KeyName::SpanTag(
-                                                String::from("exception.message"),
-                                            ))),
-                                            SpanSelector::Outside(
-                                                SpanKey::Current(KeyName::SpanTag(String::from(
-                                                    "http.status_code",
-                                                ))),
-                                                Range {
-                                                    lower: Some(LowerBound::Ge(200)),
-                                                    upper: Some(UpperBound::Le(299)),
-                                                },
-                                            ),
-                                            SpanSelector::NoMatch(
-                                                SpanKey::Current(KeyName::SpanTag(String::from(
-                                                    "http.status_code",
-                                                ))),
-                                                Regex::new("^2..$").unwrap(),
-                                            ),
-                                        ]),
+                                        select: error_selector(),
                                     },
                                     stats: StatsConfig::default_with_offset(
                                         NotNan::new(0.01).unwrap(),
-                                    ),
+                                    )
+                                    .with_reference_intervals([ReferenceInterval::R30d]),
+                                    enabled: true,
                                 },
                             ),
                         ]),
+                        carry_over: BTreeSet::new(),
+                        carry_over_age: default_carry_over_age(),
+                        pushdown: false,
+                        normalize_numbers: false,
+                        annotations: BTreeSet::new(),
+                        idle_after: None,
+                        respect_sampling: false,
+                        max_sampling_weight: default_max_sampling_weight(),
+                        // Error messages can be grouped into a few
+                        // classes, e.g. with
+                        //
+                        //   classify:
+                        //     error_class:
+                        //       classes:
+                        //         - value: timeout
+                        //           select: { match: [{ current: { span_tag: exception.message } }, "timed? ?out"] }
+                        //         - value: connection_refused
+                        //           select: { match: [{ current: { span_tag: exception.message } }, "[Cc]onnection refused"] }
+                        //       fallback: other
+                        //
+                        // and `{ current: { class: error_class } }` as
+                        // an annotation or as the `by` key of a
+                        // `rate_by` source next to `error_rate`.
+                        classify: BTreeMap::new(),
+                        pseudonymize: Vec::new(),
+                        late_bins: None,
+                        missing_key: unknown_service(false),
+                        enabled: true,
                     },
                 ),
                 (
@@ -194,9 +251,24 @@ impl Default for TraceConfig {
                                 source: MetricSource::Duration,
                                 stats: StatsConfig::default_with_offset(
                                     NotNan::new(1000.0).unwrap(),
-                                ),
+                                )
+                                .with_max_value(max_duration()),
+                                enabled: true,
                             },
                         )]),
+                        carry_over: BTreeSet::new(),
+                        carry_over_age: default_carry_over_age(),
+                        pushdown: false,
+                        normalize_numbers: false,
+                        annotations: BTreeSet::new(),
+                        idle_after: None,
+                        respect_sampling: false,
+                        max_sampling_weight: default_max_sampling_weight(),
+                        classify: BTreeMap::new(),
+                        pseudonymize: Vec::new(),
+                        late_bins: None,
+                        missing_key: unknown_service(true),
+                        enabled: true,
                     },
                 ),
                 (
@@ -222,12 +294,30 @@ impl Default for TraceConfig {
                                 source: MetricSource::Duration,
                                 stats: StatsConfig::default_with_offset(
                                     NotNan::new(1000.0).unwrap(),
-                                ),
+                                )
+                                .with_max_value(max_duration()),
+                                enabled: true,
                             },
                         )]),
+                        carry_over: BTreeSet::new(),
+                        carry_over_age: default_carry_over_age(),
+                        pushdown: false,
+                        normalize_numbers: false,
+                        annotations: BTreeSet::new(),
+                        idle_after: None,
+                        respect_sampling: false,
+                        max_sampling_weight: default_max_sampling_weight(),
+                        classify: BTreeMap::new(),
+                        pseudonymize: Vec::new(),
+                        late_bins: None,
+                        missing_key: unknown_service(true),
+                        enabled: true,
                     },
                 ),
             ]),
+            trace_metrics: TraceMetricsConfig::default(),
+            dedup: None,
+            maintenance: Vec::new(),
         }
     }
 }
@@ -235,28 +325,334 @@ impl Default for TraceConfig {
 #[derive(Serialize, Deserialize, Debug)]
 pub struct TraceState {
     groups: BTreeMap<ConfigName, SpanState>,
+    #[serde(default)]
+    trace_metrics: Option<TraceLevelState>,
+    #[serde(default)]
+    series: SeriesRegistry,
+    #[serde(default)]
+    last_sample: Option<DateTime<Utc>>,
+}
+
+impl TraceState {
+    /// The groups that could not be decoded when the state was loaded,
+    /// per config. These groups start over.
+    pub fn dropped_groups(&self) -> impl Iterator<Item = (&ConfigName, &str)> {
+        self.groups.iter().flat_map(|(name, state)| {
+            state
+                .dropped_groups()
+                .iter()
+                .map(move |group| (name, group.as_str()))
+        })
+    }
+
+    /// Drop the groups, which then start over. Returns the number of
+    /// dropped groups.
+    ///
+    /// Used when the shard changed. All groups are dropped, not only
+    /// those of the traces that moved: traces are sharded by trace id,
+    /// while groups are keyed by span attributes, so every group holds
+    /// statistics of traces that moved and of traces that did not, and
+    /// they cannot be told apart.
+    pub fn drop_groups(&mut self) -> usize {
+        self.trace_metrics = None;
+        std::mem::take(&mut self.groups)
+            .values()
+            .map(SpanState::group_count)
+            .sum()
+    }
 }
 
 pub struct TraceProcessor {
     rules: Vec<Vec<Rule>>,
     groups: BTreeMap<ConfigName, SpanProcessor>,
+    trace_metrics: TraceLevelProcessor,
+    dedup: Option<DedupSet>,
+    invalid_windows: BTreeMap<ConfigName, u64>,
+    duplicate_spans: BTreeMap<ConfigName, u64>,
+    truncated_series: BTreeMap<ConfigName, u64>,
+    compacted_groups: BTreeMap<ConfigName, u64>,
+    expired_groups: BTreeMap<ConfigName, u64>,
+    rule_stats: Arc<RuleStats>,
+    rule_totals: RuleCounts,
+    series_stats: Arc<SeriesStats>,
+    /// Set when traces were inserted or the config or baselines were
+    /// changed since the last save.
+    dirty: bool,
+    last_save: Option<SaveStats>,
+    last_ingest: Option<IngestReport>,
+    last_pit: Option<PitReport>,
+    last_write: Option<WriteReport>,
+    /// Configs whose statistics are computed by aggregation pushdown.
+    pushdown: Vec<PushdownQuery>,
+    /// Recently written series, to mark them stale when their group
+    /// is removed.
+    series: SeriesRegistry,
+    /// The time of the last sample, so that later samples are never
+    /// taken at or before it when the sample interval changes.
+    last_sample: Option<DateTime<Utc>>,
+    maintenance: Vec<MaintenanceWindow>,
+    /// The tags referenced by the config.
+    tags: TagAllowlist,
+    pseudonymization: Option<PseudonymizationKey>,
+    /// Where the groups removed by the cleanup are queued for the
+    /// expiry webhook.
+    expiry: Option<ExpiryQueue>,
+    /// Failures of the span configs, and the configs skipped because
+    /// of them.
+    quarantine: Quarantine,
+    quarantine_stats: Arc<QuarantineStats>,
+}
+
+/// Spans with an error status or an exception.
+fn error_selector() -> SpanSelector {
+    SpanKey::Current(KeyName::StatusCode)
+        .one_of([STATUS_ERROR])
+        .or(SpanKey::tag("exception.message").has())
+}
+
+/// Placeholders for the service components that not every SDK sets,
+/// for the built-in configs; with `parent`, also for those of the
+/// parent service.
+fn unknown_service(parent: bool) -> MissingKey {
+    let labels = ["service_namespace", "service_instance_id"];
+    MissingKey::Placeholder(
+        labels
+            .iter()
+            .map(|label| label.to_string())
+            .chain(
+                labels
+                    .iter()
+                    .filter(|_| parent)
+                    .map(|label| format!("parent_{label}")),
+            )
+            .map(|label| (label, String::from("unknown")))
+            .collect(),
+    )
+}
+
+/// The default bound on span durations: an hour, in microseconds.
+/// Longer spans are taken to be clock errors.
+fn max_duration() -> NotNan<f64> {
+    NotNan::new(3_600_000_000.0).unwrap()
+}
+
+/// The default bound on the busy time of a span: an hour, in
+/// nanoseconds.
+fn max_busy() -> NotNan<f64> {
+    NotNan::new(3_600_000_000_000.0).unwrap()
+}
+
+/// The version of the compiled-in default config. Version 2 added the
+/// span kind to the key of the "default" config.
+pub const DEFAULT_CONFIG_VERSION: u32 = 2;
+
+impl TraceConfig {
+    /// The compiled-in default config as of `version`, or `None` for
+    /// an unknown version. The default config is only applied when
+    /// the engine starts without saved state; saved state keeps its
+    /// own config. Moving a deployment to a version with a different
+    /// key starts the groups of the affected configs over.
+    pub fn default_version(version: u32) -> Option<Self> {
+        match version {
+            1 => {
+                let mut config = Self::default();
+                if let Some(default) = config.configs.get_mut(&ConfigName::new("default")) {
+                    default.key.remove(&SpanKey::Current(KeyName::SpanKind));
+                }
+                Some(config)
+            }
+            DEFAULT_CONFIG_VERSION => Some(Self::default()),
+            _ => None,
+        }
+    }
+
+    /// The value of the "config" label on trace-level metrics.
+    pub fn trace_metrics_config_name() -> ConfigName {
+        ConfigName::new("trace")
+    }
+
+    /// Check if any span config pseudonymizes label values, which
+    /// requires a pseudonymization key.
+    pub fn pseudonymizes(&self) -> bool {
+        self.configs
+            .values()
+            .any(|config| !config.pseudonymize.is_empty())
+    }
+
+    /// Add the "request-path-relations" config, with duration metrics
+    /// per (grandparent, parent, current) service triple for spans
+    /// crossing a service boundary. This is not part of the default
+    /// config, since it multiplies the number of series per service.
+    pub fn with_request_path_relations(mut self) -> Self {
+        let name = ConfigName::new("request-path-relations");
+        self.rules.push(Vec::from([Rule {
+            select: SpanSelector::All(Vec::from_iter([
+                SpanSelector::Has(SpanKey::Grandparent(KeyName::Duration)),
+                SpanSelector::KeyNe(
+                    SpanKey::Current(KeyName::ServiceName),
+                    SpanKey::Parent(KeyName::ServiceName),
+                ),
+            ])),
+            config: name.clone(),
+            priority: None,
+            stop: true,
+        }]));
+        self.configs.insert(
+            name,
+            SpanConfig {
+                key: BTreeSet::from_iter([
+                    SpanKey::Current(KeyName::ServiceName),
+                    SpanKey::Parent(KeyName::ServiceName),
+                    SpanKey::Grandparent(KeyName::ServiceName),
+                ]),
+                metrics: BTreeMap::from_iter([(
+                    MetricName::new("duration"),
+                    MetricConfig {
+                        source: MetricSource::Duration,
+                        stats: StatsConfig::default_with_offset(NotNan::new(1000.0).unwrap())
+                            .with_max_value(max_duration()),
+                        enabled: true,
+                    },
+                )]),
+                carry_over: BTreeSet::new(),
+                carry_over_age: default_carry_over_age(),
+                pushdown: false,
+                normalize_numbers: false,
+                annotations: BTreeSet::new(),
+                idle_after: None,
+                respect_sampling: false,
+                max_sampling_weight: default_max_sampling_weight(),
+                classify: BTreeMap::new(),
+                pseudonymize: Vec::new(),
+                late_bins: None,
+                missing_key: MissingKey::Omit,
+                enabled: true,
+            },
+        );
+        self
+    }
+
+    /// Add an "error_reasons" metric to the "default" config, counting
+    /// the errors per HTTP status code. This is not part of the default
+    /// config, since it adds a series per status code and group.
+    pub fn with_error_reasons(mut self) -> Self {
+        if let Some(config) = self.configs.get_mut(&ConfigName::new("default")) {
+            config.metrics.insert(
+                MetricName::new("error_reasons"),
+                MetricConfig {
+                    source: MetricSource::RateBy {
+                        select: error_selector(),
+                        by: SpanKey::tag("http.status_code"),
+                        max_reasons: default_max_reasons(),
+                    },
+                    stats: StatsConfig {
+                        anomaly_score: None,
+                        mean_stddev: None,
+                        summary: None,
+                        histogram: None,
+                        ..StatsConfig::default()
+                    },
+                    enabled: true,
+                },
+            );
+        }
+        self
+    }
+
+    /// The rule groups in evaluation order: by priority, then by
+    /// position in the group, with the large value sets of the
+    /// selectors indexed (see `SpanSelector::index`).
+    fn sorted_rules(&self) -> Vec<Vec<Rule>> {
+        self.rules
+            .iter()
+            .map(|rules| {
+                let mut rules = rules.clone();
+                rules.sort_by_key(|rule| rule.priority.unwrap_or(0));
+                rules.iter_mut().for_each(|rule| rule.select.index());
+                rules
+            })
+            .collect()
+    }
+
+    /// Rule group indices and priorities shared by more than one rule
+    /// in the group. The evaluation order of such rules depends on
+    /// their position in the group.
+    pub fn equal_rule_priorities(&self) -> Vec<(usize, i32)> {
+        self.rules
+            .iter()
+            .enumerate()
+            .flat_map(|(group, rules)| {
+                rules
+                    .iter()
+                    .fold(BTreeMap::<i32, usize>::new(), |mut counts, rule| {
+                        *counts.entry(rule.priority.unwrap_or(0)).or_default() += 1;
+                        counts
+                    })
+                    .into_iter()
+                    .filter(|(_, n)| *n > 1)
+                    .map(move |(priority, _)| (group, priority))
+            })
+            .collect()
+    }
+
+    /// The aggregation queries of the configs with pushdown enabled, or
+    /// the reason a config does not support it.
+    pub fn pushdown_queries(
+        &self,
+    ) -> impl Iterator<Item = Result<PushdownQuery, (ConfigName, PushdownError)>> + '_ {
+        self.configs
+            .iter()
+            .filter(|(_, config)| config.pushdown)
+            .map(|(name, config)| {
+                PushdownQuery::new(name, config, &self.rules).map_err(|e| (name.clone(), e))
+            })
+    }
+
+    /// Check if metrics can be emitted with the given "config" label.
+    fn has_config(&self, name: &ConfigName) -> bool {
+        self.configs.contains_key(name) || name == &Self::trace_metrics_config_name()
+    }
 }
 
 impl TraceProcessor {
     pub fn new(config: &TraceConfig) -> Self {
         Self {
-            rules: config.rules.clone(),
+            rules: config.sorted_rules(),
             groups: config
                 .configs
                 .iter()
-                .map(|(name, config)| (name.clone(), SpanProcessor::new(config)))
+                .map(|(name, config)| (name.clone(), SpanProcessor::new(name, config)))
                 .collect(),
+            trace_metrics: TraceLevelProcessor::new(&config.trace_metrics),
+            dedup: config.dedup.as_ref().map(DedupSet::new),
+            invalid_windows: BTreeMap::new(),
+            duplicate_spans: BTreeMap::new(),
+            truncated_series: BTreeMap::new(),
+            compacted_groups: BTreeMap::new(),
+            expired_groups: BTreeMap::new(),
+            rule_stats: Arc::default(),
+            rule_totals: RuleCounts::default(),
+            series_stats: Arc::default(),
+            dirty: true,
+            last_save: None,
+            last_ingest: None,
+            last_pit: None,
+            last_write: None,
+            pushdown: pushdown_queries(config),
+            tags: TagAllowlist::new(config),
+            series: SeriesRegistry::default(),
+            last_sample: None,
+            maintenance: config.maintenance.clone(),
+            pseudonymization: None,
+            expiry: None,
+            quarantine: Quarantine::default(),
+            quarantine_stats: Arc::default(),
         }
     }
 
     pub fn update(mut self, t: DateTime<Utc>, config: &TraceConfig) -> TraceProcessor {
         TraceProcessor {
-            rules: config.rules.clone(),
+            rules: config.sorted_rules(),
             groups: config
                 .configs
                 .iter()
@@ -264,16 +660,81 @@ impl TraceProcessor {
                     if let Some(proc) = self.groups.remove(name) {
                         (name.clone(), proc.update(t, config))
                     } else {
-                        (name.clone(), SpanProcessor::new(config))
+                        let mut proc = SpanProcessor::new(name, config);
+                        proc.set_pseudonymization(self.pseudonymization.clone());
+                        (name.clone(), proc)
                     }
                 })
                 .collect(),
+            trace_metrics: self.trace_metrics.update(t, &config.trace_metrics),
+            dedup: config.dedup.as_ref().map(|config| {
+                self.dedup
+                    .map_or_else(|| DedupSet::new(config), |dedup| dedup.update(config))
+            }),
+            invalid_windows: self
+                .invalid_windows
+                .into_iter()
+                .filter(|(name, _)| config.has_config(name))
+                .collect(),
+            duplicate_spans: self
+                .duplicate_spans
+                .into_iter()
+                .filter(|(name, _)| config.has_config(name))
+                .collect(),
+            truncated_series: self
+                .truncated_series
+                .into_iter()
+                .filter(|(name, _)| config.has_config(name))
+                .collect(),
+            compacted_groups: self
+                .compacted_groups
+                .into_iter()
+                .filter(|(name, _)| config.configs.contains_key(name))
+                .collect(),
+            expired_groups: self
+                .expired_groups
+                .into_iter()
+                .filter(|(name, _)| config.has_config(name))
+                .collect(),
+            rule_stats: self.rule_stats,
+            rule_totals: RuleCounts {
+                matched: self
+                    .rule_totals
+                    .matched
+                    .into_iter()
+                    .map(|(group, configs)| {
+                        let configs = configs
+                            .into_iter()
+                            .filter(|(name, _)| config.configs.contains_key(name))
+                            .collect();
+                        (group, configs)
+                    })
+                    .collect(),
+                ..self.rule_totals
+            },
+            series_stats: self.series_stats,
+            dirty: true,
+            last_save: self.last_save,
+            last_ingest: self.last_ingest,
+            last_pit: self.last_pit,
+            last_write: self.last_write,
+            pushdown: pushdown_queries(config),
+            tags: TagAllowlist::new(config),
+            series: self.series,
+            last_sample: self.last_sample,
+            maintenance: config.maintenance.clone(),
+            pseudonymization: self.pseudonymization,
+            expiry: self.expiry,
+            quarantine: self
+                .quarantine
+                .update(|name| config.configs.contains_key(name)),
+            quarantine_stats: self.quarantine_stats,
         }
     }
 
     pub fn load(t: DateTime<Utc>, mut state: TraceState, config: &TraceConfig) -> Self {
         Self {
-            rules: config.rules.clone(),
+            rules: config.sorted_rules(),
             groups: config
                 .configs
                 .iter()
@@ -281,16 +742,161 @@ impl TraceProcessor {
                     (
                         name.clone(),
                         if let Some(state) = state.groups.remove(name) {
-                            SpanProcessor::load(t, state, config)
+                            SpanProcessor::load(t, name, state, config)
                         } else {
-                            SpanProcessor::new(config)
+                            SpanProcessor::new(name, config)
                         },
                     )
                 })
                 .collect(),
+            trace_metrics: state.trace_metrics.map_or_else(
+                || TraceLevelProcessor::new(&config.trace_metrics),
+                |state| TraceLevelProcessor::load(t, state, &config.trace_metrics),
+            ),
+            dedup: config.dedup.as_ref().map(DedupSet::new),
+            invalid_windows: BTreeMap::new(),
+            duplicate_spans: BTreeMap::new(),
+            truncated_series: BTreeMap::new(),
+            compacted_groups: BTreeMap::new(),
+            expired_groups: BTreeMap::new(),
+            rule_stats: Arc::default(),
+            rule_totals: RuleCounts::default(),
+            series_stats: Arc::default(),
+            dirty: false,
+            last_save: None,
+            last_ingest: None,
+            last_pit: None,
+            last_write: None,
+            pushdown: pushdown_queries(config),
+            tags: TagAllowlist::new(config),
+            series: state.series,
+            last_sample: state.last_sample,
+            maintenance: config.maintenance.clone(),
+            pseudonymization: None,
+            expiry: None,
+            quarantine: Quarantine::default(),
+            quarantine_stats: Arc::default(),
         }
     }
 
+    /// Report rule evaluation counts to a shared stats struct, in
+    /// addition to the self-monitoring metrics.
+    pub fn with_rule_stats(self, rule_stats: Arc<RuleStats>) -> Self {
+        Self { rule_stats, ..self }
+    }
+
+    /// Report the series counts of the last sample to a shared stats
+    /// struct.
+    pub fn with_series_stats(self, series_stats: Arc<SeriesStats>) -> Self {
+        Self {
+            series_stats,
+            ..self
+        }
+    }
+
+    /// Use `key` for the values pseudonymized by the span configs.
+    pub fn with_pseudonymization(mut self, key: Option<PseudonymizationKey>) -> Self {
+        self.groups
+            .values_mut()
+            .for_each(|proc| proc.set_pseudonymization(key.clone()));
+        Self {
+            pseudonymization: key,
+            ..self
+        }
+    }
+
+    /// Queue the groups removed by the cleanup for the expiry webhook.
+    pub fn with_expiry_queue(self, expiry: Option<ExpiryQueue>) -> Self {
+        Self { expiry, ..self }
+    }
+
+    /// Report the config failures to a shared stats struct, in
+    /// addition to the self-monitoring metrics.
+    pub fn with_quarantine_stats(self, quarantine_stats: Arc<QuarantineStats>) -> Self {
+        Self {
+            quarantine_stats,
+            ..self
+        }
+    }
+
+    /// A frozen view of the groups at `t`. See [`TraceSnapshot`].
+    pub fn snapshot(&self, t: DateTime<Utc>) -> TraceSnapshot {
+        TraceSnapshot::new(
+            t,
+            self.groups
+                .iter()
+                .filter(|(name, _)| !self.quarantine.is_quarantined(name))
+                .map(|(name, proc)| (name.clone(), proc.snapshot()))
+                .collect(),
+            self.trace_metrics.snapshot(),
+        )
+    }
+
+    /// Record the series counts of a sample, including the series
+    /// dropped because of the series limit.
+    pub fn record_series(&mut self, report: SeriesReport) {
+        report.dropped.iter().for_each(|(config_name, n)| {
+            *self
+                .truncated_series
+                .entry(config_name.clone())
+                .or_default() += n;
+        });
+        self.series_stats.set(report);
+    }
+
+    /// Register the series written for a sample at `t`, and record `t`
+    /// as the last sample.
+    pub fn register_series(&mut self, t: DateTime<Utc>, metrics: &Metrics) {
+        self.series.register(t, metrics);
+        self.last_sample = Some(self.last_sample.map_or(t, |last| last.max(t)));
+    }
+
+    /// The time of the last sample.
+    pub fn last_sample(&self) -> Option<DateTime<Utc>> {
+        self.last_sample
+    }
+
+    /// Stop all series, for a graceful shutdown. Returns staleness
+    /// markers for all recently written series.
+    pub fn shutdown(&mut self) -> Metrics {
+        let mut stale = Metrics::new();
+        self.series.remove_all(&mut stale);
+        stale
+    }
+
+    /// Whether the processor changed since the last save. Sampling and
+    /// cleanup do not count as changes: without new traces, they only
+    /// advance the windows, which is redone when the saved state is
+    /// loaded and sampled again.
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    /// Mark the processor as saved, recording the save statistics for
+    /// the self-monitoring metrics.
+    pub fn record_save(&mut self, stats: SaveStats) {
+        self.dirty = false;
+        self.last_save = Some(stats);
+    }
+
+    /// Record the ingest statistics of the last tick for the
+    /// self-monitoring metrics.
+    pub fn record_ingest(&mut self, report: IngestReport) {
+        self.last_ingest = Some(report);
+    }
+
+    /// Record the point in time keep-alive of the last tick for the
+    /// self-monitoring metrics.
+    pub fn record_pit(&mut self, report: PitReport) {
+        self.last_pit = Some(report);
+    }
+
+    /// Record the batching statistics of the metrics writer of the last
+    /// tick for the self-monitoring metrics.
+    pub fn record_writes(&mut self, report: WriteReport) {
+        self.last_write = Some(report);
+    }
+
     pub fn save(&self) -> TraceState {
         TraceState {
             groups: self
@@ -298,10 +904,897 @@ impl TraceProcessor {
                 .iter()
                 .map(|(name, proc)| ((*name).clone(), proc.save()))
                 .collect(),
+            trace_metrics: Some(self.trace_metrics.save()),
+            series: self.series.clone(),
+            last_sample: self.last_sample,
+        }
+    }
+
+    /// Insert the spans of a trace. The trace is skipped when its root
+    /// span does not match the ingest filter; the other spans are kept
+    /// regardless of their own service, like the traces fetched by the
+    /// root span query. When dedup is enabled, spans seen before are
+    /// skipped and counted.
+    /// Configs handled by pushdown are skipped.
+    pub fn insert(&mut self, t: DateTime<Utc>, trace: &[Span], filter: &IngestFilter) {
+        self.dirty |= !trace.is_empty();
+        let relations = TraceRelations::new(trace);
+        let duplicates = self.duplicates(t, trace);
+        let sampling = relations.root(trace).and_then(sampling_probability);
+        if let Some(root) = relations.root(trace).filter(|root| filter.matches(root)) {
+            if is_duplicate(&duplicates, root) {
+                self.count_duplicate(&TraceConfig::trace_metrics_config_name());
+            } else {
+                self.trace_metrics.insert(t, root, trace);
+            }
+        }
+        let groups = &mut self.groups;
+        let duplicate_spans = &mut self.duplicate_spans;
+        let pushdown = &self.pushdown;
+        let maintenance = &self.maintenance;
+        let quarantine = &mut self.quarantine;
+        let pseudonymization = self.pseudonymization.as_ref();
+        let mut counts = RuleCounts::default();
+        for_each_match(
+            &self.rules,
+            trace,
+            &relations,
+            filter,
+            &mut counts,
+            |_, rule, span, ancestors, children| {
+                let config = &rule.config;
+                if is_pushdown(pushdown, config) {
+                    return;
+                }
+                if is_duplicate(&duplicates, span) {
+                    *duplicate_spans.entry(config.clone()).or_default() += 1;
+                } else if let Some(proc) = groups.get_mut(config) {
+                    guard(quarantine, pseudonymization, config, proc, |proc| {
+                        proc.insert(t, span, ancestors, children, sampling, maintenance)
+                    });
+                }
+            },
+        );
+        self.count_rules(&counts);
+    }
+
+    /// Insert a batch of traces, processing the configs in parallel on
+    /// the rayon thread pool. Per config, spans are inserted in the same
+    /// order as repeated calls to `insert` would, so the resulting state
+    /// is identical.
+    ///
+    /// This returns only when all configs are done; callers must not
+    /// sample in the middle of a batch, so that every sample is a
+    /// consistent snapshot of all configs at that timestamp.
+    pub fn insert_batch(&mut self, traces: &[(DateTime<Utc>, &[Span])], filter: &IngestFilter) {
+        self.dirty |= !traces.is_empty();
+        let relations = traces
+            .iter()
+            .map(|(t, trace)| {
+                let duplicates = self.duplicates(*t, trace);
+                (*t, *trace, TraceRelations::new(trace), duplicates)
+            })
+            .collect::<Vec<_>>();
+
+        let mut work = BTreeMap::<ConfigName, Vec<_>>::new();
+        let mut counts = RuleCounts::default();
+        relations
+            .iter()
+            .for_each(|(t, trace, relations, duplicates)| {
+                let sampling = relations.root(trace).and_then(sampling_probability);
+                for_each_match(
+                    &self.rules,
+                    trace,
+                    relations,
+                    filter,
+                    &mut counts,
+                    |_, rule, span, ancestors, children| {
+                        let config = &rule.config;
+                        if is_pushdown(&self.pushdown, config)
+                            || self.quarantine.is_quarantined(config)
+                        {
+                            return;
+                        }
+                        if is_duplicate(duplicates, span) {
+                            *self.duplicate_spans.entry(config.clone()).or_default() += 1;
+                        } else if let Some(items) = work.get_mut(config) {
+                            items.push((*t, span, ancestors, children, sampling));
+                        } else {
+                            work.insert(
+                                config.clone(),
+                                vec![(*t, span, ancestors, children, sampling)],
+                            );
+                        }
+                    },
+                );
+                if relations
+                    .root(trace)
+                    .is_some_and(|root| filter.matches(root) && is_duplicate(duplicates, root))
+                {
+                    self.count_duplicate(&TraceConfig::trace_metrics_config_name());
+                }
+            });
+        self.count_rules(&counts);
+
+        let maintenance = &self.maintenance;
+        let jobs = self
+            .groups
+            .iter_mut()
+            .filter_map(|(name, proc)| Some((name, proc, work.remove(name)?)))
+            .collect::<Vec<_>>();
+        let (_, results) = rayon::join(
+            || {
+                relations
+                    .iter()
+                    .for_each(|(t, trace, relations, duplicates)| {
+                        if let Some(root) = relations
+                            .root(trace)
+                            .filter(|root| filter.matches(root) && !is_duplicate(duplicates, root))
+                        {
+                            self.trace_metrics.insert(*t, root, trace);
+                        }
+                    })
+            },
+            || {
+                // Catching the panics per config keeps a panicking
+                // config from taking down the others.
+                jobs.into_par_iter()
+                    .map(|(name, proc, items)| {
+                        let result = catch_failure(|| {
+                            items.into_iter().for_each(
+                                |(t, span, ancestors, children, sampling)| {
+                                    proc.insert(t, span, ancestors, children, sampling, maintenance)
+                                },
+                            )
+                        });
+                        (name, result)
+                    })
+                    .collect::<Vec<_>>()
+            },
+        );
+        results
+            .into_iter()
+            .filter_map(|(name, result)| {
+                self.quarantine
+                    .record(name, result)
+                    .is_none()
+                    .then(|| name.clone())
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .for_each(|name| self.restart(&name));
+    }
+
+    /// The aggregation queries of the configs handled by pushdown.
+    pub fn pushdown_queries(&self) -> &[PushdownQuery] {
+        &self.pushdown
+    }
+
+    /// The span and process tags referenced by the config.
+    pub fn tag_allowlist(&self) -> &TagAllowlist {
+        &self.tags
+    }
+
+    /// Insert the backend aggregates of a pushdown config for a sample
+    /// interval ending at `t`.
+    pub fn insert_aggregates(
+        &mut self,
+        t: DateTime<Utc>,
+        config: &ConfigName,
+        aggregates: Vec<(BTreeMap<SpanKey, TagValue>, SpanAggregate)>,
+    ) {
+        self.dirty |= !aggregates.is_empty();
+        if let Some(proc) = self.groups.get_mut(config) {
+            guard(
+                &mut self.quarantine,
+                self.pseudonymization.as_ref(),
+                config,
+                proc,
+                |proc| {
+                    aggregates
+                        .into_iter()
+                        .for_each(|(key, aggregate)| proc.insert_aggregate(t, key, &aggregate))
+                },
+            );
+        }
+    }
+
+    /// Emit the samples corrected by spans inserted after they were
+    /// taken (see `SpanConfig::late_bins`), at the original sample
+    /// times, oldest first per config.
+    pub fn sample_corrections<F: FnMut(DateTime<Utc>, MetricArgs<'_>, &ConfigName, f64)>(
+        &mut self,
+        mut metric: F,
+    ) {
+        self.groups.iter_mut().for_each(|(config_name, proc)| {
+            guard(
+                &mut self.quarantine,
+                self.pseudonymization.as_ref(),
+                config_name,
+                proc,
+                |proc| {
+                    proc.take_corrections()
+                        .into_iter()
+                        .for_each(|(t, snapshot)| {
+                            snapshot.sample(t, |metric_args, value| {
+                                metric(t, metric_args, config_name, value)
+                            });
+                        })
+                },
+            );
+        });
+    }
+
+    pub fn sample<F: FnMut(MetricArgs<'_>, &ConfigName, f64)>(
+        &mut self,
+        t: DateTime<Utc>,
+        mut metric: F,
+    ) {
+        self.groups.iter_mut().for_each(|(config_name, proc)| {
+            guard(
+                &mut self.quarantine,
+                self.pseudonymization.as_ref(),
+                config_name,
+                proc,
+                |proc| {
+                    proc.remove_superseded(t);
+                    proc.record_sample(t);
+                },
+            );
+        });
+
+        let snapshot = self.snapshot(t);
+        snapshot
+            .sample(&mut metric)
+            .into_iter()
+            .for_each(
+                |(config_name, result)| match self.quarantine.record(&config_name, result) {
+                    Some(invalid) => {
+                        *self.invalid_windows.entry(config_name).or_default() += invalid
+                    }
+                    None => self.restart(&config_name),
+                },
+            );
+        self.quarantine.end_sample();
+        self.quarantine_stats.set(self.quarantine.configs().clone());
+
+        let trace_config_name = TraceConfig::trace_metrics_config_name();
+
+        // Self-monitoring: windows skipped because of out-of-order inserts.
+        let no_group = GroupLabels::default();
+        self.invalid_windows.iter().for_each(|(config_name, n)| {
+            metric(
+                MetricArgs {
+                    metric_name: String::from("jaeger_anomaly_detection_invalid_windows_total"),
+                    metric_type: "self_monitoring",
+                    labels: Labels::default(),
+                    group: &no_group,
+                },
+                config_name,
+                *n as f64,
+            );
+        });
+
+        // Self-monitoring: rule hit rates. Span counts not tied to a
+        // config are reported under the trace config name.
+        [
+            ("jad_evaluated_spans_total", self.rule_totals.evaluated),
+            ("jad_unmatched_spans_total", self.rule_totals.unmatched),
+        ]
+        .into_iter()
+        .for_each(|(metric_name, n)| {
+            metric(
+                MetricArgs {
+                    metric_name: String::from(metric_name),
+                    metric_type: "self_monitoring",
+                    labels: Labels::default(),
+                    group: &no_group,
+                },
+                &trace_config_name,
+                n as f64,
+            );
+        });
+        self.rule_totals
+            .matched
+            .iter()
+            .for_each(|(group, configs)| {
+                configs.iter().for_each(|(config_name, n)| {
+                    metric(
+                        MetricArgs {
+                            metric_name: String::from("jad_rule_matches_total"),
+                            metric_type: "self_monitoring",
+                            labels: Labels {
+                                rule_group: Some(*group),
+                                ..Labels::default()
+                            },
+                            group: &no_group,
+                        },
+                        config_name,
+                        *n as f64,
+                    );
+                });
+            });
+
+        // Self-monitoring: values above a metric's max value.
+        self.groups.iter().for_each(|(config_name, proc)| {
+            proc.guarded().iter().for_each(|(metric_name, counts)| {
+                [("clamped", counts.clamped), ("dropped", counts.dropped)]
+                    .into_iter()
+                    .for_each(|(action, n)| {
+                        metric(
+                            MetricArgs {
+                                metric_name: String::from(
+                                    "jaeger_anomaly_detection_guarded_values_total",
+                                ),
+                                metric_type: "self_monitoring",
+                                labels: Labels {
+                                    metric: Some(metric_name.clone()),
+                                    action: Some(action),
+                                    ..Labels::default()
+                                },
+                                group: &no_group,
+                            },
+                            config_name,
+                            n as f64,
+                        );
+                    });
+            });
+        });
+
+        // Self-monitoring: spans skipped for lacking key components.
+        self.groups
+            .iter()
+            .filter(|(_, proc)| proc.config().missing_key == MissingKey::Skip)
+            .for_each(|(config_name, proc)| {
+                metric(
+                    MetricArgs {
+                        metric_name: String::from(
+                            "jaeger_anomaly_detection_missing_key_spans_total",
+                        ),
+                        metric_type: "self_monitoring",
+                        labels: Labels::default(),
+                        group: &no_group,
+                    },
+                    config_name,
+                    proc.skipped_spans() as f64,
+                );
+            });
+
+        // Self-monitoring: series dropped because of the series limit.
+        self.truncated_series.iter().for_each(|(config_name, n)| {
+            metric(
+                MetricArgs {
+                    metric_name: String::from("jaeger_anomaly_detection_truncated_series_total"),
+                    metric_type: "self_monitoring",
+                    labels: Labels::default(),
+                    group: &no_group,
+                },
+                config_name,
+                *n as f64,
+            );
+        });
+
+        // Self-monitoring: duration and size of the last state save.
+        if let Some(save) = &self.last_save {
+            [
+                (
+                    "jaeger_anomaly_detection_state_save_seconds",
+                    save.duration_seconds,
+                ),
+                (
+                    "jaeger_anomaly_detection_state_size_bytes",
+                    save.bytes as f64,
+                ),
+            ]
+            .into_iter()
+            .for_each(|(metric_name, value)| {
+                metric(
+                    MetricArgs {
+                        metric_name: String::from(metric_name),
+                        metric_type: "self_monitoring",
+                        labels: Labels::default(),
+                        group: &no_group,
+                    },
+                    &trace_config_name,
+                    value,
+                );
+            });
+        }
+
+        // Self-monitoring: trace sizes and backend latencies of the
+        // last tick.
+        if let Some(ingest) = &self.last_ingest {
+            [
+                ("jaeger_anomaly_detection_ingest_traces", ingest.traces),
+                ("jaeger_anomaly_detection_ingest_spans", ingest.spans),
+            ]
+            .into_iter()
+            .for_each(|(metric_name, n)| {
+                metric(
+                    MetricArgs {
+                        metric_name: String::from(metric_name),
+                        metric_type: "self_monitoring",
+                        labels: Labels::default(),
+                        group: &no_group,
+                    },
+                    &trace_config_name,
+                    n as f64,
+                );
+            });
+            ingest.spans_per_trace.iter().for_each(|(le, n)| {
+                metric(
+                    MetricArgs {
+                        metric_name: String::from(
+                            "jaeger_anomaly_detection_ingest_spans_per_trace_buckets",
+                        ),
+                        metric_type: "self_monitoring",
+                        labels: Labels {
+                            le: Some(le.clone()),
+                            ..Labels::default()
+                        },
+                        group: &no_group,
+                    },
+                    &trace_config_name,
+                    *n as f64,
+                );
+            });
+            [
+                ("es_root_query", &ingest.root_queries),
+                ("es_span_query", &ingest.span_queries),
+                ("remote_write", &ingest.remote_writes),
+            ]
+            .into_iter()
+            .for_each(|(request, latency)| {
+                metric(
+                    MetricArgs {
+                        metric_name: format!("jaeger_anomaly_detection_{request}_count"),
+                        metric_type: "self_monitoring",
+                        labels: Labels::default(),
+                        group: &no_group,
+                    },
+                    &trace_config_name,
+                    latency.count as f64,
+                );
+                latency.quantiles.iter().for_each(|(q, seconds)| {
+                    metric(
+                        MetricArgs {
+                            metric_name: format!("jaeger_anomaly_detection_{request}_seconds"),
+                            metric_type: "self_monitoring",
+                            labels: Labels {
+                                q: Some(q.clone()),
+                                ..Labels::default()
+                            },
+                            group: &no_group,
+                        },
+                        &trace_config_name,
+                        *seconds,
+                    );
+                });
+            });
+        }
+
+        // Self-monitoring: point in time keep-alive of the last tick.
+        if let Some(pit) = &self.last_pit {
+            [
+                (
+                    "jaeger_anomaly_detection_pit_keep_alive_seconds",
+                    pit.keep_alive_seconds,
+                ),
+                (
+                    "jaeger_anomaly_detection_pit_longest_gap_seconds",
+                    pit.longest_gap_seconds,
+                ),
+                (
+                    "jaeger_anomaly_detection_pit_near_expiry_total",
+                    pit.near_expiry_ticks as f64,
+                ),
+            ]
+            .into_iter()
+            .for_each(|(metric_name, value)| {
+                metric(
+                    MetricArgs {
+                        metric_name: String::from(metric_name),
+                        metric_type: "self_monitoring",
+                        labels: Labels::default(),
+                        group: &no_group,
+                    },
+                    &trace_config_name,
+                    value,
+                );
+            });
+        }
+
+        // Self-monitoring: remote-write batching of the last tick.
+        if let Some(write) = &self.last_write {
+            [
+                (
+                    "jaeger_anomaly_detection_write_requests",
+                    write.requests as f64,
+                ),
+                (
+                    "jaeger_anomaly_detection_write_buffer_max_samples",
+                    write.max_buffered as f64,
+                ),
+                (
+                    "jaeger_anomaly_detection_write_utilization",
+                    write.utilization,
+                ),
+            ]
+            .into_iter()
+            .for_each(|(metric_name, value)| {
+                metric(
+                    MetricArgs {
+                        metric_name: String::from(metric_name),
+                        metric_type: "self_monitoring",
+                        labels: Labels::default(),
+                        group: &no_group,
+                    },
+                    &trace_config_name,
+                    value,
+                );
+            });
+            write.samples_per_request.iter().for_each(|(le, n)| {
+                metric(
+                    MetricArgs {
+                        metric_name: String::from(
+                            "jaeger_anomaly_detection_write_samples_per_request_buckets",
+                        ),
+                        metric_type: "self_monitoring",
+                        labels: Labels {
+                            le: Some(le.clone()),
+                            ..Labels::default()
+                        },
+                        group: &no_group,
+                    },
+                    &trace_config_name,
+                    *n as f64,
+                );
+            });
+        }
+
+        // Self-monitoring: groups whose summary and histogram
+        // statistics were dropped because they were idle.
+        self.compacted_groups.iter().for_each(|(config_name, n)| {
+            metric(
+                MetricArgs {
+                    metric_name: String::from("jaeger_anomaly_detection_compacted_groups_total"),
+                    metric_type: "self_monitoring",
+                    labels: Labels::default(),
+                    group: &no_group,
+                },
+                config_name,
+                *n as f64,
+            );
+        });
+
+        // Self-monitoring: groups removed because they were not seen
+        // for too long.
+        self.expired_groups.iter().for_each(|(config_name, n)| {
+            metric(
+                MetricArgs {
+                    metric_name: String::from("jad_groups_expired_total"),
+                    metric_type: "self_monitoring",
+                    labels: Labels::default(),
+                    group: &no_group,
+                },
+                config_name,
+                *n as f64,
+            );
+        });
+
+        // Self-monitoring: span config failures, and the configs
+        // quarantined because of them.
+        self.quarantine
+            .configs()
+            .iter()
+            .for_each(|(config_name, failures)| {
+                [
+                    (
+                        "jaeger_anomaly_detection_config_failures_total",
+                        failures.total as f64,
+                    ),
+                    (
+                        "jaeger_anomaly_detection_config_quarantined",
+                        f64::from(u8::from(failures.quarantined)),
+                    ),
+                ]
+                .into_iter()
+                .for_each(|(metric_name, value)| {
+                    metric(
+                        MetricArgs {
+                            metric_name: String::from(metric_name),
+                            metric_type: "self_monitoring",
+                            labels: Labels::default(),
+                            group: &no_group,
+                        },
+                        config_name,
+                        value,
+                    );
+                });
+            });
+
+        // Self-monitoring: spans skipped by dedup.
+        self.duplicate_spans.iter().for_each(|(config_name, n)| {
+            metric(
+                MetricArgs {
+                    metric_name: String::from("jaeger_anomaly_detection_duplicate_spans_total"),
+                    metric_type: "self_monitoring",
+                    labels: Labels::default(),
+                    group: &no_group,
+                },
+                config_name,
+                *n as f64,
+            );
+        });
+    }
+
+    /// Merge a baseline bundle into the running processor. Entries
+    /// that do not match the current config are skipped.
+    pub fn import_baselines(&mut self, t: DateTime<Utc>, bundle: BaselineBundle) -> ImportReport {
+        self.dirty = true;
+        let trace_config = TraceConfig::trace_metrics_config_name();
+        ImportReport::new(
+            bundle
+                .entries
+                .into_iter()
+                .map(|entry| {
+                    let result = if entry.config == trace_config {
+                        self.trace_metrics.import_baseline(
+                            t,
+                            entry.key.clone(),
+                            &entry.metric,
+                            entry.baseline,
+                        )
+                    } else if let Some(proc) = self.groups.get_mut(&entry.config) {
+                        proc.import_baseline(t, entry.key.clone(), &entry.metric, entry.baseline)
+                    } else {
+                        Err(BaselineSkip::UnknownConfig)
+                    };
+                    ImportEntry::new(entry.config, &entry.key, entry.metric, result)
+                })
+                .collect(),
+        )
+    }
+
+    /// Report how the spans of a trace would be processed: the rules
+    /// matching each span, the group key and the extracted values per
+    /// config. Nothing is inserted.
+    pub fn debug_trace(&self, trace: &[Span], filter: &IngestFilter) -> Vec<SpanDebug> {
+        let relations = TraceRelations::new(trace);
+        let kept = relations
+            .root(trace)
+            .is_some_and(|root| filter.matches(root));
+        let mut matches = BTreeMap::<&SpanId, Vec<RuleMatch>>::new();
+        for_each_match(
+            &self.rules,
+            trace,
+            &relations,
+            filter,
+            &mut RuleCounts::default(),
+            |group, rule, span, ancestors, children| {
+                let rule_match = match self.groups.get(&rule.config) {
+                    Some(proc) => {
+                        let classifiers = &proc.config().classify;
+                        let key = proc.span_key(span, ancestors);
+                        let missing_key = key.is_none();
+                        let key = proc.group_key(key.unwrap_or_default());
+                        let t =
+                            DateTime::from_timestamp_micros(span.start_time).unwrap_or_default();
+                        RuleMatch {
+                            rule_group: group,
+                            config: rule.config.clone(),
+                            select: rule.select.to_string(),
+                            key: key_labels(&key).collect(),
+                            missing_keys: proc
+                                .key()
+                                .iter()
+                                .filter(|key| key.get_with(span, ancestors, classifiers).is_none())
+                                .map(|key| key.label().into_string())
+                                .collect(),
+                            values: proc
+                                .config()
+                                .metrics
+                                .iter()
+                                .map(|(name, config)| {
+                                    let mut values = Vec::new();
+                                    SourceProcessor::new(t, &config.source).insert(
+                                        t,
+                                        span,
+                                        ancestors,
+                                        children,
+                                        classifiers,
+                                        1.0,
+                                        |v| values.push(v),
+                                    );
+                                    (name.clone(), values)
+                                })
+                                .collect(),
+                            skipped: if is_pushdown(&self.pushdown, &rule.config) {
+                                Some(SkipReason::Pushdown)
+                            } else if missing_key {
+                                Some(SkipReason::MissingKey)
+                            } else {
+                                None
+                            },
+                        }
+                    }
+                    None => RuleMatch {
+                        rule_group: group,
+                        config: rule.config.clone(),
+                        select: rule.select.to_string(),
+                        key: BTreeMap::new(),
+                        missing_keys: Vec::new(),
+                        values: BTreeMap::new(),
+                        skipped: Some(SkipReason::UnknownConfig),
+                    },
+                };
+                matches.entry(&span.span_id).or_default().push(rule_match);
+            },
+        );
+        trace
+            .iter()
+            .map(|span| {
+                let matches = matches.remove(&span.span_id).unwrap_or_default();
+                SpanDebug {
+                    span_id: span.span_id.to_string(),
+                    service_name: Some(
+                        self.debug_name(KeyName::ServiceName, &span.process.service_name.0),
+                    ),
+                    operation_name: Some(
+                        self.debug_name(KeyName::OperationName, &span.operation_name.0),
+                    ),
+                    skipped: if !kept {
+                        Some(SkipReason::IngestFilter)
+                    } else if matches.is_empty() {
+                        Some(SkipReason::NoMatchingRule)
+                    } else {
+                        None
+                    },
+                    matches,
+                }
+            })
+            .collect()
+    }
+
+    /// A service or operation name as shown in the debug report:
+    /// pseudonymized if any span config pseudonymizes it.
+    fn debug_name(&self, name: KeyName, value: &str) -> String {
+        let key = SpanKey::Current(name);
+        let value = TagValue::String(value.to_string());
+        let value = self
+            .groups
+            .values()
+            .find_map(|proc| proc.pseudonym(&key, value.clone()))
+            .unwrap_or(value);
+        label_value(&value)
+    }
+
+    fn duplicates<'a>(&mut self, t: DateTime<Utc>, trace: &'a [Span]) -> Vec<&'a Span> {
+        self.dedup
+            .as_mut()
+            .map_or_else(Vec::new, |dedup| dedup.duplicates(t, trace))
+    }
+
+    fn count_rules(&mut self, counts: &RuleCounts) {
+        self.rule_stats.add(counts);
+        self.rule_totals.add(counts);
+    }
+
+    fn count_duplicate(&mut self, config: &ConfigName) {
+        *self.duplicate_spans.entry(config.clone()).or_default() += 1;
+    }
+
+    /// Remove the groups not seen since `t`. Returns staleness markers
+    /// for the series of the removed groups. The removed groups are
+    /// counted and queued for the expiry webhook.
+    pub fn cleanup(&mut self, t: DateTime<Utc>) -> Metrics {
+        let mut stale = Metrics::new();
+        let mut removed = self
+            .groups
+            .iter_mut()
+            .map(|(config_name, proc)| {
+                let removed = guard(
+                    &mut self.quarantine,
+                    self.pseudonymization.as_ref(),
+                    config_name,
+                    proc,
+                    |proc| proc.cleanup(t),
+                )
+                .unwrap_or_default();
+                self.series
+                    .remove_groups(config_name, proc.key(), &removed, &mut stale);
+                (config_name.clone(), removed)
+            })
+            .collect::<Vec<_>>();
+        let trace_config_name = TraceConfig::trace_metrics_config_name();
+        let trace_removed = self.trace_metrics.cleanup(t);
+        self.series.remove_groups(
+            &trace_config_name,
+            self.trace_metrics.key(),
+            &trace_removed,
+            &mut stale,
+        );
+        removed.push((trace_config_name, trace_removed));
+        removed
+            .iter()
+            .filter(|(_, keys)| !keys.is_empty())
+            .for_each(|(config_name, keys)| {
+                *self.expired_groups.entry(config_name.clone()).or_default() += keys.len() as u64;
+                if let Some(expiry) = &self.expiry {
+                    expiry.push(keys.iter().map(|key| ExpiredGroup::new(config_name, key)));
+                }
+            });
+        stale
+    }
+
+    /// Drop the summary and histogram statistics of the groups that
+    /// became idle at `t`, for the configs with `idle_after` set.
+    pub fn compact_idle(&mut self, t: DateTime<Utc>) {
+        self.groups.iter_mut().for_each(|(config_name, proc)| {
+            let n = guard(
+                &mut self.quarantine,
+                self.pseudonymization.as_ref(),
+                config_name,
+                proc,
+                |proc| proc.compact_idle(t),
+            )
+            .unwrap_or(0);
+            if n > 0 {
+                *self
+                    .compacted_groups
+                    .entry(config_name.clone())
+                    .or_default() += n;
+            }
+        });
+    }
+
+    /// Start a span config over after a failure, as its state may be
+    /// inconsistent.
+    fn restart(&mut self, config_name: &ConfigName) {
+        if let Some(proc) = self.groups.get_mut(config_name) {
+            *proc = restarted(config_name, proc, self.pseudonymization.as_ref());
         }
     }
+}
+
+/// Run `f` on the processor of a span config, unless the config is
+/// quarantined. A config that panics is started over.
+fn guard<T, F: FnOnce(&mut SpanProcessor) -> T>(
+    quarantine: &mut Quarantine,
+    pseudonymization: Option<&PseudonymizationKey>,
+    config_name: &ConfigName,
+    proc: &mut SpanProcessor,
+    f: F,
+) -> Option<T> {
+    if quarantine.is_quarantined(config_name) {
+        return None;
+    }
+    let result = quarantine.guard(config_name, || f(proc));
+    if result.is_none() {
+        *proc = restarted(config_name, proc, pseudonymization);
+    }
+    result
+}
 
-    pub fn insert(&mut self, t: DateTime<Utc>, trace: &[Span]) {
+/// A new processor with the config of a failed one.
+fn restarted(
+    config_name: &ConfigName,
+    proc: &SpanProcessor,
+    pseudonymization: Option<&PseudonymizationKey>,
+) -> SpanProcessor {
+    let mut restarted = SpanProcessor::new(config_name, proc.config());
+    restarted.set_pseudonymization(pseudonymization.cloned());
+    restarted
+}
+
+/// Parent and child lookups for the spans in a trace.
+struct TraceRelations<'a> {
+    parents: BTreeMap<&'a SpanId, &'a Span>,
+    children: BTreeMap<&'a SpanId, Vec<&'a Span>>,
+}
+
+impl<'a> TraceRelations<'a> {
+    fn new(trace: &'a [Span]) -> Self {
         let spans = trace
             .iter()
             .map(|span| (&span.span_id, span))
@@ -331,35 +1824,1428 @@ impl TraceProcessor {
                 map.entry(parent).or_default().push(span);
                 map
             });
-        trace.iter().for_each(|span| {
-            for rule in self.rules.iter().filter_map(|rules| {
-                rules.iter().find(|rule| {
-                    rule.select
-                        .matches(span, parents.get(&span.span_id).copied())
+        Self { parents, children }
+    }
+
+    /// The parent and grandparent of a span.
+    fn ancestors(&self, span: &Span) -> Ancestors<'a> {
+        let parent = self.parents.get(&span.span_id).copied();
+        Ancestors {
+            parent,
+            grandparent: parent.and_then(|parent| self.parents.get(&parent.span_id).copied()),
+        }
+    }
+
+    /// The root span: the first span without a parent in the trace.
+    fn root(&self, trace: &'a [Span]) -> Option<&'a Span> {
+        trace
+            .iter()
+            .find(|span| !self.parents.contains_key(&span.span_id))
+    }
+}
+
+/// The pushdown queries of the configs that support it. Other configs
+/// with pushdown enabled are processed span by span.
+fn pushdown_queries(config: &TraceConfig) -> Vec<PushdownQuery> {
+    config
+        .pushdown_queries()
+        .filter_map(|query| {
+            query
+                .map_err(|(name, e)| {
+                    log::warn!("config {name} is processed span by span: {e}");
                 })
-            }) {
-                let parent = parents.get(&span.span_id).copied();
-                let children: &[&Span] = children.get(&span.span_id).map_or(&[], |cs| cs);
-                if let Some(proc) = self.groups.get_mut(&rule.config) {
-                    proc.insert(t, span, parent, children);
+                .ok()
+        })
+        .collect()
+}
+
+/// Check whether the config's spans are handled by pushdown.
+fn is_pushdown(pushdown: &[PushdownQuery], config: &ConfigName) -> bool {
+    pushdown.iter().any(|query| &query.config == config)
+}
+
+/// Check whether this occurrence of the span was found to be a duplicate.
+/// The root span tag holding the type of the head sampler.
+pub(crate) const SAMPLER_TYPE_TAG: &str = "sampler.type";
+
+/// The root span tag holding the sampling probability.
+pub(crate) const SAMPLER_PARAM_TAG: &str = "sampler.param";
+
+fn is_duplicate(duplicates: &[&Span], span: &Span) -> bool {
+    duplicates.iter().any(|dup| std::ptr::eq(*dup, span))
+}
+
+/// The probability with which a head-sampled trace was kept, from the
+/// sampler tags of its root span. Only probabilistic samplers with a
+/// probability below one are taken into account. `TagValue` has no
+/// float variant, so the probability must be reported as a string (or
+/// as an integer, which is never below one).
+fn sampling_probability(root: &Span) -> Option<f64> {
+    let tag = |key: &str| {
+        root.tags
+            .iter()
+            .find(|tag| tag.key == key)
+            .map(|tag| &tag.value)
+    };
+    if tag(SAMPLER_TYPE_TAG)?.as_str()? != "probabilistic" {
+        return None;
+    }
+    let p = match tag(SAMPLER_PARAM_TAG)? {
+        TagValue::String(s) => s.trim().parse::<f64>().ok()?,
+        TagValue::Int64(n) => n.0 as f64,
+        TagValue::Bool(_) => return None,
+    };
+    (p > 0.0 && p < 1.0).then_some(p)
+}
+
+/// Call `f` for every (span, rule) pair selected by the rules, with
+/// the index of the rule group. Nothing is selected when the root span
+/// does not match the ingest filter. The rules must be sorted in
+/// evaluation order (see `sorted_rules`). Evaluated, matched and
+/// unmatched spans are counted in `counts`.
+fn for_each_match<'a, F>(
+    rules: &[Vec<Rule>],
+    trace: &'a [Span],
+    relations: &'a TraceRelations<'a>,
+    filter: &IngestFilter,
+    counts: &mut RuleCounts,
+    mut f: F,
+) where
+    F: FnMut(usize, &Rule, &'a Span, Ancestors<'a>, &'a [&'a Span]),
+{
+    if !relations
+        .root(trace)
+        .is_some_and(|root| filter.matches(root))
+    {
+        return;
+    }
+    trace.iter().for_each(|span| {
+        let ancestors = relations.ancestors(span);
+        let children: &[&Span] = relations.children.get(&span.span_id).map_or(&[], |cs| cs);
+        let mut matched = false;
+        for (group, rules) in rules.iter().enumerate() {
+            for rule in rules
+                .iter()
+                .filter(|rule| rule.select.matches(span, ancestors))
+            {
+                counts.count_match(group, &rule.config);
+                matched = true;
+                f(group, rule, span, ancestors, children);
+                if rule.stop {
+                    break;
                 }
             }
-        })
+        }
+        counts.evaluated += 1;
+        if !matched {
+            counts.unmatched += 1;
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::{BTreeMap, BTreeSet},
+        sync::Arc,
+        time::Instant,
+    };
+
+    use chrono::{DateTime, TimeDelta, Utc};
+    use jaeger_anomaly_detection::ReferenceInterval;
+
+    use super::{Rule, SkipReason, TraceConfig, TraceProcessor};
+    use crate::{
+        config::{
+            ConfigName, IngestFilter, KeyName, MetricName, SpanKey, SpanSelector, ValueMatch,
+        },
+        jaeger::{Span, Tag, TagValue},
+        metrics::Metrics,
+        processor::{
+            anomaly_score::{AnomalyScoreConfig, OffsetSeries},
+            baseline::BaselineBundle,
+            dedup::DedupConfig,
+            expiry::test_queue,
+            mean_stddev::MeanStddevConfig,
+            metric::MetricConfig,
+            quarantine::MAX_FAILED_SAMPLES,
+            rule_stats::{RuleCounts, RuleStats},
+            sampling::sample_metrics,
+            sim::{span, start, synthetic_traces},
+            source::MetricSource,
+            span::{default_carry_over_age, default_max_sampling_weight, MissingKey, SpanConfig},
+            staleness::STALE_NAN,
+            stats::StatsConfig,
+            trace_level::{TraceMetricConfig, TraceMetricSource, TraceMetricsConfig},
+        },
+    };
+
+    fn sample(proc: &mut TraceProcessor, t: DateTime<Utc>) -> Vec<String> {
+        let mut output = Vec::new();
+        proc.sample(t, |args, config_name, value| {
+            output.push(format!(
+                "{config_name} {} {} {:?} {:?} {:?} {:?} {:?} {}",
+                args.metric_name,
+                args.metric_type,
+                args.group,
+                args.labels.immediate,
+                args.labels.reference,
+                args.labels.q,
+                args.labels.le,
+                value.to_bits()
+            ))
+        });
+        output
     }
 
-    pub fn sample<F: FnMut(MetricArgs<'_>, &ConfigName, f64)>(
-        &mut self,
+    fn insert_sequential(proc: &mut TraceProcessor, traces: &[Vec<Span>]) {
+        traces.iter().for_each(|trace| {
+            let t = DateTime::from_timestamp_micros(trace[0].start_time).unwrap();
+            proc.insert(t, trace, &IngestFilter::default());
+        });
+    }
+
+    fn insert_batch(proc: &mut TraceProcessor, traces: &[Vec<Span>]) {
+        let batch = traces
+            .iter()
+            .map(|trace| {
+                let t = DateTime::from_timestamp_micros(trace[0].start_time).unwrap();
+                (t, trace.as_slice())
+            })
+            .collect::<Vec<_>>();
+        proc.insert_batch(&batch, &IngestFilter::default());
+    }
+
+    #[test]
+    fn batch_insert_matches_sequential_insert() {
+        let traces = synthetic_traces(start(), 3000);
+        let end = start() + TimeDelta::minutes(6);
+
+        let mut sequential = TraceProcessor::new(&TraceConfig::default());
+        insert_sequential(&mut sequential, &traces);
+
+        let mut parallel = TraceProcessor::new(&TraceConfig::default());
+        insert_batch(&mut parallel, &traces);
+
+        let output = sample(&mut sequential, end);
+        assert!(!output.is_empty());
+        assert_eq!(output, sample(&mut parallel, end));
+    }
+
+    #[test]
+    fn dedup_skips_duplicate_spans() {
+        let config = TraceConfig {
+            dedup: Some(DedupConfig::default()),
+            ..TraceConfig::default()
+        };
+        let traces = synthetic_traces(start(), 300);
+        let end = start() + TimeDelta::minutes(6);
+
+        let mut single = TraceProcessor::new(&config);
+        insert_sequential(&mut single, &traces);
+
+        let mut sequential = TraceProcessor::new(&config);
+        insert_sequential(&mut sequential, &traces);
+        insert_sequential(&mut sequential, &traces);
+
+        let mut batch = TraceProcessor::new(&config);
+        insert_batch(&mut batch, &traces);
+        insert_batch(&mut batch, &traces);
+
+        // Rule evaluation counters include duplicates; compare only
+        // the regular metrics.
+        let (monitoring, expected): (Vec<_>, Vec<_>) = sample(&mut single, end)
+            .into_iter()
+            .partition(|line| line.contains("self_monitoring"));
+        assert!(!expected.is_empty());
+        assert!(monitoring
+            .iter()
+            .all(|line| !line.contains("duplicate_spans")));
+
+        for proc in [&mut sequential, &mut batch] {
+            let (monitoring, output): (Vec<_>, Vec<_>) = sample(proc, end)
+                .into_iter()
+                .partition(|line| line.contains("self_monitoring"));
+            assert_eq!(output, expected);
+            assert!(monitoring.contains(&format!(
+                "trace jaeger_anomaly_detection_duplicate_spans_total self_monitoring {{}} \
+                 None None None None {}",
+                300f64.to_bits()
+            )));
+        }
+    }
+
+    #[test]
+    fn rule_match_counts() {
+        let services = |services: &[&str]| {
+            SpanSelector::In(
+                SpanKey::Current(KeyName::ServiceName),
+                services.iter().map(|s| s.to_string()).collect(),
+            )
+        };
+        let config = TraceConfig {
+            rules: vec![
+                vec![
+                    Rule {
+                        select: services(&["frontend"]),
+                        config: ConfigName::new("a"),
+                        priority: None,
+                        stop: true,
+                    },
+                    Rule {
+                        select: services(&["frontend", "backend"]),
+                        config: ConfigName::new("b"),
+                        priority: None,
+                        stop: true,
+                    },
+                ],
+                vec![Rule {
+                    select: services(&["backend"]),
+                    config: ConfigName::new("c"),
+                    priority: None,
+                    stop: true,
+                }],
+            ],
+            configs: BTreeMap::new(),
+            trace_metrics: TraceMetricsConfig {
+                key: BTreeSet::new(),
+                metrics: BTreeMap::new(),
+            },
+            dedup: None,
+            maintenance: Vec::new(),
+        };
+        let traces = synthetic_traces(start(), 10);
+        let stats = Arc::new(RuleStats::default());
+
+        let mut sequential = TraceProcessor::new(&config).with_rule_stats(stats.clone());
+        insert_sequential(&mut sequential, &traces);
+        let mut batch = TraceProcessor::new(&config).with_rule_stats(stats.clone());
+        insert_batch(&mut batch, &traces);
+
+        // Both processors report to the same stats: every trace has a
+        // frontend, a backend and an (unmatched) database span.
+        assert_eq!(stats.last_tick(), None);
+        stats.end_tick();
+        assert_eq!(
+            stats.last_tick(),
+            Some(RuleCounts {
+                evaluated: 60,
+                unmatched: 20,
+                matched: BTreeMap::from_iter([
+                    (
+                        0,
+                        BTreeMap::from_iter([
+                            (ConfigName::new("a"), 20),
+                            (ConfigName::new("b"), 20),
+                        ])
+                    ),
+                    (1, BTreeMap::from_iter([(ConfigName::new("c"), 20)])),
+                ]),
+            })
+        );
+        stats.end_tick();
+        assert_eq!(stats.last_tick(), Some(RuleCounts::default()));
+
+        let output = sample(&mut sequential, start() + TimeDelta::minutes(1));
+        for (config, metric, n) in [
+            ("trace", "evaluated_spans", 30.0),
+            ("trace", "unmatched_spans", 10.0),
+            ("a", "rule_matches", 10.0),
+            ("b", "rule_matches", 10.0),
+            ("c", "rule_matches", 10.0),
+        ] {
+            assert!(output.contains(&format!(
+                "{config} jad_{metric}_total self_monitoring {{}} \
+                 None None None None {}",
+                f64::to_bits(n)
+            )));
+        }
+    }
+
+    #[test]
+    fn ingest_filter_keeps_cross_namespace_children() {
+        let config = TraceConfig {
+            rules: vec![vec![Rule {
+                select: SpanSelector::All(Vec::new()),
+                config: ConfigName::new("a"),
+                priority: None,
+                stop: true,
+            }]],
+            configs: BTreeMap::new(),
+            trace_metrics: TraceMetricsConfig {
+                key: BTreeSet::new(),
+                metrics: BTreeMap::new(),
+            },
+            dedup: None,
+            maintenance: Vec::new(),
+        };
+        let in_namespace = |mut span: Span, namespace: &str| {
+            span.process
+                .tags
+                .iter_mut()
+                .filter(|tag| tag.key == "service.namespace")
+                .for_each(|tag| tag.value = TagValue::String(namespace.to_string()));
+            span
+        };
+        let t = start().timestamp_micros();
+        let traces = vec![vec![
+            in_namespace(span("01", "1", None, "frontend", "GET", t, 1000), "shop"),
+            in_namespace(
+                span("01", "2", Some("1"), "auth", "check", t + 100, 500),
+                "platform",
+            ),
+        ]];
+        let namespace = |namespace: &str| IngestFilter {
+            include_namespaces: vec![ValueMatch::Eq(namespace.to_string())],
+            ..IngestFilter::default()
+        };
+        let insert = |proc: &mut TraceProcessor, filter: &IngestFilter| {
+            let t = DateTime::from_timestamp_micros(t).unwrap();
+            proc.insert(t, &traces[0], filter);
+            proc.insert_batch(&[(t, traces[0].as_slice())], filter);
+        };
+        let stats = Arc::new(RuleStats::default());
+        let mut proc = TraceProcessor::new(&config).with_rule_stats(stats.clone());
+
+        // The child span in another namespace is kept with its root.
+        insert(&mut proc, &namespace("shop"));
+        stats.end_tick();
+        assert_eq!(
+            stats.last_tick(),
+            Some(RuleCounts {
+                evaluated: 4,
+                unmatched: 0,
+                matched: BTreeMap::from_iter([(
+                    0,
+                    BTreeMap::from_iter([(ConfigName::new("a"), 4)])
+                )]),
+            })
+        );
+
+        // A trace is dropped as a whole when its root does not match,
+        // even if some of its spans do.
+        insert(&mut proc, &namespace("platform"));
+        stats.end_tick();
+        assert_eq!(stats.last_tick(), Some(RuleCounts::default()));
+        assert!(proc
+            .debug_trace(&traces[0], &namespace("platform"))
+            .iter()
+            .all(|span| span.skipped == Some(SkipReason::IngestFilter)));
+    }
+
+    #[test]
+    fn rule_priorities_and_stop() {
+        let services = |services: &[&str]| {
+            SpanSelector::In(
+                SpanKey::Current(KeyName::ServiceName),
+                services.iter().map(|s| s.to_string()).collect(),
+            )
+        };
+        let rule = |select, config: &str, priority, stop| Rule {
+            select,
+            config: ConfigName::new(config),
+            priority,
+            stop,
+        };
+        let config = TraceConfig {
+            rules: vec![
+                // Evaluated as "b", "a".
+                vec![
+                    rule(services(&["frontend", "backend"]), "a", Some(1), true),
+                    rule(services(&["frontend"]), "b", None, true),
+                ],
+                // Frontend spans are passed on from "c" to "d", but
+                // never reach "e".
+                vec![
+                    rule(services(&["frontend"]), "c", None, false),
+                    rule(services(&["frontend", "backend"]), "d", None, true),
+                    rule(services(&["frontend"]), "e", None, true),
+                ],
+            ],
+            configs: BTreeMap::new(),
+            trace_metrics: TraceMetricsConfig {
+                key: BTreeSet::new(),
+                metrics: BTreeMap::new(),
+            },
+            dedup: None,
+            maintenance: Vec::new(),
+        };
+        assert_eq!(config.equal_rule_priorities(), vec![(1, 0)]);
+
+        let stats = Arc::new(RuleStats::default());
+        let mut processor = TraceProcessor::new(&config).with_rule_stats(stats.clone());
+        insert_sequential(&mut processor, &synthetic_traces(start(), 10));
+        stats.end_tick();
+        assert_eq!(
+            stats.last_tick(),
+            Some(RuleCounts {
+                evaluated: 30,
+                unmatched: 10,
+                matched: BTreeMap::from_iter([
+                    (
+                        0,
+                        BTreeMap::from_iter([
+                            (ConfigName::new("a"), 10),
+                            (ConfigName::new("b"), 10),
+                        ])
+                    ),
+                    (
+                        1,
+                        BTreeMap::from_iter([
+                            (ConfigName::new("c"), 10),
+                            (ConfigName::new("d"), 20),
+                        ])
+                    ),
+                ]),
+            })
+        );
+
+        // Rules without priority and stop keep first-match semantics.
+        let rule = serde_json::from_value::<Rule>(serde_json::json!({
+            "select": { "all": [] },
+            "config": "default"
+        }))
+        .unwrap();
+        assert_eq!(rule.priority, None);
+        assert!(rule.stop);
+    }
+
+    #[test]
+    fn trace_level_aggregates() {
+        let stats = StatsConfig {
+            anomaly_score: None,
+            mean_stddev: Some(MeanStddevConfig::default()),
+            summary: None,
+            histogram: None,
+            ..StatsConfig::default()
+        };
+        let config = TraceConfig {
+            rules: Vec::new(),
+            configs: BTreeMap::new(),
+            trace_metrics: TraceMetricsConfig {
+                key: BTreeSet::from_iter([SpanKey::Current(KeyName::ServiceName)]),
+                metrics: [
+                    ("span_count", TraceMetricSource::SpanCount),
+                    ("duration", TraceMetricSource::Duration),
+                    ("service_count", TraceMetricSource::ServiceCount),
+                ]
+                .into_iter()
+                .map(|(name, source)| {
+                    (
+                        MetricName::new(name),
+                        TraceMetricConfig {
+                            source,
+                            stats: stats.clone(),
+                        },
+                    )
+                })
+                .collect(),
+            },
+            dedup: None,
+            maintenance: Vec::new(),
+        };
+
+        let t = start().timestamp_micros();
+        let traces = vec![
+            // A child outliving the root extends the trace duration.
+            vec![
+                span("01", "1", None, "frontend", "GET", t, 1000),
+                span("01", "2", Some("1"), "backend", "POST", t + 500, 2000),
+                span("01", "3", Some("1"), "backend", "POST", t + 600, 100),
+            ],
+            vec![
+                span("02", "1", None, "frontend", "GET", t + 1000, 4000),
+                span("02", "2", Some("1"), "backend", "POST", t + 1500, 1000),
+                span("02", "3", Some("2"), "database", "SELECT", t + 2000, 100),
+                span("02", "4", Some("2"), "cache", "GET", t + 2000, 100),
+            ],
+            // The root is found regardless of span order.
+            vec![
+                span("03", "2", Some("1"), "worker", "RUN", t + 2000, 100),
+                span("03", "1", None, "scheduler", "TICK", t + 2000, 500),
+            ],
+        ];
+
+        let mut proc = TraceProcessor::new(&config);
+        insert_sequential(&mut proc, &traces);
+
+        let mut values = BTreeMap::new();
+        proc.sample(
+            start() + TimeDelta::minutes(1),
+            |args, config_name, value| {
+                if args.metric_type == "welford" {
+                    let service = args
+                        .group
+                        .get("service_name")
+                        .expect("missing service name key")
+                        .to_string();
+                    values.insert((config_name.to_string(), service, args.metric_name), value);
+                }
+            },
+        );
+
+        let get = |service: &str, metric: &str| {
+            values[&(
+                String::from("trace"),
+                String::from(service),
+                format!("trace_{metric}"),
+            )]
+        };
+
+        assert_eq!(get("frontend", "span_count_count"), 2.0);
+        assert_eq!(get("frontend", "span_count_mean"), 3.5);
+        assert_eq!(get("frontend", "duration_mean"), 3250.0);
+        assert_eq!(get("frontend", "service_count_mean"), 3.0);
+        assert_eq!(get("scheduler", "span_count_mean"), 2.0);
+        assert_eq!(get("scheduler", "duration_mean"), 500.0);
+        assert_eq!(get("scheduler", "service_count_mean"), 2.0);
+        assert!(values.keys().all(|(_, service, _)| service != "worker"));
+    }
+
+    fn baseline_config(stats: StatsConfig) -> TraceConfig {
+        TraceConfig {
+            rules: vec![vec![Rule {
+                select: SpanSelector::All(Vec::new()),
+                config: ConfigName::new("default"),
+                priority: None,
+                stop: true,
+            }]],
+            configs: BTreeMap::from_iter([(
+                ConfigName::new("default"),
+                SpanConfig {
+                    key: BTreeSet::from_iter([
+                        SpanKey::Current(KeyName::ServiceName),
+                        SpanKey::Current(KeyName::OperationName),
+                    ]),
+                    metrics: BTreeMap::from_iter([(
+                        MetricName::new("duration"),
+                        MetricConfig {
+                            source: MetricSource::Duration,
+                            stats: stats.clone(),
+                            enabled: true,
+                        },
+                    )]),
+                    carry_over: BTreeSet::new(),
+                    carry_over_age: default_carry_over_age(),
+                    pushdown: false,
+                    normalize_numbers: false,
+                    annotations: BTreeSet::new(),
+                    idle_after: None,
+                    respect_sampling: false,
+                    max_sampling_weight: default_max_sampling_weight(),
+                    classify: BTreeMap::new(),
+                    pseudonymize: Vec::new(),
+                    late_bins: None,
+                    missing_key: MissingKey::Omit,
+                    enabled: true,
+                },
+            )]),
+            trace_metrics: TraceMetricsConfig {
+                key: BTreeSet::from_iter([SpanKey::Current(KeyName::ServiceName)]),
+                metrics: BTreeMap::from_iter([(
+                    MetricName::new("duration"),
+                    TraceMetricConfig {
+                        source: TraceMetricSource::Duration,
+                        stats,
+                    },
+                )]),
+            },
+            dedup: None,
+            maintenance: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn baselines_roundtrip() {
+        let config = baseline_config(StatsConfig {
+            anomaly_score: Some(AnomalyScoreConfig::default()),
+            mean_stddev: Some(MeanStddevConfig::default()),
+            summary: None,
+            histogram: None,
+            ..StatsConfig::default()
+        });
+        let exported = start() + TimeDelta::minutes(5);
+        let end = exported + TimeDelta::minutes(20);
+
+        let mut source = TraceProcessor::new(&config);
+        insert_sequential(&mut source, &synthetic_traces(start(), 3000));
+        let data = source
+            .snapshot(exported)
+            .export_baselines()
+            .encode()
+            .unwrap();
+
+        let mut target = TraceProcessor::new(&config);
+        let report =
+            target.import_baselines(exported, BaselineBundle::decode(&data, u64::MAX).unwrap());
+        assert_eq!((report.applied, report.skipped), (4, 0));
+
+        let mut incompatible = TraceProcessor::new(&baseline_config(StatsConfig {
+            anomaly_score: None,
+            mean_stddev: None,
+            summary: None,
+            histogram: None,
+            ..StatsConfig::default()
+        }));
+        let report = incompatible
+            .import_baselines(exported, BaselineBundle::decode(&data, u64::MAX).unwrap());
+        assert_eq!((report.applied, report.skipped), (0, 4));
+
+        // Once the immediate windows have been refilled, the target
+        // continues exactly where the source left off.
+        let traces = synthetic_traces(exported, 12000);
+        insert_sequential(&mut source, &traces);
+        insert_sequential(&mut target, &traces);
+
+        let output = |proc: &mut TraceProcessor| {
+            sample(proc, end)
+                .into_iter()
+                .filter(|line| !line.contains("self_monitoring"))
+                .collect::<Vec<_>>()
+        };
+        let expected = output(&mut source);
+        assert!(expected.iter().any(|line| line.contains("_score ")));
+        assert_eq!(output(&mut target), expected);
+    }
+
+    #[test]
+    fn carry_over_on_instance_change() {
+        let instance = SpanKey::Current(KeyName::ProcessTag(String::from("service.instance.id")));
+        let config = |carry_over: BTreeSet<SpanKey>| TraceConfig {
+            rules: vec![vec![Rule {
+                select: SpanSelector::All(Vec::new()),
+                config: ConfigName::new("default"),
+                priority: None,
+                stop: true,
+            }]],
+            configs: BTreeMap::from_iter([(
+                ConfigName::new("default"),
+                SpanConfig {
+                    key: BTreeSet::from_iter([
+                        SpanKey::Current(KeyName::ServiceName),
+                        instance.clone(),
+                    ]),
+                    metrics: BTreeMap::from_iter([(
+                        MetricName::new("duration"),
+                        MetricConfig {
+                            source: MetricSource::Duration,
+                            stats: StatsConfig {
+                                anomaly_score: Some(AnomalyScoreConfig::default()),
+                                mean_stddev: Some(MeanStddevConfig::default()),
+                                summary: None,
+                                histogram: None,
+                                ..StatsConfig::default()
+                            },
+                            enabled: true,
+                        },
+                    )]),
+                    carry_over,
+                    carry_over_age: default_carry_over_age(),
+                    pushdown: false,
+                    normalize_numbers: false,
+                    annotations: BTreeSet::new(),
+                    idle_after: None,
+                    respect_sampling: false,
+                    max_sampling_weight: default_max_sampling_weight(),
+                    classify: BTreeMap::new(),
+                    pseudonymize: Vec::new(),
+                    late_bins: None,
+                    missing_key: MissingKey::Omit,
+                    enabled: true,
+                },
+            )]),
+            trace_metrics: TraceMetricsConfig {
+                key: BTreeSet::new(),
+                metrics: BTreeMap::new(),
+            },
+            dedup: None,
+            maintenance: Vec::new(),
+        };
+
+        // Run long enough to close the first reference bin.
+        let redeployed = start() + TimeDelta::minutes(20);
+        let old = synthetic_traces(start(), 12000);
+        let mut new = synthetic_traces(redeployed, 1);
+        new.iter_mut().flatten().for_each(|span| {
+            span.process
+                .tags
+                .iter_mut()
+                .filter(|tag| tag.key == "service.instance.id")
+                .for_each(|tag| tag.value = TagValue::String(String::from("test-1")));
+        });
+
+        // Reference window counts per (instance, service).
+        let reference_counts = |proc: &mut TraceProcessor, t| {
+            let mut counts = BTreeMap::new();
+            proc.sample(t, |args, _, value| {
+                if args.metric_name == "trace_duration_count"
+                    && args.labels.reference == Some(ReferenceInterval::R7d)
+                {
+                    let label = |key: &SpanKey| {
+                        args.group
+                            .get(&key.label().into_string())
+                            .expect("missing key")
+                            .to_string()
+                    };
+                    counts.insert(
+                        (
+                            label(&instance),
+                            label(&SpanKey::Current(KeyName::ServiceName)),
+                        ),
+                        value,
+                    );
+                }
+            });
+            counts
+        };
+        let count = |counts: &BTreeMap<(String, String), f64>, instance: &str| {
+            counts
+                .get(&(String::from(instance), String::from("frontend")))
+                .copied()
+        };
+
+        let mut carried = TraceProcessor::new(&config(BTreeSet::from_iter([instance.clone()])));
+        insert_sequential(&mut carried, &old);
+        insert_sequential(&mut carried, &new);
+        let counts = reference_counts(&mut carried, redeployed + TimeDelta::minutes(1));
+        let inherited = count(&counts, "test-0").unwrap();
+        assert!(inherited > 0.0);
+        assert_eq!(count(&counts, "test-1"), Some(inherited));
+
+        let mut restarted = TraceProcessor::new(&config(BTreeSet::new()));
+        insert_sequential(&mut restarted, &old);
+        insert_sequential(&mut restarted, &new);
+        let counts = reference_counts(&mut restarted, redeployed + TimeDelta::minutes(1));
+        assert_eq!(count(&counts, "test-1"), Some(0.0));
+
+        // The superseded group is dropped once it is no longer seen.
+        let later = redeployed + TimeDelta::hours(2);
+        assert_eq!(
+            count(&reference_counts(&mut carried, later), "test-0"),
+            None
+        );
+        assert!(count(&reference_counts(&mut restarted, later), "test-0").is_some());
+    }
+
+    #[test]
+    fn stale_markers_for_removed_groups() {
+        let config = baseline_config(StatsConfig {
+            anomaly_score: None,
+            mean_stddev: Some(MeanStddevConfig::default()),
+            summary: None,
+            histogram: None,
+            ..StatsConfig::default()
+        });
+        let mut proc = TraceProcessor::new(&config);
+        insert_batch(&mut proc, &synthetic_traces(start(), 100));
+
+        // Only the frontend is seen after the first traces.
+        let later = start() + TimeDelta::hours(1);
+        insert_batch(
+            &mut proc,
+            &[vec![span(
+                "later",
+                "1",
+                None,
+                "frontend",
+                "GET",
+                later.timestamp_micros(),
+                1000,
+            )]],
+        );
+        let t = later + TimeDelta::minutes(1);
+        let mut metrics = Metrics::new();
+        sample_metrics(&mut proc, t, &mut metrics, None);
+        let removed = metrics
+            .drain()
+            .map(|(labels, _, _)| labels)
+            .filter(|labels| {
+                labels
+                    .get("service_name")
+                    .is_some_and(|service| service != "frontend")
+            })
+            .collect::<BTreeSet<_>>();
+        assert!(!removed.is_empty());
+
+        // The registry is part of the saved state.
+        let mut data = Vec::new();
+        ciborium::into_writer(&proc.save(), &mut data).unwrap();
+        let mut proc =
+            TraceProcessor::load(t, ciborium::from_reader(data.as_slice()).unwrap(), &config);
+
+        let stale = proc.cleanup(later).drain().collect::<Vec<_>>();
+        assert_eq!(stale.len(), removed.len());
+        assert_eq!(
+            stale
+                .iter()
+                .map(|(labels, _, _)| labels.clone())
+                .collect::<BTreeSet<_>>(),
+            removed
+        );
+        assert!(stale
+            .iter()
+            .all(|(_, ts, value)| *ts == t + TimeDelta::milliseconds(1)
+                && value.to_bits() == STALE_NAN));
+        assert!(proc.cleanup(later).is_empty());
+
+        // The remaining series are marked stale on shutdown.
+        let remaining = proc.shutdown();
+        assert!(!remaining.is_empty());
+        assert!(remaining
+            .series()
+            .all(
+                |labels| labels.get("service_name").map(String::as_str) == Some("frontend")
+                    || labels["metric_type"] == "self_monitoring"
+            ));
+        assert!(proc.shutdown().is_empty());
+    }
+
+    #[test]
+    fn expired_groups() {
+        let config = baseline_config(StatsConfig {
+            anomaly_score: None,
+            mean_stddev: Some(MeanStddevConfig::default()),
+            summary: None,
+            histogram: None,
+            ..StatsConfig::default()
+        });
+        let (queue, mut receiver) = test_queue(1000);
+        let mut proc = TraceProcessor::new(&config).with_expiry_queue(Some(queue));
+        insert_batch(&mut proc, &synthetic_traces(start(), 100));
+
+        // Only the frontend is seen after the first traces.
+        let later = start() + TimeDelta::hours(1);
+        insert_batch(
+            &mut proc,
+            &[vec![span(
+                "later",
+                "1",
+                None,
+                "frontend",
+                "GET",
+                later.timestamp_micros(),
+                1000,
+            )]],
+        );
+        proc.cleanup(later);
+
+        let mut expired = BTreeMap::<String, u64>::new();
+        while let Ok(group) = receiver.try_recv() {
+            assert_ne!(
+                group.labels.get("service_name").map(String::as_str),
+                Some("frontend")
+            );
+            *expired.entry(group.config.to_string()).or_default() += 1;
+        }
+        assert!(expired.contains_key("default"));
+        assert!(expired.contains_key("trace"));
+
+        let counts = sample(&mut proc, later)
+            .into_iter()
+            .filter(|line| line.contains(" jad_groups_expired_total "))
+            .map(|line| {
+                let (config_name, _) = line.split_once(' ').unwrap();
+                let (_, bits) = line.rsplit_once(' ').unwrap();
+                (
+                    config_name.to_string(),
+                    f64::from_bits(bits.parse().unwrap()) as u64,
+                )
+            })
+            .collect::<BTreeMap<_, _>>();
+        assert_eq!(counts, expired);
+    }
+
+    /// Sample at `t`, panicking on the metrics of `faulty`. Returns the
+    /// configs that emitted metrics, and the self-monitoring metrics.
+    fn sample_failing(
+        proc: &mut TraceProcessor,
         t: DateTime<Utc>,
-        mut metric: F,
-    ) {
-        self.groups.iter_mut().for_each(|(config_name, proc)| {
-            proc.sample(t, |metric_args, value| {
-                metric(metric_args, config_name, value);
+        faulty: &ConfigName,
+    ) -> (BTreeSet<String>, BTreeMap<(String, String), f64>) {
+        let mut configs = BTreeSet::new();
+        let mut self_monitoring = BTreeMap::new();
+        proc.sample(t, |args, config_name, value| {
+            if args.metric_type == "self_monitoring" {
+                self_monitoring.insert((config_name.to_string(), args.metric_name), value);
+            } else if config_name == faulty {
+                panic!("failed to sample");
+            } else {
+                configs.insert(config_name.to_string());
+            }
+        });
+        (configs, self_monitoring)
+    }
+
+    #[test]
+    fn quarantine_failing_config() {
+        let mut config = baseline_config(StatsConfig {
+            anomaly_score: None,
+            mean_stddev: Some(MeanStddevConfig::default()),
+            summary: None,
+            histogram: None,
+            ..StatsConfig::default()
+        });
+        let faulty = ConfigName::new("faulty");
+        let span_config = config.configs[&ConfigName::new("default")].clone();
+        config.configs.insert(faulty.clone(), span_config);
+        config.rules.push(vec![Rule {
+            select: SpanSelector::All(Vec::new()),
+            config: faulty.clone(),
+            priority: None,
+            stop: true,
+        }]);
+        let mut proc = TraceProcessor::new(&config);
+
+        // The failing config is started over every time, while the
+        // others keep emitting.
+        let interval = |i: u64| start() + TimeDelta::minutes(10 * i as i64);
+        for i in 0..MAX_FAILED_SAMPLES {
+            assert!(!proc.quarantine.is_quarantined(&faulty));
+            insert_batch(&mut proc, &synthetic_traces(interval(i), 3000));
+            let (configs, _) =
+                sample_failing(&mut proc, interval(i) + TimeDelta::minutes(6), &faulty);
+            assert!(configs.contains("default"));
+            assert!(!configs.contains("faulty"));
+        }
+        assert!(proc.quarantine.is_quarantined(&faulty));
+
+        let t = interval(MAX_FAILED_SAMPLES);
+        insert_batch(&mut proc, &synthetic_traces(t, 3000));
+        let (configs, self_monitoring) =
+            sample_failing(&mut proc, t + TimeDelta::minutes(6), &faulty);
+        assert!(configs.contains("default"));
+        let value = |config: &str, metric: &str| {
+            self_monitoring
+                .get(&(config.to_string(), metric.to_string()))
+                .copied()
+        };
+        assert_eq!(
+            value("faulty", "jaeger_anomaly_detection_config_quarantined"),
+            Some(1.0)
+        );
+        assert_eq!(
+            value("faulty", "jaeger_anomaly_detection_config_failures_total"),
+            Some(MAX_FAILED_SAMPLES as f64)
+        );
+        assert_eq!(
+            value("default", "jaeger_anomaly_detection_config_failures_total"),
+            None
+        );
+
+        // A config update lifts the quarantine.
+        let proc = proc.update(t, &config);
+        assert!(!proc.quarantine.is_quarantined(&faulty));
+    }
+
+    #[test]
+    fn request_path_relations() {
+        let name = ConfigName::new("request-path-relations");
+        assert!(!TraceConfig::default().configs.contains_key(&name));
+
+        let config = TraceConfig::default().with_request_path_relations();
+        let mut proc = TraceProcessor::new(&config);
+        insert_sequential(&mut proc, &synthetic_traces(start(), 100));
+
+        // Only the database spans have a grandparent.
+        let key = &config.configs[&name].key;
+        let mut keys = BTreeSet::new();
+        proc.sample(start() + TimeDelta::minutes(1), |args, config_name, _| {
+            if config_name == &name {
+                keys.insert(
+                    key.iter()
+                        .filter_map(|key| {
+                            let label = key.label().into_string();
+                            let value = args.group.get(&label)?.to_string();
+                            Some((label, value))
+                        })
+                        .collect::<Vec<_>>(),
+                );
+            }
+        });
+        assert_eq!(
+            keys,
+            BTreeSet::from([Vec::from([
+                (String::from("service_name"), String::from("database")),
+                (String::from("parent_service_name"), String::from("backend")),
+                (
+                    String::from("grandparent_service_name"),
+                    String::from("frontend")
+                ),
+            ])])
+        );
+    }
+
+    /// The call counts of the frontend in the "default" config, by span
+    /// kind, for traces with a client span of the frontend calling a
+    /// server span of the same operation.
+    fn span_kind_counts(config: &TraceConfig) -> BTreeMap<String, f64> {
+        let mut config = config.clone();
+        config.rules.truncate(1);
+        config
+            .configs
+            .retain(|name, _| name == &ConfigName::new("default"));
+        let traces = (0..10)
+            .map(|i| {
+                let t = (start() + TimeDelta::seconds(i)).timestamp_micros();
+                let trace_id = i.to_string();
+                let kind = |span: &mut Span, kind: &str| {
+                    span.tags.push(Tag {
+                        key: String::from("span.kind"),
+                        value: TagValue::String(String::from(kind)),
+                    })
+                };
+                let mut client = span(&trace_id, "1", None, "frontend", "GET", t, 2000);
+                kind(&mut client, "client");
+                let mut server = span(&trace_id, "2", Some("1"), "frontend", "GET", t, 1000);
+                kind(&mut server, "server");
+                vec![client, server]
+            })
+            .collect::<Vec<_>>();
+        let mut proc = TraceProcessor::new(&config);
+        insert_sequential(&mut proc, &traces);
+
+        let mut counts = BTreeMap::new();
+        proc.sample(
+            start() + TimeDelta::minutes(1),
+            |args, config_name, value| {
+                if config_name == &ConfigName::new("default")
+                    && args.metric_name == "trace_call_rate_total"
+                    && args.metric_type == "source_count"
+                {
+                    let kind = args.group.get("span_kind").unwrap_or("-");
+                    counts.insert(kind.to_string(), value);
+                }
+            },
+        );
+        counts
+    }
+
+    #[test]
+    fn client_and_server_spans_are_split() {
+        assert_eq!(
+            span_kind_counts(&TraceConfig::default()),
+            BTreeMap::from_iter([
+                (String::from("client"), 10.0),
+                (String::from("server"), 10.0)
+            ])
+        );
+        assert_eq!(
+            TraceConfig::default_version(super::DEFAULT_CONFIG_VERSION),
+            Some(TraceConfig::default())
+        );
+        let v1 = TraceConfig::default_version(1).unwrap();
+        assert_eq!(
+            span_kind_counts(&v1),
+            BTreeMap::from_iter([(String::from("-"), 20.0)])
+        );
+        assert_eq!(TraceConfig::default_version(0), None);
+    }
+
+    /// Traces of a frontend calling a backend, sampled with probability
+    /// `param`. Every other backend span is an error.
+    fn sampled_traces(param: &str) -> Vec<Vec<Span>> {
+        (0..20)
+            .map(|i| {
+                let t = (start() + TimeDelta::seconds(i)).timestamp_micros();
+                let trace_id = i.to_string();
+                let mut root = span(&trace_id, "1", None, "frontend", "GET", t, 2000);
+                root.tags.extend([
+                    Tag {
+                        key: String::from("sampler.type"),
+                        value: TagValue::String(String::from("probabilistic")),
+                    },
+                    Tag {
+                        key: String::from("sampler.param"),
+                        value: TagValue::String(String::from(param)),
+                    },
+                ]);
+                let mut child = span(&trace_id, "2", Some("1"), "backend", "GET", t, 1000);
+                if i % 2 == 0 {
+                    child.tags.push(Tag {
+                        key: String::from("exception.message"),
+                        value: TagValue::String(String::from("failed")),
+                    });
+                }
+                vec![root, child]
+            })
+            .collect()
+    }
+
+    /// The call count and the number of error rate values of the
+    /// backend in the default config.
+    fn sampled_counts(respect_sampling: bool, param: &str, batch: bool) -> (f64, f64) {
+        let mut config = TraceConfig::default();
+        config.rules.truncate(1);
+        config
+            .configs
+            .retain(|name, _| name == &ConfigName::new("default"));
+        config.configs.values_mut().for_each(|config| {
+            config.respect_sampling = respect_sampling;
+            config.metrics.retain(|name, _| {
+                name == &MetricName::new("call_rate") || name == &MetricName::new("error_rate")
             });
+        });
+
+        let mut proc = TraceProcessor::new(&config);
+        let traces = sampled_traces(param);
+        if batch {
+            insert_batch(&mut proc, &traces);
+        } else {
+            insert_sequential(&mut proc, &traces);
+        }
+
+        let (mut calls, mut errors) = (0.0, 0.0);
+        proc.sample(
+            start() + TimeDelta::minutes(1),
+            |args, config_name, value| {
+                if config_name != &ConfigName::new("default")
+                    || args.group.get("service_name") != Some("backend")
+                {
+                    return;
+                }
+                match (args.metric_name.as_str(), args.metric_type) {
+                    ("trace_call_rate_total", "source_count") => calls = value,
+                    ("trace_error_rate_count", "welford") => errors = value,
+                    _ => {}
+                }
+            },
+        );
+        (calls, errors)
+    }
+
+    #[test]
+    fn sampled_traces_are_weighted() {
+        assert_eq!(sampled_counts(false, "0.1", false), (20.0, 20.0));
+        assert_eq!(sampled_counts(true, "0.1", false), (200.0, 200.0));
+        assert_eq!(sampled_counts(true, "0.1", true), (200.0, 200.0));
+        // Weights are capped by `max_sampling_weight`.
+        assert_eq!(sampled_counts(true, "0.0001", false), (2000.0, 2000.0));
+        // Invalid probabilities are ignored.
+        assert_eq!(sampled_counts(true, "1.5", false), (20.0, 20.0));
+        assert_eq!(sampled_counts(true, "none", false), (20.0, 20.0));
+    }
+
+    #[test]
+    fn error_reasons() {
+        let mut config = TraceConfig::default().with_error_reasons();
+        config.rules.truncate(1);
+        config
+            .configs
+            .retain(|name, _| name == &ConfigName::new("default"));
+        config.configs.values_mut().for_each(|config| {
+            config
+                .metrics
+                .retain(|name, _| name == &MetricName::new("error_reasons"));
+            match &mut config
+                .metrics
+                .get_mut(&MetricName::new("error_reasons"))
+                .unwrap()
+                .source
+            {
+                MetricSource::RateBy { max_reasons, .. } => *max_reasons = 2,
+                source => panic!("unexpected source: {source:?}"),
+            }
+        });
+
+        let mut proc = TraceProcessor::new(&config);
+        let t = start().timestamp_micros();
+        let traces = [
+            Some("500"),
+            Some("500"),
+            None,
+            Some("503"),
+            Some("404"),
+            Some("200"),
+        ]
+        .into_iter()
+        .enumerate()
+        .map(|(i, status)| {
+            let mut span = span(&i.to_string(), "1", None, "backend", "GET", t, 1000);
+            span.tags.push(match status {
+                Some(status) => Tag {
+                    key: String::from("http.status_code"),
+                    value: TagValue::String(String::from(status)),
+                },
+                None => Tag {
+                    key: String::from("exception.message"),
+                    value: TagValue::String(String::from("failed")),
+                },
+            });
+            vec![span]
         })
+        .collect::<Vec<_>>();
+        insert_sequential(&mut proc, &traces);
+
+        let reasons = |proc: &mut TraceProcessor| {
+            let mut metrics = Metrics::new();
+            sample_metrics(proc, start() + TimeDelta::minutes(1), &mut metrics, None);
+            metrics
+                .drain()
+                .filter(|(labels, _, _)| labels["__name__"] == "trace_error_reasons_total")
+                .map(|(labels, _, value)| (labels["reason"].clone(), value))
+                .collect::<BTreeMap<_, _>>()
+        };
+        let expected = BTreeMap::from_iter([
+            (String::from("500"), 2.0),
+            (String::from("unknown"), 1.0),
+            // The tail beyond `max_reasons` is folded.
+            (String::from("other"), 2.0),
+        ]);
+        assert_eq!(reasons(&mut proc), expected);
+
+        // The counts are part of the saved state.
+        let mut data = Vec::new();
+        ciborium::into_writer(&proc.save(), &mut data).unwrap();
+        let mut proc = TraceProcessor::load(
+            start(),
+            ciborium::from_reader(data.as_slice()).unwrap(),
+            &config,
+        );
+        assert_eq!(reasons(&mut proc), expected);
+    }
+
+    /// The offset series hold the configured offset of every metric,
+    /// with every group or once per config.
+    #[test]
+    fn offset_series() {
+        let traces = synthetic_traces(start(), 300);
+        let end = start() + TimeDelta::minutes(6);
+        for offset_series in [OffsetSeries::Group, OffsetSeries::Config] {
+            let mut config = TraceConfig::default();
+            config.configs.values_mut().for_each(|config| {
+                config.metrics.values_mut().for_each(|metric| {
+                    metric.stats = metric.stats.clone().with_offset_series(offset_series);
+                });
+            });
+            let mut proc = TraceProcessor::new(&config);
+            insert_sequential(&mut proc, &traces);
+            let mut metrics = Metrics::new();
+            sample_metrics(&mut proc, end, &mut metrics, None);
+            let offsets = metrics
+                .drain()
+                .filter(|(labels, _, _)| labels["__name__"].ends_with("_offset"))
+                .collect::<Vec<_>>();
+
+            let mut checked = 0;
+            config
+                .configs
+                .iter()
+                .for_each(|(config_name, span_config)| {
+                    span_config.metrics.iter().for_each(|(name, metric)| {
+                        let Some(anomaly_score) = &metric.stats.anomaly_score else {
+                            return;
+                        };
+                        let series = offsets
+                            .iter()
+                            .filter(|(labels, _, _)| {
+                                labels["config"] == config_name.to_string()
+                                    && labels["__name__"] == format!("trace_{name}_offset")
+                            })
+                            .collect::<Vec<_>>();
+                        assert!(!series.is_empty(), "{config_name}: {name}");
+                        assert!(series
+                            .iter()
+                            .all(|(_, _, value)| *value == anomaly_score.offset()));
+                        assert!(series.iter().all(|(labels, _, _)| {
+                            labels.contains_key("service_name")
+                                == (offset_series == OffsetSeries::Group)
+                        }));
+                        anomaly_score.reference_intervals().for_each(|interval| {
+                            let n = series
+                                .iter()
+                                .filter(|(labels, _, _)| {
+                                    labels["reference"] == interval.to_string()
+                                })
+                                .count();
+                            match offset_series {
+                                OffsetSeries::Group => assert!(n > 0),
+                                OffsetSeries::Config => assert_eq!(n, 1),
+                            }
+                        });
+                        checked += series.len();
+                    });
+                });
+            assert_eq!(checked, offsets.len());
+        }
+    }
+
+    #[test]
+    fn late_spans_correct_written_samples() {
+        let mut config = TraceConfig::default();
+        config
+            .configs
+            .values_mut()
+            .for_each(|config| config.late_bins = Some(2));
+        let trace = |id: &str, t: DateTime<Utc>| {
+            vec![span(
+                id,
+                "1",
+                None,
+                "frontend",
+                "GET",
+                t.timestamp_micros(),
+                1000,
+            )]
+        };
+        let samples = |proc: &mut TraceProcessor, t: DateTime<Utc>| {
+            let mut metrics = Metrics::new();
+            sample_metrics(proc, t, &mut metrics, None);
+            metrics
+                .drain()
+                .filter(|(labels, _, _)| {
+                    labels["metric_type"] != "self_monitoring" && labels["config"] != "trace"
+                })
+                .map(|(labels, t, value)| (labels, t, value.to_bits()))
+                .collect::<Vec<_>>()
+        };
+        let t1 = start() + TimeDelta::minutes(1);
+        let t2 = t1 + TimeDelta::minutes(1);
+        let late = || trace("2", start() + TimeDelta::seconds(10));
+
+        let mut on_time = TraceProcessor::new(&config);
+        insert_sequential(&mut on_time, &[trace("1", start()), late()]);
+        let expected = samples(&mut on_time, t1);
+        assert!(!expected.is_empty());
+
+        // The second trace arrives after the sample at t1 was written.
+        let mut proc = TraceProcessor::new(&config);
+        insert_sequential(&mut proc, &[trace("1", start())]);
+        assert_ne!(samples(&mut proc, t1), expected);
+        insert_sequential(&mut proc, &[late()]);
+        let written = samples(&mut proc, t2);
+        let (corrected, current) = written
+            .into_iter()
+            .partition::<Vec<_>, _>(|(_, t, _)| *t == t1);
+        assert_eq!(corrected, expected);
+        assert!(current.iter().all(|(_, t, _)| *t == t2));
+        assert!(!current.is_empty());
+
+        // Corrections are written once.
+        assert!(samples(&mut proc, t2 + TimeDelta::minutes(1))
+            .iter()
+            .all(|(_, t, _)| *t > t2));
     }
 
-    pub fn cleanup(&mut self, t: DateTime<Utc>) {
-        self.groups.values_mut().for_each(|proc| proc.cleanup(t));
+    /// Compare sequential and parallel insertion with the default
+    /// configs. Run with `cargo test --release -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_batch_insert() {
+        let traces = synthetic_traces(start(), 50_000);
+
+        let mut sequential = TraceProcessor::new(&TraceConfig::default());
+        let t0 = Instant::now();
+        insert_sequential(&mut sequential, &traces);
+        let sequential_time = t0.elapsed();
+
+        let mut parallel = TraceProcessor::new(&TraceConfig::default());
+        let t0 = Instant::now();
+        insert_batch(&mut parallel, &traces);
+        let parallel_time = t0.elapsed();
+
+        println!(
+            "sequential: {sequential_time:?}, parallel: {parallel_time:?}, speedup: {:.2}x",
+            sequential_time.as_secs_f64() / parallel_time.as_secs_f64()
+        );
     }
 }
@@ -0,0 +1,96 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use std::collections::BTreeMap;
+
+use apistos::ApiComponent;
+use serde::Serialize;
+
+use crate::{
+    config::{ConfigName, MetricName},
+    jaeger::Span,
+};
+
+/// How the spans of a trace would be processed with the current
+/// config. The report is produced on a throwaway processor, so
+/// stateful steps (dedup, windowed sources) behave as on a fresh
+/// start.
+#[derive(Serialize, schemars::JsonSchema, ApiComponent, Debug)]
+pub struct TraceDebugReport {
+    pub trace_id: String,
+    pub spans: Vec<SpanDebug>,
+}
+
+#[derive(Serialize, schemars::JsonSchema, PartialEq, Debug)]
+pub struct SpanDebug {
+    pub span_id: String,
+    /// Unset if the span could not be parsed.
+    pub service_name: Option<String>,
+    pub operation_name: Option<String>,
+    /// Why the span is not processed at all, if it is not.
+    pub skipped: Option<SkipReason>,
+    /// The rules matching the span, in evaluation order.
+    pub matches: Vec<RuleMatch>,
+}
+
+#[derive(Serialize, schemars::JsonSchema, PartialEq, Debug)]
+pub struct RuleMatch {
+    /// The index of the rule group.
+    pub rule_group: usize,
+    pub config: ConfigName,
+    /// The selector of the matching rule.
+    pub select: String,
+    /// The labels of the group the span contributes to.
+    pub key: BTreeMap<String, String>,
+    /// Key components absent on the span. These are left out of the
+    /// group key.
+    pub missing_keys: Vec<String>,
+    /// The values extracted per metric. Windowed sources (count)
+    /// produce no values per span.
+    pub values: BTreeMap<MetricName, Vec<f64>>,
+    /// Why the span is not inserted in the config, if it is not.
+    pub skipped: Option<SkipReason>,
+}
+
+#[derive(Serialize, schemars::JsonSchema, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum SkipReason {
+    /// The span document could not be parsed.
+    Parse(String),
+    /// The root span of the trace does not match the ingest filter.
+    IngestFilter,
+    /// No rule matches the span.
+    NoMatchingRule,
+    /// The config is processed by aggregation pushdown.
+    Pushdown,
+    /// The rule refers to a config that does not exist.
+    UnknownConfig,
+    /// The span lacks key components, and the config skips such
+    /// spans.
+    MissingKey,
+}
+
+/// Parse span documents, reporting the ones that fail to parse.
+pub fn parse_spans(docs: Vec<serde_json::Value>) -> (Vec<Span>, Vec<SpanDebug>) {
+    let mut spans = Vec::new();
+    let mut failed = Vec::new();
+    docs.into_iter().for_each(|doc| {
+        let span_id = doc
+            .get("spanID")
+            .and_then(|id| id.as_str())
+            .unwrap_or_default()
+            .to_string();
+        match serde_json::from_value::<Span>(doc) {
+            Ok(span) => spans.push(span),
+            Err(e) => failed.push(SpanDebug {
+                span_id,
+                service_name: None,
+                operation_name: None,
+                skipped: Some(SkipReason::Parse(e.to_string())),
+                matches: Vec::new(),
+            }),
+        }
+    });
+    (spans, failed)
+}
@@ -0,0 +1,414 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use std::{
+    collections::{btree_map::Entry, BTreeMap, BTreeSet},
+    sync::Arc,
+};
+
+use chrono::{DateTime, Utc};
+use ordered_float::NotNan;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::{Ancestors, KeyName, MetricName, SpanKey},
+    jaeger::{Span, TagValue},
+    metrics::GroupLabels,
+};
+
+use super::{
+    anomaly_score::AnomalyScoreConfig,
+    baseline::{BaselineSkip, StatsBaseline},
+    span::{config_offsets, sample_offsets, unshared},
+    stats::{StatsConfig, StatsProcessor, StatsState},
+    trace::{MetricArgs, TraceConfig},
+};
+
+/// Metrics calculated once per trace, grouped by a key evaluated on
+/// the root span.
+#[derive(Serialize, Deserialize, schemars::JsonSchema, PartialEq, Clone, Debug)]
+pub struct TraceMetricsConfig {
+    pub key: BTreeSet<SpanKey>,
+    pub metrics: BTreeMap<MetricName, TraceMetricConfig>,
+}
+
+#[derive(Serialize, Deserialize, schemars::JsonSchema, PartialEq, Clone, Debug)]
+pub struct TraceMetricConfig {
+    pub source: TraceMetricSource,
+    pub stats: StatsConfig,
+}
+
+#[derive(Serialize, Deserialize, schemars::JsonSchema, PartialEq, Eq, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum TraceMetricSource {
+    /// The number of spans in the trace.
+    SpanCount,
+    /// The end-to-end duration of the trace (max end - min start
+    /// over all spans), in microseconds.
+    Duration,
+    /// The number of distinct services in the trace.
+    ServiceCount,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TraceLevelState {
+    groups: BTreeMap<BTreeMap<SpanKey, TagValue>, TraceMetricsState>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TraceMetricsState {
+    last_seen: DateTime<Utc>,
+    metrics: BTreeMap<MetricName, StatsState>,
+}
+
+/// The groups of the trace-level metrics, shared with the snapshots
+/// taken since they last changed.
+type Groups = BTreeMap<BTreeMap<SpanKey, TagValue>, Arc<TraceMetricsProcessor>>;
+
+pub struct TraceLevelProcessor {
+    config: TraceMetricsConfig,
+    groups: Arc<Groups>,
+}
+
+/// A frozen view of the trace-level groups.
+#[derive(Clone)]
+pub struct TraceLevelSnapshot {
+    groups: Arc<Groups>,
+    /// The anomaly score configs whose offset is emitted once per
+    /// config, by metric.
+    offsets: Vec<(MetricName, AnomalyScoreConfig)>,
+}
+
+#[derive(Clone)]
+struct TraceMetricsProcessor {
+    labels: GroupLabels,
+    last_seen: DateTime<Utc>,
+    metrics: BTreeMap<MetricName, StatsProcessor>,
+}
+
+impl Default for TraceMetricsConfig {
+    fn default() -> Self {
+        Self {
+            key: BTreeSet::from_iter([
+                SpanKey::Current(KeyName::ServiceName),
+                SpanKey::Current(KeyName::OperationName),
+            ]),
+            metrics: BTreeMap::from_iter([
+                (
+                    MetricName::new("span_count"),
+                    TraceMetricConfig {
+                        source: TraceMetricSource::SpanCount,
+                        stats: StatsConfig::default_with_offset(NotNan::new(1.0).unwrap()),
+                    },
+                ),
+                (
+                    MetricName::new("duration"),
+                    TraceMetricConfig {
+                        source: TraceMetricSource::Duration,
+                        stats: StatsConfig::default_with_offset(NotNan::new(1000.0).unwrap()),
+                    },
+                ),
+                (
+                    MetricName::new("service_count"),
+                    TraceMetricConfig {
+                        source: TraceMetricSource::ServiceCount,
+                        stats: StatsConfig::default_with_offset(NotNan::new(1.0).unwrap()),
+                    },
+                ),
+            ]),
+        }
+    }
+}
+
+impl TraceMetricSource {
+    fn value(&self, trace: &[Span]) -> Option<f64> {
+        match self {
+            TraceMetricSource::SpanCount => Some(trace.len() as f64),
+            TraceMetricSource::Duration => {
+                let start = trace.iter().map(|span| span.start_time).min()?;
+                let end = trace
+                    .iter()
+                    .map(|span| span.start_time + span.duration)
+                    .max()?;
+                Some((end - start) as f64)
+            }
+            TraceMetricSource::ServiceCount => Some(
+                trace
+                    .iter()
+                    .map(|span| &span.process.service_name)
+                    .collect::<BTreeSet<_>>()
+                    .len() as f64,
+            ),
+        }
+    }
+}
+
+impl TraceLevelProcessor {
+    pub fn new(config: &TraceMetricsConfig) -> Self {
+        Self {
+            config: config.clone(),
+            groups: Arc::default(),
+        }
+    }
+
+    pub fn update(self, t: DateTime<Utc>, config: &TraceMetricsConfig) -> Self {
+        Self {
+            config: config.clone(),
+            groups: if self.config.key == config.key {
+                let groups = unshared(self.groups)
+                    .into_iter()
+                    .map(|(key, group)| {
+                        let mut group = unshared(group);
+                        group.metrics = config
+                            .metrics
+                            .iter()
+                            .map(|(name, config)| {
+                                let proc = match (
+                                    group.metrics.remove(name),
+                                    self.config.metrics.get(name),
+                                ) {
+                                    (Some(proc), Some(prev)) if prev.source == config.source => {
+                                        proc.update(t, &config.stats)
+                                    }
+                                    _ => StatsProcessor::new(t, &config.stats),
+                                };
+                                (name.clone(), proc)
+                            })
+                            .collect();
+                        (key, Arc::new(group))
+                    })
+                    .collect();
+                Arc::new(groups)
+            } else {
+                Arc::default()
+            },
+        }
+    }
+
+    pub fn load(t: DateTime<Utc>, state: TraceLevelState, config: &TraceMetricsConfig) -> Self {
+        Self {
+            config: config.clone(),
+            groups: Arc::new(
+                state
+                    .groups
+                    .into_iter()
+                    .map(|(key, mut group)| {
+                        let metrics = config
+                            .metrics
+                            .iter()
+                            .map(|(name, config)| {
+                                let proc = group.metrics.remove(name).map_or_else(
+                                    || StatsProcessor::new(t, &config.stats),
+                                    |state| StatsProcessor::load(t, state, &config.stats),
+                                );
+                                (name.clone(), proc)
+                            })
+                            .collect();
+                        let labels = TraceMetricsProcessor::labels(&key);
+                        (
+                            key,
+                            Arc::new(TraceMetricsProcessor {
+                                labels,
+                                last_seen: group.last_seen,
+                                metrics,
+                            }),
+                        )
+                    })
+                    .collect(),
+            ),
+        }
+    }
+
+    pub fn save(&self) -> TraceLevelState {
+        TraceLevelState {
+            groups: self
+                .groups
+                .iter()
+                .map(|(key, group)| {
+                    (
+                        key.clone(),
+                        TraceMetricsState {
+                            last_seen: group.last_seen,
+                            metrics: group
+                                .metrics
+                                .iter()
+                                .map(|(name, proc)| (name.clone(), proc.save()))
+                                .collect(),
+                        },
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    /// Insert a complete trace. The key is evaluated on the root span.
+    pub fn insert(&mut self, t: DateTime<Utc>, root: &Span, trace: &[Span]) {
+        let key = self
+            .config
+            .key
+            .iter()
+            .filter_map(|key| Some((key.clone(), key.get(root, Ancestors::default())?.to_owned())))
+            .collect();
+        let config = &self.config;
+        let group = match Arc::make_mut(&mut self.groups).entry(key) {
+            Entry::Occupied(entry) => Arc::make_mut(entry.into_mut()),
+            Entry::Vacant(entry) => {
+                let group = TraceMetricsProcessor::new(t, config, entry.key());
+                Arc::make_mut(entry.insert(Arc::new(group)))
+            }
+        };
+        group.last_seen = group.last_seen.max(t);
+        group.metrics.iter_mut().for_each(|(name, proc)| {
+            if let Some(value) = config
+                .metrics
+                .get(name)
+                .and_then(|config| config.source.value(trace))
+            {
+                proc.insert(t, value);
+            }
+        });
+    }
+
+    /// Apply a baseline to a group, creating the group if needed.
+    pub fn import_baseline(
+        &mut self,
+        t: DateTime<Utc>,
+        key: BTreeMap<SpanKey, TagValue>,
+        metric: &MetricName,
+        baseline: StatsBaseline,
+    ) -> Result<(), BaselineSkip> {
+        if !key.keys().all(|name| self.config.key.contains(name)) {
+            return Err(BaselineSkip::IncompatibleKey);
+        }
+        match Arc::make_mut(&mut self.groups)
+            .get_mut(&key)
+            .map(Arc::make_mut)
+        {
+            Some(group) => group
+                .metrics
+                .get_mut(metric)
+                .ok_or(BaselineSkip::UnknownMetric)?
+                .import_baseline(t, baseline),
+            None => {
+                let mut group = TraceMetricsProcessor::new(t, &self.config, &key);
+                group
+                    .metrics
+                    .get_mut(metric)
+                    .ok_or(BaselineSkip::UnknownMetric)?
+                    .import_baseline(t, baseline)?;
+                Arc::make_mut(&mut self.groups).insert(key, Arc::new(group));
+                Ok(())
+            }
+        }
+    }
+
+    /// A frozen view of the groups. Groups are copied by the processor
+    /// when they change while shared with a snapshot.
+    pub fn snapshot(&self) -> TraceLevelSnapshot {
+        TraceLevelSnapshot {
+            groups: self.groups.clone(),
+            offsets: config_offsets(
+                self.config
+                    .metrics
+                    .iter()
+                    .map(|(name, config)| (name, &config.stats)),
+            ),
+        }
+    }
+
+    /// The components of the group keys.
+    pub fn key(&self) -> &BTreeSet<SpanKey> {
+        &self.config.key
+    }
+
+    /// Remove the groups not seen since `t`. Returns the keys of the
+    /// removed groups.
+    pub fn cleanup(&mut self, t: DateTime<Utc>) -> Vec<BTreeMap<SpanKey, TagValue>> {
+        let removed = self
+            .groups
+            .iter()
+            .filter(|(_, group)| group.last_seen < t)
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        if !removed.is_empty() {
+            Arc::make_mut(&mut self.groups).retain(|_, group| group.last_seen >= t);
+        }
+        removed
+    }
+}
+
+impl TraceLevelSnapshot {
+    /// Emit metrics for all groups. Returns the number of regressed
+    /// windows, which were skipped.
+    pub fn sample<F: FnMut(MetricArgs<'_>, f64)>(&self, t: DateTime<Utc>, mut metric: F) -> u64 {
+        let mut invalid = 0;
+        self.groups.values().for_each(|group| {
+            group.metrics.iter().for_each(|(name, proc)| {
+                invalid += proc.sample(
+                    t,
+                    None,
+                    |super::metric::MetricArgs {
+                         metric_suffix,
+                         metric_type,
+                         labels,
+                     },
+                     value| {
+                        let name = metric_suffix
+                            .map_or_else(|| name.to_string(), |suffix| format!("{name}_{suffix}"));
+                        metric(
+                            MetricArgs {
+                                metric_name: format!("trace_{name}"),
+                                metric_type,
+                                labels,
+                                group: &group.labels,
+                            },
+                            value,
+                        )
+                    },
+                );
+            });
+        });
+        sample_offsets(&self.offsets, metric);
+        invalid
+    }
+
+    /// The learned baselines for all groups and metrics.
+    pub fn baselines(
+        &self,
+    ) -> impl Iterator<Item = (&BTreeMap<SpanKey, TagValue>, &MetricName, StatsBaseline)> {
+        self.groups.iter().flat_map(|(key, group)| {
+            group
+                .metrics
+                .iter()
+                .filter_map(move |(name, proc)| Some((key, name, proc.baseline()?)))
+        })
+    }
+
+    /// The keys of all groups.
+    pub fn group_keys(&self) -> impl Iterator<Item = &BTreeMap<SpanKey, TagValue>> {
+        self.groups.keys()
+    }
+}
+
+impl TraceMetricsProcessor {
+    fn new(
+        t: DateTime<Utc>,
+        config: &TraceMetricsConfig,
+        key: &BTreeMap<SpanKey, TagValue>,
+    ) -> Self {
+        Self {
+            labels: Self::labels(key),
+            last_seen: t,
+            metrics: config
+                .metrics
+                .iter()
+                .map(|(name, config)| (name.clone(), StatsProcessor::new(t, &config.stats)))
+                .collect(),
+        }
+    }
+
+    fn labels(key: &BTreeMap<SpanKey, TagValue>) -> GroupLabels {
+        GroupLabels::new(&TraceConfig::trace_metrics_config_name(), key)
+    }
+}
@@ -0,0 +1,116 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use std::{collections::BTreeMap, sync::Mutex};
+
+use super::histogram::{HistogramConfig, HistogramProcessor};
+
+/// Bucket bounds of the samples-per-request histogram.
+const SAMPLES_PER_REQUEST_BOUNDS: [f64; 11] = [
+    10.0, 100.0, 500.0, 1000.0, 2000.0, 5000.0, 10000.0, 20000.0, 50000.0, 100000.0, 200000.0,
+];
+
+/// Collects the batching statistics of the metrics writer during a
+/// single tick. Shared by reference between the write lanes.
+pub struct WriteRecorder(Mutex<WriteCounts>);
+
+struct WriteCounts {
+    samples_per_request: HistogramProcessor,
+    requests: u64,
+    samples: u64,
+    max_buffered: usize,
+}
+
+/// Batching statistics of the metrics writer for a single tick, to
+/// tell whether `metrics_per_request` and the write concurrency limit
+/// the throughput.
+#[derive(PartialEq, Clone, Debug)]
+pub struct WriteReport {
+    /// The number of requests written.
+    pub requests: u64,
+    /// The number of requests with at most `le` samples.
+    pub samples_per_request: BTreeMap<String, u64>,
+    /// The largest number of samples buffered for writing.
+    pub max_buffered: usize,
+    /// The mean number of samples per request, as a fraction of
+    /// `metrics_per_request`. Zero without requests.
+    pub utilization: f64,
+}
+
+impl Default for WriteRecorder {
+    fn default() -> Self {
+        Self(Mutex::new(WriteCounts {
+            samples_per_request: HistogramProcessor::new(&HistogramConfig {
+                bounds: SAMPLES_PER_REQUEST_BOUNDS.to_vec(),
+            }),
+            requests: 0,
+            samples: 0,
+            max_buffered: 0,
+        }))
+    }
+}
+
+impl WriteRecorder {
+    /// Record a request of `samples` samples.
+    pub fn record_request(&self, samples: usize) {
+        let mut counts = self.0.lock().unwrap();
+        counts.requests += 1;
+        counts.samples += samples as u64;
+        counts.samples_per_request.insert(samples as f64);
+    }
+
+    /// Record the size of the buffer handed to the writer.
+    pub fn record_buffer(&self, samples: usize) {
+        let mut counts = self.0.lock().unwrap();
+        counts.max_buffered = counts.max_buffered.max(samples);
+    }
+
+    /// Finish the tick.
+    pub fn finish(self, metrics_per_request: usize) -> WriteReport {
+        let counts = self.0.into_inner().unwrap();
+        let mut samples_per_request = BTreeMap::new();
+        counts.samples_per_request.sample(|args, n| {
+            if let Some(le) = args.labels.le {
+                samples_per_request.insert(le, n as u64);
+            }
+        });
+        WriteReport {
+            requests: counts.requests,
+            samples_per_request,
+            max_buffered: counts.max_buffered,
+            utilization: match counts.requests {
+                0 => 0.0,
+                n => counts.samples as f64 / n as f64 / metrics_per_request.max(1) as f64,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::WriteRecorder;
+
+    #[test]
+    fn write_report() {
+        let recorder = WriteRecorder::default();
+        [10000, 10000, 5000]
+            .into_iter()
+            .for_each(|n| recorder.record_request(n));
+        [30000, 25000, 12000]
+            .into_iter()
+            .for_each(|n| recorder.record_buffer(n));
+
+        let report = recorder.finish(10000);
+        assert_eq!(report.requests, 3);
+        assert_eq!(report.samples_per_request["2000"], 0);
+        assert_eq!(report.samples_per_request["5000"], 1);
+        assert_eq!(report.samples_per_request["10000"], 3);
+        assert_eq!(report.max_buffered, 30000);
+        assert!((report.utilization - 25000.0 / 30000.0).abs() < 1e-12);
+
+        let report = WriteRecorder::default().finish(10000);
+        assert_eq!(report.requests, 0);
+        assert_eq!(report.utilization, 0.0);
+    }
+}
@@ -2,7 +2,7 @@
  * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
  ******************************************************************************/
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 
 use apistos::ApiComponent;
 use prometheus_core::{LabelName, MetricName};
@@ -14,205 +14,147 @@ use schemars::JsonSchema;
 use serde::{ser::SerializeMap, Serialize};
 
 use crate::{
-    config::Config,
-    processor::{mean_stddev::MeanStddevAlgorithm, source::MetricSource},
+    config::{self, Config, ConfigName, SpanKey},
+    processor::{
+        mean_stddev::MeanStddevAlgorithm, source::MetricSource, stats::StatsConfig,
+        trace::TraceConfig,
+    },
 };
 
 pub fn get_prom_schema(config: &Config) -> Module {
+    let trace_metrics_name = TraceConfig::trace_metrics_config_name();
     let items = std::iter::once((
         ItemName::new("root"),
         Item {
             items: config
                 .trace
                 .configs
-                .keys()
+                .iter()
+                .filter(|(_, config)| config.enabled)
+                .map(|(name, _)| name)
+                .chain(
+                    (!config.trace.trace_metrics.metrics.is_empty()).then_some(&trace_metrics_name),
+                )
                 .map(|name| ItemRef::new(None, ItemName::new(name.to_string())))
+                .chain(std::iter::once(ItemRef::new(
+                    None,
+                    ItemName::new(SELF_MONITORING),
+                )))
                 .collect(),
             ..Default::default()
         },
     ))
-    .chain(config.trace.configs.iter().map(|(name, config)| {
+    // Paused configs and metrics (see `SpanConfig::enabled`) write no
+    // series.
+    .chain(
+        config
+            .trace
+            .configs
+            .iter()
+            .filter(|(_, config)| config.enabled)
+            .map(|(name, config)| {
+                (
+                    ItemName::new(name.to_string()),
+                    Item {
+                        query: config_query(name, &config.key, &config.annotations),
+                        keys: config_keys(&config.key).collect(),
+                        // items: config
+                        //     .metrics
+                        //     .keys()
+                        //     .map(|metric| ItemRef::new(None, ItemName::new(format!("{name}-{metric}"))))
+                        //     .collect(),
+                        metrics: {
+                            let mut metrics = BTreeMap::new();
+                            config
+                                .metrics
+                                .iter()
+                                .filter(|(_, metric)| config.is_emitted(metric))
+                                .for_each(|(name, config)| {
+                                    match &config.source {
+                                        MetricSource::Count { .. } | MetricSource::Rate { .. } => {
+                                            metrics.insert(
+                                                MetricName::new(format!("trace_{name}_total"))
+                                                    .unwrap(),
+                                                Metric::Scalar(Scalar {
+                                                    r#type: Some(ScalarType::Counter),
+                                                    query: MetricSelector(
+                                                        std::iter::once((
+                                                            LabelName::new("metric_type").unwrap(),
+                                                            LabelSelector::Eq(String::from(
+                                                                "source_count",
+                                                            )),
+                                                        ))
+                                                        .collect(),
+                                                    ),
+                                                    labels: MetricSelector::new(),
+                                                    unit: None,
+                                                }),
+                                            );
+                                        }
+                                        MetricSource::RateBy { .. } => {
+                                            metrics.insert(
+                                                MetricName::new(format!("trace_{name}_total"))
+                                                    .unwrap(),
+                                                Metric::Scalar(Scalar {
+                                                    r#type: Some(ScalarType::Counter),
+                                                    query: MetricSelector(
+                                                        std::iter::once((
+                                                            LabelName::new("metric_type").unwrap(),
+                                                            LabelSelector::Eq(String::from(
+                                                                "source_count",
+                                                            )),
+                                                        ))
+                                                        .collect(),
+                                                    ),
+                                                    labels: MetricSelector(
+                                                        std::iter::once((
+                                                            LabelName::new("reason").unwrap(),
+                                                            LabelSelector::Set,
+                                                        ))
+                                                        .collect(),
+                                                    ),
+                                                    unit: None,
+                                                }),
+                                            );
+                                        }
+                                        _ => {}
+                                    }
+                                    insert_stats_metrics(&mut metrics, name, &config.stats);
+                                });
+                            metrics
+                        },
+                        ..Default::default()
+                    },
+                )
+                //     .chain(config.metrics.iter().map(move |(metric, config)| {
+                //         (
+                //             ItemName::new(format!("{name}-{metric}")),
+                //             Item {
+                //                 ..Default::default()
+                //             },
+                //         )
+                //     }))
+            }),
+    )
+    .chain((!config.trace.trace_metrics.metrics.is_empty()).then(|| {
+        let config = &config.trace.trace_metrics;
         (
-            ItemName::new(name.to_string()),
+            ItemName::new(trace_metrics_name.to_string()),
             Item {
-                query: MetricSelector(
-                    std::iter::once((
-                        LabelName::new("config").unwrap(),
-                        LabelSelector::Eq(name.to_string()),
-                    ))
-                    .chain(config.key.iter().map(|key| {
-                        (
-                            key.label(),
-                            if key.is_required() {
-                                LabelSelector::Set
-                            } else {
-                                LabelSelector::Opt
-                            },
-                        )
-                    }))
-                    .collect(),
-                ),
-                keys: std::iter::once(LabelName::new("config").unwrap())
-                    .chain(config.key.iter().map(|key| key.label()))
-                    .collect(),
-                // items: config
-                //     .metrics
-                //     .keys()
-                //     .map(|metric| ItemRef::new(None, ItemName::new(format!("{name}-{metric}"))))
-                //     .collect(),
+                query: config_query(&trace_metrics_name, &config.key, &BTreeSet::new()),
+                keys: config_keys(&config.key).collect(),
                 metrics: {
                     let mut metrics = BTreeMap::new();
                     config.metrics.iter().for_each(|(name, config)| {
-                        match &config.source {
-                            MetricSource::Count { .. } | MetricSource::Rate { .. } => {
-                                metrics.insert(
-                                    MetricName::new(format!("trace_{name}_total")).unwrap(),
-                                    Metric::Scalar(Scalar {
-                                        r#type: Some(ScalarType::Counter),
-                                        query: MetricSelector(
-                                            std::iter::once((
-                                                LabelName::new("metric_type").unwrap(),
-                                                LabelSelector::Eq(String::from("source_count")),
-                                            ))
-                                            .collect(),
-                                        ),
-                                        labels: MetricSelector::new(),
-                                        unit: None,
-                                    }),
-                                );
-                            }
-                            _ => {}
-                        }
-                        if let Some(config) = &config.stats.mean_stddev {
-                            match &config.algorithm {
-                                MeanStddevAlgorithm::CountSum => {
-                                    metrics.insert(
-                                        MetricName::new(format!("trace_{name}_count")).unwrap(),
-                                        Metric::Scalar(Scalar {
-                                            r#type: Some(ScalarType::Counter),
-                                            query: MetricSelector(
-                                                std::iter::once((
-                                                    LabelName::new("metric_type").unwrap(),
-                                                    LabelSelector::Eq(String::from("count_sum")),
-                                                ))
-                                                .collect(),
-                                            ),
-                                            labels: MetricSelector::new(),
-                                            unit: None,
-                                        }),
-                                    );
-                                    metrics.insert(
-                                        MetricName::new(format!("trace_{name}_sum")).unwrap(),
-                                        Metric::Scalar(Scalar {
-                                            r#type: Some(ScalarType::Gauge),
-                                            query: MetricSelector(
-                                                std::iter::once((
-                                                    LabelName::new("metric_type").unwrap(),
-                                                    LabelSelector::Eq(String::from("count_sum")),
-                                                ))
-                                                .collect(),
-                                            ),
-                                            labels: MetricSelector::new(),
-                                            unit: None,
-                                        }),
-                                    );
-                                }
-                                MeanStddevAlgorithm::Welford => {
-                                    metrics.insert(
-                                        MetricName::new(format!("trace_{name}_count")).unwrap(),
-                                        Metric::Scalar(Scalar {
-                                            r#type: Some(ScalarType::Counter),
-                                            query: MetricSelector(
-                                                std::iter::once((
-                                                    LabelName::new("metric_type").unwrap(),
-                                                    LabelSelector::Eq(String::from("welford")),
-                                                ))
-                                                .collect(),
-                                            ),
-                                            labels: MetricSelector::new(),
-                                            unit: None,
-                                        }),
-                                    );
-                                    metrics.insert(
-                                        MetricName::new(format!("trace_{name}_mean")).unwrap(),
-                                        Metric::Scalar(Scalar {
-                                            r#type: Some(ScalarType::Gauge),
-                                            query: MetricSelector(
-                                                std::iter::once((
-                                                    LabelName::new("metric_type").unwrap(),
-                                                    LabelSelector::Eq(String::from("welford")),
-                                                ))
-                                                .collect(),
-                                            ),
-                                            labels: MetricSelector::new(),
-                                            unit: None,
-                                        }),
-                                    );
-                                    metrics.insert(
-                                        MetricName::new(format!("trace_{name}_m2")).unwrap(),
-                                        Metric::Scalar(Scalar {
-                                            r#type: Some(ScalarType::Gauge),
-                                            query: MetricSelector(
-                                                std::iter::once((
-                                                    LabelName::new("metric_type").unwrap(),
-                                                    LabelSelector::Eq(String::from("welford")),
-                                                ))
-                                                .collect(),
-                                            ),
-                                            labels: MetricSelector::new(),
-                                            unit: None,
-                                        }),
-                                    );
-                                }
-                            }
-                        }
-                        if config.stats.summary.is_some() {
-                            metrics.insert(
-                                MetricName::new(format!("trace_{name}")).unwrap(),
-                                Metric::Summary(Summary {
-                                    query: MetricSelector(
-                                        std::iter::once((
-                                            LabelName::new("metric_type").unwrap(),
-                                            LabelSelector::Eq(String::from("summary")),
-                                        ))
-                                        .collect(),
-                                    ),
-                                    labels: MetricSelector::new(),
-                                    unit: None,
-                                }),
-                            );
-                        }
-                        if config.stats.histogram.is_some() {
-                            metrics.insert(
-                                MetricName::new(format!("trace_{name}")).unwrap(),
-                                Metric::Histogram(Histogram {
-                                    query: MetricSelector(
-                                        std::iter::once((
-                                            LabelName::new("metric_type").unwrap(),
-                                            LabelSelector::Eq(String::from("histogram")),
-                                        ))
-                                        .collect(),
-                                    ),
-                                    labels: MetricSelector::new(),
-                                    unit: None,
-                                }),
-                            );
-                        }
+                        insert_stats_metrics(&mut metrics, name, &config.stats);
                     });
                     metrics
                 },
                 ..Default::default()
             },
         )
-        //     .chain(config.metrics.iter().map(move |(metric, config)| {
-        //         (
-        //             ItemName::new(format!("{name}-{metric}")),
-        //             Item {
-        //                 ..Default::default()
-        //             },
-        //         )
-        //     }))
     }))
+    .chain(std::iter::once(self_monitoring_item()))
     .collect();
 
     Module {
@@ -224,6 +166,265 @@ pub fn get_prom_schema(config: &Config) -> Module {
     //PromSchema(Singleton(ModuleName::new("jaeger-stats"), schema))
 }
 
+const SELF_MONITORING: &str = "self_monitoring";
+
+/// The engine's own series, identified by the instance id.
+fn self_monitoring_item() -> (ItemName, Item) {
+    let query = || {
+        MetricSelector(
+            std::iter::once((
+                LabelName::new("metric_type").unwrap(),
+                LabelSelector::Eq(String::from(SELF_MONITORING)),
+            ))
+            .collect(),
+        )
+    };
+    (
+        ItemName::new(SELF_MONITORING),
+        Item {
+            query: MetricSelector(
+                std::iter::once((LabelName::new("instance_id").unwrap(), LabelSelector::Set))
+                    .collect(),
+            ),
+            keys: std::iter::once(LabelName::new("instance_id").unwrap()).collect(),
+            metrics: [
+                "jaeger_anomaly_detection_heartbeat",
+                "jaeger_anomaly_detection_last_processed_timestamp_seconds",
+            ]
+            .into_iter()
+            .map(|name| {
+                (
+                    MetricName::new(name.to_string()).unwrap(),
+                    Metric::Scalar(Scalar {
+                        r#type: Some(ScalarType::Gauge),
+                        query: query(),
+                        labels: MetricSelector::new(),
+                        unit: None,
+                    }),
+                )
+            })
+            .collect(),
+            ..Default::default()
+        },
+    )
+}
+
+/// The selector for the series of a config. Annotation labels are
+/// optional: they are only set once a span carrying them was seen.
+fn config_query(
+    name: &ConfigName,
+    key: &BTreeSet<SpanKey>,
+    annotations: &BTreeSet<SpanKey>,
+) -> MetricSelector {
+    MetricSelector(
+        std::iter::once((
+            LabelName::new("config").unwrap(),
+            LabelSelector::Eq(name.to_string()),
+        ))
+        .chain(key.iter().map(|key| {
+            (
+                key.label(),
+                if key.is_required() {
+                    LabelSelector::Set
+                } else {
+                    LabelSelector::Opt
+                },
+            )
+        }))
+        .chain(
+            annotations
+                .iter()
+                .map(|key| (key.label(), LabelSelector::Opt)),
+        )
+        .collect(),
+    )
+}
+
+fn config_keys(key: &BTreeSet<SpanKey>) -> impl Iterator<Item = LabelName> + '_ {
+    std::iter::once(LabelName::new("config").unwrap()).chain(key.iter().map(|key| key.label()))
+}
+
+fn insert_stats_metrics(
+    metrics: &mut BTreeMap<MetricName, Metric>,
+    name: &config::MetricName,
+    stats: &StatsConfig,
+) {
+    if let Some(config) = &stats.mean_stddev {
+        match &config.algorithm {
+            MeanStddevAlgorithm::CountSum => {
+                metrics.insert(
+                    MetricName::new(format!("trace_{name}_count")).unwrap(),
+                    Metric::Scalar(Scalar {
+                        r#type: Some(ScalarType::Counter),
+                        query: MetricSelector(
+                            std::iter::once((
+                                LabelName::new("metric_type").unwrap(),
+                                LabelSelector::Eq(String::from("count_sum")),
+                            ))
+                            .collect(),
+                        ),
+                        labels: MetricSelector::new(),
+                        unit: None,
+                    }),
+                );
+                metrics.insert(
+                    MetricName::new(format!("trace_{name}_sum")).unwrap(),
+                    Metric::Scalar(Scalar {
+                        r#type: Some(ScalarType::Gauge),
+                        query: MetricSelector(
+                            std::iter::once((
+                                LabelName::new("metric_type").unwrap(),
+                                LabelSelector::Eq(String::from("count_sum")),
+                            ))
+                            .collect(),
+                        ),
+                        labels: MetricSelector::new(),
+                        unit: None,
+                    }),
+                );
+            }
+            MeanStddevAlgorithm::Welford => {
+                metrics.insert(
+                    MetricName::new(format!("trace_{name}_count")).unwrap(),
+                    Metric::Scalar(Scalar {
+                        r#type: Some(ScalarType::Counter),
+                        query: MetricSelector(
+                            std::iter::once((
+                                LabelName::new("metric_type").unwrap(),
+                                LabelSelector::Eq(String::from("welford")),
+                            ))
+                            .collect(),
+                        ),
+                        labels: MetricSelector::new(),
+                        unit: None,
+                    }),
+                );
+                metrics.insert(
+                    MetricName::new(format!("trace_{name}_mean")).unwrap(),
+                    Metric::Scalar(Scalar {
+                        r#type: Some(ScalarType::Gauge),
+                        query: MetricSelector(
+                            std::iter::once((
+                                LabelName::new("metric_type").unwrap(),
+                                LabelSelector::Eq(String::from("welford")),
+                            ))
+                            .collect(),
+                        ),
+                        labels: MetricSelector::new(),
+                        unit: None,
+                    }),
+                );
+                metrics.insert(
+                    MetricName::new(format!("trace_{name}_m2")).unwrap(),
+                    Metric::Scalar(Scalar {
+                        r#type: Some(ScalarType::Gauge),
+                        query: MetricSelector(
+                            std::iter::once((
+                                LabelName::new("metric_type").unwrap(),
+                                LabelSelector::Eq(String::from("welford")),
+                            ))
+                            .collect(),
+                        ),
+                        labels: MetricSelector::new(),
+                        unit: None,
+                    }),
+                );
+            }
+        }
+    }
+    if stats.anomaly_score.is_some() {
+        metrics.insert(
+            MetricName::new(format!("trace_{name}_sufficiency")).unwrap(),
+            Metric::Scalar(Scalar {
+                r#type: Some(ScalarType::Gauge),
+                query: MetricSelector(
+                    std::iter::once((
+                        LabelName::new("metric_type").unwrap(),
+                        LabelSelector::Eq(String::from("anomaly_score")),
+                    ))
+                    .collect(),
+                ),
+                labels: MetricSelector::new(),
+                unit: None,
+            }),
+        );
+    }
+    if stats
+        .anomaly_score
+        .as_ref()
+        .is_some_and(|config| config.offset_series().is_some())
+    {
+        metrics.insert(
+            MetricName::new(format!("trace_{name}_offset")).unwrap(),
+            Metric::Scalar(Scalar {
+                r#type: Some(ScalarType::Gauge),
+                query: MetricSelector(
+                    std::iter::once((
+                        LabelName::new("metric_type").unwrap(),
+                        LabelSelector::Eq(String::from("anomaly_score")),
+                    ))
+                    .collect(),
+                ),
+                labels: MetricSelector::new(),
+                unit: None,
+            }),
+        );
+    }
+    if stats.summary.is_some() {
+        metrics.insert(
+            MetricName::new(format!("trace_{name}")).unwrap(),
+            Metric::Summary(Summary {
+                query: MetricSelector(
+                    std::iter::once((
+                        LabelName::new("metric_type").unwrap(),
+                        LabelSelector::Eq(String::from("summary")),
+                    ))
+                    .collect(),
+                ),
+                labels: MetricSelector::new(),
+                unit: None,
+            }),
+        );
+    }
+    if stats
+        .summary
+        .as_ref()
+        .is_some_and(|config| config.emit_drift.is_some())
+    {
+        metrics.insert(
+            MetricName::new(format!("trace_{name}_drift")).unwrap(),
+            Metric::Scalar(Scalar {
+                r#type: Some(ScalarType::Gauge),
+                query: MetricSelector(
+                    std::iter::once((
+                        LabelName::new("metric_type").unwrap(),
+                        LabelSelector::Eq(String::from("summary")),
+                    ))
+                    .collect(),
+                ),
+                labels: MetricSelector::new(),
+                unit: None,
+            }),
+        );
+    }
+    if stats.histogram.is_some() {
+        metrics.insert(
+            MetricName::new(format!("trace_{name}")).unwrap(),
+            Metric::Histogram(Histogram {
+                query: MetricSelector(
+                    std::iter::once((
+                        LabelName::new("metric_type").unwrap(),
+                        LabelSelector::Eq(String::from("histogram")),
+                    ))
+                    .collect(),
+                ),
+                labels: MetricSelector::new(),
+                unit: None,
+            }),
+        );
+    }
+}
+
 #[derive(Serialize, JsonSchema, ApiComponent)]
 pub struct PromSchema(Singleton<ModuleName, Module>);
 
@@ -0,0 +1,742 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+//! A compact expression syntax for span selectors, used in logs and
+//! error messages, e.g.
+//! `any(in(status_code, ["ERROR"]), has(tag:exception.message))`, and
+//! builders for writing selectors in Rust.
+//!
+//! Keys are written as `service_name`, `operation_name`, `duration`,
+//! `span_kind`, `status_code`, `tag:<name>`, `process:<name>`,
+//! `baggage:<name>` or `class:<name>`, optionally prefixed with
+//! `parent.` or `grandparent.`. Tag names
+//! other than letters, digits and `_.-/` are quoted. Ranges use
+//! interval notation, with `*` for an open end: `[200, 299]`,
+//! `(0, *)`.
+
+use std::{
+    collections::BTreeSet,
+    fmt::{Display, Formatter},
+    ops::{RangeFrom, RangeInclusive, RangeToInclusive},
+    str::FromStr,
+};
+
+use crate::config::{
+    KeyName, LowerBound, Range, Regex, SpanKey, SpanKind, SpanKindSelector, SpanSelector,
+    UpperBound,
+};
+
+impl SpanSelector {
+    /// Matches if all selectors match.
+    pub fn all<I: IntoIterator<Item = SpanSelector>>(selectors: I) -> Self {
+        Self::All(selectors.into_iter().collect())
+    }
+
+    /// Matches if any selector matches.
+    pub fn any<I: IntoIterator<Item = SpanSelector>>(selectors: I) -> Self {
+        Self::Any(selectors.into_iter().collect())
+    }
+
+    /// Matches if both selectors match.
+    pub fn and(self, other: SpanSelector) -> Self {
+        match self {
+            Self::All(mut selectors) => {
+                selectors.push(other);
+                Self::All(selectors)
+            }
+            selector => Self::All(vec![selector, other]),
+        }
+    }
+
+    /// Matches if either selector matches.
+    pub fn or(self, other: SpanSelector) -> Self {
+        match self {
+            Self::Any(mut selectors) => {
+                selectors.push(other);
+                Self::Any(selectors)
+            }
+            selector => Self::Any(vec![selector, other]),
+        }
+    }
+}
+
+impl std::ops::Not for SpanSelector {
+    type Output = SpanSelector;
+
+    fn not(self) -> Self::Output {
+        SpanSelector::Not(Box::new(self))
+    }
+}
+
+impl KeyName {
+    pub fn span_tag<T: Into<String>>(name: T) -> Self {
+        Self::SpanTag(name.into())
+    }
+
+    pub fn process_tag<T: Into<String>>(name: T) -> Self {
+        Self::ProcessTag(name.into())
+    }
+}
+
+impl SpanKey {
+    /// A tag of the span itself.
+    pub fn tag<T: Into<String>>(name: T) -> Self {
+        Self::Current(KeyName::span_tag(name))
+    }
+
+    /// A tag of the process of the span itself.
+    pub fn process_tag<T: Into<String>>(name: T) -> Self {
+        Self::Current(KeyName::process_tag(name))
+    }
+
+    pub fn has(self) -> SpanSelector {
+        SpanSelector::Has(self)
+    }
+
+    /// The key is a string, equal to one of `values`.
+    pub fn one_of<I, T>(self, values: I) -> SpanSelector
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<String>,
+    {
+        SpanSelector::In(self, values.into_iter().map(Into::into).collect())
+    }
+
+    /// The key is a string, equal to none of `values`.
+    pub fn none_of<I, T>(self, values: I) -> SpanSelector
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<String>,
+    {
+        SpanSelector::NotIn(self, values.into_iter().map(Into::into).collect())
+    }
+
+    /// The key is a string, matching `regex`.
+    pub fn matching(self, regex: Regex) -> SpanSelector {
+        SpanSelector::Match(self, regex)
+    }
+
+    /// The key is a string, not matching `regex`.
+    pub fn not_matching(self, regex: Regex) -> SpanSelector {
+        SpanSelector::NoMatch(self, regex)
+    }
+
+    /// Both keys have the same value, or are both absent.
+    pub fn same_as(self, other: SpanKey) -> SpanSelector {
+        SpanSelector::KeyEq(self, other)
+    }
+
+    /// The keys have different values, or only one is present.
+    pub fn differs_from(self, other: SpanKey) -> SpanSelector {
+        SpanSelector::KeyNe(self, other)
+    }
+
+    /// The key is an integer, equal to `n`.
+    pub fn equals(self, n: i64) -> SpanSelector {
+        SpanSelector::Eq(self, n)
+    }
+
+    /// The key is an integer, not equal to `n`.
+    pub fn not_equals(self, n: i64) -> SpanSelector {
+        SpanSelector::Ne(self, n)
+    }
+
+    /// The key is an integer in `range`, e.g. `200..=299`.
+    pub fn inside<R: Into<Range>>(self, range: R) -> SpanSelector {
+        SpanSelector::Inside(self, range.into())
+    }
+
+    /// The key is an integer outside `range`.
+    pub fn outside<R: Into<Range>>(self, range: R) -> SpanSelector {
+        SpanSelector::Outside(self, range.into())
+    }
+
+    /// The key is a boolean, set to true.
+    pub fn is_true(self) -> SpanSelector {
+        SpanSelector::IsTrue(self)
+    }
+
+    /// The key is a boolean, set to false.
+    pub fn is_false(self) -> SpanSelector {
+        SpanSelector::IsFalse(self)
+    }
+}
+
+impl From<RangeInclusive<i64>> for Range {
+    fn from(range: RangeInclusive<i64>) -> Self {
+        Self {
+            lower: Some(LowerBound::Ge(*range.start())),
+            upper: Some(UpperBound::Le(*range.end())),
+        }
+    }
+}
+
+impl From<std::ops::Range<i64>> for Range {
+    fn from(range: std::ops::Range<i64>) -> Self {
+        Self {
+            lower: Some(LowerBound::Ge(range.start)),
+            upper: Some(UpperBound::Lt(range.end)),
+        }
+    }
+}
+
+impl From<RangeFrom<i64>> for Range {
+    fn from(range: RangeFrom<i64>) -> Self {
+        Self {
+            lower: Some(LowerBound::Ge(range.start)),
+            upper: None,
+        }
+    }
+}
+
+impl From<RangeToInclusive<i64>> for Range {
+    fn from(range: RangeToInclusive<i64>) -> Self {
+        Self {
+            lower: None,
+            upper: Some(UpperBound::Le(range.end)),
+        }
+    }
+}
+
+impl Display for SpanSelector {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SpanSelector::All(selectors) => write_call(f, "all", selectors),
+            SpanSelector::Any(selectors) => write_call(f, "any", selectors),
+            SpanSelector::Not(selector) => write!(f, "not({selector})"),
+            SpanSelector::Has(key) => write!(f, "has({key})"),
+            SpanSelector::In(key, values) => {
+                write!(f, "in({key}, ")?;
+                write_strings(f, values)?;
+                write!(f, ")")
+            }
+            SpanSelector::NotIn(key, values) => {
+                write!(f, "not_in({key}, ")?;
+                write_strings(f, values)?;
+                write!(f, ")")
+            }
+            SpanSelector::Match(key, regex) => {
+                write!(f, "match({key}, {})", Quoted(&regex.to_string()))
+            }
+            SpanSelector::NoMatch(key, regex) => {
+                write!(f, "no_match({key}, {})", Quoted(&regex.to_string()))
+            }
+            SpanSelector::KeyEq(a, b) => write!(f, "key_eq({a}, {b})"),
+            SpanSelector::KeyNe(a, b) => write!(f, "key_ne({a}, {b})"),
+            SpanSelector::Eq(key, n) => write!(f, "eq({key}, {n})"),
+            SpanSelector::Ne(key, n) => write!(f, "ne({key}, {n})"),
+            SpanSelector::Inside(key, range) => write!(f, "inside({key}, {range})"),
+            SpanSelector::Outside(key, range) => write!(f, "outside({key}, {range})"),
+            SpanSelector::IsTrue(key) => write!(f, "is_true({key})"),
+            SpanSelector::IsFalse(key) => write!(f, "is_false({key})"),
+            SpanSelector::HasLog(field, regex) => write!(
+                f,
+                "has_log({}, {})",
+                Quoted(field),
+                Quoted(&regex.to_string())
+            ),
+            SpanSelector::Kind(SpanKindSelector::One(kind)) => write!(f, "kind({})", kind.as_str()),
+            SpanSelector::Kind(SpanKindSelector::AnyOf(kinds)) => {
+                write!(f, "kind([")?;
+                kinds.iter().enumerate().try_for_each(|(i, kind)| {
+                    let sep = if i > 0 { ", " } else { "" };
+                    write!(f, "{sep}{}", kind.as_str())
+                })?;
+                write!(f, "])")
+            }
+        }
+    }
+}
+
+fn write_call(f: &mut Formatter<'_>, name: &str, selectors: &[SpanSelector]) -> std::fmt::Result {
+    write!(f, "{name}(")?;
+    selectors.iter().enumerate().try_for_each(|(i, selector)| {
+        let sep = if i > 0 { ", " } else { "" };
+        write!(f, "{sep}{selector}")
+    })?;
+    write!(f, ")")
+}
+
+fn write_strings(f: &mut Formatter<'_>, values: &BTreeSet<String>) -> std::fmt::Result {
+    write!(f, "[")?;
+    values.iter().enumerate().try_for_each(|(i, value)| {
+        let sep = if i > 0 { ", " } else { "" };
+        write!(f, "{sep}{}", Quoted(value))
+    })?;
+    write!(f, "]")
+}
+
+impl Display for SpanKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SpanKey::Current(key) => write!(f, "{key}"),
+            SpanKey::Parent(key) => write!(f, "parent.{key}"),
+            SpanKey::Grandparent(key) => write!(f, "grandparent.{key}"),
+        }
+    }
+}
+
+impl Display for KeyName {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeyName::OperationName => write!(f, "operation_name"),
+            KeyName::ServiceName => write!(f, "service_name"),
+            KeyName::Duration => write!(f, "duration"),
+            KeyName::SpanKind => write!(f, "span_kind"),
+            KeyName::StatusCode => write!(f, "status_code"),
+            KeyName::SpanTag(name) => write!(f, "tag:{}", TagName(name)),
+            KeyName::ProcessTag(name) => write!(f, "process:{}", TagName(name)),
+            KeyName::Baggage(name) => write!(f, "baggage:{}", TagName(name)),
+            KeyName::Class(name) => write!(f, "class:{}", TagName(name)),
+        }
+    }
+}
+
+impl Display for Range {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.lower {
+            Some(LowerBound::Ge(n)) => write!(f, "[{n}, ")?,
+            Some(LowerBound::Gt(n)) => write!(f, "({n}, ")?,
+            None => write!(f, "(*, ")?,
+        }
+        match &self.upper {
+            Some(UpperBound::Le(n)) => write!(f, "{n}]"),
+            Some(UpperBound::Lt(n)) => write!(f, "{n})"),
+            None => write!(f, "*)"),
+        }
+    }
+}
+
+/// A string literal, with backslashes and double quotes escaped.
+struct Quoted<'a>(&'a str);
+
+impl Display for Quoted<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "\"")?;
+        self.0.chars().try_for_each(|c| match c {
+            '"' | '\\' => write!(f, "\\{c}"),
+            c => write!(f, "{c}"),
+        })?;
+        write!(f, "\"")
+    }
+}
+
+/// A tag name, quoted unless it consists of bare name characters.
+struct TagName<'a>(&'a str);
+
+impl Display for TagName<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if !self.0.is_empty() && self.0.chars().all(is_bare) {
+            write!(f, "{}", self.0)
+        } else {
+            write!(f, "{}", Quoted(self.0))
+        }
+    }
+}
+
+fn is_bare(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-' | '/')
+}
+
+#[derive(thiserror::Error, PartialEq, Eq, Debug)]
+#[error("invalid selector at position {pos}: {msg}")]
+pub struct SelectorParseError {
+    pos: usize,
+    msg: String,
+}
+
+impl FromStr for SpanSelector {
+    type Err = SelectorParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser { s, pos: 0 };
+        let selector = parser.selector()?;
+        parser.skip_ws();
+        match parser.pos < s.len() {
+            true => Err(parser.error("unexpected trailing input")),
+            false => Ok(selector),
+        }
+    }
+}
+
+struct Parser<'a> {
+    s: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn selector(&mut self) -> Result<SpanSelector, SelectorParseError> {
+        self.skip_ws();
+        let start = self.pos;
+        let name = self.ident()?;
+        self.expect('(')?;
+        let selector = match name {
+            "all" => SpanSelector::all(self.items(')', Self::selector)?),
+            "any" => SpanSelector::any(self.items(')', Self::selector)?),
+            "not" => !self.selector()?,
+            "has" => self.key()?.has(),
+            "in" | "not_in" => {
+                let key = self.key()?;
+                self.expect(',')?;
+                self.expect('[')?;
+                let values = self.items(']', Self::string)?;
+                self.expect(']')?;
+                match name {
+                    "in" => key.one_of(values),
+                    _ => key.none_of(values),
+                }
+            }
+            "match" | "no_match" => {
+                let key = self.key()?;
+                self.expect(',')?;
+                let regex = self.regex()?;
+                match name {
+                    "match" => key.matching(regex),
+                    _ => key.not_matching(regex),
+                }
+            }
+            "key_eq" | "key_ne" => {
+                let a = self.key()?;
+                self.expect(',')?;
+                let b = self.key()?;
+                match name {
+                    "key_eq" => a.same_as(b),
+                    _ => a.differs_from(b),
+                }
+            }
+            "eq" | "ne" => {
+                let key = self.key()?;
+                self.expect(',')?;
+                let n = self.int()?;
+                match name {
+                    "eq" => key.equals(n),
+                    _ => key.not_equals(n),
+                }
+            }
+            "inside" | "outside" => {
+                let key = self.key()?;
+                self.expect(',')?;
+                let range = self.range()?;
+                match name {
+                    "inside" => key.inside(range),
+                    _ => key.outside(range),
+                }
+            }
+            "is_true" => self.key()?.is_true(),
+            "is_false" => self.key()?.is_false(),
+            "has_log" => {
+                let field = self.string()?;
+                self.expect(',')?;
+                SpanSelector::HasLog(field, self.regex()?)
+            }
+            "kind" => {
+                if self.eat('[') {
+                    let kinds = self.items(']', Self::span_kind)?;
+                    self.expect(']')?;
+                    SpanSelector::Kind(SpanKindSelector::AnyOf(kinds.into_iter().collect()))
+                } else {
+                    SpanSelector::Kind(SpanKindSelector::One(self.span_kind()?))
+                }
+            }
+            _ => {
+                self.pos = start;
+                return Err(self.error(format!("unknown selector {name}")));
+            }
+        };
+        self.expect(')')?;
+        Ok(selector)
+    }
+
+    fn key(&mut self) -> Result<SpanKey, SelectorParseError> {
+        let start = self.pos;
+        let name = self.ident()?;
+        match name {
+            "parent" if self.eat('.') => Ok(SpanKey::Parent(self.key_name()?)),
+            "grandparent" if self.eat('.') => Ok(SpanKey::Grandparent(self.key_name()?)),
+            _ => {
+                self.pos = start;
+                Ok(SpanKey::Current(self.key_name()?))
+            }
+        }
+    }
+
+    fn key_name(&mut self) -> Result<KeyName, SelectorParseError> {
+        self.skip_ws();
+        let start = self.pos;
+        match self.ident()? {
+            "operation_name" => Ok(KeyName::OperationName),
+            "service_name" => Ok(KeyName::ServiceName),
+            "duration" => Ok(KeyName::Duration),
+            "span_kind" => Ok(KeyName::SpanKind),
+            "status_code" => Ok(KeyName::StatusCode),
+            "tag" if self.eat(':') => Ok(KeyName::span_tag(self.tag_name()?)),
+            "process" if self.eat(':') => Ok(KeyName::process_tag(self.tag_name()?)),
+            "baggage" if self.eat(':') => Ok(KeyName::Baggage(self.tag_name()?)),
+            "class" if self.eat(':') => Ok(KeyName::Class(self.tag_name()?)),
+            name => {
+                self.pos = start;
+                Err(self.error(format!("unknown key {name}")))
+            }
+        }
+    }
+
+    fn tag_name(&mut self) -> Result<String, SelectorParseError> {
+        if self.peek() == Some('"') {
+            return self.string();
+        }
+        let start = self.pos;
+        let len = self.s[start..]
+            .find(|c| !is_bare(c))
+            .unwrap_or(self.s.len() - start);
+        self.pos += len;
+        match len {
+            0 => Err(self.error("expected a tag name")),
+            _ => Ok(self.s[start..self.pos].to_string()),
+        }
+    }
+
+    fn range(&mut self) -> Result<Range, SelectorParseError> {
+        let inclusive = match self.next() {
+            Some('[') => true,
+            Some('(') => false,
+            _ => return Err(self.error("expected '[' or '('")),
+        };
+        let lower = match self.eat('*') {
+            true if inclusive => return Err(self.error("open ends must use '(' or ')'")),
+            true => None,
+            false if inclusive => Some(LowerBound::Ge(self.int()?)),
+            false => Some(LowerBound::Gt(self.int()?)),
+        };
+        self.expect(',')?;
+        let upper = match self.eat('*') {
+            true => None,
+            false => Some(self.int()?),
+        };
+        let upper = match (self.next(), upper) {
+            (Some(']'), Some(n)) => Some(UpperBound::Le(n)),
+            (Some(')'), n) => n.map(UpperBound::Lt),
+            _ => return Err(self.error("expected ')' or ']'")),
+        };
+        Ok(Range { lower, upper })
+    }
+
+    fn span_kind(&mut self) -> Result<SpanKind, SelectorParseError> {
+        self.skip_ws();
+        let start = self.pos;
+        self.ident()?.parse().map_err(|e| {
+            self.pos = start;
+            self.error(format!("{e}"))
+        })
+    }
+
+    fn regex(&mut self) -> Result<Regex, SelectorParseError> {
+        self.skip_ws();
+        let start = self.pos;
+        Regex::new(&self.string()?).map_err(|e| {
+            self.pos = start;
+            self.error(format!("{e}"))
+        })
+    }
+
+    /// Comma-separated items, up to (not including) `close`.
+    fn items<T>(
+        &mut self,
+        close: char,
+        mut item: impl FnMut(&mut Self) -> Result<T, SelectorParseError>,
+    ) -> Result<Vec<T>, SelectorParseError> {
+        let mut items = Vec::new();
+        if self.peek() == Some(close) {
+            return Ok(items);
+        }
+        loop {
+            items.push(item(self)?);
+            if !self.eat(',') {
+                return Ok(items);
+            }
+        }
+    }
+
+    fn ident(&mut self) -> Result<&'a str, SelectorParseError> {
+        self.skip_ws();
+        let start = self.pos;
+        let len = self.s[start..]
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(self.s.len() - start);
+        self.pos += len;
+        match len {
+            0 => Err(self.error("expected a name")),
+            _ => Ok(&self.s[start..self.pos]),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, SelectorParseError> {
+        self.expect('"')?;
+        let mut value = String::new();
+        loop {
+            match self.s[self.pos..].chars().next() {
+                None => return Err(self.error("unterminated string")),
+                Some('"') => {
+                    self.pos += 1;
+                    return Ok(value);
+                }
+                Some('\\') => {
+                    self.pos += 1;
+                    match self.s[self.pos..].chars().next() {
+                        Some(c @ ('"' | '\\')) => {
+                            value.push(c);
+                            self.pos += 1;
+                        }
+                        _ => return Err(self.error("invalid escape")),
+                    }
+                }
+                Some(c) => {
+                    value.push(c);
+                    self.pos += c.len_utf8();
+                }
+            }
+        }
+    }
+
+    fn int(&mut self) -> Result<i64, SelectorParseError> {
+        self.skip_ws();
+        let start = self.pos;
+        let len = self.s[start..]
+            .char_indices()
+            .find(|(i, c)| !(c.is_ascii_digit() || (*i == 0 && *c == '-')))
+            .map_or(self.s.len() - start, |(i, _)| i);
+        self.pos += len;
+        self.s[start..self.pos].parse().map_err(|_| {
+            self.pos = start;
+            self.error("expected an integer")
+        })
+    }
+
+    fn skip_ws(&mut self) {
+        let len = self.s[self.pos..].len() - self.s[self.pos..].trim_start().len();
+        self.pos += len;
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.skip_ws();
+        self.s[self.pos..].chars().next()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        let found = self.peek() == Some(c);
+        if found {
+            self.pos += c.len_utf8();
+        }
+        found
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), SelectorParseError> {
+        match self.eat(c) {
+            true => Ok(()),
+            false => Err(self.error(format!("expected '{c}'"))),
+        }
+    }
+
+    fn error<T: Into<String>>(&self, msg: T) -> SelectorParseError {
+        SelectorParseError {
+            pos: self.pos,
+            msg: msg.into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::SelectorParseError;
+    use crate::config::{KeyName, Regex, SpanKey, SpanKind, SpanKindSelector, SpanSelector};
+
+    fn round_trip(selector: &SpanSelector) -> String {
+        let s = selector.to_string();
+        assert_eq!(&s.parse::<SpanSelector>().unwrap(), selector, "{s}");
+        s
+    }
+
+    #[test]
+    fn default_config_selectors() {
+        let config = crate::config::Config::default().trace;
+        config.rules.iter().flatten().for_each(|rule| {
+            round_trip(&rule.select);
+        });
+        assert_eq!(
+            round_trip(&config.rules[1][0].select),
+            "has(parent.duration)"
+        );
+        assert_eq!(
+            round_trip(&config.rules[2][0].select),
+            "all(has(parent.duration), any(\
+             key_ne(service_name, parent.service_name), \
+             key_ne(process:service.namespace, parent.process:service.namespace), \
+             key_ne(process:service.instance.id, parent.process:service.instance.id)))"
+        );
+    }
+
+    #[test]
+    fn builders() {
+        let selector = SpanKey::tag("error")
+            .is_true()
+            .or(SpanKey::tag("http.status_code").outside(200..=299));
+        assert_eq!(
+            round_trip(&selector),
+            "any(is_true(tag:error), outside(tag:http.status_code, [200, 299]))"
+        );
+
+        let selector = SpanKey::Current(KeyName::Duration)
+            .inside(1000..)
+            .and(SpanKey::Parent(KeyName::OperationName).one_of(["GET", "POST"]))
+            .and(!SpanKey::process_tag("k8s pod").matching(Regex::new("^test-").unwrap()));
+        assert_eq!(
+            round_trip(&selector),
+            "all(inside(duration, [1000, *)), in(parent.operation_name, [\"GET\", \"POST\"]), \
+             not(match(process:\"k8s pod\", \"^test-\")))"
+        );
+
+        let selector = SpanSelector::any([
+            SpanSelector::Kind(SpanKindSelector::One(SpanKind::Server)),
+            SpanSelector::Kind(SpanKindSelector::AnyOf(
+                [SpanKind::Client, SpanKind::Producer].into_iter().collect(),
+            )),
+            SpanSelector::HasLog(
+                String::from("event"),
+                Regex::new(r#"^"quoted" \\ .*$"#).unwrap(),
+            ),
+            SpanKey::Grandparent(KeyName::StatusCode).none_of(["ERROR"]),
+            SpanKey::tag("retries").not_equals(-1),
+            SpanKey::Parent(KeyName::Baggage(String::from("tenant"))).one_of(["acme"]),
+        ]);
+        round_trip(&selector);
+        round_trip(&SpanSelector::all([]));
+    }
+
+    #[test]
+    fn parse_errors() {
+        let err = |s: &str| s.parse::<SpanSelector>().unwrap_err();
+        assert_eq!(
+            err("any(has(tag:a), bogus(tag:b))"),
+            SelectorParseError {
+                pos: 16,
+                msg: String::from("unknown selector bogus")
+            }
+        );
+        assert!(err("has(tag:a) x").to_string().contains("trailing input"));
+        assert!(err("inside(duration, [1, *])").to_string().contains("')'"));
+        assert!(err("match(tag:a, \"(\")")
+            .to_string()
+            .contains("position 13"));
+        assert!(err("has(tag:)").to_string().contains("tag name"));
+        assert_eq!(
+            " any ( has ( tag:\"a b\" ) ) ".parse::<SpanSelector>(),
+            Ok(SpanSelector::any([SpanKey::tag("a b").has()]))
+        );
+    }
+}
@@ -2,15 +2,19 @@
  * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
  ******************************************************************************/
 
-use std::collections::BTreeMap;
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
 
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
 use crate::{
     config::{ConfigName, KeyName, MetricName},
+    error::{Error, Result},
     jaeger::TagValue,
-    processor::trace::TraceState,
+    processor::{bootstrap::BootstrapProgress, shard::Shard, trace::TraceState},
 };
 
 use super::config::Config;
@@ -18,8 +22,82 @@ use super::config::Config;
 #[derive(Serialize, Deserialize, Debug)]
 pub struct State {
     pub config: Config,
+    /// The number of config updates since the state was created.
+    #[serde(default)]
+    pub generation: u64,
     pub state: TraceState,
     pub last: DateTime<Utc>,
+    /// The progress of the bootstrap, while it is running.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bootstrap: Option<BootstrapProgress>,
+    /// The shard of the replica that saved the state, if sharded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shard: Option<Shard>,
+}
+
+/// The applied config, as written to `--config-export-path`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ConfigExport {
+    /// The config generation (see `State::generation`).
+    pub generation: u64,
+    /// The time the config was applied.
+    pub applied: DateTime<Utc>,
+    pub config: Config,
+}
+
+impl ConfigExport {
+    /// Write the export to `path`. The export is written to a
+    /// temporary file next to it first, so that `path` always holds a
+    /// complete config.
+    pub async fn write(&self, path: &Path) -> Result<()> {
+        let err = |e| Error::ExportConfig(path.to_path_buf(), e);
+        let data = serde_json::to_vec_pretty(self).map_err(|e| err(e.into()))?;
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        tokio::fs::write(&tmp, data).await.map_err(err)?;
+        tokio::fs::rename(&tmp, path).await.map_err(err)
+    }
+}
+
+/// Statistics of a state save.
+#[derive(Serialize, schemars::JsonSchema, PartialEq, Clone, Copy, Debug)]
+pub struct SaveStats {
+    /// The time the state was saved.
+    pub time: DateTime<Utc>,
+    /// The time taken to serialize the state, in seconds.
+    pub duration_seconds: f64,
+    /// The size of the serialized state, in bytes.
+    pub bytes: u64,
+}
+
+/// Decides when to save the state after a tick. Saves are skipped
+/// while the processor is unchanged, but forced every `force_every`
+/// ticks so that the saved `last` timestamp does not go stale.
+#[derive(Debug)]
+pub struct SaveSchedule {
+    force_every: u32,
+    skipped: u32,
+}
+
+impl SaveSchedule {
+    pub fn new(force_every: u32) -> Self {
+        Self {
+            force_every,
+            skipped: 0,
+        }
+    }
+
+    /// Whether the state should be saved after a tick.
+    pub fn tick(&mut self, dirty: bool) -> bool {
+        if dirty || self.skipped + 1 >= self.force_every {
+            self.skipped = 0;
+            true
+        } else {
+            self.skipped += 1;
+            false
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -34,3 +112,116 @@ pub struct SpanProcessorState {
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct MetricProcessorState {}
+
+#[cfg(test)]
+mod test {
+    use chrono::TimeDelta;
+
+    use super::{ConfigExport, SaveSchedule, SaveStats};
+    use crate::{
+        config::{Config, IngestFilter},
+        processor::{
+            sim::{start, synthetic_traces},
+            trace::{TraceConfig, TraceProcessor},
+        },
+    };
+
+    #[test]
+    fn skip_idle_saves() {
+        let mut processor = TraceProcessor::new(&TraceConfig::default());
+        let mut schedule = SaveSchedule::new(3);
+        let saved = |processor: &mut TraceProcessor| {
+            processor.record_save(SaveStats {
+                time: start(),
+                duration_seconds: 0.1,
+                bytes: 1024,
+            })
+        };
+
+        // A new processor has not been saved yet.
+        assert!(schedule.tick(processor.is_dirty()));
+        saved(&mut processor);
+
+        // Ticks with traces are saved.
+        let traces = synthetic_traces(start(), 10);
+        let batch = traces
+            .iter()
+            .map(|trace| (start(), trace.as_slice()))
+            .collect::<Vec<_>>();
+        processor.insert_batch(&batch, &IngestFilter::default());
+        assert!(schedule.tick(processor.is_dirty()));
+        saved(&mut processor);
+
+        // Idle ticks only sample and clean up; the third is saved anyway.
+        let mut saves = Vec::new();
+        for i in 1..=4 {
+            let t = start() + TimeDelta::minutes(i);
+            processor.sample(t, |_, _, _| {});
+            processor.insert_batch(&[], &IngestFilter::default());
+            processor.cleanup(t - TimeDelta::days(30));
+            let save = schedule.tick(processor.is_dirty());
+            if save {
+                saved(&mut processor);
+            }
+            saves.push(save);
+        }
+        assert_eq!(saves, [false, false, true, false]);
+
+        // The last save is reported in the self-monitoring metrics.
+        let mut gauges = Vec::new();
+        processor.sample(start() + TimeDelta::minutes(5), |args, _, value| {
+            if args
+                .metric_name
+                .starts_with("jaeger_anomaly_detection_state_")
+            {
+                gauges.push((args.metric_name, value));
+            }
+        });
+        assert_eq!(
+            gauges,
+            [
+                (
+                    String::from("jaeger_anomaly_detection_state_save_seconds"),
+                    0.1
+                ),
+                (
+                    String::from("jaeger_anomaly_detection_state_size_bytes"),
+                    1024.0
+                )
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn export_config() {
+        let dir = std::env::temp_dir().join(format!(
+            "jaeger-anomaly-detection-config-export-{}",
+            std::process::id()
+        ));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let path = dir.join("config.json");
+
+        let mut config = Config::default();
+        let export = |generation: u64, config: &Config| ConfigExport {
+            generation,
+            applied: start() + TimeDelta::minutes(generation as i64),
+            config: config.clone(),
+        };
+        export(0, &config).write(&path).await.unwrap();
+        config.max_series = Some(1000);
+        export(1, &config).write(&path).await.unwrap();
+
+        let data = tokio::fs::read(&path).await.unwrap();
+        let read = serde_json::from_slice::<ConfigExport>(&data).unwrap();
+        assert_eq!(read.generation, 1);
+        assert_eq!(read.applied, start() + TimeDelta::minutes(1));
+        assert_eq!(read.config, config);
+        assert!(!dir.join("config.json.tmp").exists());
+
+        // Failures are reported, not panicked on.
+        let missing = dir.join("missing").join("config.json");
+        let err = export(2, &config).write(&missing).await.unwrap_err();
+        assert!(err.to_string().contains("missing"), "{err}");
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
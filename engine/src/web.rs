@@ -2,85 +2,218 @@
  * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
  ******************************************************************************/
 
-use std::{fmt::Display, sync::Arc};
+use std::{
+    fmt::Display,
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use actix_web::{
-    body::EitherBody,
-    middleware::Compress,
-    web::{Data, Json, JsonConfig},
-    App, HttpRequest, HttpResponse, HttpServer, Responder, ResponseError,
+    body::{BodySize, BoxBody, EitherBody, MessageBody},
+    dev::{Payload, ServiceRequest, ServiceResponse},
+    error::InternalError,
+    http::{
+        header::{ACCEPT, CONTENT_LENGTH, CONTENT_TYPE, ETAG, IF_MATCH},
+        StatusCode,
+    },
+    middleware::{from_fn, Compress, Condition, Next},
+    web::{Bytes, Data, Json, JsonConfig, Path, PayloadConfig, Query},
+    App, FromRequest, HttpRequest, HttpResponse, HttpServer, Responder, ResponseError,
 };
 use apistos::{
     api_operation,
     app::OpenApiWrapper,
-    info::Info,
     spec::Spec,
-    web::{get, post, scope, Resource},
-    ApiComponent, OpenApi,
+    web::{get, patch, post, scope, Resource},
+    ApiComponent, ApiErrorComponent, OpenApi,
 };
+use chrono::{DateTime, Utc};
 use schemars::JsonSchema;
-use serde::Serialize;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use tap::Pipe;
 use tracing::instrument;
 use tracing_actix_web::TracingLogger;
 
 use crate::{
-    config::Config,
+    config::{Config, ConfigError},
+    control::{
+        generation_etag, parse_generation_etag, ConfigStore, ConfigVersion, Mode, ProcessorControl,
+        Status,
+    },
     error::{Error, Result},
-    processor::proc::Processor,
+    info::{EngineInfo, Info},
+    processor::{
+        baseline::{BaselineBundle, BundleError, ImportReport},
+        label_values::{LabelValuesQuery, LabelValuesReport},
+        maintenance::MaintenanceWindow,
+        resolve::{ResolveObjectRequest, ResolvedObject},
+        schema_push::SchemaPushStatus,
+        tick::TickReport,
+        top_movers::{TopMovers, TopMoversQuery},
+        trace::{TraceConfig, DEFAULT_CONFIG_VERSION},
+        trace_debug::TraceDebugReport,
+    },
     schema::get_prom_schema,
     Args,
 };
 
 use jaeger_anomaly_detection::{WelfordExprs, WelfordParams};
 
+mod schema_utils;
+
 #[derive(Debug)]
 pub struct AppData {
-    pub processor: Arc<Processor>,
+    /// The config of the processor; not available in web mode
+    /// without `--processor-url`.
+    pub config: Option<Arc<dyn ConfigStore>>,
+    /// The processor; not available in web mode.
+    pub processor: Option<Arc<dyn ProcessorControl>>,
+    pub info: EngineInfo,
+    /// How long to wait for commands routed to the processor task.
+    pub command_timeout: Duration,
+}
+
+impl AppData {
+    fn config_store(&self) -> WebResult<&dyn ConfigStore> {
+        self.config
+            .as_deref()
+            .ok_or(WebError::NotAvailable("config"))
+    }
+
+    fn processor(&self) -> WebResult<&dyn ProcessorControl> {
+        self.processor
+            .as_deref()
+            .ok_or(WebError::NotAvailable("processor"))
+    }
+
+    /// Wait for a command routed to the processor task, for at most the
+    /// command timeout, so that a stuck processor task does not hang
+    /// the request.
+    async fn command<T>(&self, reply: impl Future<Output = Result<T>>) -> WebResult<T> {
+        tokio::time::timeout(self.command_timeout, reply)
+            .await
+            .map_err(|_| WebError::CommandTimeout(self.command_timeout))?
+            .map_err(WebError::Processor)
+    }
 }
 
 // Macro, since i didn't succeed to name the output type.
 macro_rules! web_server {
     () => {
-        |prefix: String, data: Option<&Data<AppData>>| {
+        |args: &Args, data: Option<&Data<AppData>>| {
             App::new()
                 .document(Spec {
-                    info: Info {
+                    info: apistos::info::Info {
                         title: String::from("Jaeger Anomaly Detection API"),
                         version: String::from(env!("CARGO_PKG_VERSION")),
                         ..Default::default()
                     },
                     ..Default::default()
                 })
+                .wrap(Condition::new(!args.no_access_log, from_fn(access_log)))
                 .wrap(TracingLogger::default())
                 .wrap(Compress::default())
                 .service({
-                    scope(&prefix)
-                        .app_data(JsonConfig::default().limit(50 * (1 << 20)))
+                    scope(&args.prefix)
+                        .app_data(json_config(args.max_json_payload))
                         .pipe(|app| match data {
                             Some(data) => app.app_data(data.clone()),
                             None => app,
                         })
                         .service(
                             Resource::new("config")
+                                .app_data(json_config(args.max_config_payload))
+                                .app_data(PayloadConfig::new(args.max_config_payload))
                                 .route(get().to(get_config))
-                                .route(post().to(post_config)),
+                                .route(post().to(post_config))
+                                .route(patch().to(patch_config)),
+                        )
+                        .service(Resource::new("maintenance").route(post().to(add_maintenance)))
+                        .service(
+                            Resource::new("config/default").route(get().to(get_default_config)),
                         )
-                        .service(Resource::new("prometheus-schema").route(get().to(get_schema)))
-                        .service(Resource::new("expr/welford").route(post().to(post_welford_exprs)))
+                        .service(
+                            Resource::new("config/validate")
+                                .app_data(json_config(args.max_config_payload))
+                                .app_data(PayloadConfig::new(args.max_config_payload))
+                                .route(post().to(validate_config)),
+                        )
+                        .service(Resource::new("status").route(get().to(get_status)))
+                        .service(
+                            Resource::new("prometheus-schema/push").route(post().to(push_schema)),
+                        )
+                        .service(Resource::new("health").route(get().to(get_health)))
+                        .pipe(|app| match args.mode {
+                            Mode::Processor => app,
+                            Mode::All | Mode::Web => app
+                                .service(Resource::new("info").route(get().to(get_info)))
+                                .service(
+                                    Resource::new("baselines/export")
+                                        .route(get().to(export_baselines)),
+                                )
+                                .service(
+                                    Resource::new("baselines/import")
+                                        .app_data(PayloadConfig::new(args.max_baseline_payload))
+                                        .route(post().to(import_baselines)),
+                                )
+                                .service(
+                                    Resource::new("cached/{name}")
+                                        .route(get().to(get_cached_query)),
+                                )
+                                .service(
+                                    Resource::new("label-values")
+                                        .route(get().to(get_label_values)),
+                                )
+                                .service(
+                                    Resource::new("resolve-object")
+                                        .route(post().to(resolve_object)),
+                                )
+                                .service(
+                                    Resource::new("top-movers").route(get().to(get_top_movers)),
+                                )
+                                .service(
+                                    Resource::new("debug/trace/{trace_id}")
+                                        .route(get().to(debug_trace)),
+                                )
+                                .service(
+                                    Resource::new("prometheus-schema").route(get().to(get_schema)),
+                                )
+                                .service(
+                                    Resource::new("expr/welford")
+                                        .route(post().to(post_welford_exprs)),
+                                ),
+                        })
                 })
                 // .service(
                 //     Resource::new("graph/example").route(get().to(crate::graph::get_example_graph)),
                 // )
                 .build_spec()
+                .pipe(|(app, spec)| {
+                    // Served outside of the documented scope, so that
+                    // the spec does not describe itself.
+                    let prefix = args.prefix.trim_end_matches('/');
+                    let app = app
+                        .app_data(Data::new(spec.clone()))
+                        .service(
+                            actix_web::web::resource(format!("{prefix}/openapi.json"))
+                                .route(actix_web::web::get().to(get_openapi)),
+                        )
+                        .service(
+                            actix_web::web::resource(format!("{prefix}/docs"))
+                                .route(actix_web::web::get().to(get_docs)),
+                        );
+                    (app, spec)
+                })
         }
     };
 }
 
 pub async fn run_web_server(args: &Args, data: AppData) -> Result<()> {
     let data = Some(Data::new(data));
-    let prefix = args.prefix.clone();
-    HttpServer::new(move || web_server!()(prefix.clone(), data.as_ref()).0)
+    let server_args = args.clone();
+    HttpServer::new(move || web_server!()(&server_args, data.as_ref()).0)
         .bind(&args.bind)
         .map_err(|e| Error::Bind(args.bind.clone(), e))?
         .run()
@@ -89,29 +222,415 @@ pub async fn run_web_server(args: &Args, data: AppData) -> Result<()> {
 }
 
 pub fn web_server_spec(args: &Args) -> OpenApi {
-    web_server!()(args.prefix.clone(), None).1
+    web_server!()(args, None).1
+}
+
+/// A JSON extractor config with the given payload limit, reporting
+/// extraction errors (including oversized payloads) as JSON.
+fn json_config(limit: usize) -> JsonConfig {
+    JsonConfig::default().limit(limit).error_handler(|err, _| {
+        let res = HttpResponse::build(err.status_code()).json(ErrorBody {
+            error: err.to_string(),
+        });
+        InternalError::from_response(err, res).into()
+    })
+}
+
+/// Log one line per request, in logfmt.
+async fn access_log(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> std::result::Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let start = Instant::now();
+    let method = req.method().clone();
+    let path = req.path().to_string();
+    let bytes_in = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok()?.parse::<u64>().ok())
+        .unwrap_or(0);
+    let res = next.call(req).await?;
+    let bytes_out = match res.response().body().size() {
+        BodySize::None => String::from("0"),
+        BodySize::Sized(n) => n.to_string(),
+        BodySize::Stream => String::from("-"),
+    };
+    log::info!(
+        target: "access_log",
+        "method={method} path={path:?} status={} duration_ms={} bytes_in={bytes_in} bytes_out={bytes_out}",
+        res.status().as_u16(),
+        start.elapsed().as_millis()
+    );
+    Ok(res)
+}
+
+#[api_operation(
+    summary = "Get the current config",
+    description = "Returns YAML when requested with `Accept: application/yaml`, and JSON \
+                   otherwise. The config generation is returned in the `generation` field \
+                   and as the ETag."
+)]
+#[instrument]
+async fn get_config(data: Data<AppData>) -> WebResult<WithGeneration<Negotiated<ConfigVersion>>> {
+    let config = data
+        .config_store()?
+        .config()
+        .await
+        .map_err(WebError::Processor)?;
+    Ok(WithGeneration(config.generation, Negotiated(config)))
+}
+
+#[api_operation(
+    summary = "Get the default config",
+    description = "Returns the compiled-in default config, as a template. Returns YAML \
+                   when requested with `Accept: application/yaml`, and JSON otherwise. \
+                   Earlier versions of the default config can be selected with `version`: \
+                   version 1 does not split the \"default\" config by span kind."
+)]
+#[instrument]
+async fn get_default_config(query: Query<DefaultConfigQuery>) -> WebResult<Negotiated<Config>> {
+    let version = query.version.unwrap_or(DEFAULT_CONFIG_VERSION);
+    let trace = TraceConfig::default_version(version).ok_or(WebError::UnknownVersion(version))?;
+    Ok(Negotiated(Config {
+        trace,
+        ..Config::default()
+    }))
+}
+
+#[api_operation(
+    summary = "Update the config",
+    description = "Accepts JSON, or YAML with `Content-Type: application/yaml`. Comments \
+                   in YAML configs are not kept. With an `If-Match` header or an \
+                   `expected_generation` field, the update is rejected with 412 unless the \
+                   config is still at that generation; the current config is returned \
+                   instead. The header takes precedence."
+)]
+#[instrument]
+async fn post_config(
+    data: Data<AppData>,
+    if_match: IfMatch,
+    update: ConfigBody<ConfigUpdate>,
+) -> WebResult<Json<Success>> {
+    let ConfigUpdate {
+        expected_generation,
+        config,
+    } = update.0;
+    config.validate().map_err(WebError::Config)?;
+    set_config(
+        data.config_store()?,
+        config,
+        if_match.0.or(expected_generation),
+    )
+    .await?;
+    Ok(Json(Success("updated")))
+}
+
+/// Update the config, returning the current config if it changed
+/// since the expected generation.
+async fn set_config(
+    store: &dyn ConfigStore,
+    config: Config,
+    expected: Option<u64>,
+) -> WebResult<()> {
+    match store.set_config(config, expected).await {
+        Ok(()) => Ok(()),
+        Err(Error::ConfigConflict { .. }) => Err(WebError::ConfigConflict(Box::new(
+            store.config().await.map_err(WebError::Processor)?,
+        ))),
+        Err(e @ Error::PseudonymizationKeyRequired) => Err(WebError::ConfigRejected(e)),
+        Err(e) => Err(WebError::Processor(e)),
+    }
+}
+
+#[api_operation(
+    summary = "Validate a config",
+    description = "Checks a config without applying it. Invalid configs are rejected as \
+                   with the config endpoint; valid configs return their warnings, e.g. for \
+                   cached queries reading anomaly score windows that are not computed."
+)]
+#[instrument]
+async fn validate_config(config: ConfigBody<Config>) -> WebResult<Json<ConfigValidation>> {
+    let config = config.0;
+    config.validate().map_err(WebError::Config)?;
+    Ok(Json(ConfigValidation {
+        warnings: config.warnings(),
+    }))
+}
+
+#[api_operation(
+    summary = "Merge a partial config into the current config",
+    description = "Objects (including maps) are merged by key, null deletes a key and all \
+                   other values (including arrays) replace the current value. Enum values \
+                   are objects too, so replacing an enum variant requires setting the \
+                   previous variant to null."
+)]
+#[instrument]
+async fn patch_config(data: Data<AppData>, patch: Json<ConfigPatch>) -> WebResult<Json<Success>> {
+    let store = data.config_store()?;
+    let current = store.config().await.map_err(WebError::Processor)?;
+    let config = current
+        .config
+        .merge(patch.into_inner().0)
+        .map_err(WebError::Config)?;
+    set_config(store, config, Some(current.generation)).await?;
+    Ok(Json(Success("updated")))
+}
+
+#[api_operation(
+    summary = "Add a maintenance window",
+    description = "Adds the window to the config, dropping one-off windows that ended \
+                   more than `max_history` ago. Returns the resulting windows."
+)]
+#[instrument]
+async fn add_maintenance(
+    data: Data<AppData>,
+    window: Json<MaintenanceWindow>,
+) -> WebResult<Json<Vec<MaintenanceWindow>>> {
+    let store = data.config_store()?;
+    let ConfigVersion {
+        generation,
+        mut config,
+    } = store.config().await.map_err(WebError::Processor)?;
+    let ended = Utc::now() - config.max_history.to_time_delta();
+    config
+        .trace
+        .maintenance
+        .retain(|window| !window.has_ended(ended));
+    config.trace.maintenance.push(window.into_inner());
+    config.validate().map_err(WebError::Config)?;
+    let windows = config.trace.maintenance.clone();
+    set_config(store, config, Some(generation)).await?;
+    Ok(Json(windows))
+}
+
+#[api_operation(
+    summary = "Get the processor status",
+    description = "Reports rule evaluation and series counts, the last state save, ingest \
+                   statistics, throttling limits and the number of written and dropped \
+                   samples. Not available in web mode."
+)]
+#[instrument]
+async fn get_status(data: Data<AppData>) -> WebResult<Json<Status>> {
+    Ok(Json(data.processor()?.status()))
+}
+
+#[api_operation(
+    summary = "Check that the server is up",
+    description = "Returns \"starting\" while the processor waits for its backends at \
+                   startup and \"ok\" otherwise, with the report of the last tick; does not \
+                   check the backends."
+)]
+#[instrument]
+async fn get_health(data: Data<AppData>) -> Json<Health> {
+    match &data.processor {
+        Some(processor) if processor.starting() => Json(Health {
+            status: "starting",
+            last_tick: None,
+        }),
+        Some(processor) => Json(Health {
+            status: "ok",
+            last_tick: processor.last_tick(),
+        }),
+        None => Json(Health {
+            status: "ok",
+            last_tick: None,
+        }),
+    }
+}
+
+#[api_operation(
+    summary = "Get build information and effective parameters",
+    description = "Reports the engine version, command line parameters (with secrets \
+                   redacted), compiled-in query constants and whether the trace config in \
+                   use is the default one."
+)]
+#[instrument]
+async fn get_info(data: Data<AppData>) -> WebResult<Json<Info>> {
+    let config = data
+        .config_store()?
+        .config()
+        .await
+        .map_err(WebError::Processor)?;
+    Ok(Json(data.info.with_config(&config.config.trace)))
+}
+
+#[api_operation(
+    summary = "Export the learned baselines",
+    description = "Returns the anomaly score reference windows and welford accumulators \
+                   of all groups as a versioned, gzip-compressed CBOR bundle, as of the \
+                   end of the last tick. The config and immediate windows are not included."
+)]
+#[instrument]
+async fn export_baselines(data: Data<AppData>) -> WebResult<BundleData> {
+    let bundle = data.command(data.processor()?.export_baselines()).await?;
+    Ok(BundleData(bundle.encode().map_err(WebError::Export)?))
+}
+
+#[api_operation(
+    summary = "Import learned baselines",
+    description = "Merges a bundle produced by the export endpoint into the running \
+                   processor. Entries are applied only if their config, metric and window \
+                   shapes match the current config; the others are reported as skipped."
+)]
+#[instrument(skip(bundle))]
+async fn import_baselines(
+    data: Data<AppData>,
+    bundle: BundleData,
+) -> WebResult<Json<ImportReport>> {
+    let bundle = BaselineBundle::decode(&bundle.0, data.info.params.max_baseline_size)
+        .map_err(WebError::Import)?;
+    let report = data
+        .command(data.processor()?.import_baselines(bundle))
+        .await?;
+    Ok(Json(report))
 }
 
-#[api_operation(summary = "Get the current config")]
+#[api_operation(
+    summary = "Get the latest result of a cached query",
+    description = "Cached queries are configured in the cached_queries config section and \
+                   refreshed in the background. If the last refresh failed, the previous \
+                   result is returned with the stale flag set."
+)]
 #[instrument]
-async fn get_config(data: Data<AppData>) -> Json<Config> {
-    Json((*data.processor.get_config()).clone())
+async fn get_cached_query(
+    data: Data<AppData>,
+    path: Path<CachedQueryPath>,
+) -> WebResult<Json<CachedQueryResult>> {
+    let name = path.into_inner().name;
+    let result = data
+        .processor()?
+        .cached_query(&name)
+        .ok_or(WebError::NotCached(name))?;
+    Ok(Json(CachedQueryResult {
+        age_seconds: (Utc::now() - result.updated).num_milliseconds() as f64 / 1000.0,
+        updated: result.updated,
+        stale: result.stale,
+        data: result.data,
+    }))
+}
+
+#[api_operation(
+    summary = "Get the observed values of the group key labels",
+    description = "Lists, per config and per group key label, the distinct label values \
+                   of the groups at the end of the last tick with the number of groups \
+                   having each value. Values are sorted and paged per label with offset \
+                   and limit."
+)]
+#[instrument]
+async fn get_label_values(
+    data: Data<AppData>,
+    query: Query<LabelValuesQuery>,
+) -> WebResult<Json<LabelValuesReport>> {
+    let report = data
+        .command(data.processor()?.label_values(query.into_inner()))
+        .await?;
+    Ok(Json(report))
+}
+
+#[api_operation(
+    summary = "Resolve a trace object to its groups",
+    description = "Looks up the series of a trace object, as used in expressions, in \
+                   prometheus and returns the distinct groups seen in the last hour, \
+                   projected on the group labels of the object. The top N selection \
+                   is ignored."
+)]
+#[instrument]
+async fn resolve_object(
+    data: Data<AppData>,
+    request: Json<ResolveObjectRequest>,
+) -> WebResult<Json<ResolvedObject>> {
+    let resolved = data
+        .processor()?
+        .resolve_object(request.into_inner())
+        .await
+        .map_err(WebError::Processor)?;
+    Ok(Json(resolved))
+}
+
+#[api_operation(
+    summary = "Get the groups whose anomaly score increased most",
+    description = "Queries prometheus for the operation scores now and `lookback` earlier \
+                   (at most 7 days), joins them on the group labels and returns the `top` \
+                   (at most 1000) groups with the largest increase. A group without score \
+                   at one of both times, or scoring at most `threshold` if given, counts as \
+                   having the minimum score of 1."
+)]
+#[instrument]
+async fn get_top_movers(
+    data: Data<AppData>,
+    query: Query<TopMoversQuery>,
+) -> WebResult<Json<TopMovers>> {
+    let query = query.into_inner();
+    query.validate().map_err(WebError::InvalidQuery)?;
+    let movers = data
+        .processor()?
+        .top_movers(query)
+        .await
+        .map_err(WebError::Processor)?;
+    Ok(Json(movers))
 }
 
-#[api_operation(summary = "Update the config")]
+#[api_operation(
+    summary = "Explain how a trace would be processed",
+    description = "Fetches the spans of a trace from OpenSearch and runs them through rule \
+                   evaluation and key extraction with the current config, on a throwaway \
+                   processor. Reports, per span, the matching rules, the group key and \
+                   extracted values per config, and why spans or configs are skipped."
+)]
 #[instrument]
-async fn post_config(data: Data<AppData>, config: Json<Config>) -> Json<Success> {
-    data.processor.update_config(config.into_inner());
-    Json(Success("updated"))
+async fn debug_trace(
+    data: Data<AppData>,
+    path: Path<DebugTracePath>,
+) -> WebResult<Json<TraceDebugReport>> {
+    let trace_id = path.into_inner().trace_id;
+    data.processor()?
+        .debug_trace(&trace_id)
+        .await
+        .map_err(WebError::Processor)?
+        .ok_or(WebError::TraceNotFound(trace_id))
+        .map(Json)
 }
 
-#[api_operation(summary = "Get a prometheus schema for the current config")]
+#[api_operation(
+    summary = "Get a prometheus schema for the current config",
+    description = "Describes the metrics the processor writes for the current config, as a \
+                   prometheus schema module in YAML."
+)]
 #[instrument]
-async fn get_schema(data: Data<AppData>) -> Yaml<prometheus_schema::serial::Module> {
-    Yaml(get_prom_schema(&data.processor.get_config()))
+async fn get_schema(data: Data<AppData>) -> WebResult<Yaml<prometheus_schema::serial::Module>> {
+    let config = data
+        .config_store()?
+        .config()
+        .await
+        .map_err(WebError::Processor)?;
+    Ok(Yaml(get_prom_schema(&config.config)))
+}
+
+#[api_operation(
+    summary = "Push the prometheus schema",
+    description = "Pushes the prometheus schema of the current config to the url set with \
+                   `--schema-push-url` now, even if it did not change, and returns the push \
+                   status. Returns 502 if the push fails; the push is then retried in the \
+                   background, unless the target rejected it. Not available in web mode."
+)]
+#[instrument]
+async fn push_schema(data: Data<AppData>) -> WebResult<Json<SchemaPushStatus>> {
+    let status = data.processor()?.push_schema().await.map_err(|e| match e {
+        Error::SchemaPushDisabled => WebError::NotConfigured("schema push"),
+        e => WebError::Processor(e),
+    })?;
+    match status.last_error {
+        Some(e) => Err(WebError::SchemaPush(e)),
+        None => Ok(Json(status)),
+    }
 }
 
-#[api_operation(summary = "Get prometheus expressions")]
+#[api_operation(
+    summary = "Get prometheus expressions",
+    description = "Returns the PromQL expressions computing the welford statistics (count, \
+                   mean, standard deviation and confidence interval bounds) for the given \
+                   parameters."
+)]
 #[instrument]
 async fn post_welford_exprs(
     data: Data<AppData>,
@@ -120,9 +639,313 @@ async fn post_welford_exprs(
     Json(WelfordExprs::new(&params))
 }
 
+/// The OpenAPI spec of the running server, with the actual prefix.
+async fn get_openapi(spec: Data<OpenApi>) -> HttpResponse {
+    HttpResponse::Ok().json(spec.as_ref())
+}
+
+/// A Swagger UI page for the spec. The UI assets are loaded from a CDN.
+async fn get_docs() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(include_str!("swagger_ui.html"))
+}
+
 #[derive(Serialize, JsonSchema, ApiComponent)]
 struct Success(&'static str);
 
+#[derive(Serialize, JsonSchema, ApiComponent)]
+struct Health {
+    status: &'static str,
+    /// What the last tick of the processor did, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_tick: Option<TickReport>,
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: String,
+}
+
+#[derive(Deserialize, JsonSchema, ApiComponent, Debug)]
+struct CachedQueryPath {
+    name: String,
+}
+
+#[derive(Deserialize, JsonSchema, ApiComponent, Debug)]
+struct DefaultConfigQuery {
+    /// The version of the default config (the latest when unset).
+    version: Option<u32>,
+}
+
+#[derive(Deserialize, JsonSchema, ApiComponent, Debug)]
+struct DebugTracePath {
+    trace_id: String,
+}
+
+#[derive(Serialize, JsonSchema, ApiComponent)]
+struct CachedQueryResult {
+    /// The time of the last successful refresh.
+    updated: DateTime<Utc>,
+    /// Seconds since the last successful refresh.
+    age_seconds: f64,
+    /// Set when the last refresh failed.
+    stale: bool,
+    /// The prometheus query result.
+    data: serde_json::Value,
+}
+
+/// A partial config, merged into the current config following JSON
+/// merge patch semantics.
+#[derive(Deserialize, JsonSchema, ApiComponent, Debug)]
+#[serde(transparent)]
+struct ConfigPatch(serde_json::Value);
+
+#[derive(Serialize, JsonSchema, ApiComponent)]
+struct ConfigValidation {
+    /// Issues that do not make the config invalid, but probably do
+    /// not do what was intended.
+    warnings: Vec<String>,
+}
+
+/// A config update: the config and, optionally, the generation it
+/// is based on.
+#[derive(Deserialize, JsonSchema, ApiComponent, Debug)]
+struct ConfigUpdate {
+    /// Reject the update unless the config is still at this
+    /// generation.
+    expected_generation: Option<u64>,
+    #[serde(flatten)]
+    config: Config,
+}
+
+/// A config in the request body: JSON or, with a YAML content type,
+/// YAML.
+#[derive(Debug)]
+struct ConfigBody<T>(T);
+
+impl<T: DeserializeOwned + 'static> FromRequest for ConfigBody<T> {
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = std::result::Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let content_type = req
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok());
+        if content_type.is_some_and(is_yaml) {
+            let bytes = Bytes::from_request(req, payload);
+            Box::pin(async move {
+                let bytes = bytes.await?;
+                serde_yaml::from_slice(&bytes)
+                    .map(ConfigBody)
+                    .map_err(|err| {
+                        let res = HttpResponse::BadRequest().json(ErrorBody {
+                            error: err.to_string(),
+                        });
+                        InternalError::from_response(err, res).into()
+                    })
+            })
+        } else {
+            let json = Json::<T>::from_request(req, payload);
+            Box::pin(async move { Ok(ConfigBody(json.await?.into_inner())) })
+        }
+    }
+}
+
+impl<T: apistos::ApiComponent> apistos::ApiComponent for ConfigBody<T> {
+    fn child_schemas() -> Vec<(String, apistos::reference_or::ReferenceOr<apistos::Schema>)> {
+        T::child_schemas()
+    }
+
+    fn schema() -> Option<(String, apistos::reference_or::ReferenceOr<apistos::Schema>)> {
+        T::schema()
+    }
+
+    fn request_body() -> Option<apistos::paths::RequestBody> {
+        let (name, _) = Self::schema()?;
+        Some(apistos::paths::RequestBody {
+            content: json_and_yaml(&name),
+            required: Some(true),
+            ..Default::default()
+        })
+    }
+}
+
+/// The config generation in the If-Match header, if any. `*` matches
+/// any generation.
+#[derive(Debug)]
+struct IfMatch(Option<u64>);
+
+impl FromRequest for IfMatch {
+    type Error = WebError;
+    type Future = std::future::Ready<std::result::Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let generation = match req.headers().get(IF_MATCH) {
+            Some(value) => value
+                .to_str()
+                .map_err(|_| {
+                    Error::InvalidGeneration(String::from_utf8_lossy(value.as_bytes()).into_owned())
+                })
+                .and_then(parse_generation_etag)
+                .map_err(WebError::Generation),
+            None => Ok(None),
+        };
+        std::future::ready(generation.map(IfMatch))
+    }
+}
+
+impl apistos::ApiComponent for IfMatch {
+    fn child_schemas() -> Vec<(String, apistos::reference_or::ReferenceOr<apistos::Schema>)> {
+        Vec::new()
+    }
+
+    fn schema() -> Option<(String, apistos::reference_or::ReferenceOr<apistos::Schema>)> {
+        None
+    }
+}
+
+/// A response with the config generation as the ETag.
+struct WithGeneration<T>(u64, T);
+
+impl<T: Responder> Responder for WithGeneration<T> {
+    type Body = EitherBody<T::Body>;
+
+    fn respond_to(self, req: &HttpRequest) -> HttpResponse<Self::Body> {
+        self.1
+            .customize()
+            .insert_header((ETAG, generation_etag(self.0)))
+            .respond_to(req)
+    }
+}
+
+impl<T: apistos::ApiComponent> apistos::ApiComponent for WithGeneration<T> {
+    fn child_schemas() -> Vec<(String, apistos::reference_or::ReferenceOr<apistos::Schema>)> {
+        T::child_schemas()
+    }
+
+    fn schema() -> Option<(String, apistos::reference_or::ReferenceOr<apistos::Schema>)> {
+        T::schema()
+    }
+
+    fn responses(content_type: Option<String>) -> Option<apistos::paths::Responses> {
+        T::responses(content_type)
+    }
+}
+
+/// A response in YAML when requested with the Accept header, and in
+/// JSON otherwise.
+struct Negotiated<T>(T);
+
+impl<T: Serialize> Responder for Negotiated<T> {
+    type Body = BoxBody;
+
+    fn respond_to(self, req: &HttpRequest) -> HttpResponse<Self::Body> {
+        let accept = req
+            .headers()
+            .get(ACCEPT)
+            .and_then(|value| value.to_str().ok());
+        if accept.is_some_and(|accept| accept.split(',').any(is_yaml)) {
+            Yaml(self.0).respond_to(req).map_into_boxed_body()
+        } else {
+            JsonSchemaBody(self.0).respond_to(req)
+        }
+    }
+}
+
+impl<T: apistos::ApiComponent> apistos::ApiComponent for Negotiated<T> {
+    fn child_schemas() -> Vec<(String, apistos::reference_or::ReferenceOr<apistos::Schema>)> {
+        T::child_schemas()
+    }
+
+    fn schema() -> Option<(String, apistos::reference_or::ReferenceOr<apistos::Schema>)> {
+        T::schema()
+    }
+
+    fn responses(_content_type: Option<String>) -> Option<apistos::paths::Responses> {
+        let (name, _) = Self::schema()?;
+        Some(apistos::paths::Responses {
+            responses: std::collections::BTreeMap::from([(
+                StatusCode::OK.as_str().to_string(),
+                apistos::reference_or::ReferenceOr::Object(apistos::paths::Response {
+                    content: json_and_yaml(&name),
+                    ..Default::default()
+                }),
+            )]),
+            ..Default::default()
+        })
+    }
+}
+
+/// Media types for a schema sent as JSON or YAML.
+fn json_and_yaml(name: &str) -> std::collections::BTreeMap<String, apistos::paths::MediaType> {
+    ["application/json", "application/yaml"]
+        .into_iter()
+        .map(|content_type| {
+            (
+                content_type.to_string(),
+                apistos::paths::MediaType {
+                    schema: Some(apistos::reference_or::ReferenceOr::Reference {
+                        _ref: format!("#/components/schemas/{name}"),
+                    }),
+                    ..Default::default()
+                },
+            )
+        })
+        .collect()
+}
+
+/// Whether a media type (from a Content-Type or Accept header) is YAML.
+fn is_yaml(media_type: &str) -> bool {
+    let essence = media_type.split(';').next().unwrap_or_default().trim();
+    ["application/yaml", "application/x-yaml", "text/yaml"]
+        .iter()
+        .any(|yaml| essence.eq_ignore_ascii_case(yaml))
+}
+
+/// An encoded baseline bundle.
+struct BundleData(Vec<u8>);
+
+impl Responder for BundleData {
+    type Body = BoxBody;
+
+    fn respond_to(self, _: &HttpRequest) -> HttpResponse<Self::Body> {
+        HttpResponse::Ok()
+            .content_type("application/octet-stream")
+            .body(self.0)
+    }
+}
+
+impl FromRequest for BundleData {
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = std::result::Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let bytes = Bytes::from_request(req, payload);
+        Box::pin(async move { Ok(BundleData(bytes.await?.to_vec())) })
+    }
+}
+
+impl apistos::ApiComponent for BundleData {
+    fn child_schemas() -> Vec<(String, apistos::reference_or::ReferenceOr<apistos::Schema>)> {
+        Vec::new()
+    }
+
+    fn schema() -> Option<(String, apistos::reference_or::ReferenceOr<apistos::Schema>)> {
+        Some((
+            String::from("BaselineBundle"),
+            apistos::reference_or::ReferenceOr::Object(schemars::schema::Schema::Object(
+                schemars::schema::SchemaObject {
+                    instance_type: Some(schemars::schema::InstanceType::String.into()),
+                    format: Some(String::from("binary")),
+                    ..Default::default()
+                },
+            )),
+        ))
+    }
+}
+
 #[derive(Serialize, JsonSchema)]
 struct Yaml<T>(T);
 
@@ -154,229 +977,862 @@ impl Display for YamlSerializeErr {
 
 impl ResponseError for YamlSerializeErr {}
 
-// Adapted from auto-derived.
-#[automatically_derived]
+// The schema of the wrapper is kept, for a stable component name.
 impl<T: JsonSchema> apistos::ApiComponent for Yaml<T> {
     fn child_schemas() -> Vec<(String, apistos::reference_or::ReferenceOr<apistos::Schema>)> {
-        let settings = schemars::gen::SchemaSettings::openapi3();
-        let gen = settings.into_generator();
-        let schema: apistos::RootSchema = gen.into_root_schema_for::<Self>();
-        let mut schemas: Vec<(String, apistos::reference_or::ReferenceOr<apistos::Schema>)> =
-            vec![];
-        for (def_name, mut def) in schema.definitions {
-            match &mut def {
-                schemars::schema::Schema::Bool(_) => {}
-
-                schemars::schema::Schema::Object(schema) => {
-                    if let Some(one_of) = schema.subschemas.as_mut().and_then(|s| s.one_of.as_mut())
-                    {
-                        for s in &mut *one_of {
-                            match s {
-                                schemars::schema::Schema::Bool(_) => {}
-
-                                schemars::schema::Schema::Object(sch_obj) => {
-                                    if let Some(obj) = sch_obj.object.as_mut() {
-                                        if obj.properties.len() == 1 {
-                                            if let Some((prop_name, _)) =
-                                                obj.properties.first_key_value()
-                                            {
-                                                match sch_obj.metadata.as_mut() {
-                                                    None => {
-                                                        sch_obj.metadata = Some(Box::new(
-                                                            schemars::schema::Metadata {
-                                                                title: Some(prop_name.clone()),
-                                                                ..Default::default()
-                                                            },
-                                                        ));
-                                                    }
-                                                    Some(m) => {
-                                                        m.title = m
-                                                            .title
-                                                            .clone()
-                                                            .or_else(|| Some(prop_name.clone()))
-                                                    }
-                                                };
-                                            }
-                                        } else if let Some(enum_values) =
-                                            obj.properties.iter_mut().find_map(|(_, p)| match p {
-                                                schemars::schema::Schema::Bool(_) => None,
-                                                schemars::schema::Schema::Object(sch_obj) => {
-                                                    sch_obj.enum_values.as_mut()
-                                                }
-                                            })
-                                        {
-                                            if enum_values.len() == 1 {
-                                                if let Some(schemars::_serde_json::Value::String(
-                                                    prop_name,
-                                                )) = enum_values.first()
-                                                {
-                                                    match sch_obj.metadata.as_mut() {
-                                                        None => {
-                                                            sch_obj.metadata = Some(Box::new(
-                                                                schemars::schema::Metadata {
-                                                                    title: Some(prop_name.clone()),
-                                                                    ..Default::default()
-                                                                },
-                                                            ));
-                                                        }
-                                                        Some(m) => {
-                                                            m.title = m
-                                                                .title
-                                                                .clone()
-                                                                .or_else(|| Some(prop_name.clone()))
-                                                        }
-                                                    }
-                                                }
-                                            }
-                                        }
-                                    } else if let Some(enum_values) = sch_obj.enum_values.as_mut() {
-                                        if enum_values.len() == 1 {
-                                            if let Some(schemars::_serde_json::Value::String(
-                                                prop_name,
-                                            )) = enum_values.first()
-                                            {
-                                                match sch_obj.metadata.as_mut() {
-                                                    None => {
-                                                        sch_obj.metadata = Some(Box::new(
-                                                            schemars::schema::Metadata {
-                                                                title: Some(prop_name.clone()),
-                                                                ..Default::default()
-                                                            },
-                                                        ));
-                                                    }
-                                                    Some(m) => {
-                                                        m.title = m
-                                                            .title
-                                                            .clone()
-                                                            .or_else(|| Some(prop_name.clone()))
-                                                    }
-                                                }
-                                            }
-                                        }
-                                    };
-                                }
-                            }
-                        }
-                    }
-                }
+        schema_utils::child_schemas::<Self>()
+    }
+
+    fn schema() -> Option<(String, apistos::reference_or::ReferenceOr<apistos::Schema>)> {
+        schema_utils::schema::<Self>()
+    }
+}
+
+/// A JSON response for types that implement `JsonSchema` but not
+/// `ApiComponent`.
+struct JsonSchemaBody<T>(T);
+
+impl<T: Serialize> Responder for JsonSchemaBody<T> {
+    type Body = BoxBody;
+
+    fn respond_to(self, req: &HttpRequest) -> HttpResponse<Self::Body> {
+        Json(self.0).respond_to(req).map_into_boxed_body()
+    }
+}
+
+impl<T: JsonSchema> apistos::ApiComponent for JsonSchemaBody<T> {
+    fn child_schemas() -> Vec<(String, apistos::reference_or::ReferenceOr<apistos::Schema>)> {
+        schema_utils::child_schemas::<T>()
+    }
+
+    fn schema() -> Option<(String, apistos::reference_or::ReferenceOr<apistos::Schema>)> {
+        schema_utils::schema::<T>()
+    }
+}
+
+type WebResult<T> = std::result::Result<T, WebError>;
+
+#[derive(thiserror::Error, ApiErrorComponent, Debug)]
+#[openapi_error(
+    status(code = 400, description = "Invalid request"),
+    status(code = 404, description = "Not found"),
+    status(
+        code = 412,
+        description = "The config changed since the expected generation"
+    ),
+    status(code = 413, description = "Payload too large"),
+    status(code = 500, description = "Internal server error"),
+    status(code = 501, description = "Not available in this mode")
+)]
+enum WebError {
+    #[error("{0}")]
+    Config(ConfigError),
+    #[error("{0}")]
+    Export(BundleError),
+    #[error("{0}")]
+    Import(BundleError),
+    #[error("{0}")]
+    Processor(Error),
+    #[error("no cached result for query: {0}")]
+    NotCached(String),
+    #[error("trace not found: {0}")]
+    TraceNotFound(String),
+    #[error("unknown default config version: {0}")]
+    UnknownVersion(u32),
+    #[error("{0} not available in this mode")]
+    NotAvailable(&'static str),
+    #[error("{0} is not configured")]
+    NotConfigured(&'static str),
+    #[error("{0}")]
+    SchemaPush(String),
+    #[error("{0}")]
+    Generation(Error),
+    #[error("{0}")]
+    ConfigRejected(Error),
+    #[error("{0}")]
+    InvalidQuery(Error),
+    #[error("the config changed: the current generation is {}", .0.generation)]
+    ConfigConflict(Box<ConfigVersion>),
+    #[error("the processor did not respond within {0:?}")]
+    CommandTimeout(Duration),
+}
+
+impl ResponseError for WebError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            WebError::Import(BundleError::TooLarge(_)) => StatusCode::PAYLOAD_TOO_LARGE,
+            WebError::Config(_)
+            | WebError::Import(_)
+            | WebError::Generation(_)
+            | WebError::ConfigRejected(_)
+            | WebError::InvalidQuery(_) => StatusCode::BAD_REQUEST,
+            WebError::NotCached(_) | WebError::TraceNotFound(_) | WebError::UnknownVersion(_) => {
+                StatusCode::NOT_FOUND
             }
-            schemas.push((def_name, apistos::reference_or::ReferenceOr::Object(def)));
+            WebError::Export(_) | WebError::Processor(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            WebError::NotAvailable(_) | WebError::NotConfigured(_) => StatusCode::NOT_IMPLEMENTED,
+            WebError::SchemaPush(_) => StatusCode::BAD_GATEWAY,
+            WebError::ConfigConflict(_) => StatusCode::PRECONDITION_FAILED,
+            WebError::CommandTimeout(_) => StatusCode::GATEWAY_TIMEOUT,
         }
-        schemas
     }
-    fn schema() -> Option<(String, apistos::reference_or::ReferenceOr<apistos::Schema>)> {
-        let (name, schema) = {
-            let schema_name = <Self as schemars::JsonSchema>::schema_name();
-            let settings = schemars::gen::SchemaSettings::openapi3();
-            let gen = settings.into_generator();
-            let mut schema: apistos::RootSchema = gen.into_root_schema_for::<Self>();
-            if let Some(one_of) = schema
-                .schema
-                .subschemas
-                .as_mut()
-                .and_then(|s| s.one_of.as_mut())
-            {
-                for s in &mut *one_of {
-                    match s {
-                        schemars::schema::Schema::Bool(_) => {}
-
-                        schemars::schema::Schema::Object(sch_obj) => {
-                            if let Some(obj) = sch_obj.object.as_mut() {
-                                if obj.properties.len() == 1 {
-                                    if let Some((prop_name, _)) = obj.properties.first_key_value() {
-                                        match sch_obj.metadata.as_mut() {
-                                            None => {
-                                                sch_obj.metadata =
-                                                    Some(Box::new(schemars::schema::Metadata {
-                                                        title: Some(prop_name.clone()),
-                                                        ..Default::default()
-                                                    }));
-                                            }
-                                            Some(m) => {
-                                                m.title = m
-                                                    .title
-                                                    .clone()
-                                                    .or_else(|| Some(prop_name.clone()))
-                                            }
-                                        };
-                                    }
-                                } else if let Some(enum_values) =
-                                    obj.properties.iter_mut().find_map(|(_, p)| match p {
-                                        schemars::schema::Schema::Bool(_) => None,
-                                        schemars::schema::Schema::Object(sch_obj) => {
-                                            sch_obj.enum_values.as_mut()
-                                        }
-                                    })
-                                {
-                                    if enum_values.len() == 1 {
-                                        if let Some(schemars::_serde_json::Value::String(
-                                            prop_name,
-                                        )) = enum_values.first()
-                                        {
-                                            match sch_obj.metadata.as_mut() {
-                                                None => {
-                                                    sch_obj.metadata = Some(Box::new(
-                                                        schemars::schema::Metadata {
-                                                            title: Some(prop_name.clone()),
-                                                            ..Default::default()
-                                                        },
-                                                    ));
-                                                }
-                                                Some(m) => {
-                                                    m.title = m
-                                                        .title
-                                                        .clone()
-                                                        .or_else(|| Some(prop_name.clone()))
-                                                }
-                                            }
-                                        }
-                                    }
-                                }
-                            } else if let Some(enum_values) = sch_obj.enum_values.as_mut() {
-                                if enum_values.len() == 1 {
-                                    if let Some(schemars::_serde_json::Value::String(prop_name)) =
-                                        enum_values.first()
-                                    {
-                                        match sch_obj.metadata.as_mut() {
-                                            None => {
-                                                sch_obj.metadata =
-                                                    Some(Box::new(schemars::schema::Metadata {
-                                                        title: Some(prop_name.clone()),
-                                                        ..Default::default()
-                                                    }));
-                                            }
-                                            Some(m) => {
-                                                m.title = m
-                                                    .title
-                                                    .clone()
-                                                    .or_else(|| Some(prop_name.clone()))
-                                            }
-                                        }
-                                    }
-                                }
-                            };
-                        }
-                    }
-                }
+
+    /// Conflicts return the current config, so that the client can
+    /// merge its changes. Timeouts are reported as JSON, like the
+    /// replies they stand in for.
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            WebError::ConfigConflict(current) => HttpResponse::build(self.status_code())
+                .insert_header((ETAG, generation_etag(current.generation)))
+                .json(current),
+            WebError::CommandTimeout(_) => HttpResponse::build(self.status_code())
+                .json(serde_json::json!({ "error": self.to_string() })),
+            _ => HttpResponse::build(self.status_code())
+                .content_type("text/plain; charset=utf-8")
+                .body(self.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Mutex;
+
+    use actix_web::{test, web};
+    use clap::Parser;
+    use flate2::{write::GzEncoder, Compression};
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        config::{ConfigName, MetricName},
+        control::{check_generation, BoxFuture, RemoteProcessor},
+        ctl::{self, Cli, CtlError},
+        processor::cache::CachedResult,
+    };
+
+    #[derive(Default, Debug)]
+    struct MemoryStore(Mutex<ConfigVersion>);
+
+    impl ConfigStore for MemoryStore {
+        fn config(&self) -> BoxFuture<'_, Result<ConfigVersion>> {
+            Box::pin(std::future::ready(Ok(self.0.lock().unwrap().clone())))
+        }
+
+        fn set_config(&self, config: Config, expected: Option<u64>) -> BoxFuture<'_, Result<()>> {
+            let mut current = self.0.lock().unwrap();
+            let res = check_generation(expected, current.generation).map(|()| {
+                current.generation += 1;
+                current.config = config;
+            });
+            Box::pin(std::future::ready(res))
+        }
+    }
+
+    async fn echo(body: web::Json<serde_json::Value>) -> web::Json<serde_json::Value> {
+        body
+    }
+
+    #[actix_web::test]
+    async fn oversized_json_payload() {
+        let app = test::init_service(
+            App::new()
+                .app_data(json_config(1024))
+                .service(
+                    web::resource("/config")
+                        .app_data(json_config(16))
+                        .route(web::post().to(echo)),
+                )
+                .route("/other", web::post().to(echo)),
+        )
+        .await;
+        let body = json!({ "value": "x".repeat(32) });
+
+        let req = test::TestRequest::post()
+            .uri("/other")
+            .set_json(&body)
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let req = test::TestRequest::post()
+            .uri("/config")
+            .set_json(&body)
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(res.headers().get(CONTENT_TYPE).unwrap(), "application/json");
+        let error: serde_json::Value = test::read_body_json(res).await;
+        assert!(error["error"].is_string());
+    }
+
+    #[actix_web::test]
+    async fn invalid_json_payload() {
+        let app = test::init_service(
+            App::new()
+                .app_data(json_config(1024))
+                .route("/", web::post().to(echo)),
+        )
+        .await;
+        let req = test::TestRequest::post()
+            .uri("/")
+            .insert_header((CONTENT_TYPE, "application/json"))
+            .set_payload("{")
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let error: serde_json::Value = test::read_body_json(res).await;
+        assert!(error["error"].is_string());
+    }
+
+    #[actix_web::test]
+    async fn web_mode_against_processor_mode() {
+        let store = Arc::new(MemoryStore::default());
+        let processor_args = Args::parse_from(["engine", "--mode=processor", "--no-access-log"]);
+        let processor_data = Data::new(AppData {
+            config: Some(store.clone()),
+            processor: None,
+            info: EngineInfo::new(&processor_args),
+            command_timeout: Duration::from_millis(processor_args.command_timeout_ms),
+        });
+        let server_args = processor_args.clone();
+        let server = HttpServer::new(move || web_server!()(&server_args, Some(&processor_data)).0)
+            .workers(1)
+            .bind("127.0.0.1:0")
+            .unwrap();
+        let addr = server.addrs()[0];
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let processor_url = format!("http://{addr}{}/", processor_args.prefix);
+        let web_args = Args::parse_from(["engine", "--mode=web", "--no-access-log"]);
+        let web_data = Data::new(AppData {
+            config: Some(Arc::new(RemoteProcessor::new(
+                processor_url.parse().unwrap(),
+            ))),
+            processor: None,
+            info: EngineInfo::new(&web_args),
+            command_timeout: Duration::from_millis(web_args.command_timeout_ms),
+        });
+        let app = test::init_service(web_server!()(&web_args, Some(&web_data)).0).await;
+        let uri = |path: &str| format!("{}/{path}", web_args.prefix);
+
+        let req = test::TestRequest::get().uri(&uri("config")).to_request();
+        let config: Config = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(config, Config::default());
+
+        let config = Config {
+            max_series: Some(100),
+            ..Config::default()
+        };
+        let req = test::TestRequest::post()
+            .uri(&uri("config"))
+            .set_json(&config)
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(store.0.lock().unwrap().config, config);
+
+        let req = test::TestRequest::get().uri(&uri("status")).to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::NOT_IMPLEMENTED);
+        let req = test::TestRequest::post()
+            .uri(&uri("prometheus-schema/push"))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::NOT_IMPLEMENTED);
+
+        let client = reqwest::Client::new();
+        let res = client
+            .get(format!("{processor_url}label-values"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::NOT_FOUND);
+        let res = client
+            .get(format!("{processor_url}health"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+
+        handle.stop(true).await;
+    }
+
+    #[actix_web::test]
+    async fn yaml_config() {
+        let store = Arc::new(MemoryStore::default());
+        let args = Args::parse_from(["engine", "--no-access-log"]);
+        let data = Data::new(AppData {
+            config: Some(store.clone()),
+            processor: None,
+            info: EngineInfo::new(&args),
+            command_timeout: Duration::from_millis(args.command_timeout_ms),
+        });
+        let app = test::init_service(web_server!()(&args, Some(&data)).0).await;
+        let uri = |path: &str| format!("{}/{path}", args.prefix);
+
+        let req = test::TestRequest::get()
+            .uri(&uri("config/default"))
+            .insert_header((ACCEPT, "application/yaml"))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers().get(CONTENT_TYPE).unwrap(), "application/yaml");
+        let body = test::read_body(res).await;
+        let config: Config = serde_yaml::from_slice(&body).unwrap();
+        assert_eq!(config, Config::default());
+
+        let req = test::TestRequest::get()
+            .uri(&uri("config/default?version=1"))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let config: Config = test::read_body_json(res).await;
+        assert_eq!(config.trace, TraceConfig::default_version(1).unwrap());
+        assert_ne!(config, Config::default());
+
+        let req = test::TestRequest::get()
+            .uri(&uri("config/default?version=99"))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        let config = Config {
+            max_series: Some(100),
+            ..Config::default()
+        };
+        let req = test::TestRequest::post()
+            .uri(&uri("config"))
+            .insert_header((CONTENT_TYPE, "application/yaml"))
+            .set_payload(serde_yaml::to_string(&config).unwrap())
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(store.0.lock().unwrap().config, config);
+
+        // JSON without an Accept header.
+        let req = test::TestRequest::get().uri(&uri("config")).to_request();
+        let current: Config = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(current, config);
+
+        let req = test::TestRequest::post()
+            .uri(&uri("config"))
+            .insert_header((CONTENT_TYPE, "application/yaml"))
+            .set_payload("max_series: [")
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let error: serde_json::Value = test::read_body_json(res).await;
+        assert!(error["error"].is_string());
+    }
+
+    #[actix_web::test]
+    async fn config_validation() {
+        let store = Arc::new(MemoryStore::default());
+        let args = Args::parse_from(["engine", "--no-access-log"]);
+        let data = Data::new(AppData {
+            config: Some(store.clone()),
+            processor: None,
+            info: EngineInfo::new(&args),
+            command_timeout: Duration::from_millis(args.command_timeout_ms),
+        });
+        let app = test::init_service(web_server!()(&args, Some(&data)).0).await;
+        let uri = |path: &str| format!("{}/{path}", args.prefix);
+
+        let mut config = Config::default();
+        let rule = config.trace.rules[0][0].clone();
+        config.trace.rules.push(vec![rule.clone(), rule]);
+        let req = test::TestRequest::post()
+            .uri(&uri("config/validate"))
+            .set_json(&config)
+            .to_request();
+        let res: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(res["warnings"].as_array().unwrap().len(), 1, "{res}");
+        // The config is not applied.
+        assert_eq!(store.0.lock().unwrap().config, Config::default());
+
+        config.trace.rules[0][0].config = ConfigName::new("unknown");
+        let req = test::TestRequest::post()
+            .uri(&uri("config/validate"))
+            .set_json(&config)
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn reject_invalid_metric_name() {
+        let store = Arc::new(MemoryStore::default());
+        let args = Args::parse_from(["engine", "--no-access-log"]);
+        let data = Data::new(AppData {
+            config: Some(store.clone()),
+            processor: None,
+            info: EngineInfo::new(&args),
+            command_timeout: Duration::from_millis(args.command_timeout_ms),
+        });
+        let app = test::init_service(web_server!()(&args, Some(&data)).0).await;
+
+        let mut config = Config {
+            max_series: Some(100),
+            ..Config::default()
+        };
+        let metrics = &mut config
+            .trace
+            .configs
+            .get_mut(&ConfigName::new("default"))
+            .unwrap()
+            .metrics;
+        let metric = metrics.values().next().unwrap().clone();
+        metrics.insert(MetricName::new("error rate"), metric);
+        let req = test::TestRequest::post()
+            .uri(&format!("{}/config", args.prefix))
+            .set_json(&config)
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let body = test::read_body(res).await;
+        assert_eq!(body, "invalid metric name: error rate");
+        assert_eq!(store.0.lock().unwrap().config, Config::default());
+    }
+
+    #[actix_web::test]
+    async fn config_update_at_generation() {
+        let store = Arc::new(MemoryStore(Mutex::new(ConfigVersion {
+            generation: 1,
+            config: Config::default(),
+        })));
+        let args = Args::parse_from(["engine", "--no-access-log"]);
+        let data = Data::new(AppData {
+            config: Some(store.clone()),
+            processor: None,
+            info: EngineInfo::new(&args),
+            command_timeout: Duration::from_millis(args.command_timeout_ms),
+        });
+        let app = test::init_service(web_server!()(&args, Some(&data)).0).await;
+        let uri = format!("{}/config", args.prefix);
+
+        let req = test::TestRequest::get().uri(&uri).to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.headers().get(ETAG).unwrap(), "\"1\"");
+        let current: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(current["generation"], 1);
+
+        let config = Config {
+            max_series: Some(100),
+            ..Config::default()
+        };
+        let req = test::TestRequest::post()
+            .uri(&uri)
+            .insert_header((IF_MATCH, "\"1\""))
+            .set_json(&config)
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(store.0.lock().unwrap().generation, 2);
+
+        // The generation can also be given in the body.
+        let mut body = serde_json::to_value(&config).unwrap();
+        body["expected_generation"] = json!(2);
+        let req = test::TestRequest::post()
+            .uri(&uri)
+            .set_json(&body)
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let req = test::TestRequest::get().uri(&uri).to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.headers().get(ETAG).unwrap(), "\"3\"");
+        let current: ConfigVersion = test::read_body_json(res).await;
+        assert_eq!(current.generation, 3);
+        assert_eq!(current.config, config);
+    }
+
+    #[actix_web::test]
+    async fn stale_config_update() {
+        let store = Arc::new(MemoryStore(Mutex::new(ConfigVersion {
+            generation: 1,
+            config: Config::default(),
+        })));
+        let args = Args::parse_from(["engine", "--no-access-log"]);
+        let data = Data::new(AppData {
+            config: Some(store.clone()),
+            processor: None,
+            info: EngineInfo::new(&args),
+            command_timeout: Duration::from_millis(args.command_timeout_ms),
+        });
+        let app = test::init_service(web_server!()(&args, Some(&data)).0).await;
+        let uri = format!("{}/config", args.prefix);
+        let config = Config {
+            max_series: Some(100),
+            ..Config::default()
+        };
+
+        let req = test::TestRequest::post()
+            .uri(&uri)
+            .insert_header((IF_MATCH, "\"0\""))
+            .set_json(&config)
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::PRECONDITION_FAILED);
+        assert_eq!(res.headers().get(ETAG).unwrap(), "\"1\"");
+        let current: ConfigVersion = test::read_body_json(res).await;
+        assert_eq!(current.generation, 1);
+        assert_eq!(current.config, Config::default());
+
+        let mut body = serde_json::to_value(&config).unwrap();
+        body["expected_generation"] = json!(0);
+        let req = test::TestRequest::post()
+            .uri(&uri)
+            .set_json(&body)
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::PRECONDITION_FAILED);
+
+        let req = test::TestRequest::post()
+            .uri(&uri)
+            .insert_header((IF_MATCH, "one"))
+            .set_json(&config)
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        assert_eq!(store.0.lock().unwrap().generation, 1);
+        assert_eq!(store.0.lock().unwrap().config, Config::default());
+    }
+
+    #[actix_web::test]
+    async fn config_update_without_generation() {
+        let store = Arc::new(MemoryStore(Mutex::new(ConfigVersion {
+            generation: 1,
+            config: Config::default(),
+        })));
+        let args = Args::parse_from(["engine", "--no-access-log"]);
+        let data = Data::new(AppData {
+            config: Some(store.clone()),
+            processor: None,
+            info: EngineInfo::new(&args),
+            command_timeout: Duration::from_millis(args.command_timeout_ms),
+        });
+        let app = test::init_service(web_server!()(&args, Some(&data)).0).await;
+        let uri = format!("{}/config", args.prefix);
+        let config = Config {
+            max_series: Some(100),
+            ..Config::default()
+        };
+        for if_match in [None, Some("*")] {
+            let req = test::TestRequest::post()
+                .uri(&uri)
+                .set_json(&config)
+                .pipe(|req| match if_match {
+                    Some(if_match) => req.insert_header((IF_MATCH, if_match)),
+                    None => req,
+                })
+                .to_request();
+            let res = test::call_service(&app, req).await;
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+        assert_eq!(store.0.lock().unwrap().generation, 3);
+        assert_eq!(store.0.lock().unwrap().config, config);
+    }
+
+    /// A processor taking this long to handle commands, as when its
+    /// task is stuck.
+    #[derive(Debug)]
+    struct SlowProcessor(Duration);
+
+    impl ProcessorControl for SlowProcessor {
+        fn status(&self) -> Status {
+            unimplemented!()
+        }
+
+        fn starting(&self) -> bool {
+            false
+        }
+
+        fn last_tick(&self) -> Option<TickReport> {
+            None
+        }
+
+        fn export_baselines(&self) -> BoxFuture<'_, Result<BaselineBundle>> {
+            unimplemented!()
+        }
+
+        fn import_baselines(&self, _bundle: BaselineBundle) -> BoxFuture<'_, Result<ImportReport>> {
+            Box::pin(async move {
+                tokio::time::sleep(self.0).await;
+                Ok(ImportReport::new(Vec::new()))
+            })
+        }
+
+        fn label_values(
+            &self,
+            _query: LabelValuesQuery,
+        ) -> BoxFuture<'_, Result<LabelValuesReport>> {
+            unimplemented!()
+        }
+
+        fn debug_trace<'a>(
+            &'a self,
+            _trace_id: &'a str,
+        ) -> BoxFuture<'a, Result<Option<TraceDebugReport>>> {
+            unimplemented!()
+        }
+
+        fn cached_query(&self, _name: &str) -> Option<CachedResult> {
+            None
+        }
+
+        fn resolve_object(
+            &self,
+            _request: ResolveObjectRequest,
+        ) -> BoxFuture<'_, Result<ResolvedObject>> {
+            unimplemented!()
+        }
+
+        fn top_movers(&self, _query: TopMoversQuery) -> BoxFuture<'_, Result<TopMovers>> {
+            unimplemented!()
+        }
+
+        fn push_schema(&self) -> BoxFuture<'_, Result<SchemaPushStatus>> {
+            unimplemented!()
+        }
+    }
+
+    #[actix_web::test]
+    async fn command_timeout() {
+        let args = Args::parse_from(["engine", "--no-access-log", "--command-timeout-ms=50"]);
+        let bundle = BaselineBundle::new(Utc::now(), Vec::new())
+            .encode()
+            .unwrap();
+        for (delay, status) in [
+            (Duration::ZERO, StatusCode::OK),
+            (Duration::from_secs(5), StatusCode::GATEWAY_TIMEOUT),
+        ] {
+            let data = Data::new(AppData {
+                config: None,
+                processor: Some(Arc::new(SlowProcessor(delay))),
+                info: EngineInfo::new(&args),
+                command_timeout: Duration::from_millis(args.command_timeout_ms),
+            });
+            let app = test::init_service(web_server!()(&args, Some(&data)).0).await;
+            let req = test::TestRequest::post()
+                .uri(&format!("{}/baselines/import", args.prefix))
+                .set_payload(bundle.clone())
+                .to_request();
+            let res = test::call_service(&app, req).await;
+            assert_eq!(res.status(), status);
+            if status == StatusCode::GATEWAY_TIMEOUT {
+                let body: serde_json::Value = test::read_body_json(res).await;
+                assert_eq!(body["error"], "the processor did not respond within 50ms");
             }
-            (
-                schema_name,
-                apistos::reference_or::ReferenceOr::Object(schemars::schema::Schema::Object(
-                    schema.schema,
-                )),
+        }
+    }
+
+    #[actix_web::test]
+    async fn oversized_baseline_bundle() {
+        let args = Args::parse_from(["engine", "--no-access-log", "--max-baseline-size=4096"]);
+        let data = Data::new(AppData {
+            config: None,
+            processor: Some(Arc::new(SlowProcessor(Duration::ZERO))),
+            info: EngineInfo::new(&args),
+            command_timeout: Duration::from_millis(args.command_timeout_ms),
+        });
+        let app = test::init_service(web_server!()(&args, Some(&data)).0).await;
+
+        // An unknown field padded past the limit, which compresses to
+        // far less than it.
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        ciborium::into_writer(&json!({ "padding": "0".repeat(8192) }), &mut encoder).unwrap();
+        let bomb = encoder.finish().unwrap();
+        let bundle = BaselineBundle::new(Utc::now(), Vec::new())
+            .encode()
+            .unwrap();
+
+        for (payload, status) in [
+            (bundle, StatusCode::OK),
+            (bomb, StatusCode::PAYLOAD_TOO_LARGE),
+        ] {
+            let req = test::TestRequest::post()
+                .uri(&format!("{}/baselines/import", args.prefix))
+                .set_payload(payload)
+                .to_request();
+            let res = test::call_service(&app, req).await;
+            assert_eq!(res.status(), status);
+        }
+    }
+
+    #[test]
+    fn json_yaml_round_trip() {
+        let config = Config {
+            trace: Config::default().trace.with_request_path_relations(),
+            max_series: Some(1000),
+            ..Config::default()
+        };
+        let json = serde_json::to_string(&config).unwrap();
+        let yaml = serde_yaml::to_string(&serde_json::from_str::<Config>(&json).unwrap()).unwrap();
+        let config_from_yaml = serde_yaml::from_str::<Config>(&yaml).unwrap();
+        assert_eq!(config_from_yaml, config);
+        assert_eq!(serde_json::to_string(&config_from_yaml).unwrap(), json);
+    }
+
+    #[actix_web::test]
+    async fn top_movers_limits() {
+        let args = Args::parse_from(["engine", "--no-access-log"]);
+        let data = Data::new(AppData {
+            config: None,
+            processor: None,
+            info: EngineInfo::new(&args),
+            command_timeout: Duration::from_millis(args.command_timeout_ms),
+        });
+        let app = test::init_service(web_server!()(&args, Some(&data)).0).await;
+        let uri = |params: &str| {
+            format!(
+                "{}/top-movers?metric=duration&immediate=15m&reference=30d&{params}",
+                args.prefix
             )
         };
-        Some((name, schema))
+
+        for (params, status) in [
+            ("top=0", StatusCode::BAD_REQUEST),
+            ("lookback=14d", StatusCode::BAD_REQUEST),
+            ("top=10&lookback=1h", StatusCode::NOT_IMPLEMENTED),
+        ] {
+            let req = test::TestRequest::get().uri(&uri(params)).to_request();
+            let res = test::call_service(&app, req).await;
+            assert_eq!(res.status(), status, "{params}");
+        }
     }
-}
 
-// type WebResult<T> = std::result::Result<T, WebError>;
+    #[actix_web::test]
+    async fn openapi_spec() {
+        let args = Args::parse_from(["engine", "--no-access-log", "--prefix=/custom"]);
+        let data = Data::new(AppData {
+            config: None,
+            processor: None,
+            info: EngineInfo::new(&args),
+            command_timeout: Duration::from_millis(args.command_timeout_ms),
+        });
+        let app = test::init_service(web_server!()(&args, Some(&data)).0).await;
+
+        let req = test::TestRequest::get()
+            .uri("/custom/openapi.json")
+            .to_request();
+        let spec: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert!(spec["openapi"].as_str().unwrap().starts_with("3.0."));
+        assert_eq!(spec["info"]["title"], "Jaeger Anomaly Detection API");
+        let paths = spec["paths"].as_object().unwrap();
+        assert!(paths.contains_key("/custom/config"), "{:?}", paths.keys());
+        assert!(paths.keys().all(|path| path.starts_with("/custom/")));
+        assert!(!paths.contains_key("/custom/openapi.json"));
+        paths
+            .values()
+            .flat_map(|item| item.as_object().unwrap())
+            .filter(|(method, _)| ["get", "post", "patch"].contains(&method.as_str()))
+            .for_each(|(method, op)| {
+                assert!(op["summary"].is_string(), "{method}: {op}");
+                assert!(op["description"].is_string(), "{method}: {op}");
+            });
+
+        let req = test::TestRequest::get().uri("/custom/docs").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = test::read_body(res).await;
+        assert!(std::str::from_utf8(&body).unwrap().contains("openapi.json"));
+    }
+
+    #[actix_web::test]
+    async fn jadctl_against_engine() {
+        let store = Arc::new(MemoryStore(Mutex::new(ConfigVersion {
+            generation: 1,
+            config: Config::default(),
+        })));
+        let args = Args::parse_from(["engine", "--no-access-log"]);
+        let data = Data::new(AppData {
+            config: Some(store.clone()),
+            processor: None,
+            info: EngineInfo::new(&args),
+            command_timeout: Duration::from_millis(args.command_timeout_ms),
+        });
+        let server_args = args.clone();
+        let server = HttpServer::new(move || web_server!()(&server_args, Some(&data)).0)
+            .workers(1)
+            .bind("127.0.0.1:0")
+            .unwrap();
+        let addr = server.addrs()[0];
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let url = format!("http://{addr}{}", args.prefix);
+        let jadctl = |argv: &[&str]| {
+            let cli = Cli::parse_from(["jadctl", "--url", url.as_str()].iter().chain(argv));
+            async move {
+                let mut out = Vec::new();
+                let res = ctl::run(&cli, &mut out, false).await;
+                (res, String::from_utf8(out).unwrap())
+            }
+        };
 
-// #[derive(thiserror::Error, Debug)]
-// enum WebError {}
+        let (res, out) = jadctl(&["--json", "config", "get"]).await;
+        res.unwrap();
+        let mut config = serde_json::from_str::<serde_json::Value>(&out).unwrap();
+        assert_eq!(config["generation"], 1);
+        config["max_series"] = json!(100);
+        let file = std::env::temp_dir().join(format!("jadctl-{}.yaml", std::process::id()));
+        std::fs::write(&file, serde_yaml::to_string(&config).unwrap()).unwrap();
+        let file = file.to_str().unwrap();
 
-// impl ResponseError for WebError {
-//     fn status_code(&self) -> StatusCode {
-//         StatusCode::INTERNAL_SERVER_ERROR
-//     }
-// }
+        let (res, out) = jadctl(&["--json", "config", "diff", file]).await;
+        res.unwrap();
+        let changes = serde_json::from_str::<serde_json::Value>(&out).unwrap();
+        assert_eq!(
+            changes,
+            json!([{ "change": "changed", "path": "/max_series", "old": null, "new": 100 }])
+        );
+
+        let (res, out) = jadctl(&["config", "set", file]).await;
+        res.unwrap();
+        assert_eq!(out, "config updated to generation 2\n");
+        assert_eq!(store.0.lock().unwrap().config.max_series, Some(100));
+
+        // The file is at generation 1, the config at generation 2.
+        let e = jadctl(&["config", "set", file]).await.0.unwrap_err();
+        assert!(
+            matches!(
+                e,
+                CtlError::Conflict {
+                    expected: 1,
+                    current: 2
+                }
+            ),
+            "{e}"
+        );
+        assert_eq!(e.exit_code(), 3);
+        jadctl(&["config", "set", "--force", file]).await.0.unwrap();
+        assert_eq!(store.0.lock().unwrap().generation, 3);
+
+        let (res, out) = jadctl(&["config", "diff", file]).await;
+        res.unwrap();
+        assert_eq!(out, "no changes\n");
+
+        let (res, out) = jadctl(&["schema", "get"]).await;
+        res.unwrap();
+        assert!(!out.is_empty());
+
+        // Without a processor, the status is not available.
+        let e = jadctl(&["status"]).await.0.unwrap_err();
+        assert!(e.to_string().contains("processor"), "{e}");
+        assert_eq!(e.exit_code(), 4);
+
+        std::fs::remove_file(file).unwrap();
+        let e = jadctl(&["config", "set", file]).await.0.unwrap_err();
+        assert_eq!(e.exit_code(), 1);
+
+        handle.stop(true).await;
+        let e = jadctl(&["status"]).await.0.unwrap_err();
+        assert_eq!(e.exit_code(), 2);
+    }
+}
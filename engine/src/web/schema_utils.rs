@@ -0,0 +1,175 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+//! OpenAPI components for types that implement `JsonSchema` but not
+//! `ApiComponent`, with the fixups the apistos derive macro applies.
+
+use apistos::{reference_or::ReferenceOr, RootSchema, Schema};
+use schemars::{gen::SchemaSettings, schema::SchemaObject, JsonSchema};
+
+/// The definitions referenced by the schema of `T`.
+pub fn child_schemas<T: JsonSchema>() -> Vec<(String, ReferenceOr<Schema>)> {
+    root_schema_for::<T>()
+        .definitions
+        .into_iter()
+        .map(|(name, def)| (name, ReferenceOr::Object(def)))
+        .collect()
+}
+
+/// The named schema of `T`.
+pub fn schema<T: JsonSchema>() -> Option<(String, ReferenceOr<Schema>)> {
+    Some((
+        T::schema_name(),
+        ReferenceOr::Object(Schema::Object(root_schema_for::<T>().schema)),
+    ))
+}
+
+fn root_schema_for<T: JsonSchema>() -> RootSchema {
+    let mut schema = SchemaSettings::openapi3()
+        .into_generator()
+        .into_root_schema_for::<T>();
+    fix_oneof_titles(&mut schema);
+    schema
+}
+
+/// Title the `oneOf` variants of the schema and its definitions, so
+/// that clients generated from the spec get readable variant names:
+/// single-property objects (externally tagged enum variants) after
+/// their property and single-value enums (unit variants and internal
+/// tags) after their value. Existing titles are kept.
+pub fn fix_oneof_titles(schema: &mut RootSchema) {
+    fix_schema(&mut schema.schema);
+    schema.definitions.values_mut().for_each(|def| {
+        if let Schema::Object(def) = def {
+            fix_schema(def);
+        }
+    });
+}
+
+fn fix_schema(schema: &mut SchemaObject) {
+    let Some(one_of) = schema.subschemas.as_mut().and_then(|s| s.one_of.as_mut()) else {
+        return;
+    };
+    one_of.iter_mut().for_each(|variant| {
+        if let Schema::Object(variant) = variant {
+            if let Some(title) = variant_title(variant) {
+                variant.metadata().title.get_or_insert(title);
+            }
+        }
+    });
+}
+
+fn variant_title(variant: &SchemaObject) -> Option<String> {
+    match &variant.object {
+        Some(obj) if obj.properties.len() == 1 => obj.properties.keys().next().cloned(),
+        Some(obj) => obj
+            .properties
+            .values()
+            .find_map(|prop| match prop {
+                Schema::Object(prop) => prop.enum_values.as_ref(),
+                Schema::Bool(_) => None,
+            })
+            .and_then(|values| single_string(values)),
+        None => variant
+            .enum_values
+            .as_ref()
+            .and_then(|values| single_string(values)),
+    }
+}
+
+fn single_string(values: &[serde_json::Value]) -> Option<String> {
+    match values {
+        [serde_json::Value::String(value)] => Some(value.clone()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use apistos::{RootSchema, Schema};
+    use schemars::{gen::SchemaSettings, schema::SchemaObject, JsonSchema};
+    use serde::{Deserialize, Serialize};
+
+    use super::fix_oneof_titles;
+
+    #[derive(Serialize, Deserialize, JsonSchema)]
+    #[serde(rename_all = "snake_case")]
+    enum External {
+        Unit,
+        Newtype(u32),
+        Struct { a: u32 },
+    }
+
+    #[derive(Serialize, Deserialize, JsonSchema)]
+    #[serde(tag = "kind")]
+    enum Internal {
+        A { x: u32 },
+        B { y: u32 },
+    }
+
+    #[derive(Serialize, Deserialize, JsonSchema)]
+    struct Wrapper {
+        internal: Internal,
+    }
+
+    fn root_schema<T: JsonSchema>() -> RootSchema {
+        SchemaSettings::openapi3()
+            .into_generator()
+            .into_root_schema_for::<T>()
+    }
+
+    fn titles(schema: &SchemaObject) -> Vec<Option<&str>> {
+        schema
+            .subschemas
+            .as_ref()
+            .and_then(|s| s.one_of.as_ref())
+            .unwrap()
+            .iter()
+            .map(|variant| match variant {
+                Schema::Object(variant) => variant.metadata.as_ref()?.title.as_deref(),
+                Schema::Bool(_) => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn externally_tagged() {
+        let mut schema = root_schema::<External>();
+        assert_eq!(titles(&schema.schema), [None, None, None]);
+        fix_oneof_titles(&mut schema);
+        assert_eq!(
+            titles(&schema.schema),
+            [Some("unit"), Some("newtype"), Some("struct")]
+        );
+    }
+
+    #[test]
+    fn internally_tagged_definition() {
+        let mut schema = root_schema::<Wrapper>();
+        fix_oneof_titles(&mut schema);
+        let Schema::Object(internal) = &schema.definitions["Internal"] else {
+            panic!("expected a schema object");
+        };
+        assert_eq!(titles(internal), [Some("A"), Some("B")]);
+    }
+
+    #[test]
+    fn keeps_titles() {
+        let mut schema = root_schema::<External>();
+        if let Some(Schema::Object(variant)) = schema
+            .schema
+            .subschemas
+            .as_mut()
+            .and_then(|s| s.one_of.as_mut())
+            .and_then(|one_of| one_of.get_mut(1))
+        {
+            variant.metadata().title = Some(String::from("Custom"));
+        }
+        fix_oneof_titles(&mut schema);
+        assert_eq!(
+            titles(&schema.schema),
+            [Some("unit"), Some("Custom"), Some("struct")]
+        );
+    }
+}
@@ -4,7 +4,9 @@
 
 use std::{fmt::Display, str::FromStr};
 
+#[cfg(feature = "exprs")]
 use prometheus_core::LabelName;
+#[cfg(feature = "exprs")]
 use prometheus_expr::LabelSelector;
 use serde_with::{DeserializeFromStr, SerializeDisplay};
 
@@ -15,16 +17,27 @@ use crate::{Duration, WindowConfig};
 )]
 #[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 #[cfg_attr(feature = "tsify", derive(tsify::Tsify))]
+// Serialized as the display form of either interval.
+#[cfg_attr(any(feature = "schemars", feature = "tsify"), serde(untagged))]
 pub enum Interval {
     Immediate(ImmediateInterval),
     Reference(ReferenceInterval),
 }
 
 impl Interval {
+    pub fn window_config(self) -> WindowConfig {
+        match self {
+            Interval::Immediate(immediate_interval) => immediate_interval.window_config(),
+            Interval::Reference(reference_interval) => reference_interval.window_config(),
+        }
+    }
+
+    #[cfg(feature = "exprs")]
     pub(crate) fn labels(self) -> impl Iterator<Item = (LabelName, LabelSelector)> {
         std::iter::once(self.label())
     }
 
+    #[cfg(feature = "exprs")]
     fn label(self) -> (LabelName, LabelSelector) {
         match self {
             Interval::Immediate(immediate_interval) => immediate_interval.label(),
@@ -95,10 +108,12 @@ impl ReferenceInterval {
         }
     }
 
+    #[cfg(feature = "exprs")]
     pub(crate) fn labels(self) -> impl Iterator<Item = (LabelName, LabelSelector)> {
         std::iter::once(self.label())
     }
 
+    #[cfg(feature = "exprs")]
     fn label(self) -> (LabelName, LabelSelector) {
         (
             LabelName::new_static("reference"),
@@ -158,10 +173,12 @@ impl ImmediateInterval {
         }
     }
 
+    #[cfg(feature = "exprs")]
     pub(crate) fn labels(self) -> impl Iterator<Item = (LabelName, LabelSelector)> {
         std::iter::once(self.label())
     }
 
+    #[cfg(feature = "exprs")]
     fn label(self) -> (LabelName, LabelSelector) {
         (
             LabelName::new_static("immediate"),
@@ -15,6 +15,13 @@ pub struct WindowConfig {
     pub num_bins: usize,
 }
 
+impl WindowConfig {
+    /// The length of the window in seconds.
+    pub fn seconds(&self) -> i64 {
+        self.bin_width.to_time_delta().num_seconds() * self.num_bins as i64
+    }
+}
+
 impl Default for WindowConfig {
     fn default() -> Self {
         Self {
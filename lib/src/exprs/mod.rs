@@ -6,9 +6,9 @@ mod precalculated;
 mod welford;
 
 pub use precalculated::{
-    CombinationFactor, Combine, CombineScores, ItemOrRelation, NoCombine, OperationFilter,
-    OperationKey, OperationOrService, ServiceFilter, ServiceKey, SingleOrMultiple, TraceAggr,
-    TraceAggrKind, TraceAggrKindParseError, TraceExpr, TraceMetric, TraceMetricParseError,
-    TraceObject, TraceObjectBuilder,
+    CombinationFactor, Combine, CombineScores, CompareOp, ItemOrRelation, NoCombine,
+    OperationOrService, OverTimeFunc, OverTimeFuncParseError, SingleOrMultiple, TraceAggr,
+    TraceAggrError, TraceAggrKind, TraceAggrKindParseError, TraceExpr, TraceMetric,
+    TraceMetricParseError, TraceObject, TraceObjectBuilder,
 };
 pub use welford::{WelfordExprs, WelfordParams};
@@ -7,14 +7,23 @@ use std::{fmt::Display, marker::PhantomData, str::FromStr};
 use const_format::formatcp;
 use ordered_float::NotNan;
 use prometheus_core::{LabelName, MetricName};
-use prometheus_expr::{Expr, LabelSelector, MetricSelector, PromSelect, SelectItem};
+use prometheus_expr::{
+    Expr, LabelSelector, MetricSelector, Offset, PromDuration, PromSelect, SelectItem,
+};
 use serde::{Deserialize, Serialize};
 use serde_with::{with_prefix, DeserializeFromStr, SerializeDisplay};
 use unit::{FracPrefix, TimeUnit, Unit, NEUTRAL_UNIT};
 
-use crate::{anomaly_score::Interval, ImmediateInterval, ReferenceInterval};
+use crate::{
+    anomaly_score::Interval, Duration, ImmediateInterval, OperationFilter, OperationKey,
+    ReferenceInterval, ServiceFilter, ServiceKey,
+};
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Weight of the span count, as a fraction of the total, in stable
+/// top N score selections.
+const TOP_TIE_BREAKER: f64 = 1e-9;
+
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
 pub struct TraceExpr {
     metric: TraceMetric,
     aggr: TraceAggr,
@@ -55,6 +64,33 @@ impl TraceMetric {
             TraceMetric::ErrorRate => NEUTRAL_UNIT,
         }
     }
+
+    pub const fn display_name(&self) -> &'static str {
+        match self {
+            TraceMetric::Duration => "Duration",
+            TraceMetric::Busy => "Busy time",
+            TraceMetric::CallRate => "Call rate",
+            TraceMetric::ErrorRate => "Error rate",
+        }
+    }
+
+    /// The factor to multiply values with to get seconds, for time
+    /// metrics.
+    pub const fn scale_to_seconds(&self) -> Option<f64> {
+        match self {
+            TraceMetric::Duration => Some(1e-6),
+            TraceMetric::Busy => Some(1e-9),
+            TraceMetric::CallRate | TraceMetric::ErrorRate => None,
+        }
+    }
+}
+
+impl TryFrom<&MetricName> for TraceMetric {
+    type Error = TraceMetricParseError;
+
+    fn try_from(value: &MetricName) -> Result<Self, Self::Error> {
+        value.to_string().parse()
+    }
 }
 
 impl Display for TraceMetric {
@@ -89,13 +125,60 @@ pub enum TraceMetricParseError {
     Unknown,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Range functions to apply to a trace expression, e.g. to smooth
+/// scores in recording rules.
+#[derive(SerializeDisplay, DeserializeFromStr, PartialEq, Eq, Clone, Copy, Debug)]
+pub enum OverTimeFunc {
+    Avg,
+    Max,
+    Min,
+    Last,
+}
+
+impl Display for OverTimeFunc {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OverTimeFunc::Avg => write!(f, "avg_over_time"),
+            OverTimeFunc::Max => write!(f, "max_over_time"),
+            OverTimeFunc::Min => write!(f, "min_over_time"),
+            OverTimeFunc::Last => write!(f, "last_over_time"),
+        }
+    }
+}
+
+impl FromStr for OverTimeFunc {
+    type Err = OverTimeFuncParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "avg_over_time" => Ok(Self::Avg),
+            "max_over_time" => Ok(Self::Max),
+            "min_over_time" => Ok(Self::Min),
+            "last_over_time" => Ok(Self::Last),
+            _ => Err(OverTimeFuncParseError::Unknown),
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+#[non_exhaustive]
+pub enum OverTimeFuncParseError {
+    #[error("unknown range function")]
+    Unknown,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
 #[serde(tag = "aggr", rename_all = "snake_case")]
 pub enum TraceAggr {
     Count {
         interval: Interval,
         object: TraceObject<NoCombine>,
     },
+    /// The count per second over the interval.
+    Rate {
+        interval: Interval,
+        object: TraceObject<NoCombine>,
+    },
     Mean {
         interval: Interval,
         object: TraceObject<NoCombine>,
@@ -108,35 +191,145 @@ pub enum TraceAggr {
         immediate_interval: ImmediateInterval,
         reference_interval: ReferenceInterval,
         object: TraceObject<CombineScores>,
+        /// Only return the groups scoring above the threshold. The
+        /// comparison filters: groups at or below the threshold are
+        /// left out, rather than returned as 0. It applies after the
+        /// top N selection, so that the top N are still selected among
+        /// all groups.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        threshold: Option<NotNan<f64>>,
+    },
+    /// The immediate count relative to the reference count, scaled to
+    /// the length of the immediate window.
+    Sufficiency {
+        immediate_interval: ImmediateInterval,
+        reference_interval: ReferenceInterval,
+        object: TraceObject<NoCombine>,
+    },
+    /// The mean compared to the mean `offset` earlier (e.g. a week
+    /// ago), per group of the object. The top N selection applies to
+    /// the comparison.
+    MeanOffset {
+        interval: Interval,
+        offset: Duration,
+        #[serde(default)]
+        op: CompareOp,
+        object: TraceObject<NoCombine>,
     },
 }
 
+/// How a value is compared to its value at an offset.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Default, Debug)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[cfg_attr(feature = "tsify", derive(tsify::Tsify))]
+#[serde(rename_all = "snake_case")]
+pub enum CompareOp {
+    /// The current value divided by the earlier value.
+    #[default]
+    Ratio,
+    /// The current value minus the earlier value.
+    Diff,
+}
+
+#[derive(thiserror::Error, Debug)]
+#[non_exhaustive]
+pub enum TraceAggrError {
+    #[error("offset {0} falls within the {1} window")]
+    OffsetWithinWindow(Duration, Interval),
+}
+
 impl TraceAggr {
+    /// Whether the generated expression is a plain metric selector.
+    fn is_selector(&self) -> bool {
+        match self {
+            TraceAggr::Count { object, .. }
+            | TraceAggr::Mean { object, .. }
+            | TraceAggr::Ci { object, .. }
+            | TraceAggr::Sufficiency { object, .. } => object.top().is_none(),
+            TraceAggr::Rate { .. } | TraceAggr::Score { .. } | TraceAggr::MeanOffset { .. } => {
+                false
+            }
+        }
+    }
+
+    /// The unit of the generated expression's values.
+    pub const fn unit(&self, metric: TraceMetric) -> Unit {
+        match self {
+            TraceAggr::Count { .. } | TraceAggr::Score { .. } | TraceAggr::Sufficiency { .. } => {
+                NEUTRAL_UNIT
+            }
+            TraceAggr::Rate { .. } => Unit::Frequency(unit::FrequencyUnit::PerTime(
+                TimeUnit::Second(FracPrefix::Unit),
+            )),
+            TraceAggr::Mean { .. } | TraceAggr::Ci { .. } => metric.unit(),
+            TraceAggr::MeanOffset { op, .. } => match op {
+                CompareOp::Ratio => NEUTRAL_UNIT,
+                CompareOp::Diff => metric.unit(),
+            },
+        }
+    }
+
     fn kind(&self) -> TraceAggrKind {
         match self {
             TraceAggr::Count { .. } => TraceAggrKind::Count,
+            TraceAggr::Rate { .. } => TraceAggrKind::Rate,
             TraceAggr::Mean { .. } => TraceAggrKind::Mean,
             TraceAggr::Ci { .. } => TraceAggrKind::Ci,
             TraceAggr::Score { .. } => TraceAggrKind::Score,
+            TraceAggr::Sufficiency { .. } => TraceAggrKind::Sufficiency,
+            TraceAggr::MeanOffset { .. } => TraceAggrKind::MeanOffset,
+        }
+    }
+
+    /// Check what deserialization cannot: a comparison's offset must
+    /// not be shorter than the window, or both sides would share
+    /// spans.
+    pub fn validate(&self) -> Result<(), TraceAggrError> {
+        match self {
+            TraceAggr::MeanOffset {
+                interval, offset, ..
+            } if offset.to_time_delta().num_seconds() < interval.window_config().seconds() => {
+                Err(TraceAggrError::OffsetWithinWindow(*offset, *interval))
+            }
+            _ => Ok(()),
         }
     }
 }
 
-#[derive(SerializeDisplay, DeserializeFromStr, Debug)]
+#[derive(SerializeDisplay, DeserializeFromStr, PartialEq, Eq, Clone, Copy, Debug)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[cfg_attr(feature = "tsify", derive(tsify::Tsify))]
+#[cfg_attr(feature = "tsify", tsify(from_wasm_abi, into_wasm_abi))]
+#[cfg_attr(
+    any(feature = "schemars", feature = "tsify"),
+    serde(rename_all = "snake_case")
+)]
 pub enum TraceAggrKind {
     Count,
+    Rate,
     Mean,
     Ci,
     Score,
+    Sufficiency,
+    MeanOffset,
+    /// The offset added to the reference bound of the scores, per
+    /// reference interval, if the engine is configured to emit it.
+    /// There is no aggregation for it; use it with
+    /// [`TraceObject::selector`].
+    Offset,
 }
 
 impl Display for TraceAggrKind {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             TraceAggrKind::Count => write!(f, "count"),
+            TraceAggrKind::Rate => write!(f, "rate"),
             TraceAggrKind::Mean => write!(f, "mean"),
             TraceAggrKind::Ci => write!(f, "ci"),
             TraceAggrKind::Score => write!(f, "score"),
+            TraceAggrKind::Sufficiency => write!(f, "sufficiency"),
+            TraceAggrKind::MeanOffset => write!(f, "mean_offset"),
+            TraceAggrKind::Offset => write!(f, "offset"),
         }
     }
 }
@@ -147,9 +340,13 @@ impl FromStr for TraceAggrKind {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
             "count" => Ok(Self::Count),
+            "rate" => Ok(Self::Rate),
             "mean" => Ok(Self::Mean),
             "ci" => Ok(Self::Ci),
             "score" => Ok(Self::Score),
+            "sufficiency" => Ok(Self::Sufficiency),
+            "mean_offset" => Ok(Self::MeanOffset),
+            "offset" => Ok(Self::Offset),
             _ => Err(TraceAggrKindParseError::Unknown),
         }
     }
@@ -189,11 +386,13 @@ const fn metric_name(metric: TraceMetric, aggr: TraceAggrKind) -> MetricName {
     macro_rules! aggrs {
         ($aggr:ident, $var:ident, $expr:expr) => {
             match $aggr {
-                TraceAggrKind::Count => {
+                // Rates are calculated from the count metric.
+                TraceAggrKind::Count | TraceAggrKind::Rate => {
                     const $var: &str = "count";
                     $expr
                 }
-                TraceAggrKind::Mean => {
+                // Comparisons are calculated from the mean metric.
+                TraceAggrKind::Mean | TraceAggrKind::MeanOffset => {
                     const $var: &str = "mean";
                     $expr
                 }
@@ -205,6 +404,14 @@ const fn metric_name(metric: TraceMetric, aggr: TraceAggrKind) -> MetricName {
                     const $var: &str = "score";
                     $expr
                 }
+                TraceAggrKind::Sufficiency => {
+                    const $var: &str = "sufficiency";
+                    $expr
+                }
+                TraceAggrKind::Offset => {
+                    const $var: &str = "offset";
+                    $expr
+                }
             }
         };
     }
@@ -216,28 +423,39 @@ const fn metric_name(metric: TraceMetric, aggr: TraceAggrKind) -> MetricName {
     ))
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct TraceObject<C>(OperationOrService<TraceOperation, Combine<TraceService, C>>);
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct TraceObject<C> {
+    #[serde(flatten)]
+    object: OperationOrService<TraceOperation, Combine<TraceService, C>>,
+    /// The engine config name, if different from the one used for
+    /// this kind of object in the default engine config.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    config: Option<String>,
+    /// Break ties in top N score selections by the number of spans,
+    /// so that groups with equal scores are selected consistently.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    stable_top: bool,
+}
 
 type TraceOperation =
     SingleOrMultiple<ItemOrRelation<OperationKey>, ItemOrRelation<OperationFilter>>;
 type TraceService = SingleOrMultiple<ItemOrRelation<ServiceKey>, ItemOrRelation<ServiceFilter>>;
 
-#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy, Debug)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum OperationOrService<O, S> {
     Operation(O),
     Service(S),
 }
 
-#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy, Debug)]
 #[serde(tag = "multiplicity", rename_all = "snake_case")]
 pub enum SingleOrMultiple<K, F> {
     Single(K),
     Multiple { filter: F, top: Option<u64> },
 }
 
-#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy, Debug)]
 #[serde(tag = "kind", rename_all = "snake_case")]
 pub enum ItemOrRelation<K> {
     Item(K),
@@ -249,273 +467,23 @@ pub enum ItemOrRelation<K> {
     },
 }
 
-with_prefix!(prefix_child "child_");
-with_prefix!(prefix_parent "parent_");
-
-#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
-#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
-#[cfg_attr(feature = "tsify", derive(tsify::Tsify))]
-pub struct ServiceKey {
-    service_name: String,
-    namespace: Option<String>,
-    instance_id: Option<String>,
-}
-
-impl ServiceKey {
-    pub fn new<T: Into<String>>(service_name: T) -> Self {
-        Self {
-            service_name: service_name.into(),
-            namespace: None,
-            instance_id: None,
-        }
-    }
-
-    pub fn namespace<T: Into<String>>(self, namespace: T) -> Self {
-        self.opt_namespace(Some(namespace))
-    }
-
-    pub fn opt_namespace<T: Into<String>>(mut self, namespace: Option<T>) -> Self {
-        self.namespace = namespace.map(|s| s.into());
-        self
-    }
-
-    pub fn instance_id<T: Into<String>>(self, instance_id: T) -> Self {
-        self.opt_instance_id(Some(instance_id))
-    }
-
-    pub fn opt_instance_id<T: Into<String>>(mut self, instance_id: Option<T>) -> Self {
-        self.instance_id = instance_id.map(|s| s.into());
-        self
-    }
-
-    pub fn into_filter(self) -> ServiceFilter {
-        ServiceFilter {
-            service_name: Some(self.service_name),
-            namespace: self.namespace,
-            instance_id: self.instance_id,
-        }
-    }
-
-    pub fn labels(&self) -> impl Iterator<Item = (LabelName, LabelSelector)> {
-        std::iter::once((
-            LabelName::new_static("service_name"),
-            LabelSelector::Eq(self.service_name.to_string()),
-        ))
-        .chain(self.namespace.as_ref().map(|namespace| {
-            (
-                LabelName::new_static("service_namespace"),
-                LabelSelector::Eq(namespace.to_string()),
-            )
-        }))
-        .chain(self.instance_id.as_ref().map(|instance_id| {
-            (
-                LabelName::new_static("service_instance_id"),
-                LabelSelector::Eq(instance_id.to_string()),
-            )
-        }))
-    }
-
-    pub fn parent_labels(&self) -> impl Iterator<Item = (LabelName, LabelSelector)> {
-        std::iter::once((
-            LabelName::new_static("parent_service_name"),
-            LabelSelector::Eq(self.service_name.to_string()),
-        ))
-        .chain(self.namespace.as_ref().map(|namespace| {
-            (
-                LabelName::new_static("parent_service_namespace"),
-                LabelSelector::Eq(namespace.to_string()),
-            )
-        }))
-        .chain(self.instance_id.as_ref().map(|instance_id| {
-            (
-                LabelName::new_static("parent_service_instance_id"),
-                LabelSelector::Eq(instance_id.to_string()),
-            )
-        }))
-    }
-}
-
-#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Default, Debug)]
-#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
-#[cfg_attr(feature = "tsify", derive(tsify::Tsify))]
-pub struct ServiceFilter {
-    service_name: Option<String>,
-    namespace: Option<String>,
-    instance_id: Option<String>,
-}
-
-impl ServiceFilter {
-    pub fn new() -> Self {
-        Self::default()
-    }
-
-    pub fn service_name<T: Into<String>>(self, service_name: T) -> Self {
-        self.opt_service_name(Some(service_name))
-    }
-
-    pub fn opt_service_name<T: Into<String>>(mut self, service_name: Option<T>) -> Self {
-        self.service_name = service_name.map(|s| s.into());
-        self
-    }
-
-    pub fn namespace<T: Into<String>>(self, namespace: T) -> Self {
-        self.opt_namespace(Some(namespace))
-    }
-
-    pub fn opt_namespace<T: Into<String>>(mut self, namespace: Option<T>) -> Self {
-        self.namespace = namespace.map(|s| s.into());
-        self
-    }
-
-    pub fn instance_id<T: Into<String>>(self, instance_id: T) -> Self {
-        self.opt_instance_id(Some(instance_id))
-    }
-
-    pub fn opt_instance_id<T: Into<String>>(mut self, instance_id: Option<T>) -> Self {
-        self.instance_id = instance_id.map(|s| s.into());
-        self
-    }
-
-    pub fn labels(&self) -> impl Iterator<Item = (LabelName, LabelSelector)> {
-        self.service_name
-            .as_ref()
-            .map(|name| {
-                (
-                    LabelName::new_static("service_name"),
-                    LabelSelector::Eq(name.to_string()),
-                )
-            })
-            .into_iter()
-            .chain(self.namespace.as_ref().map(|namespace| {
-                (
-                    LabelName::new_static("service_namespace"),
-                    LabelSelector::Eq(namespace.to_string()),
-                )
-            }))
-            .chain(self.instance_id.as_ref().map(|instance_id| {
-                (
-                    LabelName::new_static("service_instance_id"),
-                    LabelSelector::Eq(instance_id.to_string()),
-                )
-            }))
-    }
-
-    pub fn parent_labels(&self) -> impl Iterator<Item = (LabelName, LabelSelector)> {
-        self.service_name
-            .as_ref()
-            .map(|name| {
-                (
-                    LabelName::new_static("parent_service_name"),
-                    LabelSelector::Eq(name.to_string()),
-                )
-            })
-            .into_iter()
-            .chain(self.namespace.as_ref().map(|namespace| {
-                (
-                    LabelName::new_static("parent_service_namespace"),
-                    LabelSelector::Eq(namespace.to_string()),
-                )
-            }))
-            .chain(self.instance_id.as_ref().map(|instance_id| {
-                (
-                    LabelName::new_static("parent_service_instance_id"),
-                    LabelSelector::Eq(instance_id.to_string()),
-                )
-            }))
-    }
-}
-
-#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
-#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
-#[cfg_attr(feature = "tsify", derive(tsify::Tsify))]
-pub struct OperationKey {
-    #[serde(flatten)]
-    service: ServiceKey,
-    operation_name: String,
-}
-
-impl OperationKey {
-    pub fn new<T: Into<String>>(service: ServiceKey, operation_name: T) -> Self {
-        Self {
-            service,
-            operation_name: operation_name.into(),
-        }
-    }
-
-    pub fn into_filter(self) -> OperationFilter {
-        OperationFilter {
-            service: self.service.into_filter(),
-            operation_name: Some(self.operation_name),
-        }
-    }
-
-    fn labels(&self) -> impl Iterator<Item = (LabelName, LabelSelector)> {
-        self.service.labels().chain(std::iter::once((
-            LabelName::new_static("operation_name"),
-            LabelSelector::Eq(self.operation_name.to_string()),
-        )))
-    }
-
-    fn parent_labels(&self) -> impl Iterator<Item = (LabelName, LabelSelector)> {
-        self.service.parent_labels().chain(std::iter::once((
-            LabelName::new_static("parent_operation_name"),
-            LabelSelector::Eq(self.operation_name.to_string()),
-        )))
+impl<K, F> SingleOrMultiple<ItemOrRelation<K>, ItemOrRelation<F>> {
+    fn is_relation(&self) -> bool {
+        matches!(
+            self,
+            SingleOrMultiple::Single(ItemOrRelation::Relation { .. })
+                | SingleOrMultiple::Multiple {
+                    filter: ItemOrRelation::Relation { .. },
+                    ..
+                }
+        )
     }
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Default, Debug)]
-#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
-#[cfg_attr(feature = "tsify", derive(tsify::Tsify))]
-pub struct OperationFilter {
-    #[serde(flatten)]
-    service: ServiceFilter,
-    operation_name: Option<String>,
-}
-
-impl OperationFilter {
-    pub fn new() -> Self {
-        Self::default()
-    }
-
-    pub fn service(mut self, service: ServiceFilter) -> Self {
-        self.service = service;
-        self
-    }
-
-    pub fn operation_name<T: Into<String>>(self, operation_name: T) -> Self {
-        self.opt_operation_name(Some(operation_name))
-    }
-
-    pub fn opt_operation_name<T: Into<String>>(mut self, operation_name: Option<T>) -> Self {
-        self.operation_name = operation_name.map(|s| s.into());
-        self
-    }
-
-    fn labels(&self) -> impl Iterator<Item = (LabelName, LabelSelector)> {
-        self.service
-            .labels()
-            .chain(self.operation_name.as_ref().map(|operation_name| {
-                (
-                    LabelName::new_static("operation_name"),
-                    LabelSelector::Eq(operation_name.to_string()),
-                )
-            }))
-    }
-
-    fn parent_labels(&self) -> impl Iterator<Item = (LabelName, LabelSelector)> {
-        self.service
-            .parent_labels()
-            .chain(self.operation_name.as_ref().map(|operation_name| {
-                (
-                    LabelName::new_static("parent_operation_name"),
-                    LabelSelector::Eq(operation_name.to_string()),
-                )
-            }))
-    }
-}
+with_prefix!(prefix_child "child_");
+with_prefix!(prefix_parent "parent_");
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
 pub struct Combine<T, C> {
     #[serde(flatten)]
     value: T,
@@ -537,7 +505,7 @@ impl CombineScores {
 }
 
 // Do not allow combining series.
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
 pub enum NoCombine {}
 
 // Number between 0 and 1?.
@@ -574,9 +542,79 @@ impl TraceExpr {
     pub fn expr<P: PromSelect>(&self, params: &P) -> Expr {
         self.aggr.expr(self.metric, params)
     }
+
+    pub fn metric(&self) -> TraceMetric {
+        self.metric
+    }
+
+    pub fn aggr(&self) -> &TraceAggr {
+        &self.aggr
+    }
+
+    pub fn validate(&self) -> Result<(), TraceAggrError> {
+        self.aggr.validate()
+    }
+
+    /// Apply a range function over `range`. Plain selectors are used
+    /// as range vectors directly; other expressions are evaluated as a
+    /// subquery with resolution `step`, which should normally match the
+    /// engine's query interval.
+    pub fn expr_over_time<P: PromSelect>(
+        &self,
+        params: &P,
+        func: OverTimeFunc,
+        range: PromDuration,
+        step: PromDuration,
+    ) -> Expr {
+        let expr = self.expr(params);
+        let range = if self.aggr.is_selector() {
+            expr.range(range)
+        } else {
+            expr.subquery(range, step)
+        };
+        Expr::function(&func.to_string(), vec![range])
+    }
 }
 
 impl TraceAggr {
+    /// The windows the expression reads, which the engine must compute
+    /// for the expression to return data.
+    pub fn intervals(&self) -> Vec<Interval> {
+        match self {
+            TraceAggr::Count { interval, .. }
+            | TraceAggr::Rate { interval, .. }
+            | TraceAggr::Mean { interval, .. }
+            | TraceAggr::Ci { interval, .. }
+            | TraceAggr::MeanOffset { interval, .. } => vec![*interval],
+            TraceAggr::Score {
+                immediate_interval,
+                reference_interval,
+                ..
+            }
+            | TraceAggr::Sufficiency {
+                immediate_interval,
+                reference_interval,
+                ..
+            } => vec![
+                Interval::Immediate(*immediate_interval),
+                Interval::Reference(*reference_interval),
+            ],
+        }
+    }
+
+    /// The engine config name selected by the expression.
+    pub fn config_name(&self) -> &str {
+        match self {
+            TraceAggr::Count { object, .. }
+            | TraceAggr::Rate { object, .. }
+            | TraceAggr::Mean { object, .. }
+            | TraceAggr::Ci { object, .. }
+            | TraceAggr::Sufficiency { object, .. }
+            | TraceAggr::MeanOffset { object, .. } => object.config_name(),
+            TraceAggr::Score { object, .. } => object.config_name(),
+        }
+    }
+
     pub fn count<T: Into<Interval>>(interval: T, object: TraceObject<NoCombine>) -> Self {
         Self::Count {
             interval: interval.into(),
@@ -584,6 +622,13 @@ impl TraceAggr {
         }
     }
 
+    pub fn rate<T: Into<Interval>>(interval: T, object: TraceObject<NoCombine>) -> Self {
+        Self::Rate {
+            interval: interval.into(),
+            object,
+        }
+    }
+
     pub fn mean<T: Into<Interval>>(interval: T, object: TraceObject<NoCombine>) -> Self {
         Self::Mean {
             interval: interval.into(),
@@ -607,6 +652,43 @@ impl TraceAggr {
             immediate_interval,
             reference_interval,
             object,
+            threshold: None,
+        }
+    }
+
+    /// Only return the groups scoring above `threshold` (see `Score`).
+    /// Other aggregations have no threshold and are returned
+    /// unchanged.
+    pub fn threshold(mut self, threshold: Option<NotNan<f64>>) -> Self {
+        if let Self::Score { threshold: t, .. } = &mut self {
+            *t = threshold;
+        }
+        self
+    }
+
+    pub fn sufficiency(
+        immediate_interval: ImmediateInterval,
+        reference_interval: ReferenceInterval,
+        object: TraceObject<NoCombine>,
+    ) -> Self {
+        Self::Sufficiency {
+            immediate_interval,
+            reference_interval,
+            object,
+        }
+    }
+
+    pub fn mean_offset<T: Into<Interval>>(
+        interval: T,
+        offset: Duration,
+        op: CompareOp,
+        object: TraceObject<NoCombine>,
+    ) -> Self {
+        Self::MeanOffset {
+            interval: interval.into(),
+            offset,
+            op,
+            object,
         }
     }
 
@@ -624,139 +706,256 @@ impl TraceAggr {
                     None => expr,
                 }
             }
-            TraceAggr::Score {
-                immediate_interval,
-                reference_interval,
-                object,
-            } => {
+            TraceAggr::Rate { interval, object } => {
                 let ms = object
                     .metric(metric_name(metric, self.kind()))
-                    .label(
-                        LabelName::new_static("metric_type"),
-                        LabelSelector::Eq(String::from("anomaly_score")),
-                    )
+                    .labels(interval.labels());
+                let expr = Expr::metric(ms) / interval.window_config().seconds() as f64;
+                match object.top() {
+                    Some(n) => params.select(&SelectItem::Top { n }, expr),
+                    None => expr,
+                }
+            }
+            TraceAggr::MeanOffset {
+                interval,
+                offset,
+                op,
+                object,
+            } => {
+                let ms = object
+                    .metric(metric_name(metric, self.kind()))
+                    .labels(interval.labels());
+                // Both sides are aggregated to the group labels, so
+                // that they match regardless of other labels.
+                let labels = object.group_labels();
+                let current = Expr::metric(ms.clone()).sum_by(labels.clone());
+                let previous = Expr::metric_offset(ms, Offset::Positive(prom_duration(*offset)))
+                    .sum_by(labels);
+                let expr = match op {
+                    CompareOp::Ratio => current / previous,
+                    CompareOp::Diff => current - previous,
+                };
+                match object.top() {
+                    Some(n) => params.select(&SelectItem::Top { n }, expr),
+                    None => expr,
+                }
+            }
+            TraceAggr::Sufficiency {
+                immediate_interval,
+                reference_interval,
+                object,
+            } => {
+                let ms = object
+                    .metric(metric_name(metric, self.kind()))
+                    .labels(immediate_interval.labels())
+                    .labels(reference_interval.labels());
+                let expr = Expr::metric(ms);
+                match object.top() {
+                    Some(n) => params.select(&SelectItem::Top { n }, expr),
+                    None => expr,
+                }
+            }
+            TraceAggr::Score {
+                immediate_interval,
+                reference_interval,
+                object,
+                threshold,
+            } => {
+                let ms = object
+                    .metric(metric_name(metric, self.kind()))
+                    .label(
+                        LabelName::new_static("metric_type"),
+                        LabelSelector::Eq(String::from("anomaly_score")),
+                    )
                     .labels(immediate_interval.labels())
                     .labels(reference_interval.labels());
+                let counts = Expr::metric(
+                    object
+                        .metric(metric_name(metric, TraceAggrKind::Count))
+                        .label(
+                            LabelName::new_static("metric_type"),
+                            LabelSelector::Eq(String::from("anomaly_score")),
+                        )
+                        .label(
+                            LabelName::new_static("immediate"),
+                            LabelSelector::Eq(immediate_interval.to_string()),
+                        ),
+                );
+                let labels = object.group_labels();
                 let expr = match object.combine() {
                     Some(CombineScores {
                         combine: CombinationFactor(c),
                     }) => {
                         let expr = Expr::metric(ms);
-                        let counts = Expr::metric(
-                            object
-                                .metric(metric_name(metric, TraceAggrKind::Count))
-                                .label(
-                                    LabelName::new_static("metric_type"),
-                                    LabelSelector::Eq(String::from("anomaly_score")),
-                                )
-                                .label(
-                                    LabelName::new_static("immediate"),
-                                    LabelSelector::Eq(immediate_interval.to_string()),
-                                ),
-                        );
-                        let labels = Vec::from_iter([
-                            LabelName::new_static("service_name"),
-                            LabelName::new_static("service_namespace"),
-                            LabelName::new_static("service_instance_id"),
-                        ]);
                         (expr - 1.0)
                             .clamp_min(0.0)
                             .is_ge(0.0)
                             .sum_by(labels.clone())
-                            / counts.sum_by(labels).clamp_min(1.0).pow(c.into_inner())
+                            / counts
+                                .clone()
+                                .sum_by(labels.clone())
+                                .clamp_min(1.0)
+                                .pow(c.into_inner())
                             + 1.0
                     }
                     None => Expr::metric(ms).clamp_min(1.0),
                 };
-                match object.top() {
+                let expr = match object.top() {
+                    Some(n) if object.stable_top => {
+                        // Add a small fraction of the group's share of the
+                        // spans, so that equal scores are ordered by
+                        // activity. The share is at most one, which keeps
+                        // the tie-breaker below any real score difference
+                        // however busy the group. Both sides are aggregated
+                        // to the same labels to match. Item scores take the
+                        // highest score of the group: series differing in
+                        // other labels, such as the shard, must not add up.
+                        let expr = match object.combine() {
+                            Some(_) => expr,
+                            None => expr.max_by(labels.clone()),
+                        };
+                        let total = counts.clone().sum_by(Vec::<LabelName>::new());
+                        let total = Expr::function("scalar", vec![total.clamp_min(1.0)]);
+                        let tie_breaker = counts.sum_by(labels) / total * TOP_TIE_BREAKER;
+                        params.select(&SelectItem::Top { n }, expr + tie_breaker)
+                    }
                     Some(n) => params.select(&SelectItem::Top { n }, expr),
                     None => expr,
+                };
+                match threshold {
+                    Some(threshold) => expr.is_gt(threshold.into_inner()),
+                    None => expr,
                 }
             }
         }
     }
 }
 
+fn prom_duration(duration: Duration) -> PromDuration {
+    match duration {
+        Duration::Seconds(n) => PromDuration::Seconds(n.into()),
+        Duration::Minutes(n) => PromDuration::Minutes(n.into()),
+        Duration::Hours(n) => PromDuration::Hours(n.into()),
+        Duration::Days(n) => PromDuration::Days(n.into()),
+        Duration::Weeks(n) => PromDuration::Weeks(n.into()),
+    }
+}
+
 impl<C> TraceObject<C> {
     pub fn builder() -> TraceObjectBuilder<WantsOperationOrService<C>> {
         TraceObjectBuilder(WantsOperationOrService(PhantomData))
     }
 
+    pub fn config<T: Into<String>>(self, config: T) -> Self {
+        self.opt_config(Some(config))
+    }
+
+    pub fn opt_config<T: Into<String>>(mut self, config: Option<T>) -> Self {
+        self.config = config.map(|s| s.into());
+        self
+    }
+
+    /// Break ties between equal scores when selecting the top N, by
+    /// adding a small fraction of the span count to the score.
+    pub fn stable_top(mut self, stable_top: bool) -> Self {
+        self.stable_top = stable_top;
+        self
+    }
+
+    /// The engine config name selected by the expressions.
+    pub fn config_name(&self) -> &str {
+        self.config
+            .as_deref()
+            .unwrap_or_else(|| match &self.object {
+                OperationOrService::Operation(v) if v.is_relation() => "operation-relations",
+                OperationOrService::Service(Combine { value, .. }) if value.is_relation() => {
+                    "service-relations"
+                }
+                _ => "default",
+            })
+    }
+
+    /// The selector of the series of the object for a metric and
+    /// aggregation. The top N selection is not part of the selector.
+    pub fn selector(&self, metric: TraceMetric, aggr: TraceAggrKind) -> MetricSelector {
+        self.metric(metric_name(metric, aggr))
+    }
+
     fn metric(&self, name: MetricName) -> MetricSelector {
-        let metric = MetricSelector::new().metric(name).label(
-            LabelName::new_static("metric_type"),
-            LabelSelector::Eq(String::from("anomaly_score")),
-        );
-        match &self.0 {
+        let metric = MetricSelector::new()
+            .metric(name)
+            .label(
+                LabelName::new_static("metric_type"),
+                LabelSelector::Eq(String::from("anomaly_score")),
+            )
+            .label(
+                LabelName::new_static("config"),
+                LabelSelector::Eq(self.config_name().to_string()),
+            );
+        match &self.object {
             OperationOrService::Operation(v) => match v {
                 SingleOrMultiple::Single(v) => match v {
-                    ItemOrRelation::Item(key) => metric
-                        .label(
-                            LabelName::new_static("config"),
-                            LabelSelector::Eq(String::from("default")),
-                        )
-                        .labels(key.labels()),
-                    ItemOrRelation::Relation { child, parent } => metric
-                        .label(
-                            LabelName::new_static("config"),
-                            LabelSelector::Eq(String::from("operation-relations")),
-                        )
-                        .labels(child.labels())
-                        .labels(parent.parent_labels()),
+                    ItemOrRelation::Item(key) => metric.labels(key.labels()),
+                    ItemOrRelation::Relation { child, parent } => {
+                        metric.labels(child.labels()).labels(parent.parent_labels())
+                    }
                 },
                 SingleOrMultiple::Multiple { filter, .. } => match filter {
-                    ItemOrRelation::Item(filter) => metric
-                        .label(
-                            LabelName::new_static("config"),
-                            LabelSelector::Eq(String::from("default")),
-                        )
-                        .labels(filter.labels()),
-                    ItemOrRelation::Relation { child, parent } => metric
-                        .label(
-                            LabelName::new_static("config"),
-                            LabelSelector::Eq(String::from("operation-relations")),
-                        )
-                        .labels(child.labels())
-                        .labels(parent.parent_labels()),
+                    ItemOrRelation::Item(filter) => metric.labels(filter.labels()),
+                    ItemOrRelation::Relation { child, parent } => {
+                        metric.labels(child.labels()).labels(parent.parent_labels())
+                    }
                 },
             },
             OperationOrService::Service(Combine { value, .. }) => match value {
                 SingleOrMultiple::Single(v) => match v {
-                    ItemOrRelation::Item(key) => metric
-                        .label(
-                            LabelName::new_static("config"),
-                            LabelSelector::Eq(String::from("default")),
-                        )
-                        .labels(key.labels()),
-                    ItemOrRelation::Relation { child, parent } => metric
-                        .label(
-                            LabelName::new_static("config"),
-                            LabelSelector::Eq(String::from("operation-relations")),
-                        )
-                        .labels(child.labels())
-                        .labels(parent.parent_labels()),
+                    ItemOrRelation::Item(key) => metric.labels(key.labels()),
+                    ItemOrRelation::Relation { child, parent } => {
+                        metric.labels(child.labels()).labels(parent.parent_labels())
+                    }
                 },
                 SingleOrMultiple::Multiple { filter, .. } => match filter {
-                    ItemOrRelation::Item(key) => metric
-                        .label(
-                            LabelName::new_static("config"),
-                            LabelSelector::Eq(String::from("default")),
-                        )
-                        .labels(key.labels()),
-                    ItemOrRelation::Relation { child, parent } => metric
-                        .label(
-                            LabelName::new_static("config"),
-                            LabelSelector::Eq(String::from("operation-relations")),
-                        )
-                        .labels(child.labels())
-                        .labels(parent.parent_labels()),
+                    ItemOrRelation::Item(key) => metric.labels(key.labels()),
+                    ItemOrRelation::Relation { child, parent } => {
+                        metric.labels(child.labels()).labels(parent.parent_labels())
+                    }
                 },
             },
         }
     }
 
+    /// The labels identifying a single group of the object. Combined
+    /// service scores are aggregated to the service labels.
+    pub fn group_labels(&self) -> Vec<LabelName> {
+        let service = [
+            LabelName::new_static("service_name"),
+            LabelName::new_static("service_namespace"),
+            LabelName::new_static("service_instance_id"),
+        ];
+        match &self.object {
+            OperationOrService::Operation(v) => {
+                let labels = service
+                    .into_iter()
+                    .chain(std::iter::once(LabelName::new_static("operation_name")));
+                if v.is_relation() {
+                    labels
+                        .chain([
+                            LabelName::new_static("parent_service_name"),
+                            LabelName::new_static("parent_service_namespace"),
+                            LabelName::new_static("parent_service_instance_id"),
+                            LabelName::new_static("parent_operation_name"),
+                        ])
+                        .collect()
+                } else {
+                    labels.collect()
+                }
+            }
+            OperationOrService::Service(_) => Vec::from(service),
+        }
+    }
+
     fn top(&self) -> Option<u64> {
-        match &self.0 {
+        match &self.object {
             OperationOrService::Operation(SingleOrMultiple::Multiple { top, .. })
             | OperationOrService::Service(Combine {
                 value: SingleOrMultiple::Multiple { top, .. },
@@ -767,7 +966,7 @@ impl<C> TraceObject<C> {
     }
 
     fn combine(&self) -> Option<&C> {
-        match &self.0 {
+        match &self.object {
             OperationOrService::Service(Combine { combine, .. }) => Some(combine),
             _ => None,
         }
@@ -898,21 +1097,31 @@ impl<T: IsOperationOrService<C>, S: IsSingleOrMultiple<T, C>, C>
     fn build(self, item_or_relation: ItemOrRelation<S::Key>) -> TraceObject<C> {
         let single_or_multiple = self.0 .1.build(item_or_relation);
         let operation_or_service = self.0 .0.build(single_or_multiple);
-        TraceObject(operation_or_service)
+        TraceObject {
+            object: operation_or_service,
+            config: None,
+            stable_top: false,
+        }
     }
 }
 
 #[cfg(test)]
 mod test {
+    use std::collections::BTreeMap;
+
     use ordered_float::NotNan;
     use prometheus_api::InstantQueryParams;
+    use unit::{FracPrefix, FrequencyUnit, TimeUnit, Unit, NEUTRAL_UNIT};
 
     use crate::{
-        exprs::precalculated::{CombinationFactor, CombineScores},
-        ImmediateInterval, ReferenceInterval, ServiceFilter, TraceAggr, TraceExpr, TraceMetric,
+        exprs::precalculated::{CombinationFactor, CombineScores, CompareOp, TraceAggrError},
+        Duration, ImmediateInterval, OperationFilter, ReferenceInterval, ServiceFilter, TraceAggr,
+        TraceAggrKind, TraceExpr, TraceMetric,
     };
 
-    use super::{NoCombine, OperationKey, ServiceKey, TraceObject};
+    use super::{
+        Expr, NoCombine, OperationKey, OverTimeFunc, PromDuration, ServiceKey, TraceObject,
+    };
 
     #[test]
     fn build_trace_object() {
@@ -1007,4 +1216,556 @@ mod test {
             r#"topk(5, sum by (service_name, service_namespace, service_instance_id) (clamp_min(trace_duration_score { config = "default", immediate = "15m", metric_type = "anomaly_score", reference = "30d" } - 1, 0) >= 0) / clamp_min(sum by (service_name, service_namespace, service_instance_id) (trace_duration_count { config = "default", immediate = "15m", metric_type = "anomaly_score" }), 1) ^ 0.5 + 1)"#
         );
     }
+
+    #[test]
+    fn score_threshold_expr() {
+        let threshold = Some(NotNan::new(2.5).unwrap());
+        let params = InstantQueryParams { time: None };
+
+        let expr = TraceExpr::new(
+            TraceMetric::Duration,
+            TraceAggr::score(
+                ImmediateInterval::I15m,
+                ReferenceInterval::R30d,
+                TraceObject::builder()
+                    .operation()
+                    .multiple(None)
+                    .item(OperationFilter::new()),
+            )
+            .threshold(threshold),
+        );
+        assert_eq!(
+            expr.expr(&params).to_string(),
+            r#"clamp_min(trace_duration_score { config = "default", immediate = "15m", metric_type = "anomaly_score", reference = "30d" }, 1) > 2.5"#
+        );
+
+        // The top 5 are selected before filtering.
+        let expr = TraceExpr::new(
+            TraceMetric::Duration,
+            TraceAggr::score(
+                ImmediateInterval::I15m,
+                ReferenceInterval::R30d,
+                TraceObject::builder()
+                    .service(CombineScores::new(CombinationFactor::new(
+                        NotNan::new(0.5).unwrap(),
+                    )))
+                    .multiple(Some(5))
+                    .item(ServiceFilter::new()),
+            )
+            .threshold(threshold),
+        );
+        assert_eq!(
+            expr.expr(&params).to_string(),
+            r#"topk(5, sum by (service_name, service_namespace, service_instance_id) (clamp_min(trace_duration_score { config = "default", immediate = "15m", metric_type = "anomaly_score", reference = "30d" } - 1, 0) >= 0) / clamp_min(sum by (service_name, service_namespace, service_instance_id) (trace_duration_count { config = "default", immediate = "15m", metric_type = "anomaly_score" }), 1) ^ 0.5 + 1) > 2.5"#
+        );
+
+        // The threshold is optional in payloads, and only serialized
+        // when set.
+        let json = serde_json::to_value(&expr).unwrap();
+        assert_eq!(json["aggr"]["threshold"], 2.5);
+        let mut without = json.clone();
+        without["aggr"].as_object_mut().unwrap().remove("threshold");
+        let parsed = serde_json::from_value::<TraceExpr>(without).unwrap();
+        assert_eq!(parsed.aggr(), &expr.aggr().clone().threshold(None));
+        assert!(serde_json::to_value(&parsed).unwrap()["aggr"]
+            .get("threshold")
+            .is_none());
+        assert_eq!(serde_json::from_value::<TraceExpr>(json).unwrap(), expr);
+    }
+
+    #[test]
+    fn stable_top_combined_score_expr() {
+        let expr = |stable_top| {
+            TraceExpr::new(
+                TraceMetric::Duration,
+                TraceAggr::score(
+                    ImmediateInterval::I15m,
+                    ReferenceInterval::R30d,
+                    TraceObject::builder()
+                        .service(CombineScores::new(CombinationFactor::new(
+                            NotNan::new(0.5).unwrap(),
+                        )))
+                        .multiple(Some(5))
+                        .item(ServiceFilter::new())
+                        .stable_top(stable_top),
+                ),
+            )
+        };
+        let params = InstantQueryParams { time: None };
+        assert_eq!(
+            expr(false).expr(&params).to_string(),
+            r#"topk(5, sum by (service_name, service_namespace, service_instance_id) (clamp_min(trace_duration_score { config = "default", immediate = "15m", metric_type = "anomaly_score", reference = "30d" } - 1, 0) >= 0) / clamp_min(sum by (service_name, service_namespace, service_instance_id) (trace_duration_count { config = "default", immediate = "15m", metric_type = "anomaly_score" }), 1) ^ 0.5 + 1)"#
+        );
+        assert_eq!(
+            expr(true).expr(&params).to_string(),
+            r#"topk(5, sum by (service_name, service_namespace, service_instance_id) (clamp_min(trace_duration_score { config = "default", immediate = "15m", metric_type = "anomaly_score", reference = "30d" } - 1, 0) >= 0) / clamp_min(sum by (service_name, service_namespace, service_instance_id) (trace_duration_count { config = "default", immediate = "15m", metric_type = "anomaly_score" }), 1) ^ 0.5 + 1 + sum by (service_name, service_namespace, service_instance_id) (trace_duration_count { config = "default", immediate = "15m", metric_type = "anomaly_score" }) / scalar(clamp_min(sum by () (trace_duration_count { config = "default", immediate = "15m", metric_type = "anomaly_score" }), 1)) * 0.000000001)"#
+        );
+    }
+
+    /// With sharding, every shard writes its own score series for an
+    /// operation; they differ only in the `shard` label. The stable
+    /// top N selection must take the highest of them rather than
+    /// their sum, like the selection without tie-breaker does.
+    #[test]
+    fn stable_top_ignores_extra_labels() {
+        let object = TraceObject::<NoCombine>::builder()
+            .operation()
+            .multiple(Some(5))
+            .item(OperationFilter::new())
+            .stable_top(true);
+        let expr = TraceExpr::new(
+            TraceMetric::Duration,
+            TraceAggr::score(ImmediateInterval::I15m, ReferenceInterval::R30d, object),
+        )
+        .expr(&InstantQueryParams { time: None })
+        .to_string();
+        let (score, tie_breaker) = expr.split_once(" + ").unwrap();
+        assert!(
+            score.starts_with(
+                "topk(5, max by (service_name, service_namespace, service_instance_id, \
+                 operation_name) (clamp_min(trace_duration_score {"
+            ),
+            "{expr}"
+        );
+        assert!(!score.contains("sum by"), "{expr}");
+        assert!(tie_breaker.starts_with("sum by ("), "{expr}");
+    }
+
+    #[test]
+    fn stable_top_item_score_expr() {
+        let expr = |stable_top| {
+            TraceExpr::new(
+                TraceMetric::Duration,
+                TraceAggr::score(
+                    ImmediateInterval::I15m,
+                    ReferenceInterval::R30d,
+                    TraceObject::builder()
+                        .operation()
+                        .multiple(Some(5))
+                        .item(OperationFilter::new())
+                        .stable_top(stable_top),
+                ),
+            )
+        };
+        let params = InstantQueryParams { time: None };
+        assert_eq!(
+            expr(false).expr(&params).to_string(),
+            r#"topk(5, clamp_min(trace_duration_score { config = "default", immediate = "15m", metric_type = "anomaly_score", reference = "30d" }, 1))"#
+        );
+        assert_eq!(
+            expr(true).expr(&params).to_string(),
+            r#"topk(5, max by (service_name, service_namespace, service_instance_id, operation_name) (clamp_min(trace_duration_score { config = "default", immediate = "15m", metric_type = "anomaly_score", reference = "30d" }, 1)) + sum by (service_name, service_namespace, service_instance_id, operation_name) (trace_duration_count { config = "default", immediate = "15m", metric_type = "anomaly_score" }) / scalar(clamp_min(sum by () (trace_duration_count { config = "default", immediate = "15m", metric_type = "anomaly_score" }), 1)) * 0.000000001)"#
+        );
+
+        // The flag is only serialized when set.
+        let object = TraceObject::<NoCombine>::builder()
+            .operation()
+            .multiple(Some(5))
+            .item(OperationFilter::new());
+        assert!(!serde_json::to_string(&object)
+            .unwrap()
+            .contains("stable_top"));
+        let object = object.stable_top(true);
+        let s = serde_json::to_string(&object).unwrap();
+        assert!(s.contains(r#""stable_top":true"#), "{s}");
+        assert_eq!(
+            serde_json::from_str::<TraceObject<NoCombine>>(&s).unwrap(),
+            object
+        );
+    }
+
+    #[test]
+    fn selector_expr_over_time() {
+        let expr = TraceExpr::new(
+            TraceMetric::Duration,
+            TraceAggr::mean(
+                ImmediateInterval::I15m,
+                TraceObject::<NoCombine>::builder()
+                    .operation()
+                    .multiple(None)
+                    .item(OperationFilter::new()),
+            ),
+        );
+        let params = InstantQueryParams { time: None };
+        assert_eq!(
+            expr.expr_over_time(
+                &params,
+                OverTimeFunc::Avg,
+                PromDuration::Minutes(5),
+                PromDuration::Seconds(30)
+            )
+            .to_string(),
+            r#"avg_over_time(trace_duration_mean { config = "default", immediate = "15m", metric_type = "anomaly_score" }[5m])"#
+        );
+    }
+
+    #[test]
+    fn combined_score_expr_over_time() {
+        let expr = TraceExpr::new(
+            TraceMetric::Duration,
+            TraceAggr::score(
+                ImmediateInterval::I15m,
+                ReferenceInterval::R30d,
+                TraceObject::builder()
+                    .service(CombineScores::new(CombinationFactor::new(
+                        NotNan::new(0.5).unwrap(),
+                    )))
+                    .multiple(Some(5))
+                    .item(ServiceFilter::new()),
+            ),
+        );
+        let params = InstantQueryParams { time: None };
+        assert_eq!(
+            expr.expr_over_time(
+                &params,
+                OverTimeFunc::Max,
+                PromDuration::Minutes(5),
+                PromDuration::Seconds(30)
+            )
+            .to_string(),
+            r#"max_over_time((topk(5, sum by (service_name, service_namespace, service_instance_id) (clamp_min(trace_duration_score { config = "default", immediate = "15m", metric_type = "anomaly_score", reference = "30d" } - 1, 0) >= 0) / clamp_min(sum by (service_name, service_namespace, service_instance_id) (trace_duration_count { config = "default", immediate = "15m", metric_type = "anomaly_score" }), 1) ^ 0.5 + 1))[5m:30s])"#
+        );
+    }
+
+    #[test]
+    fn relation_expr() {
+        let expr = TraceExpr::new(
+            TraceMetric::Duration,
+            TraceAggr::count(
+                ImmediateInterval::I5m,
+                TraceObject::<NoCombine>::builder()
+                    .operation()
+                    .single()
+                    .relation(
+                        OperationKey::new(ServiceKey::new("backend"), "POST"),
+                        OperationKey::new(ServiceKey::new("frontend"), "GET"),
+                    ),
+            ),
+        );
+        let params = InstantQueryParams { time: None };
+        assert_eq!(
+            expr.expr(&params).to_string(),
+            r#"trace_duration_count { config = "operation-relations", immediate = "5m", metric_type = "anomaly_score", operation_name = "POST", parent_operation_name = "GET", parent_service_name = "frontend", service_name = "backend" }"#
+        );
+    }
+
+    #[test]
+    fn sufficiency_expr() {
+        let expr = TraceExpr::new(
+            TraceMetric::Duration,
+            TraceAggr::sufficiency(
+                ImmediateInterval::I5m,
+                ReferenceInterval::R7d,
+                TraceObject::<NoCombine>::builder()
+                    .operation()
+                    .single()
+                    .item(OperationKey::new(ServiceKey::new("frontend"), "GET")),
+            ),
+        );
+        let params = InstantQueryParams { time: None };
+        assert_eq!(
+            expr.expr(&params).to_string(),
+            r#"trace_duration_sufficiency { config = "default", immediate = "5m", metric_type = "anomaly_score", operation_name = "GET", reference = "7d", service_name = "frontend" }"#
+        );
+    }
+
+    #[test]
+    fn rate_expr() {
+        let params = InstantQueryParams { time: None };
+        let expr = TraceExpr::new(
+            TraceMetric::CallRate,
+            TraceAggr::rate(
+                ImmediateInterval::I5m,
+                TraceObject::<NoCombine>::builder()
+                    .operation()
+                    .single()
+                    .item(OperationKey::new(ServiceKey::new("frontend"), "GET")),
+            ),
+        );
+        assert_eq!(
+            expr.expr(&params).to_string(),
+            r#"trace_call_rate_count { config = "default", immediate = "5m", metric_type = "anomaly_score", operation_name = "GET", service_name = "frontend" } / 300"#
+        );
+
+        let expr = TraceExpr::new(
+            TraceMetric::Duration,
+            TraceAggr::rate(
+                ReferenceInterval::R7d,
+                TraceObject::<NoCombine>::builder()
+                    .operation()
+                    .multiple(Some(5))
+                    .item(OperationFilter::new()),
+            ),
+        );
+        assert_eq!(
+            expr.expr(&params).to_string(),
+            r#"topk(5, trace_duration_count { config = "default", metric_type = "anomaly_score", reference = "7d" } / 604800)"#
+        );
+    }
+
+    #[test]
+    fn rate_unit_and_serialization() {
+        let aggr = TraceAggr::rate(
+            ImmediateInterval::I15m,
+            TraceObject::<NoCombine>::builder()
+                .operation()
+                .single()
+                .item(OperationKey::new(ServiceKey::new("frontend"), "GET")),
+        );
+        assert!(matches!(
+            aggr.unit(TraceMetric::Duration),
+            Unit::Frequency(FrequencyUnit::PerTime(TimeUnit::Second(FracPrefix::Unit)))
+        ));
+        assert_eq!("rate".parse::<TraceAggrKind>().unwrap().to_string(), "rate");
+
+        let value = serde_json::to_value(&aggr).unwrap();
+        assert_eq!(value["aggr"], "rate");
+        assert_eq!(value["interval"], "15m");
+        assert_eq!(serde_json::from_value::<TraceAggr>(value).unwrap(), aggr);
+    }
+
+    #[test]
+    fn mean_offset_expr() {
+        let params = InstantQueryParams { time: None };
+        let operation = || {
+            TraceObject::<NoCombine>::builder()
+                .operation()
+                .single()
+                .item(OperationKey::new(ServiceKey::new("frontend"), "GET"))
+        };
+        let expr = TraceExpr::new(
+            TraceMetric::Duration,
+            TraceAggr::mean_offset(
+                ImmediateInterval::I15m,
+                Duration::Weeks(1),
+                CompareOp::Ratio,
+                operation(),
+            ),
+        );
+        assert_eq!(
+            expr.expr(&params).to_string(),
+            r#"sum by (service_name, service_namespace, service_instance_id, operation_name) (trace_duration_mean { config = "default", immediate = "15m", metric_type = "anomaly_score", operation_name = "GET", service_name = "frontend" }) / sum by (service_name, service_namespace, service_instance_id, operation_name) (trace_duration_mean { config = "default", immediate = "15m", metric_type = "anomaly_score", operation_name = "GET", service_name = "frontend" } offset 1w)"#
+        );
+        let expr = TraceExpr::new(
+            TraceMetric::Duration,
+            TraceAggr::mean_offset(
+                ImmediateInterval::I15m,
+                Duration::Weeks(1),
+                CompareOp::Diff,
+                operation(),
+            ),
+        );
+        assert_eq!(
+            expr.expr(&params).to_string(),
+            r#"sum by (service_name, service_namespace, service_instance_id, operation_name) (trace_duration_mean { config = "default", immediate = "15m", metric_type = "anomaly_score", operation_name = "GET", service_name = "frontend" }) - sum by (service_name, service_namespace, service_instance_id, operation_name) (trace_duration_mean { config = "default", immediate = "15m", metric_type = "anomaly_score", operation_name = "GET", service_name = "frontend" } offset 1w)"#
+        );
+
+        // The top N is selected from the comparison.
+        let filter = || {
+            TraceObject::<NoCombine>::builder()
+                .operation()
+                .multiple(Some(5))
+                .item(OperationFilter::new().service(ServiceFilter::new().service_name("frontend")))
+        };
+        let expr = TraceExpr::new(
+            TraceMetric::Duration,
+            TraceAggr::mean_offset(
+                ImmediateInterval::I15m,
+                Duration::Days(1),
+                CompareOp::Ratio,
+                filter(),
+            ),
+        );
+        assert_eq!(
+            expr.expr(&params).to_string(),
+            r#"topk(5, sum by (service_name, service_namespace, service_instance_id, operation_name) (trace_duration_mean { config = "default", immediate = "15m", metric_type = "anomaly_score", service_name = "frontend" }) / sum by (service_name, service_namespace, service_instance_id, operation_name) (trace_duration_mean { config = "default", immediate = "15m", metric_type = "anomaly_score", service_name = "frontend" } offset 1d))"#
+        );
+        let expr = TraceExpr::new(
+            TraceMetric::Duration,
+            TraceAggr::mean_offset(
+                ImmediateInterval::I15m,
+                Duration::Days(1),
+                CompareOp::Diff,
+                filter(),
+            ),
+        );
+        assert_eq!(
+            expr.expr(&params).to_string(),
+            r#"topk(5, sum by (service_name, service_namespace, service_instance_id, operation_name) (trace_duration_mean { config = "default", immediate = "15m", metric_type = "anomaly_score", service_name = "frontend" }) - sum by (service_name, service_namespace, service_instance_id, operation_name) (trace_duration_mean { config = "default", immediate = "15m", metric_type = "anomaly_score", service_name = "frontend" } offset 1d))"#
+        );
+    }
+
+    #[test]
+    fn offset_selector() {
+        let object = TraceObject::<NoCombine>::builder()
+            .operation()
+            .single()
+            .item(OperationKey::new(ServiceKey::new("frontend"), "GET"));
+        assert_eq!(
+            "offset".parse::<TraceAggrKind>().unwrap().to_string(),
+            "offset"
+        );
+        assert_eq!(
+            Expr::metric(object.selector(TraceMetric::Duration, TraceAggrKind::Offset)).to_string(),
+            r#"trace_duration_offset { config = "default", metric_type = "anomaly_score", operation_name = "GET", service_name = "frontend" }"#
+        );
+    }
+
+    #[test]
+    fn mean_offset_serialization_and_validation() {
+        let object = TraceObject::<NoCombine>::builder()
+            .operation()
+            .single()
+            .item(OperationKey::new(ServiceKey::new("frontend"), "GET"));
+        let aggr = TraceAggr::mean_offset(
+            ReferenceInterval::R7d,
+            Duration::Weeks(1),
+            CompareOp::Ratio,
+            object.clone(),
+        );
+        assert_eq!(aggr.unit(TraceMetric::Duration), NEUTRAL_UNIT);
+        assert_eq!(
+            "mean_offset".parse::<TraceAggrKind>().unwrap().to_string(),
+            "mean_offset"
+        );
+        assert!(aggr.validate().is_ok());
+
+        let mut value = serde_json::to_value(&aggr).unwrap();
+        assert_eq!(value["aggr"], "mean_offset");
+        assert_eq!(value["offset"], "1w");
+        assert_eq!(value["op"], "ratio");
+        // The comparison defaults to a ratio.
+        value.as_object_mut().unwrap().remove("op");
+        assert_eq!(serde_json::from_value::<TraceAggr>(value).unwrap(), aggr);
+
+        let aggr = TraceAggr::mean_offset(
+            ReferenceInterval::R7d,
+            Duration::Days(1),
+            CompareOp::Diff,
+            object,
+        );
+        assert!(matches!(
+            aggr.unit(TraceMetric::Duration),
+            Unit::Time(TimeUnit::Second(FracPrefix::Micro))
+        ));
+        assert!(matches!(
+            TraceExpr::new(TraceMetric::Duration, aggr).validate(),
+            Err(TraceAggrError::OffsetWithinWindow(Duration::Days(1), _))
+        ));
+    }
+
+    #[test]
+    fn config_per_object_shape() {
+        let operation =
+            |service: &str, operation: &str| OperationKey::new(ServiceKey::new(service), operation);
+        let combine = || CombineScores::new(CombinationFactor::default());
+        let objects = [
+            (
+                TraceObject::<CombineScores>::builder()
+                    .operation()
+                    .single()
+                    .item(operation("frontend", "GET")),
+                "default",
+            ),
+            (
+                TraceObject::builder()
+                    .operation()
+                    .single()
+                    .relation(operation("backend", "POST"), operation("frontend", "GET")),
+                "operation-relations",
+            ),
+            (
+                TraceObject::builder()
+                    .operation()
+                    .multiple(None)
+                    .item(OperationFilter::new()),
+                "default",
+            ),
+            (
+                TraceObject::builder()
+                    .operation()
+                    .multiple(None)
+                    .relation(OperationFilter::new(), OperationFilter::new()),
+                "operation-relations",
+            ),
+            (
+                TraceObject::builder()
+                    .service(combine())
+                    .single()
+                    .item(ServiceKey::new("frontend")),
+                "default",
+            ),
+            (
+                TraceObject::builder()
+                    .service(combine())
+                    .single()
+                    .relation(ServiceKey::new("backend"), ServiceKey::new("frontend")),
+                "service-relations",
+            ),
+            (
+                TraceObject::builder()
+                    .service(combine())
+                    .multiple(None)
+                    .item(ServiceFilter::new()),
+                "default",
+            ),
+            (
+                TraceObject::builder()
+                    .service(combine())
+                    .multiple(None)
+                    .relation(ServiceFilter::new(), ServiceFilter::new()),
+                "service-relations",
+            ),
+        ];
+        let params = InstantQueryParams { time: None };
+        for (object, config) in objects {
+            for (object, config) in [
+                (object.clone(), config),
+                (object.config("tenant-a"), "tenant-a"),
+            ] {
+                assert_eq!(object.config_name(), config);
+                let expr = TraceExpr::new(
+                    TraceMetric::Duration,
+                    TraceAggr::score(ImmediateInterval::I5m, ReferenceInterval::R7d, object),
+                )
+                .expr(&params)
+                .to_string();
+                let label = format!(r#"config = "{config}""#);
+                assert!(expr.contains(&label), "{expr}");
+                assert_eq!(
+                    expr.matches("config = ").count(),
+                    expr.matches(&label).count(),
+                    "{expr}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn serialize_trace_object_config() {
+        let example = TraceObject::<NoCombine>::builder()
+            .operation()
+            .single()
+            .item(OperationKey::new(ServiceKey::new("frontend"), "GET"))
+            .config("tenant-a");
+        let s = serde_json::to_string(&example).unwrap();
+        assert_eq!(
+            s,
+            r#"{"type":"operation","multiplicity":"single","kind":"item","service_name":"frontend","namespace":null,"instance_id":null,"operation_name":"GET","config":"tenant-a"}"#
+        );
+        let example = serde_json::from_str::<TraceObject<NoCombine>>(&s).unwrap();
+        assert_eq!(example.config_name(), "tenant-a");
+    }
+
+    #[test]
+    fn over_time_func_roundtrip() {
+        for func in [
+            OverTimeFunc::Avg,
+            OverTimeFunc::Max,
+            OverTimeFunc::Min,
+            OverTimeFunc::Last,
+        ] {
+            assert_eq!(func.to_string().parse::<OverTimeFunc>().unwrap(), func);
+        }
+    }
 }
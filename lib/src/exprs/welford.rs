@@ -13,7 +13,9 @@ use prometheus_core::{LabelName, MetricName};
 use prometheus_expr::{Expr, LabelSelector, MetricSelector, Offset, PromDuration};
 use serde::{Deserialize, Serialize};
 use statrs::distribution::{ContinuousCDF, Normal, StudentsT};
-use tap::Pipe;
+use unit::{Unit, NEUTRAL_UNIT};
+
+use crate::TraceMetric;
 
 #[cfg_attr(feature = "apistos", derive(apistos::ApiComponent))]
 #[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
@@ -22,7 +24,7 @@ use tap::Pipe;
 pub struct WelfordParams {
     pub metric: MetricName,
     pub labels: GenericLabels,
-    // TODO:
+    /// Pool the statistics of all series sharing these labels.
     pub group_by: Option<Vec<LabelName>>,
     pub duration: PromDuration,
     pub q: f64,
@@ -40,6 +42,9 @@ pub struct WelfordExprs {
     pub confidence_interval: Expr,
     pub low: Expr,
     pub high: Expr,
+    pub unit: Unit,
+    pub display_name: String,
+    pub scale_to_seconds: Option<f64>,
 }
 
 impl WelfordExprs {
@@ -81,78 +86,43 @@ impl WelfordExprs {
             .labels(query());
 
         let offset = Offset::Positive(*duration);
-        let counts = Expr::metric(count.clone()).sub(Expr::metric_offset(count.clone(), offset));
-        let means = Expr::metric_offset(mean.clone(), offset).add(
-            Expr::metric(mean.clone())
-                .sub(Expr::metric_offset(mean.clone(), offset))
-                .mul(Expr::metric(count.clone()).div(counts.clone().is_gt(Expr::number(0.0)))),
-        );
+        let delta = |selector: &MetricSelector| {
+            Expr::metric(selector.clone()).sub(Expr::metric_offset(selector.clone(), offset))
+        };
 
-        let count_over_time = counts
-            .clone()
-            .pipe(|expr| {
-                if let Some(labels) = group_by {
-                    expr.sum_by(labels.clone())
-                } else {
-                    expr
-                }
-            })
-            .clamp_min(0.0);
+        // Per-series statistics over the window, derived from the
+        // cumulative snapshots. Series that were reset or did not
+        // receive any samples are filtered out on the count, so that
+        // all legs cover the same set of series.
+        let counts = delta(&count).is_gt(0.0);
+        let means = Expr::metric_offset(mean.clone(), offset)
+            .add(delta(&mean).mul(Expr::metric(count.clone()).div(counts.clone())));
+        let m2s = delta(&m2).sub(
+            delta(&mean).pow(2.0).mul(
+                Expr::metric(count.clone())
+                    .mul(Expr::metric_offset(count.clone(), offset))
+                    .div(counts.clone()),
+            ),
+        );
 
-        let df_over_time = counts
-            .clone()
-            .pipe(|expr| {
-                if let Some(labels) = group_by {
-                    expr.sum_by(labels.clone())
-                } else {
-                    expr
-                }
-            })
-            .sub(1.0)
-            .is_gt(0.0);
-
-        let mean_over_time = means
-            .clone()
-            .pipe(|expr| {
-                if let Some(labels) = group_by {
-                    expr.mul(counts.clone())
-                        .sum_by(labels.clone())
-                        .div(counts.clone().sum_by(labels.clone()))
-                } else {
-                    expr
-                }
-            })
-            .clamp_min(0.0);
+        let (count_over_time, mean_over_time, m2_over_time) = match group_by {
+            None => (counts, means, m2s),
+            Some(labels) => {
+                // Pool the series in each group. Every leg is aggregated
+                // before being combined, so series carrying additional
+                // labels (e.g. the parent labels on relation metrics) are
+                // never matched against each other.
+                let n = counts.clone().sum_by(labels.clone());
+                let sum = means.clone().mul(counts.clone()).sum_by(labels.clone());
+                let sum_sq = m2s.add(counts.mul(means.pow(2.0))).sum_by(labels.clone());
+                let pooled = sum_sq.sub(sum.clone().pow(2.0).div(n.clone()));
+                (n.clone(), sum.div(n), pooled)
+            }
+        };
 
-        let stddev_over_time = Expr::metric(m2.clone())
-            .sub(Expr::metric_offset(m2.clone(), offset))
-            .sub(
-                Expr::metric(mean.clone())
-                    .sub(Expr::metric_offset(mean.clone(), offset))
-                    .pow(2.0)
-                    .mul(
-                        Expr::metric(count.clone())
-                            .mul(Expr::metric_offset(count.clone(), offset))
-                            .div(counts.clone()),
-                    ),
-            )
-            .pipe(|expr| {
-                // To be verified...
-                if let Some(labels) = group_by {
-                    expr.add(counts.clone().mul(means.clone().pow(2.0)))
-                        .sum_by(labels.clone())
-                        .sub(
-                            means
-                                .clone()
-                                .mul(counts.clone())
-                                .sum_by(labels.clone())
-                                .pow(2.0)
-                                .div(counts.clone().sum_by(labels.clone())),
-                        )
-                } else {
-                    expr
-                }
-            })
+        let df_over_time = count_over_time.clone().sub(1.0).is_gt(0.0);
+        let mean_over_time = mean_over_time.clamp_min(0.0);
+        let stddev_over_time = m2_over_time
             .div(df_over_time.clone())
             .clamp_min(0.0)
             .pow(0.5);
@@ -165,6 +135,7 @@ impl WelfordExprs {
             .sub(confidence_interval.clone())
             .clamp_min(0.0);
         let high = mean_over_time.clone().add(confidence_interval.clone());
+        let (unit, display_name, scale_to_seconds) = metric_info(metric);
         Self {
             count: count_over_time,
             mean: mean_over_time.clone(),
@@ -172,10 +143,26 @@ impl WelfordExprs {
             confidence_interval,
             low,
             high,
+            unit,
+            display_name,
+            scale_to_seconds,
         }
     }
 }
 
+/// Unit, display name and scale factor for a metric, falling back to
+/// a neutral unit for metrics not known to the lib.
+fn metric_info(metric: &MetricName) -> (Unit, String, Option<f64>) {
+    match TraceMetric::try_from(metric) {
+        Ok(metric) => (
+            metric.unit(),
+            metric.display_name().to_string(),
+            metric.scale_to_seconds(),
+        ),
+        Err(_) => (NEUTRAL_UNIT, metric.to_string(), None),
+    }
+}
+
 /* Approximate qt(q, df) for fixed q, variable df.
  *
  * R session:
@@ -261,3 +248,129 @@ fn studentst_approx(q: f64, df_over_time: Expr) -> Expr {
 
     Expr::number(n).add(Expr::number(m).div(df_over_time.sub(Expr::number(s))))
 }
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use prometheus_core::{LabelName, MetricName};
+    use prometheus_expr::PromDuration;
+    use unit::{FracPrefix, FrequencyUnit, TimeUnit, Unit, NEUTRAL_UNIT};
+
+    use super::{metric_info, WelfordExprs, WelfordParams};
+
+    fn params(group_by: Option<Vec<LabelName>>) -> WelfordParams {
+        WelfordParams {
+            metric: MetricName::new_static("duration"),
+            labels: std::iter::once((
+                LabelName::new_static("child_service_name"),
+                String::from("frontend"),
+            ))
+            .collect(),
+            group_by,
+            duration: PromDuration::Minutes(5),
+            q: 0.95,
+            labels_selectors: BTreeMap::new(),
+        }
+    }
+
+    fn sums(expr: &impl ToString) -> usize {
+        expr.to_string().matches("sum by (").count()
+    }
+
+    #[test]
+    fn ungrouped_exprs() {
+        let exprs = WelfordExprs::new(&params(None));
+        for expr in [
+            &exprs.count,
+            &exprs.mean,
+            &exprs.stddev,
+            &exprs.confidence_interval,
+        ] {
+            assert_eq!(sums(expr), 0, "{expr}");
+        }
+        assert!(exprs.count.to_string().ends_with("> 0"), "{}", exprs.count);
+    }
+
+    #[test]
+    fn grouped_exprs_aggregate_every_leg() {
+        // Relation series for a child carry the parent labels as well;
+        // grouping them away must not require matching series with
+        // different parent labels.
+        let exprs = WelfordExprs::new(&params(Some(vec![LabelName::new_static(
+            "child_service_name",
+        )])));
+        let group = "sum by (child_service_name) (";
+
+        let count = exprs.count.to_string();
+        assert!(
+            count.starts_with(group) && count.ends_with("> 0)"),
+            "{count}"
+        );
+        assert_eq!(sums(&count), 1);
+
+        // sum(mean * count) / sum(count)
+        let mean = exprs.mean.to_string();
+        assert!(mean.starts_with(&format!("clamp_min({group}")), "{mean}");
+        assert_eq!(sums(&mean), 2, "{mean}");
+
+        // (sum(m2 + count * mean^2) - sum(mean * count)^2 / sum(count))
+        //   / (sum(count) - 1 > 0)
+        assert_eq!(sums(&exprs.stddev), 4, "{}", exprs.stddev);
+        // qt(q, df) * stddev / sum(count)^0.5
+        assert_eq!(
+            sums(&exprs.confidence_interval),
+            6,
+            "{}",
+            exprs.confidence_interval
+        );
+    }
+
+    #[test]
+    fn standard_metric_units() {
+        assert_eq!(
+            metric_info(&MetricName::new_static("duration")),
+            (
+                Unit::Time(TimeUnit::Second(FracPrefix::Micro)),
+                String::from("Duration"),
+                Some(1e-6)
+            )
+        );
+        assert_eq!(
+            metric_info(&MetricName::new_static("busy")),
+            (
+                Unit::Time(TimeUnit::Second(FracPrefix::Nano)),
+                String::from("Busy time"),
+                Some(1e-9)
+            )
+        );
+        assert_eq!(
+            metric_info(&MetricName::new_static("call_rate")),
+            (
+                Unit::Frequency(FrequencyUnit::PerTime(TimeUnit::Second(FracPrefix::Unit))),
+                String::from("Call rate"),
+                None
+            )
+        );
+        assert_eq!(
+            metric_info(&MetricName::new_static("error_rate")),
+            (NEUTRAL_UNIT, String::from("Error rate"), None)
+        );
+    }
+
+    #[test]
+    fn unknown_metric_unit() {
+        assert_eq!(
+            metric_info(&MetricName::new_static("queue_size")),
+            (NEUTRAL_UNIT, String::from("queue_size"), None)
+        );
+    }
+
+    #[cfg(feature = "schemars")]
+    #[test]
+    fn unit_schema() {
+        let schema = schemars::schema_for!(WelfordExprs);
+        let unit = &schema.schema.object.as_ref().unwrap().properties["unit"];
+        assert_ne!(unit, &schemars::schema::Schema::Bool(true));
+    }
+}
@@ -0,0 +1,616 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use std::collections::BTreeMap;
+
+#[cfg(feature = "exprs")]
+use prometheus_core::LabelName;
+#[cfg(feature = "exprs")]
+use prometheus_expr::LabelSelector;
+use serde::{Deserialize, Serialize};
+
+/// The prometheus labels of the service key fields, as `(field,
+/// label)` pairs. The fields are serialized without the `service_`
+/// prefix, but the prefixed labels are accepted on input.
+const SERVICE_LABELS: [(&str, &str); 3] = [
+    ("service_name", "service_name"),
+    ("namespace", "service_namespace"),
+    ("instance_id", "service_instance_id"),
+];
+
+/// The prometheus labels of the operation key fields.
+const OPERATION_LABELS: [(&str, &str); 4] = [
+    SERVICE_LABELS[0],
+    SERVICE_LABELS[1],
+    SERVICE_LABELS[2],
+    ("operation_name", "operation_name"),
+];
+
+/// The prometheus label of the optional span kind. The field has the
+/// same name.
+const SPAN_KIND_LABEL: &str = "span_kind";
+
+/// The labels of the service fields of a relation's parent.
+#[cfg(feature = "exprs")]
+const PARENT_SERVICE_LABELS: [&str; 3] = [
+    "parent_service_name",
+    "parent_service_namespace",
+    "parent_service_instance_id",
+];
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[cfg_attr(feature = "tsify", derive(tsify::Tsify))]
+pub struct ServiceKey {
+    service_name: String,
+    #[serde(alias = "service_namespace")]
+    namespace: Option<String>,
+    #[serde(alias = "service_instance_id")]
+    instance_id: Option<String>,
+}
+
+impl ServiceKey {
+    pub fn new<T: Into<String>>(service_name: T) -> Self {
+        Self {
+            service_name: service_name.into(),
+            namespace: None,
+            instance_id: None,
+        }
+    }
+
+    pub fn namespace<T: Into<String>>(self, namespace: T) -> Self {
+        self.opt_namespace(Some(namespace))
+    }
+
+    pub fn opt_namespace<T: Into<String>>(mut self, namespace: Option<T>) -> Self {
+        self.namespace = namespace.map(|s| s.into());
+        self
+    }
+
+    pub fn instance_id<T: Into<String>>(self, instance_id: T) -> Self {
+        self.opt_instance_id(Some(instance_id))
+    }
+
+    pub fn opt_instance_id<T: Into<String>>(mut self, instance_id: Option<T>) -> Self {
+        self.instance_id = instance_id.map(|s| s.into());
+        self
+    }
+
+    /// Build a key from the labels of a series, as emitted by the
+    /// engine. Returns `None` if the series has no service name.
+    pub fn from_labels(labels: &BTreeMap<String, String>) -> Option<Self> {
+        Some(Self {
+            service_name: labels.get("service_name")?.clone(),
+            namespace: labels.get("service_namespace").cloned(),
+            instance_id: labels.get("service_instance_id").cloned(),
+        })
+    }
+
+    /// Build a key from label selectors, as returned by `labels`. Only
+    /// the equality selectors are used.
+    #[cfg(feature = "exprs")]
+    pub fn from_prom_labels(
+        labels: impl IntoIterator<Item = (LabelName, LabelSelector)>,
+    ) -> Option<Self> {
+        Self::from_labels(&label_map(labels.into_iter()))
+    }
+
+    /// The prometheus label names of the serialized fields, as
+    /// `(field, label)` pairs. In the relation form, the fields are
+    /// prefixed with `child_` and `parent_`, while only the parent
+    /// labels are prefixed (see `parent_labels`).
+    pub const fn prom_label_names() -> &'static [(&'static str, &'static str)] {
+        &SERVICE_LABELS
+    }
+
+    /// The labels selecting this service, as used in the expressions.
+    pub fn to_label_map(&self) -> BTreeMap<String, String> {
+        owned_label_map(self.label_pairs())
+    }
+
+    pub fn into_filter(self) -> ServiceFilter {
+        ServiceFilter {
+            service_name: Some(self.service_name),
+            namespace: self.namespace,
+            instance_id: self.instance_id,
+        }
+    }
+
+    #[cfg(feature = "exprs")]
+    pub fn labels(&self) -> impl Iterator<Item = (LabelName, LabelSelector)> {
+        prom_labels(self.label_pairs())
+    }
+
+    #[cfg(feature = "exprs")]
+    pub fn parent_labels(&self) -> impl Iterator<Item = (LabelName, LabelSelector)> {
+        prom_labels(self.parent_label_pairs())
+    }
+
+    fn label_pairs(&self) -> impl Iterator<Item = (&'static str, &str)> {
+        service_label_pairs(
+            SERVICE_LABELS.map(|(_, label)| label),
+            [
+                Some(self.service_name.as_str()),
+                self.namespace.as_deref(),
+                self.instance_id.as_deref(),
+            ],
+        )
+    }
+
+    #[cfg(feature = "exprs")]
+    fn parent_label_pairs(&self) -> impl Iterator<Item = (&'static str, &str)> {
+        service_label_pairs(
+            PARENT_SERVICE_LABELS,
+            [
+                Some(self.service_name.as_str()),
+                self.namespace.as_deref(),
+                self.instance_id.as_deref(),
+            ],
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Default, Debug)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[cfg_attr(feature = "tsify", derive(tsify::Tsify))]
+pub struct ServiceFilter {
+    service_name: Option<String>,
+    #[serde(alias = "service_namespace")]
+    namespace: Option<String>,
+    #[serde(alias = "service_instance_id")]
+    instance_id: Option<String>,
+}
+
+impl ServiceFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn service_name<T: Into<String>>(self, service_name: T) -> Self {
+        self.opt_service_name(Some(service_name))
+    }
+
+    pub fn opt_service_name<T: Into<String>>(mut self, service_name: Option<T>) -> Self {
+        self.service_name = service_name.map(|s| s.into());
+        self
+    }
+
+    pub fn namespace<T: Into<String>>(self, namespace: T) -> Self {
+        self.opt_namespace(Some(namespace))
+    }
+
+    pub fn opt_namespace<T: Into<String>>(mut self, namespace: Option<T>) -> Self {
+        self.namespace = namespace.map(|s| s.into());
+        self
+    }
+
+    pub fn instance_id<T: Into<String>>(self, instance_id: T) -> Self {
+        self.opt_instance_id(Some(instance_id))
+    }
+
+    pub fn opt_instance_id<T: Into<String>>(mut self, instance_id: Option<T>) -> Self {
+        self.instance_id = instance_id.map(|s| s.into());
+        self
+    }
+
+    /// The labels selecting the matching services.
+    pub fn to_label_map(&self) -> BTreeMap<String, String> {
+        owned_label_map(self.label_pairs())
+    }
+
+    #[cfg(feature = "exprs")]
+    pub fn labels(&self) -> impl Iterator<Item = (LabelName, LabelSelector)> {
+        prom_labels(self.label_pairs())
+    }
+
+    #[cfg(feature = "exprs")]
+    pub fn parent_labels(&self) -> impl Iterator<Item = (LabelName, LabelSelector)> {
+        prom_labels(self.parent_label_pairs())
+    }
+
+    fn label_pairs(&self) -> impl Iterator<Item = (&'static str, &str)> {
+        service_label_pairs(
+            SERVICE_LABELS.map(|(_, label)| label),
+            [
+                self.service_name.as_deref(),
+                self.namespace.as_deref(),
+                self.instance_id.as_deref(),
+            ],
+        )
+    }
+
+    #[cfg(feature = "exprs")]
+    fn parent_label_pairs(&self) -> impl Iterator<Item = (&'static str, &str)> {
+        service_label_pairs(
+            PARENT_SERVICE_LABELS,
+            [
+                self.service_name.as_deref(),
+                self.namespace.as_deref(),
+                self.instance_id.as_deref(),
+            ],
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[cfg_attr(feature = "tsify", derive(tsify::Tsify))]
+pub struct OperationKey {
+    #[serde(flatten)]
+    service: ServiceKey,
+    operation_name: String,
+    /// The kind of the spans ("client", "server", ...), for configs
+    /// that include the span kind in their key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    span_kind: Option<String>,
+}
+
+impl OperationKey {
+    pub fn new<T: Into<String>>(service: ServiceKey, operation_name: T) -> Self {
+        Self {
+            service,
+            operation_name: operation_name.into(),
+            span_kind: None,
+        }
+    }
+
+    pub fn span_kind<T: Into<String>>(self, span_kind: T) -> Self {
+        self.opt_span_kind(Some(span_kind))
+    }
+
+    pub fn opt_span_kind<T: Into<String>>(mut self, span_kind: Option<T>) -> Self {
+        self.span_kind = span_kind.map(|s| s.into());
+        self
+    }
+
+    /// Build a key from the labels of a series, as emitted by the
+    /// engine. Returns `None` if the series has no service or
+    /// operation name.
+    pub fn from_labels(labels: &BTreeMap<String, String>) -> Option<Self> {
+        Some(Self {
+            service: ServiceKey::from_labels(labels)?,
+            operation_name: labels.get("operation_name")?.clone(),
+            span_kind: labels.get(SPAN_KIND_LABEL).cloned(),
+        })
+    }
+
+    /// Build a key from label selectors, as returned by `labels`. Only
+    /// the equality selectors are used.
+    #[cfg(feature = "exprs")]
+    pub fn from_prom_labels(
+        labels: impl IntoIterator<Item = (LabelName, LabelSelector)>,
+    ) -> Option<Self> {
+        Self::from_labels(&label_map(labels.into_iter()))
+    }
+
+    /// The prometheus label names of the serialized fields, as
+    /// `(field, label)` pairs. See `ServiceKey::prom_label_names`. The
+    /// optional span kind is left out; its field and label are both
+    /// named `span_kind`.
+    pub const fn prom_label_names() -> &'static [(&'static str, &'static str)] {
+        &OPERATION_LABELS
+    }
+
+    /// The labels selecting this operation, as used in the expressions.
+    pub fn to_label_map(&self) -> BTreeMap<String, String> {
+        owned_label_map(self.label_pairs())
+    }
+
+    pub fn into_filter(self) -> OperationFilter {
+        OperationFilter {
+            service: self.service.into_filter(),
+            operation_name: Some(self.operation_name),
+            span_kind: self.span_kind,
+        }
+    }
+
+    #[cfg(feature = "exprs")]
+    pub fn labels(&self) -> impl Iterator<Item = (LabelName, LabelSelector)> {
+        prom_labels(self.label_pairs())
+    }
+
+    #[cfg(feature = "exprs")]
+    pub fn parent_labels(&self) -> impl Iterator<Item = (LabelName, LabelSelector)> {
+        prom_labels(
+            self.service
+                .parent_label_pairs()
+                .chain([("parent_operation_name", self.operation_name.as_str())])
+                .chain(
+                    self.span_kind
+                        .as_deref()
+                        .map(|span_kind| ("parent_span_kind", span_kind)),
+                ),
+        )
+    }
+
+    fn label_pairs(&self) -> impl Iterator<Item = (&'static str, &str)> {
+        self.service
+            .label_pairs()
+            .chain([("operation_name", self.operation_name.as_str())])
+            .chain(
+                self.span_kind
+                    .as_deref()
+                    .map(|span_kind| (SPAN_KIND_LABEL, span_kind)),
+            )
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Default, Debug)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[cfg_attr(feature = "tsify", derive(tsify::Tsify))]
+pub struct OperationFilter {
+    #[serde(flatten)]
+    service: ServiceFilter,
+    operation_name: Option<String>,
+    /// Only the operations' spans of this kind ("client", "server",
+    /// ...), for configs that include the span kind in their key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    span_kind: Option<String>,
+}
+
+impl OperationFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn service(mut self, service: ServiceFilter) -> Self {
+        self.service = service;
+        self
+    }
+
+    pub fn operation_name<T: Into<String>>(self, operation_name: T) -> Self {
+        self.opt_operation_name(Some(operation_name))
+    }
+
+    pub fn opt_operation_name<T: Into<String>>(mut self, operation_name: Option<T>) -> Self {
+        self.operation_name = operation_name.map(|s| s.into());
+        self
+    }
+
+    pub fn span_kind<T: Into<String>>(self, span_kind: T) -> Self {
+        self.opt_span_kind(Some(span_kind))
+    }
+
+    pub fn opt_span_kind<T: Into<String>>(mut self, span_kind: Option<T>) -> Self {
+        self.span_kind = span_kind.map(|s| s.into());
+        self
+    }
+
+    /// The labels selecting the matching operations.
+    pub fn to_label_map(&self) -> BTreeMap<String, String> {
+        owned_label_map(self.label_pairs())
+    }
+
+    #[cfg(feature = "exprs")]
+    pub fn labels(&self) -> impl Iterator<Item = (LabelName, LabelSelector)> {
+        prom_labels(self.label_pairs())
+    }
+
+    #[cfg(feature = "exprs")]
+    pub fn parent_labels(&self) -> impl Iterator<Item = (LabelName, LabelSelector)> {
+        prom_labels(
+            self.service
+                .parent_label_pairs()
+                .chain(
+                    self.operation_name
+                        .as_deref()
+                        .map(|operation_name| ("parent_operation_name", operation_name)),
+                )
+                .chain(
+                    self.span_kind
+                        .as_deref()
+                        .map(|span_kind| ("parent_span_kind", span_kind)),
+                ),
+        )
+    }
+
+    fn label_pairs(&self) -> impl Iterator<Item = (&'static str, &str)> {
+        self.service
+            .label_pairs()
+            .chain(
+                self.operation_name
+                    .as_deref()
+                    .map(|operation_name| ("operation_name", operation_name)),
+            )
+            .chain(
+                self.span_kind
+                    .as_deref()
+                    .map(|span_kind| (SPAN_KIND_LABEL, span_kind)),
+            )
+    }
+}
+
+/// The `(label, value)` pairs of the service fields that are set.
+fn service_label_pairs<'a>(
+    labels: [&'static str; 3],
+    values: [Option<&'a str>; 3],
+) -> impl Iterator<Item = (&'static str, &'a str)> {
+    labels
+        .into_iter()
+        .zip(values)
+        .filter_map(|(label, value)| Some((label, value?)))
+}
+
+fn owned_label_map<'a>(
+    labels: impl Iterator<Item = (&'static str, &'a str)>,
+) -> BTreeMap<String, String> {
+    labels
+        .map(|(label, value)| (label.to_string(), value.to_string()))
+        .collect()
+}
+
+/// Equality selectors for the labels. Collected, so that the selectors
+/// do not borrow from the key.
+#[cfg(feature = "exprs")]
+fn prom_labels<'a>(
+    labels: impl Iterator<Item = (&'static str, &'a str)>,
+) -> std::vec::IntoIter<(LabelName, LabelSelector)> {
+    labels
+        .map(|(label, value)| {
+            (
+                LabelName::new_static(label),
+                LabelSelector::Eq(value.to_string()),
+            )
+        })
+        .collect::<Vec<_>>()
+        .into_iter()
+}
+
+/// Collect the values of equality selectors.
+#[cfg(feature = "exprs")]
+pub(crate) fn label_map(
+    labels: impl Iterator<Item = (LabelName, LabelSelector)>,
+) -> BTreeMap<String, String> {
+    labels
+        .filter_map(|(name, selector)| match selector {
+            LabelSelector::Eq(value) => Some((name.into_string(), value)),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use super::{OperationFilter, OperationKey, ServiceFilter, ServiceKey};
+
+    #[test]
+    fn key_label_maps() {
+        let key = OperationKey::new(
+            ServiceKey::new("relation-graph-engine")
+                .namespace("continuousc")
+                .instance_id("demo"),
+            "POST",
+        );
+        let labels = key.to_label_map();
+        assert_eq!(
+            labels,
+            BTreeMap::from_iter(
+                [
+                    ("service_name", "relation-graph-engine"),
+                    ("service_namespace", "continuousc"),
+                    ("service_instance_id", "demo"),
+                    ("operation_name", "POST"),
+                ]
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+            )
+        );
+        assert_eq!(OperationKey::from_labels(&labels), Some(key));
+
+        let key = ServiceKey::new("frontend");
+        let labels = key.to_label_map();
+        assert_eq!(labels.len(), 1);
+        assert_eq!(ServiceKey::from_labels(&labels), Some(key));
+        assert_eq!(OperationKey::from_labels(&labels), None);
+        assert_eq!(ServiceKey::from_labels(&BTreeMap::new()), None);
+
+        assert!(ServiceFilter::new().to_label_map().is_empty());
+        assert_eq!(
+            OperationFilter::new().operation_name("GET").to_label_map(),
+            BTreeMap::from([("operation_name".to_string(), "GET".to_string())])
+        );
+    }
+
+    /// The mapping between the serialized fields and the prometheus
+    /// labels, in one place.
+    #[test]
+    fn prom_label_mapping() {
+        assert_eq!(
+            OperationKey::prom_label_names(),
+            [
+                ("service_name", "service_name"),
+                ("namespace", "service_namespace"),
+                ("instance_id", "service_instance_id"),
+                ("operation_name", "operation_name"),
+            ]
+        );
+        assert_eq!(
+            ServiceKey::prom_label_names(),
+            &OperationKey::prom_label_names()[..3]
+        );
+
+        let key = OperationKey::new(
+            ServiceKey::new("frontend")
+                .namespace("continuousc")
+                .instance_id("demo"),
+            "GET",
+        );
+        let fields = serde_json::to_value(&key).unwrap();
+        let labels = key.to_label_map();
+        assert_eq!(fields.as_object().unwrap().len(), 4);
+        assert_eq!(labels.len(), 4);
+        for (field, label) in OperationKey::prom_label_names() {
+            assert_eq!(fields[field].as_str(), Some(labels[*label].as_str()));
+        }
+
+        // Both forms are accepted on input; the field names are
+        // written.
+        let prom = serde_json::to_value(&labels).unwrap();
+        assert_eq!(serde_json::from_value::<OperationKey>(prom).unwrap(), key);
+        assert_eq!(
+            serde_json::from_value::<OperationFilter>(serde_json::to_value(&labels).unwrap())
+                .unwrap(),
+            key.clone().into_filter()
+        );
+        assert_eq!(serde_json::to_value(&key).unwrap(), fields);
+    }
+
+    #[cfg(feature = "exprs")]
+    #[test]
+    fn prom_selectors() {
+        let key = OperationKey::new(
+            ServiceKey::new("frontend")
+                .namespace("continuousc")
+                .instance_id("demo"),
+            "GET",
+        );
+        let labels = key.to_label_map();
+        assert_eq!(super::label_map(key.labels()), labels);
+        let parent = super::label_map(key.parent_labels());
+        for (_, label) in OperationKey::prom_label_names() {
+            assert_eq!(parent[&format!("parent_{label}")], labels[*label]);
+        }
+
+        assert_eq!(OperationKey::from_prom_labels(key.labels()), Some(key));
+        let service = ServiceKey::new("frontend").namespace("continuousc");
+        assert_eq!(
+            ServiceKey::from_prom_labels(service.labels()),
+            Some(service)
+        );
+        assert_eq!(
+            ServiceKey::from_prom_labels(ServiceFilter::new().labels()),
+            None
+        );
+        assert_eq!(
+            super::label_map(OperationFilter::new().operation_name("GET").labels()),
+            BTreeMap::from([("operation_name".to_string(), "GET".to_string())])
+        );
+    }
+
+    #[cfg(feature = "exprs")]
+    #[test]
+    fn span_kind_selectors() {
+        let key = OperationKey::new(ServiceKey::new("frontend"), "GET").span_kind("client");
+        let labels = key.to_label_map();
+        assert_eq!(labels["span_kind"], "client");
+        assert_eq!(OperationKey::from_labels(&labels), Some(key.clone()));
+        assert_eq!(
+            serde_json::to_value(&key).unwrap()["span_kind"].as_str(),
+            Some("client")
+        );
+        assert_eq!(
+            super::label_map(key.parent_labels())["parent_span_kind"],
+            "client"
+        );
+        assert_ne!(
+            key,
+            OperationKey::new(ServiceKey::new("frontend"), "GET").span_kind("server")
+        );
+        assert_eq!(
+            super::label_map(OperationFilter::new().span_kind("server").labels()),
+            BTreeMap::from([("span_kind".to_string(), "server".to_string())])
+        );
+    }
+}
@@ -2,17 +2,32 @@
  * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
  ******************************************************************************/
 
+mod accum;
 mod anomaly_score;
 mod config;
+#[cfg(feature = "exprs")]
 mod exprs;
+mod key;
+#[cfg(all(test, feature = "exprs"))]
+mod round_trip;
+mod score;
+mod welford;
+mod window;
 
+pub use accum::{AccByKey, Accum, Count, MergeAcc};
 pub use anomaly_score::{
-    ImmediateInterval, InvalidImmediateInterval, InvalidReferenceInterval, ReferenceInterval,
+    ImmediateInterval, Interval, InvalidImmediateInterval, InvalidReferenceInterval,
+    ReferenceInterval,
 };
 pub use config::{Duration, ParseDurationErr, WindowConfig};
+#[cfg(feature = "exprs")]
 pub use exprs::{
-    CombinationFactor, Combine, CombineScores, ItemOrRelation, NoCombine, OperationFilter,
-    OperationKey, OperationOrService, ServiceFilter, ServiceKey, SingleOrMultiple, TraceAggr,
-    TraceAggrKind, TraceAggrKindParseError, TraceExpr, TraceMetric, TraceMetricParseError,
-    TraceObject, TraceObjectBuilder, WelfordExprs, WelfordParams,
+    CombinationFactor, Combine, CombineScores, CompareOp, ItemOrRelation, NoCombine,
+    OperationOrService, OverTimeFunc, OverTimeFuncParseError, SingleOrMultiple, TraceAggr,
+    TraceAggrError, TraceAggrKind, TraceAggrKindParseError, TraceExpr, TraceMetric,
+    TraceMetricParseError, TraceObject, TraceObjectBuilder, WelfordExprs, WelfordParams,
 };
+pub use key::{OperationFilter, OperationKey, ServiceFilter, ServiceKey};
+pub use score::{anomaly_score, WelfordSummary};
+pub use welford::{from_f64, to_f64, Welford};
+pub use window::{SameBin, Window};
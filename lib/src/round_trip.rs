@@ -0,0 +1,341 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+//! Round trips of the serialized forms shared with the UI and the
+//! engine. The JSON forms are checked against `fixtures/serde.json`,
+//! so that format changes are deliberate.
+
+use std::{
+    collections::BTreeSet,
+    fmt::{Debug, Display},
+    str::FromStr,
+};
+
+use ordered_float::NotNan;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    anomaly_score::Interval, CombinationFactor, CombineScores, CompareOp, Duration,
+    ImmediateInterval, NoCombine, OperationFilter, OperationKey, ReferenceInterval, ServiceFilter,
+    ServiceKey, TraceAggr, TraceAggrKind, TraceExpr, TraceMetric, TraceObject, WindowConfig,
+};
+
+const FIXTURES: &str = include_str!("../fixtures/serde.json");
+
+fn check_display<T>(values: impl IntoIterator<Item = T>)
+where
+    T: Display + FromStr + PartialEq + Debug,
+    T::Err: Debug,
+{
+    values.into_iter().for_each(|value| {
+        let s = value.to_string();
+        assert_eq!(s.parse::<T>().unwrap(), value, "{s}");
+    });
+}
+
+// The matches fail to compile when a variant is added, as a reminder
+// to add it to the round trips.
+
+fn trace_metrics() -> impl Iterator<Item = TraceMetric> {
+    [
+        TraceMetric::Duration,
+        TraceMetric::Busy,
+        TraceMetric::CallRate,
+        TraceMetric::ErrorRate,
+    ]
+    .into_iter()
+    .map(|metric| match metric {
+        TraceMetric::Duration
+        | TraceMetric::Busy
+        | TraceMetric::CallRate
+        | TraceMetric::ErrorRate => metric,
+    })
+}
+
+fn trace_aggr_kinds() -> impl Iterator<Item = TraceAggrKind> {
+    [
+        TraceAggrKind::Count,
+        TraceAggrKind::Rate,
+        TraceAggrKind::Mean,
+        TraceAggrKind::Ci,
+        TraceAggrKind::Score,
+        TraceAggrKind::Sufficiency,
+        TraceAggrKind::MeanOffset,
+        TraceAggrKind::Offset,
+    ]
+    .into_iter()
+    .map(|kind| match kind {
+        TraceAggrKind::Count
+        | TraceAggrKind::Rate
+        | TraceAggrKind::Mean
+        | TraceAggrKind::Ci
+        | TraceAggrKind::Score
+        | TraceAggrKind::Sufficiency
+        | TraceAggrKind::MeanOffset
+        | TraceAggrKind::Offset => kind,
+    })
+}
+
+fn immediate_intervals() -> impl Iterator<Item = ImmediateInterval> {
+    [ImmediateInterval::I5m, ImmediateInterval::I15m]
+        .into_iter()
+        .map(|interval| match interval {
+            ImmediateInterval::I5m | ImmediateInterval::I15m => interval,
+        })
+}
+
+fn reference_intervals() -> impl Iterator<Item = ReferenceInterval> {
+    [ReferenceInterval::R7d, ReferenceInterval::R30d]
+        .into_iter()
+        .map(|interval| match interval {
+            ReferenceInterval::R7d | ReferenceInterval::R30d => interval,
+        })
+}
+
+fn durations() -> impl Iterator<Item = Duration> {
+    [0, 1, 30, 1000].into_iter().flat_map(|n| {
+        [
+            Duration::Seconds(n),
+            Duration::Minutes(n),
+            Duration::Hours(n),
+            Duration::Days(n),
+            Duration::Weeks(n),
+        ]
+        .map(|duration| match duration {
+            Duration::Seconds(_)
+            | Duration::Minutes(_)
+            | Duration::Hours(_)
+            | Duration::Days(_)
+            | Duration::Weeks(_) => duration,
+        })
+    })
+}
+
+#[test]
+fn display_round_trips() {
+    check_display(trace_metrics());
+    check_display(trace_aggr_kinds());
+    check_display(immediate_intervals());
+    check_display(reference_intervals());
+    check_display(
+        immediate_intervals()
+            .map(Interval::from)
+            .chain(reference_intervals().map(Interval::from)),
+    );
+    check_display(durations());
+}
+
+/// Display forms are unique, so that parsing is unambiguous.
+#[test]
+fn display_forms_are_unique() {
+    let intervals = immediate_intervals()
+        .map(|interval| interval.to_string())
+        .chain(reference_intervals().map(|interval| interval.to_string()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        intervals.iter().collect::<BTreeSet<_>>().len(),
+        intervals.len()
+    );
+    assert_eq!(
+        trace_aggr_kinds()
+            .map(|kind| kind.to_string())
+            .collect::<BTreeSet<_>>()
+            .len(),
+        trace_aggr_kinds().count()
+    );
+}
+
+/// Check that the JSON form of `value` matches the fixture called
+/// `name`, and that the fixture deserializes to `value`.
+fn check_json<T>(
+    fixtures: &serde_json::Value,
+    checked: &mut BTreeSet<&'static str>,
+    name: &'static str,
+    value: T,
+) where
+    T: Serialize + DeserializeOwned + PartialEq + Debug,
+{
+    let fixture = fixtures
+        .get(name)
+        .unwrap_or_else(|| panic!("missing fixture: {name}"));
+    assert_eq!(&serde_json::to_value(&value).unwrap(), fixture, "{name}");
+    assert_eq!(
+        serde_json::from_value::<T>(fixture.clone()).unwrap(),
+        value,
+        "{name}"
+    );
+    checked.insert(name);
+}
+
+#[test]
+fn json_matches_fixtures() {
+    let fixtures = serde_json::from_str::<serde_json::Value>(FIXTURES).unwrap();
+    let mut checked = BTreeSet::new();
+
+    let combine = || CombineScores::new(CombinationFactor::default());
+    let operation_item = TraceObject::<NoCombine>::builder()
+        .operation()
+        .single()
+        .item(OperationKey::new(
+            ServiceKey::new("frontend").namespace("shop"),
+            "GET",
+        ));
+    let operation_relation = TraceObject::<NoCombine>::builder()
+        .operation()
+        .single()
+        .relation(
+            OperationKey::new(ServiceKey::new("backend"), "POST"),
+            OperationKey::new(ServiceKey::new("frontend"), "GET"),
+        );
+    let operations = TraceObject::<NoCombine>::builder()
+        .operation()
+        .multiple(Some(5))
+        .item(OperationFilter::new().service(ServiceFilter::new().namespace("shop")))
+        .stable_top(true);
+    let service_item = TraceObject::<CombineScores>::builder()
+        .service(combine())
+        .single()
+        .item(ServiceKey::new("frontend").instance_id("pod-1"));
+    let service_relations = TraceObject::<CombineScores>::builder()
+        .service(combine())
+        .multiple(None)
+        .relation(
+            ServiceFilter::new().service_name("backend"),
+            ServiceFilter::new(),
+        )
+        .config("tenant-a");
+
+    check_json(
+        &fixtures,
+        &mut checked,
+        "combination_factor",
+        CombinationFactor::new(NotNan::new(0.5).unwrap()),
+    );
+    check_json(
+        &fixtures,
+        &mut checked,
+        "window_config_default",
+        WindowConfig::default(),
+    );
+    check_json(
+        &fixtures,
+        &mut checked,
+        "window_config_30d",
+        ReferenceInterval::R30d.window_config(),
+    );
+    check_json(
+        &fixtures,
+        &mut checked,
+        "object_operation_single_item",
+        operation_item.clone(),
+    );
+    check_json(
+        &fixtures,
+        &mut checked,
+        "object_operation_single_relation",
+        operation_relation.clone(),
+    );
+    check_json(
+        &fixtures,
+        &mut checked,
+        "object_operation_multiple_item",
+        operations.clone(),
+    );
+    check_json(
+        &fixtures,
+        &mut checked,
+        "object_service_single_item",
+        service_item,
+    );
+    check_json(
+        &fixtures,
+        &mut checked,
+        "object_service_multiple_relation",
+        service_relations.clone(),
+    );
+    check_json(
+        &fixtures,
+        &mut checked,
+        "expr_count",
+        TraceExpr::new(
+            TraceMetric::CallRate,
+            TraceAggr::count(ImmediateInterval::I5m, operation_item.clone()),
+        ),
+    );
+    check_json(
+        &fixtures,
+        &mut checked,
+        "expr_rate",
+        TraceExpr::new(
+            TraceMetric::Duration,
+            TraceAggr::rate(ReferenceInterval::R7d, operation_relation),
+        ),
+    );
+    check_json(
+        &fixtures,
+        &mut checked,
+        "expr_mean",
+        TraceExpr::new(
+            TraceMetric::Busy,
+            TraceAggr::mean(ImmediateInterval::I15m, operations),
+        ),
+    );
+    check_json(
+        &fixtures,
+        &mut checked,
+        "expr_ci",
+        TraceExpr::new(
+            TraceMetric::ErrorRate,
+            TraceAggr::ci(ReferenceInterval::R30d, operation_item.clone()),
+        ),
+    );
+    check_json(
+        &fixtures,
+        &mut checked,
+        "expr_score",
+        TraceExpr::new(
+            TraceMetric::Duration,
+            TraceAggr::score(
+                ImmediateInterval::I5m,
+                ReferenceInterval::R30d,
+                service_relations,
+            ),
+        ),
+    );
+    check_json(
+        &fixtures,
+        &mut checked,
+        "expr_sufficiency",
+        TraceExpr::new(
+            TraceMetric::Duration,
+            TraceAggr::sufficiency(
+                ImmediateInterval::I15m,
+                ReferenceInterval::R7d,
+                operation_item.clone(),
+            ),
+        ),
+    );
+    check_json(
+        &fixtures,
+        &mut checked,
+        "expr_mean_offset",
+        TraceExpr::new(
+            TraceMetric::Duration,
+            TraceAggr::mean_offset(
+                ImmediateInterval::I15m,
+                Duration::Weeks(1),
+                CompareOp::Diff,
+                operation_item,
+            ),
+        ),
+    );
+
+    let names = fixtures
+        .as_object()
+        .unwrap()
+        .keys()
+        .map(String::as_str)
+        .collect::<BTreeSet<_>>();
+    assert_eq!(names, checked, "unchecked fixtures");
+}
@@ -0,0 +1,121 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use rustc_apfloat::ieee::Double;
+use serde::{Deserialize, Serialize};
+
+use crate::welford::{from_f64, to_f64, Welford};
+
+/// Welford statistics over a window: the number of values, their mean
+/// and the sum of squared differences from the mean.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy, Debug)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[cfg_attr(feature = "tsify", derive(tsify::Tsify))]
+#[cfg_attr(feature = "tsify", tsify(from_wasm_abi, into_wasm_abi))]
+pub struct WelfordSummary {
+    pub count: f64,
+    pub mean: f64,
+    pub m2: f64,
+}
+
+impl WelfordSummary {
+    /// The statistics as an accumulator, to share its math with the
+    /// engine.
+    fn welford(&self) -> Welford<Double> {
+        Welford {
+            count: from_f64(self.count),
+            mean: from_f64(self.mean),
+            m2: from_f64(self.m2),
+        }
+    }
+
+    pub fn stddev(&self) -> Option<f64> {
+        self.welford().stddev().map(to_f64)
+    }
+
+    pub fn confidence_interval(&self, q: f64) -> Option<f64> {
+        self.welford().confidence_interval(q).map(to_f64)
+    }
+
+    pub fn lower_bound_of_confidence_interval(&self, q: f64) -> Option<f64> {
+        self.welford()
+            .lower_bound_of_confidence_interval(q)
+            .map(to_f64)
+    }
+
+    pub fn upper_bound_of_confidence_interval(&self, q: f64) -> Option<f64> {
+        self.welford()
+            .upper_bound_of_confidence_interval(q)
+            .map(to_f64)
+    }
+}
+
+/// Calculate the anomaly score for an immediate and a reference window,
+/// as the engine does: the lower bound of the immediate confidence
+/// interval (clamped at zero), divided by the upper bound of the
+/// reference confidence interval plus the offset.
+///
+/// Returns `None` (`undefined` in JavaScript) when the engine would
+/// not write a score either: if a window has a count of zero or NaN,
+/// or if its `m2` is negative or NaN.
+#[cfg_attr(
+    feature = "tsify",
+    wasm_bindgen::prelude::wasm_bindgen(js_name = "anomalyScore")
+)]
+pub fn anomaly_score(
+    immediate: WelfordSummary,
+    reference: WelfordSummary,
+    q: f64,
+    offset: f64,
+) -> Option<f64> {
+    let lower = immediate.lower_bound_of_confidence_interval(q)?.max(0.0);
+    let upper = reference.upper_bound_of_confidence_interval(q)? + offset;
+    Some(lower / upper)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{anomaly_score, WelfordSummary};
+
+    #[test]
+    fn invalid_windows_have_no_score() {
+        let valid = WelfordSummary {
+            count: 5.0,
+            mean: 3.0,
+            m2: 10.0,
+        };
+        let empty = WelfordSummary {
+            count: 0.0,
+            mean: 0.0,
+            m2: 0.0,
+        };
+        let negative = WelfordSummary { m2: -1.0, ..valid };
+        let nan = WelfordSummary {
+            count: f64::NAN,
+            ..valid
+        };
+        assert!(anomaly_score(valid, valid, 0.99, 0.0).is_some());
+        assert!(anomaly_score(empty, valid, 0.99, 0.0).is_none());
+        assert!(anomaly_score(valid, empty, 0.99, 0.0).is_none());
+        assert!(anomaly_score(negative, valid, 0.99, 0.0).is_none());
+        assert!(anomaly_score(nan, valid, 0.99, 0.0).is_none());
+    }
+
+    #[test]
+    fn offset_lowers_score() {
+        let immediate = WelfordSummary {
+            count: 10.0,
+            mean: 20.0,
+            m2: 90.0,
+        };
+        let reference = WelfordSummary {
+            count: 1000.0,
+            mean: 10.0,
+            m2: 9990.0,
+        };
+        let score = anomaly_score(immediate, reference, 0.99, 0.0).unwrap();
+        assert!(score > 1.0);
+        assert!(anomaly_score(immediate, reference, 0.99, 10.0).unwrap() < score);
+    }
+}
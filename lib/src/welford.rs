@@ -0,0 +1,490 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use std::marker::PhantomData;
+
+use rustc_apfloat::{ieee::Double, Float, FloatConvert};
+use serde::{
+    de::{IgnoredAny, Visitor},
+    ser::SerializeStruct,
+    Deserialize, Serialize, Serializer,
+};
+
+use crate::{
+    accum::Accum,
+    window::{SameBin, Window},
+    WelfordSummary,
+};
+
+/// Welford statistics: the number of values, their mean and the sum of
+/// squared differences from the mean. The engine keeps them in quad
+/// precision (`rustc_apfloat::ieee::Quad`), so that the differences
+/// between the bins of a cumulative window stay accurate. They are
+/// saved as the bits of the floats, to load them without loss.
+#[derive(Clone, Default, Debug)]
+pub struct Welford<T> {
+    pub count: T,
+    pub mean: T,
+    pub m2: T,
+}
+
+impl<T> Accum for Welford<T>
+where
+    T: Float + FloatConvert<Double>,
+    Double: FloatConvert<T>,
+{
+    type Input = f64;
+    type Output = Welford<f64>;
+
+    fn insert(&mut self, n: f64) {
+        let n = from_f64(n);
+        let old_mean = self.mean;
+        self.count += from_f64(1.0);
+        self.mean += ((n - old_mean).value / self.count).value;
+        self.m2 = (n - self.mean)
+            .value
+            .mul_add((n - old_mean).value, self.m2)
+            .value;
+    }
+
+    fn merge(&mut self, other: &Self) {
+        let count = (self.count + other.count).value;
+        if count.is_zero() {
+            return;
+        }
+        let delta = (other.mean - self.mean).value;
+        self.mean += (delta * (other.count / count).value).value;
+        self.m2 += (delta * delta)
+            .value
+            .mul_add(((self.count * other.count).value / count).value, other.m2)
+            .value;
+        self.count = count;
+    }
+
+    fn extract(&self) -> Self::Output {
+        Welford {
+            count: to_f64(self.count),
+            mean: to_f64(self.mean),
+            m2: to_f64(self.m2),
+        }
+    }
+}
+
+/// Convert an `f64` to a float of another precision.
+pub fn from_f64<T>(n: f64) -> T
+where
+    T: Float,
+    Double: FloatConvert<T>,
+{
+    Double::from_bits(n.to_bits() as u128)
+        .convert(&mut false)
+        .value
+}
+
+/// Convert a float of another precision to the nearest `f64`.
+pub fn to_f64<T>(n: T) -> f64
+where
+    T: Float + FloatConvert<Double>,
+{
+    f64::from_bits(n.convert(&mut false).value.to_bits() as u64)
+}
+
+impl<T> Welford<T>
+where
+    T: Float,
+    Double: FloatConvert<T>,
+{
+    /// An accumulator holding `value` `weight` times.
+    pub fn weighted(value: f64, weight: f64) -> Self {
+        Self {
+            count: from_f64(weight),
+            mean: from_f64(value),
+            m2: from_f64(0.0),
+        }
+    }
+}
+
+impl<T: Float> SameBin for Welford<T> {
+    fn same_bin(&self, other: &Self) -> bool {
+        self.count.to_bits() == other.count.to_bits()
+            && self.mean.to_bits() == other.mean.to_bits()
+            && self.m2.to_bits() == other.m2.to_bits()
+    }
+}
+
+impl<T> Welford<T>
+where
+    T: Float + FloatConvert<Double>,
+    Double: FloatConvert<T>,
+{
+    /// Scale the weight of the accumulated values by `factor`,
+    /// leaving the mean unchanged.
+    pub fn decay(&mut self, factor: f64) {
+        let factor = from_f64(factor);
+        self.count = (self.count * factor).value;
+        self.m2 = (self.m2 * factor).value;
+    }
+
+    fn valid_count(&self) -> Option<T> {
+        (!self.count.is_zero() && !self.count.is_nan()).then_some(self.count)
+    }
+
+    pub fn valid_mean(&self) -> Option<T> {
+        self.valid_count()?;
+        Some(self.mean)
+    }
+
+    pub fn valid_m2(&self) -> Option<T> {
+        self.valid_count()?;
+        (!self.m2.is_negative() && !self.m2.is_nan()).then_some(self.m2)
+    }
+
+    pub fn stddev(&self) -> Option<T> {
+        let df = (self.valid_count()? - from_f64(1.0)).value;
+        let var = (self.valid_m2()? / df).value;
+        if var.is_nan() || var.is_negative() {
+            return None;
+        }
+        Some(T::from_bits(
+            ieee_apsqrt::sqrt_fast(var.to_bits(), rustc_apfloat::Round::NearestTiesToEven)
+                .0
+                .value,
+        ))
+    }
+
+    pub fn confidence_interval(&self, q: f64) -> Option<T> {
+        let count = self.valid_count()?;
+        let df = (count - from_f64(1.0)).value;
+        Some(
+            ((self.stddev()? * from_f64(distrs::StudentsT::cdf(q, to_f64(df)))).value / count)
+                .value,
+        )
+    }
+
+    pub fn lower_bound_of_confidence_interval(&self, q: f64) -> Option<T> {
+        Some((self.valid_mean()? - self.confidence_interval(q)?).value)
+    }
+
+    pub fn upper_bound_of_confidence_interval(&self, q: f64) -> Option<T> {
+        Some((self.valid_mean()? + self.confidence_interval(q)?).value)
+    }
+
+    /// The statistics as `f64`, e.g. to compute an `anomaly_score`.
+    pub fn summary(&self) -> WelfordSummary {
+        WelfordSummary {
+            count: to_f64(self.count),
+            mean: to_f64(self.mean),
+            m2: to_f64(self.m2),
+        }
+    }
+}
+
+impl<T> Window<Welford<T>>
+where
+    T: Float + FloatConvert<Double>,
+    Double: FloatConvert<T>,
+{
+    /// The accumulator of the values in the window, i.e. the
+    /// difference between the current and the first bin. Since
+    /// windows tolerate time regressions (out-of-order traces), the
+    /// first bin can end up with a larger count than the current one;
+    /// the count is clamped at zero.
+    pub fn values(&self) -> Welford<T> {
+        let (a, ab) = (self.first(), self.current());
+        let count = (ab.count - a.count).value.max(from_f64(0.0));
+        let mean_diff = (ab.mean - a.mean).value;
+        Welford {
+            count,
+            mean: (a.mean + (mean_diff * (ab.count / count).value).value).value,
+            m2: ((ab.m2 - a.m2).value
+                - ((mean_diff * mean_diff).value * ((ab.count * a.count).value / count).value)
+                    .value)
+                .value,
+        }
+    }
+
+    /// Whether the window regressed: its first bin holds more values
+    /// than the current one, or the sum of squared differences of its
+    /// values is negative. Empty windows did not regress.
+    pub fn regressed(&self) -> bool {
+        self.first().count > self.current().count || self.values().m2 < from_f64(0.0)
+    }
+
+    /// The number of values in the window, clamped at zero.
+    pub fn count(&self) -> T {
+        self.values().count
+    }
+
+    pub fn mean(&self) -> Option<T> {
+        self.values().valid_mean()
+    }
+
+    pub fn confidence_interval(&self, q: f64) -> Option<T> {
+        self.values().confidence_interval(q)
+    }
+
+    pub fn lower_bound_of_confidence_interval(&self, q: f64) -> Option<T> {
+        self.values().lower_bound_of_confidence_interval(q)
+    }
+
+    /// The statistics of the values in the window, as `f64`.
+    pub fn summary(&self) -> WelfordSummary {
+        self.values().summary()
+    }
+}
+
+impl<T: Float> Serialize for Welford<T> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut s = serializer.serialize_struct("Welford", 3)?;
+        s.serialize_field("count", &self.count.to_bits())?;
+        s.serialize_field("mean", &self.mean.to_bits())?;
+        s.serialize_field("m2", &self.m2.to_bits())?;
+        s.end()
+    }
+}
+
+impl<'de, T: Float> Deserialize<'de> for Welford<T> {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        struct WelfordVisitor<T>(PhantomData<T>);
+
+        impl<'de, T: Float> Visitor<'de> for WelfordVisitor<T> {
+            type Value = Welford<T>;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                write!(formatter, "struct Welford")
+            }
+
+            fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+            where
+                A: serde::de::MapAccess<'de>,
+            {
+                let (mut count, mut mean, mut m2) = (None, None, None);
+
+                while let Some(field) = map.next_key::<String>()? {
+                    match field.as_str() {
+                        "count" => {
+                            count = Some(T::from_bits(map.next_value()?));
+                        }
+                        "mean" => {
+                            mean = Some(T::from_bits(map.next_value()?));
+                        }
+                        "m2" => {
+                            m2 = Some(T::from_bits(map.next_value()?));
+                        }
+                        _ => {
+                            let _ = map.next_value::<IgnoredAny>()?;
+                        }
+                    }
+                }
+
+                let count = count.ok_or_else(|| {
+                    <A::Error as serde::de::Error>::custom("missing field 'count'")
+                })?;
+                let mean = mean.ok_or_else(|| {
+                    <A::Error as serde::de::Error>::custom("missing field 'mean'")
+                })?;
+                let m2 =
+                    m2.ok_or_else(|| <A::Error as serde::de::Error>::custom("missing field 'm2'"))?;
+
+                Ok(Welford { count, mean, m2 })
+            }
+        }
+
+        deserializer.deserialize_struct(
+            "Welford",
+            &["count", "mean", "m2"],
+            WelfordVisitor(PhantomData),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use chrono::{DateTime, Utc};
+    use rustc_apfloat::ieee::Quad;
+
+    use super::{to_f64, Welford};
+    use crate::{accum::Accum, window::Window, Duration, WelfordSummary, WindowConfig};
+
+    fn welford(values: &[f64]) -> Welford<Quad> {
+        let mut acc = Welford::default();
+        values.iter().for_each(|v| acc.insert(*v));
+        acc
+    }
+
+    /// Build a window whose first bin holds more values than the
+    /// current one, as happens after out-of-order inserts.
+    fn regressed_window() -> Window<Welford<Quad>> {
+        let start = DateTime::<Utc>::from_timestamp(1_699_999_980, 0).unwrap();
+        Window::new_init(
+            start,
+            |t| {
+                if t == start {
+                    welford(&[1.0, 2.0])
+                } else {
+                    welford(&[1.0, 2.0, 3.0, 4.0, 5.0])
+                }
+            },
+            &WindowConfig {
+                bin_width: Duration::Seconds(30),
+                num_bins: 4,
+            },
+        )
+    }
+
+    #[test]
+    fn regressed_window_count_is_clamped() {
+        let window = regressed_window();
+        assert!(window.regressed());
+        assert_eq!(to_f64(window.count()), 0.0);
+        assert!(window.mean().is_none());
+        assert!(window.values().valid_m2().is_none());
+        assert!(window.values().stddev().is_none());
+        assert!(window.confidence_interval(0.99).is_none());
+        assert!(window.lower_bound_of_confidence_interval(0.99).is_none());
+        assert!(window
+            .values()
+            .upper_bound_of_confidence_interval(0.99)
+            .is_none());
+    }
+
+    #[test]
+    fn valid_window_statistics() {
+        let start = DateTime::<Utc>::from_timestamp(1_699_999_980, 0).unwrap();
+        let window = Window::new_init(
+            start,
+            |t| {
+                if t == start {
+                    welford(&[1.0, 2.0, 3.0, 4.0, 5.0])
+                } else {
+                    welford(&[])
+                }
+            },
+            &WindowConfig {
+                bin_width: Duration::Seconds(30),
+                num_bins: 4,
+            },
+        );
+        assert!(!window.regressed());
+        assert_eq!(to_f64(window.count()), 5.0);
+        assert_eq!(to_f64(window.mean().unwrap()), 3.0);
+        assert_eq!(to_f64(window.values().valid_m2().unwrap()), 10.0);
+        assert!(to_f64(window.confidence_interval(0.99).unwrap()) > 0.0);
+    }
+
+    /// A window as saved by the engine: the floats are saved as their
+    /// bits, which must not change, so that saved states can be
+    /// loaded without loss.
+    #[test]
+    fn saved_window_format() {
+        const SAVED: &str = include_str!("../fixtures/window.json");
+        let start = DateTime::<Utc>::from_timestamp(1_699_999_980, 0).unwrap();
+        let window = Window::new_init(
+            start,
+            |t| {
+                if t == start {
+                    welford(&[1.0, 2.0, 3.0, 4.0, 5.0])
+                } else {
+                    welford(&[])
+                }
+            },
+            &WindowConfig {
+                bin_width: Duration::Seconds(30),
+                num_bins: 4,
+            },
+        );
+        assert_eq!(serde_json::to_string(&window).unwrap(), SAVED.trim_end());
+
+        let loaded = serde_json::from_str::<Window<Welford<Quad>>>(SAVED).unwrap();
+        assert_eq!(
+            loaded.summary(),
+            WelfordSummary {
+                count: 5.0,
+                mean: 3.0,
+                m2: 10.0
+            }
+        );
+        assert_eq!(loaded.current().summary(), window.current().summary());
+        assert_eq!(serde_json::to_string(&loaded).unwrap(), SAVED.trim_end());
+    }
+
+    /// A window with different, non-trivial statistics in every bin,
+    /// which is saved without run-length encoding.
+    fn busy_window() -> Window<Welford<Quad>> {
+        let start = DateTime::<Utc>::from_timestamp(1_699_999_980, 0).unwrap();
+        Window::new_init(
+            start,
+            |t| {
+                let k = (t - start).num_seconds() as f64 / 30.0;
+                welford(&[k, 0.1 * k + 3.7, 2.0 * k + 1.0 / 3.0])
+            },
+            &WindowConfig {
+                bin_width: Duration::Seconds(30),
+                num_bins: 8,
+            },
+        )
+    }
+
+    /// A window in a CBOR state saved by the engine before the windows
+    /// moved to this crate: it must load, and save back byte for byte.
+    ///
+    /// The fixture is written by this test with `UPDATE_GOLDEN` set. It
+    /// is to be generated with the same test on the engine of before
+    /// the move (with the engine's `window` and `welford` modules), not
+    /// with this crate.
+    #[test]
+    fn saved_cbor_window() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/window.cbor");
+        let window = busy_window();
+        let encode = |window: &Window<Welford<Quad>>| {
+            let mut data = Vec::new();
+            ciborium::into_writer(window, &mut data).unwrap();
+            data
+        };
+        if std::env::var_os("UPDATE_GOLDEN").is_some() {
+            std::fs::write(&path, encode(&window)).unwrap();
+            return;
+        }
+
+        let saved = std::fs::read(&path).unwrap_or_else(|_| {
+            panic!(
+                "{} is missing; rerun with UPDATE_GOLDEN=1 to generate it",
+                path.display()
+            )
+        });
+        let loaded = ciborium::from_reader::<Window<Welford<Quad>>, _>(&saved[..]).unwrap();
+        assert_eq!(loaded.summary(), window.summary());
+        assert!(loaded
+            .bins()
+            .zip(window.bins())
+            .all(|(a, b)| a.summary() == b.summary()));
+        assert_eq!(encode(&loaded), saved);
+    }
+
+    #[test]
+    fn merge_matches_sequential_insert() {
+        let mut acc = welford(&[1.0, 2.0, 4.0]);
+        acc.merge(&welford(&[8.0, 16.0]));
+        let expected = welford(&[1.0, 2.0, 4.0, 8.0, 16.0]).extract();
+        let merged = acc.extract();
+        assert_eq!(merged.count, expected.count);
+        assert!((merged.mean - expected.mean).abs() < 1e-12);
+        assert!((merged.m2 - expected.m2).abs() < 1e-9);
+
+        let mut empty = Welford::<Quad>::default();
+        empty.merge(&welford(&[3.0, 5.0]));
+        assert_eq!(to_f64(empty.mean), 4.0);
+        assert_eq!(to_f64(empty.m2), 2.0);
+        empty.merge(&Welford::default());
+        assert_eq!(to_f64(empty.count), 2.0);
+    }
+}
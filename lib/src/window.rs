@@ -0,0 +1,691 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use chrono::{DateTime, DurationRound, TimeDelta, Utc};
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{Duration, WindowConfig};
+
+/// A sliding window of `num_bins` bins of `bin_width`. The bins are
+/// accumulators (see `Accum`): either of the values inserted in the
+/// bin's interval, or cumulative, in which case the statistics of the
+/// window are the difference between the current and the first bin.
+#[derive(Clone, Debug)]
+pub struct Window<T> {
+    i: usize,
+    start: DateTime<Utc>,
+    bin_width: Duration,
+    ring: Ring<T>,
+}
+
+/// The bins of a window, in ring buffer order. Windows of mostly idle
+/// groups hold long runs of equal bins. These are run-length encoded
+/// when the window is saved or loaded, if there are at most a quarter
+/// as many runs as bins, and stay encoded while the group is idle.
+#[derive(Clone, Debug)]
+enum Ring<T> {
+    Dense(Box<[T]>),
+    Rle(Vec<(T, u32)>),
+}
+
+/// Run-length encoding is only used for windows with at least this many
+/// bins per run on average.
+const MIN_BINS_PER_RUN: usize = 4;
+
+/// Equality of bins, for the run-length encoding of windows.
+pub trait SameBin {
+    fn same_bin(&self, other: &Self) -> bool;
+}
+
+impl<T: SameBin> SameBin for &T {
+    fn same_bin(&self, other: &Self) -> bool {
+        (*self).same_bin(*other)
+    }
+}
+
+impl<T: Default> Window<T> {
+    pub fn new(start: DateTime<Utc>, config: &WindowConfig) -> Self {
+        Self::new_init(start, |_| T::default(), config)
+    }
+
+    // pub fn advance(&mut self, t: DateTime<Utc>) {
+    //     self.advance_init(t, |_| T::default());
+    // }
+
+    pub fn advance_with<'a, F, U>(
+        &'a mut self,
+        t: DateTime<Utc>,
+        output: F,
+    ) -> impl Iterator<Item = U> + 'a
+    where
+        T: Clone + SameBin,
+        F: FnMut(&Self) -> U + 'a,
+    {
+        self.advance_with_init(t, |_| T::default(), output)
+    }
+}
+
+impl<T> Window<T> {
+    pub fn new_init<F>(start: DateTime<Utc>, mut init: F, config: &WindowConfig) -> Self
+    where
+        F: FnMut(DateTime<Utc>) -> T,
+    {
+        let start = start
+            .duration_trunc(config.bin_width.to_time_delta())
+            .unwrap();
+        let bin_width = config.bin_width.to_time_delta();
+        Window {
+            i: 0,
+            start,
+            bin_width: config.bin_width,
+            ring: Ring::Dense(
+                (0..config.num_bins)
+                    .map(|i| init(start + bin_width * i as i32))
+                    .collect(),
+            ),
+        }
+    }
+
+    pub fn advance_init<F>(&mut self, t: DateTime<Utc>, mut init: F)
+    where
+        T: Clone + SameBin,
+        F: FnMut(DateTime<Utc>) -> T,
+    {
+        let t = t.duration_trunc(self.bin_width()).unwrap();
+        // T can regress from one query to the next if traces are
+        // written out-of-order.
+        // assert!(t >= self.start);
+        loop {
+            let next = self.start + self.bin_width();
+            if next <= t {
+                self.i = (self.i + 1) % self.num_bins();
+                self.ring.set(self.i, init(next));
+                self.start = next;
+            } else {
+                break;
+            }
+        }
+    }
+
+    pub fn advance_with_init<'a, F, G, U>(
+        &'a mut self,
+        t: DateTime<Utc>,
+        mut init: F,
+        mut output: G,
+    ) -> impl Iterator<Item = U> + 'a
+    where
+        T: Clone + SameBin,
+        F: FnMut(DateTime<Utc>) -> T + 'a,
+        G: FnMut(&Self) -> U + 'a,
+    {
+        let t = t.duration_trunc(self.bin_width()).unwrap();
+        // T can regress from one query to the next if traces are
+        // written out-of-order.
+        // assert!(t >= self.start);
+        std::iter::from_fn(move || {
+            let next = self.start + self.bin_width();
+            if next <= t {
+                let value = output(&*self);
+                self.i = (self.i + 1) % self.num_bins();
+                self.ring.set(self.i, init(next));
+                self.start = next;
+                Some(value)
+            } else {
+                None
+            }
+        })
+    }
+
+    // pub fn start(&self) -> DateTime<Utc> {
+    //     self.start
+    // }
+
+    // pub fn end(&self) -> DateTime<Utc> {
+    //     self.start + self.bin_width
+    // }
+
+    pub const fn bin_width(&self) -> TimeDelta {
+        self.bin_width.to_time_delta()
+    }
+
+    pub const fn num_bins(&self) -> usize {
+        self.ring.len()
+    }
+
+    pub const fn minutes(&self) -> f64 {
+        self.bin_width.multiply(self.ring.len() as u32).minutes()
+    }
+
+    pub fn compatible_with(&self, config: &WindowConfig) -> bool {
+        self.bin_width() == config.bin_width.to_time_delta() && self.num_bins() == config.num_bins
+    }
+
+    pub const fn current(&self) -> &T {
+        self.ring.get(self.i)
+    }
+
+    pub const fn first(&self) -> &T {
+        self.ring.get((self.i + 1) % self.ring.len())
+    }
+
+    pub fn current_mut(&mut self) -> &mut T
+    where
+        T: Clone + SameBin,
+    {
+        self.ring.get_mut(self.i)
+    }
+
+    pub fn bins(&self) -> impl Iterator<Item = &T> {
+        let first = (self.i + 1) % self.num_bins();
+        self.ring
+            .iter()
+            .skip(first)
+            .chain(self.ring.iter().take(first))
+    }
+
+    /// The bin holding `t`, for values inserted out-of-order. Times
+    /// outside the window map onto the current bin.
+    pub fn bin_mut(&mut self, t: DateTime<Utc>) -> &mut T
+    where
+        T: Clone + SameBin,
+    {
+        let n = self.num_bins();
+        let t = t.duration_trunc(self.bin_width()).unwrap();
+        let back = (self.start - t).num_milliseconds() / self.bin_width().num_milliseconds();
+        let back = usize::try_from(back).ok().filter(|k| *k < n).unwrap_or(0);
+        self.ring.get_mut((self.i + n - back) % n)
+    }
+
+    /// Apply `f` to the bins started after `t`. In a window of
+    /// cumulative bins, this adds a value inserted out-of-order, at
+    /// `t`, to the accumulators taken since.
+    pub fn correct_after<F: FnMut(&mut T)>(&mut self, t: DateTime<Utc>, mut f: F)
+    where
+        T: Clone + SameBin,
+    {
+        let n = self.num_bins();
+        let bin_width = self.bin_width();
+        let after = (0..n)
+            .take_while(|k| self.start - bin_width * *k as i32 > t)
+            .count();
+        (0..after).for_each(|k| f(self.ring.get_mut((self.i + n - k) % n)));
+    }
+}
+
+impl<T> Ring<T> {
+    const fn len(&self) -> usize {
+        match self {
+            Ring::Dense(bins) => bins.len(),
+            Ring::Rle(runs) => total(runs.as_slice()),
+        }
+    }
+
+    const fn get(&self, k: usize) -> &T {
+        match self {
+            Ring::Dense(bins) => &bins[k],
+            Ring::Rle(runs) => {
+                let runs = runs.as_slice();
+                &runs[locate(runs, k).0].0
+            }
+        }
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &T> {
+        let (bins, runs) = match self {
+            Ring::Dense(bins) => (&bins[..], &[][..]),
+            Ring::Rle(runs) => (&[][..], &runs[..]),
+        };
+        bins.iter().chain(
+            runs.iter()
+                .flat_map(|(value, n)| std::iter::repeat(value).take(*n as usize)),
+        )
+    }
+}
+
+impl<T: Clone + SameBin> Ring<T> {
+    fn get_mut(&mut self, k: usize) -> &mut T {
+        if let Ring::Rle(runs) = self {
+            isolate(runs, k);
+            self.expand_if_dense();
+        }
+        match self {
+            Ring::Dense(bins) => &mut bins[k],
+            Ring::Rle(runs) => {
+                let (r, _) = locate(runs, k);
+                &mut runs[r].0
+            }
+        }
+    }
+
+    fn set(&mut self, k: usize, value: T) {
+        match self {
+            Ring::Dense(bins) => bins[k] = value,
+            Ring::Rle(runs) => {
+                let r = isolate(runs, k);
+                runs[r].0 = value;
+                if r + 1 < runs.len() && runs[r + 1].0.same_bin(&runs[r].0) {
+                    let (_, n) = runs.remove(r + 1);
+                    runs[r].1 += n;
+                }
+                if r > 0 && runs[r - 1].0.same_bin(&runs[r].0) {
+                    let (_, n) = runs.remove(r);
+                    runs[r - 1].1 += n;
+                }
+            }
+        }
+        self.expand_if_dense();
+    }
+
+    /// Switch back to a dense ring once there are more runs than half
+    /// the bins, when the runs no longer save much memory.
+    fn expand_if_dense(&mut self) {
+        if let Ring::Rle(runs) = self {
+            if runs.len() * 2 > total(runs) {
+                let bins = std::mem::take(runs)
+                    .into_iter()
+                    .flat_map(|(value, n)| std::iter::repeat(value).take(n as usize))
+                    .collect();
+                *self = Ring::Dense(bins);
+            }
+        }
+    }
+}
+
+impl<T: SameBin> Ring<T> {
+    /// The runs to save, if the ring is sparse enough.
+    fn runs(&self) -> Option<Vec<(&T, u32)>> {
+        match self {
+            Ring::Dense(bins) => is_sparse(count_runs(bins), bins.len())
+                .then(|| bins.iter().fold(Vec::new(), push_run)),
+            Ring::Rle(runs) => Some(runs.iter().map(|(value, n)| (value, *n)).collect()),
+        }
+    }
+
+    /// Run-length encode a dense ring, if it is sparse enough.
+    fn compact(self) -> Self {
+        match self {
+            Ring::Dense(bins) if is_sparse(count_runs(&bins), bins.len()) => {
+                Ring::Rle(bins.into_vec().into_iter().fold(Vec::new(), push_run))
+            }
+            ring => ring,
+        }
+    }
+}
+
+fn is_sparse(runs: usize, bins: usize) -> bool {
+    runs * MIN_BINS_PER_RUN <= bins
+}
+
+fn count_runs<T: SameBin>(bins: &[T]) -> usize {
+    1 + bins
+        .windows(2)
+        .filter(|pair| !pair[0].same_bin(&pair[1]))
+        .count()
+}
+
+fn push_run<T: SameBin>(mut runs: Vec<(T, u32)>, value: T) -> Vec<(T, u32)> {
+    match runs.last_mut() {
+        Some((last, n)) if last.same_bin(&value) => *n += 1,
+        _ => runs.push((value, 1)),
+    }
+    runs
+}
+
+const fn total<T>(runs: &[(T, u32)]) -> usize {
+    let (mut n, mut r) = (0, 0);
+    while r < runs.len() {
+        n += runs[r].1 as usize;
+        r += 1;
+    }
+    n
+}
+
+/// The run holding bin `k`, and the offset of the bin in that run.
+const fn locate<T>(runs: &[(T, u32)], k: usize) -> (usize, usize) {
+    let (mut start, mut r) = (0, 0);
+    while r < runs.len() {
+        let end = start + runs[r].1 as usize;
+        if k < end {
+            return (r, k - start);
+        }
+        start = end;
+        r += 1;
+    }
+    panic!("bin index out of range")
+}
+
+/// Split the runs so that bin `k` has a run of its own, and return the
+/// index of that run.
+fn isolate<T: Clone>(runs: &mut Vec<(T, u32)>, k: usize) -> usize {
+    let (r, offset) = locate(runs, k);
+    let after = runs[r].1 - offset as u32 - 1;
+    if after > 0 {
+        let value = runs[r].0.clone();
+        runs.insert(r + 1, (value, after));
+    }
+    if offset > 0 {
+        let value = runs[r].0.clone();
+        runs[r].1 = offset as u32;
+        runs.insert(r + 1, (value, 1));
+        r + 1
+    } else {
+        runs[r].1 = 1;
+        r
+    }
+}
+
+/// The saved form of a window. States saved before the run-length
+/// encoding have `ring` only.
+#[derive(Serialize)]
+struct SavedWindow<'a, T> {
+    i: usize,
+    start: DateTime<Utc>,
+    bin_width: Duration,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ring: Option<&'a [T]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    runs: Option<Vec<(&'a T, u32)>>,
+}
+
+#[derive(Deserialize)]
+struct LoadedWindow<T> {
+    i: usize,
+    start: DateTime<Utc>,
+    bin_width: Duration,
+    #[serde(default)]
+    ring: Option<Box<[T]>>,
+    #[serde(default)]
+    runs: Option<Vec<(T, u32)>>,
+}
+
+impl<T: Serialize + SameBin> Serialize for Window<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let runs = self.ring.runs();
+        let ring = match &self.ring {
+            Ring::Dense(bins) if runs.is_none() => Some(&bins[..]),
+            _ => None,
+        };
+        SavedWindow {
+            i: self.i,
+            start: self.start,
+            bin_width: self.bin_width,
+            ring,
+            runs,
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de, T: Deserialize<'de> + SameBin> Deserialize<'de> for Window<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let window = LoadedWindow::deserialize(deserializer)?;
+        let ring = match (window.ring, window.runs) {
+            (Some(bins), None) => Ring::Dense(bins).compact(),
+            (None, Some(runs)) if runs.iter().all(|(_, n)| *n > 0) => Ring::Rle(runs),
+            (None, Some(_)) => return Err(D::Error::custom("empty run in window")),
+            _ => return Err(D::Error::custom("expected one of ring or runs")),
+        };
+        // Whether the number of bins matches the config is checked by
+        // the owners of the window (see `compatible_with`), which start
+        // over otherwise. It must be usable until then.
+        let len = ring.len();
+        if len == 0 || len > u32::MAX as usize {
+            return Err(D::Error::custom(format_args!(
+                "invalid number of bins in window: {len}"
+            )));
+        }
+        if window.i >= len {
+            return Err(D::Error::custom(format_args!(
+                "bin index {} out of range for a window of {len} bins",
+                window.i
+            )));
+        }
+        Ok(Self {
+            i: window.i,
+            start: window.start,
+            bin_width: window.bin_width,
+            ring,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Instant;
+
+    use chrono::{DateTime, TimeDelta, Utc};
+    use rustc_apfloat::ieee::Quad;
+    use serde::Serialize;
+
+    use super::{Ring, SameBin, Window};
+    use crate::{
+        accum::Accum,
+        welford::{to_f64, Welford},
+        Duration, ReferenceInterval, WindowConfig,
+    };
+
+    fn start() -> DateTime<Utc> {
+        DateTime::from_timestamp(1_699_999_980, 0).unwrap()
+    }
+
+    fn config() -> WindowConfig {
+        WindowConfig {
+            bin_width: Duration::Seconds(30),
+            num_bins: 40,
+        }
+    }
+
+    /// Advance a cumulative window (as kept for the anomaly score) over
+    /// `bins` bins, inserting a value in the bins for which `active`
+    /// returns true.
+    fn advance(window: &mut Window<Welford<Quad>>, bins: usize, active: impl Fn(usize) -> bool) {
+        (0..bins).for_each(|bin| {
+            let acc = window.current().clone();
+            let t = window.start + window.bin_width();
+            window.advance_init(t, |_| acc.clone());
+            if active(bin) {
+                window.current_mut().insert(bin as f64);
+            }
+        });
+    }
+
+    fn window(active: &[usize]) -> Window<Welford<Quad>> {
+        let mut window = Window::new(start(), &config());
+        advance(&mut window, 60, |bin| active.contains(&bin));
+        window
+    }
+
+    /// The derived form windows were saved in before the run-length
+    /// encoding.
+    #[derive(Serialize)]
+    struct DenseWindow<'a> {
+        i: usize,
+        start: DateTime<Utc>,
+        bin_width: Duration,
+        ring: Vec<&'a Welford<Quad>>,
+    }
+
+    fn dense(window: &Window<Welford<Quad>>) -> DenseWindow<'_> {
+        DenseWindow {
+            i: window.i,
+            start: window.start,
+            bin_width: window.bin_width,
+            ring: window.ring.iter().collect(),
+        }
+    }
+
+    fn encode<T: Serialize>(value: &T) -> Vec<u8> {
+        let mut data = Vec::new();
+        ciborium::into_writer(value, &mut data).unwrap();
+        data
+    }
+
+    fn decode(data: &[u8]) -> Window<Welford<Quad>> {
+        ciborium::from_reader(data).unwrap()
+    }
+
+    fn is_rle(window: &Window<Welford<Quad>>) -> bool {
+        matches!(window.ring, Ring::Rle(_))
+    }
+
+    fn assert_same(a: &Window<Welford<Quad>>, b: &Window<Welford<Quad>>) {
+        assert_eq!(a.i, b.i);
+        assert_eq!(a.start, b.start);
+        assert_eq!(a.num_bins(), b.num_bins());
+        assert!(a.first().same_bin(b.first()));
+        assert!(a.current().same_bin(b.current()));
+        assert!(a.bins().zip(b.bins()).all(|(x, y)| x.same_bin(y)));
+    }
+
+    #[test]
+    fn load_dense_state() {
+        let idle = window(&[10, 45, 50]);
+        let loaded = decode(&encode(&dense(&idle)));
+        assert!(is_rle(&loaded));
+        assert_same(&loaded, &idle);
+
+        let busy = window(&(0..60).collect::<Vec<_>>());
+        let loaded = decode(&encode(&dense(&busy)));
+        assert!(!is_rle(&loaded));
+        assert_same(&loaded, &busy);
+        assert_eq!(encode(&loaded), encode(&dense(&busy)));
+    }
+
+    #[test]
+    fn rle_round_trip() {
+        let idle = window(&[10, 45, 50]);
+        let data = encode(&idle);
+        assert!(data.len() * 4 < encode(&dense(&idle)).len());
+        let loaded = decode(&data);
+        assert!(is_rle(&loaded));
+        assert_same(&loaded, &idle);
+        assert_eq!(encode(&loaded), data);
+    }
+
+    #[test]
+    fn advance_after_load() {
+        let mut idle = window(&[10, 45, 50]);
+        let mut loaded = decode(&encode(&idle));
+        (0..3).for_each(|_| {
+            advance(&mut idle, 10, |bin| bin == 3);
+            advance(&mut loaded, 10, |bin| bin == 3);
+            assert!(is_rle(&loaded));
+            assert_same(&loaded, &idle);
+        });
+
+        advance(&mut idle, 40, |_| true);
+        advance(&mut loaded, 40, |_| true);
+        assert!(!is_rle(&loaded));
+        assert_same(&loaded, &idle);
+    }
+
+    #[test]
+    fn invalid_runs() {
+        #[derive(Serialize)]
+        struct Runs {
+            i: usize,
+            start: DateTime<Utc>,
+            bin_width: Duration,
+            runs: Vec<(Welford<Quad>, u32)>,
+        }
+        let runs = |runs| Runs {
+            i: 0,
+            start: start(),
+            bin_width: Duration::Seconds(30),
+            runs,
+        };
+        let load = |data: Vec<u8>| ciborium::from_reader::<Window<Welford<Quad>>, _>(&data[..]);
+        assert!(load(encode(&runs(vec![(Welford::default(), 40)]))).is_ok());
+        assert!(load(encode(&runs(vec![
+            (Welford::default(), 40),
+            (Welford::default(), 0)
+        ])))
+        .is_err());
+        assert!(load(encode(&runs(Vec::new()))).is_err());
+    }
+
+    #[test]
+    fn invalid_index() {
+        let idle = window(&[10, 45, 50]);
+        let load = |window: &Window<Welford<Quad>>| {
+            ciborium::from_reader::<Window<Welford<Quad>>, _>(&encode(window)[..])
+        };
+        let mut rle = decode(&encode(&idle));
+        rle.i = rle.num_bins();
+        assert!(load(&rle).is_err());
+
+        let mut dense = window(&(0..60).collect::<Vec<_>>());
+        dense.i = dense.num_bins() + 1;
+        assert!(load(&dense).is_err());
+    }
+
+    #[test]
+    fn out_of_order_values() {
+        let mut window = window(&[]);
+        let late = window.start - window.bin_width() * 2 + TimeDelta::seconds(1);
+
+        // Windows of per-bin accumulators take the value in its bin.
+        let mut bins = window.clone();
+        bins.bin_mut(late).insert(1.0);
+        let counts = bins.bins().map(|bin| to_f64(bin.count)).collect::<Vec<_>>();
+        assert_eq!(counts.iter().sum::<f64>(), 1.0);
+        assert_eq!(counts[counts.len() - 3], 1.0);
+
+        // Cumulative windows take it in every bin started since.
+        window.correct_after(late, |bin| bin.insert(1.0));
+        let counts = window
+            .bins()
+            .map(|bin| to_f64(bin.count))
+            .collect::<Vec<_>>();
+        assert_eq!(counts[counts.len() - 3..], [0.0, 1.0, 1.0]);
+        assert_eq!(to_f64(window.count()), 1.0);
+        window.correct_after(window.start, |bin| bin.insert(1.0));
+        assert_eq!(to_f64(window.count()), 1.0);
+    }
+
+    /// State size and load time of 10 000 groups with a 30 day reference
+    /// window and a burst of traffic once a day. Run with
+    /// `cargo test --release -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_idle_windows() {
+        let mut window = Window::new(start(), &ReferenceInterval::R30d.window_config());
+        let bins_per_day =
+            (TimeDelta::days(1).num_seconds() / window.bin_width().num_seconds()) as usize;
+        let num_bins = window.num_bins();
+        advance(&mut window, num_bins, |bin| bin % bins_per_day == 0);
+        let windows = vec![window; 10_000];
+        let dense_data = encode(&windows.iter().map(dense).collect::<Vec<_>>());
+        let rle_data = encode(&windows);
+
+        let t0 = Instant::now();
+        let dense_windows =
+            ciborium::from_reader::<Vec<Window<Welford<Quad>>>, _>(&dense_data[..]).unwrap();
+        let dense_time = t0.elapsed();
+
+        let t0 = Instant::now();
+        let rle_windows =
+            ciborium::from_reader::<Vec<Window<Welford<Quad>>>, _>(&rle_data[..]).unwrap();
+        let rle_time = t0.elapsed();
+
+        let heap = |windows: &[Window<Welford<Quad>>]| {
+            windows
+                .iter()
+                .map(|window| match &window.ring {
+                    Ring::Dense(bins) => std::mem::size_of_val(&bins[..]),
+                    Ring::Rle(runs) => std::mem::size_of_val(&runs[..]),
+                })
+                .sum::<usize>()
+        };
+        println!(
+            "dense: {} bytes saved, {} bytes heap; rle: {} bytes saved, {} bytes heap; \
+             load: dense {dense_time:?}, rle {rle_time:?}",
+            dense_data.len(),
+            windows.len() * std::mem::size_of::<Welford<Quad>>() * windows[0].num_bins(),
+            rle_data.len(),
+            heap(&rle_windows),
+        );
+        assert_eq!(heap(&dense_windows), heap(&rle_windows));
+    }
+}